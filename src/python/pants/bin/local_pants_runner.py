@@ -101,7 +101,7 @@ class LocalPantsRunner(object):
       build_config
     )
 
-    return graph_scheduler_helper.new_session(global_options.v2_ui)
+    return graph_scheduler_helper.new_session(global_options.v2_ui, global_options.build_deadline)
 
   @staticmethod
   def _maybe_init_target_roots(target_roots, graph_session, options, build_root):
@@ -17,6 +17,7 @@ from pants.core_tasks.reporting_server_run import ReportingServerRun
 from pants.core_tasks.roots import ListRoots
 from pants.core_tasks.run_prep_command import (RunBinaryPrepCommand, RunCompilePrepCommand,
                                                RunTestPrepCommand)
+from pants.core_tasks.store_stats import StoreStats
 from pants.core_tasks.substitute_aliased_targets import SubstituteAliasedTargets
 from pants.core_tasks.targets_help import TargetsHelp
 from pants.goal.goal import Goal
@@ -90,6 +91,7 @@ def register_goals():
 
   # Workspace information.
   task(name='roots', action=ListRoots).install()
+  task(name='store-stats', action=StoreStats).install()
   task(name='bash-completion', action=BashCompletion).install()
 
   # Handle sources that aren't loose files in the repo.
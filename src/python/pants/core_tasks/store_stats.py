@@ -0,0 +1,17 @@
+# coding=utf-8
+# Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+# Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+from __future__ import absolute_import, division, print_function, unicode_literals
+
+from pants.task.console_task import ConsoleTask
+
+
+class StoreStats(ConsoleTask):
+  """Show the number and size of the entries in the engine's local store, by kind and by shard,
+  and how many of the store's reads hit or missed locally and remotely since pants started."""
+
+  def console_output(self, targets):
+    stats = self.context._scheduler.store_stats()
+    for name, value in sorted(stats.items()):
+      yield '{}: {}'.format(name, value)
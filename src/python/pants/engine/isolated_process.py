@@ -28,6 +28,18 @@ class ExecuteProcessRequest(datatype([
   # NB: timeout_seconds covers the whole remote operation including queuing and setup.
   ('timeout_seconds', Exactly(float, int)),
   ('jdk_home', string_optional),
+  # If True, the output of the process is streamed to the console as it runs (when it's run
+  # locally), in addition to being captured.
+  ('tee_output', Exactly(bool)),
+  # Remote execution platform properties (e.g. OSFamily or container-image), which take precedence
+  # over the --remote-execution-extra-platform-properties.
+  ('platform_properties', hashable_string_list),
+  # The priority of the process with respect to others on a shared remote execution cluster: lower
+  # values run sooner, and 0 is the server's default. Ignored for local execution.
+  ('priority', int),
+  # gRPC metadata headers (e.g. x-worker-pool) to send with the remote execution of the process, for
+  # services which route on headers. They take precedence over the --remote-execution-headers.
+  ('execution_headers', hashable_string_list),
 ])):
   """Request for execution with args and snapshots to extract."""
 
@@ -43,6 +55,10 @@ class ExecuteProcessRequest(datatype([
     output_directories=(),
     timeout_seconds=_default_timeout_seconds,
     jdk_home=None,
+    tee_output=False,
+    platform_properties=None,
+    priority=0,
+    execution_headers=None,
   ):
     if env is None:
       env = ()
@@ -52,6 +68,26 @@ class ExecuteProcessRequest(datatype([
           "arg 'env' was invalid: value {} (with type {}) must be a dict".format(env, type(env)))
       env = tuple(item for pair in env.items() for item in pair)
 
+    if platform_properties is None:
+      platform_properties = ()
+    else:
+      if not isinstance(platform_properties, dict):
+        raise cls.make_type_error(
+          "arg 'platform_properties' was invalid: value {} (with type {}) must be a dict".format(
+            platform_properties, type(platform_properties)))
+      platform_properties = tuple(
+        item for pair in sorted(platform_properties.items()) for item in pair)
+
+    if execution_headers is None:
+      execution_headers = ()
+    else:
+      if not isinstance(execution_headers, dict):
+        raise cls.make_type_error(
+          "arg 'execution_headers' was invalid: value {} (with type {}) must be a dict".format(
+            execution_headers, type(execution_headers)))
+      execution_headers = tuple(
+        item for pair in sorted(execution_headers.items()) for item in pair)
+
     return super(ExecuteProcessRequest, cls).__new__(
       cls,
       argv=argv,
@@ -62,6 +98,10 @@ class ExecuteProcessRequest(datatype([
       output_directories=output_directories,
       timeout_seconds=timeout_seconds,
       jdk_home=jdk_home,
+      tee_output=tee_output,
+      platform_properties=platform_properties,
+      priority=priority,
+      execution_headers=execution_headers,
     )
 
 
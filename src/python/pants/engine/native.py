@@ -25,6 +25,7 @@ from pants.util.dirutil import read_file, safe_mkdir, safe_mkdtemp
 from pants.util.memo import memoized_classproperty, memoized_property
 from pants.util.meta import Singleton
 from pants.util.objects import SubclassesOf, datatype
+from pants.version import VERSION
 
 
 logger = logging.getLogger(__name__)
@@ -747,8 +748,12 @@ class Native(Singleton):
       self.lib.execution_request_create(),
       self.lib.execution_request_destroy)
 
-  def new_session(self, scheduler, should_render_ui, ui_worker_count):
-    return self.gc(self.lib.session_create(scheduler, should_render_ui, ui_worker_count), self.lib.session_destroy)
+  def new_session(self, scheduler, should_render_ui, ui_worker_count, build_deadline=None):
+    # A deadline of zero milliseconds means that there is no deadline.
+    build_deadline_millis = int(build_deadline * 1000) if build_deadline else 0
+    return self.gc(
+      self.lib.session_create(scheduler, should_render_ui, ui_worker_count, build_deadline_millis),
+      self.lib.session_destroy)
 
   def new_scheduler(self,
                     tasks,
@@ -821,15 +826,60 @@ class Native(Singleton):
         self.context.utf8_buf(execution_options.remote_execution_server or ""),
         self.context.utf8_buf(execution_options.remote_execution_process_cache_namespace or ""),
         self.context.utf8_buf(execution_options.remote_instance_name or ""),
+        self.context.utf8_buf_buf(execution_options.remote_instance_routes),
         self.context.utf8_buf(execution_options.remote_ca_certs_path or ""),
+        self.context.utf8_buf(execution_options.remote_client_cert_path or ""),
+        self.context.utf8_buf(execution_options.remote_client_key_path or ""),
         self.context.utf8_buf(execution_options.remote_oauth_bearer_token_path or ""),
+        self.context.utf8_buf(execution_options.remote_oauth_bearer_token_helper or ""),
+        self.context.utf8_buf(execution_options.remote_proxy or ""),
+        execution_options.remote_keepalive_interval_seconds,
+        execution_options.remote_keepalive_timeout_seconds,
+        execution_options.remote_max_reconnect_backoff_seconds,
+        self.context.utf8_buf(VERSION),
+        self.context.utf8_buf(execution_options.remote_correlated_invocations_id or ""),
+        self.context.utf8_buf(execution_options.remote_store_compression),
+        self.context.utf8_buf(execution_options.remote_store_load_balancing),
         execution_options.remote_store_thread_count,
         execution_options.remote_store_chunk_bytes,
         execution_options.remote_store_chunk_upload_timeout_seconds,
+        execution_options.remote_store_read_timeout_seconds,
+        execution_options.remote_action_cache_timeout_seconds,
         execution_options.remote_store_rpc_retries,
+        execution_options.remote_store_lease_extension_interval_seconds,
+        execution_options.remote_execution_rpc_retries,
+        execution_options.remote_execution_rpc_timeout_seconds,
         self.context.utf8_buf_buf(execution_options.remote_execution_extra_platform_properties),
+        self.context.utf8_buf_buf(execution_options.remote_execution_headers),
+        execution_options.remote_execution_circuit_breaker_failures,
+        execution_options.remote_execution_circuit_breaker_cool_down_seconds,
+        execution_options.remote_execution_verify_results,
         execution_options.process_execution_parallelism,
+        execution_options.process_execution_remote_parallelism,
         execution_options.process_execution_cleanup_local_dirs,
+        execution_options.remote_cache_write,
+        execution_options.remote_cache_read,
+        execution_options.remote_asset_fetch,
+        execution_options.local_store_target_size,
+        execution_options.local_store_verify_digests,
+        self.context.utf8_buf(execution_options.local_store_compression_threshold),
+        execution_options.local_store_shard_count,
+        self.context.utf8_buf(execution_options.local_store_shard_map_size),
+        execution_options.local_store_max_readers,
+        self.context.utf8_buf(execution_options.remote_store_small_blob_cache or ""),
+        execution_options.local_store_max_unused_age_days,
+        self.context.utf8_buf(execution_options.local_store_file_shard_quota),
+        self.context.utf8_buf(execution_options.local_store_directory_shard_quota),
+        execution_options.local_store_eviction_dry_run,
+        execution_options.local_store_hashing_parallelism,
+        self.context.utf8_buf(execution_options.local_store_mmap_threshold),
+        execution_options.remote_store_read_through,
+        execution_options.remote_store_write_through,
+        self.context.utf8_buf(execution_options.remote_store_max_upload_file_size),
+        self.context.utf8_buf(execution_options.remote_store_max_upload_directory_size),
+        execution_options.remote_store_known_blobs_capacity,
+        execution_options.remote_store_known_blobs_lifetime_seconds,
+        self.context.utf8_buf(execution_options.process_execution_local_materialization),
       )
     return self.gc(scheduler, self.lib.scheduler_destroy)
 
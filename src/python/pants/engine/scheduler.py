@@ -343,9 +343,15 @@ class Scheduler(object):
   def garbage_collect_store(self):
     self._native.lib.garbage_collect_store(self._scheduler)
 
-  def new_session(self, v2_ui=False):
+  def store_stats(self):
+    """Returns statistics about the engine's local store, as a dict of name to count."""
+    return self._raise_or_return(self._native.lib.store_stats(self._scheduler))
+
+  def new_session(self, v2_ui=False, build_deadline=None):
     """Creates a new SchedulerSession for this Scheduler."""
-    return SchedulerSession(self, self._native.new_session(self._scheduler, v2_ui, multiprocessing.cpu_count()))
+    native_session = self._native.new_session(
+      self._scheduler, v2_ui, multiprocessing.cpu_count(), build_deadline)
+    return SchedulerSession(self, native_session)
 
 
 _PathGlobsAndRootCollection = Collection.of(PathGlobsAndRoot)
@@ -593,3 +599,6 @@ class SchedulerSession(object):
 
   def garbage_collect_store(self):
     self._scheduler.garbage_collect_store()
+
+  def store_stats(self):
+    return self._scheduler.store_stats()
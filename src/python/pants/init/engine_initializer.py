@@ -154,8 +154,8 @@ def _make_target_adaptor(base_class, target_type):
 class LegacyGraphScheduler(datatype(['scheduler', 'build_file_aliases', 'goal_map'])):
   """A thin wrapper around a Scheduler configured with @rules for a symbol table."""
 
-  def new_session(self, v2_ui=False):
-    session = self.scheduler.new_session(v2_ui)
+  def new_session(self, v2_ui=False, build_deadline=None):
+    session = self.scheduler.new_session(v2_ui, build_deadline)
     return LegacyGraphSession(session, self.build_file_aliases, self.goal_map)
 
 
@@ -105,6 +105,44 @@ def file_option(s):
   return _normalize_directory_separators(s)
 
 
+_DURATION_UNIT_SECONDS = {'s': 1, 'm': 60, 'h': 60 * 60}
+
+
+def duration_option(s):
+  """An option of type 'float', whose value is a number of seconds.
+
+  The value may be a plain number of seconds, or a number suffixed with one of `s`, `m` or `h`
+  (e.g., `30m`).
+
+  :API: public
+  """
+  if isinstance(s, (int, float)):
+    return float(s)
+  match = re.match(r'^\s*([0-9]*\.?[0-9]+)\s*([smh]?)\s*$', s)
+  if not match:
+    raise ParseError('Invalid duration {!r}: expected e.g. 90, 90s, 30m or 2h'.format(s))
+  return float(match.group(1)) * _DURATION_UNIT_SECONDS[match.group(2) or 's']
+
+
+_MEMORY_SIZE_UNIT_BYTES = {'B': 1, 'KiB': 1 << 10, 'MiB': 1 << 20, 'GiB': 1 << 30, 'TiB': 1 << 40}
+
+
+def memory_size_option(s):
+  """An option of type 'int', whose value is a number of bytes.
+
+  The value may be a plain number of bytes, or a number suffixed with one of the binary units
+  `KiB`, `MiB`, `GiB` or `TiB` (e.g., `20GiB`).
+
+  :API: public
+  """
+  if isinstance(s, int):
+    return s
+  match = re.match(r'^\s*([0-9]+)\s*(B|KiB|MiB|GiB|TiB)?\s*$', s)
+  if not match:
+    raise ParseError('Invalid size {!r}: expected e.g. 1048576, 512KiB or 20GiB'.format(s))
+  return int(match.group(1)) * _MEMORY_SIZE_UNIT_BYTES[match.group(2) or 'B']
+
+
 def dict_with_files_option(s):
   """Same as 'dict', but fingerprints the file contents of any values which are file paths.
 
@@ -13,7 +13,8 @@ from textwrap import dedent
 from pants.base.build_environment import (get_buildroot, get_default_pants_config_file,
                                           get_pants_cachedir, get_pants_configdir, pants_version)
 from pants.option.arg_splitter import GLOBAL_SCOPE
-from pants.option.custom_types import dir_option, file_option
+from pants.option.custom_types import (dir_option, duration_option, file_option,
+                                       memory_size_option)
 from pants.option.errors import OptionsError
 from pants.option.optionable import Optionable
 from pants.option.scope import ScopeInfo
@@ -34,15 +35,59 @@ class ExecutionOptions(datatype([
   'remote_store_thread_count',
   'remote_execution_server',
   'remote_store_chunk_bytes',
+  'remote_store_compression',
+  'remote_store_load_balancing',
   'remote_store_chunk_upload_timeout_seconds',
+  'remote_store_read_timeout_seconds',
+  'remote_action_cache_timeout_seconds',
   'remote_store_rpc_retries',
+  'remote_store_lease_extension_interval_seconds',
+  'remote_execution_rpc_retries',
+  'remote_execution_rpc_timeout_seconds',
   'process_execution_parallelism',
+  'process_execution_remote_parallelism',
   'process_execution_cleanup_local_dirs',
   'remote_execution_process_cache_namespace',
   'remote_instance_name',
+  'remote_instance_routes',
   'remote_ca_certs_path',
+  'remote_client_cert_path',
+  'remote_client_key_path',
   'remote_oauth_bearer_token_path',
+  'remote_oauth_bearer_token_helper',
+  'remote_proxy',
+  'remote_keepalive_interval_seconds',
+  'remote_keepalive_timeout_seconds',
+  'remote_max_reconnect_backoff_seconds',
+  'remote_correlated_invocations_id',
   'remote_execution_extra_platform_properties',
+  'remote_execution_headers',
+  'remote_execution_circuit_breaker_failures',
+  'remote_execution_circuit_breaker_cool_down_seconds',
+  'remote_execution_verify_results',
+  'remote_cache_write',
+  'remote_cache_read',
+  'remote_asset_fetch',
+  'local_store_target_size',
+  'local_store_verify_digests',
+  'local_store_compression_threshold',
+  'local_store_shard_count',
+  'local_store_shard_map_size',
+  'local_store_max_readers',
+  'remote_store_small_blob_cache',
+  'local_store_max_unused_age_days',
+  'local_store_file_shard_quota',
+  'local_store_directory_shard_quota',
+  'local_store_eviction_dry_run',
+  'local_store_hashing_parallelism',
+  'local_store_mmap_threshold',
+  'remote_store_read_through',
+  'remote_store_write_through',
+  'remote_store_max_upload_file_size',
+  'remote_store_max_upload_directory_size',
+  'remote_store_known_blobs_capacity',
+  'remote_store_known_blobs_lifetime_seconds',
+  'process_execution_local_materialization',
 ])):
   """A collection of all options related to (remote) execution of processes.
 
@@ -57,15 +102,62 @@ class ExecutionOptions(datatype([
       remote_execution_server=bootstrap_options.remote_execution_server,
       remote_store_thread_count=bootstrap_options.remote_store_thread_count,
       remote_store_chunk_bytes=bootstrap_options.remote_store_chunk_bytes,
+      remote_store_compression=bootstrap_options.remote_store_compression,
+      remote_store_load_balancing=bootstrap_options.remote_store_load_balancing,
       remote_store_chunk_upload_timeout_seconds=bootstrap_options.remote_store_chunk_upload_timeout_seconds,
+      remote_store_read_timeout_seconds=bootstrap_options.remote_store_read_timeout_seconds,
+      remote_action_cache_timeout_seconds=bootstrap_options.remote_action_cache_timeout_seconds,
       remote_store_rpc_retries=bootstrap_options.remote_store_rpc_retries,
+      remote_store_lease_extension_interval_seconds=bootstrap_options.remote_store_lease_extension_interval_seconds,
+      remote_execution_rpc_retries=bootstrap_options.remote_execution_rpc_retries,
+      remote_execution_rpc_timeout_seconds=bootstrap_options.remote_execution_rpc_timeout_seconds,
       process_execution_parallelism=bootstrap_options.process_execution_parallelism,
+      process_execution_remote_parallelism=bootstrap_options.process_execution_remote_parallelism,
       process_execution_cleanup_local_dirs=bootstrap_options.process_execution_cleanup_local_dirs,
       remote_execution_process_cache_namespace=bootstrap_options.remote_execution_process_cache_namespace,
       remote_instance_name=bootstrap_options.remote_instance_name,
+      remote_instance_routes=bootstrap_options.remote_instance_routes,
       remote_ca_certs_path=bootstrap_options.remote_ca_certs_path,
+      remote_client_cert_path=bootstrap_options.remote_client_cert_path,
+      remote_client_key_path=bootstrap_options.remote_client_key_path,
       remote_oauth_bearer_token_path=bootstrap_options.remote_oauth_bearer_token_path,
+      remote_oauth_bearer_token_helper=bootstrap_options.remote_oauth_bearer_token_helper,
+      remote_proxy=bootstrap_options.remote_proxy,
+      remote_keepalive_interval_seconds=bootstrap_options.remote_keepalive_interval_seconds,
+      remote_keepalive_timeout_seconds=bootstrap_options.remote_keepalive_timeout_seconds,
+      remote_max_reconnect_backoff_seconds=bootstrap_options.remote_max_reconnect_backoff_seconds,
+      remote_correlated_invocations_id=bootstrap_options.remote_correlated_invocations_id,
       remote_execution_extra_platform_properties=bootstrap_options.remote_execution_extra_platform_properties,
+      remote_execution_headers=bootstrap_options.remote_execution_headers,
+      remote_execution_circuit_breaker_failures=bootstrap_options.remote_execution_circuit_breaker_failures,
+      remote_execution_circuit_breaker_cool_down_seconds=bootstrap_options.remote_execution_circuit_breaker_cool_down_seconds,
+      remote_execution_verify_results=bootstrap_options.remote_execution_verify_results,
+      remote_cache_write=bootstrap_options.remote_cache_write,
+      remote_cache_read=bootstrap_options.remote_cache_read,
+      remote_asset_fetch=bootstrap_options.remote_asset_fetch,
+      local_store_target_size=bootstrap_options.local_store_target_size,
+      local_store_verify_digests=bootstrap_options.local_store_verify_digests,
+      local_store_compression_threshold=bootstrap_options.local_store_compression_threshold,
+      local_store_shard_count=bootstrap_options.local_store_shard_count,
+      local_store_shard_map_size=bootstrap_options.local_store_shard_map_size,
+      local_store_max_readers=bootstrap_options.local_store_max_readers,
+      remote_store_small_blob_cache=bootstrap_options.remote_store_small_blob_cache,
+      local_store_max_unused_age_days=bootstrap_options.local_store_max_unused_age_days,
+      local_store_file_shard_quota=bootstrap_options.local_store_file_shard_quota,
+      local_store_directory_shard_quota=bootstrap_options.local_store_directory_shard_quota,
+      local_store_eviction_dry_run=bootstrap_options.local_store_eviction_dry_run,
+      local_store_hashing_parallelism=bootstrap_options.local_store_hashing_parallelism,
+      local_store_mmap_threshold=bootstrap_options.local_store_mmap_threshold,
+      remote_store_read_through=bootstrap_options.remote_store_read_through,
+      remote_store_write_through=bootstrap_options.remote_store_write_through,
+      remote_store_max_upload_file_size=bootstrap_options.remote_store_max_upload_file_size,
+      remote_store_max_upload_directory_size=(
+        bootstrap_options.remote_store_max_upload_directory_size),
+      remote_store_known_blobs_capacity=bootstrap_options.remote_store_known_blobs_capacity,
+      remote_store_known_blobs_lifetime_seconds=(
+        bootstrap_options.remote_store_known_blobs_lifetime_seconds),
+      process_execution_local_materialization=(
+        bootstrap_options.process_execution_local_materialization),
     )
 
 
@@ -74,15 +166,59 @@ DEFAULT_EXECUTION_OPTIONS = ExecutionOptions(
     remote_store_thread_count=1,
     remote_execution_server=None,
     remote_store_chunk_bytes=1024*1024,
+    remote_store_compression='none',
+    remote_store_load_balancing='round-robin',
     remote_store_chunk_upload_timeout_seconds=60,
+    remote_store_read_timeout_seconds=5*60,
+    remote_action_cache_timeout_seconds=30,
     remote_store_rpc_retries=2,
+    remote_store_lease_extension_interval_seconds=30*60,
+    remote_execution_rpc_retries=10,
+    remote_execution_rpc_timeout_seconds=60*60,
     process_execution_parallelism=multiprocessing.cpu_count()*2,
+    process_execution_remote_parallelism=128,
     process_execution_cleanup_local_dirs=True,
     remote_execution_process_cache_namespace=None,
     remote_instance_name=None,
+    remote_instance_routes=[],
     remote_ca_certs_path=None,
+    remote_client_cert_path=None,
+    remote_client_key_path=None,
     remote_oauth_bearer_token_path=None,
+    remote_oauth_bearer_token_helper=None,
+    remote_proxy=None,
+    remote_keepalive_interval_seconds=0,
+    remote_keepalive_timeout_seconds=20,
+    remote_max_reconnect_backoff_seconds=30,
+    remote_correlated_invocations_id=None,
     remote_execution_extra_platform_properties=[],
+    remote_execution_headers=[],
+    remote_execution_circuit_breaker_failures=5,
+    remote_execution_circuit_breaker_cool_down_seconds=60,
+    remote_execution_verify_results=False,
+    remote_cache_write=False,
+    remote_cache_read=False,
+    remote_asset_fetch=False,
+    local_store_target_size=4 * 1024 * 1024 * 1024,
+    local_store_verify_digests=False,
+    local_store_compression_threshold='64KiB',
+    local_store_shard_count=16,
+    local_store_shard_map_size='100GiB',
+    local_store_max_readers=126,
+    remote_store_small_blob_cache=None,
+    local_store_max_unused_age_days=0,
+    local_store_file_shard_quota='0',
+    local_store_directory_shard_quota='0',
+    local_store_eviction_dry_run=False,
+    local_store_hashing_parallelism=multiprocessing.cpu_count(),
+    local_store_mmap_threshold='1MiB',
+    remote_store_read_through=True,
+    remote_store_write_through=False,
+    remote_store_max_upload_file_size='0',
+    remote_store_max_upload_directory_size='0',
+    remote_store_known_blobs_capacity=1000000,
+    remote_store_known_blobs_lifetime_seconds=60*60,
+    process_execution_local_materialization='copy',
   )
 
 
@@ -334,12 +470,87 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
              help='Whether to allow import statements in BUILD files')
 
     register('--local-store-dir', advanced=True,
-             help="Directory to use for engine's local file store.",
+             help="Directory to use for engine's local file store. Like the default (which is "
+                  "per user), it may be shared by any number of checkouts and pantsd instances on a "
+                  "machine, which lock it so that only one of them garbage collects it at a time.",
              # This default is also hard-coded into the engine's rust code in
              # fs::Store::default_path
              default=os.path.expanduser('~/.cache/pants/lmdb_store'))
+    register('--local-store-target-size', type=memory_size_option, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_target_size,
+             help="The size (e.g. 20GiB, or a number of bytes) to which garbage collection shrinks "
+                  "the engine's local file store, by removing its least recently used digests. "
+                  "pantsd garbage collects the store periodically.")
+    register('--local-store-verify-digests', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_verify_digests,
+             help="Whether to re-hash everything which is read from the engine's local file "
+                  "store, to detect corruption (e.g. after a power loss). Corrupted entries are "
+                  "deleted, and re-fetched from the remote store if there is one.")
+    register('--local-store-compression-threshold', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_compression_threshold,
+             help="Files and directories of at least this size (e.g. 64KiB, or a number of bytes) "
+                  "are compressed (with zstd) in the engine's local file store, if that makes "
+                  "them smaller. 0 disables compression. Entries which are already stored are "
+                  "left as they are.")
+    register('--local-store-shard-count', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_shard_count,
+             help="The number of LMDB databases (a power of two, up to 256) that each kind of "
+                  "entry in the engine's local file store is sharded between, each of which can "
+                  "be written to concurrently. Changing this starts an empty local store.")
+    register('--local-store-shard-map-size', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_shard_map_size,
+             help="The size (e.g. 100GiB, or a number of bytes) that the memory map of each shard "
+                  "of the engine's local file store starts at. Maps which fill up are grown, so "
+                  "this rarely needs changing; it doesn't reflect space used on disk or in RAM.")
+    register('--local-store-max-readers', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_max_readers,
+             help="The maximum number of concurrent reads of each shard of the engine's local "
+                  "file store.")
+    register('--local-store-max-unused-age-days', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_max_unused_age_days,
+             help="Garbage collection of the engine's local file store evicts the entries which "
+                  "have not been used (or leased) for more than this many days. 0 disables "
+                  "evicting entries by age.")
+    register('--local-store-file-shard-quota', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_file_shard_quota,
+             help="Garbage collection of the engine's local file store evicts the least recently "
+                  "used files of each shard until they take up at most this much space (e.g. "
+                  "256MiB, or a number of bytes). Leased files are never evicted. 0 disables the "
+                  "quota.")
+    register('--local-store-directory-shard-quota', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_directory_shard_quota,
+             help="As --local-store-file-shard-quota, but for directories.")
+    register('--local-store-eviction-dry-run', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_eviction_dry_run,
+             help="Rather than evicting the entries of the engine's local file store which its "
+                  "maximum age and shard quotas evict, log what would be evicted and why. This "
+                  "doesn't affect shrinking the store to --local-store-target-size.")
+    register('--local-store-hashing-parallelism', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_hashing_parallelism,
+             help="The number of threads that large files (of at least 1MiB) are hashed on as "
+                  "they are stored in the engine's local file store, e.g. when snapshotting, so "
+                  "that many large files are hashed on as many cores at once. 0 hashes them "
+                  "alongside the rest of storing them.")
+    register('--local-store-mmap-threshold', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_mmap_threshold,
+             help="Files of at least this size (e.g. 1MiB, or a number of bytes) are memory "
+                  "mapped to hash them and store them in the engine's local file store, rather "
+                  "than read into memory. Files which can't be mapped are read. 0 disables "
+                  "memory mapping.")
     register('--remote-store-server', advanced=True, type=list, default=[],
-             help='host:port of grpc server to use as remote execution file store.')
+             help='host:port of grpc server to use as remote execution file store, or the '
+                  'http:// or https:// URL of an HTTP cache (e.g. bazel-remote) which speaks '
+                  'Bazel\'s HTTP caching protocol, to use only as a remote cache, or the '
+                  's3://<bucket>[/<prefix>] URL of an S3 bucket, to use only as a remote cache. '
+                  'S3 URLs take the parameters region, endpoint (of an S3-compatible service), '
+                  'sse (AES256 or aws:kms), sse_kms_key_id and part_size (of multipart uploads), '
+                  'e.g. s3://cache/pants?region=eu-west-1&sse=AES256, and requests are signed '
+                  'with the AWS credentials of the environment. Likewise, a '
+                  'gs://<bucket>[/<prefix>] URL is a GCS bucket, which takes the parameters '
+                  'credentials (the JSON key file of a service account, by default '
+                  '$GOOGLE_APPLICATION_CREDENTIALS), endpoint and chunk_size (of resumable '
+                  'uploads); without a service account, requests are authenticated with the '
+                  '--remote-oauth-bearer-token-path or -helper, if any.')
     register('--remote-store-thread-count', type=int, advanced=True,
              default=DEFAULT_EXECUTION_OPTIONS.remote_store_thread_count,
              help='Thread count to use for the pool that interacts with the remote file store.')
@@ -348,12 +559,88 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
     register('--remote-store-chunk-bytes', type=int, advanced=True,
              default=DEFAULT_EXECUTION_OPTIONS.remote_store_chunk_bytes,
              help='Size in bytes of chunks transferred to/from the remote file store.')
+    register('--remote-store-compression', advanced=True,
+             choices=['none', 'deflate', 'gzip'],
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_compression,
+             help='How to compress messages sent to the remote file store, which must support the '
+                  'chosen algorithm. Compression saves bandwidth on slow connections, at the cost '
+                  'of CPU time.')
+    register('--remote-store-load-balancing', advanced=True,
+             choices=['round-robin', 'pick-first'],
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_load_balancing,
+             help='How to spread requests across the --remote-store-server addresses: across all '
+                  'of the healthy servers in turn (in a random order), or to the first healthy '
+                  'server in the order given, failing over to the next while it is unhealthy.')
     register('--remote-store-chunk-upload-timeout-seconds', type=int, advanced=True,
              default=DEFAULT_EXECUTION_OPTIONS.remote_store_chunk_upload_timeout_seconds,
              help='Timeout (in seconds) for uploads of individual chunks to the remote file store.')
+    register('--remote-store-read-timeout-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_read_timeout_seconds,
+             help='Timeout (in seconds) for reads of individual files from the remote file store, '
+                  'and for queries of which files it is missing.')
+    register('--remote-action-cache-timeout-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_action_cache_timeout_seconds,
+             help='Timeout (in seconds) for lookups in (and updates of) the remote action cache.')
     register('--remote-store-rpc-retries', type=int, advanced=True,
              default=DEFAULT_EXECUTION_OPTIONS.remote_store_rpc_retries,
-             help='Number of times to retry any RPC to the remote store before giving up.')
+             help='Number of times to retry any RPC to the remote store before giving up. '
+                  'Retries back off exponentially, with jitter.')
+    register('--remote-store-lease-extension-interval-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_lease_extension_interval_seconds,
+             help='How often to extend the leases of the blobs which a run uploaded to (or found '
+                  'in) the remote store, by checking that the remote store still has them and '
+                  're-uploading any which it garbage collected, so that the later processes of '
+                  'long builds do not fail for missing inputs. 0 disables extending leases.')
+    register('--remote-store-small-blob-cache', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_small_blob_cache,
+             help='The redis://[:<password>@]<host>[:<port>][/<database>] or '
+                  'memcached://<host>[:<port>] URL of an in-memory cache which small blobs '
+                  '(e.g. directories, and small source files) are read from before the remote '
+                  'store, and which is filled with the small blobs read from the remote store. '
+                  'This cuts the latency of walking trees in a distant remote store. The URL '
+                  'may have the query parameters max_blob_size (e.g. 64KiB, the default), ttl '
+                  '(in seconds, or 0 to never expire, default one day), timeout (in '
+                  'milliseconds, default 200; slower or failing reads fall back to the remote '
+                  'store) and prefix (of the keys, default pants:cas:).')
+    register('--remote-store-read-through', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_read_through,
+             help='Whether to store the blobs which are read from --remote-store-server in the '
+                  "engine's local file store, so that they are only read from the remote store "
+                  'once. If not, they are read from the remote store whenever they are used.')
+    register('--remote-store-write-through', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_write_through,
+             help="Whether to upload the blobs which are stored in the engine's local file store "
+                  'to --remote-store-server as soon as they are stored, rather than only when a '
+                  'remote execution needs them. Failures to upload them are only warned about.')
+    register('--remote-store-max-upload-file-size', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_max_upload_file_size,
+             help='Files larger than this (e.g. 100MiB, or a number of bytes) are never uploaded '
+                  'to --remote-store-server. 0 means there is no limit.')
+    register('--remote-store-max-upload-directory-size', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_max_upload_directory_size,
+             help='As --remote-store-max-upload-file-size, but for the protos of directories.')
+    register('--remote-store-known-blobs-capacity', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_known_blobs_capacity,
+             help='How many of the blobs which are known to be in --remote-store-server to '
+                  'remember (in bloom filters, saved in --local-store-dir), so that the server '
+                  "isn't asked whether it has them again, e.g. by every remote execution of a "
+                  'warm build. Each million blobs takes about 8MB. 0 disables remembering them.')
+    register('--remote-store-known-blobs-lifetime-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_known_blobs_lifetime_seconds,
+             help='For how long (in seconds) blobs are remembered as being in '
+                  '--remote-store-server. This should be shorter than the server keeps blobs, '
+                  'although remote executions re-upload any inputs which it reports missing. 0 '
+                  'disables remembering them.')
+    register('--remote-execution-rpc-retries', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_rpc_retries,
+             help='Number of times in a row to reconnect to a remote execution whose stream of '
+                  'updates breaks before giving up. Reconnections back off exponentially, with '
+                  'jitter.')
+    register('--remote-execution-rpc-timeout-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_rpc_timeout_seconds,
+             help='Timeout (in seconds) for each call which waits on a remote execution, after '
+                  'which waiting is resumed with a new call. Calls are also never given longer '
+                  'than the remaining timeout of the process being executed.')
     register('--remote-execution-process-cache-namespace', advanced=True,
              help="The cache namespace for remote process execution. "
                   "Bump this to invalidate every artifact's remote execution. "
@@ -362,28 +649,139 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
     register('--remote-instance-name', advanced=True,
              help='Name of the remote execution instance to use. Used for routing within '
                   '--remote-execution-server and --remote-store-server.')
+    register('--remote-instance-routes', advanced=True, type=list,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_instance_routes,
+             help='Rules which route processes to remote instances other than '
+                  '--remote-instance-name, for both execution and caching: for example, to '
+                  'separate Linux and macOS clusters behind the same servers. Format: '
+                  'property=value:instance_name, which routes the processes with the given '
+                  'platform property (whether set for the process, or by '
+                  '--remote-execution-extra-platform-properties) to the given instance. The first '
+                  'matching rule wins.')
     register('--remote-ca-certs-path', advanced=True,
              help='Path to a PEM file containing CA certificates used for verifying secure '
                   'connections to --remote-execution-server and --remote-store-server. '
                   'If not specified, TLS will not be used.')
+    register('--remote-client-cert-path', advanced=True,
+             help='Path to a PEM file containing a certificate chain with which to authenticate to '
+                  '--remote-execution-server and --remote-store-server, for servers which require '
+                  'mutual TLS. Requires --remote-client-key-path. If --remote-ca-certs-path is not '
+                  'specified, the server certificates are verified against the default roots.')
+    register('--remote-client-key-path', advanced=True,
+             help='Path to a PEM file containing the private key of --remote-client-cert-path.')
     register('--remote-oauth-bearer-token-path', advanced=True,
              help='Path to a file containing an oauth token to use for grpc connections to '
                   '--remote-execution-server and --remote-store-server. If not specified, no '
-                  'authorization will be performed.')
+                  'authorization will be performed. The file is re-read whenever a server rejects '
+                  'the token, so it may be replaced as tokens expire.')
+    register('--remote-oauth-bearer-token-helper', advanced=True,
+             help='Path to an executable which prints an oauth token to use for grpc connections '
+                  'to --remote-execution-server and --remote-store-server, as an alternative to '
+                  '--remote-oauth-bearer-token-path. The executable is re-run whenever a server '
+                  'rejects the token.')
+    register('--remote-proxy', advanced=True,
+             help='The HTTP proxy through which to connect to --remote-execution-server and '
+                  '--remote-store-server, as [http://][user:password@]host[:port], which is '
+                  'connected to with HTTP CONNECT. If not specified, the proxy (if any) is taken '
+                  'from the https_proxy or http_proxy environment variables. SOCKS proxies are '
+                  'not supported.')
+    register('--remote-keepalive-interval-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_keepalive_interval_seconds,
+             help='How often (in seconds) to ping --remote-execution-server and '
+                  '--remote-store-server, even while idle, to detect connections which have '
+                  'silently died (e.g. behind a VPN or NAT). The servers must permit pings this '
+                  'often: by default, gRPC servers only permit them every five minutes. 0 '
+                  'disables keepalive pings.')
+    register('--remote-keepalive-timeout-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_keepalive_timeout_seconds,
+             help='How long (in seconds) to wait for a keepalive ping to be acknowledged before '
+                  'considering the connection dead, failing (and retrying) its RPCs and '
+                  'reconnecting.')
+    register('--remote-max-reconnect-backoff-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_max_reconnect_backoff_seconds,
+             help='The longest time (in seconds) to wait between attempts to re-establish a '
+                  'failed connection to a remote server.')
+    register('--remote-correlated-invocations-id', advanced=True,
+             help='An identifier to send (as the correlated_invocations_id of the RequestMetadata '
+                  'of each RPC to --remote-execution-server and --remote-store-server) to tie '
+                  'runs of pants together: for example, all of the runs of a single CI build. '
+                  'Every run also sends its own random tool_invocation_id.')
     register('--remote-execution-extra-platform-properties', advanced=True,
              help='Platform properties to set on remote execution requests. '
                   'Format: property=value. Multiple values should be specified as multiple '
                   'occurrences of this flag. Pants itself may add additional platform properties.',
                    type=list, default=[])
+    register('--remote-execution-headers', advanced=True, type=list,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_headers,
+             help='gRPC metadata headers to send with every remote execution request, for '
+                  'services which route actions to worker pools on headers (e.g. '
+                  'x-worker-pool=gpu) rather than on platform properties. Format: name=value. '
+                  'Header names must be lowercase. The headers of each process take precedence.')
+    register('--remote-execution-circuit-breaker-failures', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_circuit_breaker_failures,
+             help='After this many processes in a row fail to execute remotely (because of '
+                  'errors talking to --remote-execution-server, rather than processes exiting '
+                  'unsuccessfully), execute processes locally instead until the server recovers. '
+                  'Processes which fail to execute remotely are always retried locally. Only '
+                  'applies if this machine has the same platform as the remote execution '
+                  'workers. 0 disables local fallback.')
+    register('--remote-execution-circuit-breaker-cool-down-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_circuit_breaker_cool_down_seconds,
+             help='How long to execute processes locally after '
+                  '--remote-execution-circuit-breaker-failures is reached, before probing '
+                  '--remote-execution-server with a single process to see whether it has '
+                  'recovered.')
+    register('--remote-execution-verify-results', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_verify_results,
+             help='Whether to download and re-hash every output of each remote execution (or '
+                  'remote action cache hit), and check that its outputs are the declared output '
+                  'files and directories of the process, before using it. Results which fail '
+                  'verification are errors, so are retried locally if '
+                  '--remote-execution-circuit-breaker-failures is enabled.')
+    register('--remote-cache-write', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_cache_write,
+             help='Whether to record the results of hermetic processes which are executed locally '
+                  'in the action cache of --remote-store-server, so that remote executions (and '
+                  'other machines) can use them. Remote executions always check the action cache '
+                  'before executing.')
+    register('--remote-cache-read', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_cache_read,
+             help='Whether to check the action cache of --remote-store-server before executing '
+                  'hermetic processes locally, and to use the results recorded there (e.g. by '
+                  'other machines with --remote-cache-write) rather than executing them. Together '
+                  'with --remote-cache-write, this uses a remote cache without remote execution.')
+    register('--remote-asset-fetch', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_asset_fetch,
+             help='Whether to ask --remote-store-server to fetch files which are downloaded by URL '
+                  '(with an expected digest) itself, with the Remote Asset API, so that they only '
+                  'pass through this machine if something here needs their content. Files are '
+                  'downloaded here if the server does not support fetching them.')
 
     # This should eventually deprecate the RunTracker worker count, which is used for legacy cache
     # lookups via CacheSetup in TaskBase.
     register('--process-execution-parallelism', type=int, default=multiprocessing.cpu_count(),
              advanced=True,
-             help='Number of concurrent processes that may be executed either locally and remotely.')
+             help='Number of concurrent processes that may be executed locally.')
+    register('--process-execution-remote-parallelism', type=int,
+             default=DEFAULT_EXECUTION_OPTIONS.process_execution_remote_parallelism,
+             advanced=True,
+             help='Number of concurrent executions that may be in flight on '
+                  '--remote-execution-server. Remote clusters can usually run far more processes '
+                  'at once than this machine, so this is independent of '
+                  '--process-execution-parallelism.')
     register('--process-execution-cleanup-local-dirs', type=bool, default=True, advanced=True,
              help='Whether or not to cleanup directories used for local process execution '
                   '(primarily useful for e.g. debugging).')
+    register('--process-execution-local-materialization', advanced=True,
+             choices=['copy', 'hardlink', 'symlink', 'reflink'],
+             default=DEFAULT_EXECUTION_OPTIONS.process_execution_local_materialization,
+             help='How to lay out the inputs of processes which are executed locally. hardlink '
+                  'and symlink link them to read-only copies in --local-store-dir, which is much '
+                  'faster than copying them for large inputs: processes must not modify them. '
+                  'Inputs which are also outputs of a process are always copied, and hardlink '
+                  'falls back to copying across filesystems. reflink clones those copies on '
+                  'filesystems which support it (e.g. Btrfs, XFS and APFS), which is as fast as '
+                  'hardlinking but leaves inputs writable, and otherwise falls back to copying.')
 
   @classmethod
   def register_options(cls, register):
@@ -410,6 +808,10 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
     register('--v2-ui', default=False, type=bool, daemon=False,
              help='Whether to show v2 engine execution progress. '
                   'This requires the --v2 flag to take effect.')
+    register('--build-deadline', type=duration_option, default=None, daemon=False,
+             help='How long (in seconds, or e.g. 30m) the v2 engine may spend executing processes '
+                  'in a run. Processes which start before the deadline have their timeouts capped '
+                  'at it, and processes still waiting to start once it has passed fail.')
 
     loop_flag = '--loop'
     register(loop_flag, type=bool,
@@ -179,8 +179,8 @@ class SchedulerService(PantsService):
     if graph_len > 0:
       self._logger.debug('graph len was {}, waiting for initial watchman event'.format(graph_len))
       self._watchman_is_running.wait()
-    v2_ui = options.for_global_scope().v2_ui
-    session = self._graph_helper.new_session(v2_ui)
+    global_options = options.for_global_scope()
+    session = self._graph_helper.new_session(global_options.v2_ui, global_options.build_deadline)
 
     if options.for_global_scope().loop:
       prefork_fn = self._prefork_loop
@@ -0,0 +1,124 @@
+// Detection of tasks which attempt to acquire permits from a semaphore that they already hold
+// permits from, which is a common cause of (otherwise silent) deadlocks.
+//
+// Tracking holders has a cost (including capturing a backtrace per acquisition), so it is only
+// enabled by the `deadlock_detection` feature: otherwise, the methods here are no-ops.
+
+#[cfg(feature = "deadlock_detection")]
+mod imp {
+  use backtrace::Backtrace;
+  use futures::task::{self, Task};
+
+  pub type HolderId = Option<usize>;
+
+  struct Holder {
+    id: usize,
+    task: Task,
+    backtrace: Backtrace,
+  }
+
+  pub struct Holders {
+    next_id: usize,
+    holders: Vec<Holder>,
+  }
+
+  impl Holders {
+    pub fn new() -> Holders {
+      Holders {
+        next_id: 0,
+        holders: Vec::new(),
+      }
+    }
+
+    ///
+    /// Records that the current task has acquired permits. Must be called within a task.
+    ///
+    pub fn add(&mut self) -> HolderId {
+      let id = self.next_id;
+      self.next_id += 1;
+      self.holders.push(Holder {
+        id,
+        task: task::current(),
+        backtrace: Backtrace::new(),
+      });
+      Some(id)
+    }
+
+    ///
+    /// A HolderId for permits which were acquired outside of any task, and so are not tracked.
+    ///
+    pub fn untracked() -> HolderId {
+      None
+    }
+
+    pub fn remove(&mut self, id: HolderId) {
+      if let Some(id) = id {
+        self.holders.retain(|holder| holder.id != id);
+      }
+    }
+
+    ///
+    /// Panics if the current task holds permits, since it is about to wait for more permits
+    /// which might only become available when it releases them.
+    ///
+    pub fn check_reentrant(&self) {
+      if let Some(holder) = self
+        .holders
+        .iter()
+        .find(|holder| holder.task.will_notify_current())
+      {
+        panic!(
+          "A task attempted to acquire a semaphore permit while no permits were available, but \
+           it already holds a permit from the same semaphore, which will deadlock if no other \
+           permits are released. The held permit was acquired at:\n{:?}",
+          holder.backtrace
+        );
+      }
+    }
+  }
+}
+
+#[cfg(not(feature = "deadlock_detection"))]
+mod imp {
+  #[derive(Clone, Copy)]
+  pub struct HolderId;
+
+  pub struct Holders;
+
+  impl Holders {
+    pub fn new() -> Holders {
+      Holders
+    }
+
+    pub fn add(&mut self) -> HolderId {
+      HolderId
+    }
+
+    pub fn untracked() -> HolderId {
+      HolderId
+    }
+
+    pub fn remove(&mut self, _id: HolderId) {}
+
+    pub fn check_reentrant(&self) {}
+  }
+}
+
+pub use self::imp::{HolderId, Holders};
+
+#[cfg(all(test, feature = "deadlock_detection"))]
+mod tests {
+  use crate::AsyncSemaphore;
+  use futures::Future;
+
+  #[test]
+  #[should_panic(expected = "already holds a permit from the same semaphore")]
+  fn reentrant_acquisition_panics() {
+    let sema = AsyncSemaphore::new(1);
+    let handle = sema.clone();
+    sema
+      .with_acquired(move |_slot| handle.acquire().map(|_permit| ()))
+      .wait()
+      .unwrap();
+  }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use futures::future::Future;
+use parking_lot::Mutex;
+
+use crate::{AcquireError, AsyncSemaphore};
+
+struct Entry {
+  semaphore: AsyncSemaphore,
+  // The number of acquisitions (waiting, or holding a permit) using this entry's semaphore.
+  users: usize,
+}
+
+///
+/// Bounds concurrency independently for each of a (possibly unbounded) set of keys, by lazily
+/// creating an AsyncSemaphore per key.
+///
+/// The semaphore for a key is only retained while some acquisition is using it, so keys which are
+/// no longer in use do not accumulate in long-lived processes.
+///
+#[derive(Clone)]
+pub struct KeyedSemaphore<K: Eq + Hash> {
+  permits_per_key: usize,
+  entries: Arc<Mutex<HashMap<K, Entry>>>,
+}
+
+impl<K: Clone + Eq + Hash + Send + 'static> KeyedSemaphore<K> {
+  pub fn new(permits_per_key: usize) -> KeyedSemaphore<K> {
+    KeyedSemaphore {
+      permits_per_key,
+      entries: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  ///
+  /// Runs the given Future-creating function (and the Future it returns) under the semaphore for
+  /// the given key. See `AsyncSemaphore::with_acquired`.
+  ///
+  pub fn with_acquired<F, B, T, E>(
+    &self,
+    key: K,
+    f: F,
+  ) -> Box<dyn Future<Item = T, Error = E> + Send>
+  where
+    F: FnOnce(usize) -> B + Send + 'static,
+    B: Future<Item = T, Error = E> + Send + 'static,
+    T: Send + 'static,
+    E: From<AcquireError> + Send + 'static,
+  {
+    let semaphore = {
+      let mut entries = self.entries.lock();
+      let permits_per_key = self.permits_per_key;
+      let entry = entries.entry(key.clone()).or_insert_with(|| Entry {
+        semaphore: AsyncSemaphore::new(permits_per_key),
+        users: 0,
+      });
+      entry.users += 1;
+      entry.semaphore.clone()
+    };
+    // The guard is dropped (releasing our use of the entry) whether the acquisition completes or
+    // is cancelled.
+    let guard = EntryGuard {
+      key,
+      entries: self.entries.clone(),
+    };
+    Box::new(semaphore.with_acquired(f).then(move |res| {
+      drop(guard);
+      res
+    }))
+  }
+}
+
+struct EntryGuard<K: Eq + Hash> {
+  key: K,
+  entries: Arc<Mutex<HashMap<K, Entry>>>,
+}
+
+impl<K: Eq + Hash> Drop for EntryGuard<K> {
+  fn drop(&mut self) {
+    let mut entries = self.entries.lock();
+    let unused = match entries.get_mut(&self.key) {
+      Some(entry) => {
+        entry.users -= 1;
+        entry.users == 0
+      }
+      None => false,
+    };
+    if unused {
+      entries.remove(&self.key);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::KeyedSemaphore;
+  use futures::sync::oneshot;
+  use futures::{future, Future};
+  use std::sync::mpsc;
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn keys_are_bounded_independently() {
+    let sema = KeyedSemaphore::new(1);
+
+    let (acquired_tx, acquired_a) = mpsc::channel();
+    let (unblock_a, blocked_a) = oneshot::channel::<()>();
+    let handle = sema.clone();
+    let thread_a = thread::spawn(move || {
+      handle
+        .with_acquired("a", move |_slot| {
+          acquired_tx.send(()).unwrap();
+          blocked_a.map_err(|_| "Canceled".to_owned())
+        })
+        .wait()
+        .unwrap();
+    });
+    acquired_a
+      .recv_timeout(Duration::from_secs(5))
+      .expect("Didn't acquire a.");
+
+    // While "a" is held, "b" can still be acquired.
+    sema
+      .with_acquired("b", |_slot| future::ok::<_, String>(()))
+      .wait()
+      .unwrap();
+
+    // But "a" can't be.
+    let (acquired_tx, acquired_a_again) = mpsc::channel();
+    let handle = sema.clone();
+    let thread_a_again = thread::spawn(move || {
+      handle
+        .with_acquired("a", move |_slot| {
+          acquired_tx.send(()).unwrap();
+          future::ok::<_, String>(())
+        })
+        .wait()
+        .unwrap();
+    });
+    match acquired_a_again.recv_timeout(Duration::from_millis(100)) {
+      Err(_) => (),
+      Ok(_) => panic!("a should not have been acquired twice concurrently."),
+    }
+
+    unblock_a.send(()).unwrap();
+    acquired_a_again
+      .recv_timeout(Duration::from_secs(5))
+      .expect("Didn't acquire a again.");
+    thread_a.join().unwrap();
+    thread_a_again.join().unwrap();
+
+    // Having completed, no state should be retained for either key.
+    assert!(sema.entries.lock().is_empty());
+  }
+}
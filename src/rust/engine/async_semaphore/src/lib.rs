@@ -26,17 +26,345 @@
 // Arc<Mutex> can be more clear than needing to grok Orderings:
 #![allow(clippy::mutex_atomic)]
 
-use std::collections::VecDeque;
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use futures::future::Future;
+use futures::future::{self, Either, Future};
 use futures::task::{self, Task};
 use futures::{Async, Poll};
+use futures_timer::Delay;
 use parking_lot::Mutex;
 
+mod deadlock_detection;
+use crate::deadlock_detection::{HolderId, Holders};
+
+mod keyed;
+pub use crate::keyed::KeyedSemaphore;
+
+mod rate_limiter;
+pub use crate::rate_limiter::RateLimiter;
+
+///
+/// The reasons that an acquisition of a semaphore permit might fail.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AcquireError {
+  ///
+  /// No permit became available within the given Duration.
+  ///
+  TimedOut(Duration),
+  ///
+  /// The timer used to bound the acquisition failed.
+  ///
+  Timer(String),
+  ///
+  /// The semaphore was closed: see `AsyncSemaphore::close`.
+  ///
+  Closed,
+  ///
+  /// The cost of the acquisition exceeded the semaphore's entire cost budget, so it could never
+  /// be acquired: see `AsyncSemaphore::set_cost_budget`.
+  ///
+  OverBudget { cost: u64, budget: u64 },
+  ///
+  /// The acquisition was for no permits, which is meaningless.
+  ///
+  NoPermits,
+  ///
+  /// The acquisition was for more permits than the semaphore has, so it could never be acquired:
+  /// see `AsyncSemaphore::acquire_many`.
+  ///
+  TooManyPermits { permits: usize, total: usize },
+}
+
+impl fmt::Display for AcquireError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      AcquireError::TimedOut(timeout) => write!(
+        f,
+        "Timed out after {:?} waiting to acquire a semaphore permit",
+        timeout
+      ),
+      AcquireError::Timer(err) => write!(
+        f,
+        "Timer failed while waiting to acquire a semaphore permit: {}",
+        err
+      ),
+      AcquireError::Closed => write!(f, "The semaphore was closed"),
+      AcquireError::OverBudget { cost, budget } => write!(
+        f,
+        "An acquisition with cost {} exceeded the semaphore's cost budget of {}",
+        cost, budget
+      ),
+      AcquireError::NoPermits => write!(f, "An acquisition must be for at least one permit"),
+      AcquireError::TooManyPermits { permits, total } => write!(
+        f,
+        "An acquisition of {} permits exceeded the semaphore's {} permits",
+        permits, total
+      ),
+    }
+  }
+}
+
+impl From<AcquireError> for String {
+  fn from(err: AcquireError) -> String {
+    err.to_string()
+  }
+}
+
+///
+/// The upper bounds (in milliseconds) of the buckets of WaitTimeHistogram. Acquisitions which
+/// wait for longer than the last bound are counted in a final, unbounded, bucket.
+///
+const WAIT_TIME_BUCKET_BOUNDS_MS: [u64; WAIT_TIME_BUCKETS - 1] = [1, 10, 100, 1_000, 10_000];
+const WAIT_TIME_BUCKETS: usize = 6;
+
+///
+/// A point-in-time snapshot of the usage of an AsyncSemaphore.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SemaphoreMetrics {
+  ///
+  /// The number of acquisitions which are currently waiting for permits.
+  ///
+  pub queue_depth: usize,
+  ///
+  /// The number of permits which are currently held.
+  ///
+  pub permits_in_use: usize,
+  ///
+  /// The sum of the costs of the permits which are currently held.
+  ///
+  pub outstanding_cost: u64,
+  pub wait_times: WaitTimeHistogram,
+}
+
+///
+/// A histogram of how long acquisitions waited before they acquired their permits.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WaitTimeHistogram {
+  ///
+  /// The inclusive upper bound of each bucket (None for the final bucket, which is unbounded)
+  /// paired with the number of acquisitions which waited for longer than the previous bucket's
+  /// bound, but no longer than this one's.
+  ///
+  pub buckets: Vec<(Option<Duration>, u64)>,
+  ///
+  /// The sum of the wait times of all acquisitions.
+  ///
+  pub total_wait_time: Duration,
+}
+
+///
+/// Receives notifications of the acquisition and release of permits, for instrumentation.
+///
+/// Methods are called without any locks held, but on the thread which acquired or released the
+/// permits, so they should return quickly.
+///
+pub trait SemaphoreObserver: Send + Sync {
+  ///
+  /// Called when an acquisition of the given number of permits begins.
+  ///
+  fn acquire_started(&self, _permits: usize, _at: Instant) {}
+
+  ///
+  /// Called when an acquisition which began at `started_at` has acquired its permits.
+  ///
+  fn acquire_completed(&self, _permits: usize, _started_at: Instant, _at: Instant) {}
+
+  ///
+  /// Called when the given number of permits are released.
+  ///
+  fn released(&self, _permits: usize, _at: Instant) {}
+}
+
+///
+/// The order in which waiters with equal priorities are woken.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fairness {
+  ///
+  /// Waiters are woken in the order that they began waiting.
+  ///
+  Fifo,
+  ///
+  /// The most recent waiter is woken first. This can improve latency for bursty workloads (since
+  /// recently suspended work is more likely to be hot in caches), at the cost of potentially
+  /// starving older waiters.
+  ///
+  Lifo,
+}
+
+struct Waiter {
+  id: usize,
+  permits: usize,
+  cost: u64,
+  priority: i32,
+  enqueued_at: Instant,
+  task: Task,
+}
+
 struct Inner {
-  waiters: VecDeque<Task>,
-  available_permits: usize,
+  waiters: VecDeque<Waiter>,
+  fairness: Fairness,
+  total_permits: usize,
+  // Each permit is a slot in `0..total_permits`, which is either free or held. The lowest free
+  // slots are handed out first, so that the same slots are reused as much as possible.
+  free_slots: BTreeSet<usize>,
+  // If the semaphore has been shrunk, some slots which are currently held may be above the new
+  // total: they are retired (rather than freed) when they are released.
+  held_slots: BTreeSet<usize>,
+  next_waiter_id: usize,
+  wait_time_counts: [u64; WAIT_TIME_BUCKETS],
+  total_wait_time: Duration,
+  observer: Option<Arc<dyn SemaphoreObserver>>,
+  holders: Holders,
+  closed: bool,
+  outstanding_cost: u64,
+  cost_budget: Option<u64>,
+  // Incremented by `bump_epoch`: Permits from earlier epochs no longer count against the
+  // semaphore.
+  epoch: u64,
+}
+
+impl Inner {
+  fn record_wait_time(&mut self, waited: Duration) {
+    let bucket = WAIT_TIME_BUCKET_BOUNDS_MS
+      .iter()
+      .position(|&bound_ms| waited <= Duration::from_millis(bound_ms))
+      .unwrap_or(WAIT_TIME_BUCKET_BOUNDS_MS.len());
+    self.wait_time_counts[bucket] += 1;
+    self.total_wait_time += waited;
+  }
+
+  ///
+  /// The position in the queue at which a new waiter with the given priority should be inserted.
+  ///
+  fn queue_position(&self, priority: i32) -> usize {
+    let fairness = self.fairness;
+    self
+      .waiters
+      .iter()
+      .position(|waiter| match fairness {
+        Fairness::Fifo => waiter.priority < priority,
+        Fairness::Lifo => waiter.priority <= priority,
+      })
+      .unwrap_or_else(|| self.waiters.len())
+  }
+
+  fn available_permits(&self) -> usize {
+    self.free_slots.len()
+  }
+
+  ///
+  /// True if the given number of permits, with the given cost, could be acquired now (ignoring
+  /// the queue).
+  ///
+  fn can_acquire(&self, permits: usize, cost: u64) -> bool {
+    let within_budget = match self.cost_budget {
+      Some(budget) => self.outstanding_cost + cost <= budget,
+      None => true,
+    };
+    permits <= self.available_permits() && within_budget
+  }
+
+  fn take_slots(&mut self, permits: usize) -> Vec<usize> {
+    let slots: Vec<usize> = self.free_slots.iter().take(permits).cloned().collect();
+    for slot in &slots {
+      self.free_slots.remove(slot);
+      self.held_slots.insert(*slot);
+    }
+    slots
+  }
+
+  fn release(&mut self, slots: &[usize], cost: u64) {
+    self.outstanding_cost -= cost;
+    for &slot in slots {
+      self.held_slots.remove(&slot);
+      if slot < self.total_permits {
+        self.free_slots.insert(slot);
+      }
+    }
+  }
+
+  ///
+  /// The reason that an acquisition of the given number of permits, with the given cost, could
+  /// never succeed, if it couldn't.
+  ///
+  fn acquire_error(&self, permits: usize, cost: u64) -> Option<AcquireError> {
+    if self.closed {
+      return Some(AcquireError::Closed);
+    }
+    if permits == 0 {
+      return Some(AcquireError::NoPermits);
+    }
+    if permits > self.total_permits {
+      return Some(AcquireError::TooManyPermits {
+        permits,
+        total: self.total_permits,
+      });
+    }
+    match self.cost_budget {
+      Some(budget) if cost > budget => Some(AcquireError::OverBudget { cost, budget }),
+      _ => None,
+    }
+  }
+
+  ///
+  /// Removes the waiter with the given id from the queue (if it is still queued), returning the
+  /// Task of the next waiter if it can acquire now that it is at the front of the queue.
+  ///
+  fn remove_waiter(&mut self, id: usize) -> Option<Task> {
+    let position = self.waiters.iter().position(|waiter| waiter.id == id)?;
+    self.waiters.remove(position);
+    if position == 0 {
+      self.task_to_notify()
+    } else {
+      None
+    }
+  }
+
+  ///
+  /// Removes the waiters which want more permits than the semaphore has, or a higher cost than
+  /// its entire budget, and so could never acquire, returning their Tasks so that they can be
+  /// woken to fail.
+  ///
+  fn remove_unsatisfiable_waiters(&mut self) -> Vec<Task> {
+    let total_permits = self.total_permits;
+    let cost_budget = self.cost_budget;
+    let (unsatisfiable, waiters) = self
+      .waiters
+      .drain(..)
+      .partition::<VecDeque<_>, _>(|waiter| {
+        waiter.permits > total_permits || cost_budget.map_or(false, |budget| waiter.cost > budget)
+      });
+    self.waiters = waiters;
+    unsatisfiable
+      .into_iter()
+      .map(|waiter| waiter.task)
+      .collect()
+  }
+
+  ///
+  /// Returns the Task of the waiter at the front of the queue if enough permits (and budget) are
+  /// now available to satisfy it.
+  ///
+  /// Waiters are only ever satisfied from the front of the queue, so that a waiter for many
+  /// permits is not starved by waiters for fewer (or equal priority) permits which arrive after
+  /// it.
+  ///
+  fn task_to_notify(&self) -> Option<Task> {
+    self.waiters.front().and_then(|waiter| {
+      if self.can_acquire(waiter.permits, waiter.cost) {
+        Some(waiter.task.clone())
+      } else {
+        None
+      }
+    })
+  }
 }
 
 #[derive(Clone)]
@@ -46,30 +374,343 @@ pub struct AsyncSemaphore {
 
 impl AsyncSemaphore {
   pub fn new(permits: usize) -> AsyncSemaphore {
+    Self::new_with_fairness(permits, Fairness::Fifo)
+  }
+
+  pub fn new_with_fairness(permits: usize, fairness: Fairness) -> AsyncSemaphore {
     AsyncSemaphore {
       inner: Arc::new(Mutex::new(Inner {
         waiters: VecDeque::new(),
-        available_permits: permits,
+        fairness,
+        total_permits: permits,
+        free_slots: (0..permits).collect(),
+        held_slots: BTreeSet::new(),
+        next_waiter_id: 0,
+        wait_time_counts: [0; WAIT_TIME_BUCKETS],
+        total_wait_time: Duration::from_secs(0),
+        observer: None,
+        holders: Holders::new(),
+        closed: false,
+        outstanding_cost: 0,
+        cost_budget: None,
+        epoch: 0,
       })),
     }
   }
 
+  ///
+  /// Registers an observer to be notified of acquisitions and releases, replacing any previously
+  /// registered observer.
+  ///
+  pub fn set_observer(&self, observer: Arc<dyn SemaphoreObserver>) {
+    self.inner.lock().observer = Some(observer);
+  }
+
+  ///
+  /// Sets a budget for the total cost of the permits which may be held at once (see
+  /// `acquire_with_cost`), or removes the budget if None.
+  ///
+  /// Acquisitions wait until both a permit and enough of the budget are available. Lowering the
+  /// budget does not affect permits which are already held, but waiting acquisitions with costs
+  /// higher than the new budget fail with AcquireError::OverBudget.
+  ///
+  pub fn set_cost_budget(&self, budget: Option<u64>) {
+    let (failed, task) = {
+      let mut inner = self.inner.lock();
+      inner.cost_budget = budget;
+      (inner.remove_unsatisfiable_waiters(), inner.task_to_notify())
+    };
+    for task in failed {
+      task.notify();
+    }
+    if let Some(task) = task {
+      task.notify();
+    }
+  }
+
+  ///
+  /// Returns a snapshot of the current usage of the semaphore, and of the time that acquisitions
+  /// have spent waiting for it since it was created.
+  ///
+  pub fn metrics(&self) -> SemaphoreMetrics {
+    let inner = self.inner.lock();
+    let bounds = WAIT_TIME_BUCKET_BOUNDS_MS
+      .iter()
+      .map(|&bound_ms| Some(Duration::from_millis(bound_ms)))
+      .chain(Some(None));
+    SemaphoreMetrics {
+      queue_depth: inner.waiters.len(),
+      permits_in_use: inner.held_slots.len(),
+      outstanding_cost: inner.outstanding_cost,
+      wait_times: WaitTimeHistogram {
+        buckets: bounds.zip(inner.wait_time_counts.iter().cloned()).collect(),
+        total_wait_time: inner.total_wait_time,
+      },
+    }
+  }
+
+  ///
+  /// Closes the semaphore: all waiting and future acquisitions fail with AcquireError::Closed.
+  ///
+  /// Permits which are already held are unaffected, and may continue to be used until they are
+  /// dropped.
+  ///
+  pub fn close(&self) {
+    let waiters = {
+      let mut inner = self.inner.lock();
+      inner.closed = true;
+      inner.waiters.drain(..).collect::<Vec<_>>()
+    };
+    for waiter in waiters {
+      waiter.task.notify();
+    }
+  }
+
+  ///
+  /// Begins a new epoch, in which all permits are available again.
+  ///
+  /// Permits acquired in earlier epochs may still be held, but no longer count against the
+  /// semaphore: dropping them has no effect. Since their slots may be handed out again, callers
+  /// should ensure that whatever work was using them has been abandoned. Waiting acquisitions are
+  /// unaffected, and will acquire in the new epoch.
+  ///
+  pub fn bump_epoch(&self) {
+    let task = {
+      let mut inner = self.inner.lock();
+      inner.epoch += 1;
+      inner.free_slots = (0..inner.total_permits).collect();
+      inner.held_slots.clear();
+      inner.outstanding_cost = 0;
+      inner.holders = Holders::new();
+      inner.task_to_notify()
+    };
+    if let Some(task) = task {
+      task.notify();
+    }
+  }
+
+  ///
+  /// Changes the total number of permits that the semaphore hands out.
+  ///
+  /// Growing the semaphore makes the new permits available (and wakes waiters) immediately.
+  /// Shrinking it takes effect lazily: permits for slots above the new total which are currently
+  /// held are retired as they are released, rather than being made available again. Waiting
+  /// acquisitions of more permits than the new total fail with AcquireError::TooManyPermits.
+  ///
+  pub fn resize(&self, new_permits: usize) {
+    let (failed, task) = {
+      let mut inner = self.inner.lock();
+      if new_permits >= inner.total_permits {
+        for slot in inner.total_permits..new_permits {
+          if !inner.held_slots.contains(&slot) {
+            inner.free_slots.insert(slot);
+          }
+        }
+      } else {
+        inner.free_slots.split_off(&new_permits);
+      }
+      inner.total_permits = new_permits;
+      (inner.remove_unsatisfiable_waiters(), inner.task_to_notify())
+    };
+    for task in failed {
+      task.notify();
+    }
+    if let Some(task) = task {
+      task.notify();
+    }
+  }
+
+  ///
+  /// Attempts to acquire a Permit without waiting.
+  ///
+  /// Returns None (without registering as a waiter) if no permits are currently available, or if
+  /// other acquisitions are already waiting ahead of it for them, which allows callers to fall
+  /// back to some other strategy rather than queueing.
+  ///
+  pub fn try_acquire(&self) -> Option<Permit> {
+    let (slots, epoch, observer) = {
+      let mut inner = self.inner.lock();
+      if inner.closed || !inner.can_acquire(1, 0) || inner.queue_position(0) != 0 {
+        return None;
+      }
+      let slots = inner.take_slots(1);
+      inner.record_wait_time(Duration::from_secs(0));
+      (slots, inner.epoch, inner.observer.clone())
+    };
+    if let Some(observer) = observer {
+      let now = Instant::now();
+      observer.acquire_started(1, now);
+      observer.acquire_completed(1, now, now);
+    }
+    Some(Permit::new(
+      self.inner.clone(),
+      slots,
+      0,
+      epoch,
+      Holders::untracked(),
+    ))
+  }
+
+  ///
+  /// Returns a Future which acquires a single permit, and holds it until the resulting Permit is
+  /// dropped.
+  ///
+  /// Unlike `with_acquired`, this allows the Permit to be held for as long as the caller needs it:
+  /// for example, across multiple Futures, or stored in a struct.
+  ///
+  pub fn acquire(&self) -> PermitFuture {
+    self.permit_future(1, 0, 0)
+  }
+
+  ///
+  /// Acquires a single permit, parking the current thread until it is available.
+  ///
+  /// This waits in the same queue as asynchronous acquisitions, so it is suitable for synchronous
+  /// code (for example, on a blocking thread pool) which needs to respect the same bound. It
+  /// should never be called from a thread which is responsible for driving Futures.
+  ///
+  pub fn acquire_blocking(&self) -> Result<Permit, AcquireError> {
+    self.acquire().wait()
+  }
+
+  ///
+  /// Returns a Future which acquires the given number of permits at once, and holds them until
+  /// the resulting Permit is dropped.
+  ///
+  /// Acquisitions are satisfied in queue order (by default, the order that they began waiting: see
+  /// `Fairness`): while a request for many permits is waiting at the front of the queue for enough
+  /// of them to be released, requests behind it (even for fewer permits) continue to wait.
+  ///
+  /// Fails with AcquireError::NoPermits if `permits` is zero, and with
+  /// AcquireError::TooManyPermits if it is more than the semaphore has, rather than waiting
+  /// forever (and holding up the queue behind it).
+  ///
+  pub fn acquire_many(&self, permits: usize) -> PermitFuture {
+    self.permit_future(permits, 0, 0)
+  }
+
+  ///
+  /// Returns a Future which acquires a single permit, ahead of any waiters with a lower priority.
+  ///
+  /// Waiters with a higher priority are satisfied first, and waiters with equal priorities are
+  /// satisfied in the order that they began waiting. `acquire_many` and `with_acquired` wait with
+  /// priority 0, so positive priorities can be used for latency sensitive work, and negative
+  /// priorities for background work.
+  ///
+  pub fn acquire_with_priority(&self, priority: i32) -> PermitFuture {
+    self.permit_future(1, priority, 0)
+  }
+
+  ///
+  /// Returns a Future which acquires a single permit tagged with the given cost (in arbitrary
+  /// units: for example, an estimate of memory usage), which counts against the semaphore's cost
+  /// budget while the permit is held.
+  ///
+  /// Fails with AcquireError::OverBudget if the cost exceeds the entire budget.
+  ///
+  pub fn acquire_with_cost(&self, cost: u64) -> PermitFuture {
+    self.permit_future(1, 0, cost)
+  }
+
+  ///
+  /// Returns a Future which acquires a permit from each of the given semaphores, and which
+  /// completes with the Permits in the same order as the semaphores.
+  ///
+  /// To avoid deadlocks between callers which need permits from the same semaphores, the permits
+  /// are always acquired in a canonical order, regardless of the order in which the semaphores
+  /// are given. A semaphore which is given more than once must have at least that many permits.
+  ///
+  pub fn acquire_all(
+    semaphores: &[&AsyncSemaphore],
+  ) -> Box<dyn Future<Item = Vec<Permit>, Error = AcquireError> + Send> {
+    let mut ordered: Vec<(usize, AsyncSemaphore)> =
+      semaphores.iter().cloned().cloned().enumerate().collect();
+    ordered.sort_by_key(|(_, semaphore)| semaphore.address());
+
+    let acquired: Box<dyn Future<Item = Vec<(usize, Permit)>, Error = AcquireError> + Send> =
+      Box::new(future::ok(Vec::with_capacity(ordered.len())));
+    let acquired = ordered
+      .into_iter()
+      .fold(acquired, |acquired, (index, semaphore)| {
+        Box::new(acquired.and_then(move |mut permits| {
+          semaphore.acquire().map(move |permit| {
+            permits.push((index, permit));
+            permits
+          })
+        }))
+      });
+    Box::new(acquired.map(|mut permits| {
+      permits.sort_by_key(|(index, _)| *index);
+      permits.into_iter().map(|(_, permit)| permit).collect()
+    }))
+  }
+
+  fn address(&self) -> usize {
+    &*self.inner as *const Mutex<Inner> as usize
+  }
+
+  fn permit_future(&self, permits: usize, priority: i32, cost: u64) -> PermitFuture {
+    PermitFuture {
+      inner: Some(self.inner.clone()),
+      permits,
+      priority,
+      cost,
+      waiter_id: None,
+      started_at: None,
+    }
+  }
+
   ///
   /// Runs the given Future-creating function (and the Future it returns) under the semaphore.
   ///
+  /// The function is passed the slot of the acquired permit: a value in `0..permits` which no
+  /// other concurrently running function will be passed. If the semaphore is closed before a
+  /// permit is acquired, the function is not run, and AcquireError::Closed is converted into the
+  /// error type of the Future.
+  ///
   pub fn with_acquired<F, B, T, E>(&self, f: F) -> Box<dyn Future<Item = T, Error = E> + Send>
   where
-    F: FnOnce() -> B + Send + 'static,
+    F: FnOnce(usize) -> B + Send + 'static,
     B: Future<Item = T, Error = E> + Send + 'static,
+    E: From<AcquireError> + Send + 'static,
+  {
+    Box::new(self.acquire().map_err(E::from).and_then(|permit| {
+      f(permit.slot()).map(move |t| {
+        drop(permit);
+        t
+      })
+    }))
+  }
+
+  ///
+  /// As with `with_acquired`, but fails with AcquireError::TimedOut (converted into the error type
+  /// of the Future) if a permit cannot be acquired within the given timeout. The timeout only
+  /// bounds the acquisition: once the permit is acquired, the Future returned by `f` runs to
+  /// completion.
+  ///
+  pub fn with_acquired_timeout<F, B, T, E>(
+    &self,
+    timeout: Duration,
+    f: F,
+  ) -> Box<dyn Future<Item = T, Error = E> + Send>
+  where
+    F: FnOnce(usize) -> B + Send + 'static,
+    B: Future<Item = T, Error = E> + Send + 'static,
+    E: From<AcquireError> + Send + 'static,
   {
-    let permit = PermitFuture {
-      inner: Some(self.inner.clone()),
-    };
     Box::new(
-      permit
-        .map_err(|()| panic!("Acquisition is infalliable."))
+      self
+        .acquire()
+        .select2(Delay::new(timeout))
+        .then(move |res| match res {
+          Ok(Either::A((permit, _))) => Ok(permit),
+          Ok(Either::B(((), _))) => Err(AcquireError::TimedOut(timeout)),
+          Err(Either::A((err, _))) => Err(err),
+          Err(Either::B((err, _))) => Err(AcquireError::Timer(err.to_string())),
+        })
+        .map_err(E::from)
         .and_then(|permit| {
-          f().map(move |t| {
+          f(permit.slot()).map(move |t| {
             drop(permit);
             t
           })
@@ -80,45 +721,199 @@ impl AsyncSemaphore {
 
 pub struct Permit {
   inner: Arc<Mutex<Inner>>,
+  slots: Vec<usize>,
+  cost: u64,
+  epoch: u64,
+  holder: HolderId,
+}
+
+impl Permit {
+  ///
+  /// Permits always hold at least one slot (acquisitions of no permits fail with
+  /// AcquireError::NoPermits), so that `slot` is always defined.
+  ///
+  fn new(
+    inner: Arc<Mutex<Inner>>,
+    slots: Vec<usize>,
+    cost: u64,
+    epoch: u64,
+    holder: HolderId,
+  ) -> Permit {
+    assert!(!slots.is_empty(), "A Permit must hold at least one slot");
+    Permit {
+      inner,
+      slots,
+      cost,
+      epoch,
+      holder,
+    }
+  }
+
+  ///
+  /// The slots held by this Permit: one per permit, each in `0..permits`, and each held by no other
+  /// Permit while this one exists.
+  ///
+  pub fn slots(&self) -> &[usize] {
+    &self.slots
+  }
+
+  ///
+  /// The lowest slot held by this Permit.
+  ///
+  pub fn slot(&self) -> usize {
+    self.slots[0]
+  }
+}
+
+impl fmt::Debug for Permit {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Permit")
+      .field("slots", &self.slots)
+      .finish()
+  }
 }
 
 impl Drop for Permit {
   fn drop(&mut self) {
-    let task = {
+    let (task, observer) = {
       let mut inner = self.inner.lock();
-      inner.available_permits += 1;
-      if let Some(task) = inner.waiters.pop_front() {
-        task
-      } else {
+      if inner.epoch != self.epoch {
+        // The semaphore was reset since this Permit was acquired.
         return;
       }
+      inner.release(&self.slots, self.cost);
+      inner.holders.remove(self.holder);
+      (inner.task_to_notify(), inner.observer.clone())
     };
-    task.notify();
+    if let Some(task) = task {
+      task.notify();
+    }
+    if let Some(observer) = observer {
+      observer.released(self.slots.len(), Instant::now());
+    }
   }
 }
 
 pub struct PermitFuture {
   inner: Option<Arc<Mutex<Inner>>>,
+  permits: usize,
+  priority: i32,
+  cost: u64,
+  waiter_id: Option<usize>,
+  started_at: Option<Instant>,
 }
 
 impl Future for PermitFuture {
   type Item = Permit;
-  type Error = ();
+  type Error = AcquireError;
 
-  fn poll(&mut self) -> Poll<Permit, ()> {
+  fn poll(&mut self) -> Poll<Permit, AcquireError> {
     let inner = self.inner.take().expect("cannot poll PermitFuture twice");
-    let acquired = {
+    let failure = {
       let mut inner = inner.lock();
-      if inner.available_permits == 0 {
-        inner.waiters.push_back(task::current());
-        false
+      let waiter_id = self.waiter_id;
+      inner.acquire_error(self.permits, self.cost).map(|err| {
+        // Leave the queue (if we had joined it), so that we don't hold up the waiters behind us:
+        // Drop won't, since we've given up our reference to the semaphore.
+        (err, waiter_id.and_then(|id| inner.remove_waiter(id)))
+      })
+    };
+    if let Some((err, task)) = failure {
+      if let Some(task) = task {
+        task.notify();
+      }
+      return Err(err);
+    }
+    let first_poll = self.started_at.is_none();
+    let started_at = *self.started_at.get_or_insert_with(Instant::now);
+    let (acquired, task_to_notify, observer) = {
+      let mut inner = inner.lock();
+      let observer = inner.observer.clone();
+      if let Some(id) = self.waiter_id {
+        if inner.waiters.iter().all(|waiter| waiter.id != id) {
+          // We were removed from the queue because we could never acquire, but the semaphore
+          // (or its cost budget) has since grown enough for us to: wait again.
+          self.waiter_id = None;
+        }
+      }
+      // Only the waiter at the front of the queue (or a new arrival, if it would be inserted at
+      // the front of the queue) may acquire permits.
+      let at_front = match self.waiter_id {
+        Some(id) => inner
+          .waiters
+          .front()
+          .map(|front| front.id == id)
+          .unwrap_or(false),
+        None => inner.queue_position(self.priority) == 0,
+      };
+      if at_front && inner.can_acquire(self.permits, self.cost) {
+        let slots = inner.take_slots(self.permits);
+        inner.outstanding_cost += self.cost;
+        let holder = inner.holders.add();
+        let epoch = inner.epoch;
+        let waited = if self.waiter_id.is_some() {
+          inner
+            .waiters
+            .pop_front()
+            .map(|waiter| waiter.enqueued_at.elapsed())
+            .unwrap_or_else(|| Duration::from_secs(0))
+        } else {
+          Duration::from_secs(0)
+        };
+        inner.record_wait_time(waited);
+        // The remaining permits might be enough for the next waiter in line.
+        (
+          Some((slots, epoch, holder)),
+          inner.task_to_notify(),
+          observer,
+        )
       } else {
-        inner.available_permits -= 1;
-        true
+        if inner.available_permits() < self.permits {
+          inner.holders.check_reentrant();
+        }
+        match self.waiter_id {
+          Some(id) => {
+            // We may have been moved to a different task since we were last polled.
+            if let Some(waiter) = inner.waiters.iter_mut().find(|waiter| waiter.id == id) {
+              waiter.task = task::current();
+            }
+          }
+          None => {
+            let id = inner.next_waiter_id;
+            inner.next_waiter_id += 1;
+            let position = inner.queue_position(self.priority);
+            inner.waiters.insert(
+              position,
+              Waiter {
+                id,
+                permits: self.permits,
+                cost: self.cost,
+                priority: self.priority,
+                enqueued_at: Instant::now(),
+                task: task::current(),
+              },
+            );
+            self.waiter_id = Some(id);
+          }
+        }
+        (None, None, observer)
       }
     };
-    if acquired {
-      Ok(Async::Ready(Permit { inner }))
+    if let Some(task) = task_to_notify {
+      task.notify();
+    }
+    if let Some(observer) = observer {
+      if first_poll {
+        observer.acquire_started(self.permits, started_at);
+      }
+      if acquired.is_some() {
+        observer.acquire_completed(self.permits, started_at, Instant::now());
+      }
+    }
+    if let Some((slots, epoch, holder)) = acquired {
+      Ok(Async::Ready(Permit::new(
+        inner, slots, self.cost, epoch, holder,
+      )))
     } else {
       self.inner = Some(inner);
       Ok(Async::NotReady)
@@ -126,25 +921,515 @@ impl Future for PermitFuture {
   }
 }
 
+impl Drop for PermitFuture {
+  fn drop(&mut self) {
+    // If we were still waiting, remove ourselves from the queue: otherwise we would hold up the
+    // waiters behind us, and permits would be handed to a Task which will never use them.
+    let (inner, id) = match (self.inner.take(), self.waiter_id) {
+      (Some(inner), Some(id)) => (inner, id),
+      _ => return,
+    };
+    let task = inner.lock().remove_waiter(id);
+    if let Some(task) = task {
+      task.notify();
+    }
+  }
+}
+
 #[cfg(test)]
 mod tests {
 
-  use super::AsyncSemaphore;
-  use futures::{future, Future};
+  use super::{
+    AcquireError, AsyncSemaphore, Fairness, Permit, SemaphoreMetrics, SemaphoreObserver,
+  };
+  use futures::executor::{self, Notify, NotifyHandle, Spawn};
+  use futures::{future, Async, Future, Poll};
+  use parking_lot::Mutex;
   use std::sync::mpsc;
+  use std::sync::Arc;
   use std::thread;
-  use std::time::Duration;
+  use std::time::{Duration, Instant};
 
   #[test]
   fn acquire_and_release() {
     let sema = AsyncSemaphore::new(1);
 
     sema
-      .with_acquired(|| future::ok::<_, ()>(()))
+      .with_acquired(|_slot| future::ok::<_, String>(()))
+      .wait()
+      .unwrap();
+  }
+
+  #[test]
+  fn try_acquire_only_when_available() {
+    let sema = AsyncSemaphore::new(1);
+
+    let permit = sema
+      .try_acquire()
+      .expect("Should acquire when a permit is available.");
+    assert!(sema.try_acquire().is_none());
+
+    drop(permit);
+    assert!(sema.try_acquire().is_some());
+  }
+
+  #[test]
+  fn acquire_timeout() {
+    let sema = AsyncSemaphore::new(1);
+    let permit = sema.try_acquire().unwrap();
+
+    let timeout = Duration::from_millis(10);
+    assert_eq!(
+      Err(String::from(AcquireError::TimedOut(timeout))),
+      sema
+        .with_acquired_timeout(timeout, |_slot| future::ok::<_, String>(()))
+        .wait()
+    );
+
+    drop(permit);
+    sema
+      .with_acquired_timeout(Duration::from_secs(5), |_slot| future::ok::<_, String>(()))
       .wait()
       .unwrap();
   }
 
+  #[test]
+  fn acquire_many_is_not_starved() {
+    let sema = AsyncSemaphore::new(2);
+    let single = sema.try_acquire().unwrap();
+
+    let mut many = executor::spawn(sema.acquire_many(2));
+    assert!(poll(&mut many).unwrap().is_not_ready());
+
+    // A permit is available, but must not be handed out ahead of the queued request.
+    assert!(sema.try_acquire().is_none());
+    let mut later_single = executor::spawn(sema.acquire_many(1));
+    assert!(poll(&mut later_single).unwrap().is_not_ready());
+
+    drop(single);
+    let both = match poll(&mut many).unwrap() {
+      Async::Ready(permit) => permit,
+      Async::NotReady => panic!("Should have acquired both permits once they were released."),
+    };
+    assert!(poll(&mut later_single).unwrap().is_not_ready());
+
+    drop(both);
+    assert!(poll(&mut later_single).unwrap().is_ready());
+  }
+
+  #[test]
+  fn acquire_many_fails_if_it_could_never_acquire() {
+    let sema = AsyncSemaphore::new(2);
+    assert_eq!(
+      Err(AcquireError::TooManyPermits {
+        permits: 3,
+        total: 2
+      }),
+      sema.acquire_many(3).wait().map(|_| ())
+    );
+    assert_eq!(
+      Err(AcquireError::NoPermits),
+      sema.acquire_many(0).wait().map(|_| ())
+    );
+
+    // Nothing was left queued to hold up later acquisitions.
+    assert_eq!(0, sema.metrics().queue_depth);
+    assert!(sema.acquire_many(2).wait().is_ok());
+  }
+
+  #[test]
+  fn higher_priority_jumps_the_queue() {
+    let sema = AsyncSemaphore::new(1);
+    let held = sema.try_acquire().unwrap();
+
+    let mut background = executor::spawn(sema.acquire_with_priority(-1));
+    let mut normal = executor::spawn(sema.acquire_many(1));
+    let mut urgent = executor::spawn(sema.acquire_with_priority(1));
+    assert!(poll(&mut background).unwrap().is_not_ready());
+    assert!(poll(&mut normal).unwrap().is_not_ready());
+    assert!(poll(&mut urgent).unwrap().is_not_ready());
+
+    drop(held);
+    assert!(poll(&mut background).unwrap().is_not_ready());
+    assert!(poll(&mut normal).unwrap().is_not_ready());
+    let urgent_permit = match poll(&mut urgent).unwrap() {
+      Async::Ready(permit) => permit,
+      Async::NotReady => panic!("The highest priority waiter should have acquired first."),
+    };
+
+    drop(urgent_permit);
+    assert!(poll(&mut background).unwrap().is_not_ready());
+    assert!(poll(&mut normal).unwrap().is_ready());
+    assert!(poll(&mut background).unwrap().is_ready());
+  }
+
+  #[test]
+  fn dropped_waiters_are_removed() {
+    let sema = AsyncSemaphore::new(1);
+    let held = sema.try_acquire().unwrap();
+
+    for _ in 0..1000 {
+      let mut waiting = executor::spawn(sema.acquire_many(1));
+      assert!(poll(&mut waiting).unwrap().is_not_ready());
+    }
+    assert_eq!(0, sema.inner.lock().waiters.len());
+
+    // A live waiter queued behind dropped waiters should still be woken promptly.
+    let mut abandoned: Vec<_> = (0..1000)
+      .map(|_| executor::spawn(sema.acquire_many(1)))
+      .collect();
+    for waiting in &mut abandoned {
+      assert!(poll(waiting).unwrap().is_not_ready());
+    }
+    let (tx, acquired) = mpsc::channel();
+    let handle = sema.clone();
+    thread::spawn(move || {
+      let _permit = handle.acquire_many(1).wait().unwrap();
+      tx.send(()).unwrap();
+    });
+    drop(abandoned);
+    drop(held);
+    acquired
+      .recv_timeout(Duration::from_secs(5))
+      .expect("Waiter behind dropped waiters didn't acquire.");
+  }
+
+  #[test]
+  fn metrics() {
+    let sema = AsyncSemaphore::new(2);
+    let held = sema.try_acquire().unwrap();
+
+    let mut waiting = executor::spawn(sema.acquire_many(2));
+    assert!(poll(&mut waiting).unwrap().is_not_ready());
+
+    let metrics = sema.metrics();
+    assert_eq!(1, metrics.queue_depth);
+    assert_eq!(1, metrics.permits_in_use);
+    assert_eq!(1, acquisitions(&metrics));
+
+    drop(held);
+    let _both = match poll(&mut waiting).unwrap() {
+      Async::Ready(permit) => permit,
+      Async::NotReady => panic!("Should have acquired both permits once they were released."),
+    };
+
+    let metrics = sema.metrics();
+    assert_eq!(0, metrics.queue_depth);
+    assert_eq!(2, metrics.permits_in_use);
+    assert_eq!(6, metrics.wait_times.buckets.len());
+    assert_eq!(None, metrics.wait_times.buckets[5].0);
+    assert_eq!(2, acquisitions(&metrics));
+  }
+
+  fn acquisitions(metrics: &SemaphoreMetrics) -> u64 {
+    metrics
+      .wait_times
+      .buckets
+      .iter()
+      .map(|&(_, count)| count)
+      .sum()
+  }
+
+  #[test]
+  fn slots_are_distinct_and_reused() {
+    let sema = AsyncSemaphore::new(3);
+    let first = sema.try_acquire().unwrap();
+    let rest = sema.acquire_many(2).wait().unwrap();
+    assert_eq!(0, first.slot());
+    assert_eq!(vec![1, 2], rest.slots());
+
+    drop(first);
+    let slot = sema
+      .with_acquired(|slot| future::ok::<_, String>(Some(slot)))
+      .wait()
+      .unwrap();
+    assert_eq!(Some(0), slot);
+  }
+
+  #[test]
+  fn lifo_wakes_the_most_recent_waiter() {
+    let sema = AsyncSemaphore::new_with_fairness(1, Fairness::Lifo);
+    let held = sema.try_acquire().unwrap();
+
+    let mut older = executor::spawn(sema.acquire_many(1));
+    let mut newer = executor::spawn(sema.acquire_many(1));
+    assert!(poll(&mut older).unwrap().is_not_ready());
+    assert!(poll(&mut newer).unwrap().is_not_ready());
+
+    drop(held);
+    assert!(poll(&mut older).unwrap().is_not_ready());
+    assert!(poll(&mut newer).unwrap().is_ready());
+    assert!(poll(&mut older).unwrap().is_ready());
+  }
+
+  #[test]
+  fn acquired_permit_can_be_moved() {
+    struct Holder {
+      _permit: Permit,
+    }
+
+    let sema = AsyncSemaphore::new(1);
+    let holder = Holder {
+      _permit: sema.acquire().wait().unwrap(),
+    };
+    assert!(sema.try_acquire().is_none());
+
+    thread::spawn(move || drop(holder)).join().unwrap();
+    assert!(sema.try_acquire().is_some());
+  }
+
+  #[test]
+  fn acquire_all_in_any_order() {
+    let first = AsyncSemaphore::new(1);
+    let second = AsyncSemaphore::new(1);
+
+    // Callers which would deadlock if they acquired in the order that they list the semaphores.
+    let (done_tx, done) = mpsc::channel();
+    for &reversed in &[false, true] {
+      let (first, second) = (first.clone(), second.clone());
+      let done_tx = done_tx.clone();
+      thread::spawn(move || {
+        for _ in 0..1000 {
+          let semaphores = if reversed {
+            [&second, &first]
+          } else {
+            [&first, &second]
+          };
+          let permits = AsyncSemaphore::acquire_all(&semaphores).wait().unwrap();
+          assert_eq!(2, permits.len());
+        }
+        done_tx.send(()).unwrap();
+      });
+    }
+
+    for _ in 0..2 {
+      done
+        .recv_timeout(Duration::from_secs(10))
+        .expect("Acquisitions deadlocked.");
+    }
+    assert!(first.try_acquire().is_some());
+    assert!(second.try_acquire().is_some());
+  }
+
+  #[derive(Default)]
+  struct RecordingObserver {
+    events: Mutex<Vec<(&'static str, usize)>>,
+  }
+
+  impl SemaphoreObserver for RecordingObserver {
+    fn acquire_started(&self, permits: usize, _at: Instant) {
+      self.events.lock().push(("started", permits));
+    }
+
+    fn acquire_completed(&self, permits: usize, started_at: Instant, at: Instant) {
+      assert!(started_at <= at);
+      self.events.lock().push(("completed", permits));
+    }
+
+    fn released(&self, permits: usize, _at: Instant) {
+      self.events.lock().push(("released", permits));
+    }
+  }
+
+  #[test]
+  fn observer() {
+    let sema = AsyncSemaphore::new(2);
+    let observer = Arc::new(RecordingObserver::default());
+    sema.set_observer(observer.clone());
+
+    let single = sema.try_acquire().unwrap();
+    let mut many = executor::spawn(sema.acquire_many(2));
+    assert!(poll(&mut many).unwrap().is_not_ready());
+    drop(single);
+    assert!(poll(&mut many).unwrap().is_ready());
+
+    assert_eq!(
+      vec![
+        ("started", 1),
+        ("completed", 1),
+        ("started", 2),
+        ("released", 1),
+        ("completed", 2),
+        ("released", 2),
+      ],
+      *observer.events.lock()
+    );
+  }
+
+  #[test]
+  fn acquire_blocking_waits_behind_async_waiters() {
+    let sema = AsyncSemaphore::new(1);
+    let held = sema.try_acquire().unwrap();
+    let mut queued = executor::spawn(sema.acquire());
+    assert!(poll(&mut queued).unwrap().is_not_ready());
+
+    let (acquired_tx, acquired) = mpsc::channel();
+    let handle = sema.clone();
+    thread::spawn(move || {
+      let _permit = handle.acquire_blocking().unwrap();
+      acquired_tx.send(()).unwrap();
+    });
+
+    // Neither the release of the held permit nor the queued async waiter should be skipped.
+    match acquired.recv_timeout(Duration::from_millis(100)) {
+      Err(_) => (),
+      Ok(_) => panic!("Should not have acquired while the permit was held."),
+    }
+    drop(held);
+    match acquired.recv_timeout(Duration::from_millis(100)) {
+      Err(_) => (),
+      Ok(_) => panic!("Should not have acquired ahead of the queued waiter."),
+    }
+    assert!(poll(&mut queued).unwrap().is_ready());
+    acquired
+      .recv_timeout(Duration::from_secs(5))
+      .expect("Blocking acquisition didn't acquire.");
+  }
+
+  #[test]
+  fn close_fails_waiting_and_future_acquisitions() {
+    let sema = AsyncSemaphore::new(1);
+    let held = sema.try_acquire().unwrap();
+    let mut waiting = executor::spawn(sema.acquire());
+    assert!(poll(&mut waiting).unwrap().is_not_ready());
+
+    sema.close();
+    assert_eq!(Err(AcquireError::Closed), poll(&mut waiting).map(|_| ()));
+    assert!(sema.try_acquire().is_none());
+    assert_eq!(
+      Err(String::from(AcquireError::Closed)),
+      sema
+        .with_acquired(|_slot| future::ok::<_, String>(()))
+        .wait()
+    );
+
+    // Releasing a permit after closing is harmless.
+    drop(held);
+    assert!(sema.try_acquire().is_none());
+  }
+
+  #[test]
+  fn cost_budget() {
+    let sema = AsyncSemaphore::new(3);
+    sema.set_cost_budget(Some(10));
+
+    let expensive = sema.acquire_with_cost(6).wait().unwrap();
+    let mut waiting = executor::spawn(sema.acquire_with_cost(6));
+    assert!(poll(&mut waiting).unwrap().is_not_ready());
+    assert_eq!(6, sema.metrics().outstanding_cost);
+
+    // Too expensive to ever be acquired.
+    assert_eq!(
+      Err(AcquireError::OverBudget {
+        cost: 11,
+        budget: 10
+      }),
+      sema.acquire_with_cost(11).wait().map(|_| ())
+    );
+
+    drop(expensive);
+    assert!(poll(&mut waiting).unwrap().is_ready());
+    assert_eq!(0, sema.metrics().outstanding_cost);
+  }
+
+  #[test]
+  fn lowering_the_cost_budget_fails_waiters_which_no_longer_fit() {
+    let sema = AsyncSemaphore::new(3);
+    sema.set_cost_budget(Some(10));
+    let held = sema.acquire_with_cost(4).wait().unwrap();
+
+    let mut expensive = executor::spawn(sema.acquire_with_cost(8));
+    let mut cheap = executor::spawn(sema.acquire_with_cost(2));
+    assert!(poll(&mut expensive).unwrap().is_not_ready());
+    assert!(poll(&mut cheap).unwrap().is_not_ready());
+
+    sema.set_cost_budget(Some(6));
+    assert_eq!(
+      Err(AcquireError::OverBudget { cost: 8, budget: 6 }),
+      poll(&mut expensive).map(|_| ())
+    );
+    // The waiter behind the failed one is no longer held up.
+    assert!(poll(&mut cheap).unwrap().is_ready());
+    assert_eq!(0, sema.metrics().queue_depth);
+    drop(held);
+  }
+
+  #[test]
+  fn bump_epoch_invalidates_held_permits() {
+    let sema = AsyncSemaphore::new(1);
+    let stale = sema.try_acquire().unwrap();
+    let mut waiting = executor::spawn(sema.acquire());
+    assert!(poll(&mut waiting).unwrap().is_not_ready());
+
+    sema.bump_epoch();
+    let current = match poll(&mut waiting).unwrap() {
+      Async::Ready(permit) => permit,
+      Async::NotReady => panic!("Should have acquired in the new epoch."),
+    };
+
+    // Dropping the stale permit must not make a second permit available.
+    drop(stale);
+    assert!(sema.try_acquire().is_none());
+
+    drop(current);
+    assert!(sema.try_acquire().is_some());
+  }
+
+  #[test]
+  fn growing_wakes_waiters() {
+    let sema = AsyncSemaphore::new(1);
+    let _held = sema.try_acquire().unwrap();
+
+    let mut waiting = executor::spawn(sema.acquire_many(1));
+    assert!(poll(&mut waiting).unwrap().is_not_ready());
+
+    sema.resize(2);
+    assert!(poll(&mut waiting).unwrap().is_ready());
+  }
+
+  #[test]
+  fn shrinking_retires_released_permits() {
+    let sema = AsyncSemaphore::new(3);
+    let first = sema.try_acquire().unwrap();
+    let second = sema.try_acquire().unwrap();
+
+    assert_eq!(vec![0], first.slots());
+    assert_eq!(vec![1], second.slots());
+
+    // The free slot is retired immediately, and the second slot is retired when it is released.
+    sema.resize(1);
+    assert!(sema.try_acquire().is_none());
+
+    drop(second);
+    assert!(sema.try_acquire().is_none());
+
+    drop(first);
+    assert_eq!(0, sema.try_acquire().unwrap().slot());
+  }
+
+  #[test]
+  fn shrinking_fails_waiters_which_could_never_acquire() {
+    let sema = AsyncSemaphore::new(3);
+    let held = sema.try_acquire().unwrap();
+
+    let mut many = executor::spawn(sema.acquire_many(3));
+    let mut single = executor::spawn(sema.acquire());
+    assert!(poll(&mut many).unwrap().is_not_ready());
+    assert!(poll(&mut single).unwrap().is_not_ready());
+
+    sema.resize(2);
+    assert_eq!(
+      Err(AcquireError::TooManyPermits {
+        permits: 3,
+        total: 2
+      }),
+      poll(&mut many).map(|_| ())
+    );
+    // The waiter behind the failed one is no longer held up.
+    assert!(poll(&mut single).unwrap().is_ready());
+    drop(held);
+  }
+
   #[test]
   fn at_most_n_acquisitions() {
     let sema = AsyncSemaphore::new(1);
@@ -157,11 +1442,11 @@ mod tests {
 
     thread::spawn(move || {
       handle1
-        .with_acquired(move || {
+        .with_acquired(move |_slot| {
           // Indicate that we've acquired, and then wait to be signaled to exit.
           tx_thread1.send(()).unwrap();
           rx_thread1.recv().unwrap();
-          future::ok::<_, ()>(())
+          future::ok::<_, String>(())
         })
         .wait()
         .unwrap();
@@ -174,9 +1459,9 @@ mod tests {
 
     thread::spawn(move || {
       handle2
-        .with_acquired(move || {
+        .with_acquired(move |_slot| {
           tx_thread2.send(()).unwrap();
-          future::ok::<_, ()>(())
+          future::ok::<_, String>(())
         })
         .wait()
         .unwrap();
@@ -194,4 +1479,17 @@ mod tests {
       .recv_timeout(Duration::from_secs(5))
       .expect("thread2 didn't acquire.");
   }
+
+  struct NoopNotify;
+
+  impl Notify for NoopNotify {
+    fn notify(&self, _id: usize) {}
+  }
+
+  ///
+  /// Polls the given Future once, on the current thread.
+  ///
+  fn poll<F: Future>(spawn: &mut Spawn<F>) -> Poll<F::Item, F::Error> {
+    spawn.poll_future_notify(&NotifyHandle::from(Arc::new(NoopNotify)), 0)
+  }
 }
@@ -0,0 +1,104 @@
+use std::cmp;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Future};
+use futures_timer::Delay;
+use parking_lot::Mutex;
+
+use crate::AcquireError;
+
+struct Inner {
+  // The earliest time at which the next operation may start, ignoring any burst allowance.
+  next_start: Option<Instant>,
+}
+
+///
+/// Limits operations to a rate (`operations` per `per`), while allowing bursts of up to `burst`
+/// operations to start immediately after a sufficiently idle period.
+///
+/// Unlike AsyncSemaphore, a RateLimiter bounds how often operations start, rather than how many
+/// run concurrently.
+///
+#[derive(Clone)]
+pub struct RateLimiter {
+  interval: Duration,
+  burst_allowance: Duration,
+  inner: Arc<Mutex<Inner>>,
+}
+
+impl RateLimiter {
+  pub fn new(operations: u32, per: Duration, burst: u32) -> RateLimiter {
+    let interval = per / cmp::max(operations, 1);
+    RateLimiter {
+      interval,
+      burst_allowance: interval * burst.saturating_sub(1),
+      inner: Arc::new(Mutex::new(Inner { next_start: None })),
+    }
+  }
+
+  ///
+  /// Returns a Future which completes when an operation may start.
+  ///
+  /// The start time for the operation is reserved when this method is called, rather than when the
+  /// Future is polled: if the Future is dropped without completing, the reservation is not reused.
+  ///
+  pub fn acquire(&self) -> Box<dyn Future<Item = (), Error = AcquireError> + Send> {
+    let now = Instant::now();
+    let start = {
+      let mut inner = self.inner.lock();
+      let earliest = now.checked_sub(self.burst_allowance).unwrap_or(now);
+      let start = match inner.next_start {
+        Some(next_start) if next_start > earliest => next_start,
+        _ => earliest,
+      };
+      inner.next_start = Some(start + self.interval);
+      start
+    };
+    if start <= now {
+      Box::new(future::ok(()))
+    } else {
+      Box::new(Delay::new(start - now).map_err(|err| AcquireError::Timer(err.to_string())))
+    }
+  }
+
+  ///
+  /// Runs the given Future-creating function (and the Future it returns) once the rate limit
+  /// allows it to start.
+  ///
+  pub fn with_acquired<F, B, T, E>(&self, f: F) -> Box<dyn Future<Item = T, Error = E> + Send>
+  where
+    F: FnOnce() -> B + Send + 'static,
+    B: Future<Item = T, Error = E> + Send + 'static,
+    E: From<AcquireError> + Send + 'static,
+  {
+    Box::new(self.acquire().map_err(E::from).and_then(|()| f()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::RateLimiter;
+  use futures::{future, Future};
+  use std::time::{Duration, Instant};
+
+  #[test]
+  fn bursts_then_limits() {
+    let limiter = RateLimiter::new(10, Duration::from_secs(1), 2);
+
+    let start = Instant::now();
+    for _ in 0..2 {
+      limiter
+        .with_acquired(|| future::ok::<_, String>(()))
+        .wait()
+        .unwrap();
+    }
+    assert!(start.elapsed() < Duration::from_millis(50));
+
+    limiter
+      .with_acquired(|| future::ok::<_, String>(()))
+      .wait()
+      .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(50));
+  }
+}
@@ -31,9 +31,12 @@
 // This avoids needing to call Box::new() around every future that we produce.
 
 use futures::future::Future;
+use futures::stream::Stream;
 
 pub type BoxFuture<T, E> = Box<dyn Future<Item = T, Error = E> + Send>;
 
+pub type BoxStream<T, E> = Box<dyn Stream<Item = T, Error = E> + Send>;
+
 pub trait Boxable<T, E> {
   fn to_boxed(self) -> BoxFuture<T, E>;
 }
@@ -48,7 +48,7 @@ use parking_lot::Mutex;
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::collections::HashMap;
 use std::ffi::{CString, OsStr, OsString};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 const TTL: time::Timespec = time::Timespec { sec: 0, nsec: 0 };
@@ -648,11 +648,44 @@ fn main() {
           .takes_value(true)
           .long("root-ca-cert-file")
           .required(false)
+    ).arg(
+      clap::Arg::with_name("client-cert-file")
+          .help("Path to file containing a certificate chain with which to authenticate to the remote, if it requires mutual TLS.")
+          .takes_value(true)
+          .long("client-cert-file")
+          .required(false)
+          .requires("client-key-file")
+    ).arg(
+      clap::Arg::with_name("client-key-file")
+          .help("Path to file containing the private key of --client-cert-file.")
+          .takes_value(true)
+          .long("client-key-file")
+          .required(false)
+          .requires("client-cert-file")
+    ).arg(
+      clap::Arg::with_name("proxy")
+          .help("The HTTP proxy through which to connect to remote servers, as [http://][user:password@]host[:port]. If not set, the proxy (if any) is taken from $https_proxy or $http_proxy.")
+          .takes_value(true)
+          .long("proxy")
+          .required(false)
+    ).arg(
+      clap::Arg::with_name("keepalive-interval-seconds")
+          .help("How often to ping the remote servers (in seconds) to detect connections which have silently died, which the servers must permit. 0 disables keepalive pings.")
+          .takes_value(true)
+          .long("keepalive-interval-seconds")
+          .required(false)
+          .default_value("0")
     ).arg(clap::Arg::with_name("oauth-bearer-token-file")
         .help("Path to file containing oauth bearer token. If not set, no authorization will be provided to remote servers.")
         .takes_value(true)
         .long("oauth-bearer-token-file")
         .required(false)
+  ).arg(clap::Arg::with_name("compression")
+        .help("How to compress messages to the CAS server, which must support the chosen algorithm.")
+        .takes_value(true)
+        .long("compression")
+        .possible_values(&["none", "deflate", "gzip"])
+        .default_value("none")
   ).arg(
       clap::Arg::with_name("mount-path")
         .required(true)
@@ -680,8 +713,32 @@ fn main() {
     None
   };
 
+  let client_certificate = match (
+    args.value_of("client-cert-file"),
+    args.value_of("client-key-file"),
+  ) {
+    (Some(cert_path), Some(key_path)) => Some(fs::ClientCertificate {
+      cert_chain: std::fs::read(cert_path).expect("Error reading client cert file"),
+      private_key: std::fs::read(key_path).expect("Error reading client key file"),
+    }),
+    _ => None,
+  };
+
+  let proxy = fs::Proxy::given_or_from_env(args.value_of("proxy")).expect("Invalid proxy");
+  let channel_policy = fs::ChannelPolicy::with_keepalive_interval_secs(
+    args
+      .value_of("keepalive-interval-seconds")
+      .unwrap()
+      .parse::<u64>()
+      .expect("Bad keepalive-interval-seconds flag"),
+  )
+  .expect("Invalid keepalive interval");
+
   let oauth_bearer_token = if let Some(path) = args.value_of("oauth-bearer-token-file") {
-    Some(std::fs::read_to_string(path).expect("Error reading oauth bearer token file"))
+    Some(
+      fs::BearerToken::new(fs::TokenSource::File(PathBuf::from(path)))
+        .expect("Error reading oauth bearer token file"),
+    )
   } else {
     None
   };
@@ -692,10 +749,21 @@ fn main() {
       &[address.to_owned()],
       args.value_of("remote-instance-name").map(str::to_owned),
       &root_ca_certs,
+      &client_certificate,
+      &proxy,
+      channel_policy,
       oauth_bearer_token,
+      None,
+      args
+        .value_of("compression")
+        .unwrap()
+        .parse::<fs::Compression>()
+        .expect("Bad compression flag"),
       1,
       4 * 1024 * 1024,
       std::time::Duration::from_secs(5 * 60),
+      std::time::Duration::from_secs(5 * 60),
+      std::time::Duration::from_secs(5 * 60),
       // TODO: Take a command line arg.
       serverset::BackoffConfig::new(
         std::time::Duration::from_secs(1),
@@ -703,6 +771,7 @@ fn main() {
         std::time::Duration::from_secs(20),
       )
       .expect("Error making BackoffConfig"),
+      fs::LoadBalancing::RoundRobin,
       1,
       futures_timer::TimerHandle::default(),
     ),
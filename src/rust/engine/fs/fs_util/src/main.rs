@@ -187,6 +187,15 @@ to this directory.",
                     .required(true),
               )
         )
+        .subcommand(
+          SubCommand::with_name("fsck")
+              .about("Check that every file and directory in the on-disk store has the digest that it is stored as, and that directories are valid and canonical, printing those which are broken.")
+              .arg(
+                Arg::with_name("delete")
+                    .long("delete")
+                    .help("Delete the broken entries, so that they are re-fetched from the CAS (if there is one) when they are next loaded.")
+              )
+        )
       .arg(
         Arg::with_name("local-store-path")
           .takes_value(true)
@@ -208,6 +217,29 @@ to this directory.",
               .long("root-ca-cert-file")
               .required(false)
         )
+        .arg(
+          Arg::with_name("client-cert-file")
+              .help("Path to file containing a certificate chain with which to authenticate to the remote, if it requires mutual TLS.")
+              .takes_value(true)
+              .long("client-cert-file")
+              .required(false)
+              .requires("client-key-file")
+        )
+        .arg(
+          Arg::with_name("client-key-file")
+              .help("Path to file containing the private key of --client-cert-file.")
+              .takes_value(true)
+              .long("client-key-file")
+              .required(false)
+              .requires("client-cert-file")
+        )
+        .arg(
+          Arg::with_name("proxy")
+              .help("The HTTP proxy through which to connect to remote servers, as [http://][user:password@]host[:port]. If not set, the proxy (if any) is taken from $https_proxy or $http_proxy.")
+              .takes_value(true)
+              .long("proxy")
+              .required(false)
+        )
         .arg(
           Arg::with_name("oauth-bearer-token-file")
               .help("Path to file containing oauth bearer token. If not set, no authorization will be provided to remote servers.")
@@ -215,6 +247,22 @@ to this directory.",
               .long("oauth-bearer-token-file")
               .required(false)
         )
+        .arg(
+          Arg::with_name("load-balancing")
+              .help("How to spread requests across multiple --server-address values: across all of the healthy servers in turn, or to the first healthy server in the order given.")
+              .takes_value(true)
+              .long("load-balancing")
+              .possible_values(&["round-robin", "pick-first"])
+              .default_value("round-robin")
+        )
+        .arg(
+          Arg::with_name("compression")
+              .help("How to compress messages to the CAS server, which must support the chosen algorithm.")
+              .takes_value(true)
+              .long("compression")
+              .possible_values(&["none", "deflate", "gzip"])
+              .default_value("none")
+        )
         .arg(Arg::with_name("remote-instance-name")
             .takes_value(true)
                  .long("remote-instance-name")
@@ -242,6 +290,14 @@ to this directory.",
               .required(false)
               .default_value("3")
         )
+        .arg(
+          Arg::with_name("keepalive-interval-seconds")
+              .help("How often to ping the remote servers (in seconds) to detect connections which have silently died, which the servers must permit. 0 disables keepalive pings.")
+              .takes_value(true)
+              .long("keepalive-interval-seconds")
+              .required(false)
+              .default_value("0")
+        )
       .get_matches(),
   ) {
     Ok(_) => {}
@@ -273,14 +329,32 @@ fn execute(top_match: &clap::ArgMatches<'_>) -> Result<(), ExitError> {
           None
         };
 
-        let oauth_bearer_token =
-          if let Some(path) = top_match.value_of("oauth-bearer-token-file") {
-            Some(std::fs::read_to_string(path).map_err(|err| {
-              format!("Error reading oauth bearer token from {:?}: {}", path, err)
-            })?)
-          } else {
-            None
-          };
+        let client_certificate = match (
+          top_match.value_of("client-cert-file"),
+          top_match.value_of("client-key-file"),
+        ) {
+          (Some(cert_path), Some(key_path)) => Some(fs::ClientCertificate {
+            cert_chain: std::fs::read(cert_path)
+              .map_err(|err| format!("Error reading client cert file {}: {}", cert_path, err))?,
+            private_key: std::fs::read(key_path)
+              .map_err(|err| format!("Error reading client key file {}: {}", key_path, err))?,
+          }),
+          _ => None,
+        };
+
+        let proxy = fs::Proxy::given_or_from_env(top_match.value_of("proxy"))?;
+        let channel_policy = fs::ChannelPolicy::with_keepalive_interval_secs(
+          value_t!(top_match.value_of("keepalive-interval-seconds"), u64)
+            .expect("Bad keepalive-interval-seconds flag"),
+        )?;
+
+        let oauth_bearer_token = if let Some(path) = top_match.value_of("oauth-bearer-token-file") {
+          Some(fs::BearerToken::new(fs::TokenSource::File(PathBuf::from(
+            path,
+          )))?)
+        } else {
+          None
+        };
 
         // Randomize CAS address order to avoid thundering herds from common config.
         let mut cas_addresses = cas_address.map(str::to_owned).collect::<Vec<_>>();
@@ -294,24 +368,34 @@ fn execute(top_match: &clap::ArgMatches<'_>) -> Result<(), ExitError> {
               .value_of("remote-instance-name")
               .map(str::to_owned),
             &root_ca_certs,
+            &client_certificate,
+            &proxy,
+            channel_policy,
             oauth_bearer_token,
+            None,
+            value_t!(top_match.value_of("compression"), fs::Compression)
+              .expect("Bad compression flag"),
             value_t!(top_match.value_of("thread-count"), usize).expect("Invalid thread count"),
             chunk_size,
-            // This deadline is really only in place because otherwise DNS failures
+            // These deadlines are really only in place because otherwise DNS failures
             // leave this hanging forever.
             //
-            // Make fs_util have a very long deadline (because it's not configurable,
-            // like it is inside pants) until we switch to Tower (where we can more
+            // Make fs_util have very long deadlines (because they're not configurable,
+            // like they are inside pants) until we switch to Tower (where we can more
             // carefully control specific components of timeouts).
             //
             // See https://github.com/pantsbuild/pants/pull/6433 for more context.
             Duration::from_secs(30 * 60),
+            Duration::from_secs(30 * 60),
+            Duration::from_secs(30 * 60),
             // TODO: Take a command line arg.
             fs::BackoffConfig::new(
               std::time::Duration::from_secs(1),
               1.2,
               std::time::Duration::from_secs(20),
             )?,
+            value_t!(top_match.value_of("load-balancing"), fs::LoadBalancing)
+              .expect("Bad load-balancing flag"),
             value_t!(top_match.value_of("rpc-attempts"), usize).expect("Bad rpc-attempts flag"),
             futures_timer::TimerHandle::default(),
           ),
@@ -530,11 +614,29 @@ fn execute(top_match: &clap::ArgMatches<'_>) -> Result<(), ExitError> {
       }
     }
     ("gc", Some(args)) => {
-      let target_size_bytes = value_t!(args.value_of("target-size-bytes"), usize)
-        .expect("--target-size-bytes must be passed as a non-negative integer");
+      let target_size_bytes = fs::parse_size_bytes(args.value_of("target-size-bytes").unwrap())
+        .map_err(|err| format!("Invalid --target-size-bytes: {}", err))?;
       store.garbage_collect(target_size_bytes, fs::ShrinkBehavior::Compact)?;
       Ok(())
     }
+    ("fsck", Some(args)) => {
+      let delete = args.is_present("delete");
+      let broken_entries = store.fsck(!delete)?;
+      for broken_entry in &broken_entries {
+        println!("{}", broken_entry);
+      }
+      if broken_entries.is_empty() || delete {
+        Ok(())
+      } else {
+        Err(ExitError(
+          format!(
+            "Found {} broken entries (which --delete deletes)",
+            broken_entries.len()
+          ),
+          ExitCode::UnknownError,
+        ))
+      }
+    }
 
     (_, _) => unimplemented!(),
   }
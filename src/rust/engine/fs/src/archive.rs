@@ -0,0 +1,268 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::thread;
+
+use boxfuture::{BoxFuture, BoxStream, Boxable};
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Sink, Stream};
+use hashing::{Digest, Fingerprint};
+use tempfile::NamedTempFile;
+
+use crate::store::EntryType;
+
+// Entries are passed to (and from) the thread which writes (or reads) an archive through a channel
+// of this many entries, so that at most this many are buffered in memory.
+const CHANNEL_CAPACITY: usize = 16;
+
+// The name of the entry which holds the digest of the root Directory of an archive.
+const ROOT_NAME: &str = "ROOT";
+
+///
+/// An entry of an archive of a Directory tree, which is a gzipped tarball of:
+///  * `ROOT`, which holds the digest (as `{fingerprint}-{size}`) of the root Directory.
+///  * `directories/{fingerprint}-{size}`, which hold the protos of Directories.
+///  * `files/{fingerprint}-{size}`, which hold the contents of files.
+///
+pub enum Entry {
+  Root(Digest),
+  Blob(EntryType, Digest, Bytes),
+}
+
+///
+/// Writes an archive on a thread of its own, to a temporary file which only replaces the archive
+/// once it is finished, so that an archive which wasn't finished (e.g. because a blob was
+/// missing) is never left behind.
+///
+pub struct Writer {
+  // None is sent once everything has been appended.
+  sender: mpsc::Sender<Option<(String, Bytes)>>,
+  finished: oneshot::Receiver<Result<(), String>>,
+}
+
+impl Writer {
+  ///
+  /// Starts writing an archive of the Directory tree with the given root to the given path. What
+  /// the tree contains should then be appended, and the Writer finished.
+  ///
+  pub fn create(path: &Path, root: Digest) -> BoxFuture<Writer, String> {
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let (finished_sender, finished) = oneshot::channel();
+    let path = path.to_owned();
+    thread::spawn(move || {
+      let _ = finished_sender.send(write(&path, receiver));
+    });
+    Writer { sender, finished }.append(ROOT_NAME.to_owned(), Bytes::from(digest_name(root)))
+  }
+
+  pub fn append_blob(
+    self,
+    entry_type: EntryType,
+    digest: Digest,
+    bytes: Bytes,
+  ) -> BoxFuture<Writer, String> {
+    self.append(blob_name(entry_type, digest), bytes)
+  }
+
+  ///
+  /// Waits for everything which was appended to be written, and the archive to be moved into
+  /// place.
+  ///
+  pub fn finish(self) -> BoxFuture<(), String> {
+    let finished = self.finished;
+    self
+      .sender
+      .send(None)
+      .then(move |_| written(finished))
+      .to_boxed()
+  }
+
+  fn append(self, name: String, bytes: Bytes) -> BoxFuture<Writer, String> {
+    let finished = self.finished;
+    self
+      .sender
+      .send(Some((name, bytes)))
+      .then(move |sent| match sent {
+        Ok(sender) => future::ok(Writer { sender, finished }).to_boxed(),
+        // The thread only stops receiving entries if it failed to write one.
+        Err(_) => written(finished)
+          .and_then(|()| Err("Archive stopped being written early".to_owned()))
+          .to_boxed(),
+      })
+      .to_boxed()
+  }
+}
+
+fn written(finished: oneshot::Receiver<Result<(), String>>) -> BoxFuture<(), String> {
+  finished
+    .then(|received| match received {
+      Ok(result) => result,
+      Err(_) => Err("The thread which was writing an archive panicked".to_owned()),
+    })
+    .to_boxed()
+}
+
+fn write(path: &Path, entries: mpsc::Receiver<Option<(String, Bytes)>>) -> Result<(), String> {
+  // Relative paths of files in the current directory have an empty parent.
+  let dir = path
+    .parent()
+    .filter(|parent| !parent.as_os_str().is_empty())
+    .unwrap_or_else(|| Path::new("."));
+  let tmp = NamedTempFile::new_in(dir)
+    .map_err(|err| format!("Error making temporary file in {:?}: {}", dir, err))?;
+  let mut builder = tar::Builder::new(GzEncoder::new(tmp, flate2::Compression::default()));
+  for entry in entries.wait() {
+    match entry {
+      Ok(Some((name, bytes))) => {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o644);
+        builder
+          .append_data(&mut header, &name, &bytes[..])
+          .map_err(|err| format!("Error writing {} to archive {:?}: {}", name, path, err))?;
+      }
+      Ok(None) => {
+        let tmp = builder
+          .into_inner()
+          .and_then(GzEncoder::finish)
+          .and_then(|tmp| tmp.as_file().sync_all().map(|()| tmp))
+          .map_err(|err| format!("Error writing archive {:?}: {}", path, err))?;
+        return tmp
+          .persist(path)
+          .map(|_| ())
+          .map_err(|err| format!("Error writing archive {:?}: {}", path, err.error));
+      }
+      Err(()) => break,
+    }
+  }
+  Err(format!(
+    "Archive {:?} was abandoned before it was finished",
+    path
+  ))
+}
+
+///
+/// Reads the entries of an archive on a thread of its own, in the order that they were written.
+///
+pub fn read(path: &Path) -> BoxStream<Entry, String> {
+  let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+  let path = path.to_owned();
+  thread::spawn(move || read_entries(&path, sender));
+  Box::new(receiver.then(|received| match received {
+    Ok(entry) => entry,
+    Err(()) => Err("The thread which was reading an archive panicked".to_owned()),
+  }))
+}
+
+///
+/// Sends the entries of the archive, until the first which can't be read (which is sent as an
+/// Err), or until the receiver of the entries is dropped.
+///
+fn read_entries(path: &Path, mut sender: mpsc::Sender<Result<Entry, String>>) {
+  let file = match File::open(path) {
+    Ok(file) => file,
+    Err(err) => {
+      let _ = sender
+        .send(Err(format!("Error opening archive {:?}: {}", path, err)))
+        .wait();
+      return;
+    }
+  };
+  let mut archive = tar::Archive::new(GzDecoder::new(file));
+  let entries = match archive.entries() {
+    Ok(entries) => entries,
+    Err(err) => {
+      let _ = sender
+        .send(Err(format!("Error reading archive {:?}: {}", path, err)))
+        .wait();
+      return;
+    }
+  };
+  for entry in entries {
+    let entry = entry
+      .and_then(|mut entry| {
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut bytes = Vec::with_capacity(entry.header().size().unwrap_or(0) as usize);
+        entry.read_to_end(&mut bytes)?;
+        Ok((name, bytes))
+      })
+      .map_err(|err| format!("Error reading archive {:?}: {}", path, err))
+      .and_then(|(name, bytes)| parse_entry(&name, Bytes::from(bytes)));
+    let failed = entry.is_err();
+    sender = match sender.send(entry).wait() {
+      Ok(sender) => sender,
+      Err(_) => return,
+    };
+    if failed {
+      return;
+    }
+  }
+}
+
+fn digest_name(digest: Digest) -> String {
+  format!("{}-{}", digest.0.to_hex(), digest.1)
+}
+
+fn blob_name(entry_type: EntryType, digest: Digest) -> String {
+  let dir = match entry_type {
+    EntryType::File => "files",
+    EntryType::Directory => "directories",
+  };
+  format!("{}/{}", dir, digest_name(digest))
+}
+
+fn parse_digest(name: &str) -> Result<Digest, String> {
+  let mut parts = name.splitn(2, '-');
+  match (parts.next(), parts.next()) {
+    (Some(fingerprint), Some(size)) => {
+      let fingerprint = Fingerprint::from_hex_string(fingerprint)?;
+      let size = size
+        .parse::<usize>()
+        .map_err(|err| format!("Bad size in digest {:?}: {}", name, err))?;
+      Ok(Digest(fingerprint, size))
+    }
+    _ => Err(format!("Bad digest {:?}", name)),
+  }
+}
+
+fn parse_entry(name: &str, bytes: Bytes) -> Result<Entry, String> {
+  if name == ROOT_NAME {
+    return parse_digest(String::from_utf8_lossy(&bytes).trim()).map(Entry::Root);
+  }
+  let mut parts = name.splitn(2, '/');
+  let entry_type = match parts.next() {
+    Some("files") => EntryType::File,
+    Some("directories") => EntryType::Directory,
+    _ => return Err(format!("Unknown entry {:?} in archive", name)),
+  };
+  let digest = parse_digest(parts.next().unwrap_or(""))?;
+  Ok(Entry::Blob(entry_type, digest, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{blob_name, parse_entry, Entry};
+  use crate::store::EntryType;
+  use testutil::data::TestData;
+
+  #[test]
+  fn parse_blob_names() {
+    let roland = TestData::roland();
+    for entry_type in &[EntryType::File, EntryType::Directory] {
+      match parse_entry(&blob_name(*entry_type, roland.digest()), roland.bytes()) {
+        Ok(Entry::Blob(parsed_type, digest, bytes)) => {
+          assert_eq!(parsed_type, *entry_type);
+          assert_eq!(digest, roland.digest());
+          assert_eq!(bytes, roland.bytes());
+        }
+        _ => panic!("Want blob entry"),
+      }
+    }
+    parse_entry("files/not-a-digest", roland.bytes()).expect_err("Want err");
+    parse_entry("symlinks/roland", roland.bytes()).expect_err("Want err");
+  }
+}
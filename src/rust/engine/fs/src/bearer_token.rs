@@ -0,0 +1,201 @@
+// OAuth bearer tokens with which to authenticate to remote servers, which can be refreshed (e.g.
+// once they have expired) without restarting.
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+
+use log::{debug, warn};
+use parking_lot::RwLock;
+
+///
+/// Where the bearer token comes from.
+///
+#[derive(Clone, Debug)]
+pub enum TokenSource {
+  ///
+  /// A fixed token, which can't be refreshed.
+  ///
+  Static(String),
+
+  ///
+  /// A file containing the token, which is re-read on refresh.
+  ///
+  File(PathBuf),
+
+  ///
+  /// A credential helper: an executable which prints the token to stdout, which is re-run on
+  /// refresh.
+  ///
+  Helper(PathBuf),
+}
+
+impl TokenSource {
+  fn load(&self) -> Result<String, String> {
+    match self {
+      TokenSource::Static(token) => Ok(token.clone()),
+      TokenSource::File(path) => std::fs::read_to_string(path)
+        .map(|token| token.trim().to_owned())
+        .map_err(|err| format!("Error reading oauth bearer token from {:?}: {}", path, err)),
+      TokenSource::Helper(path) => {
+        let output = Command::new(path)
+          .output()
+          .map_err(|err| format!("Error running credential helper {:?}: {}", path, err))?;
+        if !output.status.success() {
+          return Err(format!(
+            "Credential helper {:?} failed with {}: {}",
+            path,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+          ));
+        }
+        String::from_utf8(output.stdout)
+          .map(|token| token.trim().to_owned())
+          .map_err(|err| {
+            format!(
+              "Credential helper {:?} printed a non-UTF8 token: {}",
+              path, err
+            )
+          })
+      }
+    }
+  }
+}
+
+///
+/// The current bearer token from a TokenSource, which is shared between clones, so that refreshing
+/// it in response to a failed RPC affects every future RPC which uses it.
+///
+#[derive(Clone)]
+pub struct BearerToken {
+  source: TokenSource,
+  token: Arc<RwLock<String>>,
+}
+
+impl BearerToken {
+  pub fn new(source: TokenSource) -> Result<BearerToken, String> {
+    let token = source.load()?;
+    Ok(BearerToken {
+      source,
+      token: Arc::new(RwLock::new(token)),
+    })
+  }
+
+  ///
+  /// A BearerToken which never changes.
+  ///
+  pub fn fixed(token: String) -> BearerToken {
+    BearerToken {
+      source: TokenSource::Static(token.clone()),
+      token: Arc::new(RwLock::new(token)),
+    }
+  }
+
+  ///
+  /// The value of the authorization header for the current token.
+  ///
+  pub fn authorization_header(&self) -> String {
+    format!("Bearer {}", self.token.read())
+  }
+
+  ///
+  /// Reloads the token from its source, returning whether it changed.
+  ///
+  pub fn refresh(&self) -> Result<bool, String> {
+    let token = self.source.load()?;
+    let mut current = self.token.write();
+    if *current == token {
+      Ok(false)
+    } else {
+      *current = token;
+      Ok(true)
+    }
+  }
+
+  ///
+  /// If the given error is a rejection as unauthenticated (most likely because the token has
+  /// expired), refreshes the token, returning whether it changed (in which case the RPC is worth
+  /// retrying).
+  ///
+  pub fn refresh_if_unauthenticated(&self, err: &grpcio::Error) -> bool {
+    match err {
+      grpcio::Error::RpcFailure(status)
+        if status.status == grpcio::RpcStatusCode::Unauthenticated =>
+      {
+        match self.refresh() {
+          Ok(changed) => {
+            debug!(
+              "Refreshed oauth bearer token after an unauthenticated RPC (changed: {})",
+              changed
+            );
+            changed
+          }
+          Err(err) => {
+            warn!("Failed to refresh oauth bearer token: {}", err);
+            false
+          }
+        }
+      }
+      _ => false,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{BearerToken, TokenSource};
+  use tempfile::TempDir;
+
+  fn unauthenticated() -> grpcio::Error {
+    grpcio::Error::RpcFailure(grpcio::RpcStatus::new(
+      grpcio::RpcStatusCode::Unauthenticated,
+      None,
+    ))
+  }
+
+  #[test]
+  fn refreshes_from_file() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("token");
+    std::fs::write(&path, "first\n").unwrap();
+
+    let token = BearerToken::new(TokenSource::File(path.clone())).unwrap();
+    let clone = token.clone();
+    assert_eq!(token.authorization_header(), "Bearer first");
+
+    // Only unauthenticated errors, and changed tokens, are worth retrying.
+    assert!(!token.refresh_if_unauthenticated(&unauthenticated()));
+    std::fs::write(&path, "second\n").unwrap();
+    assert!(!token.refresh_if_unauthenticated(&grpcio::Error::RpcFinished(None)));
+    assert!(token.refresh_if_unauthenticated(&unauthenticated()));
+    assert_eq!(clone.authorization_header(), "Bearer second");
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn refreshes_from_helper() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new().unwrap();
+    let counter = dir.path().join("counter");
+    let helper = dir.path().join("helper");
+    std::fs::write(
+      &helper,
+      format!("#!/bin/sh\necho x >> {0}\nwc -l < {0}\n", counter.display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let token = BearerToken::new(TokenSource::Helper(helper)).unwrap();
+    assert_eq!(token.authorization_header(), "Bearer 1");
+    assert_eq!(token.refresh(), Ok(true));
+    assert_eq!(token.authorization_header(), "Bearer 2");
+  }
+
+  #[test]
+  fn fixed_tokens_do_not_change() {
+    let token = BearerToken::fixed("Armory.Key".to_owned());
+    assert_eq!(token.refresh(), Ok(false));
+    assert_eq!(token.authorization_header(), "Bearer Armory.Key");
+  }
+}
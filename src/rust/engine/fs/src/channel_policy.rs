@@ -0,0 +1,162 @@
+// How gRPC channels to remote servers detect connections which have silently died (e.g. behind a
+// flaky VPN or NAT), and how they re-establish them.
+
+use std::ffi::CString;
+use std::time::Duration;
+
+///
+/// How channels keep their connections alive, and reconnect once they have failed.
+///
+/// With keepalive enabled, the channel pings the server every `keepalive_interval` (even while no
+/// RPCs are in flight), and considers the connection dead if a ping isn't acknowledged within the
+/// `keepalive_timeout`. Servers reject clients which ping more often than they permit (by default
+/// every five minutes), so the interval must be agreed with the server.
+///
+/// Once a connection has failed (whether detected by keepalive or otherwise), RPCs on it fail as
+/// unavailable (and are retried), and the channel re-establishes it with an exponential backoff of
+/// at most `max_reconnect_backoff` between attempts.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChannelPolicy {
+  keepalive_interval: Option<Duration>,
+  keepalive_timeout: Duration,
+  max_reconnect_backoff: Duration,
+}
+
+impl ChannelPolicy {
+  const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+
+  pub fn new(
+    keepalive_interval: Option<Duration>,
+    keepalive_timeout: Duration,
+    max_reconnect_backoff: Duration,
+  ) -> Result<ChannelPolicy, String> {
+    if keepalive_interval == Some(Duration::from_secs(0)) {
+      return Err("Keepalive interval must be positive".to_owned());
+    }
+    if keepalive_interval.is_some() && keepalive_timeout == Duration::from_secs(0) {
+      return Err("Keepalive timeout must be positive".to_owned());
+    }
+    if max_reconnect_backoff < Self::INITIAL_RECONNECT_BACKOFF {
+      return Err(format!(
+        "Max reconnect backoff must be at least {:?}, but was {:?}",
+        Self::INITIAL_RECONNECT_BACKOFF,
+        max_reconnect_backoff
+      ));
+    }
+    Ok(ChannelPolicy {
+      keepalive_interval,
+      keepalive_timeout,
+      max_reconnect_backoff,
+    })
+  }
+
+  ///
+  /// The default policy, but with keepalive pings every given number of seconds (unless it is 0).
+  ///
+  pub fn with_keepalive_interval_secs(secs: u64) -> Result<ChannelPolicy, String> {
+    let default = ChannelPolicy::default();
+    ChannelPolicy::new(
+      if secs == 0 {
+        None
+      } else {
+        Some(Duration::from_secs(secs))
+      },
+      default.keepalive_timeout,
+      default.max_reconnect_backoff,
+    )
+  }
+
+  ///
+  /// Applies the policy to a channel.
+  ///
+  pub fn configure(&self, builder: grpcio::ChannelBuilder) -> grpcio::ChannelBuilder {
+    let mut builder = builder
+      .raw_cfg_int(
+        arg("grpc.initial_reconnect_backoff_ms"),
+        millis(Self::INITIAL_RECONNECT_BACKOFF),
+      )
+      .raw_cfg_int(
+        arg("grpc.min_reconnect_backoff_ms"),
+        millis(Self::INITIAL_RECONNECT_BACKOFF),
+      )
+      .raw_cfg_int(
+        arg("grpc.max_reconnect_backoff_ms"),
+        millis(self.max_reconnect_backoff),
+      );
+    if let Some(keepalive_interval) = self.keepalive_interval {
+      builder = builder
+        .raw_cfg_int(arg("grpc.keepalive_time_ms"), millis(keepalive_interval))
+        .raw_cfg_int(
+          arg("grpc.keepalive_timeout_ms"),
+          millis(self.keepalive_timeout),
+        )
+        // Idle connections are the ones most likely to have died unnoticed.
+        .raw_cfg_int(arg("grpc.keepalive_permit_without_calls"), 1)
+        .raw_cfg_int(arg("grpc.http2.max_pings_without_data"), 0)
+        .raw_cfg_int(
+          arg("grpc.http2.min_time_between_pings_ms"),
+          millis(keepalive_interval),
+        );
+    }
+    builder
+  }
+}
+
+impl Default for ChannelPolicy {
+  ///
+  /// gRPC's own defaults: no keepalive, and up to two minutes between reconnection attempts.
+  ///
+  fn default() -> ChannelPolicy {
+    ChannelPolicy {
+      keepalive_interval: None,
+      keepalive_timeout: Duration::from_secs(20),
+      max_reconnect_backoff: Duration::from_secs(120),
+    }
+  }
+}
+
+fn arg(name: &'static str) -> CString {
+  CString::new(name).expect("Static strings contain no nul bytes")
+}
+
+fn millis(duration: Duration) -> i32 {
+  let millis = duration.as_secs() * 1000 + u64::from(duration.subsec_millis());
+  if millis > i32::max_value() as u64 {
+    i32::max_value()
+  } else {
+    millis as i32
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::ChannelPolicy;
+  use std::time::Duration;
+
+  #[test]
+  fn new_validates() {
+    ChannelPolicy::new(
+      Some(Duration::from_secs(300)),
+      Duration::from_secs(20),
+      Duration::from_secs(30),
+    )
+    .expect("Want valid policy");
+    ChannelPolicy::new(None, Duration::from_secs(0), Duration::from_secs(30))
+      .expect("Want timeout to be ignored without keepalive");
+    ChannelPolicy::new(
+      Some(Duration::from_secs(0)),
+      Duration::from_secs(20),
+      Duration::from_secs(30),
+    )
+    .expect_err("Want Err for zero interval");
+    ChannelPolicy::new(
+      Some(Duration::from_secs(300)),
+      Duration::from_secs(0),
+      Duration::from_secs(30),
+    )
+    .expect_err("Want Err for zero timeout");
+    ChannelPolicy::new(None, Duration::from_secs(20), Duration::from_millis(10))
+      .expect_err("Want Err for max backoff less than the initial backoff");
+  }
+}
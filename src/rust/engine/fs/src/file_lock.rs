@@ -0,0 +1,150 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+///
+/// An advisory lock (see flock(2)) of a file, with which the processes which share a directory
+/// (e.g. a local store which several checkouts and pantsd instances use) coordinate their use of
+/// it. The lock is released when this is dropped.
+///
+/// Locks belong to open files rather than to processes, so two FileLocks of the same path exclude
+/// each other even within one process, and a lock which a forked process inherits is shared with
+/// it.
+///
+pub struct FileLock {
+  path: PathBuf,
+  file: File,
+}
+
+impl FileLock {
+  ///
+  /// Takes a shared lock of the file (which is created if it doesn't exist), waiting for any
+  /// exclusive lock of it to be released.
+  ///
+  pub fn shared(path: &Path) -> Result<FileLock, String> {
+    let lock = FileLock::open(path)?;
+    lock.flock(libc::LOCK_SH)?;
+    Ok(lock)
+  }
+
+  ///
+  /// Takes a shared lock of the file (which is created if it doesn't exist), or returns None if an
+  /// exclusive lock of it is held.
+  ///
+  pub fn try_shared(path: &Path) -> Result<Option<FileLock>, String> {
+    let lock = FileLock::open(path)?;
+    if lock.flock(libc::LOCK_SH | libc::LOCK_NB)? {
+      Ok(Some(lock))
+    } else {
+      Ok(None)
+    }
+  }
+
+  ///
+  /// Takes an exclusive lock of the file (which is created if it doesn't exist), or returns None
+  /// if any other lock of it is held.
+  ///
+  pub fn try_exclusive(path: &Path) -> Result<Option<FileLock>, String> {
+    let lock = FileLock::open(path)?;
+    if lock.flock(libc::LOCK_EX | libc::LOCK_NB)? {
+      Ok(Some(lock))
+    } else {
+      Ok(None)
+    }
+  }
+
+  ///
+  /// Converts this shared lock to an exclusive lock, returning whether it could be converted,
+  /// which it can't while any other lock of the file is held.
+  ///
+  /// Conversions aren't atomic: if this lock can't be converted, it is shared again when this
+  /// returns, but another process may have briefly held an exclusive lock in between.
+  ///
+  pub fn try_upgrade(&self) -> Result<bool, String> {
+    if self.flock(libc::LOCK_EX | libc::LOCK_NB)? {
+      Ok(true)
+    } else {
+      self.downgrade()?;
+      Ok(false)
+    }
+  }
+
+  ///
+  /// Converts this (exclusive) lock to a shared lock.
+  ///
+  pub fn downgrade(&self) -> Result<(), String> {
+    self.flock(libc::LOCK_SH).map(|_| ())
+  }
+
+  fn open(path: &Path) -> Result<FileLock, String> {
+    OpenOptions::new()
+      .create(true)
+      .write(true)
+      .open(path)
+      .map(|file| FileLock {
+        path: path.to_owned(),
+        file,
+      })
+      .map_err(|err| format!("Error opening lock file {:?}: {}", path, err))
+  }
+
+  ///
+  /// Returns false if the lock is non-blocking and would have blocked.
+  ///
+  fn flock(&self, operation: libc::c_int) -> Result<bool, String> {
+    loop {
+      if unsafe { libc::flock(self.file.as_raw_fd(), operation) } == 0 {
+        return Ok(true);
+      }
+      let err = io::Error::last_os_error();
+      match err.kind() {
+        io::ErrorKind::WouldBlock => return Ok(false),
+        io::ErrorKind::Interrupted => continue,
+        _ => return Err(format!("Error locking {:?}: {}", self.path, err)),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::FileLock;
+  use tempfile::TempDir;
+
+  #[test]
+  fn shared_locks_exclude_exclusive_locks() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("lock");
+
+    let first = FileLock::shared(&path).expect("Error locking");
+    let second = FileLock::shared(&path).expect("Error locking");
+    assert!(FileLock::try_exclusive(&path).unwrap().is_none());
+
+    std::mem::drop(first);
+    assert!(FileLock::try_exclusive(&path).unwrap().is_none());
+
+    std::mem::drop(second);
+    let exclusive = FileLock::try_exclusive(&path).unwrap();
+    assert!(exclusive.is_some());
+    assert!(FileLock::try_exclusive(&path).unwrap().is_none());
+  }
+
+  #[test]
+  fn upgrade_only_the_only_lock() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("lock");
+
+    let first = FileLock::shared(&path).expect("Error locking");
+    let second = FileLock::shared(&path).expect("Error locking");
+    assert_eq!(first.try_upgrade(), Ok(false));
+    // The lock which couldn't be upgraded is still held.
+    std::mem::drop(second);
+    assert!(FileLock::try_exclusive(&path).unwrap().is_none());
+
+    assert_eq!(first.try_upgrade(), Ok(true));
+    assert!(FileLock::try_exclusive(&path).unwrap().is_none());
+    first.downgrade().expect("Error downgrading");
+    let _shared = FileLock::shared(&path).expect("Error locking");
+  }
+}
@@ -0,0 +1,1031 @@
+// A remote store which keeps blobs as objects in a Google Cloud Storage bucket, so that teams on
+// GCP can share a cache without running a CAS: as in an S3 bucket, blobs are stored at
+// `[<prefix>/][<instance name>/]cas/<sha256>`, and serialized ActionResults at
+// `[<prefix>/][<instance name>/]ac/<sha256 of the Action>`.
+
+use crate::http_cache::{self, digest_of, status_error};
+use crate::s3::uri_encode;
+use crate::{
+  parse_size_bytes, BackoffConfig, BearerToken, ClientCertificate, LoadBalancing, Proxy,
+};
+
+use bazel_protos;
+use boxfuture::{try_future, BoxFuture, Boxable};
+use bytes::Bytes;
+use futures::{future, Future, Stream};
+use hashing::{Digest, Fingerprint};
+use log::debug;
+use parking_lot::Mutex;
+use protobuf::Message;
+use reqwest::header::{HeaderMap, AUTHORIZATION, CONTENT_RANGE, LOCATION, RANGE};
+use reqwest::r#async::Client;
+use reqwest::{Method, StatusCode, Url};
+use ring::rand::SystemRandom;
+use ring::signature::{RsaKeyPair, RSA_PKCS1_SHA256};
+use serde_derive::Deserialize;
+use serverset::{AttemptError, Retry, RetryPolicy, Serverset};
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+
+// Blobs which are larger than this are uploaded with resumable uploads in chunks of this size (by
+// default), so that a failed upload continues from the last chunk which GCS persisted, rather than
+// starting over. GCS requires every chunk but the last to be a multiple of 256KiB.
+const DEFAULT_CHUNK_SIZE_BYTES: usize = 16 * 1024 * 1024;
+const CHUNK_SIZE_MULTIPLE_BYTES: usize = 256 * 1024;
+
+// The scope of the access tokens of service accounts.
+const READ_WRITE_SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+const JWT_BEARER_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+// How long the assertions which are exchanged for access tokens are valid for, which is the most
+// that Google allows.
+const ASSERTION_LIFETIME_SECS: u64 = 60 * 60;
+// Access tokens are refreshed this long before they expire, so that requests don't race expiry.
+const ACCESS_TOKEN_EXPIRY_MARGIN_SECS: u64 = 60;
+
+///
+/// Whether the given remote store address is the URL of a GCS bucket.
+///
+pub fn is_gcs_url(address: &str) -> bool {
+  address.starts_with("gs://")
+}
+
+///
+/// Where and how objects are stored, as given by a URL of the form
+/// `gs://<bucket>[/<prefix>][?<parameter>=<value>&...]`, whose parameters are:
+///
+///  * `credentials`: The JSON key file of the service account to authenticate as. By default, that
+///    of the environment (`GOOGLE_APPLICATION_CREDENTIALS`), if any.
+///  * `endpoint`: The URL of the service to use instead of `https://storage.googleapis.com` (e.g.
+///    an emulator).
+///  * `chunk_size`: The size (e.g. `16MiB`) of the chunks which larger blobs are uploaded in, which
+///    must be a multiple of 256KiB.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Bucket {
+  endpoint: String,
+  name: String,
+  prefix: Option<String>,
+  credentials: Option<PathBuf>,
+  chunk_size_bytes: usize,
+}
+
+impl Bucket {
+  fn parse(address: &str, default_credentials: Option<PathBuf>) -> Result<Bucket, String> {
+    let invalid = |reason: &str| format!("Invalid GCS bucket URL {:?}: {}", address, reason);
+    let location_and_parameters = address
+      .trim_start_matches("gs://")
+      .splitn(2, '?')
+      .collect::<Vec<_>>();
+    let mut location = location_and_parameters[0].splitn(2, '/');
+    let name = location
+      .next()
+      .filter(|name| !name.is_empty())
+      .ok_or_else(|| invalid("no bucket name"))?
+      .to_owned();
+    let prefix = location
+      .next()
+      .map(|prefix| prefix.trim_matches('/'))
+      .filter(|prefix| !prefix.is_empty())
+      .map(str::to_owned);
+
+    let mut credentials = default_credentials;
+    let mut endpoint = DEFAULT_ENDPOINT.to_owned();
+    let mut chunk_size_bytes = DEFAULT_CHUNK_SIZE_BYTES;
+    for parameter in location_and_parameters
+      .get(1)
+      .into_iter()
+      .flat_map(|parameters| parameters.split('&'))
+      .filter(|parameter| !parameter.is_empty())
+    {
+      let mut parameter = parameter.splitn(2, '=');
+      let name = parameter.next().unwrap_or_default();
+      let value = parameter
+        .next()
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| invalid(&format!("no value for parameter {:?}", name)))?
+        .to_owned();
+      match name {
+        "credentials" => credentials = Some(PathBuf::from(value)),
+        "endpoint" => endpoint = value.trim_end_matches('/').to_owned(),
+        "chunk_size" => {
+          chunk_size_bytes = parse_size_bytes(&value).map_err(|err| invalid(&err))?;
+          if chunk_size_bytes == 0 || chunk_size_bytes % CHUNK_SIZE_MULTIPLE_BYTES != 0 {
+            return Err(invalid(
+              "the chunk size must be a positive multiple of 256KiB",
+            ));
+          }
+        }
+        name => return Err(invalid(&format!("unknown parameter {:?}", name))),
+      }
+    }
+    Url::parse(&endpoint).map_err(|err| invalid(&format!("{}: {}", endpoint, err)))?;
+
+    Ok(Bucket {
+      endpoint,
+      name,
+      prefix,
+      credentials,
+      chunk_size_bytes,
+    })
+  }
+
+  fn object_name(
+    &self,
+    instance_name: &Option<String>,
+    namespace: &str,
+    fingerprint: Fingerprint,
+  ) -> String {
+    self
+      .prefix
+      .iter()
+      .chain(instance_name)
+      .map(String::as_str)
+      .chain(vec![namespace, &fingerprint.to_hex()])
+      .collect::<Vec<_>>()
+      .join("/")
+  }
+
+  ///
+  /// The path (below the endpoint) of the metadata of the given object, or with `?alt=media`, of
+  /// its content.
+  ///
+  fn object_path(&self, object_name: &str) -> String {
+    format!(
+      "/storage/v1/b/{}/o/{}",
+      self.name,
+      uri_encode(object_name, true)
+    )
+  }
+
+  ///
+  /// The path (below the endpoint) to upload the given object to, with the given type of upload.
+  ///
+  fn upload_path(&self, object_name: &str, upload_type: &str) -> String {
+    format!(
+      "/upload/storage/v1/b/{}/o?uploadType={}&name={}",
+      self.name,
+      upload_type,
+      uri_encode(object_name, true)
+    )
+  }
+}
+
+///
+/// The parts of the JSON key file of a service account which are needed to authenticate as it.
+///
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+  #[serde(rename = "type")]
+  key_type: String,
+  client_email: String,
+  private_key: String,
+  token_uri: String,
+}
+
+///
+/// A service account, whose access tokens are got by exchanging assertions signed with its key, as
+/// described in https://developers.google.com/identity/protocols/OAuth2ServiceAccount.
+///
+struct ServiceAccount {
+  client_email: String,
+  token_uri: String,
+  key_pair: RsaKeyPair,
+  // The current access token, and when it should be refreshed.
+  access_token: Mutex<Option<(String, Instant)>>,
+}
+
+impl ServiceAccount {
+  fn from_key_file(path: &Path) -> Result<ServiceAccount, String> {
+    let invalid = |reason: String| {
+      format!(
+        "Invalid service account key file {}: {}",
+        path.display(),
+        reason
+      )
+    };
+    let content = std::fs::read(path).map_err(|err| invalid(err.to_string()))?;
+    let key: ServiceAccountKey =
+      serde_json::from_slice(&content).map_err(|err| invalid(err.to_string()))?;
+    if key.key_type != "service_account" {
+      return Err(invalid(format!(
+        "it is a key of type {:?}, rather than service_account",
+        key.key_type
+      )));
+    }
+    // The key is a PEM-encoded PKCS#8 document.
+    let der = base64::decode(
+      &key
+        .private_key
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect::<String>(),
+    )
+    .map_err(|err| invalid(format!("private key isn't PEM: {}", err)))?;
+    let key_pair = RsaKeyPair::from_pkcs8(untrusted::Input::from(&der[..]))
+      .map_err(|err| invalid(format!("private key isn't a PKCS#8 RSA key: {:?}", err)))?;
+    Ok(ServiceAccount {
+      client_email: key.client_email,
+      token_uri: key.token_uri,
+      key_pair,
+      access_token: Mutex::new(None),
+    })
+  }
+
+  ///
+  /// A JWT asserting (until an hour after the given number of seconds since the epoch) that we are
+  /// the service account, which is signed with its key.
+  ///
+  fn assertion(&self, now_secs: u64) -> Result<String, String> {
+    let encode = |content: &[u8]| base64::encode_config(content, base64::URL_SAFE_NO_PAD);
+    let header = serde_json::json!({"alg": "RS256", "typ": "JWT"});
+    let claims = serde_json::json!({
+      "iss": self.client_email,
+      "scope": READ_WRITE_SCOPE,
+      "aud": self.token_uri,
+      "iat": now_secs,
+      "exp": now_secs + ASSERTION_LIFETIME_SECS,
+    });
+    let message = format!(
+      "{}.{}",
+      encode(header.to_string().as_bytes()),
+      encode(claims.to_string().as_bytes())
+    );
+    let mut signature = vec![0; self.key_pair.public_modulus_len()];
+    self
+      .key_pair
+      .sign(
+        &RSA_PKCS1_SHA256,
+        &SystemRandom::new(),
+        message.as_bytes(),
+        &mut signature,
+      )
+      .map_err(|_| format!("Error signing assertion for {}", self.client_email))?;
+    Ok(format!("{}.{}", message, encode(&signature)))
+  }
+
+  fn current_access_token(&self, now: Instant) -> Option<String> {
+    match *self.access_token.lock() {
+      Some((ref token, refresh_at)) if now < refresh_at => Some(token.clone()),
+      _ => None,
+    }
+  }
+}
+
+///
+/// The response of the token endpoint to an assertion.
+///
+#[derive(Deserialize)]
+struct AccessTokenResponse {
+  access_token: String,
+  expires_in: u64,
+}
+
+///
+/// How requests are authenticated.
+///
+#[derive(Clone)]
+enum Credentials {
+  ServiceAccount(Arc<ServiceAccount>),
+  // E.g. from a credential helper which prints the token of `gcloud auth`.
+  BearerToken(BearerToken),
+  // For public buckets, and emulators.
+  Anonymous,
+}
+
+impl Credentials {
+  ///
+  /// Forgets (or refreshes) the current token after a request with it was rejected as unauthorized,
+  /// returning whether the request is worth retrying with a new one.
+  ///
+  fn invalidate(&self) -> bool {
+    match self {
+      Credentials::ServiceAccount(service_account) => {
+        *service_account.access_token.lock() = None;
+        true
+      }
+      Credentials::BearerToken(token) => token.refresh().unwrap_or(false),
+      Credentials::Anonymous => false,
+    }
+  }
+}
+
+///
+/// Where a request is sent.
+///
+#[derive(Clone)]
+enum Location {
+  // A path (including its query) below the endpoint.
+  Path(String),
+  // The URL of the session of a resumable upload.
+  Url(String),
+}
+
+///
+/// A GCS bucket. Requests are authenticated as the service account whose key is configured (see
+/// `Bucket`), or else with the given oauth bearer token, or are anonymous if there is neither.
+///
+/// Like HTTP caches, buckets don't support batching, or querying which of many objects are missing,
+/// so each blob is sent, fetched or checked for with its own request.
+///
+#[derive(Clone)]
+pub struct ByteStore {
+  bucket: Bucket,
+  credentials: Credentials,
+  instance_name: Option<String>,
+  upload_timeout: Duration,
+  read_timeout: Duration,
+  action_cache_timeout: Duration,
+  retry_policy: RetryPolicy,
+  client: Client,
+  serverset: Serverset<String>,
+  timer_handle: futures_timer::TimerHandle,
+}
+
+impl ByteStore {
+  pub fn new(
+    bucket_url: &str,
+    instance_name: Option<String>,
+    root_ca_certs: &Option<Vec<u8>>,
+    client_certificate: &Option<ClientCertificate>,
+    proxy: &Option<Proxy>,
+    oauth_bearer_token: Option<BearerToken>,
+    upload_timeout: Duration,
+    read_timeout: Duration,
+    action_cache_timeout: Duration,
+    backoff_config: BackoffConfig,
+    load_balancing: LoadBalancing,
+    rpc_retries: usize,
+    futures_timer_thread: futures_timer::TimerHandle,
+  ) -> Result<ByteStore, String> {
+    let default_credentials = env::var_os("GOOGLE_APPLICATION_CREDENTIALS")
+      .filter(|path| !path.is_empty())
+      .map(PathBuf::from);
+    let bucket = Bucket::parse(bucket_url, default_credentials)?;
+    let credentials = match (&bucket.credentials, oauth_bearer_token) {
+      (Some(path), _) => {
+        Credentials::ServiceAccount(Arc::new(ServiceAccount::from_key_file(path)?))
+      }
+      (None, Some(token)) => Credentials::BearerToken(token),
+      (None, None) => {
+        debug!("No GCS credentials are configured, so requests to GCS will be anonymous");
+        Credentials::Anonymous
+      }
+    };
+    Ok(ByteStore {
+      serverset: Serverset::new(
+        vec![bucket.endpoint.clone()],
+        backoff_config,
+        load_balancing,
+        futures_timer_thread.clone(),
+      )?,
+      bucket,
+      credentials,
+      instance_name,
+      upload_timeout,
+      read_timeout,
+      action_cache_timeout,
+      retry_policy: http_cache::retry_policy(rpc_retries)?,
+      client: http_cache::client(root_ca_certs, client_certificate, proxy)?,
+      timer_handle: futures_timer_thread,
+    })
+  }
+
+  pub fn with_instance_name(&self, instance_name: Option<String>) -> ByteStore {
+    ByteStore {
+      instance_name,
+      ..self.clone()
+    }
+  }
+
+  pub fn instance_name(&self) -> Option<String> {
+    self.instance_name.clone()
+  }
+
+  ///
+  /// The value of the authorization header of requests, if they are authenticated. The access
+  /// tokens of service accounts are cached until shortly before they expire.
+  ///
+  fn authorization(&self) -> BoxFuture<Option<String>, String> {
+    let service_account = match self.credentials {
+      Credentials::ServiceAccount(ref service_account) => service_account.clone(),
+      Credentials::BearerToken(ref token) => {
+        return future::ok(Some(token.authorization_header())).to_boxed();
+      }
+      Credentials::Anonymous => return future::ok(None).to_boxed(),
+    };
+    if let Some(token) = service_account.current_access_token(Instant::now()) {
+      return future::ok(Some(format!("Bearer {}", token))).to_boxed();
+    }
+
+    let now_secs = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|since_epoch| since_epoch.as_secs())
+      .unwrap_or(0);
+    let assertion = try_future!(service_account.assertion(now_secs));
+    let description = format!("POST {}", service_account.token_uri);
+    let description2 = description.clone();
+    let response = self
+      .client
+      .post(&service_account.token_uri)
+      .form(&[
+        ("grant_type", JWT_BEARER_GRANT_TYPE),
+        ("assertion", assertion.as_str()),
+      ])
+      .send()
+      .and_then(|response| {
+        let status = response.status();
+        response
+          .into_body()
+          .concat2()
+          .map(move |body| (status, Bytes::from(&body[..])))
+      })
+      .map_err(move |err| AttemptError::retryable(format!("{}: {}", description2, err)));
+    http_cache::with_timeout(
+      &self.timer_handle,
+      response,
+      self.read_timeout,
+      description.clone(),
+    )
+    .map_err(|err| err.message)
+    .and_then(move |(status, body)| {
+      if !status.is_success() {
+        return Err(format!(
+          "Error getting an access token for {}: {}: {}",
+          service_account.client_email,
+          status,
+          String::from_utf8_lossy(&body)
+        ));
+      }
+      let response: AccessTokenResponse = serde_json::from_slice(&body).map_err(|err| {
+        format!(
+          "{}: invalid access token response for {}: {}",
+          description, service_account.client_email, err
+        )
+      })?;
+      let refresh_at = Instant::now()
+        + Duration::from_secs(
+          response
+            .expires_in
+            .saturating_sub(ACCESS_TOKEN_EXPIRY_MARGIN_SECS),
+        );
+      *service_account.access_token.lock() = Some((response.access_token.clone(), refresh_at));
+      Ok(Some(format!("Bearer {}", response.access_token)))
+    })
+    .to_boxed()
+  }
+
+  ///
+  /// Makes an (authenticated) request (retrying failures which are likely to be transient with the
+  /// given policy), and converts the status, headers and body of the response with `f`.
+  ///
+  fn request_with_policy<
+    Value: Send + 'static,
+    F: Fn(StatusCode, &HeaderMap, Bytes) -> Result<Value, AttemptError>
+      + Send
+      + Sync
+      + Clone
+      + 'static,
+  >(
+    &self,
+    method: Method,
+    location: Location,
+    headers: Vec<(String, String)>,
+    body: Option<Bytes>,
+    timeout: Duration,
+    retry_policy: RetryPolicy,
+    f: F,
+  ) -> BoxFuture<Value, String> {
+    let store = self.clone();
+    Retry(self.serverset.clone())
+      .with_policy(
+        move |endpoint: String| {
+          let url_string = match location {
+            Location::Path(ref path) => format!("{}{}", endpoint, path),
+            Location::Url(ref url) => url.clone(),
+          };
+          let description = format!("{} {}", method, url_string);
+          let url = match Url::parse(&url_string) {
+            Ok(url) => url,
+            Err(err) => {
+              return future::err(AttemptError::fatal(format!("{}: {}", description, err)))
+                .to_boxed();
+            }
+          };
+          let (store2, method, headers, body, f) = (
+            store.clone(),
+            method.clone(),
+            headers.clone(),
+            body.clone(),
+            f.clone(),
+          );
+          let description2 = description.clone();
+          let response = store
+            .authorization()
+            .map_err(AttemptError::retryable)
+            .and_then(move |authorization| {
+              let mut request = store2.client.request(method, url);
+              if let Some(authorization) = authorization {
+                request = request.header(AUTHORIZATION, authorization.as_str());
+              }
+              for (name, value) in &headers {
+                request = request.header(name.as_str(), value.as_str());
+              }
+              if let Some(body) = body {
+                request = request.body(body);
+              }
+              request
+                .send()
+                .and_then(|response| {
+                  let status = response.status();
+                  let headers = response.headers().clone();
+                  response
+                    .into_body()
+                    .concat2()
+                    .map(move |body| (status, headers, Bytes::from(&body[..])))
+                })
+                .map_err(move |err| AttemptError::retryable(format!("{}: {}", description2, err)))
+            });
+          let credentials = store.credentials.clone();
+          http_cache::with_timeout(&store.timer_handle, response, timeout, description.clone())
+            .and_then(move |(status, headers, body)| {
+              if status == StatusCode::UNAUTHORIZED && credentials.invalidate() {
+                return Err(AttemptError::retryable(format!(
+                  "{}: unauthorized, so retrying with a new token",
+                  description
+                )));
+              }
+              f(status, &headers, body).map_err(|err| AttemptError {
+                message: format!("{}: {}", description, err.message),
+                ..err
+              })
+            })
+            .to_boxed()
+        },
+        retry_policy,
+      )
+      .map(|(value, retries)| {
+        if retries > 0 {
+          debug!("GCS request succeeded after {} retries", retries);
+        }
+        value
+      })
+      .to_boxed()
+  }
+
+  fn request<
+    Value: Send + 'static,
+    F: Fn(StatusCode, &HeaderMap, Bytes) -> Result<Value, AttemptError>
+      + Send
+      + Sync
+      + Clone
+      + 'static,
+  >(
+    &self,
+    method: Method,
+    location: Location,
+    headers: Vec<(String, String)>,
+    body: Option<Bytes>,
+    timeout: Duration,
+    f: F,
+  ) -> BoxFuture<Value, String> {
+    self.request_with_policy(
+      method,
+      location,
+      headers,
+      body,
+      timeout,
+      self.retry_policy,
+      f,
+    )
+  }
+
+  fn cas_object_name(&self, digest: Digest) -> String {
+    self
+      .bucket
+      .object_name(&self.instance_name, "cas", digest.0)
+  }
+
+  fn action_cache_object_name(&self, action_digest: Digest) -> String {
+    self
+      .bucket
+      .object_name(&self.instance_name, "ac", action_digest.0)
+  }
+
+  ///
+  /// Downloads the blob with the given digest, if the bucket has it. The content is verified
+  /// against the digest, because objects are neither checked by GCS nor guaranteed to have been
+  /// written by us.
+  ///
+  pub fn load_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + Clone + 'static>(
+    &self,
+    digest: Digest,
+    f: F,
+  ) -> BoxFuture<Option<T>, String> {
+    self.request(
+      Method::GET,
+      Location::Path(format!(
+        "{}?alt=media",
+        self.bucket.object_path(&self.cas_object_name(digest))
+      )),
+      vec![],
+      None,
+      self.read_timeout,
+      move |status, _headers, body| match status {
+        StatusCode::OK => {
+          let actual_digest = digest_of(&body);
+          if actual_digest == digest {
+            Ok(Some(f(body)))
+          } else {
+            Err(AttemptError::retryable(format!(
+              "GCS gave wrong digest: expected {:?}, got {:?}",
+              digest, actual_digest
+            )))
+          }
+        }
+        StatusCode::NOT_FOUND => Ok(None),
+        status => Err(status_error(status)),
+      },
+    )
+  }
+
+  pub fn store_bytes(&self, bytes: Bytes) -> BoxFuture<Digest, String> {
+    let digest = digest_of(&bytes);
+    if bytes.len() > self.bucket.chunk_size_bytes {
+      return self.store_bytes_resumably(digest, bytes);
+    }
+    self
+      .upload(&self.cas_object_name(digest), bytes, self.upload_timeout)
+      .map(move |()| digest)
+      .to_boxed()
+  }
+
+  ///
+  /// Uploads the given object in one request.
+  ///
+  fn upload(&self, object_name: &str, bytes: Bytes, timeout: Duration) -> BoxFuture<(), String> {
+    self.request(
+      Method::POST,
+      Location::Path(self.bucket.upload_path(object_name, "media")),
+      vec![(
+        "content-type".to_owned(),
+        "application/octet-stream".to_owned(),
+      )],
+      Some(bytes),
+      timeout,
+      |status, _headers, _body| {
+        if status.is_success() {
+          Ok(())
+        } else {
+          Err(status_error(status))
+        }
+      },
+    )
+  }
+
+  ///
+  /// Uploads the given blob with a resumable upload, whose chunks are uploaded in order. When a
+  /// chunk fails, GCS is asked how much of the blob it has persisted, and the upload resumes from
+  /// there (as many times as requests are retried).
+  ///
+  fn store_bytes_resumably(&self, digest: Digest, bytes: Bytes) -> BoxFuture<Digest, String> {
+    let store = self.clone();
+    let total = bytes.len();
+    self
+      .request(
+        Method::POST,
+        Location::Path(
+          self
+            .bucket
+            .upload_path(&self.cas_object_name(digest), "resumable"),
+        ),
+        vec![
+          ("x-upload-content-length".to_owned(), total.to_string()),
+          (
+            "x-upload-content-type".to_owned(),
+            "application/octet-stream".to_owned(),
+          ),
+        ],
+        Some(Bytes::new()),
+        self.upload_timeout,
+        |status, headers, _body| {
+          if status.is_success() {
+            headers
+              .get(LOCATION)
+              .and_then(|location| location.to_str().ok())
+              .map(str::to_owned)
+              .ok_or_else(|| AttemptError::fatal("No session URL for resumable upload".to_owned()))
+          } else {
+            Err(status_error(status))
+          }
+        },
+      )
+      .and_then(move |session_url| {
+        let resumptions = store.retry_policy.max_attempts() - 1;
+        future::loop_fn(
+          (0, resumptions),
+          move |(offset, resumptions_left): (usize, usize)| {
+            let chunk_end = min(offset + store.bucket.chunk_size_bytes, total);
+            let store2 = store.clone();
+            let session_url2 = session_url.clone();
+            store
+              .upload_chunk(
+                &session_url,
+                Some((offset, bytes.slice(offset, chunk_end))),
+                total,
+              )
+              .then(move |result| match result {
+                Ok(persisted) => future::ok((persisted, resumptions_left)).to_boxed(),
+                Err(err) if resumptions_left > 0 => {
+                  debug!("Resuming upload of {:?} after: {}", digest, err);
+                  store2
+                    .upload_chunk(&session_url2, None, total)
+                    .map(move |persisted| (persisted, resumptions_left - 1))
+                    .to_boxed()
+                }
+                Err(err) => future::err(err).to_boxed(),
+              })
+              .map(|(persisted, resumptions_left)| match persisted {
+                Some(persisted) => future::Loop::Continue((persisted, resumptions_left)),
+                None => future::Loop::Break(()),
+              })
+          },
+        )
+      })
+      .map(move |()| digest)
+      .to_boxed()
+  }
+
+  ///
+  /// Uploads the given chunk (at the given offset) of a resumable upload, or with no chunk, asks
+  /// for the status of the upload. Returns how many bytes GCS has persisted, unless the upload is
+  /// complete.
+  ///
+  /// Chunks are only tried once, because a failed chunk may have been partially persisted, so
+  /// should be resumed from wherever GCS got to rather than retried.
+  ///
+  fn upload_chunk(
+    &self,
+    session_url: &str,
+    chunk: Option<(usize, Bytes)>,
+    total: usize,
+  ) -> BoxFuture<Option<usize>, String> {
+    let (content_range, body, retry_policy) = match chunk {
+      Some((offset, bytes)) => (
+        format!("bytes {}-{}/{}", offset, offset + bytes.len() - 1, total),
+        bytes,
+        try_future!(http_cache::retry_policy(0)),
+      ),
+      None => (
+        format!("bytes */{}", total),
+        Bytes::new(),
+        self.retry_policy,
+      ),
+    };
+    self.request_with_policy(
+      Method::PUT,
+      Location::Url(session_url.to_owned()),
+      vec![(CONTENT_RANGE.as_str().to_owned(), content_range)],
+      Some(body),
+      self.upload_timeout,
+      retry_policy,
+      |status, headers, _body| match status.as_u16() {
+        200 | 201 => Ok(None),
+        // "Resume Incomplete", with the range which has been persisted (if any) so far.
+        308 => Ok(Some(
+          headers
+            .get(RANGE)
+            .and_then(|range| range.to_str().ok())
+            .and_then(|range| range.rsplit('-').next())
+            .and_then(|last_byte| last_byte.parse::<usize>().ok())
+            .map(|last_byte| last_byte + 1)
+            .unwrap_or(0),
+        )),
+        _ => Err(status_error(status)),
+      },
+    )
+  }
+
+  pub fn store_bytes_batch(&self, blobs: Vec<Bytes>) -> BoxFuture<Vec<Digest>, String> {
+    future::join_all(
+      blobs
+        .into_iter()
+        .map(|bytes| self.store_bytes(bytes))
+        .collect::<Vec<_>>(),
+    )
+    .to_boxed()
+  }
+
+  pub fn load_bytes_batch(
+    &self,
+    digests: Vec<Digest>,
+  ) -> BoxFuture<HashMap<Digest, Bytes>, String> {
+    future::join_all(
+      digests
+        .into_iter()
+        .map(|digest| {
+          self
+            .load_bytes_with(digest, |bytes| bytes)
+            .map(move |maybe_bytes| maybe_bytes.map(|bytes| (digest, bytes)))
+        })
+        .collect::<Vec<_>>(),
+    )
+    .map(|blobs| blobs.into_iter().flatten().collect())
+    .to_boxed()
+  }
+
+  ///
+  /// Returns which of the given digests the bucket is missing, by requesting the metadata of each
+  /// of their objects.
+  ///
+  pub fn list_missing_digests(&self, digests: Vec<Digest>) -> BoxFuture<HashSet<Digest>, String> {
+    future::join_all(
+      digests
+        .into_iter()
+        .map(|digest| {
+          self.request(
+            Method::GET,
+            Location::Path(self.bucket.object_path(&self.cas_object_name(digest))),
+            vec![],
+            None,
+            self.read_timeout,
+            move |status, _headers, _body| match status {
+              StatusCode::OK => Ok(None),
+              StatusCode::NOT_FOUND => Ok(Some(digest)),
+              status => Err(status_error(status)),
+            },
+          )
+        })
+        .collect::<Vec<_>>(),
+    )
+    .map(|missing| missing.into_iter().flatten().collect())
+    .to_boxed()
+  }
+
+  pub fn get_action_result(
+    &self,
+    action_digest: Digest,
+  ) -> BoxFuture<Option<bazel_protos::remote_execution::ActionResult>, String> {
+    self.request(
+      Method::GET,
+      Location::Path(format!(
+        "{}?alt=media",
+        self
+          .bucket
+          .object_path(&self.action_cache_object_name(action_digest))
+      )),
+      vec![],
+      None,
+      self.action_cache_timeout,
+      move |status, _headers, body| match status {
+        StatusCode::OK => protobuf::parse_from_bytes(&body).map(Some).map_err(|err| {
+          AttemptError::fatal(format!(
+            "Invalid ActionResult for {:?}: {:?}",
+            action_digest, err
+          ))
+        }),
+        StatusCode::NOT_FOUND => Ok(None),
+        status => Err(status_error(status)),
+      },
+    )
+  }
+
+  pub fn update_action_result(
+    &self,
+    action_digest: Digest,
+    action_result: bazel_protos::remote_execution::ActionResult,
+  ) -> BoxFuture<(), String> {
+    let bytes = match action_result.write_to_bytes() {
+      Ok(bytes) => Bytes::from(bytes),
+      Err(err) => {
+        return future::err(format!(
+          "Error serializing ActionResult for {:?}: {:?}",
+          action_digest, err
+        ))
+        .to_boxed();
+      }
+    };
+    self.upload(
+      &self.action_cache_object_name(action_digest),
+      bytes,
+      self.action_cache_timeout,
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Bucket, ServiceAccount};
+  use mock::StubGcs;
+  use std::path::PathBuf;
+  use tempfile::TempDir;
+  use testutil::data::TestData;
+
+  #[test]
+  fn parse_bucket() {
+    assert_eq!(
+      Bucket::parse("gs://cache/pants/v1?chunk_size=8MiB", None),
+      Ok(Bucket {
+        endpoint: "https://storage.googleapis.com".to_owned(),
+        name: "cache".to_owned(),
+        prefix: Some("pants/v1".to_owned()),
+        credentials: None,
+        chunk_size_bytes: 8 * 1024 * 1024,
+      })
+    );
+    assert_eq!(
+      Bucket::parse(
+        "gs://cache?endpoint=http://localhost:4443/&credentials=/etc/key.json",
+        Some(PathBuf::from("/default.json"))
+      ),
+      Ok(Bucket {
+        endpoint: "http://localhost:4443".to_owned(),
+        name: "cache".to_owned(),
+        prefix: None,
+        credentials: Some(PathBuf::from("/etc/key.json")),
+        chunk_size_bytes: 16 * 1024 * 1024,
+      })
+    );
+    assert_eq!(
+      Bucket::parse("gs://cache", Some(PathBuf::from("/default.json"))).map(|b| b.credentials),
+      Ok(Some(PathBuf::from("/default.json")))
+    );
+    Bucket::parse("gs://", None).expect_err("Want Err for missing bucket");
+    Bucket::parse("gs://cache?chunk_size=1000", None).expect_err("Want Err for uneven chunk size");
+    Bucket::parse("gs://cache?colour=blue", None).expect_err("Want Err for unknown parameter");
+  }
+
+  #[test]
+  fn object_paths() {
+    let fingerprint = TestData::roland().fingerprint();
+    let bucket = Bucket::parse("gs://cache/pants/", None).unwrap();
+    let name = bucket.object_name(&Some("main".to_owned()), "cas", fingerprint);
+    assert_eq!(name, format!("pants/main/cas/{}", fingerprint));
+    // Object names are a single segment of the path.
+    assert_eq!(
+      bucket.object_path(&name),
+      format!("/storage/v1/b/cache/o/pants%2Fmain%2Fcas%2F{}", fingerprint)
+    );
+    assert_eq!(
+      bucket.upload_path(&name, "media"),
+      format!(
+        "/upload/storage/v1/b/cache/o?uploadType=media&name=pants%2Fmain%2Fcas%2F{}",
+        fingerprint
+      )
+    );
+  }
+
+  #[test]
+  fn service_account_assertion() {
+    let gcs = StubGcs::empty();
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("key.json");
+    std::fs::write(&path, gcs.service_account_key()).unwrap();
+    let service_account = ServiceAccount::from_key_file(&path).unwrap();
+
+    let assertion = service_account.assertion(1_000_000).unwrap();
+    let parts = assertion.split('.').collect::<Vec<_>>();
+    assert_eq!(parts.len(), 3);
+    let decode = |part: &str| base64::decode_config(part, base64::URL_SAFE_NO_PAD).unwrap();
+    let claims: serde_json::Value = serde_json::from_slice(&decode(parts[1])).unwrap();
+    assert_eq!(
+      claims,
+      serde_json::json!({
+        "iss": "pants@stub.iam.gserviceaccount.com",
+        "scope": "https://www.googleapis.com/auth/devstorage.read_write",
+        "aud": gcs.token_uri(),
+        "iat": 1_000_000,
+        "exp": 1_003_600,
+      })
+    );
+    // Signed with a 2048 bit key.
+    assert_eq!(decode(parts[2]).len(), 256);
+  }
+
+  #[test]
+  fn invalid_service_account_keys() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("key.json");
+    let key = |key_type: &str, private_key: &str| {
+      serde_json::json!({
+        "type": key_type,
+        "client_email": "pants@stub.iam.gserviceaccount.com",
+        "private_key": private_key,
+        "token_uri": "https://oauth2.googleapis.com/token",
+      })
+      .to_string()
+    };
+    std::fs::write(&path, key("authorized_user", "")).unwrap();
+    assert!(ServiceAccount::from_key_file(&path).is_err());
+    std::fs::write(&path, key("service_account", "abc")).unwrap();
+    assert!(ServiceAccount::from_key_file(&path).is_err());
+    std::fs::write(&path, "{}").unwrap();
+    assert!(ServiceAccount::from_key_file(&path).is_err());
+    assert!(ServiceAccount::from_key_file(&dir.path().join("missing.json")).is_err());
+  }
+}
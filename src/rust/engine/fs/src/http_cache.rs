@@ -0,0 +1,479 @@
+// A remote store which speaks the simple HTTP caching protocol of Bazel (as served by bazel-remote,
+// nginx with WebDAV, and many CDN-backed caches) rather than gRPC: blobs are uploaded with PUT and
+// downloaded with GET at `/cas/<sha256>`, and serialized ActionResults are likewise stored at
+// `/ac/<sha256 of the Action>`.
+
+use crate::{BackoffConfig, BearerToken, ClientCertificate, LoadBalancing, Proxy};
+
+use bazel_protos;
+use boxfuture::{BoxFuture, Boxable};
+use bytes::Bytes;
+use digest::{Digest as DigestTrait, FixedOutput};
+use futures::{future, Future, Stream};
+use futures_timer::Delay;
+use hashing::{Digest, Fingerprint};
+use log::debug;
+use protobuf::Message;
+use reqwest::header::AUTHORIZATION;
+use reqwest::r#async::Client;
+use reqwest::{Method, StatusCode};
+use serverset::{AttemptError, Retry, RetryPolicy, Serverset};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+// The backoff before the first retry of a failed request, which doubles with each further retry up
+// to the max.
+const INITIAL_REQUEST_BACKOFF_MILLIS: u64 = 100;
+const MAX_REQUEST_BACKOFF_MILLIS: u64 = 5000;
+
+///
+/// Whether the given remote store address is the URL of an HTTP cache, rather than the address of
+/// a gRPC server.
+///
+pub fn is_http_cache_url(address: &str) -> bool {
+  address.starts_with("http://") || address.starts_with("https://")
+}
+
+///
+/// Which of the two namespaces of an HTTP cache a request is for.
+///
+#[derive(Clone, Copy, Debug)]
+enum Namespace {
+  Cas,
+  ActionCache,
+}
+
+impl Namespace {
+  fn path(self) -> &'static str {
+    match self {
+      Namespace::Cas => "cas",
+      Namespace::ActionCache => "ac",
+    }
+  }
+}
+
+///
+/// An HTTP cache, or several interchangeable ones, between which requests are balanced as they
+/// are between gRPC servers. The instance name (if any) is used as a prefix of every path, which is
+/// how caches like bazel-remote separate instances.
+///
+/// HTTP caches don't support batching, or querying which of many blobs are missing, so each blob
+/// is sent, fetched or checked for with its own request.
+///
+#[derive(Clone)]
+pub struct ByteStore {
+  instance_name: Option<String>,
+  upload_timeout: Duration,
+  read_timeout: Duration,
+  action_cache_timeout: Duration,
+  retry_policy: RetryPolicy,
+  client: Client,
+  serverset: Serverset<String>,
+  oauth_bearer_token: Option<BearerToken>,
+  timer_handle: futures_timer::TimerHandle,
+}
+
+impl ByteStore {
+  pub fn new(
+    cache_urls: &[String],
+    instance_name: Option<String>,
+    root_ca_certs: &Option<Vec<u8>>,
+    client_certificate: &Option<ClientCertificate>,
+    proxy: &Option<Proxy>,
+    oauth_bearer_token: Option<BearerToken>,
+    upload_timeout: Duration,
+    read_timeout: Duration,
+    action_cache_timeout: Duration,
+    backoff_config: BackoffConfig,
+    load_balancing: LoadBalancing,
+    rpc_retries: usize,
+    futures_timer_thread: futures_timer::TimerHandle,
+  ) -> Result<ByteStore, String> {
+    let client = client(root_ca_certs, client_certificate, proxy)?;
+
+    let serverset = Serverset::new(
+      cache_urls
+        .iter()
+        .map(|url| url.trim_end_matches('/').to_owned())
+        .collect(),
+      backoff_config,
+      load_balancing,
+      futures_timer_thread.clone(),
+    )?;
+
+    Ok(ByteStore {
+      instance_name,
+      upload_timeout,
+      read_timeout,
+      action_cache_timeout,
+      retry_policy: retry_policy(rpc_retries)?,
+      client,
+      serverset,
+      oauth_bearer_token,
+      timer_handle: futures_timer_thread,
+    })
+  }
+
+  pub fn with_instance_name(&self, instance_name: Option<String>) -> ByteStore {
+    ByteStore {
+      instance_name,
+      ..self.clone()
+    }
+  }
+
+  pub fn instance_name(&self) -> Option<String> {
+    self.instance_name.clone()
+  }
+
+  fn url(&self, cache_url: &str, namespace: Namespace, fingerprint: Fingerprint) -> String {
+    match self.instance_name {
+      Some(ref instance_name) => format!(
+        "{}/{}/{}/{}",
+        cache_url,
+        instance_name,
+        namespace.path(),
+        fingerprint
+      ),
+      None => format!("{}/{}/{}", cache_url, namespace.path(), fingerprint),
+    }
+  }
+
+  ///
+  /// Makes a request for the given entry of one of the caches (retrying failures which are likely
+  /// to be transient), and converts the status and body of the response with `f`.
+  ///
+  fn request<
+    Value: Send + 'static,
+    F: Fn(StatusCode, Bytes) -> Result<Value, AttemptError> + Send + Sync + Clone + 'static,
+  >(
+    &self,
+    method: Method,
+    namespace: Namespace,
+    fingerprint: Fingerprint,
+    body: Option<Bytes>,
+    timeout: Duration,
+    f: F,
+  ) -> BoxFuture<Value, String> {
+    let store = self.clone();
+    Retry(self.serverset.clone())
+      .with_policy(
+        move |cache_url: String| {
+          let url = store.url(&cache_url, namespace, fingerprint);
+          let description = format!("{} {}", method, url);
+          let mut request = store.client.request(method.clone(), url.as_str());
+          if let Some(ref oauth_bearer_token) = store.oauth_bearer_token {
+            request = request.header(AUTHORIZATION, oauth_bearer_token.authorization_header());
+          }
+          if let Some(ref body) = body {
+            request = request.body(body.clone());
+          }
+          let description2 = description.clone();
+          let response = request
+            .send()
+            .and_then(|response| {
+              let status = response.status();
+              response
+                .into_body()
+                .concat2()
+                .map(move |body| (status, Bytes::from(&body[..])))
+            })
+            .map_err(move |err| AttemptError::retryable(format!("{}: {}", description2, err)));
+          let store = store.clone();
+          let f = f.clone();
+          with_timeout(&store.timer_handle, response, timeout, description.clone()).and_then(
+            move |(status, body)| {
+              // Rejections as unauthenticated are worth retrying if they were because the token
+              // had expired.
+              if status == StatusCode::UNAUTHORIZED && store.refresh_bearer_token() {
+                return Err(AttemptError::retryable(format!(
+                  "{}: unauthorized, so refreshed oauth bearer token",
+                  description
+                )));
+              }
+              f(status, body).map_err(|err| AttemptError {
+                message: format!("{}: {}", description, err.message),
+                ..err
+              })
+            },
+          )
+        },
+        self.retry_policy,
+      )
+      .map(|(value, retries)| {
+        if retries > 0 {
+          debug!("HTTP cache request succeeded after {} retries", retries);
+        }
+        value
+      })
+      .to_boxed()
+  }
+
+  fn refresh_bearer_token(&self) -> bool {
+    match self.oauth_bearer_token {
+      Some(ref oauth_bearer_token) => match oauth_bearer_token.refresh() {
+        Ok(changed) => changed,
+        Err(err) => {
+          debug!("Failed to refresh oauth bearer token: {}", err);
+          false
+        }
+      },
+      None => false,
+    }
+  }
+
+  ///
+  /// Downloads the blob with the given digest, if the cache has it. The content is verified against
+  /// the digest, because unlike a CAS, an HTTP cache is often just a key-value store (or a CDN in
+  /// front of one) which doesn't check what it serves.
+  ///
+  pub fn load_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + Clone + 'static>(
+    &self,
+    digest: Digest,
+    f: F,
+  ) -> BoxFuture<Option<T>, String> {
+    self.request(
+      Method::GET,
+      Namespace::Cas,
+      digest.0,
+      None,
+      self.read_timeout,
+      move |status, body| match status {
+        StatusCode::OK => {
+          let actual_digest = digest_of(&body);
+          if actual_digest == digest {
+            Ok(Some(f(body)))
+          } else {
+            // A corrupt (e.g. truncated) response may succeed on a retry, or from another cache.
+            Err(AttemptError::retryable(format!(
+              "HTTP cache gave wrong digest: expected {:?}, got {:?}",
+              digest, actual_digest
+            )))
+          }
+        }
+        StatusCode::NOT_FOUND => Ok(None),
+        status => Err(status_error(status)),
+      },
+    )
+  }
+
+  pub fn store_bytes(&self, bytes: Bytes) -> BoxFuture<Digest, String> {
+    let digest = digest_of(&bytes);
+    self.request(
+      Method::PUT,
+      Namespace::Cas,
+      digest.0,
+      Some(bytes),
+      self.upload_timeout,
+      move |status, _body| {
+        if status.is_success() {
+          Ok(digest)
+        } else {
+          Err(status_error(status))
+        }
+      },
+    )
+  }
+
+  pub fn store_bytes_batch(&self, blobs: Vec<Bytes>) -> BoxFuture<Vec<Digest>, String> {
+    future::join_all(
+      blobs
+        .into_iter()
+        .map(|bytes| self.store_bytes(bytes))
+        .collect::<Vec<_>>(),
+    )
+    .to_boxed()
+  }
+
+  pub fn load_bytes_batch(
+    &self,
+    digests: Vec<Digest>,
+  ) -> BoxFuture<HashMap<Digest, Bytes>, String> {
+    future::join_all(
+      digests
+        .into_iter()
+        .map(|digest| {
+          self
+            .load_bytes_with(digest, |bytes| bytes)
+            .map(move |maybe_bytes| maybe_bytes.map(|bytes| (digest, bytes)))
+        })
+        .collect::<Vec<_>>(),
+    )
+    .map(|blobs| blobs.into_iter().flatten().collect())
+    .to_boxed()
+  }
+
+  ///
+  /// Returns which of the given digests the cache is missing, by checking for each of them with a
+  /// HEAD request.
+  ///
+  pub fn list_missing_digests(&self, digests: Vec<Digest>) -> BoxFuture<HashSet<Digest>, String> {
+    future::join_all(
+      digests
+        .into_iter()
+        .map(|digest| {
+          self.request(
+            Method::HEAD,
+            Namespace::Cas,
+            digest.0,
+            None,
+            self.read_timeout,
+            move |status, _body| match status {
+              StatusCode::OK => Ok(None),
+              StatusCode::NOT_FOUND => Ok(Some(digest)),
+              status => Err(status_error(status)),
+            },
+          )
+        })
+        .collect::<Vec<_>>(),
+    )
+    .map(|missing| missing.into_iter().flatten().collect())
+    .to_boxed()
+  }
+
+  pub fn get_action_result(
+    &self,
+    action_digest: Digest,
+  ) -> BoxFuture<Option<bazel_protos::remote_execution::ActionResult>, String> {
+    self.request(
+      Method::GET,
+      Namespace::ActionCache,
+      action_digest.0,
+      None,
+      self.action_cache_timeout,
+      move |status, body| match status {
+        StatusCode::OK => protobuf::parse_from_bytes(&body).map(Some).map_err(|err| {
+          AttemptError::fatal(format!(
+            "Invalid ActionResult for {:?}: {:?}",
+            action_digest, err
+          ))
+        }),
+        StatusCode::NOT_FOUND => Ok(None),
+        status => Err(status_error(status)),
+      },
+    )
+  }
+
+  pub fn update_action_result(
+    &self,
+    action_digest: Digest,
+    action_result: bazel_protos::remote_execution::ActionResult,
+  ) -> BoxFuture<(), String> {
+    let bytes = match action_result.write_to_bytes() {
+      Ok(bytes) => Bytes::from(bytes),
+      Err(err) => {
+        return future::err(format!(
+          "Error serializing ActionResult for {:?}: {:?}",
+          action_digest, err
+        ))
+        .to_boxed();
+      }
+    };
+    self.request(
+      Method::PUT,
+      Namespace::ActionCache,
+      action_digest.0,
+      Some(bytes),
+      self.action_cache_timeout,
+      |status, _body| {
+        if status.is_success() {
+          Ok(())
+        } else {
+          Err(status_error(status))
+        }
+      },
+    )
+  }
+}
+
+///
+/// An HTTP client, which trusts the given root CA certs (in addition to the system's), identifies
+/// itself with the given client certificate, and connects via the given proxy.
+///
+pub(crate) fn client(
+  root_ca_certs: &Option<Vec<u8>>,
+  client_certificate: &Option<ClientCertificate>,
+  proxy: &Option<Proxy>,
+) -> Result<Client, String> {
+  let mut builder = Client::builder();
+  if let Some(ref root_ca_certs) = root_ca_certs {
+    builder = builder.add_root_certificate(
+      reqwest::Certificate::from_pem(root_ca_certs)
+        .map_err(|err| format!("Error parsing root CA certs: {}", err))?,
+    );
+  }
+  if let Some(ref client_certificate) = client_certificate {
+    let mut pem = client_certificate.cert_chain.clone();
+    pem.extend_from_slice(&client_certificate.private_key);
+    builder = builder.identity(
+      reqwest::Identity::from_pem(&pem)
+        .map_err(|err| format!("Error parsing client certificate: {}", err))?,
+    );
+  }
+  if let Some(ref proxy) = proxy {
+    builder = builder.proxy(
+      reqwest::Proxy::all(proxy.url())
+        .map_err(|err| format!("Invalid proxy {:?}: {}", proxy.url(), err))?,
+    );
+  }
+  builder
+    .build()
+    .map_err(|err| format!("Error creating HTTP client: {}", err))
+}
+
+///
+/// The policy for retrying failed requests, up to the given number of times.
+///
+pub(crate) fn retry_policy(retries: usize) -> Result<RetryPolicy, String> {
+  RetryPolicy::new(
+    Duration::from_millis(INITIAL_REQUEST_BACKOFF_MILLIS),
+    Duration::from_millis(MAX_REQUEST_BACKOFF_MILLIS),
+    retries + 1,
+  )
+}
+
+///
+/// Fails the given request (retryably) if it doesn't complete within the timeout.
+///
+pub(crate) fn with_timeout<Value: Send + 'static>(
+  timer_handle: &futures_timer::TimerHandle,
+  future: impl Future<Item = Value, Error = AttemptError> + Send + 'static,
+  timeout: Duration,
+  description: String,
+) -> BoxFuture<Value, AttemptError> {
+  let deadline = Delay::new_handle(Instant::now() + timeout, timer_handle.clone()).then(
+    move |_| -> Result<Value, AttemptError> {
+      Err(AttemptError::retryable(format!(
+        "{}: timed out after {:?}",
+        description, timeout
+      )))
+    },
+  );
+  future
+    .select(deadline)
+    .map(|(value, _)| value)
+    .map_err(|(err, _)| err)
+    .to_boxed()
+}
+
+pub(crate) fn digest_of(bytes: &Bytes) -> Digest {
+  let mut hasher = Sha256::default();
+  hasher.input(bytes);
+  Digest(
+    Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice()),
+    bytes.len(),
+  )
+}
+
+///
+/// The failure for a response with an unexpected status, which is worth retrying if the cache was
+/// overloaded or failed.
+///
+pub(crate) fn status_error(status: StatusCode) -> AttemptError {
+  let message = format!("Unexpected response with status {}", status);
+  if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+    AttemptError::retryable(message)
+  } else {
+    AttemptError::fatal(message)
+  }
+}
@@ -0,0 +1,512 @@
+// A record of the blobs which are known to be in a remote CAS (because they were uploaded to it, or
+// it reported having them), so that it needn't be asked whether it has them again each time that
+// it is ensured to have them: e.g. for every remote execution of a warm build, which would
+// otherwise check thousands of blobs which have been in the remote since the build before.
+
+use std::cmp::max;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use digest::{Digest as DigestTrait, FixedOutput};
+use hashing::Digest;
+use log::{debug, warn};
+use parking_lot::Mutex;
+use sha2::Sha256;
+use tempfile::NamedTempFile;
+
+use crate::store::EntryType;
+
+// Identifies (the version of) the format of the file which the filters are saved in.
+const MAGIC: &[u8; 8] = b"pantskb1";
+
+// The rate of false positives of a generation once it holds as many blobs as its capacity.
+const FALSE_POSITIVE_RATE: f64 = 1e-7;
+
+// Recorded blobs are saved at most this often (and when the last clone is dropped).
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+///
+/// The blobs which are known to be in a remote (per instance of the remote), recorded in bloom
+/// filters which are saved to a file, so that they outlive pantsd. Shared between clones.
+///
+/// Bloom filters can't forget blobs, but remotes do, so blobs are recorded in generations: a new
+/// generation is started whenever the current one is full, or half of the lifetime has passed since
+/// it was started, and only the current generation and the one before it are kept. Blobs are only
+/// known until the generation which they were recorded in is as old as the lifetime.
+///
+/// Bloom filters also have (rare) false positives, so a blob which the remote is missing may be
+/// treated as known, just like a blob which the remote dropped within the lifetime: remote
+/// executions re-upload any inputs which the server reports missing.
+///
+#[derive(Clone)]
+pub struct KnownRemoteBlobs {
+  inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+  path: PathBuf,
+  capacity: usize,
+  lifetime: Duration,
+  current: Generation,
+  previous: Option<Generation>,
+  unsaved: bool,
+  last_saved: Instant,
+}
+
+struct Generation {
+  // In seconds since the epoch.
+  started: u64,
+  count: usize,
+  filter: BloomFilter,
+}
+
+impl KnownRemoteBlobs {
+  ///
+  /// The known blobs of the remote with the given addresses, which are saved in the given directory
+  /// (e.g. of the local store), in a file of their own.
+  ///
+  pub fn for_remote(
+    dir: &Path,
+    cas_addresses: &[String],
+    capacity: usize,
+    lifetime: Duration,
+  ) -> KnownRemoteBlobs {
+    let mut cas_addresses = cas_addresses.to_vec();
+    cas_addresses.sort();
+    let mut hasher = Sha256::default();
+    hasher.input(cas_addresses.join("\n").as_bytes());
+    let name = hex::encode(hasher.fixed_result().as_slice());
+    KnownRemoteBlobs::load(
+      &dir.join("known_remote_blobs").join(name),
+      capacity,
+      lifetime,
+      SystemTime::now(),
+    )
+  }
+
+  ///
+  /// Loads the known blobs which were saved to the given path, or starts with none if there are
+  /// none (or they were saved with a different capacity).
+  ///
+  fn load(path: &Path, capacity: usize, lifetime: Duration, now: SystemTime) -> KnownRemoteBlobs {
+    let capacity = max(capacity, 1);
+    let now = seconds_since_epoch(now);
+    let (current, previous) = match fs::read(path) {
+      Ok(bytes) => parse(&bytes, capacity).unwrap_or_else(|| {
+        debug!(
+          "Ignoring known remote blobs in {:?}, which were saved differently",
+          path
+        );
+        (Generation::new(capacity, now), None)
+      }),
+      Err(_) => (Generation::new(capacity, now), None),
+    };
+    let mut inner = Inner {
+      path: path.to_owned(),
+      capacity,
+      lifetime,
+      current,
+      previous,
+      unsaved: false,
+      last_saved: Instant::now(),
+    };
+    inner.advance(now);
+    KnownRemoteBlobs {
+      inner: Arc::new(Mutex::new(inner)),
+    }
+  }
+
+  ///
+  /// The given blobs, except those which are known to be in the remote (with the given instance
+  /// name).
+  ///
+  pub fn unknown(
+    &self,
+    instance_name: &Option<String>,
+    blobs: &HashMap<Digest, EntryType>,
+    now: SystemTime,
+  ) -> HashMap<Digest, EntryType> {
+    let mut inner = self.inner.lock();
+    inner.advance(seconds_since_epoch(now));
+    blobs
+      .iter()
+      .filter(|(digest, _)| !inner.contains(&key(instance_name, **digest)))
+      .map(|(digest, entry_type)| (*digest, *entry_type))
+      .collect()
+  }
+
+  ///
+  /// Records that the remote (with the given instance name) was just ensured to have the given
+  /// blobs.
+  ///
+  pub fn record<'a, I: IntoIterator<Item = &'a Digest>>(
+    &self,
+    instance_name: &Option<String>,
+    digests: I,
+    now: SystemTime,
+  ) {
+    let mut inner = self.inner.lock();
+    let now = seconds_since_epoch(now);
+    inner.advance(now);
+    for digest in digests {
+      let blob_key = key(instance_name, *digest);
+      if !inner.current.filter.contains(&blob_key) {
+        if inner.current.count >= inner.capacity {
+          inner.start_generation(now);
+        }
+        inner.current.filter.insert(&blob_key);
+        inner.current.count += 1;
+        inner.unsaved = true;
+      }
+    }
+    if inner.unsaved && inner.last_saved.elapsed() >= SAVE_INTERVAL {
+      inner.save();
+    }
+  }
+}
+
+impl Inner {
+  ///
+  /// Starts a new generation if the current one is half of the lifetime old, and forgets the
+  /// generation before if it is as old as the lifetime.
+  ///
+  fn advance(&mut self, now: u64) {
+    let lifetime = self.lifetime.as_secs();
+    if age(self.current.started, now) >= lifetime / 2 {
+      self.start_generation(now);
+    }
+    let previous_expired = match self.previous {
+      Some(ref previous) => age(previous.started, now) >= lifetime,
+      None => false,
+    };
+    if previous_expired {
+      self.previous = None;
+      self.unsaved = true;
+    }
+  }
+
+  fn start_generation(&mut self, now: u64) {
+    let previous = mem::replace(&mut self.current, Generation::new(self.capacity, now));
+    self.previous = Some(previous);
+    self.unsaved = true;
+  }
+
+  fn contains(&self, key: &[u8]) -> bool {
+    self.current.filter.contains(key)
+      || self
+        .previous
+        .as_ref()
+        .map(|previous| previous.filter.contains(key))
+        .unwrap_or(false)
+  }
+
+  ///
+  /// Failing to save is not an error, because the blobs are only forgotten.
+  ///
+  fn save(&mut self) {
+    self.last_saved = Instant::now();
+    if let Err(err) = self.try_save() {
+      warn!(
+        "Error saving known remote blobs to {:?}: {}",
+        self.path, err
+      );
+      return;
+    }
+    self.unsaved = false;
+  }
+
+  fn try_save(&self) -> Result<(), String> {
+    let dir = self
+      .path
+      .parent()
+      .ok_or_else(|| "Path has no parent".to_owned())?;
+    fs::create_dir_all(dir).map_err(|err| format!("Error making {:?}: {}", dir, err))?;
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 16 * self.current.filter.bits.len() + 64);
+    bytes.extend_from_slice(MAGIC);
+    put_u64(&mut bytes, self.capacity as u64);
+    self.current.write_to(&mut bytes);
+    match self.previous {
+      Some(ref previous) => {
+        put_u64(&mut bytes, 1);
+        previous.write_to(&mut bytes);
+      }
+      None => put_u64(&mut bytes, 0),
+    }
+    // Replace the file in one step, because other processes may be reading it.
+    let mut tmp = NamedTempFile::new_in(dir)
+      .map_err(|err| format!("Error making temporary file in {:?}: {}", dir, err))?;
+    tmp
+      .write_all(&bytes)
+      .map_err(|err| format!("Error writing: {}", err))?;
+    tmp
+      .persist(&self.path)
+      .map(|_| ())
+      .map_err(|err| format!("Error replacing file: {}", err.error))
+  }
+}
+
+impl Drop for Inner {
+  fn drop(&mut self) {
+    if self.unsaved {
+      self.save();
+    }
+  }
+}
+
+impl Generation {
+  fn new(capacity: usize, now: u64) -> Generation {
+    Generation {
+      started: now,
+      count: 0,
+      filter: BloomFilter::new(capacity),
+    }
+  }
+
+  fn write_to(&self, bytes: &mut Vec<u8>) {
+    put_u64(bytes, self.started);
+    put_u64(bytes, self.count as u64);
+    for word in &self.filter.bits {
+      put_u64(bytes, *word);
+    }
+  }
+}
+
+///
+/// A bloom filter sized to hold the given number of keys with at most FALSE_POSITIVE_RATE. Keys
+/// must be uniformly distributed (e.g. hashes), because they are used directly as the hashes which
+/// pick the bits of each key.
+///
+struct BloomFilter {
+  bits: Vec<u64>,
+  hash_count: u64,
+}
+
+impl BloomFilter {
+  fn new(capacity: usize) -> BloomFilter {
+    let ln_2 = 2f64.ln();
+    let bit_count = (-(capacity as f64) * FALSE_POSITIVE_RATE.ln() / (ln_2 * ln_2)).ceil() as usize;
+    let word_count = max((bit_count + 63) / 64, 1);
+    let hash_count = ((word_count * 64) as f64 / capacity as f64 * ln_2).round() as u64;
+    BloomFilter {
+      bits: vec![0; word_count],
+      hash_count: max(hash_count, 1),
+    }
+  }
+
+  fn insert(&mut self, key: &[u8]) {
+    for bit in self.bit_indices(key) {
+      self.bits[bit / 64] |= 1 << (bit % 64);
+    }
+  }
+
+  fn contains(&self, key: &[u8]) -> bool {
+    self
+      .bit_indices(key)
+      .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+  }
+
+  ///
+  /// The bits of the key, picked by double hashing the first two words of the key.
+  ///
+  fn bit_indices(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+    let bit_count = (self.bits.len() * 64) as u64;
+    let first = get_u64(&key[0..8]);
+    // Odd, so that it is never 0.
+    let second = get_u64(&key[8..16]) | 1;
+    (0..self.hash_count)
+      .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % bit_count) as usize)
+  }
+}
+
+///
+/// The key of a blob in an instance of a remote.
+///
+fn key(instance_name: &Option<String>, digest: Digest) -> Vec<u8> {
+  let mut hasher = Sha256::default();
+  match instance_name {
+    Some(instance_name) => {
+      hasher.input(&[1u8]);
+      hasher.input(instance_name.as_bytes());
+    }
+    None => hasher.input(&[0u8]),
+  }
+  hasher.input(&digest.0.as_bytes()[..]);
+  hasher.input(&(digest.1 as u64).to_le_bytes());
+  hasher.fixed_result().as_slice().to_vec()
+}
+
+///
+/// Parses the generations which were saved, if they were saved with the given capacity.
+///
+fn parse(bytes: &[u8], capacity: usize) -> Option<(Generation, Option<Generation>)> {
+  if !bytes.starts_with(MAGIC) {
+    return None;
+  }
+  let mut words = bytes[MAGIC.len()..].chunks(8).map(get_u64);
+  if words.next()? != capacity as u64 {
+    return None;
+  }
+  let parse_generation = |words: &mut dyn Iterator<Item = u64>| {
+    let mut generation = Generation::new(capacity, words.next()?);
+    generation.count = words.next()? as usize;
+    for word in generation.filter.bits.iter_mut() {
+      *word = words.next()?;
+    }
+    Some(generation)
+  };
+  let current = parse_generation(&mut words)?;
+  let previous = match words.next()? {
+    0 => None,
+    _ => Some(parse_generation(&mut words)?),
+  };
+  if words.next().is_some() {
+    return None;
+  }
+  Some((current, previous))
+}
+
+fn put_u64(bytes: &mut Vec<u8>, word: u64) {
+  bytes.extend_from_slice(&word.to_le_bytes());
+}
+
+///
+/// Reads a little-endian u64 (zero-padded, if there are fewer than 8 bytes).
+///
+fn get_u64(bytes: &[u8]) -> u64 {
+  let mut word = [0; 8];
+  let len = bytes.len().min(8);
+  word[..len].copy_from_slice(&bytes[..len]);
+  u64::from_le_bytes(word)
+}
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or(0)
+}
+
+///
+/// Clocks may go backwards, in which case generations are treated as having just started.
+///
+fn age(started: u64, now: u64) -> u64 {
+  now.saturating_sub(started)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::KnownRemoteBlobs;
+  use crate::store::EntryType;
+  use hashing::Digest;
+  use std::collections::HashMap;
+  use std::time::{Duration, SystemTime};
+  use tempfile::TempDir;
+  use testutil::data::{TestData, TestDirectory};
+
+  fn blobs(digests: &[(Digest, EntryType)]) -> HashMap<Digest, EntryType> {
+    digests.iter().cloned().collect()
+  }
+
+  #[test]
+  fn recorded_blobs_are_known_per_instance() {
+    let dir = TempDir::new().unwrap();
+    let now = SystemTime::now();
+    let known = KnownRemoteBlobs::load(
+      &dir.path().join("known"),
+      1000,
+      Duration::from_secs(60 * 60),
+      now,
+    );
+    let roland = (TestData::roland().digest(), EntryType::File);
+    let catnip = (TestData::catnip().digest(), EntryType::File);
+    let directory = (
+      TestDirectory::containing_roland().digest(),
+      EntryType::Directory,
+    );
+    let all = blobs(&[roland, catnip, directory]);
+
+    assert_eq!(known.unknown(&None, &all, now), all);
+    known.record(&None, &[roland.0, directory.0], now);
+    assert_eq!(known.unknown(&None, &all, now), blobs(&[catnip]));
+    assert_eq!(known.unknown(&Some("mac".to_owned()), &all, now), all);
+  }
+
+  #[test]
+  fn blobs_are_forgotten_after_the_lifetime() {
+    let dir = TempDir::new().unwrap();
+    let start = SystemTime::now();
+    let known = KnownRemoteBlobs::load(
+      &dir.path().join("known"),
+      1000,
+      Duration::from_secs(60),
+      start,
+    );
+    let roland = (TestData::roland().digest(), EntryType::File);
+    let catnip = (TestData::catnip().digest(), EntryType::File);
+    let both = blobs(&[roland, catnip]);
+
+    known.record(&None, &[roland.0], start);
+    known.record(&None, &[catnip.0], start + Duration::from_secs(40));
+    assert_eq!(
+      known.unknown(&None, &both, start + Duration::from_secs(59)),
+      blobs(&[])
+    );
+    // roland's generation is as old as the lifetime, but catnip was recorded in the next.
+    assert_eq!(
+      known.unknown(&None, &both, start + Duration::from_secs(60)),
+      blobs(&[roland])
+    );
+    assert_eq!(
+      known.unknown(&None, &both, start + Duration::from_secs(100)),
+      both
+    );
+  }
+
+  #[test]
+  fn full_generations_are_replaced() {
+    let dir = TempDir::new().unwrap();
+    let now = SystemTime::now();
+    let known = KnownRemoteBlobs::load(
+      &dir.path().join("known"),
+      1,
+      Duration::from_secs(60 * 60),
+      now,
+    );
+    let roland = (TestData::roland().digest(), EntryType::File);
+    let catnip = (TestData::catnip().digest(), EntryType::File);
+    let robin = (TestData::robin().digest(), EntryType::File);
+    let all = blobs(&[roland, catnip, robin]);
+
+    known.record(&None, &[roland.0, catnip.0, robin.0], now);
+    assert_eq!(known.unknown(&None, &all, now), blobs(&[roland]));
+  }
+
+  #[test]
+  fn known_blobs_are_saved() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("known");
+    let now = SystemTime::now();
+    let lifetime = Duration::from_secs(60 * 60);
+    let roland = (TestData::roland().digest(), EntryType::File);
+    let catnip = (TestData::catnip().digest(), EntryType::File);
+    let both = blobs(&[roland, catnip]);
+
+    {
+      let known = KnownRemoteBlobs::load(&path, 1000, lifetime, now);
+      known.record(&None, &[roland.0], now);
+      // Saved once the last clone is dropped.
+      let _clone = known.clone();
+    }
+    let known = KnownRemoteBlobs::load(&path, 1000, lifetime, now);
+    assert_eq!(known.unknown(&None, &both, now), blobs(&[catnip]));
+
+    // Blobs which were saved with a different capacity are ignored.
+    let known = KnownRemoteBlobs::load(&path, 2000, lifetime, now);
+    assert_eq!(known.unknown(&None, &both, now), both);
+  }
+}
@@ -26,16 +26,45 @@
 // Arc<Mutex> can be more clear than needing to grok Orderings:
 #![allow(clippy::mutex_atomic)]
 
+mod archive;
+mod bearer_token;
+pub use crate::bearer_token::{BearerToken, TokenSource};
+mod channel_policy;
+pub use crate::channel_policy::ChannelPolicy;
+mod file_lock;
+pub use crate::file_lock::FileLock;
+mod gcs;
+pub use crate::gcs::is_gcs_url;
 mod glob_matching;
 pub use crate::glob_matching::GlobMatching;
+mod http_cache;
+pub use crate::http_cache::is_http_cache_url;
+mod known_remote_blobs;
+pub use crate::known_remote_blobs::KnownRemoteBlobs;
+mod mmap;
+mod proxy;
+mod reflink;
+pub use crate::proxy::Proxy;
+mod remote_leases;
+mod request_metadata;
+mod s3;
+mod small_blob_cache;
+pub use crate::request_metadata::{RequestMetadata, REQUEST_METADATA_HEADER};
+pub use crate::s3::is_s3_url;
+pub use crate::small_blob_cache::SmallBlobCache;
 mod snapshot;
 pub use crate::snapshot::{
   OneOffStoreFileByDigest, Snapshot, StoreFileByDigest, EMPTY_DIGEST, EMPTY_FINGERPRINT,
 };
 mod store;
-pub use crate::store::{ShrinkBehavior, Store, UploadSummary, DEFAULT_LOCAL_STORE_GC_TARGET_BYTES};
+pub use crate::store::{
+  channel_credentials, is_retryable_rpc_error, parse_size_bytes, BrokenEntry, ClientCertificate,
+  Compression, EntryProblem, Eviction, EvictionPolicy, EvictionReason, LocalStoreOptions,
+  MaterializationStrategy, ShardStats, ShrinkBehavior, Store, StoreStats, TierRules, TieringPolicy,
+  UploadSummary, WalkEntry, DEFAULT_LOCAL_STORE_SHARD_MAP_SIZE_BYTES,
+};
 
-pub use serverset::BackoffConfig;
+pub use serverset::{BackoffConfig, LoadBalancing, RetryPolicy};
 
 use std::cmp::min;
 use std::ffi::OsStr;
@@ -617,6 +646,13 @@ impl PosixFS {
     self.ignore.is_ignored(stat)
   }
 
+  ///
+  /// The absolute path of the given file.
+  ///
+  pub fn file_path(&self, file: &File) -> PathBuf {
+    self.root.0.join(&file.path)
+  }
+
   pub fn read_file(&self, file: &File) -> impl Future<Item = FileContent, Error = io::Error> {
     let path = file.path.clone();
     let path_abs = self.root.0.join(&file.path);
@@ -0,0 +1,132 @@
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+///
+/// A read-only memory map of the whole of a (non-empty) file, which is unmapped when the last of
+/// its clones is dropped.
+///
+/// Changes which other processes make to the file while it is mapped may show through the map, so
+/// users of the map should check that the file is `unchanged` once they are done with it.
+///
+#[derive(Clone)]
+pub struct MappedFile {
+  mapping: Arc<Mapping>,
+}
+
+struct Mapping {
+  address: *mut libc::c_void,
+  len: usize,
+  file: File,
+  modified: SystemTime,
+}
+
+// The mapping is read-only, so it can be shared between threads like a slice.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl MappedFile {
+  pub fn map(file: &File) -> io::Result<MappedFile> {
+    let file = file.try_clone()?;
+    let metadata = file.metadata()?;
+    let len = metadata.len() as usize;
+    if len == 0 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Empty files can't be mapped",
+      ));
+    }
+    let address = unsafe {
+      libc::mmap(
+        ptr::null_mut(),
+        len,
+        libc::PROT_READ,
+        libc::MAP_PRIVATE,
+        file.as_raw_fd(),
+        0,
+      )
+    };
+    if address == libc::MAP_FAILED {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(MappedFile {
+      mapping: Arc::new(Mapping {
+        address,
+        len,
+        file,
+        modified: metadata.modified()?,
+      }),
+    })
+  }
+
+  ///
+  /// Whether the file still has the size and modification time that it had when it was mapped.
+  ///
+  pub fn unchanged(&self) -> io::Result<bool> {
+    let metadata = self.mapping.file.metadata()?;
+    Ok(metadata.len() as usize == self.mapping.len && metadata.modified()? == self.mapping.modified)
+  }
+}
+
+impl Deref for MappedFile {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    unsafe { slice::from_raw_parts(self.mapping.address as *const u8, self.mapping.len) }
+  }
+}
+
+impl AsRef<[u8]> for MappedFile {
+  fn as_ref(&self) -> &[u8] {
+    self
+  }
+}
+
+impl Drop for Mapping {
+  fn drop(&mut self) {
+    unsafe {
+      libc::munmap(self.address, self.len);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::MappedFile;
+  use std::fs::{File, OpenOptions};
+  use std::io::Write;
+  use tempfile::TempDir;
+
+  #[test]
+  fn map_file() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("roland");
+    std::fs::write(&path, "European Burmese").unwrap();
+
+    let mapped = MappedFile::map(&File::open(&path).unwrap()).unwrap();
+    assert_eq!(&mapped[..], b"European Burmese");
+    assert_eq!(&mapped.clone()[9..], b"Burmese");
+    assert!(mapped.unchanged().unwrap());
+
+    OpenOptions::new()
+      .append(true)
+      .open(&path)
+      .and_then(|mut file| file.write_all(b"!"))
+      .expect("Error changing file");
+    assert!(!mapped.unchanged().unwrap());
+  }
+
+  #[test]
+  fn map_empty_file() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("empty");
+    std::fs::write(&path, "").unwrap();
+    MappedFile::map(&File::open(&path).unwrap()).expect_err("Want Err for an empty file");
+  }
+
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+///
+/// Clones files (see ioctl_ficlone(2) on Linux, and clonefile(2) on macOS), so that the clone
+/// shares the blocks of the original until either is modified: a copy at the cost of a hardlink.
+/// Only some filesystems (e.g. Btrfs, XFS and APFS) support cloning, and only within themselves.
+///
+/// Whether files can be cloned from each filesystem (by device) to each other is remembered once
+/// it is known, so that clones which can't work aren't attempted again. Shared between clones.
+///
+#[derive(Clone, Default)]
+pub struct Reflinks {
+  // Keyed by the devices of the source and destination directories.
+  supported: Arc<Mutex<HashMap<(u64, u64), bool>>>,
+}
+
+impl Reflinks {
+  ///
+  /// Whether files can't be cloned from the source directory to the destination directory,
+  /// because they're on different filesystems (which is checked without attempting a clone, and
+  /// remembered), or because the filesystem is known not to support cloning.
+  ///
+  pub fn known_unsupported(&self, source_dir: &Path, destination_dir: &Path) -> io::Result<bool> {
+    let devices = (
+      source_dir.metadata()?.dev(),
+      destination_dir.metadata()?.dev(),
+    );
+    let mut supported = self.supported.lock();
+    if devices.0 != devices.1 {
+      supported.insert(devices, false);
+    }
+    Ok(supported.get(&devices) == Some(&false))
+  }
+
+  ///
+  /// Clones the source file to the destination (which must not exist), with the given mode.
+  /// Returns false (having made nothing) if the file can't be cloned because the filesystem doesn't
+  /// support cloning, or the source is on a different filesystem, in which case it should be
+  /// copied instead.
+  ///
+  pub fn clone_file(&self, source: &Path, destination: &Path, mode: u32) -> io::Result<bool> {
+    let devices = (
+      source.metadata()?.dev(),
+      parent(destination).metadata()?.dev(),
+    );
+    if devices.0 != devices.1 {
+      self.supported.lock().insert(devices, false);
+      return Ok(false);
+    }
+    if self.supported.lock().get(&devices) == Some(&false) {
+      return Ok(false);
+    }
+    match clone(source, destination, mode) {
+      Ok(()) => {
+        self.supported.lock().insert(devices, true);
+        Ok(true)
+      }
+      Err(ref err) if is_unsupported(err) => {
+        self.supported.lock().insert(devices, false);
+        Ok(false)
+      }
+      Err(err) => Err(err),
+    }
+  }
+}
+
+///
+/// The directory containing the given path: relative paths of files in the current directory have
+/// an empty parent.
+///
+fn parent(path: &Path) -> &Path {
+  path
+    .parent()
+    .filter(|parent| !parent.as_os_str().is_empty())
+    .unwrap_or_else(|| Path::new("."))
+}
+
+#[cfg(all(
+  target_os = "linux",
+  any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+  )
+))]
+fn clone(source: &Path, destination: &Path, mode: u32) -> io::Result<()> {
+  use std::fs::{File, OpenOptions};
+  use std::os::unix::fs::OpenOptionsExt;
+  use std::os::unix::io::AsRawFd;
+
+  // _IOW(0x94, 9, int), from linux/fs.h.
+  const FICLONE: libc::c_ulong = 0x4004_9409;
+
+  let source = File::open(source)?;
+  let destination_file = OpenOptions::new()
+    .create_new(true)
+    .write(true)
+    .mode(mode)
+    .open(destination)?;
+  if unsafe {
+    libc::ioctl(
+      destination_file.as_raw_fd(),
+      FICLONE as _,
+      source.as_raw_fd(),
+    )
+  } == 0
+  {
+    return Ok(());
+  }
+  let err = io::Error::last_os_error();
+  std::mem::drop(destination_file);
+  let _ = std::fs::remove_file(destination);
+  Err(err)
+}
+
+#[cfg(target_os = "macos")]
+fn clone(source: &Path, destination: &Path, mode: u32) -> io::Result<()> {
+  use std::ffi::CString;
+  use std::os::unix::ffi::OsStrExt;
+  use std::os::unix::fs::PermissionsExt;
+
+  extern "C" {
+    fn clonefile(src: *const libc::c_char, dst: *const libc::c_char, flags: u32) -> libc::c_int;
+  }
+  // From sys/clonefile.h.
+  const CLONE_NOFOLLOW: u32 = 0x0001;
+
+  let to_c_string = |path: &Path| {
+    CString::new(path.as_os_str().as_bytes())
+      .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+  };
+  let source_c_string = to_c_string(source)?;
+  let destination_c_string = to_c_string(destination)?;
+  if unsafe {
+    clonefile(
+      source_c_string.as_ptr(),
+      destination_c_string.as_ptr(),
+      CLONE_NOFOLLOW,
+    )
+  } != 0
+  {
+    return Err(io::Error::last_os_error());
+  }
+  // Clones have the mode of their source.
+  std::fs::set_permissions(destination, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(any(
+  target_os = "macos",
+  all(
+    target_os = "linux",
+    any(
+      target_arch = "x86",
+      target_arch = "x86_64",
+      target_arch = "arm",
+      target_arch = "aarch64"
+    )
+  )
+)))]
+fn clone(_source: &Path, _destination: &Path, _mode: u32) -> io::Result<()> {
+  Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+///
+/// Whether the error means that the filesystem doesn't support cloning (rather than that this
+/// particular file couldn't be cloned).
+///
+fn is_unsupported(err: &io::Error) -> bool {
+  match err.raw_os_error() {
+    Some(errno) => {
+      errno == libc::EOPNOTSUPP
+        || errno == libc::ENOTSUP
+        || errno == libc::EXDEV
+        || errno == libc::ENOTTY
+        || errno == libc::ENOSYS
+        // Linux filesystems which don't implement remapping return EINVAL.
+        || (cfg!(target_os = "linux") && errno == libc::EINVAL)
+    }
+    None => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Reflinks;
+  use std::os::unix::fs::{MetadataExt, PermissionsExt};
+  use tempfile::TempDir;
+
+  #[test]
+  fn clone_file_or_report_unsupported() {
+    let dir = TempDir::new().unwrap();
+    let source = dir.path().join("roland");
+    let destination = dir.path().join("cloned");
+    std::fs::write(&source, "European Burmese").unwrap();
+
+    let reflinks = Reflinks::default();
+    // Nothing is known until a file has been cloned (or failed to be).
+    assert!(!reflinks.known_unsupported(dir.path(), dir.path()).unwrap());
+    if reflinks
+      .clone_file(&source, &destination, 0o644)
+      .expect("Error cloning")
+    {
+      assert_eq!(std::fs::read(&destination).unwrap(), b"European Burmese");
+      assert_eq!(
+        std::fs::metadata(&destination)
+          .unwrap()
+          .permissions()
+          .mode()
+          & 0o200,
+        0o200
+      );
+      // The clone is independent of its source.
+      std::fs::write(&destination, "Tabby").unwrap();
+      assert_eq!(std::fs::read(&source).unwrap(), b"European Burmese");
+      assert!(!reflinks.known_unsupported(dir.path(), dir.path()).unwrap());
+    } else {
+      assert!(!destination.exists());
+      assert!(reflinks.known_unsupported(dir.path(), dir.path()).unwrap());
+      // Unsupported filesystems aren't attempted again.
+      assert_eq!(
+        reflinks
+          .clone_file(&source, &destination, 0o644)
+          .expect("Error cloning"),
+        false
+      );
+    }
+  }
+
+  #[test]
+  fn cross_device_clones_are_unsupported() {
+    let dir = TempDir::new().unwrap();
+    // Only checkable where a tmpfs is on a different device to the temporary directory.
+    let other_dir = match TempDir::new_in("/dev/shm") {
+      Ok(other_dir) => other_dir,
+      Err(_) => return,
+    };
+    if dir.path().metadata().unwrap().dev() == other_dir.path().metadata().unwrap().dev() {
+      return;
+    }
+    let source = dir.path().join("roland");
+    std::fs::write(&source, "European Burmese").unwrap();
+
+    let reflinks = Reflinks::default();
+    assert!(reflinks
+      .known_unsupported(dir.path(), other_dir.path())
+      .unwrap());
+    assert!(!reflinks
+      .clone_file(&source, &other_dir.path().join("cloned"), 0o644)
+      .unwrap());
+    assert!(!other_dir.path().join("cloned").exists());
+  }
+
+  #[test]
+  fn clone_missing_file_is_error() {
+    let dir = TempDir::new().unwrap();
+    Reflinks::default()
+      .clone_file(
+        &dir.path().join("missing"),
+        &dir.path().join("cloned"),
+        0o644,
+      )
+      .expect_err("Want err");
+  }
+}
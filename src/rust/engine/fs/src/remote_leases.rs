@@ -0,0 +1,187 @@
+// Tracking of the blobs which the current session has ensured that the remote CAS has, so that
+// their leases can be extended while the session runs. Shared CAS clusters may aggressively
+// garbage collect blobs which haven't been used recently, which would otherwise make the late
+// actions of long builds fail because blobs which they need have gone missing.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hashing::Digest;
+use parking_lot::Mutex;
+
+use crate::store::EntryType;
+
+///
+/// The blobs whose leases in the remote CAS are extended (by asking the remote whether it is
+/// missing them, and re-uploading any which it is) at most once per interval, per instance of the
+/// remote.
+///
+/// Shared between clones, and between the Stores for different instances of the same remote.
+///
+#[derive(Clone)]
+pub struct RemoteLeases {
+  interval: Option<Duration>,
+  instances: Arc<Mutex<HashMap<Option<String>, LeasedBlobs>>>,
+}
+
+struct LeasedBlobs {
+  blobs: HashMap<Digest, EntryType>,
+  last_extended: Instant,
+}
+
+impl RemoteLeases {
+  ///
+  /// Leases are only extended if there is an interval.
+  ///
+  pub fn new(interval: Option<Duration>) -> RemoteLeases {
+    RemoteLeases {
+      interval,
+      instances: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  ///
+  /// Records that the remote (with the given instance name) was just ensured to have the given
+  /// blobs, which extended their leases. Returns the other blobs which were leased before, if their
+  /// leases are due to be extended.
+  ///
+  pub fn record(
+    &self,
+    instance_name: Option<String>,
+    blobs: &HashMap<Digest, EntryType>,
+    now: Instant,
+  ) -> HashMap<Digest, EntryType> {
+    let interval = if let Some(interval) = self.interval {
+      interval
+    } else {
+      return HashMap::new();
+    };
+    let mut instances = self.instances.lock();
+    let leased = instances
+      .entry(instance_name)
+      .or_insert_with(|| LeasedBlobs {
+        blobs: HashMap::new(),
+        last_extended: now,
+      });
+    let due = if now.duration_since(leased.last_extended) >= interval {
+      leased.last_extended = now;
+      leased
+        .blobs
+        .iter()
+        .filter(|(digest, _)| !blobs.contains_key(digest))
+        .map(|(digest, entry_type)| (*digest, *entry_type))
+        .collect()
+    } else {
+      HashMap::new()
+    };
+    leased.blobs.extend(blobs);
+    due
+  }
+
+  ///
+  /// Forgets every leased blob, e.g. because a new session is starting, which may not need them.
+  ///
+  pub fn clear(&self) {
+    self.instances.lock().clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::RemoteLeases;
+  use crate::store::EntryType;
+  use hashing::Digest;
+  use std::collections::HashMap;
+  use std::time::{Duration, Instant};
+  use testutil::data::{TestData, TestDirectory};
+
+  fn blobs(digests: &[(Digest, EntryType)]) -> HashMap<Digest, EntryType> {
+    digests.iter().cloned().collect()
+  }
+
+  #[test]
+  fn leases_are_extended_once_per_interval() {
+    let leases = RemoteLeases::new(Some(Duration::from_secs(60)));
+    let start = Instant::now();
+    let roland = (TestData::roland().digest(), EntryType::File);
+    let catnip = (TestData::catnip().digest(), EntryType::File);
+    let directory = (
+      TestDirectory::containing_roland().digest(),
+      EntryType::Directory,
+    );
+
+    assert_eq!(leases.record(None, &blobs(&[roland]), start), blobs(&[]));
+    assert_eq!(
+      leases.record(None, &blobs(&[catnip]), start + Duration::from_secs(30)),
+      blobs(&[])
+    );
+    // Blobs which were just ensured don't need their leases extended.
+    assert_eq!(
+      leases.record(
+        None,
+        &blobs(&[directory, catnip]),
+        start + Duration::from_secs(60)
+      ),
+      blobs(&[roland])
+    );
+    assert_eq!(
+      leases.record(None, &blobs(&[]), start + Duration::from_secs(90)),
+      blobs(&[])
+    );
+    assert_eq!(
+      leases.record(None, &blobs(&[]), start + Duration::from_secs(120)),
+      blobs(&[roland, catnip, directory])
+    );
+  }
+
+  #[test]
+  fn leases_are_per_instance() {
+    let leases = RemoteLeases::new(Some(Duration::from_secs(60)));
+    let start = Instant::now();
+    let roland = (TestData::roland().digest(), EntryType::File);
+    let catnip = (TestData::catnip().digest(), EntryType::File);
+
+    leases.record(None, &blobs(&[roland]), start);
+    leases.record(Some("mac".to_owned()), &blobs(&[catnip]), start);
+    assert_eq!(
+      leases.record(
+        Some("mac".to_owned()),
+        &blobs(&[]),
+        start + Duration::from_secs(60)
+      ),
+      blobs(&[catnip])
+    );
+    assert_eq!(
+      leases.record(None, &blobs(&[]), start + Duration::from_secs(60)),
+      blobs(&[roland])
+    );
+  }
+
+  #[test]
+  fn leases_are_not_extended_without_an_interval() {
+    let leases = RemoteLeases::new(None);
+    let start = Instant::now();
+    let roland = (TestData::roland().digest(), EntryType::File);
+
+    leases.record(None, &blobs(&[roland]), start);
+    assert_eq!(
+      leases.record(None, &blobs(&[]), start + Duration::from_secs(60 * 60)),
+      blobs(&[])
+    );
+  }
+
+  #[test]
+  fn cleared_leases_are_not_extended() {
+    let leases = RemoteLeases::new(Some(Duration::from_secs(60)));
+    let start = Instant::now();
+    let roland = (TestData::roland().digest(), EntryType::File);
+
+    leases.record(None, &blobs(&[roland]), start);
+    leases.clear();
+    assert_eq!(
+      leases.record(None, &blobs(&[]), start + Duration::from_secs(60)),
+      blobs(&[])
+    );
+  }
+}
@@ -0,0 +1,127 @@
+// Metadata about the run of pants (and the action) on whose behalf each RPC to a remote server is
+// made, so that servers can group together the RPCs of each run, and of each action.
+
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use protobuf::Message;
+
+///
+/// The header which carries a serialized RequestMetadata proto, as specified by the REAPI.
+///
+pub const REQUEST_METADATA_HEADER: &str = "build.bazel.remote.execution.v2.requestmetadata-bin";
+
+const TOOL_NAME: &str = "pants";
+
+///
+/// The RequestMetadata of the current invocation of pants, which is shared between clones, so that
+/// starting a new invocation (e.g. for each run in pantsd) affects every future RPC which uses it.
+///
+#[derive(Clone)]
+pub struct RequestMetadata {
+  tool_version: String,
+  correlated_invocations_id: Option<String>,
+  tool_invocation_id: Arc<RwLock<String>>,
+}
+
+impl RequestMetadata {
+  ///
+  /// Creates RequestMetadata for a new invocation of the given version of pants, which is
+  /// optionally correlated with other invocations (e.g. of the same CI build) by the given id.
+  ///
+  pub fn new(tool_version: String, correlated_invocations_id: Option<String>) -> RequestMetadata {
+    RequestMetadata {
+      tool_version,
+      correlated_invocations_id,
+      tool_invocation_id: Arc::new(RwLock::new(new_invocation_id())),
+    }
+  }
+
+  pub fn tool_invocation_id(&self) -> String {
+    self.tool_invocation_id.read().clone()
+  }
+
+  ///
+  /// Starts a new invocation, with a new (random) tool invocation id.
+  ///
+  pub fn new_invocation(&self) {
+    *self.tool_invocation_id.write() = new_invocation_id();
+  }
+
+  ///
+  /// The RequestMetadata proto for an RPC on behalf of the given action (identified by the hash of
+  /// its digest, and with a short description of its kind), if it is on behalf of an action.
+  ///
+  pub fn to_proto(
+    &self,
+    action: Option<(&str, &str)>,
+  ) -> bazel_protos::remote_execution::RequestMetadata {
+    let mut tool_details = bazel_protos::remote_execution::ToolDetails::new();
+    tool_details.set_tool_name(TOOL_NAME.to_owned());
+    tool_details.set_tool_version(self.tool_version.clone());
+
+    let mut metadata = bazel_protos::remote_execution::RequestMetadata::new();
+    metadata.set_tool_details(tool_details);
+    metadata.set_tool_invocation_id(self.tool_invocation_id());
+    if let Some(ref correlated_invocations_id) = self.correlated_invocations_id {
+      metadata.set_correlated_invocations_id(correlated_invocations_id.clone());
+    }
+    if let Some((action_id, action_mnemonic)) = action {
+      metadata.set_action_id(action_id.to_owned());
+      metadata.set_action_mnemonic(action_mnemonic.to_owned());
+    }
+    metadata
+  }
+
+  ///
+  /// Adds the RequestMetadata header for an RPC (optionally on behalf of an action, as for
+  /// `to_proto`) to the given headers.
+  ///
+  pub fn add_header(&self, builder: &mut grpcio::MetadataBuilder, action: Option<(&str, &str)>) {
+    let bytes = self
+      .to_proto(action)
+      .write_to_bytes()
+      .expect("RequestMetadata protos can always be serialized");
+    builder
+      .add_bytes(REQUEST_METADATA_HEADER, &bytes)
+      .expect("The RequestMetadata header name is valid for binary headers");
+  }
+}
+
+fn new_invocation_id() -> String {
+  uuid::Uuid::new_v4().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::RequestMetadata;
+
+  #[test]
+  fn to_proto() {
+    let metadata = RequestMetadata::new("1.17.0".to_owned(), Some("ci-build-42".to_owned()));
+    let proto = metadata.to_proto(Some(("abc123", "javac")));
+    assert_eq!(proto.get_tool_details().get_tool_name(), "pants");
+    assert_eq!(proto.get_tool_details().get_tool_version(), "1.17.0");
+    assert_eq!(
+      proto.get_tool_invocation_id(),
+      metadata.tool_invocation_id()
+    );
+    assert_eq!(proto.get_correlated_invocations_id(), "ci-build-42");
+    assert_eq!(proto.get_action_id(), "abc123");
+    assert_eq!(proto.get_action_mnemonic(), "javac");
+
+    let proto = metadata.to_proto(None);
+    assert_eq!(proto.get_action_id(), "");
+    assert_eq!(proto.get_action_mnemonic(), "");
+  }
+
+  #[test]
+  fn new_invocation_is_shared_between_clones() {
+    let metadata = RequestMetadata::new("1.17.0".to_owned(), None);
+    let clone = metadata.clone();
+    let before = metadata.tool_invocation_id();
+    clone.new_invocation();
+    assert_ne!(metadata.tool_invocation_id(), before);
+    assert_eq!(metadata.tool_invocation_id(), clone.tool_invocation_id());
+  }
+}
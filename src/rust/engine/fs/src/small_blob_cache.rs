@@ -0,0 +1,564 @@
+// A cache of small blobs in Redis or memcached, which is consulted before the remote store. Walking
+// a tree against a high-latency CAS fetches its (small) Directories one level at a time, as well as
+// many small files, so answering those reads from an in-memory cache near the client saves most of
+// the latency of the walk.
+
+use crate::http_cache::digest_of;
+use crate::parse_size_bytes;
+
+use boxfuture::{BoxFuture, Boxable};
+use bytes::Bytes;
+use futures::Future;
+use hashing::Digest;
+use parking_lot::Mutex;
+use reqwest::Url;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+///
+/// Blobs which are larger than this (by default) aren't cached, because they are cheap to fetch
+/// from the remote relative to their size, and would crowd out the small blobs.
+///
+pub const DEFAULT_SMALL_BLOB_MAX_BYTES: usize = 64 * 1024;
+
+const DEFAULT_TTL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_TIMEOUT_MILLIS: u64 = 200;
+const DEFAULT_KEY_PREFIX: &str = "pants:cas:";
+// Connections are kept open for reuse, up to this many at once.
+const MAX_IDLE_CONNECTIONS: usize = 16;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Protocol {
+  Redis,
+  Memcached,
+}
+
+///
+/// Where the cache is, and how it is used, as given by a URL of the form
+/// `redis://[:<password>@]<host>[:<port>][/<database>][?<parameter>=<value>&...]` or
+/// `memcached://<host>[:<port>][?<parameter>=<value>&...]`, whose parameters are:
+///
+///  * `max_blob_size`: The size (e.g. `64KiB`) of the largest blob which is cached.
+///  * `ttl`: The number of seconds for which blobs are cached, or 0 to cache them until they are
+///    evicted.
+///  * `timeout`: The number of milliseconds to wait for the cache to respond (or to connect)
+///    before giving up on it, and reading from the remote store instead.
+///  * `prefix`: The prefix of the keys of blobs, which are otherwise their digests.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Config {
+  protocol: Protocol,
+  address: String,
+  password: Option<String>,
+  database: Option<u32>,
+  max_blob_bytes: usize,
+  ttl_secs: u64,
+  timeout: Duration,
+  key_prefix: String,
+}
+
+impl Config {
+  fn parse(url: &str) -> Result<Config, String> {
+    let invalid = |reason: &str| format!("Invalid small blob cache URL {:?}: {}", url, reason);
+    let parsed = Url::parse(url).map_err(|err| invalid(&err.to_string()))?;
+    let (protocol, default_port) = match parsed.scheme() {
+      "redis" => (Protocol::Redis, 6379),
+      "memcached" => (Protocol::Memcached, 11211),
+      scheme => {
+        return Err(invalid(&format!(
+          "unknown scheme {:?} (rather than redis or memcached)",
+          scheme
+        )));
+      }
+    };
+    let host = parsed
+      .host_str()
+      .filter(|host| !host.is_empty())
+      .ok_or_else(|| invalid("no host"))?;
+    let address = format!("{}:{}", host, parsed.port().unwrap_or(default_port));
+    let password = parsed.password().map(str::to_owned);
+    let database = match parsed.path().trim_matches('/') {
+      "" => None,
+      database => Some(
+        database
+          .parse()
+          .map_err(|_| invalid(&format!("invalid database {:?}", database)))?,
+      ),
+    };
+    if protocol == Protocol::Memcached && (password.is_some() || database.is_some()) {
+      return Err(invalid("memcached has neither passwords nor databases"));
+    }
+
+    let mut config = Config {
+      protocol,
+      address,
+      password,
+      database,
+      max_blob_bytes: DEFAULT_SMALL_BLOB_MAX_BYTES,
+      ttl_secs: DEFAULT_TTL_SECS,
+      timeout: Duration::from_millis(DEFAULT_TIMEOUT_MILLIS),
+      key_prefix: DEFAULT_KEY_PREFIX.to_owned(),
+    };
+    for (name, value) in parsed.query_pairs() {
+      match name.as_ref() {
+        "max_blob_size" => {
+          config.max_blob_bytes = parse_size_bytes(&value).map_err(|err| invalid(&err))?
+        }
+        "ttl" => {
+          config.ttl_secs = value
+            .parse()
+            .map_err(|_| invalid(&format!("invalid ttl {:?}", value)))?
+        }
+        "timeout" => {
+          config.timeout = value
+            .parse::<u64>()
+            .ok()
+            .filter(|millis| *millis > 0)
+            .map(Duration::from_millis)
+            .ok_or_else(|| invalid(&format!("invalid timeout {:?}", value)))?
+        }
+        "prefix" => {
+          if value.bytes().any(|byte| byte <= b' ') {
+            return Err(invalid("the prefix must not contain spaces"));
+          }
+          config.key_prefix = value.into_owned()
+        }
+        name => return Err(invalid(&format!("unknown parameter {:?}", name))),
+      }
+    }
+    Ok(config)
+  }
+
+  fn key(&self, digest: Digest) -> String {
+    format!("{}{}-{}", self.key_prefix, digest.0, digest.1)
+  }
+}
+
+///
+/// A Redis or memcached server (see `Config` for its URL), in which blobs are cached by digest.
+///
+/// Blobs which the cache gives which don't match their digests are errors, which (like failures to
+/// reach the cache) the Store falls back to the remote store for, so that a slow or missing cache
+/// only costs its timeout.
+///
+#[derive(Clone)]
+pub struct SmallBlobCache {
+  inner: Arc<Inner>,
+}
+
+struct Inner {
+  config: Config,
+  idle_connections: Mutex<Vec<Connection>>,
+}
+
+impl SmallBlobCache {
+  pub fn new(url: &str) -> Result<SmallBlobCache, String> {
+    Ok(SmallBlobCache {
+      inner: Arc::new(Inner {
+        config: Config::parse(url)?,
+        idle_connections: Mutex::new(vec![]),
+      }),
+    })
+  }
+
+  ///
+  /// A cache with the same configuration as this one, but none of its connections, which must not
+  /// be shared with forked processes.
+  ///
+  pub fn without_connections(&self) -> SmallBlobCache {
+    SmallBlobCache {
+      inner: Arc::new(Inner {
+        config: self.inner.config.clone(),
+        idle_connections: Mutex::new(vec![]),
+      }),
+    }
+  }
+
+  ///
+  /// Whether blobs with the given digest are small enough to be cached.
+  ///
+  pub fn caches(&self, digest: Digest) -> bool {
+    digest.1 <= self.inner.config.max_blob_bytes
+  }
+
+  ///
+  /// Loads the blob with the given digest, if the cache has it.
+  ///
+  pub fn load(&self, digest: Digest) -> BoxFuture<Option<Bytes>, String> {
+    let inner = self.inner.clone();
+    blocking(move || {
+      let key = inner.config.key(digest);
+      match inner.with_connection(|connection| connection.get(&key))? {
+        Some(bytes) => {
+          let bytes = Bytes::from(bytes);
+          if digest_of(&bytes) == digest {
+            Ok(Some(bytes))
+          } else {
+            Err(format!(
+              "Small blob cache gave wrong content for {:?}",
+              digest
+            ))
+          }
+        }
+        None => Ok(None),
+      }
+    })
+  }
+
+  ///
+  /// Stores the given blob, which must have the given digest, and be small enough to be cached.
+  ///
+  pub fn store(&self, digest: Digest, bytes: Bytes) -> BoxFuture<(), String> {
+    let inner = self.inner.clone();
+    blocking(move || {
+      let key = inner.config.key(digest);
+      let ttl_secs = inner.config.ttl_secs;
+      inner.with_connection(|connection| connection.set(&key, &bytes, ttl_secs))
+    })
+  }
+}
+
+///
+/// Runs the given (blocking) function on the tokio threadpool.
+///
+fn blocking<T: Send + 'static, F: Fn() -> Result<T, String> + Send + 'static>(
+  f: F,
+) -> BoxFuture<T, String> {
+  futures::future::poll_fn(move || tokio_threadpool::blocking(&f))
+    .then(|blocking_result| match blocking_result {
+      Ok(result) => result,
+      Err(err) => Err(format!(
+        "Unable to run blocking task for small blob cache: {}",
+        err
+      )),
+    })
+    .to_boxed()
+}
+
+impl Inner {
+  ///
+  /// Runs the given function with an idle connection (or a new one if there is none), which is kept
+  /// for reuse if the function succeeds. Connections which fail are closed, because whatever they
+  /// were in the middle of might still arrive.
+  ///
+  fn with_connection<T, F: FnOnce(&mut Connection) -> Result<T, String>>(
+    &self,
+    f: F,
+  ) -> Result<T, String> {
+    let idle_connection = self.idle_connections.lock().pop();
+    let mut connection = match idle_connection {
+      Some(connection) => connection,
+      None => self.connect()?,
+    };
+    let result = f(&mut connection)?;
+    let mut idle_connections = self.idle_connections.lock();
+    if idle_connections.len() < MAX_IDLE_CONNECTIONS {
+      idle_connections.push(connection);
+    }
+    Ok(result)
+  }
+
+  fn connect(&self) -> Result<Connection, String> {
+    let config = &self.config;
+    let addresses: Vec<SocketAddr> = config
+      .address
+      .to_socket_addrs()
+      .map_err(|err| format!("Error resolving {}: {}", config.address, err))?
+      .collect();
+    let mut last_err = format!("{} resolved to no addresses", config.address);
+    for address in addresses {
+      match TcpStream::connect_timeout(&address, config.timeout) {
+        Ok(stream) => {
+          let mut connection = Connection::new(config.protocol, stream, config.timeout)
+            .map_err(|err| format!("Error configuring connection to {}: {}", address, err))?;
+          if let Some(ref password) = config.password {
+            connection.redis_command(&[b"AUTH", password.as_bytes()])?;
+          }
+          if let Some(database) = config.database {
+            connection.redis_command(&[b"SELECT", database.to_string().as_bytes()])?;
+          }
+          return Ok(connection);
+        }
+        Err(err) => last_err = format!("Error connecting to {}: {}", address, err),
+      }
+    }
+    Err(last_err)
+  }
+}
+
+///
+/// A reply to a Redis command, other than an error.
+///
+#[derive(Debug, Eq, PartialEq)]
+enum RedisReply {
+  Status(String),
+  Integer(i64),
+  Bulk(Option<Vec<u8>>),
+}
+
+struct Connection {
+  protocol: Protocol,
+  reader: BufReader<TcpStream>,
+  writer: TcpStream,
+}
+
+impl Connection {
+  fn new(protocol: Protocol, stream: TcpStream, timeout: Duration) -> std::io::Result<Connection> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.set_nodelay(true)?;
+    Ok(Connection {
+      protocol,
+      reader: BufReader::new(stream.try_clone()?),
+      writer: stream,
+    })
+  }
+
+  fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, String> {
+    match self.protocol {
+      Protocol::Redis => match self.redis_command(&[b"GET", key.as_bytes()])? {
+        RedisReply::Bulk(value) => Ok(value),
+        reply => Err(format!("Unexpected reply to GET: {:?}", reply)),
+      },
+      Protocol::Memcached => {
+        self.write_all(format!("get {}\r\n", key).as_bytes())?;
+        let line = self.read_line()?;
+        if line == b"END" {
+          return Ok(None);
+        }
+        let header = String::from_utf8_lossy(&line).into_owned();
+        let length = match header.split(' ').collect::<Vec<_>>().as_slice() {
+          ["VALUE", _key, _flags, length] => length
+            .parse()
+            .map_err(|_| format!("Invalid length in {:?}", header))?,
+          _ => return Err(format!("Unexpected reply to get: {:?}", header)),
+        };
+        let value = self.read_exactly(length)?;
+        match self.read_line()?.as_slice() {
+          b"END" => Ok(Some(value)),
+          line => Err(format!(
+            "Unexpected end of reply to get: {:?}",
+            String::from_utf8_lossy(line)
+          )),
+        }
+      }
+    }
+  }
+
+  fn set(&mut self, key: &str, value: &[u8], ttl_secs: u64) -> Result<(), String> {
+    match self.protocol {
+      Protocol::Redis => {
+        let reply = if ttl_secs == 0 {
+          self.redis_command(&[b"SET", key.as_bytes(), value])?
+        } else {
+          self.redis_command(&[
+            b"SET",
+            key.as_bytes(),
+            value,
+            b"EX",
+            ttl_secs.to_string().as_bytes(),
+          ])?
+        };
+        match reply {
+          RedisReply::Status(ref status) if status == "OK" => Ok(()),
+          reply => Err(format!("Unexpected reply to SET: {:?}", reply)),
+        }
+      }
+      Protocol::Memcached => {
+        let mut request = format!("set {} 0 {} {}\r\n", key, ttl_secs, value.len()).into_bytes();
+        request.extend_from_slice(value);
+        request.extend_from_slice(b"\r\n");
+        self.write_all(&request)?;
+        match self.read_line()?.as_slice() {
+          b"STORED" => Ok(()),
+          line => Err(format!(
+            "Unexpected reply to set: {:?}",
+            String::from_utf8_lossy(line)
+          )),
+        }
+      }
+    }
+  }
+
+  ///
+  /// Sends the given command (in RESP, the protocol of Redis), and reads its reply.
+  ///
+  fn redis_command(&mut self, arguments: &[&[u8]]) -> Result<RedisReply, String> {
+    let mut request = format!("*{}\r\n", arguments.len()).into_bytes();
+    for argument in arguments {
+      request.extend_from_slice(format!("${}\r\n", argument.len()).as_bytes());
+      request.extend_from_slice(argument);
+      request.extend_from_slice(b"\r\n");
+    }
+    self.write_all(&request)?;
+
+    let line = self.read_line()?;
+    let (kind, rest) = match line.split_first() {
+      Some((kind, rest)) => (*kind, String::from_utf8_lossy(rest).into_owned()),
+      None => return Err("Empty reply from Redis".to_owned()),
+    };
+    match kind {
+      b'+' => Ok(RedisReply::Status(rest)),
+      b'-' => Err(format!("Redis error: {}", rest)),
+      b':' => rest
+        .parse()
+        .map(RedisReply::Integer)
+        .map_err(|_| format!("Invalid integer reply from Redis: {:?}", rest)),
+      b'$' => match rest.parse::<i64>() {
+        Ok(-1) => Ok(RedisReply::Bulk(None)),
+        Ok(length) if length >= 0 => {
+          let value = self.read_exactly(length as usize)?;
+          match self.read_line()?.as_slice() {
+            b"" => Ok(RedisReply::Bulk(Some(value))),
+            _ => Err("Bulk reply from Redis was longer than its length".to_owned()),
+          }
+        }
+        _ => Err(format!("Invalid bulk length from Redis: {:?}", rest)),
+      },
+      kind => Err(format!(
+        "Unsupported kind of reply from Redis: {:?}",
+        kind as char
+      )),
+    }
+  }
+
+  fn write_all(&mut self, bytes: &[u8]) -> Result<(), String> {
+    self
+      .writer
+      .write_all(bytes)
+      .map_err(|err| format!("Error writing to small blob cache: {}", err))
+  }
+
+  ///
+  /// Reads a line, without its CRLF.
+  ///
+  fn read_line(&mut self) -> Result<Vec<u8>, String> {
+    let mut line = vec![];
+    self
+      .reader
+      .read_until(b'\n', &mut line)
+      .map_err(|err| format!("Error reading from small blob cache: {}", err))?;
+    if !line.ends_with(b"\r\n") {
+      return Err("Small blob cache closed the connection".to_owned());
+    }
+    line.truncate(line.len() - 2);
+    Ok(line)
+  }
+
+  fn read_exactly(&mut self, length: usize) -> Result<Vec<u8>, String> {
+    let mut value = vec![0; length];
+    self
+      .reader
+      .read_exact(&mut value)
+      .map_err(|err| format!("Error reading from small blob cache: {}", err))?;
+    Ok(value)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Config, Protocol, SmallBlobCache};
+  use futures::Future;
+  use mock::StubMemoryCache;
+  use std::time::Duration;
+  use testutil::data::TestData;
+
+  fn block_on<Item: Send + 'static>(
+    future: impl Future<Item = Item, Error = String> + Send + 'static,
+  ) -> Result<Item, String> {
+    tokio::runtime::Runtime::new().unwrap().block_on(future)
+  }
+
+  #[test]
+  fn parse_config() {
+    assert_eq!(
+      Config::parse("redis://:secret@cache.local:7000/2?max_blob_size=16KiB&ttl=60"),
+      Ok(Config {
+        protocol: Protocol::Redis,
+        address: "cache.local:7000".to_owned(),
+        password: Some("secret".to_owned()),
+        database: Some(2),
+        max_blob_bytes: 16 * 1024,
+        ttl_secs: 60,
+        timeout: Duration::from_millis(200),
+        key_prefix: "pants:cas:".to_owned(),
+      })
+    );
+    assert_eq!(
+      Config::parse("memcached://cache.local?timeout=50&prefix=ci:"),
+      Ok(Config {
+        protocol: Protocol::Memcached,
+        address: "cache.local:11211".to_owned(),
+        password: None,
+        database: None,
+        max_blob_bytes: 64 * 1024,
+        ttl_secs: 24 * 60 * 60,
+        timeout: Duration::from_millis(50),
+        key_prefix: "ci:".to_owned(),
+      })
+    );
+    Config::parse("http://cache.local").expect_err("Want Err for unknown scheme");
+    Config::parse("redis://cache.local/db").expect_err("Want Err for invalid database");
+    Config::parse("memcached://cache.local/1").expect_err("Want Err for memcached database");
+    Config::parse("redis://cache.local?timeout=0").expect_err("Want Err for zero timeout");
+    Config::parse("redis://cache.local?colour=blue").expect_err("Want Err for unknown parameter");
+  }
+
+  fn round_trip(stub: &StubMemoryCache) {
+    let cache = SmallBlobCache::new(&stub.url("ttl=60")).unwrap();
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+
+    assert_eq!(block_on(cache.load(roland.digest())), Ok(None));
+    block_on(cache.store(roland.digest(), roland.bytes())).unwrap();
+    assert_eq!(
+      block_on(cache.load(roland.digest())),
+      Ok(Some(roland.bytes()))
+    );
+    assert_eq!(
+      stub.entries.lock().get(&format!(
+        "pants:cas:{}-{}",
+        roland.fingerprint(),
+        roland.len()
+      )),
+      Some(&roland.bytes())
+    );
+    stub.entries.lock().insert(
+      format!("pants:cas:{}-{}", catnip.fingerprint(), catnip.len()),
+      roland.bytes(),
+    );
+    block_on(cache.load(catnip.digest())).expect_err("Want Err for wrong content");
+    // Connections are reused.
+    assert_eq!(stub.connection_count(), 1);
+  }
+
+  #[test]
+  fn redis_round_trip() {
+    round_trip(&StubMemoryCache::redis());
+  }
+
+  #[test]
+  fn memcached_round_trip() {
+    round_trip(&StubMemoryCache::memcached());
+  }
+
+  #[test]
+  fn unreachable_caches_fail() {
+    let stub = StubMemoryCache::redis();
+    let cache = SmallBlobCache::new(&stub.url("timeout=50")).unwrap();
+    drop(stub);
+    let roland = TestData::roland();
+
+    block_on(cache.store(roland.digest(), roland.bytes())).expect_err("Want Err storing");
+    block_on(cache.load(roland.digest())).expect_err("Want Err loading");
+  }
+
+  #[test]
+  fn only_small_blobs_are_cached() {
+    let cache = SmallBlobCache::new("redis://localhost?max_blob_size=10").unwrap();
+    assert!(cache.caches(TestData::catnip().digest()));
+    assert!(!cache.caches(TestData::roland().digest()));
+  }
+}
@@ -561,12 +561,10 @@ impl OneOffStoreFileByDigest {
 
 impl StoreFileByDigest<String> for OneOffStoreFileByDigest {
   fn store_by_digest(&self, file: File) -> BoxFuture<Digest, String> {
-    let store = self.store.clone();
     self
-      .posix_fs
-      .read_file(&file)
-      .map_err(move |err| format!("Error reading file {:?}: {:?}", file, err))
-      .and_then(move |content| store.store_file_bytes(content.content, true))
+      .store
+      .store_file(&self.posix_fs.file_path(&file), true)
+      .map_err(move |err| format!("Error storing file {:?}: {}", file, err))
       .to_boxed()
   }
 }
@@ -1,31 +1,46 @@
-use crate::{BackoffConfig, FileContent};
+use crate::archive;
+use crate::gcs;
+use crate::http_cache;
+use crate::known_remote_blobs::KnownRemoteBlobs;
+use crate::reflink::Reflinks;
+use crate::remote_leases::RemoteLeases;
+use crate::s3;
+use crate::small_blob_cache::SmallBlobCache;
+use crate::{
+  BackoffConfig, BearerToken, ChannelPolicy, FileContent, LoadBalancing, Proxy, RequestMetadata,
+};
 
 use bazel_protos;
-use boxfuture::{try_future, BoxFuture, Boxable};
+use boxfuture::{try_future, BoxFuture, BoxStream, Boxable};
 use bytes::Bytes;
 use dirs;
-use futures::{future, Future};
-use hashing::Digest;
+use futures::{future, stream, Future, Stream};
+use hashing::{Digest, Fingerprint, WriterHasher};
+use log::{debug, info, warn};
 use protobuf::Message;
 use serde_derive::Serialize;
-use std::collections::HashMap;
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fs::OpenOptions;
-use std::io::Write;
-use std::os::unix::fs::OpenOptionsExt;
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use parking_lot::Mutex;
 
-// This is the maximum size any particular local LMDB store file is allowed to grow to.
-// It doesn't reflect space allocated on disk, or RAM allocated (it may be reflected in VIRT but
-// not RSS). There is no practical upper bound on this number, so we set it ridiculously high.
-const MAX_LOCAL_STORE_SIZE_BYTES: usize = 1024 * 1024 * 1024 * 1024 / 10;
+// This is the default size that the memory map of each local LMDB store file starts at (it is
+// grown whenever it fills up). It doesn't reflect space allocated on disk, or RAM allocated (it may
+// be reflected in VIRT but not RSS), so we set it ridiculously high.
+pub const DEFAULT_LOCAL_STORE_SHARD_MAP_SIZE_BYTES: usize = 100 * 1024 * 1024 * 1024;
 
-// This is the target number of bytes which should be present in all combined LMDB store files
-// after garbage collection. We almost certainly want to make this configurable.
-pub const DEFAULT_LOCAL_STORE_GC_TARGET_BYTES: usize = 4 * 1024 * 1024 * 1024;
+// This is the maximum (encoded) size of each FindMissingBlobs request. Servers reject messages
+// larger than 4MB by default, which the digests of large trees (e.g. toolchains) would exceed.
+const MAX_FIND_MISSING_BLOBS_REQUEST_BYTES: usize = 1024 * 1024;
 
 // Summary of the files and directories uploaded with an operation
 // ingested_file_{count, bytes}: Number and combined size of processed files
@@ -40,6 +55,124 @@ pub struct UploadSummary {
   pub upload_wall_time: Duration,
 }
 
+///
+/// The entries stored in each shard of a Store's local storage, by kind, and how many of the
+/// Store's reads (since it was created) hit or missed locally, then in the small blob cache (if it
+/// has one), and then remotely.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StoreStats {
+  pub files: Vec<ShardStats>,
+  pub directories: Vec<ShardStats>,
+  pub local_hits: usize,
+  pub local_misses: usize,
+  pub small_blob_cache_hits: usize,
+  pub small_blob_cache_misses: usize,
+  pub remote_hits: usize,
+  pub remote_misses: usize,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ShardStats {
+  ///
+  /// The bits of the first byte of fingerprints which select this shard.
+  ///
+  pub fingerprint_prefix: u8,
+  pub entry_count: usize,
+  ///
+  /// The size of the entries as stored, which is smaller than their digests say if they are
+  /// compressed.
+  ///
+  pub stored_bytes: usize,
+}
+
+impl StoreStats {
+  ///
+  /// The statistics, as named counts: totals for each kind of entry, then the counts of each shard,
+  /// then the read counts.
+  ///
+  pub fn metrics(&self) -> Vec<(String, i64)> {
+    let mut metrics = vec![];
+    for (kind, shards) in &[("file", &self.files), ("directory", &self.directories)] {
+      metrics.push((
+        format!("local_store_{}_count", kind),
+        shards.iter().map(|shard| shard.entry_count).sum::<usize>() as i64,
+      ));
+      metrics.push((
+        format!("local_store_{}_bytes", kind),
+        shards.iter().map(|shard| shard.stored_bytes).sum::<usize>() as i64,
+      ));
+    }
+    for (kind, shards) in &[("file", &self.files), ("directory", &self.directories)] {
+      for shard in shards.iter() {
+        let name = format!(
+          "local_store_{}_shard_{:02x}",
+          kind, shard.fingerprint_prefix
+        );
+        metrics.push((format!("{}_count", name), shard.entry_count as i64));
+        metrics.push((format!("{}_bytes", name), shard.stored_bytes as i64));
+      }
+    }
+    metrics.extend(
+      ReadCounts::metrics_of(
+        self.local_hits,
+        self.local_misses,
+        self.small_blob_cache_hits,
+        self.small_blob_cache_misses,
+        self.remote_hits,
+        self.remote_misses,
+      )
+      .into_iter()
+      .map(|(name, value)| (name.to_owned(), value)),
+    );
+    metrics
+  }
+}
+
+///
+/// Counts of reads, shared between the clones of a Store.
+///
+#[derive(Debug, Default)]
+struct ReadCounts {
+  local_hits: AtomicUsize,
+  local_misses: AtomicUsize,
+  small_blob_cache_hits: AtomicUsize,
+  small_blob_cache_misses: AtomicUsize,
+  remote_hits: AtomicUsize,
+  remote_misses: AtomicUsize,
+}
+
+impl ReadCounts {
+  fn metrics_of(
+    local_hits: usize,
+    local_misses: usize,
+    small_blob_cache_hits: usize,
+    small_blob_cache_misses: usize,
+    remote_hits: usize,
+    remote_misses: usize,
+  ) -> Vec<(&'static str, i64)> {
+    vec![
+      ("local_store_hits", local_hits as i64),
+      ("local_store_misses", local_misses as i64),
+      ("small_blob_cache_hits", small_blob_cache_hits as i64),
+      ("small_blob_cache_misses", small_blob_cache_misses as i64),
+      ("remote_store_hits", remote_hits as i64),
+      ("remote_store_misses", remote_misses as i64),
+    ]
+  }
+
+  fn metrics(&self) -> Vec<(&'static str, i64)> {
+    Self::metrics_of(
+      self.local_hits.load(Ordering::Relaxed),
+      self.local_misses.load(Ordering::Relaxed),
+      self.small_blob_cache_hits.load(Ordering::Relaxed),
+      self.small_blob_cache_misses.load(Ordering::Relaxed),
+      self.remote_hits.load(Ordering::Relaxed),
+      self.remote_misses.load(Ordering::Relaxed),
+    )
+  }
+}
+
 ///
 /// A content-addressed store of file contents, and Directories.
 ///
@@ -50,14 +183,103 @@ pub struct UploadSummary {
 /// https://github.com/googleapis/googleapis/blob/master/google/devtools/remoteexecution/v1test/)
 ///
 /// It can also write back to a remote gRPC server, but will only do so when explicitly instructed
-/// to do so.
+/// to do so, unless its `TieringPolicy` writes blobs through as they are stored.
+///
+/// Instead of a gRPC server, the remote may be an HTTP cache (see `http_cache`).
+///
+/// Small blobs may additionally be cached in Redis or memcached (see `small_blob_cache`), which is
+/// consulted before the remote.
 ///
 #[derive(Clone)]
 pub struct Store {
   local: local::ByteStore,
-  remote: Option<remote::ByteStore>,
+  remote: Option<RemoteStore>,
+  small_blob_cache: Option<SmallBlobCache>,
+  remote_leases: RemoteLeases,
+  known_remote_blobs: Option<KnownRemoteBlobs>,
+  read_counts: Arc<ReadCounts>,
+  tiering_policy: TieringPolicy,
+  // Read-only copies of files, which materialized files are linked to (or cloned from) when they
+  // are materialized via links or reflinks rather than copies.
+  materialized_files_root: PathBuf,
+  reflinks: Reflinks,
+}
+
+///
+/// How the files of a Directory are laid out on disk when it is materialized.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MaterializationStrategy {
+  ///
+  /// Files are written out of the Store.
+  ///
+  Copy,
+
+  ///
+  /// Files are hardlinked to read-only copies which are shared between materializations (falling
+  /// back to copying if the Store and the destination are on different filesystems, or a copy has
+  /// as many links as its filesystem allows). Processes must not attempt to modify the files, which
+  /// will be read-only: files which must be writable are copied instead.
+  ///
+  Hardlink,
+
+  ///
+  /// Files are symlinked to read-only copies which are shared between materializations. Processes
+  /// must not attempt to modify the files, and must tolerate them being symlinks. Files which must
+  /// be writable are copied instead.
+  ///
+  Symlink,
+
+  ///
+  /// Files are cloned (sharing their blocks until either is modified) from read-only copies which
+  /// are shared between materializations, so they're as independent and writable as copies. Falls
+  /// back to copying on filesystems which don't support cloning, which is remembered per
+  /// filesystem.
+  ///
+  Reflink,
+}
+
+impl MaterializationStrategy {
+  ///
+  /// The strategy for the file at the given (relative) path: files which must be writable (i.e.
+  /// which are, or are beneath, any of the writable paths) are always copied, unless they're
+  /// cloned, because clones are already writable.
+  ///
+  fn for_file(self, path: &Path, writable_paths: &BTreeSet<PathBuf>) -> MaterializationStrategy {
+    if self != MaterializationStrategy::Reflink
+      && writable_paths
+        .iter()
+        .any(|writable_path| path.starts_with(writable_path))
+    {
+      MaterializationStrategy::Copy
+    } else {
+      self
+    }
+  }
 }
 
+impl FromStr for MaterializationStrategy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<MaterializationStrategy, String> {
+    match s {
+      "copy" => Ok(MaterializationStrategy::Copy),
+      "hardlink" => Ok(MaterializationStrategy::Hardlink),
+      "symlink" => Ok(MaterializationStrategy::Symlink),
+      "reflink" => Ok(MaterializationStrategy::Reflink),
+      _ => Err(format!(
+        "Expected one of copy, hardlink, symlink or reflink materialization, but got {:?}",
+        s
+      )),
+    }
+  }
+}
+
+// The errors (from errno.h) for a link which would cross filesystems, and for a file which has as
+// many links as its filesystem allows, which are the same on Linux and macOS.
+const EXDEV: i32 = 18;
+const EMLINK: i32 = 31;
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ShrinkBehavior {
   ///
@@ -76,6 +298,405 @@ pub enum ShrinkBehavior {
   Compact,
 }
 
+///
+/// Which entries of local storage are evicted when it is garbage collected, besides enough of the
+/// least recently used to shrink it to its target size. Leased entries are never evicted.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct EvictionPolicy {
+  ///
+  /// Entries which haven't been used (or leased) for longer than this are evicted.
+  ///
+  pub max_unused_age: Option<Duration>,
+  ///
+  /// The least recently used files of each shard are evicted until the shard's files are stored in
+  /// at most this many bytes.
+  ///
+  pub file_shard_quota_bytes: Option<usize>,
+  ///
+  /// As `file_shard_quota_bytes`, but for directories.
+  ///
+  pub directory_shard_quota_bytes: Option<usize>,
+}
+
+impl EvictionPolicy {
+  ///
+  /// Whether the policy evicts nothing.
+  ///
+  pub fn is_empty(&self) -> bool {
+    *self == EvictionPolicy::default()
+  }
+
+  fn shard_quota_bytes(&self, entry_type: EntryType) -> Option<usize> {
+    match entry_type {
+      EntryType::File => self.file_shard_quota_bytes,
+      EntryType::Directory => self.directory_shard_quota_bytes,
+    }
+  }
+}
+
+///
+/// How a Store with a remote moves blobs of one type between its local storage and its remote.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TierRules {
+  ///
+  /// Whether blobs which are loaded from the remote are stored locally. If not, they are verified
+  /// against their digests and used, but loaded from the remote again the next time.
+  ///
+  pub read_through: bool,
+  ///
+  /// Whether blobs which are stored locally are uploaded to the remote straight away, rather than
+  /// only when the remote is explicitly ensured to have them (e.g. before remote execution).
+  /// Failing to upload them is not an error.
+  ///
+  pub write_through: bool,
+  ///
+  /// Blobs which are larger than this are never uploaded to the remote.
+  ///
+  pub max_upload_bytes: Option<usize>,
+}
+
+impl Default for TierRules {
+  fn default() -> TierRules {
+    TierRules {
+      read_through: true,
+      write_through: false,
+      max_upload_bytes: None,
+    }
+  }
+}
+
+///
+/// How a Store with a remote moves files and Directories between its local storage and its remote.
+/// By default, blobs which are loaded from the remote are stored locally, and blobs are only
+/// uploaded when the remote is explicitly ensured to have them.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TieringPolicy {
+  pub files: TierRules,
+  pub directories: TierRules,
+}
+
+impl TieringPolicy {
+  fn rules(&self, entry_type: EntryType) -> &TierRules {
+    match entry_type {
+      EntryType::File => &self.files,
+      EntryType::Directory => &self.directories,
+    }
+  }
+
+  fn uploads(&self, entry_type: EntryType, digest: Digest) -> bool {
+    match self.rules(entry_type).max_upload_bytes {
+      Some(max_upload_bytes) => digest.1 <= max_upload_bytes,
+      None => true,
+    }
+  }
+}
+
+///
+/// An entry which was (or in a dry run, would have been) evicted by an `EvictionPolicy`.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Eviction {
+  pub entry_type: EntryType,
+  pub fingerprint: Fingerprint,
+  pub stored_bytes: usize,
+  pub reason: EvictionReason,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EvictionReason {
+  ///
+  /// The entry hadn't been used (or leased) for longer than the maximum age.
+  ///
+  Unused {
+    unused_for: Duration,
+    max_unused_age: Duration,
+  },
+
+  ///
+  /// The entry was the least recently used of its shard, whose entries of its kind were stored in
+  /// more bytes than their quota.
+  ///
+  OverShardQuota {
+    fingerprint_prefix: u8,
+    shard_stored_bytes: usize,
+    quota_bytes: usize,
+  },
+}
+
+impl fmt::Display for Eviction {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{:?} {} ({} bytes): ",
+      self.entry_type, self.fingerprint, self.stored_bytes
+    )?;
+    match self.reason {
+      EvictionReason::Unused {
+        unused_for,
+        max_unused_age,
+      } => write!(
+        f,
+        "unused for {} seconds, which is longer than the maximum of {} seconds",
+        unused_for.as_secs(),
+        max_unused_age.as_secs()
+      ),
+      EvictionReason::OverShardQuota {
+        fingerprint_prefix,
+        shard_stored_bytes,
+        quota_bytes,
+      } => write!(
+        f,
+        "least recently used of shard {:02x}, which was stored in {} bytes, more than its quota of \
+         {} bytes",
+        fingerprint_prefix, shard_stored_bytes, quota_bytes
+      ),
+    }
+  }
+}
+
+///
+/// An entry of local storage which `Store::fsck` found to be broken.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BrokenEntry {
+  pub entry_type: EntryType,
+  pub fingerprint: Fingerprint,
+  pub problem: EntryProblem,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum EntryProblem {
+  ///
+  /// The entry was stored compressed, but can't be decompressed.
+  ///
+  Undecompressable,
+
+  ///
+  /// The content of the entry has a different fingerprint than the one it is stored as.
+  ///
+  WrongFingerprint(Fingerprint),
+
+  ///
+  /// The entry is a Directory whose content doesn't parse as a Directory proto.
+  ///
+  InvalidDirectory(String),
+
+  ///
+  /// The entry is a Directory proto which isn't canonical.
+  ///
+  NonCanonicalDirectory(String),
+
+  ///
+  /// The entry is a file whose read-only materialized copy (which the files of sandboxes are
+  /// linked to) has been changed, or made writable. The copy is deleted, rather than the entry.
+  ///
+  ModifiedMaterializedCopy,
+}
+
+impl fmt::Display for BrokenEntry {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:?} {}: ", self.entry_type, self.fingerprint)?;
+    match self.problem {
+      EntryProblem::Undecompressable => write!(f, "stored compressed, but can't be decompressed"),
+      EntryProblem::WrongFingerprint(actual) => write!(f, "content has fingerprint {}", actual),
+      EntryProblem::InvalidDirectory(ref err) => write!(f, "not a valid Directory: {}", err),
+      EntryProblem::NonCanonicalDirectory(ref err) => {
+        write!(f, "not a canonical Directory: {}", err)
+      }
+      EntryProblem::ModifiedMaterializedCopy => write!(f, "materialized copy has been modified"),
+    }
+  }
+}
+
+///
+/// An entry of a Directory tree, as yielded by `Store::walk_entries`.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub enum WalkEntry {
+  File(bazel_protos::remote_execution::FileNode),
+  Directory(bazel_protos::remote_execution::DirectoryNode),
+}
+
+///
+/// A PEM encoded certificate chain and private key, with which to authenticate to servers which
+/// require mutual TLS.
+///
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+  pub cert_chain: Vec<u8>,
+  pub private_key: Vec<u8>,
+}
+
+///
+/// The credentials for a secure channel which trusts the given root CA certs (or the default roots,
+/// if there are none) and presents the given client certificate, or None if neither is configured
+/// and the channel should be insecure.
+///
+pub fn channel_credentials(
+  root_ca_certs: &Option<Vec<u8>>,
+  client_certificate: &Option<ClientCertificate>,
+) -> Option<grpcio::ChannelCredentials> {
+  if root_ca_certs.is_none() && client_certificate.is_none() {
+    return None;
+  }
+  let mut builder = grpcio::ChannelCredentialsBuilder::new();
+  if let Some(ref root_ca_certs) = root_ca_certs {
+    builder = builder.root_cert(root_ca_certs.clone());
+  }
+  if let Some(ref client_certificate) = client_certificate {
+    builder = builder.cert(
+      client_certificate.cert_chain.clone(),
+      client_certificate.private_key.clone(),
+    );
+  }
+  Some(builder.build())
+}
+
+///
+/// How the messages that we send to the CAS are compressed, which can save a lot of bandwidth on
+/// slow connections. The server must accept the algorithm; gRPC advertises each of them as
+/// accepted by us, so the server may compress its responses with any of them.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+  None,
+  Deflate,
+  Gzip,
+}
+
+impl Compression {
+  fn algorithm(self) -> grpcio::CompressionAlgorithms {
+    match self {
+      Compression::None => grpcio::CompressionAlgorithms::None,
+      Compression::Deflate => grpcio::CompressionAlgorithms::Deflate,
+      Compression::Gzip => grpcio::CompressionAlgorithms::Gzip,
+    }
+  }
+}
+
+impl FromStr for Compression {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Compression, String> {
+    match s {
+      "none" => Ok(Compression::None),
+      "deflate" => Ok(Compression::Deflate),
+      "gzip" => Ok(Compression::Gzip),
+      _ => Err(format!(
+        "Expected one of none, deflate or gzip compression, but got {:?}",
+        s
+      )),
+    }
+  }
+}
+
+///
+/// How the local store's LMDB environments are laid out and opened.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LocalStoreOptions {
+  ///
+  /// The number of LMDB environments (each of which can have one concurrent writer) that each kind
+  /// of entry is sharded between, by a prefix of their fingerprints.
+  ///
+  pub shard_count: usize,
+  ///
+  /// The size that the memory map of each shard starts at. Shards whose maps fill up are grown.
+  ///
+  pub shard_map_size_bytes: usize,
+  ///
+  /// The maximum number of concurrent read transactions per shard.
+  ///
+  pub max_readers: u32,
+}
+
+impl LocalStoreOptions {
+  ///
+  /// Shard counts must be powers of two between 1 and 256, because shards are chosen by the first
+  /// bits of fingerprints.
+  ///
+  pub fn new(
+    shard_count: usize,
+    shard_map_size_bytes: usize,
+    max_readers: u32,
+  ) -> Result<LocalStoreOptions, String> {
+    if !shard_count.is_power_of_two() || shard_count > 256 {
+      return Err(format!(
+        "The shard count must be a power of two between 1 and 256, but got {}",
+        shard_count
+      ));
+    }
+    if shard_map_size_bytes == 0 {
+      return Err("The shard map size must be greater than 0".to_owned());
+    }
+    if max_readers == 0 {
+      return Err("The maximum number of readers must be greater than 0".to_owned());
+    }
+    Ok(LocalStoreOptions {
+      shard_count,
+      shard_map_size_bytes,
+      max_readers,
+    })
+  }
+}
+
+impl Default for LocalStoreOptions {
+  fn default() -> LocalStoreOptions {
+    LocalStoreOptions {
+      shard_count: 16,
+      shard_map_size_bytes: DEFAULT_LOCAL_STORE_SHARD_MAP_SIZE_BYTES,
+      // The default of LMDB.
+      max_readers: 126,
+    }
+  }
+}
+
+///
+/// Parses a size, which is either a number of bytes or a number with a binary unit (e.g. `20GiB`).
+///
+pub fn parse_size_bytes(size: &str) -> Result<usize, String> {
+  let invalid = || {
+    format!(
+      "Sizes must be a number of bytes, optionally with one of the units KiB, MiB, GiB or TiB, \
+       but got {:?}",
+      size
+    )
+  };
+  let size = size.trim();
+  let number_len = size
+    .find(|c: char| !c.is_ascii_digit())
+    .unwrap_or_else(|| size.len());
+  let (number, unit) = size.split_at(number_len);
+  let number: usize = number.parse().map_err(|_| invalid())?;
+  let multiplier: usize = match unit.trim() {
+    "" | "B" => 1,
+    "KiB" => 1 << 10,
+    "MiB" => 1 << 20,
+    "GiB" => 1 << 30,
+    "TiB" => 1 << 40,
+    _ => return Err(invalid()),
+  };
+  number.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+///
+/// Whether the given error from an RPC is likely to be transient, and so worth retrying: because
+/// the server was unavailable, the deadline was exceeded, or the transport failed.
+///
+pub fn is_retryable_rpc_error(err: &grpcio::Error) -> bool {
+  match err {
+    grpcio::Error::RpcFailure(status) => match status.status {
+      grpcio::RpcStatusCode::Unavailable | grpcio::RpcStatusCode::DeadlineExceeded => true,
+      _ => false,
+    },
+    grpcio::Error::CallFailure(_) | grpcio::Error::RemoteStopped => true,
+    _ => false,
+  }
+}
+
 // Note that Store doesn't implement ByteStore because it operates at a higher level of abstraction,
 // considering Directories as a standalone concept, rather than a buffer of bytes.
 // This has the nice property that Directories can be trusted to be valid and canonical.
@@ -85,9 +706,29 @@ impl Store {
   /// Make a store which only uses its local storage.
   ///
   pub fn local_only<P: AsRef<Path>>(path: P) -> Result<Store, String> {
+    Store::local_only_with_options(path, LocalStoreOptions::default())
+  }
+
+  ///
+  /// Make a store which only uses its local storage, laid out according to the given options.
+  ///
+  /// Changing the shard count starts an empty local store, because each shard count is stored in
+  /// its own directories.
+  ///
+  pub fn local_only_with_options<P: AsRef<Path>>(
+    path: P,
+    options: LocalStoreOptions,
+  ) -> Result<Store, String> {
     Ok(Store {
-      local: local::ByteStore::new(path)?,
+      local: local::ByteStore::new_with_options(&path, options)?,
       remote: None,
+      small_blob_cache: None,
+      remote_leases: RemoteLeases::new(None),
+      known_remote_blobs: None,
+      read_counts: Arc::new(ReadCounts::default()),
+      tiering_policy: TieringPolicy::default(),
+      materialized_files_root: path.as_ref().join("materialized_files"),
+      reflinks: Reflinks::default(),
     })
   }
 
@@ -95,36 +736,311 @@ impl Store {
   /// Make a store which uses local storage, and if it is missing a value which it tries to load,
   /// will attempt to back-fill its local storage from a remote CAS.
   ///
+  /// Each RPC to the CAS or ActionCache is given a deadline, which the server may use to schedule
+  /// it: uploads get `upload_timeout`, reads (and queries of which blobs are missing) get
+  /// `read_timeout`, and ActionCache lookups and updates get `action_cache_timeout`.
+  ///
+  /// If the addresses are `http://` or `https://` URLs, they are HTTP caches rather than gRPC
+  /// servers, for which the options which only apply to gRPC (the channel policy, request
+  /// metadata, compression, thread count and chunk size) are ignored.
+  ///
+  /// If the address is an `s3://` URL, it is an S3 bucket (see `s3::Bucket` for its parameters),
+  /// for which the oauth bearer token is also ignored, because requests are signed with the AWS
+  /// credentials of the environment instead.
+  ///
+  /// If the address is a `gs://` URL, it is a GCS bucket (see `gcs::Bucket` for its parameters),
+  /// whose requests are authenticated as its service account if it has one, or else with the oauth
+  /// bearer token.
+  ///
   pub fn with_remote<P: AsRef<Path>>(
     path: P,
     cas_addresses: &[String],
     instance_name: Option<String>,
     root_ca_certs: &Option<Vec<u8>>,
-    oauth_bearer_token: Option<String>,
+    client_certificate: &Option<ClientCertificate>,
+    proxy: &Option<Proxy>,
+    channel_policy: ChannelPolicy,
+    oauth_bearer_token: Option<BearerToken>,
+    request_metadata: Option<RequestMetadata>,
+    compression: Compression,
     thread_count: usize,
     chunk_size_bytes: usize,
     upload_timeout: Duration,
+    read_timeout: Duration,
+    action_cache_timeout: Duration,
     backoff_config: BackoffConfig,
+    load_balancing: LoadBalancing,
     rpc_retries: usize,
     futures_timer_thread: futures_timer::TimerHandle,
   ) -> Result<Store, String> {
-    Ok(Store {
-      local: local::ByteStore::new(path)?,
-      remote: Some(remote::ByteStore::new(
+    Store::local_only(path)?.into_with_remote(
+      cas_addresses,
+      instance_name,
+      root_ca_certs,
+      client_certificate,
+      proxy,
+      channel_policy,
+      oauth_bearer_token,
+      request_metadata,
+      compression,
+      thread_count,
+      chunk_size_bytes,
+      upload_timeout,
+      read_timeout,
+      action_cache_timeout,
+      backoff_config,
+      load_balancing,
+      rpc_retries,
+      futures_timer_thread,
+    )
+  }
+
+  ///
+  /// This store (which must not already have a remote), but backed by the given remote CAS, as
+  /// for `with_remote`.
+  ///
+  pub fn into_with_remote(
+    self,
+    cas_addresses: &[String],
+    instance_name: Option<String>,
+    root_ca_certs: &Option<Vec<u8>>,
+    client_certificate: &Option<ClientCertificate>,
+    proxy: &Option<Proxy>,
+    channel_policy: ChannelPolicy,
+    oauth_bearer_token: Option<BearerToken>,
+    request_metadata: Option<RequestMetadata>,
+    compression: Compression,
+    thread_count: usize,
+    chunk_size_bytes: usize,
+    upload_timeout: Duration,
+    read_timeout: Duration,
+    action_cache_timeout: Duration,
+    backoff_config: BackoffConfig,
+    load_balancing: LoadBalancing,
+    rpc_retries: usize,
+    futures_timer_thread: futures_timer::TimerHandle,
+  ) -> Result<Store, String> {
+    let http_cache_urls = cas_addresses
+      .iter()
+      .filter(|address| http_cache::is_http_cache_url(address))
+      .count();
+    let bucket_urls = cas_addresses
+      .iter()
+      .filter(|address| s3::is_s3_url(address) || gcs::is_gcs_url(address))
+      .count();
+    let remote = if bucket_urls == 1 && cas_addresses.len() == 1 && s3::is_s3_url(&cas_addresses[0])
+    {
+      RemoteStore::S3(s3::ByteStore::new(
+        &cas_addresses[0],
+        instance_name,
+        root_ca_certs,
+        client_certificate,
+        proxy,
+        upload_timeout,
+        read_timeout,
+        action_cache_timeout,
+        backoff_config,
+        load_balancing,
+        rpc_retries,
+        futures_timer_thread,
+      )?)
+    } else if bucket_urls == 1 && cas_addresses.len() == 1 {
+      RemoteStore::Gcs(gcs::ByteStore::new(
+        &cas_addresses[0],
+        instance_name,
+        root_ca_certs,
+        client_certificate,
+        proxy,
+        oauth_bearer_token,
+        upload_timeout,
+        read_timeout,
+        action_cache_timeout,
+        backoff_config,
+        load_balancing,
+        rpc_retries,
+        futures_timer_thread,
+      )?)
+    } else if bucket_urls > 0 {
+      return Err(format!(
+        "An S3 or GCS bucket must be the only remote store, but got {:?}",
+        cas_addresses
+      ));
+    } else if http_cache_urls == 0 {
+      RemoteStore::Grpc(remote::ByteStore::new(
         cas_addresses,
         instance_name,
         root_ca_certs,
+        client_certificate,
+        proxy,
+        channel_policy,
         oauth_bearer_token,
+        request_metadata,
+        compression,
         thread_count,
         chunk_size_bytes,
         upload_timeout,
+        read_timeout,
+        action_cache_timeout,
+        backoff_config,
+        load_balancing,
+        rpc_retries,
+        futures_timer_thread,
+      )?)
+    } else if http_cache_urls == cas_addresses.len() {
+      RemoteStore::Http(http_cache::ByteStore::new(
+        cas_addresses,
+        instance_name,
+        root_ca_certs,
+        client_certificate,
+        proxy,
+        oauth_bearer_token,
+        upload_timeout,
+        read_timeout,
+        action_cache_timeout,
         backoff_config,
+        load_balancing,
         rpc_retries,
         futures_timer_thread,
-      )?),
+      )?)
+    } else {
+      return Err(format!(
+        "Remote stores must either all be HTTP caches or all be gRPC servers, but got {:?}",
+        cas_addresses
+      ));
+    };
+    Ok(Store {
+      remote: Some(remote),
+      ..self
     })
   }
 
+  ///
+  /// This store, but extending the leases of the blobs which it ensures that its remote has (for as
+  /// long as they are needed, i.e. until `forget_remote_leases` is called) at most once per the
+  /// given interval, rather than relying on the remote to keep them as long as they are needed.
+  ///
+  /// Leases are extended while blobs are ensured to be in the remote (e.g. while uploading the
+  /// inputs of remote executions), so only while the remote is being used.
+  ///
+  pub fn with_remote_lease_extension_interval(self, interval: Option<Duration>) -> Store {
+    Store {
+      remote_leases: RemoteLeases::new(interval),
+      ..self
+    }
+  }
+
+  ///
+  /// This store, but reading blobs which are small enough from the given cache (if any) before its
+  /// remote, and caching the small blobs which it reads from its remote. The cache is only used
+  /// alongside a remote.
+  ///
+  pub fn with_small_blob_cache(self, small_blob_cache: Option<SmallBlobCache>) -> Store {
+    Store {
+      small_blob_cache,
+      ..self
+    }
+  }
+
+  ///
+  /// This store, but not asking its remote whether it has the blobs which are known (by the given
+  /// KnownRemoteBlobs, if any) to be in it when ensuring that it has them. Blobs become known when
+  /// the remote is ensured to have them. Leases are still extended by asking the remote.
+  ///
+  pub fn with_known_remote_blobs(self, known_remote_blobs: Option<KnownRemoteBlobs>) -> Store {
+    Store {
+      known_remote_blobs,
+      ..self
+    }
+  }
+
+  ///
+  /// This store, but moving blobs between its local storage and its remote (if it has one)
+  /// according to the given policy.
+  ///
+  pub fn with_tiering_policy(self, tiering_policy: TieringPolicy) -> Store {
+    Store {
+      tiering_policy,
+      ..self
+    }
+  }
+
+  ///
+  /// This store, but re-hashing every blob that it loads from its local storage before using it.
+  /// Corrupted blobs (e.g. because of a power loss while they were being written) are deleted, and
+  /// treated as missing: so re-fetched, if there is a remote.
+  ///
+  pub fn with_local_digest_verification(self, verify_digests: bool) -> Store {
+    Store {
+      local: self.local.with_digest_verification(verify_digests),
+      ..self
+    }
+  }
+
+  ///
+  /// This store, but compressing the blobs of at least the given size which it stores locally (if
+  /// that makes them smaller), and decompressing them when they are loaded. Digests are of the
+  /// uncompressed blobs, as ever.
+  ///
+  pub fn with_local_compression_threshold(self, threshold_bytes: Option<usize>) -> Store {
+    Store {
+      local: self.local.with_compression_threshold(threshold_bytes),
+      ..self
+    }
+  }
+
+  ///
+  /// This store, but memory mapping files of at least the given size to store them with
+  /// `store_file`, rather than reading them into memory, to save copying large files.
+  ///
+  pub fn with_local_mmap_threshold(self, threshold_bytes: Option<usize>) -> Store {
+    Store {
+      local: self.local.with_mmap_threshold(threshold_bytes),
+      ..self
+    }
+  }
+
+  ///
+  /// This store, but hashing the large blobs which it stores locally on a pool of the given number
+  /// of threads (see `local::ByteStore::with_hashing_parallelism`), or 0 to hash them without one.
+  ///
+  pub fn with_local_hashing_parallelism(self, parallelism: usize) -> Store {
+    Store {
+      local: self.local.with_hashing_parallelism(parallelism),
+      ..self
+    }
+  }
+
+  ///
+  /// Stops extending the leases of the blobs which were ensured to be in the remote so far, e.g.
+  /// because a new session is starting.
+  ///
+  pub fn forget_remote_leases(&self) {
+    self.remote_leases.clear();
+  }
+
+  ///
+  /// This store, but using the given instance of its remote CAS and ActionCache (if it has them),
+  /// e.g. for a process which is routed to a different instance than the default.
+  ///
+  /// Instances behind the same servers are assumed to have the same capabilities.
+  ///
+  pub fn with_instance_name(&self, instance_name: Option<String>) -> Store {
+    Store {
+      local: self.local.clone(),
+      remote: self
+        .remote
+        .as_ref()
+        .map(|remote| remote.with_instance_name(instance_name)),
+      small_blob_cache: self.small_blob_cache.clone(),
+      remote_leases: self.remote_leases.clone(),
+      known_remote_blobs: self.known_remote_blobs.clone(),
+      read_counts: self.read_counts.clone(),
+      tiering_policy: self.tiering_policy,
+      materialized_files_root: self.materialized_files_root.clone(),
+      reflinks: self.reflinks.clone(),
+    }
+  }
+
   // This default is also hard-coded into the Python options code in global_options.py
   pub fn default_path() -> PathBuf {
     match dirs::home_dir() {
@@ -134,12 +1050,55 @@ impl Store {
   }
 
   ///
-  /// Store a file locally.
+  /// Store a file locally (and in the remote, if the tiering policy writes files through).
   ///
   pub fn store_file_bytes(&self, bytes: Bytes, initial_lease: bool) -> BoxFuture<Digest, String> {
+    let store = self.clone();
     self
       .local
       .store_bytes(EntryType::File, bytes, initial_lease)
+      .and_then(move |digest| store.write_through(EntryType::File, digest))
+      .to_boxed()
+  }
+
+  ///
+  /// Store the contents of the file at the given path locally, memory mapping large files rather
+  /// than reading them (see `with_local_mmap_threshold`), and in the remote if the tiering policy
+  /// writes files through.
+  ///
+  pub fn store_file(&self, path: &Path, initial_lease: bool) -> BoxFuture<Digest, String> {
+    let store = self.clone();
+    self
+      .local
+      .store_file(path, initial_lease)
+      .and_then(move |digest| store.write_through(EntryType::File, digest))
+      .to_boxed()
+  }
+
+  ///
+  /// Uploads a blob which was just stored locally to the remote, if there is one and the tiering
+  /// policy writes blobs of its type through. The remote isn't first asked whether it has the blob,
+  /// and the contents of Directories aren't uploaded, because they were written through (or not)
+  /// when they were stored.
+  ///
+  /// Failing to upload the blob is not an error, because it is stored locally, and will be uploaded
+  /// whenever the remote is ensured to have it.
+  ///
+  fn write_through(&self, entry_type: EntryType, digest: Digest) -> BoxFuture<Digest, String> {
+    if self.remote.is_none() || !self.tiering_policy.rules(entry_type).write_through {
+      return future::ok(digest).to_boxed();
+    }
+    self
+      .ensure_remote_has(vec![digest])
+      .then(move |result| {
+        if let Err(err) = result {
+          warn!(
+            "Failed to write {:?} through to the remote store: {}",
+            digest, err
+          );
+        }
+        Ok(digest)
+      })
       .to_boxed()
   }
 
@@ -169,21 +1128,25 @@ impl Store {
   ///
   /// Save the bytes of the Directory proto locally, without regard for any of the
   /// contents of any FileNodes or DirectoryNodes therein (i.e. does not require that its
-  /// children are already stored).
+  /// children are already stored). It is also uploaded to the remote if the tiering policy writes
+  /// Directories through.
   ///
   pub fn record_directory(
     &self,
     directory: &bazel_protos::remote_execution::Directory,
     initial_lease: bool,
   ) -> BoxFuture<Digest, String> {
-    let local = self.local.clone();
+    let store = self.clone();
     future::result(
       directory
         .write_to_bytes()
         .map_err(|e| format!("Error serializing directory proto {:?}: {:?}", directory, e)),
     )
     .and_then(move |bytes| {
-      local.store_bytes(EntryType::Directory, Bytes::from(bytes), initial_lease)
+      store
+        .local
+        .store_bytes(EntryType::Directory, Bytes::from(bytes), initial_lease)
+        .and_then(move |digest| store.write_through(EntryType::Directory, digest))
     })
     .to_boxed()
   }
@@ -230,6 +1193,133 @@ impl Store {
     )
   }
 
+  ///
+  /// Records each of the Directories in the Tree proto with the given digest locally, returning
+  /// the digest of its root Directory, or None if the Tree doesn't exist. Trees (which, like files,
+  /// are blobs in the CAS) are how remote ActionResults refer to output directories: fetching one
+  /// fetches every Directory beneath the root at once, rather than one level at a time.
+  ///
+  pub fn load_tree(&self, tree_digest: Digest) -> BoxFuture<Option<Digest>, String> {
+    let store = self.clone();
+    self
+      .load_file_bytes_with(tree_digest, |bytes| bytes)
+      .and_then(move |maybe_bytes| {
+        let bytes = match maybe_bytes {
+          Some(bytes) => bytes,
+          None => return future::ok(None).to_boxed(),
+        };
+        let mut tree = bazel_protos::remote_execution::Tree::new();
+        try_future!(tree
+          .merge_from_bytes(&bytes)
+          .map_err(|e| format!("Tree proto for {:?} was not valid: {:?}", tree_digest, e)));
+        let root = tree.take_root();
+        let children = tree.take_children().into_vec();
+        try_future!(std::iter::once(&root)
+          .chain(&children)
+          .map(bazel_protos::verify_directory_canonical)
+          .collect::<Result<Vec<_>, _>>());
+        store
+          .record_directory(&root, true)
+          .join(future::join_all(
+            children
+              .iter()
+              .map(|child| store.record_directory(child, true))
+              .collect::<Vec<_>>(),
+          ))
+          .map(|(root_digest, _)| Some(root_digest))
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Stores a Tree proto containing the (locally stored) Directory with the given digest and all
+  /// of the Directories beneath it, returning the digest of the Tree.
+  ///
+  pub fn record_tree(&self, root_digest: Digest) -> BoxFuture<Digest, String> {
+    let store = self.clone();
+    self
+      .walk(root_digest, |_, _, digest, directory| {
+        future::ok((digest, directory.clone())).to_boxed()
+      })
+      .and_then(move |directories| {
+        let mut tree = bazel_protos::remote_execution::Tree::new();
+        let mut seen = HashSet::new();
+        for (digest, directory) in directories {
+          if digest == root_digest {
+            tree.set_root(directory);
+          } else if seen.insert(digest) {
+            tree.mut_children().push(directory);
+          }
+        }
+        let bytes = try_future!(tree.write_to_bytes().map_err(|e| format!(
+          "Error serializing Tree proto for {:?}: {:?}",
+          root_digest, e
+        )));
+        store.store_file_bytes(Bytes::from(bytes), true)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Records the (serialized) result of running the Action with the given digest locally, for
+  /// later retrieval by `load_action_result`. Action results recorded this way are never uploaded
+  /// to, or fetched from, the remote: see `record_remote_action_result` for that.
+  ///
+  /// Action results are not leased or garbage collected, so callers should verify that anything
+  /// an action result refers to is still present in the Store before using it.
+  ///
+  pub fn record_action_result(&self, action_digest: Digest, bytes: Bytes) -> BoxFuture<(), String> {
+    self.local.store_action_result(action_digest, bytes)
+  }
+
+  pub fn load_action_result(&self, action_digest: Digest) -> BoxFuture<Option<Bytes>, String> {
+    self.local.load_action_result(action_digest)
+  }
+
+  ///
+  /// Loads the result of the Action with the given digest from the ActionCache of the remote, if
+  /// there is a remote and it has a result for the Action. Nothing that the result refers to is
+  /// fetched.
+  ///
+  pub fn load_remote_action_result(
+    &self,
+    action_digest: Digest,
+  ) -> BoxFuture<Option<bazel_protos::remote_execution::ActionResult>, String> {
+    match self.remote {
+      Some(ref remote) => remote.get_action_result(action_digest),
+      None => future::ok(None).to_boxed(),
+    }
+  }
+
+  ///
+  /// Asks the remote (if there is one) to fetch the file with the given digest from one of the
+  /// given URIs straight into its CAS, without the file's content passing through this machine.
+  /// Returns whether it did: if so, the file can be loaded (lazily) from the remote.
+  ///
+  pub fn fetch_remote_file(&self, uris: Vec<String>, digest: Digest) -> BoxFuture<bool, String> {
+    match self.remote {
+      Some(ref remote) => remote.fetch_blob(uris, digest),
+      None => future::ok(false).to_boxed(),
+    }
+  }
+
+  ///
+  /// Records the result of the Action with the given digest in the ActionCache of the remote.
+  /// Everything that the result refers to should already have been uploaded, e.g. with
+  /// `ensure_remote_has_recursive`.
+  ///
+  pub fn record_remote_action_result(
+    &self,
+    action_digest: Digest,
+    action_result: bazel_protos::remote_execution::ActionResult,
+  ) -> BoxFuture<(), String> {
+    match self.remote {
+      Some(ref remote) => remote.update_action_result(action_digest, action_result),
+      None => future::err("Cannot record an action result without a remote".to_owned()).to_boxed(),
+    }
+  }
+
   ///
   /// Loads bytes from remote cas if required and possible (i.e. if remote is configured). Takes
   /// two functions f_local and f_remote. These functions are any validation or transformations you
@@ -248,23 +1338,82 @@ impl Store {
   ) -> BoxFuture<Option<T>, String> {
     let local = self.local.clone();
     let maybe_remote = self.remote.clone();
+    let small_blob_cache = self
+      .small_blob_cache
+      .clone()
+      .filter(|cache| cache.caches(digest));
+    let read_counts = self.read_counts.clone();
+    let read_through = self.tiering_policy.rules(entry_type).read_through;
     self
       .local
       .load_bytes_with(entry_type, digest, f_local)
-      .and_then(
-        move |maybe_local_value| match (maybe_local_value, maybe_remote) {
+      .and_then(move |maybe_local_value| {
+        let local_counter = if maybe_local_value.is_some() {
+          &read_counts.local_hits
+        } else {
+          &read_counts.local_misses
+        };
+        local_counter.fetch_add(1, Ordering::Relaxed);
+        match (maybe_local_value, maybe_remote) {
           (Some(value_result), _) => future::done(value_result.map(Some)).to_boxed(),
           (None, None) => future::ok(None).to_boxed(),
-          (None, Some(remote)) => remote
-            .load_bytes_with(entry_type, digest, move |bytes: Bytes| bytes)
-            .and_then(move |maybe_bytes: Option<Bytes>| match maybe_bytes {
-              Some(bytes) => future::done(f_remote(bytes.clone()))
-                .and_then(move |value| {
-                  local
-                    .store_bytes(entry_type, bytes, true)
-                    .and_then(move |stored_digest| {
+          (None, Some(remote)) => {
+            // Failures of the small blob cache are treated as misses, so that reads fall back to
+            // the remote.
+            let from_cache: BoxFuture<Option<Bytes>, String> = match small_blob_cache {
+              Some(ref cache) => {
+                let read_counts = read_counts.clone();
+                cache
+                  .load(digest)
+                  .then(move |result| {
+                    let maybe_bytes = result.unwrap_or_else(|err| {
+                      warn!("Error loading {:?} from small blob cache: {}", digest, err);
+                      None
+                    });
+                    let cache_counter = if maybe_bytes.is_some() {
+                      &read_counts.small_blob_cache_hits
+                    } else {
+                      &read_counts.small_blob_cache_misses
+                    };
+                    cache_counter.fetch_add(1, Ordering::Relaxed);
+                    Ok(maybe_bytes)
+                  })
+                  .to_boxed()
+              }
+              None => future::ok(None).to_boxed(),
+            };
+            from_cache
+              .and_then(
+                move |maybe_cached_bytes: Option<Bytes>| match maybe_cached_bytes {
+                  Some(bytes) => future::ok((Some(bytes), None)).to_boxed(),
+                  None => remote
+                    .load_bytes_with(entry_type, digest, move |bytes: Bytes| bytes)
+                    .map(move |maybe_bytes: Option<Bytes>| {
+                      let remote_counter = if maybe_bytes.is_some() {
+                        &read_counts.remote_hits
+                      } else {
+                        &read_counts.remote_misses
+                      };
+                      remote_counter.fetch_add(1, Ordering::Relaxed);
+                      // Blobs from the remote are cached once they are known to be intact.
+                      (maybe_bytes, small_blob_cache)
+                    })
+                    .to_boxed(),
+                },
+              )
+              .and_then(move |(maybe_bytes, cache_to_fill)| match maybe_bytes {
+                Some(bytes) => future::done(f_remote(bytes.clone()))
+                  .and_then(move |value| {
+                    // Blobs which aren't read through are hashed to verify them, rather than
+                    // hashed as they are stored locally.
+                    let stored_digest = if read_through {
+                      local.store_bytes(entry_type, bytes.clone(), true)
+                    } else {
+                      future::ok(http_cache::digest_of(&bytes)).to_boxed()
+                    };
+                    stored_digest.and_then(move |stored_digest| {
                       if digest == stored_digest {
-                        Ok(Some(value))
+                        Ok((value, bytes))
                       } else {
                         Err(format!(
                           "CAS gave wrong digest: expected {:?}, got {:?}",
@@ -272,13 +1421,26 @@ impl Store {
                         ))
                       }
                     })
-                })
-                .to_boxed(),
-              None => future::ok(None).to_boxed(),
-            })
-            .to_boxed(),
-        },
-      )
+                  })
+                  .and_then(move |(value, bytes)| match cache_to_fill {
+                    Some(cache) => cache
+                      .store(digest, bytes)
+                      .then(move |result| {
+                        if let Err(err) = result {
+                          warn!("Error storing {:?} in small blob cache: {}", digest, err);
+                        }
+                        Ok(Some(value))
+                      })
+                      .to_boxed(),
+                    None => future::ok(Some(value)).to_boxed(),
+                  })
+                  .to_boxed(),
+                None => future::ok(None).to_boxed(),
+              })
+              .to_boxed()
+          }
+        }
+      })
       .to_boxed()
   }
 
@@ -286,6 +1448,13 @@ impl Store {
   /// Ensures that the remote ByteStore has a copy of each passed Fingerprint, including any files
   /// contained in any Directories in the list.
   ///
+  /// Unless there are too few blobs for it to be worthwhile, the remote is first asked (in batches)
+  /// which of the blobs it is missing, and only those are uploaded. Blobs which are known to be in
+  /// the remote (see `with_known_remote_blobs`) are neither asked about nor uploaded.
+  ///
+  /// If the leases of the blobs which were ensured before are due to be extended, that happens
+  /// alongside.
+  ///
   /// Returns a structure with the summary of operations.
   ///
   pub fn ensure_remote_has_recursive(
@@ -322,9 +1491,8 @@ impl Store {
       };
     }
 
-    let local = self.local.clone();
+    let store = self.clone();
     let remote = remote.clone();
-    let remote2 = remote.clone();
     future::join_all(expanding_futures)
       .map(move |futures| {
         for mut digests in futures {
@@ -335,35 +1503,179 @@ impl Store {
         expanded_digests
       })
       .and_then(move |ingested_digests| {
-        if Store::upload_is_faster_than_checking_whether_to_upload(&ingested_digests) {
-          return future::ok((ingested_digests.keys().cloned().collect(), ingested_digests))
+        let leases_to_extend =
+          store
+            .remote_leases
+            .record(remote.instance_name(), &ingested_digests, Instant::now());
+        let extend_leases = store.extend_remote_leases(leases_to_extend);
+        let unknown_digests = match store.known_remote_blobs {
+          Some(ref known_remote_blobs) => known_remote_blobs.unknown(
+            &remote.instance_name(),
+            &ingested_digests,
+            SystemTime::now(),
+          ),
+          None => ingested_digests.clone(),
+        };
+        let digests_to_upload =
+          if Store::upload_is_faster_than_checking_whether_to_upload(&unknown_digests) {
+            future::ok(unknown_digests.keys().cloned().collect()).to_boxed()
+          } else {
+            let digests = unknown_digests.keys().cloned().collect();
+            remote
+              .capabilities()
+              .and_then(move |capabilities| remote.list_missing_digests(digests, capabilities))
+              .map(|missing_digests| missing_digests.into_iter().collect())
+              .to_boxed()
+          };
+        digests_to_upload
+          .and_then(move |digests_to_upload| {
+            store.upload(digests_to_upload, ingested_digests, start_time)
+          })
+          .join(extend_leases)
+          .map(|(upload_summary, ())| upload_summary)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Extends the leases of the given blobs in the remote, by asking it which of them it is missing
+  /// (which counts as a use of the others), and re-uploading those.
+  ///
+  /// Failing to extend leases is not an error, because the blobs may not be needed again.
+  ///
+  fn extend_remote_leases(&self, blobs: HashMap<Digest, EntryType>) -> BoxFuture<(), String> {
+    let remote = match self.remote {
+      Some(ref remote) if !blobs.is_empty() => remote.clone(),
+      _ => return future::ok(()).to_boxed(),
+    };
+    let store = self.clone();
+    let digests = blobs.keys().cloned().collect();
+    remote
+      .capabilities()
+      .and_then(move |capabilities| remote.list_missing_digests(digests, capabilities))
+      .and_then(move |missing_digests| {
+        debug!(
+          "Extended the remote leases of {} blobs, of which {} were missing",
+          blobs.len(),
+          missing_digests.len()
+        );
+        store.upload(missing_digests.into_iter().collect(), blobs, Instant::now())
+      })
+      .then(|result| {
+        if let Err(err) = result {
+          warn!(
+            "Failed to extend the leases of blobs in the remote store: {}",
+            err
+          );
+        }
+        Ok(())
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Uploads exactly the passed blobs (and not the contents of Directories among them) to the
+  /// remote ByteStore, without first asking it which it is missing: e.g. because it reported them
+  /// as missing.
+  ///
+  pub fn ensure_remote_has(&self, digests: Vec<Digest>) -> BoxFuture<UploadSummary, String> {
+    let start_time = Instant::now();
+
+    let mut ingested_digests = HashMap::new();
+    for digest in &digests {
+      match self.local.entry_type(&digest.0) {
+        Ok(Some(entry_type)) => {
+          ingested_digests.insert(*digest, entry_type);
+        }
+        Ok(None) => {
+          return future::err(format!("Failed to upload digest {:?}: Not found", digest))
             .to_boxed();
         }
-        let request = remote.find_missing_blobs_request(ingested_digests.keys());
-        let f = remote.list_missing_digests(request);
-        f.map(move |digests_to_upload| (digests_to_upload, ingested_digests))
-          .to_boxed()
+        Err(err) => {
+          return future::err(format!("Failed to upload digest {:?}: {:?}", digest, err))
+            .to_boxed();
+        }
+      }
+    }
+    self.upload(digests, ingested_digests, start_time)
+  }
+
+  fn upload(
+    &self,
+    digests_to_upload: Vec<Digest>,
+    ingested_digests: HashMap<Digest, EntryType>,
+    start_time: Instant,
+  ) -> BoxFuture<UploadSummary, String> {
+    let remote = if let Some(ref remote) = self.remote {
+      remote.clone()
+    } else {
+      return future::err("Cannot ensure remote has blobs without a remote".to_owned()).to_boxed();
+    };
+    // Blobs which are too large for the tiering policy to upload are left out, and so are not
+    // counted as uploaded.
+    let tiering_policy = self.tiering_policy;
+    let digests_to_upload: Vec<Digest> = digests_to_upload
+      .into_iter()
+      .filter(|digest| {
+        let uploads = tiering_policy.uploads(ingested_digests[digest], *digest);
+        if !uploads {
+          debug!(
+            "Not uploading {:?}, which is larger than the tiering policy uploads",
+            digest
+          );
+        }
+        uploads
       })
-      .and_then(move |(digests_to_upload, ingested_digests)| {
-        future::join_all(
-          digests_to_upload
-            .into_iter()
-            .map(|digest| {
-              let entry_type = ingested_digests[&digest];
-              let remote = remote2.clone();
-              local
-                .load_bytes_with(entry_type, digest, move |bytes| remote.store_bytes(bytes))
-                .and_then(move |maybe_future| match maybe_future {
-                  Some(future) => Ok(future),
-                  None => Err(format!("Failed to upload digest {:?}: Not found", digest)),
-                })
+      .collect();
+    let known_remote_blobs = self.known_remote_blobs.clone();
+    let instance_name = remote.instance_name();
+    let local = self.local.clone();
+    remote
+      .capabilities()
+      .and_then(move |capabilities| {
+        // Small blobs are uploaded together in batches, and larger ones individually.
+        let (batches, unbatched) =
+          remote::ByteStore::batches(digests_to_upload, capabilities.max_batch_bytes());
+        let load_bytes = |digest: Digest| {
+          local
+            .load_bytes_with(ingested_digests[&digest], digest, |bytes| bytes)
+            .and_then(move |maybe_bytes| {
+              maybe_bytes.ok_or_else(|| format!("Failed to upload digest {:?}: Not found", digest))
             })
-            .collect::<Vec<_>>(),
+        };
+        let batch_uploads = batches.into_iter().map(|batch| {
+          let remote = remote.clone();
+          future::join_all(batch.into_iter().map(&load_bytes).collect::<Vec<_>>())
+            .and_then(move |blobs| remote.store_bytes_batch(blobs))
+            .to_boxed()
+        });
+        let individual_uploads = unbatched.into_iter().map(|digest| {
+          let remote = remote.clone();
+          load_bytes(digest)
+            .and_then(move |bytes| remote.store_bytes(bytes))
+            .map(|digest| vec![digest])
+            .to_boxed()
+        });
+        future::join_all(batch_uploads.chain(individual_uploads).collect::<Vec<_>>()).map(
+          move |uploaded_digests| {
+            let uploaded_digests: Vec<Digest> = uploaded_digests.into_iter().flatten().collect();
+            (uploaded_digests, ingested_digests)
+          },
         )
-        .and_then(future::join_all)
-        .map(|uploaded_digests| (uploaded_digests, ingested_digests))
       })
       .map(move |(uploaded_digests, ingested_digests)| {
+        // The remote now has every blob which it was ensured to have, except any which were too
+        // large to upload.
+        if let Some(known_remote_blobs) = known_remote_blobs {
+          known_remote_blobs.record(
+            &instance_name,
+            ingested_digests
+              .iter()
+              .filter(|(digest, entry_type)| tiering_policy.uploads(**entry_type, **digest))
+              .map(|(digest, _)| digest),
+            SystemTime::now(),
+          );
+        }
         let ingested_file_sizes = ingested_digests.iter().map(|(digest, _)| digest.1);
         let uploaded_file_sizes = uploaded_digests.iter().map(|digest| digest.1);
 
@@ -391,14 +1703,12 @@ impl Store {
       })
       .and_then(move |directory| {
         // Traverse the files within directory
-        let file_futures = directory
+        let file_digests = try_future!(directory
           .get_files()
           .iter()
-          .map(|file_node| {
-            let file_digest = try_future!(file_node.get_digest().into());
-            store.load_bytes_with(EntryType::File, file_digest, |_| Ok(()), |_| Ok(()))
-          })
-          .collect::<Vec<_>>();
+          .map(|file_node| file_node.get_digest().into())
+          .collect::<Result<Vec<Digest>, String>>());
+        let files_future = store.ensure_local_has_files(file_digests);
 
         // Recursively call with sub-directories
         let directory_futures = directory
@@ -409,9 +1719,82 @@ impl Store {
             store.ensure_local_has_recursive_directory(child_digest)
           })
           .collect::<Vec<_>>();
-        future::join_all(file_futures)
+        files_future
           .join(future::join_all(directory_futures))
           .map(|_| ())
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Fetches any of the given files which the local store is missing from the remote (if there is
+  /// one), with small files fetched together in batches. Files which neither has are ignored.
+  ///
+  fn ensure_local_has_files(&self, digests: Vec<Digest>) -> BoxFuture<(), String> {
+    let remote = match self.remote {
+      Some(ref remote) => remote.clone(),
+      None => return future::ok(()).to_boxed(),
+    };
+    let mut missing_digests = vec![];
+    for digest in digests {
+      match self.local.entry_type(&digest.0) {
+        Ok(Some(_)) => {}
+        Ok(None) => missing_digests.push(digest),
+        Err(err) => {
+          return future::err(format!(
+            "Failed to check for digest {:?}: {:?}",
+            digest, err
+          ))
+          .to_boxed();
+        }
+      }
+    }
+    if missing_digests.is_empty() {
+      return future::ok(()).to_boxed();
+    }
+
+    let store = self.clone();
+    remote
+      .capabilities()
+      .and_then(move |capabilities| {
+        let (batches, unbatched) =
+          remote::ByteStore::batches(missing_digests, capabilities.max_batch_bytes());
+        let batch_fetches = batches.into_iter().map(|batch| {
+          let local = store.local.clone();
+          remote
+            .load_bytes_batch(batch)
+            .and_then(move |blobs| {
+              future::join_all(
+                blobs
+                  .into_iter()
+                  .map(|(digest, bytes)| {
+                    local
+                      .store_bytes(EntryType::File, bytes, true)
+                      .and_then(move |stored_digest| {
+                        if digest == stored_digest {
+                          Ok(())
+                        } else {
+                          Err(format!(
+                            "CAS gave wrong digest: expected {:?}, got {:?}",
+                            digest, stored_digest
+                          ))
+                        }
+                      })
+                  })
+                  .collect::<Vec<_>>(),
+              )
+            })
+            .map(|_| ())
+            .to_boxed()
+        });
+        let individual_fetches = unbatched.into_iter().map(|digest| {
+          store
+            .load_bytes_with(EntryType::File, digest, |_| Ok(()), |_| Ok(()))
+            .map(|_| ())
+            .to_boxed()
+        });
+        future::join_all(batch_fetches.chain(individual_fetches).collect::<Vec<_>>()).map(|_| ())
       })
       .to_boxed()
   }
@@ -420,12 +1803,163 @@ impl Store {
     self.local.lease_all(digests)
   }
 
+  ///
+  /// Statistics about the entries in local storage, and the reads of this Store since it was
+  /// created.
+  ///
+  /// Note: This reads every entry in local storage, on the calling thread.
+  ///
+  pub fn stats(&self) -> Result<StoreStats, String> {
+    Ok(StoreStats {
+      files: self.local.shard_stats(EntryType::File)?,
+      directories: self.local.shard_stats(EntryType::Directory)?,
+      local_hits: self.read_counts.local_hits.load(Ordering::Relaxed),
+      local_misses: self.read_counts.local_misses.load(Ordering::Relaxed),
+      small_blob_cache_hits: self
+        .read_counts
+        .small_blob_cache_hits
+        .load(Ordering::Relaxed),
+      small_blob_cache_misses: self
+        .read_counts
+        .small_blob_cache_misses
+        .load(Ordering::Relaxed),
+      remote_hits: self.read_counts.remote_hits.load(Ordering::Relaxed),
+      remote_misses: self.read_counts.remote_misses.load(Ordering::Relaxed),
+    })
+  }
+
+  ///
+  /// The counts of the reads of this Store since it was created, which (unlike `stats`) are cheap
+  /// to get.
+  ///
+  pub fn read_metrics(&self) -> Vec<(&'static str, i64)> {
+    self.read_counts.metrics()
+  }
+
+  ///
+  /// Checks that every file and Directory in local storage can be decompressed (if it was stored
+  /// compressed) and has the fingerprint that it is stored as, and that every Directory is a
+  /// valid, canonical Directory proto. Returns the entries which are broken, and unless this is a
+  /// dry run deletes them, so that they are treated as missing (and so re-fetched from the remote,
+  /// if there is one) from now on. The read-only copies of files which materialized files are
+  /// linked to are checked too, and those which have been modified are deleted (to be re-made).
+  ///
+  /// Note: This reads (and hashes) every entry in local storage, on the calling thread.
+  ///
+  /// Only one of the processes which share local storage checks (or otherwise collects) it at a
+  /// time: if another is already collecting it, nothing is checked, and this fails (rather than
+  /// reporting that nothing is broken).
+  ///
+  pub fn fsck(&self, dry_run: bool) -> Result<Vec<BrokenEntry>, String> {
+    let _collection_lock = match self.local.try_lock_for_collection()? {
+      Some(lock) => lock,
+      None => {
+        return Err(
+          "Not checking the local store, because another process is collecting it".to_owned(),
+        );
+      }
+    };
+    let mut broken_entries = self
+      .local
+      .fsck(dry_run)
+      .map_err(|err| format!("Checking the local store failed: {}", err))?;
+    broken_entries.extend(self.fsck_materialized_files(dry_run)?);
+    Ok(broken_entries)
+  }
+
+  fn fsck_materialized_files(&self, dry_run: bool) -> Result<Vec<BrokenEntry>, String> {
+    let root = &self.materialized_files_root;
+    let entries = match std::fs::read_dir(root) {
+      Ok(entries) => entries,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+      Err(e) => return Err(format!("Error listing {:?}: {:?}", root, e)),
+    };
+    let mut broken_entries = vec![];
+    for entry in entries {
+      let entry = entry.map_err(|e| format!("Error listing {:?}: {:?}", root, e))?;
+      let (digest, is_executable) = match entry
+        .file_name()
+        .to_str()
+        .and_then(Self::parse_materialized_file_name)
+      {
+        Some(parsed) => parsed,
+        None => continue,
+      };
+      let path = entry.path();
+      let intact = std::fs::metadata(&path)
+        .and_then(|metadata| {
+          if !Self::materialized_file_is_reusable(&metadata, digest, is_executable) {
+            return Ok(false);
+          }
+          let mut hasher = WriterHasher::new(io::sink());
+          io::copy(&mut std::fs::File::open(&path)?, &mut hasher)?;
+          Ok(hasher.finish().0 == digest)
+        })
+        .map_err(|e| format!("Error checking {:?}: {:?}", path, e))?;
+      if intact {
+        continue;
+      }
+      if !dry_run {
+        std::fs::remove_file(&path).map_err(|e| format!("Error removing {:?}: {:?}", path, e))?;
+      }
+      broken_entries.push(BrokenEntry {
+        entry_type: EntryType::File,
+        fingerprint: digest.0,
+        problem: EntryProblem::ModifiedMaterializedCopy,
+      });
+    }
+    Ok(broken_entries)
+  }
+
+  ///
+  /// Evicts the entries of local storage which the given policy evicts (and the materialized
+  /// copies of evicted files), returning them, or in a dry run only returns them.
+  ///
+  /// Note: This reads every entry in local storage, on the calling thread.
+  ///
+  /// Only one of the processes which share local storage evicts from it at a time: if another is
+  /// already collecting it, nothing is evicted.
+  ///
+  pub fn evict(&self, policy: &EvictionPolicy, dry_run: bool) -> Result<Vec<Eviction>, String> {
+    let _collection_lock = match self.local.try_lock_for_collection()? {
+      Some(lock) => lock,
+      None => {
+        info!("Not evicting from the local store, because another process is collecting it");
+        return Ok(vec![]);
+      }
+    };
+    let evictions = self
+      .local
+      .evict(policy, dry_run)
+      .map_err(|err| format!("Eviction failed: {}", err))?;
+    if !dry_run {
+      self.remove_uncollected_materialized_files()?;
+    }
+    Ok(evictions)
+  }
+
+  ///
+  /// Shrinks local storage to the given size (see `local::ByteStore::shrink`), and removes the
+  /// materialized copies of the files which are no longer stored.
+  ///
+  /// Only one of the processes which share local storage garbage collects it at a time: if another
+  /// is already collecting it, this does nothing.
+  ///
   pub fn garbage_collect(
     &self,
     target_size_bytes: usize,
     shrink_behavior: ShrinkBehavior,
   ) -> Result<(), String> {
-    match self.local.shrink(target_size_bytes, shrink_behavior) {
+    let _collection_lock = match self.local.try_lock_for_collection()? {
+      Some(lock) => lock,
+      None => {
+        info!("Not garbage collecting the local store, because another process is collecting it");
+        return Ok(());
+      }
+    };
+    let shrunk = self.local.shrink(target_size_bytes, shrink_behavior);
+    self.remove_uncollected_materialized_files()?;
+    match shrunk {
       Ok(size) => {
         if size > target_size_bytes {
           Err(format!(
@@ -476,14 +2010,101 @@ impl Store {
       .to_boxed()
   }
 
+  ///
+  /// Whether the Directory with the given digest, and every Directory and file beneath it, is in
+  /// the local store: since entries may be garbage collected or evicted independently, something
+  /// which refers to a whole tree (like a cached action result) should check that it is complete
+  /// before using it. Nothing is fetched from the remote.
+  ///
+  pub fn contains_directory_recursive(&self, digest: Digest) -> BoxFuture<bool, String> {
+    let store = self.clone();
+    self
+      .local
+      .load_bytes_with(EntryType::Directory, digest, move |bytes: Bytes| {
+        let mut directory = bazel_protos::remote_execution::Directory::new();
+        directory.merge_from_bytes(&bytes).map_err(|e| {
+          format!(
+            "LMDB corruption: Directory bytes for {:?} were not valid: {:?}",
+            digest, e
+          )
+        })?;
+        Ok(directory)
+      })
+      .and_then(move |maybe_directory| {
+        let directory = match maybe_directory {
+          Some(directory) => try_future!(directory),
+          None => return future::ok(false).to_boxed(),
+        };
+        for file in directory.get_files() {
+          let file_digest: Digest = try_future!(file.get_digest().into());
+          if try_future!(store.local.entry_type(&file_digest.0)).is_none() {
+            return future::ok(false).to_boxed();
+          }
+        }
+        future::join_all(
+          directory
+            .get_directories()
+            .iter()
+            .map(|dir_node| {
+              let subdir_digest = try_future!(dir_node.get_digest().into());
+              store.contains_directory_recursive(subdir_digest)
+            })
+            .collect::<Vec<_>>(),
+        )
+        .map(|contained| contained.into_iter().all(|contains| contains))
+        .to_boxed()
+      })
+      .to_boxed()
+  }
+
   ///
   /// Lays out the directory and all of its contents (files and directories) on disk so that a
   /// process which uses the directory structure can run.
   ///
-  pub fn materialize_directory(
+  pub fn materialize_directory(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
+  ) -> BoxFuture<(), String> {
+    self.materialize_directory_with(
+      destination,
+      digest,
+      MaterializationStrategy::Copy,
+      &BTreeSet::new(),
+    )
+  }
+
+  ///
+  /// As `materialize_directory`, but laying out files using the given strategy, except for the
+  /// files which must be writable (which are, or are beneath, any of the given relative paths),
+  /// which are copied.
+  ///
+  pub fn materialize_directory_with(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
+    strategy: MaterializationStrategy,
+    writable_paths: &BTreeSet<PathBuf>,
+  ) -> BoxFuture<(), String> {
+    self.materialize_subdirectory_with(
+      destination,
+      PathBuf::new(),
+      digest,
+      strategy,
+      Arc::new(writable_paths.clone()),
+    )
+  }
+
+  ///
+  /// Materializes the Directory at the given path relative to the root of a materialization.
+  ///
+  fn materialize_subdirectory_with(
     &self,
     destination: PathBuf,
+    relative_path: PathBuf,
     digest: Digest,
+    strategy: MaterializationStrategy,
+    writable_paths: Arc<BTreeSet<PathBuf>>,
   ) -> BoxFuture<(), String> {
     try_future!(super::safe_create_dir_all(&destination));
     let store = self.clone();
@@ -499,8 +2120,10 @@ impl Store {
           .map(|file_node| {
             let store = store.clone();
             let path = destination.join(file_node.get_name());
+            let strategy =
+              strategy.for_file(&relative_path.join(file_node.get_name()), &writable_paths);
             let digest = try_future!(file_node.get_digest().into());
-            store.materialize_file(path, digest, file_node.is_executable)
+            store.materialize_file_with(path, digest, file_node.is_executable, strategy)
           })
           .collect::<Vec<_>>();
         let directory_futures = directory
@@ -510,7 +2133,13 @@ impl Store {
             let store = store.clone();
             let path = destination.join(directory_node.get_name());
             let digest = try_future!(directory_node.get_digest().into());
-            store.materialize_directory(path, digest)
+            store.materialize_subdirectory_with(
+              path,
+              relative_path.join(directory_node.get_name()),
+              digest,
+              strategy,
+              writable_paths.clone(),
+            )
           })
           .collect::<Vec<_>>();
         future::join_all(file_futures)
@@ -520,6 +2149,301 @@ impl Store {
       .to_boxed()
   }
 
+  ///
+  /// Updates a directory on disk which was previously materialized from the Directory `previous`
+  /// (if any) to match `digest`: only files which were added or changed are written, and files
+  /// which were removed are deleted. Anything else in the destination (i.e. which was not
+  /// materialized from `previous`) is left untouched. Files are laid out as by
+  /// `materialize_directory_with`.
+  ///
+  pub fn materialize_directory_incrementally(
+    &self,
+    destination: PathBuf,
+    previous: Option<Digest>,
+    digest: Digest,
+    strategy: MaterializationStrategy,
+    writable_paths: &BTreeSet<PathBuf>,
+  ) -> BoxFuture<(), String> {
+    try_future!(super::safe_create_dir_all(&destination));
+    let previous_files = match previous {
+      Some(previous) => self
+        .directory_entries(previous)
+        .map(|(_, files)| files)
+        .to_boxed(),
+      None => future::ok(BTreeMap::new()).to_boxed(),
+    };
+    let store = self.clone();
+    let writable_paths = writable_paths.clone();
+    previous_files
+      .join(self.directory_entries(digest))
+      .and_then(move |(previous_files, (dirs, files))| {
+        let remove = |path: &Path| match std::fs::remove_file(path) {
+          Ok(()) => Ok(()),
+          Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+          Err(e) => Err(format!("Error removing file {:?}: {:?}", path, e)),
+        };
+        for path in previous_files
+          .keys()
+          .filter(|path| !files.contains_key(*path))
+        {
+          try_future!(remove(&destination.join(path)));
+        }
+        for dir in dirs {
+          try_future!(super::safe_create_dir_all(&destination.join(dir)));
+        }
+        let file_futures = files
+          .into_iter()
+          .filter(|(path, file)| previous_files.get(path) != Some(file))
+          .map(|(path, (digest, is_executable))| {
+            let strategy = strategy.for_file(&path, &writable_paths);
+            // Changed files are replaced rather than overwritten, so that their mode is updated.
+            let path = destination.join(path);
+            try_future!(remove(&path));
+            store.materialize_file_with(path, digest, is_executable, strategy)
+          })
+          .collect::<Vec<_>>();
+        future::join_all(file_futures).map(|_| ()).to_boxed()
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// The relative paths of all of the directories beneath the given Directory, and the digest and
+  /// executable bit of each of its files, by their relative path.
+  ///
+  fn directory_entries(
+    &self,
+    digest: Digest,
+  ) -> BoxFuture<(Vec<PathBuf>, BTreeMap<PathBuf, (Digest, bool)>), String> {
+    self
+      .walk(digest, |_, path_so_far, _, directory| {
+        let files: Result<Vec<_>, String> = directory
+          .get_files()
+          .iter()
+          .map(|file_node| {
+            let digest: Result<Digest, String> = file_node.get_digest().into();
+            digest.map(|digest| {
+              (
+                path_so_far.join(file_node.get_name()),
+                (digest, file_node.is_executable),
+              )
+            })
+          })
+          .collect();
+        let path_so_far = path_so_far.clone();
+        future::done(files.map(|files| (path_so_far, files))).to_boxed()
+      })
+      .map(|entries_per_directory| {
+        let mut dirs = Vec::new();
+        let mut files = BTreeMap::new();
+        for (dir, dir_files) in entries_per_directory {
+          dirs.push(dir);
+          files.extend(dir_files);
+        }
+        (dirs, files)
+      })
+      .to_boxed()
+  }
+
+  fn materialize_file_with(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
+    is_executable: bool,
+    strategy: MaterializationStrategy,
+  ) -> BoxFuture<(), String> {
+    let link: fn(&Path, &Path) -> io::Result<()> = match strategy {
+      MaterializationStrategy::Copy => {
+        return self.materialize_file(destination, digest, is_executable);
+      }
+      MaterializationStrategy::Hardlink => |src, dst| std::fs::hard_link(src, dst),
+      MaterializationStrategy::Symlink => |src, dst| std::os::unix::fs::symlink(src, dst),
+      MaterializationStrategy::Reflink => {
+        return self.reflink_file(destination, digest, is_executable);
+      }
+    };
+    let store = self.clone();
+    self
+      .materialized_file(digest, is_executable)
+      .and_then(move |source| match link(&source, &destination) {
+        Ok(()) => future::ok(()).to_boxed(),
+        // Hardlinks can't cross filesystems, or exceed the filesystem's limit of links per file.
+        Err(ref e) if e.raw_os_error() == Some(EXDEV) || e.raw_os_error() == Some(EMLINK) => {
+          store.materialize_file(destination, digest, is_executable)
+        }
+        Err(e) => future::err(format!(
+          "Error linking file {:?} to {:?}: {:?}",
+          destination, source, e
+        ))
+        .to_boxed(),
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Clones the read-only copy of the file to the destination, or copies the file there if it
+  /// can't be cloned. No read-only copy is made once it is known that files can't be cloned to the
+  /// destination: e.g. because it is on a different filesystem to the Store, which is checked
+  /// first.
+  ///
+  fn reflink_file(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
+    is_executable: bool,
+  ) -> BoxFuture<(), String> {
+    let root = &self.materialized_files_root;
+    try_future!(super::safe_create_dir_all(root));
+    let known_unsupported = match destination.parent() {
+      Some(dir) => try_future!(self
+        .reflinks
+        .known_unsupported(root, dir)
+        .map_err(|e| format!(
+          "Error checking whether files can be cloned from {:?} to {:?}: {:?}",
+          root, dir, e
+        ))),
+      None => false,
+    };
+    if known_unsupported {
+      return self.materialize_file(destination, digest, is_executable);
+    }
+    let store = self.clone();
+    self
+      .materialized_file(digest, is_executable)
+      .and_then(move |source| {
+        let mode = if is_executable { 0o755 } else { 0o644 };
+        match store.reflinks.clone_file(&source, &destination, mode) {
+          Ok(true) => future::ok(()).to_boxed(),
+          Ok(false) => store.materialize_file(destination, digest, is_executable),
+          Err(e) => future::err(format!(
+            "Error cloning file {:?} to {:?}: {:?}",
+            source, destination, e
+          ))
+          .to_boxed(),
+        }
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// The path of a read-only copy of the given file, in the directory of materialized files which
+  /// files are linked to (or cloned) from. The copy is made if it doesn't already exist, or if the
+  /// existing copy has the wrong size or mode: because it is linked to, a process may have made it
+  /// writable and changed it.
+  ///
+  fn materialized_file(&self, digest: Digest, is_executable: bool) -> BoxFuture<PathBuf, String> {
+    let root = self.materialized_files_root.clone();
+    let path = root.join(Self::materialized_file_name(digest, is_executable));
+    if let Ok(metadata) = std::fs::metadata(&path) {
+      if Self::materialized_file_is_reusable(&metadata, digest, is_executable) {
+        return future::ok(path).to_boxed();
+      }
+      // Replaced below: the rename of the new copy into place is atomic.
+      warn!("Replacing modified materialized file {:?}", path);
+    }
+    self
+      .load_file_bytes_with(digest, move |bytes| {
+        super::safe_create_dir_all(&root)?;
+        // The copy is written to a temporary file which is then renamed into place, so that
+        // concurrent materializations never link to a partially written file.
+        let mut tmp = tempfile::NamedTempFile::new_in(&root)
+          .map_err(|e| format!("Error making temporary file in {:?}: {:?}", root, e))?;
+        tmp
+          .write_all(&bytes)
+          .and_then(|()| tmp.as_file().sync_all())
+          .and_then(|()| {
+            std::fs::set_permissions(
+              tmp.path(),
+              std::fs::Permissions::from_mode(if is_executable { 0o555 } else { 0o444 }),
+            )
+          })
+          .map_err(|e| format!("Error writing file {:?}: {:?}", tmp.path(), e))?;
+        tmp
+          .persist(&path)
+          .map_err(|e| format!("Error writing file {:?}: {:?}", path, e.error))?;
+        Ok(path.clone())
+      })
+      .and_then(move |write_result| match write_result {
+        Some(result) => result,
+        None => Err(format!("File with digest {:?} not found", digest)),
+      })
+      .to_boxed()
+  }
+
+  fn materialized_file_name(digest: Digest, is_executable: bool) -> String {
+    format!(
+      "{}-{}{}",
+      digest.0.to_hex(),
+      digest.1,
+      if is_executable { "-x" } else { "" }
+    )
+  }
+
+  ///
+  /// The inverse of `materialized_file_name`, or None for the names of other files (like the
+  /// temporary files which copies are written to).
+  ///
+  fn parse_materialized_file_name(name: &str) -> Option<(Digest, bool)> {
+    let (name, is_executable) = if name.ends_with("-x") {
+      (&name[..name.len() - 2], true)
+    } else {
+      (name, false)
+    };
+    let mut parts = name.splitn(2, '-');
+    let fingerprint = Fingerprint::from_hex_string(parts.next()?).ok()?;
+    let size_bytes = parts.next()?.parse().ok()?;
+    Some((Digest(fingerprint, size_bytes), is_executable))
+  }
+
+  fn materialized_file_is_reusable(
+    metadata: &std::fs::Metadata,
+    digest: Digest,
+    is_executable: bool,
+  ) -> bool {
+    let mode = if is_executable { 0o555 } else { 0o444 };
+    metadata.is_file()
+      && metadata.len() == digest.1 as u64
+      && metadata.permissions().mode() & 0o7777 == mode
+  }
+
+  ///
+  /// Removes the read-only copies of files in the directory of materialized files whose content is
+  /// no longer in local storage, because it was garbage collected or evicted, so that copies don't
+  /// outlive their entries.
+  ///
+  /// Note: This performs IO on the calling thread.
+  ///
+  fn remove_uncollected_materialized_files(&self) -> Result<(), String> {
+    let root = &self.materialized_files_root;
+    let entries = match std::fs::read_dir(root) {
+      Ok(entries) => entries,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => return Err(format!("Error listing {:?}: {:?}", root, e)),
+    };
+    let mut removed = 0;
+    for entry in entries {
+      let entry = entry.map_err(|e| format!("Error listing {:?}: {:?}", root, e))?;
+      let digest = match entry
+        .file_name()
+        .to_str()
+        .and_then(Self::parse_materialized_file_name)
+      {
+        Some((digest, _)) => digest,
+        None => continue,
+      };
+      if self.local.entry_type(&digest.0)?.is_some() {
+        continue;
+      }
+      match std::fs::remove_file(entry.path()) {
+        Ok(()) => removed += 1,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("Error removing {:?}: {:?}", entry.path(), e)),
+      }
+    }
+    debug!("Removed {} uncollected materialized files", removed);
+    Ok(())
+  }
+
   fn materialize_file(
     &self,
     destination: PathBuf,
@@ -618,6 +2542,149 @@ impl Store {
       .to_boxed()
   }
 
+  ///
+  /// Given the Digest for a Directory, lazily stream the path and node of every file and directory
+  /// beneath it (but not the Directory itself).
+  ///
+  /// Unlike `walk`, which collects its results, only one Directory is loaded at a time, and only
+  /// the digests of the subdirectories which are yet to be visited are held in memory, so this can
+  /// be used to visit trees which are too large to expand in memory. The entries of each Directory
+  /// are yielded in order, before those of its subdirectories (depth first, also in order).
+  ///
+  pub fn walk_entries(&self, digest: Digest) -> BoxStream<(PathBuf, WalkEntry), String> {
+    let store = self.clone();
+    let unvisited = vec![(PathBuf::new(), digest)];
+    Box::new(
+      stream::unfold(unvisited, move |mut unvisited| {
+        let (path, digest) = unvisited.pop()?;
+        Some(
+          store
+            .load_directory(digest)
+            .and_then(move |maybe_directory| {
+              let directory = maybe_directory.ok_or_else(|| {
+                format!(
+                  "Could not walk unknown directory at {:?}: {:?}",
+                  path, digest
+                )
+              })?;
+              let mut subdirectories = Vec::with_capacity(directory.get_directories().len());
+              for dir_node in directory.get_directories() {
+                let subdir_digest: Result<Digest, String> = dir_node.get_digest().into();
+                subdirectories.push((path.join(dir_node.get_name()), subdir_digest?));
+              }
+              // Pushed in reverse, so that they are popped (and so visited) in order.
+              unvisited.extend(subdirectories.into_iter().rev());
+
+              let files = directory.get_files().iter().map(|file_node| {
+                (
+                  path.join(file_node.get_name()),
+                  WalkEntry::File(file_node.clone()),
+                )
+              });
+              let directories = directory.get_directories().iter().map(|dir_node| {
+                (
+                  path.join(dir_node.get_name()),
+                  WalkEntry::Directory(dir_node.clone()),
+                )
+              });
+              Ok((files.chain(directories).collect::<Vec<_>>(), unvisited))
+            }),
+        )
+      })
+      .map(stream::iter_ok::<_, String>)
+      .flatten(),
+    )
+  }
+
+  ///
+  /// Writes the Directory with the given digest, and every Directory and file beneath it, to an
+  /// archive (see `archive::Entry`) at the given path, which `import_archive` can import into
+  /// another Store, e.g. to reproduce a remote execution on another machine. Blobs which are
+  /// missing locally are fetched from the remote, if there is one.
+  ///
+  /// The tree is streamed into the archive (see `walk_entries`), but the digest of every blob
+  /// which was written is held in memory, so that blobs which appear many times in the tree are
+  /// only written once.
+  ///
+  pub fn export_archive(&self, digest: Digest, archive_path: &Path) -> BoxFuture<(), String> {
+    let store = self.clone();
+    let mut exported = HashSet::new();
+    let blobs = stream::once(Ok((EntryType::Directory, digest)))
+      .chain(self.walk_entries(digest).and_then(|(_, entry)| {
+        let (entry_type, digest): (EntryType, Result<Digest, String>) = match entry {
+          WalkEntry::File(file_node) => (EntryType::File, file_node.get_digest().into()),
+          WalkEntry::Directory(dir_node) => (EntryType::Directory, dir_node.get_digest().into()),
+        };
+        digest.map(|digest| (entry_type, digest))
+      }))
+      .filter(move |&(_, digest)| exported.insert(digest));
+    archive::Writer::create(archive_path, digest)
+      .and_then(move |writer| {
+        blobs.fold(writer, move |writer, (entry_type, digest)| {
+          store
+            .load_bytes_with(entry_type, digest, Ok, Ok)
+            .and_then(move |maybe_bytes| {
+              maybe_bytes
+                .ok_or_else(|| format!("{:?} with digest {:?} not found", entry_type, digest))
+            })
+            .and_then(move |bytes| writer.append_blob(entry_type, digest, bytes))
+        })
+      })
+      .and_then(archive::Writer::finish)
+      .to_boxed()
+  }
+
+  ///
+  /// Stores every blob of an archive which `export_archive` wrote, checking that each matches its
+  /// digest, and returns the digest of the root Directory of the archive.
+  ///
+  pub fn import_archive(&self, archive_path: &Path) -> BoxFuture<Digest, String> {
+    let store = self.clone();
+    let archive_path = archive_path.to_owned();
+    archive::read(&archive_path)
+      .fold(None, move |root, entry| match entry {
+        archive::Entry::Root(digest) => future::ok(Some(digest)).to_boxed(),
+        archive::Entry::Blob(entry_type, digest, bytes) => store
+          .import_blob(entry_type, digest, bytes)
+          .map(move |()| root)
+          .to_boxed(),
+      })
+      .and_then(move |root| root.ok_or_else(|| format!("Archive {:?} has no root", archive_path)))
+      .to_boxed()
+  }
+
+  fn import_blob(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+    bytes: Bytes,
+  ) -> BoxFuture<(), String> {
+    let stored = match entry_type {
+      EntryType::File => self.store_file_bytes(bytes, false),
+      EntryType::Directory => {
+        let mut directory = bazel_protos::remote_execution::Directory::new();
+        try_future!(directory.merge_from_bytes(&bytes).map_err(|e| format!(
+          "Archive had Directory proto for {:?} which was not valid: {:?}",
+          digest, e
+        )));
+        try_future!(bazel_protos::verify_directory_canonical(&directory));
+        self.record_directory(&directory, false)
+      }
+    };
+    stored
+      .and_then(move |stored_digest| {
+        if stored_digest == digest {
+          Ok(())
+        } else {
+          Err(format!(
+            "Archive had {:?} with digest {:?} whose digest was actually {:?}",
+            entry_type, digest, stored_digest
+          ))
+        }
+      })
+      .to_boxed()
+  }
+
   fn walk_helper<
     T: Send + 'static,
     F: Fn(
@@ -669,6 +2736,160 @@ impl Store {
   }
 }
 
+///
+/// The remote of a Store, which speaks either the gRPC remote execution API, or the simpler HTTP
+/// caching protocol, or is a bucket of S3 or GCS.
+///
+#[derive(Clone)]
+enum RemoteStore {
+  Grpc(remote::ByteStore),
+  Http(http_cache::ByteStore),
+  S3(s3::ByteStore),
+  Gcs(gcs::ByteStore),
+}
+
+impl RemoteStore {
+  fn with_instance_name(&self, instance_name: Option<String>) -> RemoteStore {
+    match self {
+      RemoteStore::Grpc(remote) => RemoteStore::Grpc(remote.with_instance_name(instance_name)),
+      RemoteStore::Http(remote) => RemoteStore::Http(remote.with_instance_name(instance_name)),
+      RemoteStore::S3(remote) => RemoteStore::S3(remote.with_instance_name(instance_name)),
+      RemoteStore::Gcs(remote) => RemoteStore::Gcs(remote.with_instance_name(instance_name)),
+    }
+  }
+
+  fn instance_name(&self) -> Option<String> {
+    match self {
+      RemoteStore::Grpc(remote) => remote.instance_name(),
+      RemoteStore::Http(remote) => remote.instance_name(),
+      RemoteStore::S3(remote) => remote.instance_name(),
+      RemoteStore::Gcs(remote) => remote.instance_name(),
+    }
+  }
+
+  fn capabilities(&self) -> BoxFuture<remote::CacheCapabilities, String> {
+    match self {
+      RemoteStore::Grpc(remote) => remote.capabilities(),
+      // HTTP caches and buckets have no particular limits, but don't support batching anyway.
+      RemoteStore::Http(_) | RemoteStore::S3(_) | RemoteStore::Gcs(_) => {
+        future::ok(remote::CacheCapabilities::default()).to_boxed()
+      }
+    }
+  }
+
+  fn load_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + Clone + 'static>(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+    f: F,
+  ) -> BoxFuture<Option<T>, String> {
+    match self {
+      RemoteStore::Grpc(remote) => remote.load_bytes_with(entry_type, digest, f),
+      RemoteStore::Http(remote) => remote.load_bytes_with(digest, f),
+      RemoteStore::S3(remote) => remote.load_bytes_with(digest, f),
+      RemoteStore::Gcs(remote) => remote.load_bytes_with(digest, f),
+    }
+  }
+
+  fn store_bytes(&self, bytes: Bytes) -> BoxFuture<Digest, String> {
+    match self {
+      RemoteStore::Grpc(remote) => remote.store_bytes(bytes),
+      RemoteStore::Http(remote) => remote.store_bytes(bytes),
+      RemoteStore::S3(remote) => remote.store_bytes(bytes),
+      RemoteStore::Gcs(remote) => remote.store_bytes(bytes),
+    }
+  }
+
+  fn store_bytes_batch(&self, blobs: Vec<Bytes>) -> BoxFuture<Vec<Digest>, String> {
+    match self {
+      RemoteStore::Grpc(remote) => remote.store_bytes_batch(blobs),
+      RemoteStore::Http(remote) => remote.store_bytes_batch(blobs),
+      RemoteStore::S3(remote) => remote.store_bytes_batch(blobs),
+      RemoteStore::Gcs(remote) => remote.store_bytes_batch(blobs),
+    }
+  }
+
+  fn load_bytes_batch(&self, digests: Vec<Digest>) -> BoxFuture<HashMap<Digest, Bytes>, String> {
+    match self {
+      RemoteStore::Grpc(remote) => remote.load_bytes_batch(digests),
+      RemoteStore::Http(remote) => remote.load_bytes_batch(digests),
+      RemoteStore::S3(remote) => remote.load_bytes_batch(digests),
+      RemoteStore::Gcs(remote) => remote.load_bytes_batch(digests),
+    }
+  }
+
+  ///
+  /// Returns which of the given digests the remote is missing.
+  ///
+  fn list_missing_digests(
+    &self,
+    digests: Vec<Digest>,
+    capabilities: remote::CacheCapabilities,
+  ) -> BoxFuture<HashSet<Digest>, String> {
+    match self {
+      RemoteStore::Grpc(remote) => {
+        // Servers which limit the size of batches most likely limit the size of the
+        // FindMissingBlobs batch requests similarly.
+        let max_request_bytes = capabilities
+          .max_batch_total_size_bytes
+          .map(|max_batch_bytes| min(max_batch_bytes, MAX_FIND_MISSING_BLOBS_REQUEST_BYTES))
+          .unwrap_or(MAX_FIND_MISSING_BLOBS_REQUEST_BYTES);
+        let requests = remote.find_missing_blobs_requests(digests.iter(), max_request_bytes);
+        future::join_all(
+          requests
+            .into_iter()
+            .map(|request| remote.list_missing_digests(request))
+            .collect::<Vec<_>>(),
+        )
+        .map(|missing_digests| missing_digests.into_iter().flatten().collect())
+        .to_boxed()
+      }
+      RemoteStore::Http(remote) => remote.list_missing_digests(digests),
+      RemoteStore::S3(remote) => remote.list_missing_digests(digests),
+      RemoteStore::Gcs(remote) => remote.list_missing_digests(digests),
+    }
+  }
+
+  ///
+  /// HTTP caches and buckets can't fetch files themselves, so leave it to us.
+  ///
+  fn fetch_blob(&self, uris: Vec<String>, digest: Digest) -> BoxFuture<bool, String> {
+    match self {
+      RemoteStore::Grpc(remote) => remote.fetch_blob(uris, digest),
+      RemoteStore::Http(_) | RemoteStore::S3(_) | RemoteStore::Gcs(_) => {
+        future::ok(false).to_boxed()
+      }
+    }
+  }
+
+  fn get_action_result(
+    &self,
+    action_digest: Digest,
+  ) -> BoxFuture<Option<bazel_protos::remote_execution::ActionResult>, String> {
+    match self {
+      RemoteStore::Grpc(remote) => remote.get_action_result(action_digest).to_boxed(),
+      RemoteStore::Http(remote) => remote.get_action_result(action_digest),
+      RemoteStore::S3(remote) => remote.get_action_result(action_digest),
+      RemoteStore::Gcs(remote) => remote.get_action_result(action_digest),
+    }
+  }
+
+  fn update_action_result(
+    &self,
+    action_digest: Digest,
+    action_result: bazel_protos::remote_execution::ActionResult,
+  ) -> BoxFuture<(), String> {
+    match self {
+      RemoteStore::Grpc(remote) => remote
+        .update_action_result(action_digest, action_result)
+        .to_boxed(),
+      RemoteStore::Http(remote) => remote.update_action_result(action_digest, action_result),
+      RemoteStore::S3(remote) => remote.update_action_result(action_digest, action_result),
+      RemoteStore::Gcs(remote) => remote.update_action_result(action_digest, action_result),
+    }
+  }
+}
+
 // Only public for testing.
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, Ord, PartialOrd)]
 pub enum EntryType {
@@ -677,34 +2898,69 @@ pub enum EntryType {
 }
 
 mod local {
-  use super::{EntryType, ShrinkBehavior};
+  use super::{
+    BrokenEntry, EntryProblem, EntryType, Eviction, EvictionPolicy, EvictionReason,
+    LocalStoreOptions, ShardStats, ShrinkBehavior,
+  };
 
   use boxfuture::{BoxFuture, Boxable};
   use bytes::Bytes;
   use digest::{Digest as DigestTrait, FixedOutput};
   use futures::future::{self, Future};
+  use futures_cpupool::{self, CpuPool};
   use hashing::{Digest, Fingerprint};
-  use lmdb::Error::{KeyExist, NotFound};
+  use lmdb::Error::{KeyExist, MapFull, MapResized, NotFound};
   use lmdb::{
     self, Cursor, Database, DatabaseFlags, Environment, EnvironmentCopyFlags, EnvironmentFlags,
-    RwTransaction, Transaction, WriteFlags,
+    RoTransaction, RwTransaction, Transaction, WriteFlags,
   };
-  use log::{error, trace};
+  use log::{debug, error, trace, warn};
+  use parking_lot::{Mutex, RwLock, RwLockReadGuard};
+  use protobuf::Message;
   use sha2::Sha256;
   use std;
   use std::collections::{BinaryHeap, HashMap};
-  use std::fmt;
+  use std::io::Read;
+  use std::ops::Deref;
   use std::path::{Path, PathBuf};
   use std::sync::Arc;
   use std::time;
   use tempfile::TempDir;
 
   use super::super::EMPTY_DIGEST;
-  use super::MAX_LOCAL_STORE_SIZE_BYTES;
+  use crate::file_lock::FileLock;
+  use crate::mmap::MappedFile;
+
+  // Every store holds a shared lock of this file in its directory while it is open.
+  const IN_USE_LOCK_FILE: &str = "in_use.lock";
+
+  // Garbage collection and eviction hold an exclusive lock of this file in the directory of the
+  // store, so that only one of the processes which share the store collects it at a time.
+  const COLLECTION_LOCK_FILE: &str = "collection.lock";
+
+  // Loads only record a use of what they load if its last recorded use was at least this long ago,
+  // so that most loads don't need a write transaction.
+  const LAST_USED_RESOLUTION_SECS: u64 = 10 * 60;
+
+  // Blobs of at least this size are hashed on the hashing pool (if there is one), because hashing
+  // them takes long enough to be worth handing them to another thread.
+  const POOLED_HASHING_THRESHOLD_BYTES: usize = 1024 * 1024;
 
   #[derive(Clone)]
   pub struct ByteStore {
     inner: Arc<InnerStore>,
+    // Whether loaded bytes are re-hashed to check that they weren't corrupted (e.g. by a power
+    // loss) since they were stored.
+    verify_digests: bool,
+    // Blobs of at least this many bytes are compressed when they are stored, if that makes them
+    // smaller.
+    compression_threshold_bytes: Option<usize>,
+    // The number of threads of the pool which large blobs are hashed on, which is created when it
+    // is first needed. 0 if large blobs are hashed alongside the rest of storing them.
+    hashing_parallelism: usize,
+    hashing_pool: Arc<Mutex<Option<CpuPool>>>,
+    // Files of at least this many bytes are memory mapped to store them, rather than read.
+    mmap_threshold_bytes: Option<usize>,
   }
 
   struct InnerStore {
@@ -713,21 +2969,117 @@ mod local {
     //  2. It's nice to know whether we should be able to parse something as a proto.
     file_dbs: Result<Arc<ShardedLmdb>, String>,
     directory_dbs: Result<Arc<ShardedLmdb>, String>,
+    // Unlike files and directories, action results are keyed by the digest of the Action which
+    // produced them, rather than by their content.
+    action_result_dbs: Result<Arc<ShardedLmdb>, String>,
+    root: PathBuf,
+    // A shared lock, which each store holds while it uses the directory (which other checkouts
+    // and pantsd instances may be using too), so that compaction (which replaces the directories
+    // of the shards) can exclude them.
+    in_use_lock: Result<FileLock, String>,
   }
 
   impl ByteStore {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<ByteStore, String> {
+      ByteStore::new_with_options(path, LocalStoreOptions::default())
+    }
+
+    pub fn new_with_options<P: AsRef<Path>>(
+      path: P,
+      options: LocalStoreOptions,
+    ) -> Result<ByteStore, String> {
       let root = path.as_ref();
       let files_root = root.join("files");
       let directories_root = root.join("directories");
+      let action_results_root = root.join("action_results");
+      // Taken before the shards are opened, so that any compaction in progress finishes first.
+      let in_use_lock = super::super::safe_create_dir_all(root)
+        .map_err(|err| format!("Error making directory for store at {:?}: {:?}", root, err))
+        .and_then(|()| FileLock::shared(&root.join(IN_USE_LOCK_FILE)));
       Ok(ByteStore {
         inner: Arc::new(InnerStore {
-          file_dbs: ShardedLmdb::new(files_root.clone()).map(Arc::new),
-          directory_dbs: ShardedLmdb::new(directories_root.clone()).map(Arc::new),
+          file_dbs: ShardedLmdb::new(files_root.clone(), options).map(Arc::new),
+          directory_dbs: ShardedLmdb::new(directories_root.clone(), options).map(Arc::new),
+          action_result_dbs: ShardedLmdb::new(action_results_root, options).map(Arc::new),
+          root: root.to_owned(),
+          in_use_lock,
         }),
+        verify_digests: false,
+        compression_threshold_bytes: None,
+        hashing_parallelism: 0,
+        hashing_pool: Arc::new(Mutex::new(None)),
+        mmap_threshold_bytes: None,
       })
     }
 
+    ///
+    /// This store, but re-hashing all bytes that it loads, and deleting (and treating as missing)
+    /// any whose digest doesn't match what they were stored as.
+    ///
+    pub fn with_digest_verification(&self, verify_digests: bool) -> ByteStore {
+      ByteStore {
+        verify_digests,
+        ..self.clone()
+      }
+    }
+
+    ///
+    /// This store, but compressing (with zstd) the blobs of at least the given size which it
+    /// stores, if that makes them smaller. Which blobs are compressed is recorded per blob, so
+    /// blobs which were stored with any other threshold (or none) can still be loaded.
+    ///
+    pub fn with_compression_threshold(&self, threshold_bytes: Option<usize>) -> ByteStore {
+      ByteStore {
+        compression_threshold_bytes: threshold_bytes,
+        ..self.clone()
+      }
+    }
+
+    ///
+    /// This store, but hashing (and compressing) the large blobs which it stores on a pool of the
+    /// given number of threads, so that many large blobs (e.g. the files of a snapshot of large
+    /// artifacts) are hashed on as many cores at once, without holding up the blocking IO of the
+    /// tokio threadpool. 0 hashes each blob on the blocking thread which stores it.
+    ///
+    /// Each blob is still hashed on one thread, because SHA-256 can't be split into chunks which
+    /// are hashed separately without changing the digests.
+    ///
+    pub fn with_hashing_parallelism(&self, parallelism: usize) -> ByteStore {
+      ByteStore {
+        hashing_parallelism: parallelism,
+        hashing_pool: Arc::new(Mutex::new(None)),
+        ..self.clone()
+      }
+    }
+
+    ///
+    /// This store, but memory mapping the files of at least the given size which it stores (see
+    /// `store_file`), or reading all files if there is no threshold.
+    ///
+    pub fn with_mmap_threshold(&self, threshold_bytes: Option<usize>) -> ByteStore {
+      ByteStore {
+        mmap_threshold_bytes: threshold_bytes,
+        ..self.clone()
+      }
+    }
+
+    ///
+    /// The pool to hash a blob of the given size on, if it should be hashed on one.
+    ///
+    fn hashing_pool(&self, size_bytes: usize) -> Option<CpuPool> {
+      if self.hashing_parallelism == 0 || size_bytes < POOLED_HASHING_THRESHOLD_BYTES {
+        return None;
+      }
+      let mut hashing_pool = self.hashing_pool.lock();
+      let pool = hashing_pool.get_or_insert_with(|| {
+        futures_cpupool::Builder::new()
+          .pool_size(self.hashing_parallelism)
+          .name_prefix("store-hashing-")
+          .create()
+      });
+      Some(pool.clone())
+    }
+
     // Note: This performs IO on the calling thread. Hopefully the IO is small enough not to matter.
     pub fn entry_type(&self, fingerprint: &Fingerprint) -> Result<Option<EntryType>, String> {
       if *fingerprint == EMPTY_DIGEST.0 {
@@ -736,11 +3088,11 @@ mod local {
         return Ok(Some(EntryType::Directory));
       }
       {
-        let (env, directory_database, _) = self.inner.directory_dbs.clone()?.get(fingerprint);
-        let txn = env
+        let shard = self.inner.directory_dbs.clone()?.get(fingerprint);
+        let txn = shard
           .begin_ro_txn()
           .map_err(|err| format!("Failed to begin read transaction: {:?}", err))?;
-        match txn.get(directory_database, &fingerprint.as_ref()) {
+        match txn.get(shard.content, &fingerprint.as_ref()) {
           Ok(_) => return Ok(Some(EntryType::Directory)),
           Err(NotFound) => {}
           Err(err) => {
@@ -751,11 +3103,11 @@ mod local {
           }
         };
       }
-      let (env, file_database, _) = self.inner.file_dbs.clone()?.get(fingerprint);
-      let txn = env
+      let shard = self.inner.file_dbs.clone()?.get(fingerprint);
+      let txn = shard
         .begin_ro_txn()
         .map_err(|err| format!("Failed to begin read transaction: {}", err))?;
-      match txn.get(file_database, &fingerprint.as_ref()) {
+      match txn.get(shard.content, &fingerprint.as_ref()) {
         Ok(_) => return Ok(Some(EntryType::File)),
         Err(NotFound) => {}
         Err(err) => {
@@ -768,16 +3120,50 @@ mod local {
       Ok(None)
     }
 
+    ///
+    /// The number and stored size of the entries of the given kind in each shard, in order of
+    /// their fingerprint prefixes.
+    ///
+    pub fn shard_stats(&self, entry_type: EntryType) -> Result<Vec<ShardStats>, String> {
+      let dbs = match entry_type {
+        EntryType::File => self.inner.file_dbs.clone(),
+        EntryType::Directory => self.inner.directory_dbs.clone(),
+      }?;
+      let mut stats = dbs
+        .lmdbs
+        .iter()
+        .map(|(fingerprint_prefix, shard)| {
+          let txn = shard
+            .begin_ro_txn()
+            .map_err(|err| format!("Failed to begin read transaction: {}", err))?;
+          let mut cursor = txn
+            .open_ro_cursor(shard.content)
+            .map_err(|err| format!("Failed to open lmdb read cursor: {}", err))?;
+          let mut shard_stats = ShardStats {
+            fingerprint_prefix: *fingerprint_prefix,
+            entry_count: 0,
+            stored_bytes: 0,
+          };
+          for (_, bytes) in cursor.iter() {
+            shard_stats.entry_count += 1;
+            shard_stats.stored_bytes += bytes.len();
+          }
+          Ok(shard_stats)
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+      stats.sort_by_key(|shard_stats| shard_stats.fingerprint_prefix);
+      Ok(stats)
+    }
+
     pub fn lease_all<'a, Ds: Iterator<Item = &'a Digest>>(
       &self,
       digests: Ds,
     ) -> Result<(), String> {
       let until = Self::default_lease_until_secs_since_epoch();
       for digest in digests {
-        let (env, _, lease_database) = self.inner.file_dbs.clone()?.get(&digest.0);
-        env
-          .begin_rw_txn()
-          .and_then(|mut txn| self.lease(lease_database, &digest.0, until, &mut txn))
+        let shard = self.inner.file_dbs.clone()?.get(&digest.0);
+        shard
+          .write(|txn| self.lease(shard.leases, &digest.0, until, txn))
           .map_err(|err| format!("Error leasing digest {:?}: {}", digest, err))?;
       }
       Ok(())
@@ -805,13 +3191,119 @@ mod local {
       )
     }
 
+    ///
+    /// The compressed form of the given bytes, if they should be stored compressed.
+    ///
+    fn compress(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+      match self.compression_threshold_bytes {
+        Some(threshold_bytes) if bytes.len() >= threshold_bytes => {
+          // A level of 0 means zstd's default level.
+          zstd::block::compress(bytes, 0)
+            .ok()
+            .filter(|compressed| compressed.len() < bytes.len())
+        }
+        _ => None,
+      }
+    }
+
+    ///
+    /// The bytes of the given digest, given what is stored for it: decompressed if they were stored
+    /// compressed. None if they were stored compressed, but can't be decompressed (i.e. they have
+    /// been corrupted).
+    ///
+    fn decompress<T: Transaction>(
+      txn: &T,
+      shard: &Shard,
+      digest: Digest,
+      stored: &[u8],
+    ) -> Result<Option<Bytes>, String> {
+      match txn.get(shard.compressed, &digest.0) {
+        // Compressed bytes can't decompress to more than the size of the digest, unless they have
+        // been corrupted.
+        Ok(_) => Ok(
+          zstd::block::decompress(stored, digest.1)
+            .ok()
+            .map(Bytes::from),
+        ),
+        Err(NotFound) => Ok(Some(Bytes::from(stored))),
+        Err(err) => Err(format!(
+          "Error checking whether digest {:?} is compressed: {}",
+          digest, err
+        )),
+      }
+    }
+
+    fn fingerprint_of(bytes: &[u8]) -> Fingerprint {
+      let mut hasher = Sha256::default();
+      hasher.input(bytes);
+      Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
+    }
+
+    fn now_secs_since_epoch() -> u64 {
+      time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("Surely you're not before the unix epoch?")
+        .as_secs()
+    }
+
+    ///
+    /// Records that the given fingerprint was used (i.e. stored or loaded) at the given time.
+    ///
+    fn record_use(
+      database: Database,
+      fingerprint: &Fingerprint,
+      secs_since_epoch: u64,
+      txn: &mut RwTransaction<'_>,
+    ) -> Result<(), lmdb::Error> {
+      txn.put(
+        database,
+        &fingerprint.as_ref(),
+        &secs_since_epoch.to_le_bytes(),
+        WriteFlags::empty(),
+      )
+    }
+
+    ///
+    /// Reads a timestamp (as seconds since the epoch) from a lease or last-used database.
+    ///
+    fn read_timestamp<T: Transaction>(
+      txn: &T,
+      database: Database,
+      key: &[u8],
+    ) -> Result<Option<u64>, lmdb::Error> {
+      match txn.get(database, &key) {
+        Ok(bytes) => {
+          let mut array = [0_u8; 8];
+          array.copy_from_slice(bytes);
+          Ok(Some(u64::from_le_bytes(array)))
+        }
+        Err(NotFound) => Ok(None),
+        Err(err) => Err(err),
+      }
+    }
+
+    ///
+    /// Takes the lock which garbage collection and eviction hold while they run, or returns None if
+    /// another process (or store) which shares this directory holds it.
+    ///
+    pub fn try_lock_for_collection(&self) -> Result<Option<FileLock>, String> {
+      FileLock::try_exclusive(&self.inner.root.join(COLLECTION_LOCK_FILE))
+    }
+
     ///
     /// Attempts to shrink the stored files to be no bigger than target_bytes
     /// (excluding lmdb overhead).
     ///
     /// Returns the size it was shrunk to, which may be larger than target_bytes.
     ///
-    /// Ignores directories. TODO: Shrink directories.
+    /// Compaction is skipped (with a warning) while any other store, in this or another process,
+    /// has the directory open.
+    ///
+    /// Entries which are leased are never removed. Of the others, the least recently used (stored
+    /// or loaded) entries are removed first. Entries which were stored before uses were recorded
+    /// are treated as last used when their lease (if any) expired.
+    ///
+    /// Sizes are of what is stored, so of compressed entries after compression.
     ///
     /// TODO: Use LMDB database statistics when lmdb-rs exposes them.
     ///
@@ -821,23 +3313,19 @@ mod local {
       shrink_behavior: ShrinkBehavior,
     ) -> Result<usize, String> {
       let mut used_bytes: usize = 0;
-      let mut fingerprints_by_expired_ago = BinaryHeap::new();
+      let mut fingerprints_by_age = BinaryHeap::new();
 
-      self.aged_fingerprints(
-        EntryType::File,
-        &mut used_bytes,
-        &mut fingerprints_by_expired_ago,
-      )?;
+      self.aged_fingerprints(EntryType::File, &mut used_bytes, &mut fingerprints_by_age)?;
       self.aged_fingerprints(
         EntryType::Directory,
         &mut used_bytes,
-        &mut fingerprints_by_expired_ago,
+        &mut fingerprints_by_age,
       )?;
       while used_bytes > target_bytes {
-        let aged_fingerprint = fingerprints_by_expired_ago
+        let aged_fingerprint = fingerprints_by_age
           .pop()
           .expect("lmdb corruption detected, sum of size of blobs exceeded stored blobs");
-        if aged_fingerprint.expired_seconds_ago == 0 {
+        if !aged_fingerprint.collectable {
           // Ran out of expired blobs - everything remaining is leased and cannot be collected.
           return Ok(used_bytes);
         }
@@ -845,28 +3333,25 @@ mod local {
           EntryType::File => self.inner.file_dbs.clone(),
           EntryType::Directory => self.inner.directory_dbs.clone(),
         };
-        let (env, database, lease_database) = lmdbs.clone()?.get(&aged_fingerprint.fingerprint);
-        {
-          env
-            .begin_rw_txn()
-            .and_then(|mut txn| {
-              txn.del(database, &aged_fingerprint.fingerprint.as_ref(), None)?;
-
-              txn
-                .del(lease_database, &aged_fingerprint.fingerprint.as_ref(), None)
-                .or_else(|err| match err {
-                  NotFound => Ok(()),
-                  err => Err(err),
-                })?;
-              used_bytes -= aged_fingerprint.size_bytes;
-              txn.commit()
-            })
-            .map_err(|err| format!("Error garbage collecting: {}", err))?;
-        }
+        let shard = lmdbs.clone()?.get(&aged_fingerprint.fingerprint);
+        shard
+          .write(|txn| shard.delete(txn, &aged_fingerprint.fingerprint))
+          .map_err(|err| format!("Error garbage collecting: {}", err))?;
+        used_bytes -= aged_fingerprint.size_bytes;
       }
 
       if shrink_behavior == ShrinkBehavior::Compact {
-        self.inner.file_dbs.clone()?.compact()?;
+        let in_use_lock = self.inner.in_use_lock.as_ref().map_err(String::clone)?;
+        if in_use_lock.try_upgrade()? {
+          let compacted = self.inner.file_dbs.clone().and_then(|dbs| dbs.compact());
+          in_use_lock.downgrade()?;
+          compacted?;
+        } else {
+          warn!(
+            "Not compacting the local store at {:?}, because other processes are using it",
+            self.inner.root
+          );
+        }
       }
 
       Ok(used_bytes)
@@ -876,57 +3361,231 @@ mod local {
       &self,
       entry_type: EntryType,
       used_bytes: &mut usize,
-      fingerprints_by_expired_ago: &mut BinaryHeap<AgedFingerprint>,
+      fingerprints_by_age: &mut BinaryHeap<AgedFingerprint>,
     ) -> Result<(), String> {
       let database = match entry_type {
         EntryType::File => self.inner.file_dbs.clone(),
         EntryType::Directory => self.inner.directory_dbs.clone(),
       };
+      let now = time::SystemTime::now();
 
-      for &(ref env, ref database, ref lease_database) in &database?.all_lmdbs() {
-        let txn = env
-          .begin_ro_txn()
-          .map_err(|err| format!("Error beginning transaction to garbage collect: {}", err))?;
-        let mut cursor = txn
-          .open_ro_cursor(*database)
-          .map_err(|err| format!("Failed to open lmdb read cursor: {}", err))?;
-        for (key, bytes) in cursor.iter() {
-          *used_bytes += bytes.len();
-
-          // Random access into the lease_database is slower than iterating, but hopefully garbage
-          // collection is rare enough that we can get away with this, rather than do two passes
-          // here (either to populate leases into pre-populated AgedFingerprints, or to read sizes
-          // when we delete from lmdb to track how much we've freed).
-          let lease_until_unix_timestamp = txn
-            .get(*lease_database, &key)
-            .map(|b| {
-              let mut array = [0_u8; 8];
-              array.copy_from_slice(b);
-              u64::from_le_bytes(array)
-            })
-            .unwrap_or_else(|e| match e {
-              NotFound => 0,
-              e => panic!("Error reading lease, probable lmdb corruption: {:?}", e),
+      for shard in &database?.all_lmdbs() {
+        Self::shard_aged_fingerprints(shard, entry_type, now, used_bytes, fingerprints_by_age)?;
+      }
+      Ok(())
+    }
+
+    fn shard_aged_fingerprints(
+      shard: &Shard,
+      entry_type: EntryType,
+      now: time::SystemTime,
+      used_bytes: &mut usize,
+      fingerprints_by_age: &mut BinaryHeap<AgedFingerprint>,
+    ) -> Result<(), String> {
+      let txn = shard
+        .begin_ro_txn()
+        .map_err(|err| format!("Error beginning transaction to garbage collect: {}", err))?;
+      let mut cursor = txn
+        .open_ro_cursor(shard.content)
+        .map_err(|err| format!("Failed to open lmdb read cursor: {}", err))?;
+      for (key, bytes) in cursor.iter() {
+        *used_bytes += bytes.len();
+
+        // Random access into the lease_database is slower than iterating, but hopefully garbage
+        // collection is rare enough that we can get away with this, rather than do two passes
+        // here (either to populate leases into pre-populated AgedFingerprints, or to read sizes
+        // when we delete from lmdb to track how much we've freed).
+        let lease_until_unix_timestamp = Self::read_timestamp(&*txn, shard.leases, key)
+          .unwrap_or_else(|e| panic!("Error reading lease, probable lmdb corruption: {:?}", e))
+          .unwrap_or(0);
+        let last_used_unix_timestamp = Self::read_timestamp(&*txn, shard.last_used, key)
+          .unwrap_or_else(|e| panic!("Error reading last use, probable lmdb corruption: {:?}", e))
+          .unwrap_or(0);
+
+        let leased_until = time::UNIX_EPOCH + time::Duration::from_secs(lease_until_unix_timestamp);
+        let last_used = time::UNIX_EPOCH + time::Duration::from_secs(last_used_unix_timestamp);
+
+        let unused_seconds = now
+          .duration_since(std::cmp::max(leased_until, last_used))
+          .map(|t| t.as_secs())
+          .unwrap_or(0);
+
+        fingerprints_by_age.push(AgedFingerprint {
+          collectable: leased_until <= now,
+          unused_seconds,
+          fingerprint: Fingerprint::from_bytes_unsafe(key),
+          size_bytes: bytes.len(),
+          entry_type: entry_type,
+        });
+      }
+      Ok(())
+    }
+
+    ///
+    /// Evicts (unless this is a dry run) and returns the entries which the given policy evicts,
+    /// shard by shard: first those which are too old, and then the least recently used of the
+    /// rest until the shard is within its quota.
+    ///
+    pub fn evict(&self, policy: &EvictionPolicy, dry_run: bool) -> Result<Vec<Eviction>, String> {
+      let now = time::SystemTime::now();
+      let mut evictions = vec![];
+      for entry_type in &[EntryType::File, EntryType::Directory] {
+        let entry_type = *entry_type;
+        let dbs = match entry_type {
+          EntryType::File => self.inner.file_dbs.clone(),
+          EntryType::Directory => self.inner.directory_dbs.clone(),
+        }?;
+        let quota_bytes = policy.shard_quota_bytes(entry_type);
+        let mut shards = dbs.lmdbs.iter().collect::<Vec<_>>();
+        shards.sort_by_key(|(fingerprint_prefix, _)| **fingerprint_prefix);
+        for (fingerprint_prefix, shard) in shards {
+          let mut shard_stored_bytes = 0;
+          let mut fingerprints_by_age = BinaryHeap::new();
+          Self::shard_aged_fingerprints(
+            shard,
+            entry_type,
+            now,
+            &mut shard_stored_bytes,
+            &mut fingerprints_by_age,
+          )?;
+
+          let mut shard_evictions = vec![];
+          while let Some(aged_fingerprint) = fingerprints_by_age.pop() {
+            if !aged_fingerprint.collectable {
+              break;
+            }
+            let reason = match (policy.max_unused_age, quota_bytes) {
+              (Some(max_unused_age), _)
+                if aged_fingerprint.unused_seconds > max_unused_age.as_secs() =>
+              {
+                EvictionReason::Unused {
+                  unused_for: time::Duration::from_secs(aged_fingerprint.unused_seconds),
+                  max_unused_age,
+                }
+              }
+              (_, Some(quota_bytes)) if shard_stored_bytes > quota_bytes => {
+                EvictionReason::OverShardQuota {
+                  fingerprint_prefix: *fingerprint_prefix,
+                  shard_stored_bytes,
+                  quota_bytes,
+                }
+              }
+              _ => break,
+            };
+            shard_stored_bytes -= aged_fingerprint.size_bytes;
+            shard_evictions.push(Eviction {
+              entry_type,
+              fingerprint: aged_fingerprint.fingerprint,
+              stored_bytes: aged_fingerprint.size_bytes,
+              reason,
             });
+          }
 
-          let leased_until =
-            time::UNIX_EPOCH + time::Duration::from_secs(lease_until_unix_timestamp);
+          if !dry_run && !shard_evictions.is_empty() {
+            shard
+              .write(|txn| {
+                for eviction in &shard_evictions {
+                  shard.delete(txn, &eviction.fingerprint)?;
+                }
+                Ok(())
+              })
+              .map_err(|err| format!("Error evicting: {}", err))?;
+          }
+          evictions.extend(shard_evictions);
+        }
+      }
+      Ok(evictions)
+    }
 
-          let expired_seconds_ago = time::SystemTime::now()
-            .duration_since(leased_until)
-            .map(|t| t.as_secs())
-            // 0 indicates unleased.
-            .unwrap_or(0);
+    ///
+    /// Checks every entry of each shard (see `Store::fsck`), deleting the broken entries of each
+    /// shard in one transaction unless this is a dry run.
+    ///
+    pub fn fsck(&self, dry_run: bool) -> Result<Vec<BrokenEntry>, String> {
+      let mut broken_entries = vec![];
+      for entry_type in &[EntryType::File, EntryType::Directory] {
+        let entry_type = *entry_type;
+        let dbs = match entry_type {
+          EntryType::File => self.inner.file_dbs.clone(),
+          EntryType::Directory => self.inner.directory_dbs.clone(),
+        }?;
+        let mut shards = dbs.lmdbs.iter().collect::<Vec<_>>();
+        shards.sort_by_key(|(fingerprint_prefix, _)| **fingerprint_prefix);
+        for (_, shard) in shards {
+          let shard_broken_entries = Self::fsck_shard(shard, entry_type)?;
+          if !dry_run && !shard_broken_entries.is_empty() {
+            shard
+              .write(|txn| {
+                for broken_entry in &shard_broken_entries {
+                  shard.delete(txn, &broken_entry.fingerprint)?;
+                }
+                Ok(())
+              })
+              .map_err(|err| format!("Error deleting broken entries: {}", err))?;
+          }
+          broken_entries.extend(shard_broken_entries);
+        }
+      }
+      Ok(broken_entries)
+    }
 
-          fingerprints_by_expired_ago.push(AgedFingerprint {
-            expired_seconds_ago: expired_seconds_ago,
-            fingerprint: Fingerprint::from_bytes_unsafe(key),
-            size_bytes: bytes.len(),
-            entry_type: entry_type,
+    fn fsck_shard(shard: &Shard, entry_type: EntryType) -> Result<Vec<BrokenEntry>, String> {
+      let txn = shard
+        .begin_ro_txn()
+        .map_err(|err| format!("Error beginning transaction to check entries: {}", err))?;
+      let mut cursor = txn
+        .open_ro_cursor(shard.content)
+        .map_err(|err| format!("Failed to open lmdb read cursor: {}", err))?;
+      let mut broken_entries = vec![];
+      for (key, stored) in cursor.iter() {
+        let fingerprint = Fingerprint::from_bytes_unsafe(key);
+        if let Some(problem) = Self::entry_problem(&*txn, shard, entry_type, fingerprint, stored)? {
+          broken_entries.push(BrokenEntry {
+            entry_type,
+            fingerprint,
+            problem,
           });
         }
       }
-      Ok(())
+      Ok(broken_entries)
+    }
+
+    fn entry_problem<T: Transaction>(
+      txn: &T,
+      shard: &Shard,
+      entry_type: EntryType,
+      fingerprint: Fingerprint,
+      stored: &[u8],
+    ) -> Result<Option<EntryProblem>, String> {
+      // Unlike loads, which decompress into the size of the digest which they load, the size of
+      // the content isn't known here, so it is decompressed as a stream.
+      let bytes = match txn.get(shard.compressed, &fingerprint) {
+        Ok(_) => match zstd::stream::decode_all(stored) {
+          Ok(bytes) => Bytes::from(bytes),
+          Err(_) => return Ok(Some(EntryProblem::Undecompressable)),
+        },
+        Err(NotFound) => Bytes::from(stored),
+        Err(err) => {
+          return Err(format!(
+            "Error checking whether {} is compressed: {}",
+            fingerprint, err
+          ));
+        }
+      };
+      let actual_fingerprint = Self::fingerprint_of(&bytes);
+      if actual_fingerprint != fingerprint {
+        return Ok(Some(EntryProblem::WrongFingerprint(actual_fingerprint)));
+      }
+      if entry_type == EntryType::Directory {
+        let mut directory = bazel_protos::remote_execution::Directory::new();
+        if let Err(err) = directory.merge_from_bytes(&bytes) {
+          return Ok(Some(EntryProblem::InvalidDirectory(format!("{:?}", err))));
+        }
+        if let Err(err) = bazel_protos::verify_directory_canonical(&directory) {
+          return Ok(Some(EntryProblem::NonCanonicalDirectory(err)));
+        }
+      }
+      Ok(None)
     }
 
     pub fn store_bytes(
@@ -935,57 +3594,150 @@ mod local {
       bytes: Bytes,
       initial_lease: bool,
     ) -> BoxFuture<Digest, String> {
-      let dbs = match entry_type {
-        EntryType::Directory => self.inner.directory_dbs.clone(),
-        EntryType::File => self.inner.file_dbs.clone(),
-      };
+      self.store_contents(entry_type, bytes, initial_lease)
+    }
+
+    ///
+    /// Stores the contents of the file at the given path. Files of at least the mmap threshold (if
+    /// there is one) are memory mapped rather than read, so that they are hashed and written into
+    /// the store without being copied into memory first; files which can't be mapped are read.
+    ///
+    /// If storing a mapped file fails (e.g. because it was changed while it was being stored), it
+    /// is read and stored instead.
+    ///
+    pub fn store_file(&self, path: &Path, initial_lease: bool) -> BoxFuture<Digest, String> {
+      self.store_file_with_mmap_threshold(path.to_owned(), self.mmap_threshold_bytes, initial_lease)
+    }
 
+    fn store_file_with_mmap_threshold(
+      &self,
+      path: PathBuf,
+      mmap_threshold_bytes: Option<usize>,
+      initial_lease: bool,
+    ) -> BoxFuture<Digest, String> {
       let bytestore = self.clone();
+      let path2 = path.clone();
       futures::future::poll_fn(move || {
-        tokio_threadpool::blocking(|| {
-          let fingerprint = {
-            let mut hasher = Sha256::default();
-            hasher.input(&bytes);
-            Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
-          };
-          let digest = Digest(fingerprint, bytes.len());
-
-          let (env, content_database, lease_database) = dbs.clone()?.get(&fingerprint);
-          let put_res = env.begin_rw_txn().and_then(|mut txn| {
-            txn.put(
-              content_database,
-              &fingerprint,
-              &bytes,
-              WriteFlags::NO_OVERWRITE,
-            )?;
-            if initial_lease {
-              bytestore.lease(
-                lease_database,
-                &fingerprint,
-                Self::default_lease_until_secs_since_epoch(),
-                &mut txn,
-              )?;
-            }
-            txn.commit()
-          });
-
-          match put_res {
-            Ok(()) => Ok(digest),
-            Err(KeyExist) => Ok(digest),
-            Err(err) => Err(format!("Error storing digest {:?}: {}", digest, err)),
-          }
-        })
+        tokio_threadpool::blocking(|| FileContents::open(&path2, mmap_threshold_bytes))
       })
       .then(|blocking_result| match blocking_result {
         Ok(v) => v,
         Err(blocking_err) => Err(format!(
-          "Unable to run blocking task to store_bytes in local ByteStore on tokio runtime: {}",
+          "Unable to run blocking task to store_file in local ByteStore on tokio runtime: {}",
           blocking_err
         )),
       })
+      .and_then(move |contents| match contents {
+        FileContents::Mapped(mapped) => bytestore
+          .store_contents(EntryType::File, mapped, initial_lease)
+          .or_else(move |err| {
+            debug!(
+              "Reading {:?} to store it, because storing it mapped failed: {}",
+              path, err
+            );
+            bytestore.store_file_with_mmap_threshold(path, None, initial_lease)
+          })
+          .to_boxed(),
+        FileContents::Read(bytes) => {
+          bytestore.store_contents(EntryType::File, bytes, initial_lease)
+        }
+      })
       .to_boxed()
     }
 
+    fn store_contents<C: StorableContents>(
+      &self,
+      entry_type: EntryType,
+      contents: C,
+      initial_lease: bool,
+    ) -> BoxFuture<Digest, String> {
+      let dbs = match entry_type {
+        EntryType::Directory => self.inner.directory_dbs.clone(),
+        EntryType::File => self.inner.file_dbs.clone(),
+      };
+
+      // The fingerprint of the bytes, and their compressed form if they should be stored
+      // compressed, if they were worked out on the hashing pool.
+      let prepared: BoxFuture<Option<(Fingerprint, Option<Vec<u8>>)>, String> =
+        match self.hashing_pool(contents.as_ref().len()) {
+          Some(pool) => {
+            let bytestore = self.clone();
+            let contents = contents.clone();
+            pool
+              .spawn_fn(move || {
+                let bytes = contents.as_ref();
+                Ok(Some((
+                  Self::fingerprint_of(bytes),
+                  bytestore.compress(bytes),
+                )))
+              })
+              .to_boxed()
+          }
+          None => future::ok(None).to_boxed(),
+        };
+
+      let bytestore = self.clone();
+      prepared
+        .and_then(move |mut prepared| {
+          futures::future::poll_fn(move || {
+            tokio_threadpool::blocking(|| {
+              let bytes = contents.as_ref();
+              let (fingerprint, compressed) = prepared
+                .take()
+                .unwrap_or_else(|| (Self::fingerprint_of(bytes), bytestore.compress(bytes)));
+              let digest = Digest(fingerprint, bytes.len());
+              contents.check_unchanged()?;
+
+              let shard = dbs.clone()?.get(&fingerprint);
+              let put_res = shard.write(|txn| {
+                let value: &[u8] = compressed.as_ref().map(Vec::as_slice).unwrap_or(bytes);
+                // Storing bytes which are already stored still counts as a use of them.
+                match txn.put(
+                  shard.content,
+                  &fingerprint,
+                  &value,
+                  WriteFlags::NO_OVERWRITE,
+                ) {
+                  Ok(()) if compressed.is_some() => {
+                    txn.put(shard.compressed, &fingerprint, &b"", WriteFlags::empty())?
+                  }
+                  Ok(()) | Err(KeyExist) => {}
+                  Err(err) => return Err(err),
+                }
+                Self::record_use(
+                  shard.last_used,
+                  &fingerprint,
+                  Self::now_secs_since_epoch(),
+                  txn,
+                )?;
+                if initial_lease {
+                  bytestore.lease(
+                    shard.leases,
+                    &fingerprint,
+                    Self::default_lease_until_secs_since_epoch(),
+                    txn,
+                  )?;
+                }
+                Ok(())
+              });
+
+              match put_res {
+                Ok(()) => Ok(digest),
+                Err(err) => Err(format!("Error storing digest {:?}: {}", digest, err)),
+              }
+            })
+          })
+          .then(|blocking_result| match blocking_result {
+            Ok(v) => v,
+            Err(blocking_err) => Err(format!(
+              "Unable to run blocking task to store_bytes in local ByteStore on tokio runtime: {}",
+              blocking_err
+            )),
+          })
+        })
+        .to_boxed()
+    }
+
     pub fn load_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + 'static>(
       &self,
       entry_type: EntryType,
@@ -1003,24 +3755,53 @@ mod local {
         EntryType::Directory => self.inner.directory_dbs.clone(),
         EntryType::File => self.inner.file_dbs.clone(),
       };
+      let verify_digests = self.verify_digests;
 
       futures::future::poll_fn(move || tokio_threadpool::blocking( || {
-          let (env, db, _) = dbs.clone()?.get(&digest.0);
-          let ro_txn = env
+          let shard = dbs.clone()?.get(&digest.0);
+          let ro_txn = shard
             .begin_ro_txn()
             .map_err(|err| format!("Failed to begin read transaction: {}", err));
-          ro_txn.and_then(|txn| match txn.get(db, &digest.0) {
-            Ok(bytes) => {
-              if bytes.len() == digest.1 {
-                Ok(Some(f(Bytes::from(bytes))))
-              } else {
-                error!("Got hash collision reading from store - digest {:?} was requested, but retrieved bytes with that fingerprint had length {}. Congratulations, you may have broken sha256! Underlying bytes: {:?}", digest, bytes.len(), bytes);
-                Ok(None)
-              }
+          let (result, last_used, corrupt) = ro_txn.and_then(|txn| {
+            let bytes = match txn.get(shard.content, &digest.0) {
+              Ok(stored) => match Self::decompress(&*txn, &shard, digest, stored)? {
+                Some(bytes) => bytes,
+                None => return Ok((None, None, true)),
+              },
+              Err(NotFound) => return Ok((None, None, false)),
+              Err(err) => return Err(format!("Error loading digest {:?}: {}", digest, err,)),
+            };
+            if bytes.len() != digest.1 {
+              error!("Got hash collision reading from store - digest {:?} was requested, but retrieved bytes with that fingerprint had length {}. Congratulations, you may have broken sha256! Underlying bytes: {:?}", digest, bytes.len(), bytes);
+              Ok((None, None, false))
+            } else if verify_digests && Self::fingerprint_of(&bytes) != digest.0 {
+              Ok((None, None, true))
+            } else {
+              let last_used = Self::read_timestamp(&*txn, shard.last_used, digest.0.as_ref())
+                .map_err(|err| format!("Error reading last use of {:?}: {}", digest, err))?;
+              Ok((Some(f(bytes)), last_used, false))
             }
-            Err(NotFound) => Ok(None),
-            Err(err) => Err(format!("Error loading digest {:?}: {}", digest, err,)),
-          })
+          })?;
+          if corrupt {
+            // Corrupt entries are deleted, so that they are treated as missing (and so re-fetched
+            // from the remote, if there is one) from now on.
+            warn!("Deleting corrupted digest {:?} from the local store", digest);
+            shard
+              .write(|txn| shard.delete(txn, &digest.0))
+              .map_err(|err| format!("Error deleting corrupted digest {:?}: {}", digest, err))?;
+            return Ok(None);
+          }
+          // Uses are only recorded at a coarse resolution, so that most loads don't need to write.
+          let now = Self::now_secs_since_epoch();
+          let used_recently = last_used
+            .map(|last_used| last_used + LAST_USED_RESOLUTION_SECS > now)
+            .unwrap_or(false);
+          if result.is_some() && !used_recently {
+            shard
+              .write(|txn| Self::record_use(shard.last_used, &digest.0, now, txn))
+              .map_err(|err| format!("Error recording use of {:?}: {}", digest, err))?;
+          }
+          Ok(result)
         })).then(|blocking_result| {
         match blocking_result {
           Ok(v) => v,
@@ -1028,24 +3809,226 @@ mod local {
         }
       }).to_boxed()
     }
-  }
 
-  // Each LMDB directory can have at most one concurrent writer.
-  // We use this type to shard storage into 16 LMDB directories, based on the first 4 bits of the
-  // fingerprint being stored, so that we can write to them in parallel.
-  #[derive(Clone)]
-  struct ShardedLmdb {
-    // First Database is content, second is leases.
-    lmdbs: HashMap<u8, (Arc<Environment>, Database, Database)>,
-    root_path: PathBuf,
+    ///
+    /// Stores the (serialized) result of the Action with the given digest, replacing any existing
+    /// result for it.
+    ///
+    pub fn store_action_result(
+      &self,
+      action_digest: Digest,
+      bytes: Bytes,
+    ) -> BoxFuture<(), String> {
+      let dbs = self.inner.action_result_dbs.clone();
+      futures::future::poll_fn(move || {
+        tokio_threadpool::blocking(|| {
+          let shard = dbs.clone()?.get(&action_digest.0);
+          shard
+            .write(|txn| txn.put(shard.content, &action_digest.0, &bytes, WriteFlags::empty()))
+            .map_err(|err| {
+              format!(
+                "Error storing action result for {:?}: {}",
+                action_digest, err
+              )
+            })
+        })
+      })
+      .then(|blocking_result| match blocking_result {
+        Ok(v) => v,
+        Err(blocking_err) => Err(format!(
+          "Unable to run blocking task to store_action_result in local ByteStore on tokio runtime: {}",
+          blocking_err
+        )),
+      })
+      .to_boxed()
+    }
+
+    pub fn load_action_result(&self, action_digest: Digest) -> BoxFuture<Option<Bytes>, String> {
+      let dbs = self.inner.action_result_dbs.clone();
+      futures::future::poll_fn(move || {
+        tokio_threadpool::blocking(|| {
+          let shard = dbs.clone()?.get(&action_digest.0);
+          let txn = shard
+            .begin_ro_txn()
+            .map_err(|err| format!("Failed to begin read transaction: {}", err))?;
+          match txn.get(shard.content, &action_digest.0) {
+            Ok(bytes) => Ok(Some(Bytes::from(bytes))),
+            Err(NotFound) => Ok(None),
+            Err(err) => Err(format!(
+              "Error loading action result for {:?}: {}",
+              action_digest, err
+            )),
+          }
+        })
+      })
+      .then(|blocking_result| match blocking_result {
+        Ok(v) => v,
+        Err(blocking_err) => Err(format!(
+          "Unable to run blocking task to load_action_result in local ByteStore on tokio runtime: {}",
+          blocking_err
+        )),
+      })
+      .to_boxed()
+    }
+  }
+
+  // Each LMDB directory can have at most one concurrent writer.
+  // We use this type to shard storage into several (by default 16) LMDB directories, based on the
+  // first bits of the fingerprint being stored, so that we can write to them in parallel.
+  #[derive(Clone)]
+  struct ShardedLmdb {
+    lmdbs: HashMap<u8, Shard>,
+    // The bits of the first byte of fingerprints which select their shard.
+    prefix_mask: u8,
+    root_path: PathBuf,
+    options: LocalStoreOptions,
+  }
+
+  // The databases of one shard of a ShardedLmdb, which are all in the same Environment, and are all
+  // keyed by fingerprint.
+  #[derive(Clone)]
+  struct Shard {
+    env: Arc<Environment>,
+    content: Database,
+    leases: Database,
+    // The times of last use.
+    last_used: Database,
+    // The fingerprints whose content is compressed, with empty values.
+    compressed: Database,
+    // The size of the memory map of the environment, which is read-locked by every transaction,
+    // because LMDB requires that there are none in this process while the map is resized.
+    map_size: Arc<RwLock<usize>>,
+  }
+
+  // A transaction, during which the memory map of its environment can't be resized.
+  struct ShardTransaction<'a, T> {
+    // Declared before the guard, so that it is dropped first.
+    txn: T,
+    _map_size: RwLockReadGuard<'a, usize>,
+  }
+
+  impl<'a, T> Deref for ShardTransaction<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+      &self.txn
+    }
+  }
+
+  impl Shard {
+    fn begin_ro_txn(&self) -> Result<ShardTransaction<'_, RoTransaction<'_>>, lmdb::Error> {
+      loop {
+        let map_size = self.map_size.read();
+        match self.env.begin_ro_txn() {
+          Ok(txn) => {
+            return Ok(ShardTransaction {
+              txn,
+              _map_size: map_size,
+            });
+          }
+          Err(MapResized) => {
+            std::mem::drop(map_size);
+            self.adopt_map_size()?;
+          }
+          Err(err) => return Err(err),
+        }
+      }
+    }
+
+    ///
+    /// Runs the given function in a write transaction, which is committed if the function succeeds.
+    ///
+    /// If the memory map fills up, it is grown, and the function is retried in a new transaction,
+    /// so it must not have side effects outside of the transaction.
+    ///
+    fn write<T, F: FnMut(&mut RwTransaction<'_>) -> Result<T, lmdb::Error>>(
+      &self,
+      mut f: F,
+    ) -> Result<T, lmdb::Error> {
+      loop {
+        let map_size = self.map_size.read();
+        let result = self.env.begin_rw_txn().and_then(|mut txn| {
+          let value = f(&mut txn)?;
+          txn.commit()?;
+          Ok(value)
+        });
+        match result {
+          Err(MapFull) => {
+            let full_map_size = *map_size;
+            std::mem::drop(map_size);
+            self.grow_map(full_map_size)?;
+          }
+          Err(MapResized) => {
+            std::mem::drop(map_size);
+            self.adopt_map_size()?;
+          }
+          result => return result,
+        }
+      }
+    }
+
+    ///
+    /// Doubles the size of the memory map, unless another writer already grew it from the given
+    /// (full) size.
+    ///
+    fn grow_map(&self, full_map_size: usize) -> Result<(), lmdb::Error> {
+      let mut map_size = self.map_size.write();
+      if *map_size == full_map_size {
+        let new_map_size = full_map_size.saturating_mul(2);
+        debug!(
+          "Growing the memory map of a local store shard from {} to {} bytes",
+          full_map_size, new_map_size
+        );
+        self.set_map_size(new_map_size)?;
+        *map_size = new_map_size;
+      }
+      Ok(())
+    }
+
+    ///
+    /// Adopts the size that another process grew the memory map to.
+    ///
+    fn adopt_map_size(&self) -> Result<(), lmdb::Error> {
+      let _map_size = self.map_size.write();
+      self.set_map_size(0)
+    }
+
+    fn set_map_size(&self, size: usize) -> Result<(), lmdb::Error> {
+      // This is safe because callers hold the write lock of the map size, so there are no
+      // transactions in this process.
+      match unsafe { lmdb_sys::mdb_env_set_mapsize(self.env.env(), size) } {
+        0 => Ok(()),
+        err_code => Err(lmdb::Error::from_err_code(err_code)),
+      }
+    }
+
+    ///
+    /// Deletes the content of the given fingerprint, and everything recorded about it.
+    ///
+    fn delete(
+      &self,
+      txn: &mut RwTransaction<'_>,
+      fingerprint: &Fingerprint,
+    ) -> Result<(), lmdb::Error> {
+      txn.del(self.content, &fingerprint.as_ref(), None)?;
+      for database in &[self.leases, self.last_used, self.compressed] {
+        txn
+          .del(*database, &fingerprint.as_ref(), None)
+          .or_else(|err| match err {
+            NotFound => Ok(()),
+            err => Err(err),
+          })?;
+      }
+      Ok(())
+    }
   }
 
   impl ShardedLmdb {
-    pub fn new(root_path: PathBuf) -> Result<ShardedLmdb, String> {
+    pub fn new(root_path: PathBuf, options: LocalStoreOptions) -> Result<ShardedLmdb, String> {
       trace!("Initializing ShardedLmdb at root {:?}", root_path);
       let mut lmdbs = HashMap::new();
 
-      for (env, dir, fingerprint_prefix) in ShardedLmdb::envs(&root_path)? {
+      for (env, dir, fingerprint_prefix) in ShardedLmdb::envs(&root_path, options)? {
         trace!("Making ShardedLmdb content database for {:?}", dir);
         let content_database = env
           .create_db(Some("content"), DatabaseFlags::empty())
@@ -1066,31 +4049,82 @@ mod local {
             )
           })?;
 
+        trace!("Making ShardedLmdb last-used database for {:?}", dir);
+        let last_used_database = env
+          .create_db(Some("last_used"), DatabaseFlags::empty())
+          .map_err(|e| {
+            format!(
+              "Error creating/opening last-used database at {:?}: {}",
+              dir, e
+            )
+          })?;
+
+        trace!("Making ShardedLmdb compressed database for {:?}", dir);
+        let compressed_database = env
+          .create_db(Some("compressed"), DatabaseFlags::empty())
+          .map_err(|e| {
+            format!(
+              "Error creating/opening compressed database at {:?}: {}",
+              dir, e
+            )
+          })?;
+
         lmdbs.insert(
           fingerprint_prefix,
-          (Arc::new(env), content_database, lease_database),
+          Shard {
+            env: Arc::new(env),
+            content: content_database,
+            leases: lease_database,
+            last_used: last_used_database,
+            compressed: compressed_database,
+            map_size: Arc::new(RwLock::new(options.shard_map_size_bytes)),
+          },
         );
       }
 
-      Ok(ShardedLmdb { lmdbs, root_path })
+      Ok(ShardedLmdb {
+        lmdbs,
+        prefix_mask: Self::prefix_mask(options.shard_count),
+        root_path,
+        options,
+      })
+    }
+
+    // The first log2(shard_count) bits of a fingerprint select its shard.
+    fn prefix_mask(shard_count: usize) -> u8 {
+      (0xFF00_u16 >> shard_count.trailing_zeros()) as u8
     }
 
-    fn envs(root_path: &Path) -> Result<Vec<(Environment, PathBuf, u8)>, String> {
-      let mut envs = Vec::with_capacity(0x10);
-      for b in 0x00..0x10 {
-        let fingerprint_prefix = b << 4;
-        let mut dirname = String::new();
-        fmt::Write::write_fmt(&mut dirname, format_args!("{:x}", fingerprint_prefix)).unwrap();
-        let dirname = dirname[0..1].to_owned();
-        let dir = root_path.join(dirname);
+    fn envs(
+      root_path: &Path,
+      options: LocalStoreOptions,
+    ) -> Result<Vec<(Environment, PathBuf, u8)>, String> {
+      let prefix_shift = 8 - options.shard_count.trailing_zeros();
+      let mut envs = Vec::with_capacity(options.shard_count);
+      for shard in 0..options.shard_count {
+        let fingerprint_prefix = (shard << prefix_shift) as u8;
+        // The default 16 shards are named by the hex digit of their prefix, and other shard counts
+        // are kept apart from them (and from each other), because they split fingerprints
+        // differently.
+        let dir = if options.shard_count == 16 {
+          root_path.join(format!("{:x}", fingerprint_prefix >> 4))
+        } else {
+          root_path
+            .join(format!("{}_shards", options.shard_count))
+            .join(format!("{:02x}", fingerprint_prefix))
+        };
         super::super::safe_create_dir_all(&dir)
           .map_err(|err| format!("Error making directory for store at {:?}: {:?}", dir, err))?;
-        envs.push((ShardedLmdb::make_env(&dir)?, dir, fingerprint_prefix));
+        envs.push((
+          ShardedLmdb::make_env(&dir, options)?,
+          dir,
+          fingerprint_prefix,
+        ));
       }
       Ok(envs)
     }
 
-    fn make_env(dir: &Path) -> Result<Environment, String> {
+    fn make_env(dir: &Path, options: LocalStoreOptions) -> Result<Environment, String> {
       Environment::new()
         // NO_SYNC
         // =======
@@ -1112,35 +4146,36 @@ mod local {
         // ======
         //
         // Without this flag, each time a read transaction is started, it eats into our
-        // transaction limit (default: 126) until that thread dies.
+        // transaction limit (max_readers) until that thread dies.
         //
         // This flag makes transactions be removed from that limit when they are dropped, rather
         // than when their thread dies. This is important, because we perform reads from a
         // thread pool, so our threads never die. Without this flag, all read requests will fail
-        // after the first 126.
+        // after the first max_readers.
         //
         // The only down-side is that you need to make sure that any individual OS thread must
         // not try to perform multiple write transactions concurrently. Fortunately, this
         // property holds for us.
         .set_flags(EnvironmentFlags::NO_SYNC | EnvironmentFlags::NO_TLS)
-        // 2 DBs; one for file contents, one for leases.
-        .set_max_dbs(2)
-        .set_map_size(MAX_LOCAL_STORE_SIZE_BYTES)
+        // 4 DBs; one for file contents, one for leases, one for the times of last use, and one for
+        // which contents are compressed.
+        .set_max_dbs(4)
+        .set_map_size(options.shard_map_size_bytes)
+        .set_max_readers(options.max_readers)
         .open(dir)
         .map_err(|e| format!("Error making env for store at {:?}: {}", dir, e))
     }
 
-    // First Database is content, second is leases.
-    pub fn get(&self, fingerprint: &Fingerprint) -> (Arc<Environment>, Database, Database) {
-      self.lmdbs[&(fingerprint.0[0] & 0xF0)].clone()
+    pub fn get(&self, fingerprint: &Fingerprint) -> Shard {
+      self.lmdbs[&(fingerprint.0[0] & self.prefix_mask)].clone()
     }
 
-    pub fn all_lmdbs(&self) -> Vec<(Arc<Environment>, Database, Database)> {
+    pub fn all_lmdbs(&self) -> Vec<Shard> {
       self.lmdbs.values().cloned().collect()
     }
 
     pub fn compact(&self) -> Result<(), String> {
-      for (env, old_dir, _) in ShardedLmdb::envs(&self.root_path)? {
+      for (env, old_dir, _) in ShardedLmdb::envs(&self.root_path, self.options)? {
         let new_dir = TempDir::new_in(old_dir.parent().unwrap()).expect("TODO");
         env
           .copy(new_dir.path(), EnvironmentCopyFlags::COMPACT)
@@ -1170,10 +4205,74 @@ mod local {
     }
   }
 
+  ///
+  /// What a ByteStore can store: bytes, or a mapped file.
+  ///
+  trait StorableContents: AsRef<[u8]> + Clone + Send + Sync + 'static {
+    ///
+    /// Fails if the contents changed while they were being stored, which only files can.
+    ///
+    fn check_unchanged(&self) -> Result<(), String> {
+      Ok(())
+    }
+  }
+
+  impl StorableContents for Bytes {}
+
+  impl StorableContents for MappedFile {
+    fn check_unchanged(&self) -> Result<(), String> {
+      match self.unchanged() {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("File was changed while it was being stored".to_owned()),
+        Err(err) => Err(format!(
+          "Error checking whether file was changed while it was being stored: {}",
+          err
+        )),
+      }
+    }
+  }
+
+  enum FileContents {
+    Mapped(MappedFile),
+    Read(Bytes),
+  }
+
+  impl FileContents {
+    ///
+    /// Maps the file at the given path if it is at least the given size, or otherwise (or if it
+    /// can't be mapped) reads it.
+    ///
+    fn open(path: &Path, mmap_threshold_bytes: Option<usize>) -> Result<FileContents, String> {
+      let mut file =
+        std::fs::File::open(path).map_err(|err| format!("Error opening {:?}: {}", path, err))?;
+      let len = file
+        .metadata()
+        .map_err(|err| format!("Error reading metadata of {:?}: {}", path, err))?
+        .len() as usize;
+      match mmap_threshold_bytes {
+        Some(threshold_bytes) if len >= threshold_bytes && len > 0 => {
+          match MappedFile::map(&file) {
+            Ok(mapped) => return Ok(FileContents::Mapped(mapped)),
+            Err(err) => debug!("Reading {:?}, which couldn't be mapped: {}", path, err),
+          }
+        }
+        _ => {}
+      }
+      let mut bytes = Vec::with_capacity(len);
+      file
+        .read_to_end(&mut bytes)
+        .map_err(|err| format!("Error reading {:?}: {}", path, err))?;
+      Ok(FileContents::Read(Bytes::from(bytes)))
+    }
+  }
+
   #[derive(Eq, PartialEq, Ord, PartialOrd)]
   struct AgedFingerprint {
-    // expired_seconds_ago must be the first field for the Ord implementation.
-    expired_seconds_ago: u64,
+    // collectable and unused_seconds must be the first fields for the Ord implementation, so that
+    // of the fingerprints which aren't leased, the least recently used are collected first.
+    collectable: bool,
+    // How long ago the fingerprint was last used, or its lease expired, whichever was later.
+    unused_seconds: u64,
     fingerprint: Fingerprint,
     size_bytes: usize,
     entry_type: EntryType,
@@ -1182,10 +4281,16 @@ mod local {
   #[cfg(test)]
   pub mod tests {
     use super::super::tests::block_on;
-    use super::{ByteStore, EntryType, ShrinkBehavior};
+    use super::{
+      ByteStore, EntryProblem, EntryType, Eviction, EvictionPolicy, EvictionReason,
+      LocalStoreOptions, ShrinkBehavior, LAST_USED_RESOLUTION_SECS,
+    };
     use bytes::{BufMut, Bytes, BytesMut};
     use hashing::{Digest, Fingerprint};
+    use lmdb::{Transaction, WriteFlags};
+    use protobuf::Message;
     use std::path::Path;
+    use std::time::Duration;
     use tempfile::TempDir;
     use testutil::data::{TestData, TestDirectory};
     use walkdir::WalkDir;
@@ -1509,6 +4614,170 @@ mod local {
       // Whether the unleased file is present is undefined.
     }
 
+    #[test]
+    fn garbage_collect_least_recently_used_first() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let roland = prime_store_with_file_bytes(&store, TestData::roland().bytes());
+      let catnip = prime_store_with_file_bytes(&store, TestData::catnip().bytes());
+      let robin = prime_store_with_file_bytes(&store, TestData::robin().bytes());
+      let hours_ago = |hours| ByteStore::now_secs_since_epoch() - hours * 60 * 60;
+      set_last_used(&store, roland, hours_ago(3));
+      set_last_used(&store, catnip, hours_ago(1));
+      set_last_used(&store, robin, hours_ago(2));
+
+      store
+        .shrink(TestData::catnip().len(), ShrinkBehavior::Fast)
+        .expect("Error shrinking");
+      assert_eq!(load_file_bytes(&store, roland), Ok(None));
+      assert_eq!(load_file_bytes(&store, robin), Ok(None));
+      assert_eq!(
+        load_file_bytes(&store, catnip),
+        Ok(Some(TestData::catnip().bytes()))
+      );
+    }
+
+    #[test]
+    fn garbage_collect_recently_used_before_leased() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let roland = prime_store_with_file_bytes(&store, TestData::roland().bytes());
+      let catnip = prime_store_with_file_bytes(&store, TestData::catnip().bytes());
+      set_last_used(
+        &store,
+        roland,
+        ByteStore::now_secs_since_epoch() - 24 * 60 * 60,
+      );
+      store.lease_all(vec![roland].iter()).expect("Error leasing");
+
+      store
+        .shrink(0, ShrinkBehavior::Fast)
+        .expect("Error shrinking");
+      assert_eq!(
+        load_file_bytes(&store, roland),
+        Ok(Some(TestData::roland().bytes()))
+      );
+      assert_eq!(load_file_bytes(&store, catnip), Ok(None));
+    }
+
+    #[test]
+    fn evict_unused_entries() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let roland = prime_store_with_file_bytes(&store, TestData::roland().bytes());
+      let catnip = prime_store_with_file_bytes(&store, TestData::catnip().bytes());
+      let robin = prime_store_with_file_bytes(&store, TestData::robin().bytes());
+      let days_ago = |days| ByteStore::now_secs_since_epoch() - days * 24 * 60 * 60;
+      set_last_used(&store, roland, days_ago(40));
+      set_last_used(&store, catnip, days_ago(1));
+      set_last_used(&store, robin, days_ago(40));
+      store.lease_all(vec![robin].iter()).expect("Error leasing");
+
+      let max_unused_age = Duration::from_secs(30 * 24 * 60 * 60);
+      let policy = EvictionPolicy {
+        max_unused_age: Some(max_unused_age),
+        ..EvictionPolicy::default()
+      };
+      let evictions = store.evict(&policy, true).expect("Error evicting");
+      assert_eq!(
+        evictions
+          .iter()
+          .map(|eviction| (eviction.fingerprint, eviction.stored_bytes))
+          .collect::<Vec<_>>(),
+        vec![(roland.0, roland.1)]
+      );
+      match evictions[0].reason {
+        EvictionReason::Unused { unused_for, .. } => assert!(unused_for > max_unused_age),
+        reason => panic!("Unexpected eviction reason: {:?}", reason),
+      }
+      // A dry run evicts nothing.
+      assert_eq!(
+        load_file_bytes(&store, roland),
+        Ok(Some(TestData::roland().bytes()))
+      );
+
+      assert_eq!(store.evict(&policy, false), Ok(evictions));
+      assert_eq!(load_file_bytes(&store, roland), Ok(None));
+      assert_eq!(
+        load_file_bytes(&store, catnip),
+        Ok(Some(TestData::catnip().bytes()))
+      );
+      assert_eq!(
+        load_file_bytes(&store, robin),
+        Ok(Some(TestData::robin().bytes()))
+      );
+    }
+
+    #[test]
+    fn evict_least_recently_used_over_shard_quota() {
+      let dir = TempDir::new().unwrap();
+      let options = LocalStoreOptions::new(1, 1024 * 1024, 126).unwrap();
+      let store = ByteStore::new_with_options(dir.path(), options).unwrap();
+      let roland = prime_store_with_file_bytes(&store, TestData::roland().bytes());
+      let catnip = prime_store_with_file_bytes(&store, TestData::catnip().bytes());
+      let robin = prime_store_with_file_bytes(&store, TestData::robin().bytes());
+      let testdir = TestDirectory::containing_roland();
+      block_on(store.store_bytes(EntryType::Directory, testdir.bytes(), false))
+        .expect("Error storing directory bytes");
+      let hours_ago = |hours| ByteStore::now_secs_since_epoch() - hours * 60 * 60;
+      set_last_used(&store, roland, hours_ago(3));
+      set_last_used(&store, catnip, hours_ago(1));
+      set_last_used(&store, robin, hours_ago(2));
+
+      let quota_bytes = catnip.1 + robin.1;
+      let policy = EvictionPolicy {
+        file_shard_quota_bytes: Some(quota_bytes),
+        ..EvictionPolicy::default()
+      };
+      assert_eq!(
+        store.evict(&policy, false),
+        Ok(vec![Eviction {
+          entry_type: EntryType::File,
+          fingerprint: roland.0,
+          stored_bytes: roland.1,
+          reason: EvictionReason::OverShardQuota {
+            fingerprint_prefix: 0,
+            shard_stored_bytes: roland.1 + catnip.1 + robin.1,
+            quota_bytes,
+          },
+        }])
+      );
+      assert_eq!(load_file_bytes(&store, roland), Ok(None));
+      assert_eq!(
+        load_file_bytes(&store, robin),
+        Ok(Some(TestData::robin().bytes()))
+      );
+      // Directories have their own quota.
+      assert_eq!(
+        load_directory_proto_bytes(&store, testdir.digest()),
+        Ok(Some(testdir.bytes()))
+      );
+    }
+
+    #[test]
+    fn storing_and_loading_record_uses() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let roland = prime_store_with_file_bytes(&store, TestData::roland().bytes());
+      let now = ByteStore::now_secs_since_epoch();
+      assert!(last_used(&store, roland).unwrap() >= now - 1);
+
+      // Recent uses aren't re-recorded by loads.
+      let recently = now - LAST_USED_RESOLUTION_SECS / 2;
+      set_last_used(&store, roland, recently);
+      load_file_bytes(&store, roland).unwrap();
+      assert_eq!(last_used(&store, roland), Some(recently));
+
+      set_last_used(&store, roland, now - 2 * LAST_USED_RESOLUTION_SECS);
+      load_file_bytes(&store, roland).unwrap();
+      assert!(last_used(&store, roland).unwrap() >= now);
+
+      // Storing bytes which are already stored is a use of them too.
+      set_last_used(&store, roland, recently);
+      prime_store_with_file_bytes(&store, TestData::roland().bytes());
+      assert!(last_used(&store, roland).unwrap() >= now);
+    }
+
     #[test]
     fn garbage_collect_and_compact() {
       let dir = TempDir::new().unwrap();
@@ -1545,6 +4814,31 @@ mod local {
       );
     }
 
+    #[test]
+    fn compaction_is_skipped_while_another_store_is_open() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let other_store = new_store(dir.path());
+
+      let mut bytes = BytesMut::with_capacity(1024 * 1024);
+      for _ in 0..1024 * 1024 {
+        bytes.put(b'0');
+      }
+      block_on(store.store_bytes(EntryType::File, bytes.freeze(), false)).expect("Error storing");
+
+      let size = get_directory_size(dir.path());
+      store
+        .shrink(0, ShrinkBehavior::Compact)
+        .expect("Error shrinking");
+      assert_eq!(get_directory_size(dir.path()), size);
+
+      std::mem::drop(other_store);
+      store
+        .shrink(0, ShrinkBehavior::Compact)
+        .expect("Error shrinking");
+      assert!(get_directory_size(dir.path()) < size);
+    }
+
     #[test]
     fn entry_type_for_file() {
       let testdata = TestData::roland();
@@ -1575,41 +4869,343 @@ mod local {
       )
     }
 
-    #[test]
-    fn entry_type_for_missing() {
-      let testdata = TestData::roland();
-      let testdir = TestDirectory::containing_roland();
+    #[test]
+    fn entry_type_for_missing() {
+      let testdata = TestData::roland();
+      let testdir = TestDirectory::containing_roland();
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      block_on(store.store_bytes(EntryType::Directory, testdir.bytes(), false))
+        .expect("Error storing");
+      prime_store_with_file_bytes(&store, testdata.bytes());
+      assert_eq!(
+        store.entry_type(&TestDirectory::recursive().fingerprint()),
+        Ok(None)
+      )
+    }
+
+    #[test]
+    pub fn empty_file_is_known() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let empty_file = TestData::empty();
+      assert_eq!(
+        block_on(store.load_bytes_with(EntryType::File, empty_file.digest(), |b| b)),
+        Ok(Some(empty_file.bytes())),
+      )
+    }
+
+    #[test]
+    pub fn empty_directory_is_known() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let empty_dir = TestDirectory::empty();
+      assert_eq!(
+        block_on(store.load_bytes_with(EntryType::Directory, empty_dir.digest(), |b| b)),
+        Ok(Some(empty_dir.bytes())),
+      )
+    }
+
+    #[test]
+    fn corrupted_files_are_deleted_when_verifying() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let roland = prime_store_with_file_bytes(&store, TestData::roland().bytes());
+      let catnip = prime_store_with_file_bytes(&store, TestData::catnip().bytes());
+      corrupt_file(&store, roland);
+
+      // Without verification, corrupted bytes are loaded as they are.
+      assert_ne!(
+        load_file_bytes(&store, roland),
+        Ok(Some(TestData::roland().bytes()))
+      );
+      assert!(load_file_bytes(&store, roland).unwrap().is_some());
+
+      let verifying_store = store.with_digest_verification(true);
+      assert_eq!(
+        load_file_bytes(&verifying_store, catnip),
+        Ok(Some(TestData::catnip().bytes()))
+      );
+      assert_eq!(load_file_bytes(&verifying_store, roland), Ok(None));
+      assert_eq!(load_file_bytes(&store, roland), Ok(None));
+      assert_eq!(store.entry_type(&roland.0), Ok(None));
+    }
+
+    #[test]
+    fn large_files_are_compressed() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path()).with_compression_threshold(Some(1024));
+      let small = Bytes::from("meow".repeat(100));
+      let large = Bytes::from("meow".repeat(1000));
+      // Pseudo-random bytes (from an xorshift generator) don't get any smaller when compressed.
+      let mut state: u32 = 2_463_534_242;
+      let incompressible: Bytes = (0..4096)
+        .map(|_| {
+          state ^= state << 13;
+          state ^= state >> 17;
+          state ^= state << 5;
+          state as u8
+        })
+        .collect::<Vec<_>>()
+        .into();
+      let files: Vec<(Digest, Bytes)> = vec![small, large, incompressible]
+        .into_iter()
+        .map(|bytes| (prime_store_with_file_bytes(&store, bytes.clone()), bytes))
+        .collect();
+
+      assert_eq!(stored_bytes(&store, files[0].0), files[0].1.to_vec());
+      assert!(stored_bytes(&store, files[1].0).len() < files[1].1.len() / 10);
+      assert_eq!(stored_bytes(&store, files[2].0), files[2].1.to_vec());
+
+      // Stores load compressed files, however they are configured.
+      let uncompressing_store = new_store(dir.path());
+      for (digest, bytes) in &files {
+        assert_eq!(
+          load_file_bytes(&uncompressing_store, *digest),
+          Ok(Some(bytes.clone()))
+        );
+        assert_eq!(
+          load_file_bytes(&store.with_digest_verification(true), *digest),
+          Ok(Some(bytes.clone()))
+        );
+      }
+    }
+
+    #[test]
+    fn corrupted_compressed_files_are_deleted() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path()).with_compression_threshold(Some(1024));
+      let large = TestData::new(&"meow".repeat(1000));
+      prime_store_with_file_bytes(&store, large.bytes());
+
+      // Truncating compressed bytes makes them impossible to decompress, whether or not digests
+      // are being verified.
+      let shard = store.inner.file_dbs.clone().unwrap().get(&large.digest().0);
+      let mut stored = stored_bytes(&store, large.digest());
+      stored.truncate(stored.len() / 2);
+      {
+        let mut txn = shard.env.begin_rw_txn().unwrap();
+        txn
+          .put(
+            shard.content,
+            &large.digest().0,
+            &stored,
+            WriteFlags::empty(),
+          )
+          .unwrap();
+        txn.commit().unwrap();
+      }
+      assert_eq!(load_file_bytes(&store, large.digest()), Ok(None));
+      assert_eq!(store.entry_type(&large.digest().0), Ok(None));
+    }
+
+    #[test]
+    fn fsck_broken_entries() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path()).with_compression_threshold(Some(1024));
+      let roland = prime_store_with_file_bytes(&store, TestData::roland().bytes());
+      let catnip = prime_store_with_file_bytes(&store, TestData::catnip().bytes());
+      corrupt_file(&store, roland);
+
+      let large = TestData::new(&"meow".repeat(1000));
+      prime_store_with_file_bytes(&store, large.bytes());
+      let shard = store.inner.file_dbs.clone().unwrap().get(&large.digest().0);
+      let mut stored = stored_bytes(&store, large.digest());
+      stored.truncate(stored.len() / 2);
+      {
+        let mut txn = shard.env.begin_rw_txn().unwrap();
+        txn
+          .put(
+            shard.content,
+            &large.digest().0,
+            &stored,
+            WriteFlags::empty(),
+          )
+          .unwrap();
+        txn.commit().unwrap();
+      }
+      // But large entries which are intact decompress, and so aren't broken.
+      let intact_large = TestData::new(&"purr".repeat(1000));
+      prime_store_with_file_bytes(&store, intact_large.bytes());
+
+      let testdir = TestDirectory::containing_roland();
+      block_on(store.store_bytes(EntryType::Directory, testdir.bytes(), false))
+        .expect("Error storing");
+      // Directories whose files aren't sorted aren't canonical.
+      let unsorted = {
+        let mut directory = TestDirectory::containing_roland_and_treats().directory();
+        directory.mut_files().reverse();
+        directory.write_to_bytes().unwrap()
+      };
+      let unsorted =
+        block_on(store.store_bytes(EntryType::Directory, Bytes::from(unsorted), false))
+          .expect("Error storing");
+
+      let problem_of = |fingerprint: Fingerprint, dry_run: bool| {
+        let broken_entries = store.fsck(dry_run).expect("Error checking store");
+        assert_eq!(broken_entries.len(), 3, "Got {:?}", broken_entries);
+        broken_entries
+          .into_iter()
+          .find(|broken_entry| broken_entry.fingerprint == fingerprint)
+          .map(|broken_entry| broken_entry.problem)
+      };
+      if let Some(EntryProblem::WrongFingerprint(_)) = problem_of(roland.0, true) {
+      } else {
+        panic!("Want wrong fingerprint of {:?}", roland);
+      }
+      assert_eq!(
+        problem_of(large.fingerprint(), true),
+        Some(EntryProblem::Undecompressable)
+      );
+      assert_eq!(problem_of(intact_large.fingerprint(), true), None);
+      if let Some(EntryProblem::NonCanonicalDirectory(_)) = problem_of(unsorted.0, false) {
+      } else {
+        panic!("Want non-canonical directory {:?}", unsorted);
+      }
+
+      // The broken entries were deleted, and the rest kept.
+      assert_eq!(store.fsck(true), Ok(vec![]));
+      assert_eq!(load_file_bytes(&store, roland), Ok(None));
+      assert_eq!(
+        load_file_bytes(&store, catnip),
+        Ok(Some(TestData::catnip().bytes()))
+      );
+      assert_eq!(
+        load_file_bytes(&store, intact_large.digest()),
+        Ok(Some(intact_large.bytes()))
+      );
+      assert_eq!(
+        block_on(store.load_bytes_with(EntryType::Directory, testdir.digest(), |b| b)),
+        Ok(Some(testdir.bytes()))
+      );
+    }
+
+    #[test]
+    fn action_results() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let action_digest = TestData::catnip().digest();
+      assert_eq!(block_on(store.load_action_result(action_digest)), Ok(None));
+
+      block_on(store.store_action_result(action_digest, TestData::roland().bytes()))
+        .expect("Error storing");
+      block_on(store.store_action_result(action_digest, TestData::robin().bytes()))
+        .expect("Error storing");
+      assert_eq!(
+        block_on(store.load_action_result(action_digest)),
+        Ok(Some(TestData::robin().bytes()))
+      );
+    }
+
+    #[test]
+    fn other_shard_counts() {
+      for shard_count in &[1, 2, 256] {
+        let dir = TempDir::new().unwrap();
+        let options = LocalStoreOptions::new(*shard_count, 1024 * 1024, 126).unwrap();
+        let store = ByteStore::new_with_options(dir.path(), options).unwrap();
+        let files = vec![TestData::roland(), TestData::catnip(), TestData::robin()];
+        for file in &files {
+          prime_store_with_file_bytes(&store, file.bytes());
+        }
+        for file in &files {
+          assert_eq!(
+            load_file_bytes(&store, file.digest()),
+            Ok(Some(file.bytes()))
+          );
+        }
+        assert!(dir
+          .path()
+          .join("files")
+          .join(format!("{}_shards", shard_count))
+          .is_dir());
+      }
+    }
+
+    #[test]
+    fn invalid_shard_counts() {
+      LocalStoreOptions::new(0, 1024, 126).expect_err("Want Err for no shards");
+      LocalStoreOptions::new(3, 1024, 126).expect_err("Want Err for non power of two");
+      LocalStoreOptions::new(512, 1024, 126).expect_err("Want Err for too many shards");
+    }
+
+    #[test]
+    fn store_files_mapped_or_read() {
+      let files_dir = TempDir::new().unwrap();
+      let large = Bytes::from("meow".repeat(1024 * 1024));
+      let files = vec![
+        ("roland", TestData::roland().bytes()),
+        ("large", large),
+        ("empty", Bytes::new()),
+      ];
+      for (name, bytes) in &files {
+        std::fs::write(files_dir.path().join(name), bytes).unwrap();
+      }
+
+      for mmap_threshold_bytes in &[None, Some(0), Some(1024)] {
+        for hashing_parallelism in &[0, 2] {
+          let dir = TempDir::new().unwrap();
+          let store = new_store(dir.path())
+            .with_mmap_threshold(*mmap_threshold_bytes)
+            .with_hashing_parallelism(*hashing_parallelism);
+          for (name, bytes) in &files {
+            let digest = block_on(store.store_file(&files_dir.path().join(name), false))
+              .expect("Error storing file");
+            assert_eq!(digest.1, bytes.len());
+            assert_eq!(load_file_bytes(&store, digest), Ok(Some(bytes.clone())));
+          }
+        }
+      }
+
       let dir = TempDir::new().unwrap();
-      let store = new_store(dir.path());
-      block_on(store.store_bytes(EntryType::Directory, testdir.bytes(), false))
-        .expect("Error storing");
-      prime_store_with_file_bytes(&store, testdata.bytes());
-      assert_eq!(
-        store.entry_type(&TestDirectory::recursive().fingerprint()),
-        Ok(None)
-      )
+      block_on(new_store(dir.path()).store_file(&files_dir.path().join("missing"), false))
+        .expect_err("Want Err for a missing file");
     }
 
     #[test]
-    pub fn empty_file_is_known() {
+    fn large_blobs_are_hashed_on_the_hashing_pool() {
+      let blobs = (0..4_u8)
+        .map(|byte| Bytes::from(vec![byte; 2 * 1024 * 1024]))
+        .collect::<Vec<_>>();
+      let store_all = |store: &ByteStore| {
+        block_on(futures::future::join_all(
+          blobs
+            .iter()
+            .map(|blob| store.store_bytes(EntryType::File, blob.clone(), false))
+            .collect::<Vec<_>>(),
+        ))
+        .expect("Error storing large blobs")
+      };
+
+      let unpooled_dir = TempDir::new().unwrap();
+      let unpooled_digests = store_all(&new_store(unpooled_dir.path()));
+
       let dir = TempDir::new().unwrap();
-      let store = new_store(dir.path());
-      let empty_file = TestData::empty();
-      assert_eq!(
-        block_on(store.load_bytes_with(EntryType::File, empty_file.digest(), |b| b)),
-        Ok(Some(empty_file.bytes())),
-      )
+      let store = new_store(dir.path())
+        .with_compression_threshold(Some(1024))
+        .with_hashing_parallelism(2);
+      assert_eq!(store_all(&store), unpooled_digests);
+      assert!(store.hashing_pool.lock().is_some());
+      for (blob, digest) in blobs.iter().zip(unpooled_digests) {
+        assert_eq!(load_file_bytes(&store, digest), Ok(Some(blob.clone())));
+        assert!(stored_bytes(&store, digest).len() < blob.len());
+      }
+
+      // Small blobs don't need the pool.
+      let small_dir = TempDir::new().unwrap();
+      let small_store = new_store(small_dir.path()).with_hashing_parallelism(2);
+      prime_store_with_file_bytes(&small_store, TestData::roland().bytes());
+      assert!(small_store.hashing_pool.lock().is_none());
     }
 
     #[test]
-    pub fn empty_directory_is_known() {
+    fn full_maps_are_grown() {
       let dir = TempDir::new().unwrap();
-      let store = new_store(dir.path());
-      let empty_dir = TestDirectory::empty();
-      assert_eq!(
-        block_on(store.load_bytes_with(EntryType::Directory, empty_dir.digest(), |b| b)),
-        Ok(Some(empty_dir.bytes())),
-      )
+      let options = LocalStoreOptions::new(16, 64 * 1024, 126).unwrap();
+      let store = ByteStore::new_with_options(dir.path(), options).unwrap();
+      let large = Bytes::from("meow".repeat(1024 * 1024));
+      let digest = prime_store_with_file_bytes(&store, large.clone());
+      assert_eq!(load_file_bytes(&store, digest), Ok(Some(large)));
+      assert_eq!(store.entry_type(&digest.0), Ok(Some(EntryType::File)));
     }
 
     pub fn new_store<P: AsRef<Path>>(dir: P) -> ByteStore {
@@ -1639,6 +5235,42 @@ mod local {
       block_on(store.store_bytes(EntryType::File, bytes, false)).expect("Error storing file bytes")
     }
 
+    ///
+    /// The bytes stored for the given file, which may be compressed.
+    ///
+    fn stored_bytes(store: &ByteStore, digest: Digest) -> Vec<u8> {
+      let shard = store.inner.file_dbs.clone().unwrap().get(&digest.0);
+      let txn = shard.env.begin_ro_txn().unwrap();
+      txn.get(shard.content, &digest.0).unwrap().to_vec()
+    }
+
+    ///
+    /// Flips a bit of the stored bytes of the given file, without changing their key.
+    ///
+    pub fn corrupt_file(store: &ByteStore, digest: Digest) {
+      let shard = store.inner.file_dbs.clone().unwrap().get(&digest.0);
+      let mut bytes = stored_bytes(store, digest);
+      bytes[0] ^= 1;
+      let mut txn = shard.env.begin_rw_txn().unwrap();
+      txn
+        .put(shard.content, &digest.0, &bytes, WriteFlags::empty())
+        .unwrap();
+      txn.commit().unwrap();
+    }
+
+    fn set_last_used(store: &ByteStore, digest: Digest, secs_since_epoch: u64) {
+      let shard = store.inner.file_dbs.clone().unwrap().get(&digest.0);
+      let mut txn = shard.env.begin_rw_txn().unwrap();
+      ByteStore::record_use(shard.last_used, &digest.0, secs_since_epoch, &mut txn).unwrap();
+      txn.commit().unwrap();
+    }
+
+    fn last_used(store: &ByteStore, digest: Digest) -> Option<u64> {
+      let shard = store.inner.file_dbs.clone().unwrap().get(&digest.0);
+      let txn = shard.env.begin_ro_txn().unwrap();
+      ByteStore::read_timestamp(&txn, shard.last_used, digest.0.as_ref()).unwrap()
+    }
+
     fn get_directory_size(path: &Path) -> usize {
       let mut len: usize = 0;
       for entry in WalkDir::new(path) {
@@ -1654,32 +5286,129 @@ mod local {
 }
 
 mod remote {
-  use super::{BackoffConfig, EntryType};
+  use super::{
+    channel_credentials, is_retryable_rpc_error, BackoffConfig, BearerToken, ChannelPolicy,
+    ClientCertificate, Compression, EntryType, LoadBalancing, Proxy, RequestMetadata,
+  };
 
   use bazel_protos;
-  use boxfuture::{BoxFuture, Boxable};
+  use boxfuture::{try_future, BoxFuture, Boxable};
   use bytes::{Bytes, BytesMut};
   use digest::{Digest as DigestTrait, FixedOutput};
   use futures::{self, future, Future, IntoFuture, Sink, Stream};
   use grpcio;
   use hashing::{Digest, Fingerprint};
-  use serverset::{Retry, Serverset};
+  use log::debug;
+  use parking_lot::Mutex;
+  use protobuf::{Message, ProtobufEnum};
+  use serverset::{AttemptError, Retry, RetryPolicy, Serverset};
   use sha2::Sha256;
   use std::cmp::min;
-  use std::collections::HashSet;
+  use std::collections::{HashMap, HashSet};
+  use std::mem;
+  use std::sync::atomic::{AtomicBool, Ordering};
   use std::sync::Arc;
   use std::time::Duration;
   use uuid;
 
+  // The backoff before the first retry of a failed RPC, which doubles with each further retry up to
+  // the max.
+  const INITIAL_RPC_BACKOFF_MILLIS: u64 = 100;
+  const MAX_RPC_BACKOFF_MILLIS: u64 = 5000;
+
+  // The maximum total size of the blobs in each batch request, for servers which don't advertise a
+  // lower limit. Servers reject messages larger than 4MB by default.
+  const MAX_BATCH_TOTAL_SIZE_BYTES: usize = 1024 * 1024;
+
+  fn log_retries<Value>((value, retries): (Value, usize)) -> Value {
+    if retries > 0 {
+      debug!("Remote store RPC succeeded after {} retries", retries);
+    }
+    value
+  }
+
+  ///
+  /// The limits and permissions which a remote store advertises via GetCapabilities.
+  ///
+  #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+  pub struct CacheCapabilities {
+    ///
+    /// The maximum total size of the blobs in a batch request, if the server limits it.
+    ///
+    pub max_batch_total_size_bytes: Option<usize>,
+    ///
+    /// Whether the results of actions may be recorded in the action cache.
+    ///
+    pub action_cache_update_enabled: bool,
+  }
+
+  impl CacheCapabilities {
+    ///
+    /// The maximum total size of the blobs to send or receive in each batch request.
+    ///
+    pub fn max_batch_bytes(&self) -> usize {
+      self
+        .max_batch_total_size_bytes
+        .map(|max_batch_bytes| min(max_batch_bytes, MAX_BATCH_TOTAL_SIZE_BYTES))
+        .unwrap_or(MAX_BATCH_TOTAL_SIZE_BYTES)
+    }
+
+    fn from_server(
+      capabilities: &bazel_protos::remote_execution::CacheCapabilities,
+    ) -> Result<CacheCapabilities, String> {
+      let digest_functions = capabilities.get_digest_function();
+      if !digest_functions.is_empty()
+        && !digest_functions.contains(&bazel_protos::remote_execution::DigestFunction::SHA256)
+      {
+        return Err(format!(
+          "The remote store only supports {:?} digests, but SHA256 digests are required",
+          digest_functions
+        ));
+      }
+      let max_batch_total_size_bytes = capabilities.get_max_batch_total_size_bytes();
+      Ok(CacheCapabilities {
+        max_batch_total_size_bytes: if max_batch_total_size_bytes > 0 {
+          Some(max_batch_total_size_bytes as usize)
+        } else {
+          None
+        },
+        // Servers which don't say whether updates are allowed are assumed to allow them.
+        action_cache_update_enabled: !capabilities.has_action_cache_update_capabilities()
+          || capabilities
+            .get_action_cache_update_capabilities()
+            .get_update_enabled(),
+      })
+    }
+  }
+
+  ///
+  /// Servers which don't implement the Capabilities service are assumed to have no particular
+  /// limits.
+  ///
+  impl Default for CacheCapabilities {
+    fn default() -> CacheCapabilities {
+      CacheCapabilities {
+        max_batch_total_size_bytes: None,
+        action_cache_update_enabled: true,
+      }
+    }
+  }
+
   #[derive(Clone)]
   pub struct ByteStore {
     instance_name: Option<String>,
     chunk_size_bytes: usize,
     upload_timeout: Duration,
-    rpc_attempts: usize,
+    read_timeout: Duration,
+    action_cache_timeout: Duration,
+    retry_policy: RetryPolicy,
     env: Arc<grpcio::Environment>,
     serverset: Serverset<grpcio::Channel>,
-    authorization_header: Option<String>,
+    oauth_bearer_token: Option<BearerToken>,
+    request_metadata: Option<RequestMetadata>,
+    capabilities: Arc<Mutex<Option<CacheCapabilities>>>,
+    batch_rpcs_unimplemented: Arc<AtomicBool>,
+    fetch_unimplemented: Arc<AtomicBool>,
   }
 
   impl ByteStore {
@@ -1687,11 +5416,19 @@ mod remote {
       cas_addresses: &[String],
       instance_name: Option<String>,
       root_ca_certs: &Option<Vec<u8>>,
-      oauth_bearer_token: Option<String>,
+      client_certificate: &Option<ClientCertificate>,
+      proxy: &Option<Proxy>,
+      channel_policy: ChannelPolicy,
+      oauth_bearer_token: Option<BearerToken>,
+      request_metadata: Option<RequestMetadata>,
+      compression: Compression,
       thread_count: usize,
       chunk_size_bytes: usize,
       upload_timeout: Duration,
+      read_timeout: Duration,
+      action_cache_timeout: Duration,
       backoff_config: BackoffConfig,
+      load_balancing: LoadBalancing,
       rpc_retries: usize,
       futures_timer_thread: futures_timer::TimerHandle,
     ) -> Result<ByteStore, String> {
@@ -1700,35 +5437,67 @@ mod remote {
       let channels = cas_addresses
         .iter()
         .map(|cas_address| {
-          let builder = grpcio::ChannelBuilder::new(env.clone());
-          if let Some(ref root_ca_certs) = root_ca_certs {
-            let creds = grpcio::ChannelCredentialsBuilder::new()
-              .root_cert(root_ca_certs.clone())
-              .build();
-            builder.secure_connect(cas_address, creds)
-          } else {
-            builder.connect(cas_address)
+          let builder = channel_policy.configure(
+            grpcio::ChannelBuilder::new(env.clone())
+              .default_compression_algorithm(compression.algorithm()),
+          );
+          let connect = |builder: grpcio::ChannelBuilder| match channel_credentials(
+            root_ca_certs,
+            client_certificate,
+          ) {
+            Some(creds) => builder.secure_connect(cas_address, creds),
+            None => builder.connect(cas_address),
+          };
+          match proxy {
+            Some(ref proxy) => proxy.connect(builder, connect),
+            None => connect(builder),
           }
         })
         .collect();
 
-      let serverset = Serverset::new(channels, backoff_config, futures_timer_thread)?;
+      let serverset = Serverset::new(
+        channels,
+        backoff_config,
+        load_balancing,
+        futures_timer_thread,
+      )?;
 
       Ok(ByteStore {
         instance_name,
         chunk_size_bytes,
         upload_timeout,
-        rpc_attempts: rpc_retries + 1,
+        read_timeout,
+        action_cache_timeout,
+        retry_policy: RetryPolicy::new(
+          Duration::from_millis(INITIAL_RPC_BACKOFF_MILLIS),
+          Duration::from_millis(MAX_RPC_BACKOFF_MILLIS),
+          rpc_retries + 1,
+        )?,
         env,
         serverset,
-        authorization_header: oauth_bearer_token.map(|t| format!("Bearer {}", t)),
+        oauth_bearer_token,
+        request_metadata,
+        capabilities: Arc::new(Mutex::new(None)),
+        batch_rpcs_unimplemented: Arc::new(AtomicBool::new(false)),
+        fetch_unimplemented: Arc::new(AtomicBool::new(false)),
       })
     }
 
+    pub fn with_instance_name(&self, instance_name: Option<String>) -> ByteStore {
+      ByteStore {
+        instance_name,
+        ..self.clone()
+      }
+    }
+
+    pub fn instance_name(&self) -> Option<String> {
+      self.instance_name.clone()
+    }
+
     fn with_byte_stream_client<
       Value: Send + 'static,
-      Fut: Future<Item = Value, Error = String>,
-      IntoFut: IntoFuture<Future = Fut, Item = Value, Error = String>,
+      Fut: Future<Item = Value, Error = AttemptError>,
+      IntoFut: IntoFuture<Future = Fut, Item = Value, Error = AttemptError>,
       F: Fn(bazel_protos::bytestream_grpc::ByteStreamClient) -> IntoFut
         + Send
         + Sync
@@ -1738,20 +5507,22 @@ mod remote {
       &self,
       f: F,
     ) -> impl Future<Item = Value, Error = String> {
-      Retry(self.serverset.clone()).all_errors_immediately(
-        move |channel| {
-          f(bazel_protos::bytestream_grpc::ByteStreamClient::new(
-            channel,
-          ))
-        },
-        self.rpc_attempts,
-      )
+      Retry(self.serverset.clone())
+        .with_policy(
+          move |channel| {
+            f(bazel_protos::bytestream_grpc::ByteStreamClient::new(
+              channel,
+            ))
+          },
+          self.retry_policy,
+        )
+        .map(log_retries)
     }
 
     fn with_cas_client<
       Value: Send + 'static,
-      Fut: Future<Item = Value, Error = String>,
-      IntoFut: IntoFuture<Future = Fut, Item = Value, Error = String>,
+      Fut: Future<Item = Value, Error = AttemptError>,
+      IntoFut: IntoFuture<Future = Fut, Item = Value, Error = AttemptError>,
       F: Fn(bazel_protos::remote_execution_grpc::ContentAddressableStorageClient) -> IntoFut
         + Send
         + Sync
@@ -1761,32 +5532,164 @@ mod remote {
       &self,
       f: F,
     ) -> impl Future<Item = Value, Error = String> {
-      Retry(self.serverset.clone()).all_errors_immediately(
-        move |channel| {
-          f(bazel_protos::remote_execution_grpc::ContentAddressableStorageClient::new(channel))
-        },
-        self.rpc_attempts,
-      )
+      Retry(self.serverset.clone())
+        .with_policy(
+          move |channel| {
+            f(bazel_protos::remote_execution_grpc::ContentAddressableStorageClient::new(channel))
+          },
+          self.retry_policy,
+        )
+        .map(log_retries)
+    }
+
+    fn with_action_cache_client<
+      Value: Send + 'static,
+      Fut: Future<Item = Value, Error = AttemptError>,
+      IntoFut: IntoFuture<Future = Fut, Item = Value, Error = AttemptError>,
+      F: Fn(bazel_protos::remote_execution_grpc::ActionCacheClient) -> IntoFut
+        + Send
+        + Sync
+        + Clone
+        + 'static,
+    >(
+      &self,
+      f: F,
+    ) -> impl Future<Item = Value, Error = String> {
+      Retry(self.serverset.clone())
+        .with_policy(
+          move |channel| {
+            f(bazel_protos::remote_execution_grpc::ActionCacheClient::new(
+              channel,
+            ))
+          },
+          self.retry_policy,
+        )
+        .map(log_retries)
+    }
+
+    fn with_capabilities_client<
+      Value: Send + 'static,
+      Fut: Future<Item = Value, Error = AttemptError>,
+      IntoFut: IntoFuture<Future = Fut, Item = Value, Error = AttemptError>,
+      F: Fn(bazel_protos::remote_execution_grpc::CapabilitiesClient) -> IntoFut
+        + Send
+        + Sync
+        + Clone
+        + 'static,
+    >(
+      &self,
+      f: F,
+    ) -> impl Future<Item = Value, Error = String> {
+      Retry(self.serverset.clone())
+        .with_policy(
+          move |channel| f(bazel_protos::remote_execution_grpc::CapabilitiesClient::new(channel)),
+          self.retry_policy,
+        )
+        .map(log_retries)
+    }
+
+    fn with_fetch_client<
+      Value: Send + 'static,
+      Fut: Future<Item = Value, Error = AttemptError>,
+      IntoFut: IntoFuture<Future = Fut, Item = Value, Error = AttemptError>,
+      F: Fn(bazel_protos::remote_asset_grpc::FetchClient) -> IntoFut + Send + Sync + Clone + 'static,
+    >(
+      &self,
+      f: F,
+    ) -> impl Future<Item = Value, Error = String> {
+      Retry(self.serverset.clone())
+        .with_policy(
+          move |channel| f(bazel_protos::remote_asset_grpc::FetchClient::new(channel)),
+          self.retry_policy,
+        )
+        .map(log_retries)
     }
 
     fn call_option(&self) -> grpcio::CallOption {
       let mut call_option = grpcio::CallOption::default();
-      if let Some(ref authorization_header) = self.authorization_header {
-        let mut builder = grpcio::MetadataBuilder::with_capacity(1);
-        builder
-          .add_str("authorization", &authorization_header)
-          .unwrap();
+      if self.oauth_bearer_token.is_some() || self.request_metadata.is_some() {
+        let mut builder = grpcio::MetadataBuilder::with_capacity(2);
+        if let Some(ref oauth_bearer_token) = self.oauth_bearer_token {
+          builder
+            .add_str("authorization", &oauth_bearer_token.authorization_header())
+            .unwrap();
+        }
+        if let Some(ref request_metadata) = self.request_metadata {
+          request_metadata.add_header(&mut builder, None);
+        }
         call_option = call_option.headers(builder.build());
       }
       call_option
     }
 
-    pub fn store_bytes(&self, bytes: Bytes) -> BoxFuture<Digest, String> {
+    ///
+    /// Converts an error from an RPC into the failure of an attempt, which is retryable if the
+    /// error was likely to be transient. If the RPC was rejected as unauthenticated, the bearer
+    /// token is refreshed, and the attempt is retryable if that changed it.
+    ///
+    fn rpc_error(&self, message: &str, err: grpcio::Error) -> AttemptError {
+      let refreshed_token = self
+        .oauth_bearer_token
+        .as_ref()
+        .map(|token| token.refresh_if_unauthenticated(&err))
+        .unwrap_or(false);
+      AttemptError {
+        retryable: refreshed_token || is_retryable_rpc_error(&err),
+        message: format!("{}: {:?}", message, err),
+      }
+    }
+
+    ///
+    /// The capabilities of the server, which are queried when they are first needed, and then
+    /// remembered. Fails if the server doesn't support the digests that we use.
+    ///
+    pub fn capabilities(&self) -> BoxFuture<CacheCapabilities, String> {
+      if let Some(capabilities) = *self.capabilities.lock() {
+        return future::ok(capabilities).to_boxed();
+      }
+      let mut request = bazel_protos::remote_execution::GetCapabilitiesRequest::new();
+      if let Some(ref instance_name) = self.instance_name {
+        request.set_instance_name(instance_name.clone());
+      }
+      let store = self.clone();
+      let cached_capabilities = self.capabilities.clone();
+      self
+        .with_capabilities_client(move |client| {
+          match client
+            .get_capabilities_opt(&request, store.call_option().timeout(store.read_timeout))
+          {
+            Ok(capabilities) => {
+              CacheCapabilities::from_server(capabilities.get_cache_capabilities())
+                .map_err(AttemptError::fatal)
+            }
+            Err(grpcio::Error::RpcFailure(ref status))
+              if status.status == grpcio::RpcStatusCode::Unimplemented =>
+            {
+              Ok(CacheCapabilities::default())
+            }
+            Err(err) => {
+              Err(store.rpc_error("Error from server in response to GetCapabilities", err))
+            }
+          }
+        })
+        .map(move |capabilities| {
+          debug!("Remote store capabilities: {:?}", capabilities);
+          *cached_capabilities.lock() = Some(capabilities);
+          capabilities
+        })
+        .to_boxed()
+    }
+
+    fn digest_of(bytes: &Bytes) -> Digest {
       let mut hasher = Sha256::default();
-      hasher.input(&bytes);
+      hasher.input(bytes);
       let fingerprint = Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice());
+      Digest(fingerprint, bytes.len())
+    }
+
+    pub fn store_bytes(&self, bytes: Bytes) -> BoxFuture<Digest, String> {
       let len = bytes.len();
-      let digest = Digest(fingerprint, len);
+      let digest = Self::digest_of(&bytes);
       let resource_name = format!(
         "{}/uploads/{}/blobs/{}/{}",
         self.instance_name.clone().unwrap_or_default(),
@@ -1795,18 +5698,32 @@ mod remote {
         digest.1,
       );
       let store = self.clone();
+      // Retries of uploads of multi-chunk blobs resume from whatever the server committed so far,
+      // rather than resending the whole blob.
+      let attempted = Arc::new(AtomicBool::new(false));
       self
         .with_byte_stream_client(move |client| {
+          let start_offset =
+            if attempted.swap(true, Ordering::SeqCst) && len > store.chunk_size_bytes {
+              match store.resume_offset(&client, &resource_name, digest) {
+                Some(offset) => offset,
+                None => return future::ok(digest).to_boxed(),
+              }
+            } else {
+              0
+            };
           match client
             .write_opt(store.call_option().timeout(store.upload_timeout))
             .map(|v| (v, client))
           {
-            Err(err) => future::err(format!(
-              "Error attempting to connect to upload digest {:?}: {:?}",
-              digest, err
+            Err(err) => future::err(store.rpc_error(
+              &format!("Error attempting to connect to upload digest {:?}", digest),
+              err,
             ))
             .to_boxed(),
             Ok(((sender, receiver), _client)) => {
+              let store2 = store.clone();
+              let store3 = store.clone();
               let chunk_size_bytes = store.chunk_size_bytes;
               let resource_name = resource_name.clone();
               let bytes = bytes.clone();
@@ -1815,7 +5732,7 @@ mod remote {
                 _,
                 futures::future::FutureResult<_, grpcio::Error>,
                 _,
-              >((0, false), move |(offset, has_sent_any)| {
+              >((start_offset, false), move |(offset, has_sent_any)| {
                 if offset >= bytes.len() && has_sent_any {
                   None
                 } else {
@@ -1824,6 +5741,15 @@ mod remote {
                   req.set_write_offset(offset as i64);
                   let next_offset = min(offset + chunk_size_bytes, bytes.len());
                   req.set_finish_write(next_offset == bytes.len());
+                  if bytes.len() > chunk_size_bytes
+                    && next_offset * 10 / bytes.len() > offset * 10 / bytes.len()
+                  {
+                    debug!(
+                      "Uploaded {}% of digest {:?}",
+                      next_offset * 100 / bytes.len(),
+                      digest
+                    );
+                  }
                   req.set_data(bytes.slice(offset, next_offset));
                   Some(future::ok((
                     (req, grpcio::WriteFlags::default()),
@@ -1849,17 +5775,17 @@ mod remote {
                     // discussion: see
                     // https://groups.google.com/d/topic/remote-execution-apis/NXUe3ItCw68/discussion.
                     grpcio::Error::RpcFinished(None) => Ok(()),
-                    e => Err(format!(
-                      "Error attempting to upload digest {:?}: {:?}",
-                      digest, e
+                    e => Err(store2.rpc_error(
+                      &format!("Error attempting to upload digest {:?}", digest),
+                      e,
                     )),
                   }
                 })
                 .and_then(move |()| {
                   receiver.map_err(move |e| {
-                    format!(
-                      "Error from server when uploading digest {:?}: {:?}",
-                      digest, e
+                    store3.rpc_error(
+                      &format!("Error from server when uploading digest {:?}", digest),
+                      e,
                     )
                   })
                 })
@@ -1867,12 +5793,12 @@ mod remote {
                   if received.get_committed_size() == len as i64 {
                     Ok(digest)
                   } else {
-                    Err(format!(
+                    Err(AttemptError::fatal(format!(
                       "Uploading file with digest {:?}: want commited size {} but got {}",
                       digest,
                       len,
                       received.get_committed_size()
-                    ))
+                    )))
                   }
                 })
                 .to_boxed()
@@ -1882,6 +5808,39 @@ mod remote {
         .to_boxed()
     }
 
+    ///
+    /// The offset from which to resume an interrupted upload to the given resource, or None if the
+    /// server already has the whole blob. Uploads whose status can't be queried start again from
+    /// the beginning.
+    ///
+    fn resume_offset(
+      &self,
+      client: &bazel_protos::bytestream_grpc::ByteStreamClient,
+      resource_name: &str,
+      digest: Digest,
+    ) -> Option<usize> {
+      let mut req = bazel_protos::bytestream::QueryWriteStatusRequest::new();
+      req.set_resource_name(resource_name.to_owned());
+      match client.query_write_status_opt(&req, self.call_option().timeout(self.read_timeout)) {
+        Ok(ref status) if status.get_complete() => None,
+        Ok(status) => {
+          let offset = min(status.get_committed_size().max(0) as usize, digest.1);
+          debug!(
+            "Resuming upload of digest {:?} from offset {}",
+            digest, offset
+          );
+          Some(offset)
+        }
+        Err(err) => {
+          debug!(
+            "Restarting upload of digest {:?}, whose status couldn't be queried: {:?}",
+            digest, err
+          );
+          Some(0)
+        }
+      }
+    }
+
     pub fn load_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + Clone + 'static>(
       &self,
       _entry_type: EntryType,
@@ -1906,12 +5865,13 @@ mod remote {
                 req.set_read_limit(0);
                 req
               },
-              store.call_option(),
+              store.call_option().timeout(store.read_timeout),
             )
             .map(|stream| (stream, client))
           {
             Ok((stream, client)) => {
               let f = f.clone();
+              let store = store.clone();
               // We shouldn't have to pass around the client here, it's a workaround for
               // https://github.com/pingcap/grpc-rs/issues/123
               future::ok(client)
@@ -1922,29 +5882,301 @@ mod remote {
                   }),
                 )
                 .map(|(_client, bytes)| Some(bytes.freeze()))
-                .or_else(|e| match e {
+                .or_else(move |e| match e {
                   grpcio::Error::RpcFailure(grpcio::RpcStatus {
                     status: grpcio::RpcStatusCode::NotFound,
                     ..
                   }) => Ok(None),
-                  _ => Err(format!(
-                    "Error from server in response to CAS read request: {:?}",
-                    e
-                  )),
+                  _ => Err(store.rpc_error("Error from server in response to CAS read request", e)),
                 })
                 .map(move |maybe_bytes| maybe_bytes.map(f))
                 .to_boxed()
             }
-            Err(err) => future::err(format!(
-              "Error making CAS read request for {:?}: {:?}",
-              digest, err
-            ))
-            .to_boxed(),
+            Err(err) => future::err(store.rpc_error(
+              &format!("Error making CAS read request for {:?}", digest),
+              err,
+            ))
+            .to_boxed(),
+          }
+        })
+        .to_boxed()
+    }
+
+    ///
+    /// Splits the given digests into batches of small blobs, each of which totals no more than
+    /// max_batch_bytes, and the remaining blobs, which are too large to be batched.
+    ///
+    pub fn batches<Digests: IntoIterator<Item = Digest>>(
+      digests: Digests,
+      max_batch_bytes: usize,
+    ) -> (Vec<Vec<Digest>>, Vec<Digest>) {
+      let mut batches = vec![];
+      let mut batch = vec![];
+      let mut batch_bytes = 0;
+      let mut unbatched = vec![];
+      for digest in digests {
+        if digest.1 > max_batch_bytes {
+          unbatched.push(digest);
+          continue;
+        }
+        if batch_bytes + digest.1 > max_batch_bytes {
+          batches.push(mem::replace(&mut batch, vec![]));
+          batch_bytes = 0;
+        }
+        batch.push(digest);
+        batch_bytes += digest.1;
+      }
+      if !batch.is_empty() {
+        batches.push(batch);
+      }
+      (batches, unbatched)
+    }
+
+    ///
+    /// Records that the server doesn't implement the batch RPCs, so that blobs are sent and
+    /// received individually from then on.
+    ///
+    fn disable_batch_rpcs(&self) {
+      if !self.batch_rpcs_unimplemented.swap(true, Ordering::SeqCst) {
+        debug!("Remote store doesn't implement batch RPCs: falling back to ByteStream");
+      }
+    }
+
+    ///
+    /// Asks the server to fetch the blob with the given digest from one of the given URIs into its
+    /// CAS, with the Remote Asset Fetch service, returning whether it did. Servers which don't
+    /// implement the Fetch service (or don't support fetching by checksum), or which couldn't fetch
+    /// the blob, leave it to the client to fetch.
+    ///
+    pub fn fetch_blob(&self, uris: Vec<String>, digest: Digest) -> BoxFuture<bool, String> {
+      if self.fetch_unimplemented.load(Ordering::SeqCst) {
+        return future::ok(false).to_boxed();
+      }
+      let mut request = bazel_protos::remote_asset::FetchBlobRequest::new();
+      if let Some(ref instance_name) = self.instance_name {
+        request.set_instance_name(instance_name.clone());
+      }
+      let mut timeout = protobuf::well_known_types::Duration::new();
+      timeout.set_seconds(self.upload_timeout.as_secs() as i64);
+      request.set_timeout(timeout);
+      request.set_uris(protobuf::RepeatedField::from_vec(uris));
+      request.mut_qualifiers().push({
+        let mut qualifier = bazel_protos::remote_asset::Qualifier::new();
+        qualifier.set_name("checksum.sri".to_owned());
+        qualifier.set_value(format!("sha256-{}", digest.0.to_base64()));
+        qualifier
+      });
+      let store = self.clone();
+      let fetch_unimplemented = self.fetch_unimplemented.clone();
+      self
+        .with_fetch_client(move |client| {
+          match client.fetch_blob_opt(&request, store.call_option().timeout(store.upload_timeout)) {
+            Ok(response) => {
+              let code = response.get_status().get_code();
+              if code != bazel_protos::code::Code::OK.value() {
+                debug!(
+                  "Remote asset server couldn't fetch {:?} ({}): {}",
+                  digest,
+                  code,
+                  response.get_status().get_message()
+                );
+                return Ok(false);
+              }
+              let fetched: Result<Digest, String> = response.get_blob_digest().into();
+              match fetched {
+                Ok(fetched) if fetched == digest => Ok(true),
+                fetched => Err(AttemptError::fatal(format!(
+                  "Remote asset server fetched {:?} rather than {:?}",
+                  fetched, digest
+                ))),
+              }
+            }
+            Err(grpcio::Error::RpcFailure(ref status))
+              if status.status == grpcio::RpcStatusCode::Unimplemented
+                || status.status == grpcio::RpcStatusCode::InvalidArgument =>
+            {
+              if !fetch_unimplemented.swap(true, Ordering::SeqCst) {
+                debug!(
+                  "Remote store doesn't support fetching assets by checksum: {:?}",
+                  status
+                );
+              }
+              Ok(false)
+            }
+            Err(err) => Err(store.rpc_error("Error from server in response to FetchBlob", err)),
+          }
+        })
+        .to_boxed()
+    }
+
+    ///
+    /// Uploads the given blobs (which should total no more than the max batch size) with a single
+    /// BatchUpdateBlobs request, or individually if the server doesn't implement it.
+    ///
+    pub fn store_bytes_batch(&self, blobs: Vec<Bytes>) -> BoxFuture<Vec<Digest>, String> {
+      if self.batch_rpcs_unimplemented.load(Ordering::SeqCst) {
+        return self.store_bytes_individually(blobs);
+      }
+      let mut request = bazel_protos::remote_execution::BatchUpdateBlobsRequest::new();
+      if let Some(ref instance_name) = self.instance_name {
+        request.set_instance_name(instance_name.clone());
+      }
+      let mut digests = Vec::with_capacity(blobs.len());
+      for bytes in &blobs {
+        let digest = Self::digest_of(bytes);
+        let mut blob_request = bazel_protos::remote_execution::UpdateBlobRequest::new();
+        blob_request.set_content_digest((&digest).into());
+        blob_request.set_data(bytes.clone());
+        request.mut_requests().push(blob_request);
+        digests.push(digest);
+      }
+      let store = self.clone();
+      let store2 = self.clone();
+      self
+        .with_cas_client(move |client| {
+          match client
+            .batch_update_blobs_opt(&request, store.call_option().timeout(store.upload_timeout))
+          {
+            Ok(response) => Ok(Some(response)),
+            Err(grpcio::Error::RpcFailure(ref status))
+              if status.status == grpcio::RpcStatusCode::Unimplemented =>
+            {
+              Ok(None)
+            }
+            Err(err) => {
+              Err(store.rpc_error("Error from server in response to BatchUpdateBlobs", err))
+            }
+          }
+        })
+        .and_then(move |maybe_response| match maybe_response {
+          Some(response) => {
+            let failures = response
+              .get_responses()
+              .iter()
+              .filter(|response| {
+                response.get_status().get_code() != bazel_protos::code::Code::OK.value()
+              })
+              .map(|response| {
+                format!(
+                  "{}: {}",
+                  response.get_blob_digest().get_hash(),
+                  response.get_status().get_message()
+                )
+              })
+              .collect::<Vec<_>>();
+            if failures.is_empty() {
+              future::ok(digests).to_boxed()
+            } else {
+              future::err(format!(
+                "Error from server uploading blobs with BatchUpdateBlobs: {}",
+                failures.join(", ")
+              ))
+              .to_boxed()
+            }
+          }
+          None => {
+            store2.disable_batch_rpcs();
+            store2.store_bytes_individually(blobs)
+          }
+        })
+        .to_boxed()
+    }
+
+    fn store_bytes_individually(&self, blobs: Vec<Bytes>) -> BoxFuture<Vec<Digest>, String> {
+      future::join_all(
+        blobs
+          .into_iter()
+          .map(|bytes| self.store_bytes(bytes))
+          .collect::<Vec<_>>(),
+      )
+      .to_boxed()
+    }
+
+    ///
+    /// Fetches the given blobs (which should total no more than the max batch size) with a single
+    /// BatchReadBlobs request, or individually if the server doesn't implement it. Blobs which the
+    /// server doesn't have are omitted.
+    ///
+    pub fn load_bytes_batch(
+      &self,
+      digests: Vec<Digest>,
+    ) -> BoxFuture<HashMap<Digest, Bytes>, String> {
+      if self.batch_rpcs_unimplemented.load(Ordering::SeqCst) {
+        return self.load_bytes_individually(digests);
+      }
+      let mut request = bazel_protos::remote_execution::BatchReadBlobsRequest::new();
+      if let Some(ref instance_name) = self.instance_name {
+        request.set_instance_name(instance_name.clone());
+      }
+      for digest in &digests {
+        request.mut_digests().push(digest.into());
+      }
+      let store = self.clone();
+      let store2 = self.clone();
+      self
+        .with_cas_client(move |client| {
+          match client
+            .batch_read_blobs_opt(&request, store.call_option().timeout(store.read_timeout))
+          {
+            Ok(response) => Ok(Some(response)),
+            Err(grpcio::Error::RpcFailure(ref status))
+              if status.status == grpcio::RpcStatusCode::Unimplemented =>
+            {
+              Ok(None)
+            }
+            Err(err) => {
+              Err(store.rpc_error("Error from server in response to BatchReadBlobs", err))
+            }
+          }
+        })
+        .and_then(move |maybe_response| match maybe_response {
+          Some(response) => {
+            let mut blobs = HashMap::new();
+            for response in response.get_responses() {
+              let digest: Digest = try_future!(response.get_digest().into());
+              match bazel_protos::code::Code::from_i32(response.get_status().get_code()) {
+                Some(bazel_protos::code::Code::OK) => {
+                  blobs.insert(digest, response.get_data().clone());
+                }
+                Some(bazel_protos::code::Code::NOT_FOUND) => {}
+                _ => {
+                  return future::err(format!(
+                    "Error from server reading {:?} with BatchReadBlobs: {}",
+                    digest,
+                    response.get_status().get_message()
+                  ))
+                  .to_boxed();
+                }
+              }
+            }
+            future::ok(blobs).to_boxed()
+          }
+          None => {
+            store2.disable_batch_rpcs();
+            store2.load_bytes_individually(digests)
           }
         })
         .to_boxed()
     }
 
+    fn load_bytes_individually(
+      &self,
+      digests: Vec<Digest>,
+    ) -> BoxFuture<HashMap<Digest, Bytes>, String> {
+      future::join_all(
+        digests
+          .into_iter()
+          .map(|digest| {
+            self
+              .load_bytes_with(EntryType::File, digest, |bytes| bytes)
+              .map(move |maybe_bytes| maybe_bytes.map(|bytes| (digest, bytes)))
+          })
+          .collect::<Vec<_>>(),
+      )
+      .map(|blobs| blobs.into_iter().flatten().collect())
+      .to_boxed()
+    }
+
     ///
     /// Given a collection of Digests (digests),
     /// returns the set of digests from that collection not present in the CAS.
@@ -1956,11 +6188,11 @@ mod remote {
       let store = self.clone();
       self.with_cas_client(move |client| {
         client
-          .find_missing_blobs_opt(&request, store.call_option())
+          .find_missing_blobs_opt(&request, store.call_option().timeout(store.read_timeout))
           .map_err(|err| {
-            format!(
-              "Error from server in response to find_missing_blobs_request: {:?}",
-              err
+            store.rpc_error(
+              "Error from server in response to find_missing_blobs_request",
+              err,
             )
           })
           .and_then(|response| {
@@ -1968,8 +6200,89 @@ mod remote {
               .get_missing_blob_digests()
               .iter()
               .map(|digest| digest.into())
-              .collect()
+              .collect::<Result<_, String>>()
+              .map_err(AttemptError::fatal)
+          })
+      })
+    }
+
+    ///
+    /// Fetches the result of the Action with the given digest from the ActionCache, returning None
+    /// if the ActionCache doesn't have one.
+    ///
+    pub fn get_action_result(
+      &self,
+      action_digest: Digest,
+    ) -> impl Future<Item = Option<bazel_protos::remote_execution::ActionResult>, Error = String>
+    {
+      let mut request = bazel_protos::remote_execution::GetActionResultRequest::new();
+      if let Some(ref instance_name) = self.instance_name {
+        request.set_instance_name(instance_name.clone());
+      }
+      request.set_action_digest((&action_digest).into());
+      let store = self.clone();
+      self.with_action_cache_client(move |client| {
+        match client.get_action_result_opt(
+          &request,
+          store.call_option().timeout(store.action_cache_timeout),
+        ) {
+          Ok(action_result) => Ok(Some(action_result)),
+          Err(grpcio::Error::RpcFailure(ref status))
+            if status.status == grpcio::RpcStatusCode::NotFound =>
+          {
+            Ok(None)
+          }
+          Err(err) => Err(store.rpc_error(
+            &format!(
+              "Error from server in response to GetActionResult for {:?}",
+              action_digest
+            ),
+            err,
+          )),
+        }
+      })
+    }
+
+    pub fn update_action_result(
+      &self,
+      action_digest: Digest,
+      action_result: bazel_protos::remote_execution::ActionResult,
+    ) -> impl Future<Item = (), Error = String> {
+      let mut request = bazel_protos::remote_execution::UpdateActionResultRequest::new();
+      if let Some(ref instance_name) = self.instance_name {
+        request.set_instance_name(instance_name.clone());
+      }
+      request.set_action_digest((&action_digest).into());
+      request.set_action_result(action_result);
+      let store = self.clone();
+      self.capabilities().and_then(move |capabilities| {
+        if !capabilities.action_cache_update_enabled {
+          debug!(
+            "Not recording the result of {:?}: the remote action cache doesn't allow updates",
+            action_digest
+          );
+          return future::ok(()).to_boxed();
+        }
+        let store2 = store.clone();
+        store
+          .with_action_cache_client(move |client| {
+            client
+              .update_action_result_opt(
+                &request,
+                store2.call_option().timeout(store2.action_cache_timeout),
+              )
+              .map(|_| ())
+              .map_err(|err| {
+                store2.rpc_error(
+                  &format!(
+                    "Error from server in response to UpdateActionResult for {:?}",
+                    action_digest
+                  ),
+                  err,
+                )
+              })
           })
+          .to_boxed()
       })
     }
 
@@ -1986,17 +6299,53 @@ mod remote {
       }
       request
     }
+
+    ///
+    /// Splits the given digests into FindMissingBlobsRequests which are each (approximately) no
+    /// larger than max_request_bytes, so that no request is rejected for its size.
+    ///
+    pub(super) fn find_missing_blobs_requests<'a, Digests: Iterator<Item = &'a Digest>>(
+      &self,
+      digests: Digests,
+      max_request_bytes: usize,
+    ) -> Vec<bazel_protos::remote_execution::FindMissingBlobsRequest> {
+      let mut requests = vec![];
+      let mut batch = vec![];
+      let mut batch_bytes = 0;
+      for digest in digests {
+        let digest_bytes = Self::encoded_digest_bytes(digest);
+        if !batch.is_empty() && batch_bytes + digest_bytes > max_request_bytes {
+          requests.push(self.find_missing_blobs_request(batch.drain(..)));
+          batch_bytes = 0;
+        }
+        batch.push(digest);
+        batch_bytes += digest_bytes;
+      }
+      if !batch.is_empty() {
+        requests.push(self.find_missing_blobs_request(batch.into_iter()));
+      }
+      requests
+    }
+
+    ///
+    /// The size of the given digest as an entry of a repeated field, including its tag and length.
+    ///
+    fn encoded_digest_bytes(digest: &Digest) -> usize {
+      let digest: bazel_protos::remote_execution::Digest = digest.into();
+      let size = digest.compute_size();
+      1 + protobuf::rt::compute_raw_varint32_size(size) as usize + size as usize
+    }
   }
 
   #[cfg(test)]
   mod tests {
-    use super::super::EntryType;
-    use super::ByteStore;
+    use super::super::{ChannelPolicy, Compression, EntryType};
+    use super::{ByteStore, CacheCapabilities};
     use bytes::Bytes;
     use futures_timer::TimerHandle;
     use hashing::Digest;
     use mock::StubCAS;
-    use serverset::BackoffConfig;
+    use serverset::{BackoffConfig, LoadBalancing};
     use std::collections::HashSet;
     use std::time::Duration;
     use testutil::data::{TestData, TestDirectory};
@@ -2144,11 +6493,19 @@ mod remote {
         &[cas.address()],
         None,
         &None,
+        &None,
+        &None,
+        ChannelPolicy::default(),
+        None,
         None,
+        Compression::None,
         1,
         10 * 1024,
         Duration::from_secs(5),
+        Duration::from_secs(5),
+        Duration::from_secs(5),
         BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+        LoadBalancing::RoundRobin,
         1,
         TimerHandle::default(),
       )
@@ -2180,6 +6537,52 @@ mod remote {
       }
     }
 
+    #[test]
+    fn write_file_resumes_after_interruption() {
+      let cas = StubCAS::builder()
+        .interrupt_write_after_bytes(500 * 1024)
+        .build();
+
+      let store = ByteStore::new(
+        &[cas.address()],
+        None,
+        &None,
+        &None,
+        &None,
+        ChannelPolicy::default(),
+        None,
+        None,
+        Compression::None,
+        1,
+        10 * 1024,
+        Duration::from_secs(5),
+        Duration::from_secs(5),
+        Duration::from_secs(5),
+        BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+        LoadBalancing::RoundRobin,
+        1,
+        TimerHandle::default(),
+      )
+      .unwrap();
+
+      assert_eq!(
+        block_on(store.store_bytes(big_file_bytes())),
+        Ok(big_file_digest())
+      );
+      assert_eq!(
+        cas.blobs.lock().get(&big_file_fingerprint()),
+        Some(&big_file_bytes())
+      );
+
+      // The retry only sent the chunks which the interrupted upload hadn't.
+      let write_message_sizes = cas.write_message_sizes.lock();
+      assert_eq!(write_message_sizes.len(), 98);
+      assert_eq!(
+        write_message_sizes.iter().sum::<usize>(),
+        big_file_bytes().len()
+      );
+    }
+
     #[test]
     fn write_empty_file() {
       let empty_file = TestData::empty();
@@ -2220,11 +6623,19 @@ mod remote {
         &[String::from("doesnotexist.example")],
         None,
         &None,
+        &None,
+        &None,
+        ChannelPolicy::default(),
         None,
+        None,
+        Compression::None,
         1,
         10 * 1024 * 1024,
         Duration::from_secs(1),
+        Duration::from_secs(1),
+        Duration::from_secs(1),
         BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+        LoadBalancing::RoundRobin,
         1,
         TimerHandle::default(),
       )
@@ -2282,6 +6693,30 @@ mod remote {
       );
     }
 
+    #[test]
+    fn find_missing_blobs_requests_are_size_limited() {
+      let cas = StubCAS::empty();
+      let store = new_byte_store(&cas);
+      let digests = vec![
+        TestData::roland().digest(),
+        TestData::catnip().digest(),
+        TestData::robin().digest(),
+      ];
+      let digest_bytes = ByteStore::encoded_digest_bytes(&digests[0]);
+
+      let blob_counts = |max_request_bytes| {
+        store
+          .find_missing_blobs_requests(digests.iter(), max_request_bytes)
+          .iter()
+          .map(|request| request.get_blob_digests().len())
+          .collect::<Vec<_>>()
+      };
+      assert_eq!(blob_counts(1024 * 1024), vec![3]);
+      assert_eq!(blob_counts(2 * digest_bytes), vec![2, 1]);
+      // Digests larger than the limit are still sent, one per request.
+      assert_eq!(blob_counts(1), vec![1, 1, 1]);
+    }
+
     #[test]
     fn reads_from_multiple_cas_servers() {
       let roland = TestData::roland();
@@ -2294,40 +6729,356 @@ mod remote {
         &[cas1.address(), cas2.address()],
         None,
         &None,
+        &None,
+        &None,
+        ChannelPolicy::default(),
+        None,
+        None,
+        Compression::None,
+        1,
+        10 * 1024 * 1024,
+        Duration::from_secs(1),
+        Duration::from_secs(1),
+        Duration::from_secs(1),
+        BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+        LoadBalancing::RoundRobin,
+        1,
+        TimerHandle::default(),
+      )
+      .unwrap();
+
+      assert_eq!(
+        load_file_bytes(&store, roland.digest()),
+        Ok(Some(roland.bytes()))
+      );
+
+      assert_eq!(
+        load_file_bytes(&store, catnip.digest()),
+        Ok(Some(catnip.bytes()))
+      );
+
+      assert_eq!(cas1.read_request_count(), 1);
+      assert_eq!(cas2.read_request_count(), 1);
+    }
+
+    #[test]
+    fn picks_first_cas_server() {
+      let roland = TestData::roland();
+      let catnip = TestData::catnip();
+
+      let cas1 = StubCAS::builder().file(&roland).file(&catnip).build();
+      let cas2 = StubCAS::builder().file(&roland).file(&catnip).build();
+      let store = new_pick_first_byte_store(&[cas1.address(), cas2.address()]);
+
+      assert_eq!(
+        load_file_bytes(&store, roland.digest()),
+        Ok(Some(roland.bytes()))
+      );
+      assert_eq!(
+        load_file_bytes(&store, catnip.digest()),
+        Ok(Some(catnip.bytes()))
+      );
+
+      assert_eq!(cas1.read_request_count(), 2);
+      assert_eq!(cas2.read_request_count(), 0);
+    }
+
+    #[test]
+    fn fails_over_from_unavailable_cas_server() {
+      let roland = TestData::roland();
+
+      let unavailable_address = StubCAS::empty().address();
+      let cas = StubCAS::builder().file(&roland).build();
+      let store = new_pick_first_byte_store(&[unavailable_address, cas.address()]);
+
+      assert_eq!(
+        load_file_bytes(&store, roland.digest()),
+        Ok(Some(roland.bytes()))
+      );
+      assert_eq!(cas.read_request_count(), 1);
+    }
+
+    fn new_pick_first_byte_store(cas_addresses: &[String]) -> ByteStore {
+      ByteStore::new(
+        cas_addresses,
+        None,
+        &None,
+        &None,
+        &None,
+        ChannelPolicy::default(),
+        None,
         None,
+        Compression::None,
         1,
         10 * 1024 * 1024,
         Duration::from_secs(1),
+        Duration::from_secs(1),
+        Duration::from_secs(1),
         BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+        LoadBalancing::PickFirst,
         1,
         TimerHandle::default(),
       )
+      .unwrap()
+    }
+
+    #[test]
+    fn round_trips_compressed() {
+      for &compression in &[Compression::Deflate, Compression::Gzip] {
+        let cas = StubCAS::empty();
+        let store = new_compressed_byte_store(&cas, compression);
+
+        assert_eq!(
+          block_on(store.store_bytes(big_file_bytes())),
+          Ok(big_file_digest())
+        );
+        assert_eq!(
+          cas.blobs.lock().get(&big_file_fingerprint()),
+          Some(&big_file_bytes())
+        );
+        assert_eq!(
+          load_file_bytes(&store, big_file_digest()),
+          Ok(Some(big_file_bytes()))
+        );
+      }
+    }
+
+    #[test]
+    fn parse_compression() {
+      assert_eq!("gzip".parse(), Ok(Compression::Gzip));
+      assert_eq!("none".parse(), Ok(Compression::None));
+      "zstd"
+        .parse::<Compression>()
+        .expect_err("Want Err for unsupported compression");
+    }
+
+    #[test]
+    fn batches_are_size_limited() {
+      let digests = vec![
+        TestData::roland().digest(),
+        TestData::catnip().digest(),
+        big_file_digest(),
+        TestData::robin().digest(),
+      ];
+      let small_bytes = digests[0].1 + digests[1].1;
+      assert_eq!(
+        ByteStore::batches(digests.clone(), small_bytes),
+        (
+          vec![vec![digests[0], digests[1]], vec![digests[3]]],
+          vec![digests[2]]
+        )
+      );
+      assert_eq!(
+        ByteStore::batches(
+          digests.clone(),
+          big_file_digest().1 + small_bytes + digests[3].1
+        ),
+        (vec![digests.clone()], vec![])
+      );
+    }
+
+    #[test]
+    fn stores_and_loads_bytes_in_batches() {
+      let cas = StubCAS::builder().batch_rpcs().build();
+      let store = new_byte_store(&cas);
+      let roland = TestData::roland();
+      let catnip = TestData::catnip();
+
+      assert_eq!(
+        block_on(store.store_bytes_batch(vec![roland.bytes(), catnip.bytes()])),
+        Ok(vec![roland.digest(), catnip.digest()])
+      );
+      assert_eq!(
+        cas.blobs.lock().get(&catnip.fingerprint()),
+        Some(&catnip.bytes())
+      );
+
+      let blobs = block_on(store.load_bytes_batch(vec![
+        roland.digest(),
+        catnip.digest(),
+        TestData::robin().digest(),
+      ]))
       .unwrap();
+      assert_eq!(blobs.len(), 2);
+      assert_eq!(blobs.get(&roland.digest()), Some(&roland.bytes()));
+      assert_eq!(cas.batch_request_count(), 2);
+      assert_eq!(cas.write_message_sizes.lock().len(), 0);
+    }
+
+    #[test]
+    fn fetches_blob_from_uri() {
+      let roland = TestData::roland();
+      let url = "https://example.com/roland";
+      let cas = StubCAS::builder().asset(url, &roland).build();
+      let store = new_byte_store(&cas);
+
+      assert_eq!(
+        block_on(store.fetch_blob(
+          vec![
+            "https://mirror.example.com/roland".to_owned(),
+            url.to_owned()
+          ],
+          roland.digest()
+        )),
+        Ok(true)
+      );
+      assert_eq!(
+        cas.blobs.lock().get(&roland.fingerprint()),
+        Some(&roland.bytes())
+      );
+
+      // Servers only fetch content which matches the checksum.
+      assert_eq!(
+        block_on(store.fetch_blob(vec![url.to_owned()], TestData::catnip().digest())),
+        Ok(false)
+      );
+      assert_eq!(
+        block_on(store.fetch_blob(
+          vec!["https://example.com/missing".to_owned()],
+          roland.digest()
+        )),
+        Ok(false)
+      );
+    }
+
+    #[test]
+    fn fetch_blob_is_skipped_if_unimplemented() {
+      let cas = StubCAS::empty();
+      let store = new_byte_store(&cas);
+      let roland = TestData::roland();
+
+      assert_eq!(
+        block_on(store.fetch_blob(
+          vec!["https://example.com/roland".to_owned()],
+          roland.digest()
+        )),
+        Ok(false)
+      );
+      assert!(cas.blobs.lock().is_empty());
+    }
+
+    #[test]
+    fn batches_fall_back_to_byte_stream() {
+      let cas = StubCAS::empty();
+      let store = new_byte_store(&cas);
+      let roland = TestData::roland();
+      let catnip = TestData::catnip();
+
+      assert_eq!(
+        block_on(store.store_bytes_batch(vec![roland.bytes(), catnip.bytes()])),
+        Ok(vec![roland.digest(), catnip.digest()])
+      );
+      assert_eq!(cas.write_message_sizes.lock().len(), 2);
+      let blobs = block_on(store.load_bytes_batch(vec![roland.digest()])).unwrap();
+      assert_eq!(blobs.get(&roland.digest()), Some(&roland.bytes()));
+      assert_eq!(cas.batch_request_count(), 0);
+    }
+
+    fn server_capabilities(
+      digest_function: bazel_protos::remote_execution::DigestFunction,
+      max_batch_total_size_bytes: i64,
+      update_enabled: bool,
+    ) -> bazel_protos::remote_execution::ServerCapabilities {
+      let mut capabilities = bazel_protos::remote_execution::ServerCapabilities::new();
+      {
+        let cache_capabilities = capabilities.mut_cache_capabilities();
+        cache_capabilities
+          .mut_digest_function()
+          .push(digest_function);
+        cache_capabilities.set_max_batch_total_size_bytes(max_batch_total_size_bytes);
+        cache_capabilities
+          .mut_action_cache_update_capabilities()
+          .set_update_enabled(update_enabled);
+      }
+      capabilities
+    }
+
+    #[test]
+    fn capabilities_are_queried_from_server() {
+      let cas = StubCAS::builder()
+        .capabilities(server_capabilities(
+          bazel_protos::remote_execution::DigestFunction::SHA256,
+          4096,
+          false,
+        ))
+        .build();
+      let store = new_byte_store(&cas);
+      assert_eq!(
+        block_on(store.capabilities()),
+        Ok(CacheCapabilities {
+          max_batch_total_size_bytes: Some(4096),
+          action_cache_update_enabled: false,
+        })
+      );
+    }
 
+    #[test]
+    fn capabilities_default_if_unimplemented() {
+      let cas = StubCAS::empty();
+      let store = new_byte_store(&cas);
       assert_eq!(
-        load_file_bytes(&store, roland.digest()),
-        Ok(Some(roland.bytes()))
+        block_on(store.capabilities()),
+        Ok(CacheCapabilities::default())
       );
+    }
+
+    #[test]
+    fn unsupported_digest_function_is_rejected() {
+      let cas = StubCAS::builder()
+        .capabilities(server_capabilities(
+          bazel_protos::remote_execution::DigestFunction::MD5,
+          0,
+          true,
+        ))
+        .build();
+      let store = new_byte_store(&cas);
+      let error = block_on(store.capabilities()).expect_err("Want Err for MD5 digests");
+      assert!(error.contains("SHA256"), "Bad error message: {}", error);
+    }
 
+    #[test]
+    fn action_cache_updates_are_skipped_if_disabled() {
+      let cas = StubCAS::builder()
+        .capabilities(server_capabilities(
+          bazel_protos::remote_execution::DigestFunction::SHA256,
+          0,
+          false,
+        ))
+        .build();
+      let store = new_byte_store(&cas);
+      let action_digest = TestData::roland().digest();
       assert_eq!(
-        load_file_bytes(&store, catnip.digest()),
-        Ok(Some(catnip.bytes()))
+        block_on(store.update_action_result(
+          action_digest,
+          bazel_protos::remote_execution::ActionResult::new()
+        )),
+        Ok(())
       );
-
-      assert_eq!(cas1.read_request_count(), 1);
-      assert_eq!(cas2.read_request_count(), 1);
+      assert!(cas.action_results.lock().is_empty());
     }
 
     fn new_byte_store(cas: &StubCAS) -> ByteStore {
+      new_compressed_byte_store(cas, Compression::None)
+    }
+
+    fn new_compressed_byte_store(cas: &StubCAS, compression: Compression) -> ByteStore {
       ByteStore::new(
         &[cas.address()],
         None,
         &None,
+        &None,
+        &None,
+        ChannelPolicy::default(),
+        None,
         None,
+        compression,
         1,
         10 * 1024 * 1024,
         Duration::from_secs(1),
+        Duration::from_secs(1),
+        Duration::from_secs(1),
         BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+        LoadBalancing::RoundRobin,
         1,
         TimerHandle::default(),
       )
@@ -2357,27 +7108,35 @@ mod remote {
 
 #[cfg(test)]
 mod tests {
-  use super::{local, EntryType, FileContent, Store, UploadSummary};
+  use super::{
+    archive, local, BrokenEntry, Compression, EntryProblem, EntryType, FileContent,
+    MaterializationStrategy, ShrinkBehavior, Store, TierRules, TieringPolicy, UploadSummary,
+    WalkEntry,
+  };
+  use crate::{BearerToken, ChannelPolicy, KnownRemoteBlobs, Proxy, SmallBlobCache, TokenSource};
 
   use bazel_protos;
   use bytes::Bytes;
   use digest::{Digest as DigestTrait, FixedOutput};
-  use futures::Future;
+  use futures::{Future, Stream};
   use futures_timer::TimerHandle;
   use hashing::{Digest, Fingerprint};
-  use mock::StubCAS;
+  use mock::{
+    StubCAS, StubConnectProxy, StubGcs, StubHttpCache, StubMemoryCache, StubS3,
+    STUB_GCS_ACCESS_TOKEN,
+  };
   use protobuf::Message;
-  use serverset::BackoffConfig;
+  use serverset::{BackoffConfig, LoadBalancing};
   use sha2::Sha256;
   use std;
-  use std::collections::HashMap;
+  use std::collections::{BTreeSet, HashMap};
   use std::fs::File;
   use std::io::Read;
   use std::os::unix::fs::PermissionsExt;
   use std::path::{Path, PathBuf};
   use std::time::Duration;
   use tempfile::TempDir;
-  use testutil::data::{TestData, TestDirectory};
+  use testutil::data::{TestData, TestDirectory, TestTree};
 
   pub fn big_file_fingerprint() -> Fingerprint {
     Fingerprint::from_hex_string("8dfba0adc29389c63062a68d76b2309b9a2486f1ab610c4720beabbdc273301f")
@@ -2447,11 +7206,19 @@ mod tests {
       &[cas_address],
       None,
       &None,
+      &None,
+      &None,
+      ChannelPolicy::default(),
       None,
+      None,
+      Compression::None,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
       BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      LoadBalancing::RoundRobin,
       1,
       TimerHandle::default(),
     )
@@ -2479,6 +7246,43 @@ mod tests {
     assert_eq!(0, cas.read_request_count());
   }
 
+  #[test]
+  fn load_file_through_proxy() {
+    let dir = TempDir::new().unwrap();
+    let testdata = TestData::roland();
+    let cas = new_cas(1024);
+    let proxy = StubConnectProxy::new();
+
+    let store = Store::with_remote(
+      dir.path(),
+      &[cas.address()],
+      None,
+      &None,
+      &None,
+      &Some(Proxy::parse(&proxy.address().to_string()).unwrap()),
+      ChannelPolicy::default(),
+      None,
+      None,
+      Compression::None,
+      1,
+      10 * 1024 * 1024,
+      Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
+      BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      LoadBalancing::RoundRobin,
+      1,
+      TimerHandle::default(),
+    )
+    .unwrap();
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()),
+      Ok(Some(testdata.bytes()))
+    );
+    assert_eq!(1, cas.read_request_count());
+    assert_eq!(proxy.tunnels(), vec![cas.address()]);
+  }
+
   #[test]
   fn load_directory_prefers_local() {
     let dir = TempDir::new().unwrap();
@@ -2506,65 +7310,360 @@ mod tests {
 
     let testdata = TestData::roland();
 
-    let cas = new_cas(1024);
+    let cas = new_cas(1024);
+    assert_eq!(
+      load_file_bytes(&new_store(dir.path(), cas.address()), testdata.digest()),
+      Ok(Some(testdata.bytes())),
+      "Read from CAS"
+    );
+    assert_eq!(1, cas.read_request_count());
+    assert_eq!(
+      local::tests::load_file_bytes(&local::tests::new_store(dir.path()), testdata.digest()),
+      Ok(Some(testdata.bytes())),
+      "Read from local cache"
+    );
+  }
+
+  #[test]
+  fn load_file_without_read_through_does_not_backfill() {
+    let dir = TempDir::new().unwrap();
+
+    let testdata = TestData::roland();
+
+    let cas = new_cas(1024);
+    let store = new_store(dir.path(), cas.address()).with_tiering_policy(TieringPolicy {
+      files: TierRules {
+        read_through: false,
+        ..TierRules::default()
+      },
+      ..TieringPolicy::default()
+    });
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()),
+      Ok(Some(testdata.bytes()))
+    );
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()),
+      Ok(Some(testdata.bytes()))
+    );
+    assert_eq!(2, cas.read_request_count());
+    assert_eq!(
+      local::tests::load_file_bytes(&local::tests::new_store(dir.path()), testdata.digest()),
+      Ok(None)
+    );
+  }
+
+  #[test]
+  fn load_file_refetches_corrupted_local_file_when_verifying() {
+    let dir = TempDir::new().unwrap();
+
+    let testdata = TestData::roland();
+    let local = local::tests::new_store(dir.path());
+    block_on(local.store_bytes(EntryType::File, testdata.bytes(), false)).expect("Store failed");
+    local::tests::corrupt_file(&local, testdata.digest());
+
+    let cas = new_cas(1024);
+    let store = new_store(dir.path(), cas.address()).with_local_digest_verification(true);
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()),
+      Ok(Some(testdata.bytes()))
+    );
+    assert_eq!(1, cas.read_request_count());
+    assert_eq!(
+      local::tests::load_file_bytes(&local, testdata.digest()),
+      Ok(Some(testdata.bytes())),
+      "Repaired local cache"
+    );
+  }
+
+  #[test]
+  fn stats_count_entries_and_reads() {
+    let dir = TempDir::new().unwrap();
+    let cas = new_cas(1024);
+    let store = new_store(dir.path(), cas.address());
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    block_on(store.store_file_bytes(catnip.bytes(), false)).expect("Store failed");
+
+    // catnip is only local, roland is only remote at first, and robin is neither.
+    load_file_bytes(&store, catnip.digest()).unwrap();
+    load_file_bytes(&store, roland.digest()).unwrap();
+    load_file_bytes(&store, roland.digest()).unwrap();
+    load_file_bytes(&store, TestData::robin().digest()).unwrap();
+
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.files.len(), 16);
+    assert_eq!(
+      stats
+        .files
+        .iter()
+        .map(|shard| shard.entry_count)
+        .sum::<usize>(),
+      2
+    );
+    assert_eq!(
+      stats
+        .files
+        .iter()
+        .map(|shard| shard.stored_bytes)
+        .sum::<usize>(),
+      roland.len() + catnip.len()
+    );
+    assert_eq!(
+      stats
+        .directories
+        .iter()
+        .map(|shard| shard.entry_count)
+        .sum::<usize>(),
+      0
+    );
+    assert_eq!(
+      (
+        stats.local_hits,
+        stats.local_misses,
+        stats.remote_hits,
+        stats.remote_misses
+      ),
+      (2, 2, 1, 1)
+    );
+    assert_eq!(store.clone().read_metrics()[0], ("local_store_hits", 2));
+  }
+
+  #[test]
+  fn small_blob_cache_is_read_before_remote_and_filled_from_it() {
+    let cache = StubMemoryCache::redis();
+    let cas = new_cas(1024);
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    let dir = TempDir::new().unwrap();
+    let store = new_store(dir.path(), cas.address())
+      .with_small_blob_cache(Some(SmallBlobCache::new(&cache.url("")).unwrap()));
+    assert_eq!(
+      load_file_bytes(&store, roland.digest()),
+      Ok(Some(roland.bytes()))
+    );
+    assert_eq!(
+      block_on(store.load_directory(testdir.digest())),
+      Ok(Some(testdir.directory()))
+    );
+    assert_eq!(cas.read_request_count(), 2);
+    assert_eq!(cache.entries.lock().len(), 2);
+
+    // Another store (with nothing local) reads both from the cache, rather than the CAS.
+    let other_dir = TempDir::new().unwrap();
+    let other_store = new_store(other_dir.path(), cas.address())
+      .with_small_blob_cache(Some(SmallBlobCache::new(&cache.url("")).unwrap()));
+    assert_eq!(
+      load_file_bytes(&other_store, roland.digest()),
+      Ok(Some(roland.bytes()))
+    );
+    assert_eq!(
+      block_on(other_store.load_directory(testdir.digest())),
+      Ok(Some(testdir.directory()))
+    );
+    assert_eq!(cas.read_request_count(), 2);
+    let stats = other_store.stats().unwrap();
+    assert_eq!(
+      (
+        stats.small_blob_cache_hits,
+        stats.small_blob_cache_misses,
+        stats.remote_hits
+      ),
+      (2, 0, 0)
+    );
+    // And backfills its local storage from it.
+    assert_eq!(
+      load_file_bytes(&new_local_store(other_dir.path()), roland.digest()),
+      Ok(Some(roland.bytes()))
+    );
+  }
+
+  #[test]
+  fn small_blob_cache_skips_large_blobs() {
+    let cache = StubMemoryCache::memcached();
+    let cas = new_cas(1024);
+    let roland = TestData::roland();
+
+    let dir = TempDir::new().unwrap();
+    let store = new_store(dir.path(), cas.address()).with_small_blob_cache(Some(
+      SmallBlobCache::new(&cache.url(&format!("max_blob_size={}", roland.len() - 1))).unwrap(),
+    ));
+    assert_eq!(
+      load_file_bytes(&store, roland.digest()),
+      Ok(Some(roland.bytes()))
+    );
+    assert_eq!(cas.read_request_count(), 1);
+    assert_eq!(cache.entries.lock().len(), 0);
+    assert_eq!(cache.connection_count(), 0);
+  }
+
+  #[test]
+  fn small_blob_cache_failures_fall_back_to_remote() {
+    let cas = new_cas(1024);
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+
+    // An entry which doesn't match its digest.
+    let cache = StubMemoryCache::redis();
+    cache.entries.lock().insert(
+      format!("pants:cas:{}-{}", roland.fingerprint(), roland.len()),
+      catnip.bytes(),
+    );
+    let dir = TempDir::new().unwrap();
+    let store = new_store(dir.path(), cas.address())
+      .with_small_blob_cache(Some(SmallBlobCache::new(&cache.url("")).unwrap()));
+    assert_eq!(
+      load_file_bytes(&store, roland.digest()),
+      Ok(Some(roland.bytes()))
+    );
+    assert_eq!(cas.read_request_count(), 1);
+
+    // An unreachable cache.
+    let cache_url = cache.url("timeout=50");
+    drop(cache);
+    let other_dir = TempDir::new().unwrap();
+    let other_store = new_store(other_dir.path(), cas.address())
+      .with_small_blob_cache(Some(SmallBlobCache::new(&cache_url).unwrap()));
+    assert_eq!(
+      load_file_bytes(&other_store, roland.digest()),
+      Ok(Some(roland.bytes()))
+    );
+    assert_eq!(cas.read_request_count(), 2);
+    assert_eq!(other_store.stats().unwrap().small_blob_cache_misses, 1);
+  }
+
+  #[test]
+  fn load_directory_falls_back_and_backfills() {
+    let dir = TempDir::new().unwrap();
+
+    let cas = new_cas(1024);
+
+    let testdir = TestDirectory::containing_roland();
+
+    assert_eq!(
+      block_on(new_store(dir.path(), cas.address()).load_directory(testdir.digest())),
+      Ok(Some(testdir.directory()))
+    );
+    assert_eq!(1, cas.read_request_count());
+    assert_eq!(
+      local::tests::load_directory_proto_bytes(
+        &local::tests::new_store(dir.path()),
+        testdir.digest(),
+      ),
+      Ok(Some(testdir.bytes()))
+    );
+  }
+
+  #[test]
+  fn load_recursive_directory() {
+    let dir = TempDir::new().unwrap();
+
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::containing_roland();
+    let testdir_digest = testdir.digest();
+    let testdir_directory = testdir.directory();
+    let recursive_testdir = TestDirectory::recursive();
+    let recursive_testdir_directory = recursive_testdir.directory();
+    let recursive_testdir_digest = recursive_testdir.digest();
+
+    let cas = StubCAS::builder()
+      .file(&roland)
+      .file(&catnip)
+      .directory(&testdir)
+      .directory(&recursive_testdir)
+      .build();
+
+    block_on(
+      new_store(dir.path(), cas.address())
+        .ensure_local_has_recursive_directory(recursive_testdir_digest),
+    )
+    .expect("Downloading recursive directory should have succeeded.");
+
+    assert_eq!(
+      load_file_bytes(&new_local_store(dir.path()), roland.digest()),
+      Ok(Some(roland.bytes()))
+    );
+    assert_eq!(
+      load_file_bytes(&new_local_store(dir.path()), catnip.digest()),
+      Ok(Some(catnip.bytes()))
+    );
+    assert_eq!(
+      block_on(new_local_store(dir.path()).load_directory(testdir_digest)),
+      Ok(Some(testdir_directory))
+    );
+    assert_eq!(
+      block_on(new_local_store(dir.path()).load_directory(recursive_testdir_digest)),
+      Ok(Some(recursive_testdir_directory))
+    );
+  }
+
+  #[test]
+  fn load_tree() {
+    let dir = TempDir::new().unwrap();
+
+    let testdir = TestDirectory::containing_roland();
+    let recursive_testdir = TestDirectory::recursive();
+    let tree = TestTree::new(&recursive_testdir, &[TestDirectory::containing_roland()]);
+
+    // Only the Tree is in the CAS: its Directories are expanded from it.
+    let cas = StubCAS::builder().tree(&tree).build();
+
     assert_eq!(
-      load_file_bytes(&new_store(dir.path(), cas.address()), testdata.digest()),
-      Ok(Some(testdata.bytes())),
-      "Read from CAS"
+      block_on(new_store(dir.path(), cas.address()).load_tree(tree.digest())),
+      Ok(Some(recursive_testdir.digest()))
     );
-    assert_eq!(1, cas.read_request_count());
     assert_eq!(
-      local::tests::load_file_bytes(&local::tests::new_store(dir.path()), testdata.digest()),
-      Ok(Some(testdata.bytes())),
-      "Read from local cache"
+      block_on(new_local_store(dir.path()).load_directory(testdir.digest())),
+      Ok(Some(testdir.directory()))
+    );
+    assert_eq!(
+      block_on(new_local_store(dir.path()).load_directory(recursive_testdir.digest())),
+      Ok(Some(recursive_testdir.directory()))
+    );
+    assert_eq!(
+      block_on(new_store(dir.path(), cas.address()).load_tree(TestData::roland().digest())),
+      Ok(None)
     );
   }
 
   #[test]
-  fn load_directory_falls_back_and_backfills() {
+  fn record_tree() {
     let dir = TempDir::new().unwrap();
 
-    let cas = new_cas(1024);
-
     let testdir = TestDirectory::containing_roland();
+    let recursive_testdir = TestDirectory::recursive();
+    let store = new_local_store(dir.path());
+    block_on(store.record_directory(&testdir.directory(), false)).expect("Error storing");
+    block_on(store.record_directory(&recursive_testdir.directory(), false)).expect("Error storing");
 
     assert_eq!(
-      block_on(new_store(dir.path(), cas.address()).load_directory(testdir.digest())),
-      Ok(Some(testdir.directory()))
-    );
-    assert_eq!(1, cas.read_request_count());
-    assert_eq!(
-      local::tests::load_directory_proto_bytes(
-        &local::tests::new_store(dir.path()),
-        testdir.digest(),
-      ),
-      Ok(Some(testdir.bytes()))
+      block_on(store.record_tree(recursive_testdir.digest())),
+      Ok(TestTree::new(&recursive_testdir, &[TestDirectory::containing_roland()]).digest())
     );
   }
 
   #[test]
-  fn load_recursive_directory() {
+  fn load_recursive_directory_in_batches() {
     let dir = TempDir::new().unwrap();
 
     let roland = TestData::roland();
     let catnip = TestData::catnip();
     let testdir = TestDirectory::containing_roland();
-    let testdir_digest = testdir.digest();
-    let testdir_directory = testdir.directory();
     let recursive_testdir = TestDirectory::recursive();
-    let recursive_testdir_directory = recursive_testdir.directory();
-    let recursive_testdir_digest = recursive_testdir.digest();
 
     let cas = StubCAS::builder()
       .file(&roland)
       .file(&catnip)
       .directory(&testdir)
       .directory(&recursive_testdir)
+      .batch_rpcs()
       .build();
 
     block_on(
       new_store(dir.path(), cas.address())
-        .ensure_local_has_recursive_directory(recursive_testdir_digest),
+        .ensure_local_has_recursive_directory(recursive_testdir.digest()),
     )
     .expect("Downloading recursive directory should have succeeded.");
 
@@ -2576,13 +7675,39 @@ mod tests {
       load_file_bytes(&new_local_store(dir.path()), catnip.digest()),
       Ok(Some(catnip.bytes()))
     );
+    // The files of each directory are fetched in a batch, and the directories themselves
+    // individually.
+    assert_eq!(cas.batch_request_count(), 2);
+    assert_eq!(cas.read_request_count(), 2);
+  }
+
+  #[test]
+  fn uploads_small_files_in_batches() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::builder().batch_rpcs().build();
+
+    let testdata = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    block_on(new_local_store(dir.path()).record_directory(&testdir.directory(), false))
+      .expect("Error storing directory locally");
+    block_on(new_local_store(dir.path()).store_file_bytes(testdata.bytes(), false))
+      .expect("Error storing file locally");
+
+    block_on(
+      new_store(dir.path(), cas.address()).ensure_remote_has_recursive(vec![testdir.digest()]),
+    )
+    .expect("Error uploading directory");
+
+    assert_eq!(cas.batch_request_count(), 1);
+    assert_eq!(cas.write_message_sizes.lock().len(), 0);
     assert_eq!(
-      block_on(new_local_store(dir.path()).load_directory(testdir_digest)),
-      Ok(Some(testdir_directory))
+      cas.blobs.lock().get(&testdata.fingerprint()),
+      Some(&testdata.bytes())
     );
     assert_eq!(
-      block_on(new_local_store(dir.path()).load_directory(recursive_testdir_digest)),
-      Ok(Some(recursive_testdir_directory))
+      cas.blobs.lock().get(&testdir.fingerprint()),
+      Some(&testdir.bytes())
     );
   }
 
@@ -2824,6 +7949,34 @@ mod tests {
     assert_eq!(expanded, want);
   }
 
+  #[test]
+  fn contains_directory_recursive() {
+    let dir = TempDir::new().unwrap();
+    let store = new_local_store(dir.path());
+
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::containing_roland();
+    let recursive_testdir = TestDirectory::recursive();
+
+    let contains = |store: &Store| {
+      block_on(store.contains_directory_recursive(recursive_testdir.digest()))
+        .expect("Error checking directory")
+    };
+    assert!(!contains(&store));
+
+    block_on(store.record_directory(&recursive_testdir.directory(), false))
+      .expect("Error storing directory locally");
+    block_on(store.record_directory(&testdir.directory(), false))
+      .expect("Error storing directory locally");
+    block_on(store.store_file_bytes(catnip.bytes(), false)).expect("Error storing file locally");
+    // The file beneath the subdirectory is missing.
+    assert!(!contains(&store));
+
+    block_on(store.store_file_bytes(roland.bytes(), false)).expect("Error storing file locally");
+    assert!(contains(&store));
+  }
+
   #[test]
   fn expand_missing_directory() {
     let dir = TempDir::new().unwrap();
@@ -2858,6 +8011,165 @@ mod tests {
     );
   }
 
+  #[test]
+  fn walk_entries_of_recursive_directory() {
+    let dir = TempDir::new().unwrap();
+
+    let testdir = TestDirectory::containing_roland();
+    let recursive_testdir = TestDirectory::recursive();
+
+    let store = new_local_store(dir.path());
+    block_on(store.record_directory(&recursive_testdir.directory(), false))
+      .expect("Error storing directory locally");
+    block_on(store.record_directory(&testdir.directory(), false))
+      .expect("Error storing directory locally");
+
+    let entries = block_on(store.walk_entries(recursive_testdir.digest()).collect())
+      .expect("Error walking directory");
+    let want = vec![
+      (
+        PathBuf::from("treats"),
+        WalkEntry::File(recursive_testdir.directory().get_files()[0].clone()),
+      ),
+      (
+        PathBuf::from("cats"),
+        WalkEntry::Directory(recursive_testdir.directory().get_directories()[0].clone()),
+      ),
+      (
+        PathBuf::from("cats/roland"),
+        WalkEntry::File(testdir.directory().get_files()[0].clone()),
+      ),
+    ];
+    assert_eq!(entries, want);
+  }
+
+  #[test]
+  fn walk_entries_of_empty_directory() {
+    let dir = TempDir::new().unwrap();
+    let entries = block_on(
+      new_local_store(dir.path())
+        .walk_entries(TestDirectory::empty().digest())
+        .collect(),
+    )
+    .expect("Error walking directory");
+    assert_eq!(entries, vec![]);
+  }
+
+  #[test]
+  fn garbage_collect_only_one_process_at_a_time() {
+    let dir = TempDir::new().unwrap();
+    let store = new_local_store(dir.path());
+    let roland = TestData::roland();
+    block_on(store.store_file_bytes(roland.bytes(), false)).expect("Error storing");
+
+    // As if another process which shares the store were collecting it.
+    let collection_lock = new_local_store(dir.path())
+      .local
+      .try_lock_for_collection()
+      .expect("Error locking")
+      .expect("Store was already being collected");
+    store
+      .garbage_collect(0, ShrinkBehavior::Fast)
+      .expect("Error garbage collecting");
+    assert_eq!(
+      load_file_bytes(&store, roland.digest()),
+      Ok(Some(roland.bytes()))
+    );
+
+    std::mem::drop(collection_lock);
+    store
+      .garbage_collect(0, ShrinkBehavior::Fast)
+      .expect("Error garbage collecting");
+    assert_eq!(load_file_bytes(&store, roland.digest()), Ok(None));
+  }
+
+  #[test]
+  fn fsck_only_one_process_at_a_time() {
+    let dir = TempDir::new().unwrap();
+    let store = new_local_store(dir.path());
+    let roland = TestData::roland();
+    block_on(store.store_file_bytes(roland.bytes(), false)).expect("Error storing");
+    local::tests::corrupt_file(&store.local, roland.digest());
+
+    // As if another process which shares the store were collecting it.
+    let collection_lock = new_local_store(dir.path())
+      .local
+      .try_lock_for_collection()
+      .expect("Error locking")
+      .expect("Store was already being collected");
+    assert_eq!(
+      store.fsck(false),
+      Err("Not checking the local store, because another process is collecting it".to_owned())
+    );
+
+    std::mem::drop(collection_lock);
+    assert_eq!(store.fsck(false).expect("Error checking store").len(), 1);
+  }
+
+  #[test]
+  fn fsck_deletes_modified_materialized_files() {
+    let dir = TempDir::new().unwrap();
+    let store = new_local_store(dir.path());
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+    block_on(store.record_directory(&testdir.directory(), false)).expect("Error saving Directory");
+    block_on(store.store_file_bytes(roland.bytes(), false)).expect("Error storing");
+
+    let materialize_dir = TempDir::new().unwrap();
+    block_on(store.materialize_directory_with(
+      materialize_dir.path().to_owned(),
+      testdir.digest(),
+      MaterializationStrategy::Hardlink,
+      &BTreeSet::new(),
+    ))
+    .expect("Error materializing");
+    assert_eq!(store.fsck(false), Ok(vec![]));
+
+    // Changes the read-only copy that the file is linked to.
+    let linked = materialize_dir.path().join("roland");
+    std::fs::set_permissions(&linked, std::fs::Permissions::from_mode(0o644)).unwrap();
+    std::fs::write(&linked, "modified").unwrap();
+
+    let broken_entries = vec![BrokenEntry {
+      entry_type: EntryType::File,
+      fingerprint: roland.fingerprint(),
+      problem: EntryProblem::ModifiedMaterializedCopy,
+    }];
+    let materialized_files = dir.path().join("materialized_files");
+    assert_eq!(store.fsck(true), Ok(broken_entries.clone()));
+    assert_eq!(list_dir(&materialized_files).len(), 1);
+    assert_eq!(store.fsck(false), Ok(broken_entries));
+    assert_eq!(list_dir(&materialized_files), Vec::<String>::new());
+    assert_eq!(store.fsck(false), Ok(vec![]));
+  }
+
+  #[test]
+  fn walk_entries_missing_subdir() {
+    let dir = TempDir::new().unwrap();
+
+    let recursive_testdir = TestDirectory::recursive();
+
+    let store = new_local_store(dir.path());
+    block_on(store.record_directory(&recursive_testdir.directory(), false))
+      .expect("Error storing directory locally");
+
+    // The entries of the root are yielded before its missing subdirectory is loaded.
+    let (first, rest) = block_on(store.walk_entries(recursive_testdir.digest()).into_future())
+      .map_err(|(error, _)| error)
+      .expect("Error walking directory");
+    assert_eq!(first.map(|(path, _)| path), Some(PathBuf::from("treats")));
+
+    let error = block_on(rest.collect()).expect_err("Want error");
+    assert!(
+      error.contains(&format!(
+        "{}",
+        TestDirectory::containing_roland().fingerprint()
+      )),
+      "Bad error message: {}",
+      error
+    );
+  }
+
   #[test]
   fn uploads_files() {
     let dir = TempDir::new().unwrap();
@@ -2881,6 +8193,45 @@ mod tests {
     );
   }
 
+  #[test]
+  fn extends_remote_leases_by_reuploading_lost_blobs() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::empty();
+
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let local_store = new_local_store(dir.path());
+    block_on(local_store.store_file_bytes(roland.bytes(), false))
+      .expect("Error storing roland locally");
+    block_on(local_store.store_file_bytes(catnip.bytes(), false))
+      .expect("Error storing catnip locally");
+
+    let store = new_store(dir.path(), cas.address())
+      .with_remote_lease_extension_interval(Some(Duration::from_secs(0)));
+    block_on(store.ensure_remote_has_recursive(vec![roland.digest()])).expect("Error uploading");
+
+    // The remote garbage collects roland, but the next upload extends its lease by re-uploading it.
+    cas.blobs.lock().remove(&roland.fingerprint());
+    let summary =
+      block_on(store.ensure_remote_has_recursive(vec![catnip.digest()])).expect("Error uploading");
+    assert_eq!(summary.ingested_file_count, 1);
+    assert_eq!(
+      cas.blobs.lock().get(&roland.fingerprint()),
+      Some(&roland.bytes())
+    );
+    assert_eq!(
+      cas.blobs.lock().get(&catnip.fingerprint()),
+      Some(&catnip.bytes())
+    );
+
+    // New sessions forget about the blobs of previous ones.
+    store.forget_remote_leases();
+    block_on(store.ensure_remote_has_recursive(vec![catnip.digest()])).expect("Error uploading");
+    cas.blobs.lock().remove(&roland.fingerprint());
+    block_on(store.ensure_remote_has_recursive(vec![catnip.digest()])).expect("Error uploading");
+    assert_eq!(cas.blobs.lock().get(&roland.fingerprint()), None);
+  }
+
   #[test]
   fn uploads_directories_recursively() {
     let dir = TempDir::new().unwrap();
@@ -2912,6 +8263,35 @@ mod tests {
     );
   }
 
+  #[test]
+  fn uploads_exactly_the_given_blobs() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::empty();
+
+    let testdata = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    block_on(new_local_store(dir.path()).record_directory(&testdir.directory(), false))
+      .expect("Error storing directory locally");
+    block_on(new_local_store(dir.path()).store_file_bytes(testdata.bytes(), false))
+      .expect("Error storing file locally");
+
+    block_on(new_store(dir.path(), cas.address()).ensure_remote_has(vec![testdir.digest()]))
+      .expect("Error uploading directory");
+
+    assert_eq!(
+      cas.blobs.lock().get(&testdir.fingerprint()),
+      Some(&testdir.bytes())
+    );
+    assert_eq!(cas.blobs.lock().get(&testdata.fingerprint()), None);
+
+    let error = block_on(
+      new_store(dir.path(), cas.address()).ensure_remote_has(vec![TestData::catnip().digest()]),
+    )
+    .expect_err("Want Err for blob which isn't stored locally");
+    assert!(error.contains("Not found"), "{}", error);
+  }
+
   #[test]
   fn uploads_files_recursively_when_under_three_digests_ignoring_items_already_in_cas() {
     let dir = TempDir::new().unwrap();
@@ -3048,32 +8428,161 @@ mod tests {
   }
 
   #[test]
-  fn upload_missing_file_in_directory() {
+  fn upload_missing_file_in_directory() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::empty();
+
+    let testdir = TestDirectory::containing_roland();
+
+    block_on(new_local_store(dir.path()).record_directory(&testdir.directory(), false))
+      .expect("Error storing directory locally");
+
+    assert_eq!(cas.blobs.lock().get(&testdir.fingerprint()), None);
+    assert_eq!(cas.blobs.lock().get(&testdir.fingerprint()), None);
+
+    let error = block_on(
+      new_store(dir.path(), cas.address()).ensure_remote_has_recursive(vec![testdir.digest()]),
+    )
+    .expect_err("Want error");
+    assert_eq!(
+      error,
+      format!(
+        "Failed to upload digest {:?}: Not found",
+        TestData::roland().digest()
+      ),
+      "Bad error message"
+    );
+  }
+
+  #[test]
+  fn write_through_uploads_stored_blobs() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::empty();
+
+    let testdata = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    let write_through = TierRules {
+      write_through: true,
+      ..TierRules::default()
+    };
+    let store = new_store(dir.path(), cas.address()).with_tiering_policy(TieringPolicy {
+      files: write_through,
+      directories: write_through,
+    });
+    block_on(store.store_file_bytes(testdata.bytes(), false)).expect("Error storing file");
+    block_on(store.record_directory(&testdir.directory(), false)).expect("Error storing directory");
+
+    assert_eq!(
+      cas.blobs.lock().get(&testdata.fingerprint()),
+      Some(&testdata.bytes())
+    );
+    assert_eq!(
+      cas.blobs.lock().get(&testdir.fingerprint()),
+      Some(&testdir.bytes())
+    );
+  }
+
+  #[test]
+  fn write_through_failures_are_not_errors() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::always_errors();
+
+    let testdata = TestData::roland();
+
+    let store = new_store(dir.path(), cas.address()).with_tiering_policy(TieringPolicy {
+      files: TierRules {
+        write_through: true,
+        ..TierRules::default()
+      },
+      ..TieringPolicy::default()
+    });
+    assert_eq!(
+      block_on(store.store_file_bytes(testdata.bytes(), false)),
+      Ok(testdata.digest())
+    );
+    assert_eq!(
+      load_file_bytes(&store, testdata.digest()),
+      Ok(Some(testdata.bytes()))
+    );
+  }
+
+  #[test]
+  fn upload_skips_blobs_larger_than_tiering_policy_allows() {
     let dir = TempDir::new().unwrap();
     let cas = StubCAS::empty();
 
+    let testdata = TestData::roland();
     let testdir = TestDirectory::containing_roland();
 
-    block_on(new_local_store(dir.path()).record_directory(&testdir.directory(), false))
-      .expect("Error storing directory locally");
-
-    assert_eq!(cas.blobs.lock().get(&testdir.fingerprint()), None);
-    assert_eq!(cas.blobs.lock().get(&testdir.fingerprint()), None);
+    let store = new_store(dir.path(), cas.address()).with_tiering_policy(TieringPolicy {
+      files: TierRules {
+        max_upload_bytes: Some(testdata.len() - 1),
+        ..TierRules::default()
+      },
+      ..TieringPolicy::default()
+    });
+    block_on(store.store_file_bytes(testdata.bytes(), false)).expect("Error storing file");
+    block_on(store.record_directory(&testdir.directory(), false)).expect("Error storing directory");
 
-    let error = block_on(
-      new_store(dir.path(), cas.address()).ensure_remote_has_recursive(vec![testdir.digest()]),
-    )
-    .expect_err("Want error");
+    let summary = block_on(store.ensure_remote_has_recursive(vec![testdir.digest()]))
+      .expect("Error uploading directory");
+    assert_eq!(summary.ingested_file_count, 2);
+    assert_eq!(summary.uploaded_file_count, 1);
+    assert_eq!(cas.blobs.lock().get(&testdata.fingerprint()), None);
     assert_eq!(
-      error,
-      format!(
-        "Failed to upload digest {:?}: Not found",
-        TestData::roland().digest()
-      ),
-      "Bad error message"
+      cas.blobs.lock().get(&testdir.fingerprint()),
+      Some(&testdir.bytes())
     );
   }
 
+  #[test]
+  fn ensure_remote_has_recursive_does_not_check_known_blobs() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::empty();
+
+    let recursive_testdir = TestDirectory::recursive();
+    let known_remote_blobs = || {
+      KnownRemoteBlobs::for_remote(
+        dir.path(),
+        &[cas.address()],
+        1000,
+        Duration::from_secs(60 * 60),
+      )
+    };
+
+    {
+      let store =
+        new_store(dir.path(), cas.address()).with_known_remote_blobs(Some(known_remote_blobs()));
+      block_on(store.store_file_bytes(TestData::roland().bytes(), false))
+        .expect("Error storing file");
+      block_on(store.store_file_bytes(TestData::catnip().bytes(), false))
+        .expect("Error storing file");
+      block_on(store.record_directory(&TestDirectory::containing_roland().directory(), false))
+        .expect("Error storing directory");
+      block_on(store.record_directory(&recursive_testdir.directory(), false))
+        .expect("Error storing directory");
+
+      block_on(store.ensure_remote_has_recursive(vec![recursive_testdir.digest()]))
+        .expect("Error uploading directory");
+      assert_eq!(cas.find_missing_request_count(), 1);
+      assert_eq!(cas.blobs.lock().len(), 4);
+
+      let summary = block_on(store.ensure_remote_has_recursive(vec![recursive_testdir.digest()]))
+        .expect("Error uploading directory");
+      assert_eq!(cas.find_missing_request_count(), 1);
+      assert_eq!(summary.ingested_file_count, 4);
+      assert_eq!(summary.uploaded_file_count, 0);
+    }
+
+    // The known blobs were saved when the Store was dropped.
+    let store =
+      new_store(dir.path(), cas.address()).with_known_remote_blobs(Some(known_remote_blobs()));
+    block_on(store.ensure_remote_has_recursive(vec![recursive_testdir.digest()]))
+      .expect("Error uploading directory");
+    assert_eq!(cas.find_missing_request_count(), 1);
+  }
+
   #[test]
   fn uploading_digest_with_wrong_size_is_error() {
     let dir = TempDir::new().unwrap();
@@ -3094,6 +8603,23 @@ mod tests {
     assert_eq!(cas.blobs.lock().get(&testdata.fingerprint()), None);
   }
 
+  #[test]
+  fn with_instance_name_download() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::builder()
+      .instance_name("dark-tower".to_owned())
+      .file(&TestData::roland())
+      .build();
+
+    let store_with_instance_name =
+      new_store(dir.path(), cas.address()).with_instance_name(Some("dark-tower".to_owned()));
+
+    assert_eq!(
+      block_on(store_with_instance_name.load_file_bytes_with(TestData::roland().digest(), |b| b)),
+      Ok(Some(TestData::roland().bytes()))
+    )
+  }
+
   #[test]
   fn instance_name_upload() {
     let dir = TempDir::new().unwrap();
@@ -3116,11 +8642,19 @@ mod tests {
       &[cas.address()],
       Some("dark-tower".to_owned()),
       &None,
+      &None,
+      &None,
+      ChannelPolicy::default(),
       None,
+      None,
+      Compression::None,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
       BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      LoadBalancing::RoundRobin,
       1,
       TimerHandle::default(),
     )
@@ -3143,11 +8677,19 @@ mod tests {
       &[cas.address()],
       Some("dark-tower".to_owned()),
       &None,
+      &None,
+      &None,
+      ChannelPolicy::default(),
+      None,
       None,
+      Compression::None,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
       BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      LoadBalancing::RoundRobin,
       1,
       TimerHandle::default(),
     )
@@ -3181,11 +8723,19 @@ mod tests {
       &[cas.address()],
       None,
       &None,
-      Some("Armory.Key".to_owned()),
+      &None,
+      &None,
+      ChannelPolicy::default(),
+      Some(BearerToken::fixed("Armory.Key".to_owned())),
+      None,
+      Compression::None,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
       BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      LoadBalancing::RoundRobin,
       1,
       TimerHandle::default(),
     )
@@ -3208,11 +8758,63 @@ mod tests {
       &[cas.address()],
       None,
       &None,
-      Some("Armory.Key".to_owned()),
+      &None,
+      &None,
+      ChannelPolicy::default(),
+      Some(BearerToken::fixed("Armory.Key".to_owned())),
+      None,
+      Compression::None,
+      1,
+      10 * 1024 * 1024,
+      Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
+      BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      LoadBalancing::RoundRobin,
+      1,
+      TimerHandle::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+      block_on(store_with_remote.load_file_bytes_with(TestData::roland().digest(), |b| b)),
+      Ok(Some(TestData::roland().bytes()))
+    )
+  }
+
+  #[test]
+  fn auth_download_refreshes_token() {
+    let dir = TempDir::new().unwrap();
+    let token_dir = TempDir::new().unwrap();
+    let cas = StubCAS::builder()
+      .required_auth_token("Armory.Key".to_owned())
+      .file(&TestData::roland())
+      .build();
+
+    // The token is replaced after it has been read, so the first attempt is unauthenticated.
+    let token_path = token_dir.path().join("token");
+    std::fs::write(&token_path, "Expired.Key").unwrap();
+    let token = BearerToken::new(TokenSource::File(token_path.clone())).unwrap();
+    std::fs::write(&token_path, "Armory.Key").unwrap();
+
+    let store_with_remote = Store::with_remote(
+      dir.path(),
+      &[cas.address()],
+      None,
+      &None,
+      &None,
+      &None,
+      ChannelPolicy::default(),
+      Some(token),
+      None,
+      Compression::None,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
       BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      LoadBalancing::RoundRobin,
       1,
       TimerHandle::default(),
     )
@@ -3337,15 +8939,292 @@ mod tests {
 
     assert_eq!(list_dir(materialize_dir.path()), vec!["feed", "food"]);
     assert_eq!(
-      file_contents(&materialize_dir.path().join("feed")),
-      catnip.bytes()
+      file_contents(&materialize_dir.path().join("feed")),
+      catnip.bytes()
+    );
+    assert_eq!(
+      file_contents(&materialize_dir.path().join("food")),
+      catnip.bytes()
+    );
+    assert!(is_executable(&materialize_dir.path().join("feed")));
+    assert!(!is_executable(&materialize_dir.path().join("food")));
+  }
+
+  #[test]
+  fn materialize_directory_with_links() {
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::with_mixed_executable_files();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    block_on(store.record_directory(&testdir.directory(), false)).expect("Error saving Directory");
+    block_on(store.store_file_bytes(catnip.bytes(), false))
+      .expect("Error saving catnip file bytes");
+
+    for strategy in &[
+      MaterializationStrategy::Hardlink,
+      MaterializationStrategy::Symlink,
+    ] {
+      let materialize_dir = TempDir::new().unwrap();
+      block_on(store.materialize_directory_with(
+        materialize_dir.path().to_owned(),
+        testdir.digest(),
+        *strategy,
+        &BTreeSet::new(),
+      ))
+      .expect("Error materializing");
+
+      assert_eq!(list_dir(materialize_dir.path()), vec!["feed", "food"]);
+      for name in &["feed", "food"] {
+        let path = materialize_dir.path().join(name);
+        assert_eq!(file_contents(&path), catnip.bytes());
+        assert_eq!(
+          std::fs::symlink_metadata(&path)
+            .unwrap()
+            .file_type()
+            .is_symlink(),
+          *strategy == MaterializationStrategy::Symlink
+        );
+      }
+      assert!(is_executable(&materialize_dir.path().join("feed")));
+      assert!(!is_executable(&materialize_dir.path().join("food")));
+    }
+  }
+
+  #[test]
+  fn materialize_directory_with_links_replaces_modified_materialized_files() {
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    block_on(store.record_directory(&testdir.directory(), false)).expect("Error saving Directory");
+    block_on(store.store_file_bytes(roland.bytes(), false))
+      .expect("Error saving roland file bytes");
+
+    let first_dir = TempDir::new().unwrap();
+    block_on(store.materialize_directory_with(
+      first_dir.path().to_owned(),
+      testdir.digest(),
+      MaterializationStrategy::Hardlink,
+      &BTreeSet::new(),
+    ))
+    .expect("Error materializing");
+    // As if a process had made its input writable and changed it, which changes the read-only
+    // copy that it is linked to.
+    let linked = first_dir.path().join("roland");
+    std::fs::set_permissions(&linked, std::fs::Permissions::from_mode(0o644)).unwrap();
+    std::fs::write(&linked, "modified").unwrap();
+
+    let second_dir = TempDir::new().unwrap();
+    block_on(store.materialize_directory_with(
+      second_dir.path().to_owned(),
+      testdir.digest(),
+      MaterializationStrategy::Hardlink,
+      &BTreeSet::new(),
+    ))
+    .expect("Error materializing");
+    let path = second_dir.path().join("roland");
+    assert_eq!(file_contents(&path), roland.bytes());
+    assert_eq!(
+      std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+      0o444
+    );
+  }
+
+  #[test]
+  fn garbage_collect_removes_materialized_files() {
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    block_on(store.record_directory(&testdir.directory(), false)).expect("Error saving Directory");
+    block_on(store.store_file_bytes(roland.bytes(), false))
+      .expect("Error saving roland file bytes");
+
+    let materialize_dir = TempDir::new().unwrap();
+    block_on(store.materialize_directory_with(
+      materialize_dir.path().to_owned(),
+      testdir.digest(),
+      MaterializationStrategy::Hardlink,
+      &BTreeSet::new(),
+    ))
+    .expect("Error materializing");
+    let materialized_files = store_dir.path().join("materialized_files");
+    assert_eq!(list_dir(&materialized_files).len(), 1);
+
+    store
+      .garbage_collect(0, ShrinkBehavior::Fast)
+      .expect("Error garbage collecting");
+    assert_eq!(list_dir(&materialized_files), Vec::<String>::new());
+    // Files which were linked to the copies are unaffected.
+    assert_eq!(
+      file_contents(&materialize_dir.path().join("roland")),
+      roland.bytes()
+    );
+  }
+
+  #[test]
+  fn materialize_directory_with_reflinks() {
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::with_mixed_executable_files();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    block_on(store.record_directory(&testdir.directory(), false)).expect("Error saving Directory");
+    block_on(store.store_file_bytes(catnip.bytes(), false))
+      .expect("Error saving catnip file bytes");
+
+    // Whether or not the filesystem supports cloning, the files are writable like copies, even
+    // when they needn't be.
+    let materialize_dir = TempDir::new().unwrap();
+    block_on(store.materialize_directory_with(
+      materialize_dir.path().to_owned(),
+      testdir.digest(),
+      MaterializationStrategy::Reflink,
+      &BTreeSet::new(),
+    ))
+    .expect("Error materializing");
+
+    assert_eq!(list_dir(materialize_dir.path()), vec!["feed", "food"]);
+    for name in &["feed", "food"] {
+      let path = materialize_dir.path().join(name);
+      assert_eq!(file_contents(&path), catnip.bytes());
+      assert!(!std::fs::symlink_metadata(&path)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    }
+    assert!(is_executable(&materialize_dir.path().join("feed")));
+    assert!(!is_executable(&materialize_dir.path().join("food")));
+
+    let food = materialize_dir.path().join("food");
+    std::fs::write(&food, "Tabby").expect("Error writing cloned file");
+    assert_eq!(
+      block_on(store.load_file_bytes_with(catnip.digest(), |bytes| bytes)).unwrap(),
+      Some(catnip.bytes())
+    );
+
+    // Materializing again is unaffected by the modified file.
+    let materialize_dir = TempDir::new().unwrap();
+    block_on(store.materialize_directory_with(
+      materialize_dir.path().to_owned(),
+      testdir.digest(),
+      MaterializationStrategy::Reflink,
+      &BTreeSet::new(),
+    ))
+    .expect("Error materializing");
+    assert_eq!(
+      file_contents(&materialize_dir.path().join("food")),
+      catnip.bytes()
+    );
+  }
+
+  #[test]
+  fn materialize_directory_with_links_copies_writable_files() {
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::containing_roland();
+    let recursive_testdir = TestDirectory::recursive();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    block_on(store.record_directory(&recursive_testdir.directory(), false))
+      .expect("Error saving recursive Directory");
+    block_on(store.record_directory(&testdir.directory(), false)).expect("Error saving Directory");
+    block_on(store.store_file_bytes(roland.bytes(), false)).expect("Error saving file bytes");
+    block_on(store.store_file_bytes(catnip.bytes(), false))
+      .expect("Error saving catnip file bytes");
+
+    // A writable directory makes the files beneath it writable.
+    let writable_paths = vec![PathBuf::from("cats")].into_iter().collect();
+    let materialize_dir = TempDir::new().unwrap();
+    block_on(store.materialize_directory_with(
+      materialize_dir.path().to_owned(),
+      recursive_testdir.digest(),
+      MaterializationStrategy::Symlink,
+      &writable_paths,
+    ))
+    .expect("Error materializing");
+
+    let is_symlink = |path: &Path| {
+      std::fs::symlink_metadata(path)
+        .unwrap()
+        .file_type()
+        .is_symlink()
+    };
+    let roland_path = materialize_dir.path().join("cats").join("roland");
+    assert!(!is_symlink(&roland_path));
+    assert!(is_symlink(&materialize_dir.path().join("treats")));
+    std::fs::write(&roland_path, "European Burmese!").expect("Error writing copied file");
+
+    let writable_paths = vec![PathBuf::from("treats")].into_iter().collect();
+    let materialize_dir = TempDir::new().unwrap();
+    block_on(store.materialize_directory_incrementally(
+      materialize_dir.path().to_owned(),
+      None,
+      recursive_testdir.digest(),
+      MaterializationStrategy::Symlink,
+      &writable_paths,
+    ))
+    .expect("Error materializing");
+    assert!(is_symlink(
+      &materialize_dir.path().join("cats").join("roland")
+    ));
+    assert!(!is_symlink(&materialize_dir.path().join("treats")));
+  }
+
+  #[test]
+  fn materialize_directory_incrementally() {
+    let materialize_dir = TempDir::new().unwrap();
+
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::containing_roland();
+    let recursive_testdir = TestDirectory::recursive();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    block_on(store.record_directory(&recursive_testdir.directory(), false))
+      .expect("Error saving recursive Directory");
+    block_on(store.record_directory(&testdir.directory(), false)).expect("Error saving Directory");
+    block_on(store.store_file_bytes(roland.bytes(), false)).expect("Error saving file bytes");
+    block_on(store.store_file_bytes(catnip.bytes(), false))
+      .expect("Error saving catnip file bytes");
+
+    block_on(store.materialize_directory_incrementally(
+      materialize_dir.path().to_owned(),
+      None,
+      testdir.digest(),
+      MaterializationStrategy::Copy,
+      &BTreeSet::new(),
+    ))
+    .expect("Error materializing");
+    assert_eq!(list_dir(materialize_dir.path()), vec!["roland"]);
+
+    std::fs::write(materialize_dir.path().join("untracked"), "state").unwrap();
+    block_on(store.materialize_directory_incrementally(
+      materialize_dir.path().to_owned(),
+      Some(testdir.digest()),
+      recursive_testdir.digest(),
+      MaterializationStrategy::Copy,
+      &BTreeSet::new(),
+    ))
+    .expect("Error materializing");
+
+    assert_eq!(
+      list_dir(materialize_dir.path()),
+      vec!["cats", "treats", "untracked"]
+    );
+    assert_eq!(
+      file_contents(&materialize_dir.path().join("cats").join("roland")),
+      roland.bytes()
     );
     assert_eq!(
-      file_contents(&materialize_dir.path().join("food")),
+      file_contents(&materialize_dir.path().join("treats")),
       catnip.bytes()
     );
-    assert!(is_executable(&materialize_dir.path().join("feed")));
-    assert!(!is_executable(&materialize_dir.path().join("food")));
   }
 
   #[test]
@@ -3393,6 +9272,89 @@ mod tests {
     );
   }
 
+  #[test]
+  fn export_and_import_archive() {
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::containing_roland();
+    let recursive_testdir = TestDirectory::recursive();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    block_on(store.record_directory(&recursive_testdir.directory(), false))
+      .expect("Error saving recursive Directory");
+    block_on(store.record_directory(&testdir.directory(), false)).expect("Error saving Directory");
+    block_on(store.store_file_bytes(roland.bytes(), false)).expect("Error saving file bytes");
+    block_on(store.store_file_bytes(catnip.bytes(), false))
+      .expect("Error saving catnip file bytes");
+
+    let archive_dir = TempDir::new().unwrap();
+    let archive_path = archive_dir.path().join("recursive.tar.gz");
+    block_on(store.export_archive(recursive_testdir.digest(), &archive_path))
+      .expect("Error exporting archive");
+
+    let other_store_dir = TempDir::new().unwrap();
+    let other_store = new_local_store(other_store_dir.path());
+    assert_eq!(
+      block_on(other_store.import_archive(&archive_path)),
+      Ok(recursive_testdir.digest())
+    );
+    assert_same_filecontents(
+      block_on(other_store.contents_for_directory(recursive_testdir.digest()))
+        .expect("Getting FileContents"),
+      block_on(store.contents_for_directory(recursive_testdir.digest()))
+        .expect("Getting FileContents"),
+    );
+  }
+
+  #[test]
+  fn export_archive_missing_file() {
+    let testdir = TestDirectory::containing_roland();
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    block_on(store.record_directory(&testdir.directory(), false)).expect("Error saving Directory");
+
+    let archive_dir = TempDir::new().unwrap();
+    let archive_path = archive_dir.path().join("roland.tar.gz");
+    let error =
+      block_on(store.export_archive(testdir.digest(), &archive_path)).expect_err("Want error");
+    assert!(
+      error.contains(&format!("{:?}", TestData::roland().digest())),
+      "Bad error message: {}",
+      error
+    );
+    assert!(!archive_path.exists());
+  }
+
+  #[test]
+  fn import_archive_with_wrong_contents() {
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    let archive_dir = TempDir::new().unwrap();
+    let archive_path = archive_dir.path().join("roland.tar.gz");
+    block_on(
+      archive::Writer::create(&archive_path, testdir.digest())
+        .and_then(move |writer| {
+          writer.append_blob(EntryType::Directory, testdir.digest(), testdir.bytes())
+        })
+        .and_then(move |writer| {
+          writer.append_blob(EntryType::File, roland.digest(), TestData::catnip().bytes())
+        })
+        .and_then(archive::Writer::finish),
+    )
+    .expect("Error writing archive");
+
+    let store_dir = TempDir::new().unwrap();
+    let error = block_on(new_local_store(store_dir.path()).import_archive(&archive_path))
+      .expect_err("Want error");
+    assert!(
+      error.contains(&format!("{:?}", TestData::roland().digest())),
+      "Bad error message: {}",
+      error
+    );
+  }
+
   fn assert_same_filecontents(left: Vec<FileContent>, right: Vec<FileContent>) {
     assert_eq!(
       left.len(),
@@ -3567,4 +9529,375 @@ mod tests {
       }
     );
   }
+
+  #[test]
+  fn http_cache_load_file_falls_back_and_backfills() {
+    let dir = TempDir::new().unwrap();
+    let testdata = TestData::roland();
+    let cache = StubHttpCache::empty().file(&testdata);
+
+    assert_eq!(
+      load_file_bytes(&new_store(dir.path(), cache.address()), testdata.digest()),
+      Ok(Some(testdata.bytes())),
+      "Read from HTTP cache"
+    );
+    assert_eq!(
+      *cache.requests.lock(),
+      vec![("GET".to_owned(), format!("/cas/{}", testdata.fingerprint()))]
+    );
+    assert_eq!(
+      local::tests::load_file_bytes(&local::tests::new_store(dir.path()), testdata.digest()),
+      Ok(Some(testdata.bytes())),
+      "Read from local cache"
+    );
+  }
+
+  #[test]
+  fn http_cache_wrong_digest_is_error() {
+    let dir = TempDir::new().unwrap();
+    let cache = StubHttpCache::empty()
+      .unverified_content(TestData::roland().fingerprint(), TestData::catnip().bytes());
+
+    load_file_bytes(
+      &new_store(dir.path(), cache.address()),
+      TestData::roland().digest(),
+    )
+    .expect_err("Want error for content which doesn't match its digest");
+  }
+
+  #[test]
+  fn http_cache_errors_are_errors() {
+    let dir = TempDir::new().unwrap();
+    let cache = StubHttpCache::always_errors();
+
+    load_file_bytes(
+      &new_store(dir.path(), cache.address()),
+      TestData::roland().digest(),
+    )
+    .expect_err("Want error for server error");
+    // The server error is retried once.
+    assert_eq!(cache.request_count("GET"), 2);
+  }
+
+  #[test]
+  fn http_cache_uploads_missing_blobs() {
+    let dir = TempDir::new().unwrap();
+    let testdata = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+    let cache = StubHttpCache::empty();
+
+    block_on(new_local_store(dir.path()).record_directory(&testdir.directory(), false))
+      .expect("Error storing directory locally");
+    block_on(new_local_store(dir.path()).store_file_bytes(testdata.bytes(), false))
+      .expect("Error storing file locally");
+
+    block_on(
+      new_store(dir.path(), cache.address()).ensure_remote_has_recursive(vec![testdir.digest()]),
+    )
+    .expect("Error uploading directory");
+
+    assert_eq!(cache.request_count("PUT"), 2);
+    assert_eq!(
+      cache.blobs.lock().get(&testdata.fingerprint()),
+      Some(&testdata.bytes())
+    );
+    assert_eq!(
+      cache.blobs.lock().get(&testdir.fingerprint()),
+      Some(&testdir.bytes())
+    );
+  }
+
+  #[test]
+  fn http_cache_action_results() {
+    let dir = TempDir::new().unwrap();
+    let cache = StubHttpCache::empty();
+    let store = new_store(dir.path(), cache.address()).with_instance_name(Some("main".to_owned()));
+    let action_digest = TestData::roland().digest();
+    let mut action_result = bazel_protos::remote_execution::ActionResult::new();
+    action_result.set_exit_code(3);
+
+    assert_eq!(
+      block_on(store.load_remote_action_result(action_digest)),
+      Ok(None)
+    );
+    block_on(store.record_remote_action_result(action_digest, action_result.clone()))
+      .expect("Error recording action result");
+    assert_eq!(
+      block_on(store.load_remote_action_result(action_digest)),
+      Ok(Some(action_result))
+    );
+    // Instance names are path prefixes.
+    assert!(cache
+      .requests
+      .lock()
+      .iter()
+      .all(|(_, path)| path == &format!("/main/ac/{}", action_digest.0)));
+  }
+
+  #[test]
+  fn s3_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let testdata = TestData::roland();
+    let s3 = StubS3::empty();
+    let store = new_store(dir.path(), s3.url("region=us-east-1&sse=AES256"))
+      .with_instance_name(Some("main".to_owned()));
+
+    block_on(new_local_store(dir.path()).store_file_bytes(testdata.bytes(), false))
+      .expect("Error storing file locally");
+    block_on(store.ensure_remote_has_recursive(vec![testdata.digest()]))
+      .expect("Error uploading file");
+    let key = format!("main/cas/{}", testdata.fingerprint());
+    assert_eq!(s3.objects.lock().get(&key), Some(&testdata.bytes()));
+    assert!(s3
+      .requests
+      .lock()
+      .iter()
+      .filter(|request| request.method == "PUT")
+      .all(
+        |request| request.headers.get("x-amz-server-side-encryption") == Some(&"AES256".to_owned())
+      ));
+
+    let other_dir = TempDir::new().unwrap();
+    assert_eq!(
+      load_file_bytes(
+        &new_store(other_dir.path(), s3.url("region=us-east-1"))
+          .with_instance_name(Some("main".to_owned())),
+        testdata.digest()
+      ),
+      Ok(Some(testdata.bytes()))
+    );
+  }
+
+  #[test]
+  fn s3_uploads_large_blobs_in_parts() {
+    let dir = TempDir::new().unwrap();
+    let testdata = TestData::roland();
+    let s3 = StubS3::empty();
+    let part_size = 4;
+    let store = new_store(dir.path(), s3.url(&format!("part_size={}B", part_size)));
+
+    block_on(new_local_store(dir.path()).store_file_bytes(testdata.bytes(), false))
+      .expect("Error storing file locally");
+    block_on(store.ensure_remote_has_recursive(vec![testdata.digest()]))
+      .expect("Error uploading file");
+    assert_eq!(
+      s3.objects
+        .lock()
+        .get(&format!("cas/{}", testdata.fingerprint())),
+      Some(&testdata.bytes())
+    );
+    assert_eq!(
+      s3.request_count("PUT"),
+      (testdata.len() + part_size - 1) / part_size
+    );
+    // One POST to start the upload, and one to complete it.
+    assert_eq!(s3.request_count("POST"), 2);
+  }
+
+  #[test]
+  fn s3_action_results() {
+    let dir = TempDir::new().unwrap();
+    let s3 = StubS3::empty();
+    let store = new_store(dir.path(), s3.url("region=us-east-1"));
+    let action_digest = TestData::roland().digest();
+    let mut action_result = bazel_protos::remote_execution::ActionResult::new();
+    action_result.set_exit_code(3);
+
+    assert_eq!(
+      block_on(store.load_remote_action_result(action_digest)),
+      Ok(None)
+    );
+    block_on(store.record_remote_action_result(action_digest, action_result.clone()))
+      .expect("Error recording action result");
+    assert_eq!(
+      block_on(store.load_remote_action_result(action_digest)),
+      Ok(Some(action_result))
+    );
+  }
+
+  #[test]
+  fn s3_buckets_and_grpc_servers_cannot_be_mixed() {
+    let dir = TempDir::new().unwrap();
+    let s3 = StubS3::empty();
+    let cas = StubCAS::empty();
+
+    assert!(Store::with_remote(
+      dir.path(),
+      &[s3.url("region=us-east-1"), cas.address()],
+      None,
+      &None,
+      &None,
+      &None,
+      ChannelPolicy::default(),
+      None,
+      None,
+      Compression::None,
+      1,
+      10 * 1024 * 1024,
+      Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
+      BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      LoadBalancing::RoundRobin,
+      1,
+      TimerHandle::default(),
+    )
+    .is_err());
+  }
+
+  ///
+  /// The URL of the given GCS bucket, authenticated as its stub service account.
+  ///
+  fn gcs_url(gcs: &StubGcs, dir: &TempDir, parameters: &str) -> String {
+    let key_file = dir.path().join("key.json");
+    std::fs::write(&key_file, gcs.service_account_key()).unwrap();
+    gcs.url(&format!(
+      "credentials={}&{}",
+      key_file.display(),
+      parameters
+    ))
+  }
+
+  #[test]
+  fn gcs_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let key_dir = TempDir::new().unwrap();
+    let testdata = TestData::roland();
+    let gcs = StubGcs::empty();
+    let store = new_store(dir.path(), gcs_url(&gcs, &key_dir, ""))
+      .with_instance_name(Some("main".to_owned()));
+
+    block_on(new_local_store(dir.path()).store_file_bytes(testdata.bytes(), false))
+      .expect("Error storing file locally");
+    block_on(store.ensure_remote_has_recursive(vec![testdata.digest()]))
+      .expect("Error uploading file");
+    assert_eq!(
+      gcs
+        .objects
+        .lock()
+        .get(&format!("main/cas/{}", testdata.fingerprint())),
+      Some(&testdata.bytes())
+    );
+
+    let other_dir = TempDir::new().unwrap();
+    assert_eq!(
+      load_file_bytes(
+        &new_store(other_dir.path(), gcs_url(&gcs, &key_dir, ""))
+          .with_instance_name(Some("main".to_owned())),
+        testdata.digest()
+      ),
+      Ok(Some(testdata.bytes()))
+    );
+
+    // Each store got one access token, which authenticated its requests.
+    let requests = gcs.requests.lock();
+    assert_eq!(
+      requests
+        .iter()
+        .filter(|request| request.path == "/token")
+        .count(),
+      2
+    );
+    assert!(requests
+      .iter()
+      .filter(|request| request.path != "/token")
+      .all(|request| request.headers.get("authorization")
+        == Some(&format!("Bearer {}", STUB_GCS_ACCESS_TOKEN))));
+  }
+
+  #[test]
+  fn gcs_resumes_interrupted_uploads() {
+    let dir = TempDir::new().unwrap();
+    let key_dir = TempDir::new().unwrap();
+    let testdata = TestData::new(&"0123456789".repeat(60 * 1024));
+    let gcs = StubGcs::empty().interrupt_chunks(1);
+    let store = new_store(dir.path(), gcs_url(&gcs, &key_dir, "chunk_size=256KiB"));
+
+    block_on(new_local_store(dir.path()).store_file_bytes(testdata.bytes(), false))
+      .expect("Error storing file locally");
+    block_on(store.ensure_remote_has_recursive(vec![testdata.digest()]))
+      .expect("Error uploading file");
+    assert_eq!(
+      gcs
+        .objects
+        .lock()
+        .get(&format!("cas/{}", testdata.fingerprint())),
+      Some(&testdata.bytes())
+    );
+    // The interrupted first chunk, the query of how much of it was persisted, and the rest of the
+    // blob in two chunks.
+    assert_eq!(gcs.request_count("PUT"), 4);
+    assert!(gcs
+      .requests
+      .lock()
+      .iter()
+      .any(|request| request.headers.get("content-range")
+        == Some(&format!("bytes */{}", testdata.len()))));
+  }
+
+  #[test]
+  fn gcs_action_results() {
+    let dir = TempDir::new().unwrap();
+    let key_dir = TempDir::new().unwrap();
+    let gcs = StubGcs::empty();
+    let store = new_store(dir.path(), gcs_url(&gcs, &key_dir, ""));
+    let action_digest = TestData::roland().digest();
+    let mut action_result = bazel_protos::remote_execution::ActionResult::new();
+    action_result.set_exit_code(3);
+
+    assert_eq!(
+      block_on(store.load_remote_action_result(action_digest)),
+      Ok(None)
+    );
+    block_on(store.record_remote_action_result(action_digest, action_result.clone()))
+      .expect("Error recording action result");
+    assert_eq!(
+      block_on(store.load_remote_action_result(action_digest)),
+      Ok(Some(action_result))
+    );
+  }
+
+  #[test]
+  fn parse_size_bytes() {
+    assert_eq!(super::parse_size_bytes("1024"), Ok(1024));
+    assert_eq!(super::parse_size_bytes("512B"), Ok(512));
+    assert_eq!(
+      super::parse_size_bytes("20GiB"),
+      Ok(20 * 1024 * 1024 * 1024)
+    );
+    assert_eq!(super::parse_size_bytes("4 MiB"), Ok(4 * 1024 * 1024));
+    super::parse_size_bytes("20GB").expect_err("Want Err for decimal unit");
+    super::parse_size_bytes("GiB").expect_err("Want Err for missing number");
+    super::parse_size_bytes("-1").expect_err("Want Err for negative size");
+  }
+
+  #[test]
+  fn http_caches_and_grpc_servers_cannot_be_mixed() {
+    let dir = TempDir::new().unwrap();
+    let cache = StubHttpCache::empty();
+    let cas = StubCAS::empty();
+
+    assert!(Store::with_remote(
+      dir.path(),
+      &[cache.address(), cas.address()],
+      None,
+      &None,
+      &None,
+      &None,
+      ChannelPolicy::default(),
+      None,
+      None,
+      Compression::None,
+      1,
+      10 * 1024 * 1024,
+      Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
+      BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      LoadBalancing::RoundRobin,
+      1,
+      TimerHandle::default(),
+    )
+    .is_err());
+  }
 }
@@ -71,6 +71,31 @@ impl Fingerprint {
     }
     s
   }
+
+  ///
+  /// The standard (padded) base64 encoding of the fingerprint, as used by e.g. subresource
+  /// integrity checksums.
+  ///
+  pub fn to_base64(&self) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut s = String::with_capacity((FINGERPRINT_SIZE + 2) / 3 * 4);
+    for chunk in self.0.chunks(3) {
+      let bytes = [
+        chunk[0],
+        chunk.get(1).cloned().unwrap_or(0),
+        chunk.get(2).cloned().unwrap_or(0),
+      ];
+      let bits = (u32::from(bytes[0]) << 16) | (u32::from(bytes[1]) << 8) | u32::from(bytes[2]);
+      for i in 0..4 {
+        if i <= chunk.len() {
+          s.push(ALPHABET[((bits >> (18 - 6 * i)) & 0x3f) as usize] as char);
+        } else {
+          s.push('=');
+        }
+      }
+    }
+    s
+  }
 }
 
 impl fmt::Display for Fingerprint {
@@ -235,6 +260,19 @@ mod fingerprint_tests {
     )
   }
 
+  #[test]
+  fn to_base64() {
+    assert_eq!(
+      Fingerprint([
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0xfe, 0xdc, 0xba, 0x98, 0x76, 0x54, 0x32,
+        0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff,
+      ],)
+      .to_base64(),
+      "ASNFZ4mrze/+3LqYdlQyEAAAAAAAAAAA//////////8="
+    )
+  }
+
   #[test]
   fn display() {
     let hex = "0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF";
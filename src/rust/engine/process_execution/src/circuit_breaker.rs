@@ -0,0 +1,445 @@
+// A circuit breaker around remote execution, which stops sending processes to a remote execution
+// cluster while it is failing, and runs them locally instead, so that an outage of the cluster
+// slows builds down rather than failing them.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::future::{self, Future};
+use log::{debug, info, warn};
+
+use super::{CommandRunner, ExecuteProcessRequest, FallibleExecuteProcessResult, Platform};
+
+///
+/// When to stop sending processes to the remote, and for how long.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct CircuitBreakerPolicy {
+  ///
+  /// How many processes in a row must fail to run remotely for the breaker to trip. Processes
+  /// which run and exit with a non-zero code are not failures of the remote.
+  ///
+  pub failure_threshold: usize,
+  ///
+  /// How long to run processes locally once the breaker has tripped, before probing the remote
+  /// again with a single process.
+  ///
+  pub cool_down: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CircuitState {
+  ///
+  /// Processes run remotely.
+  ///
+  Closed { consecutive_failures: usize },
+  ///
+  /// Processes run locally until the cool down ends.
+  ///
+  Open { until: Instant },
+  ///
+  /// A single process is probing the remote, and others run locally until it completes. If the
+  /// probe is cancelled, another is sent once it has been in flight for the cool down.
+  ///
+  HalfOpen { probing_since: Instant },
+}
+
+///
+/// How a process came to be sent to the remote, which determines what its result says about the
+/// breaker: only the result of the current probe can close (or reopen) a half open breaker.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Sent {
+  WhileClosed,
+  AsProbe { probing_since: Instant },
+}
+
+///
+/// A CommandRunner which runs processes with a remote CommandRunner, unless it has been failing
+/// (according to the `CircuitBreakerPolicy`), in which case they run with a local CommandRunner.
+///
+/// Processes which fail to run remotely are run locally instead, whether or not they trip the
+/// breaker, as are processes which can't be executed remotely at all. The remote and local
+/// CommandRunners must run processes on the same platform.
+///
+#[derive(Clone)]
+pub struct CircuitBreakingCommandRunner {
+  remote: Arc<dyn CommandRunner>,
+  local: Arc<dyn CommandRunner>,
+  policy: CircuitBreakerPolicy,
+  state: Arc<Mutex<CircuitState>>,
+}
+
+impl CircuitBreakingCommandRunner {
+  pub fn new(
+    remote: Box<dyn CommandRunner>,
+    local: Box<dyn CommandRunner>,
+    policy: CircuitBreakerPolicy,
+  ) -> CircuitBreakingCommandRunner {
+    CircuitBreakingCommandRunner {
+      remote: Arc::from(remote),
+      local: Arc::from(local),
+      policy,
+      state: Arc::new(Mutex::new(CircuitState::Closed {
+        consecutive_failures: 0,
+      })),
+    }
+  }
+
+  pub fn state(&self) -> CircuitState {
+    *self.state.lock().unwrap()
+  }
+
+  ///
+  /// Whether to run the next process remotely (and if so, how it was sent), which moves the breaker
+  /// from open to half open (with the process as its probe) once the cool down has ended.
+  ///
+  fn run_remotely(&self) -> Option<Sent> {
+    let now = Instant::now();
+    let mut state = self.state.lock().unwrap();
+    match *state {
+      CircuitState::Closed { .. } => Some(Sent::WhileClosed),
+      CircuitState::Open { until } if now >= until => {
+        info!(
+          "Probing remote execution after cooling down for {:?}",
+          self.policy.cool_down
+        );
+        *state = CircuitState::HalfOpen { probing_since: now };
+        Some(Sent::AsProbe { probing_since: now })
+      }
+      CircuitState::HalfOpen { probing_since } if now >= probing_since + self.policy.cool_down => {
+        *state = CircuitState::HalfOpen { probing_since: now };
+        Some(Sent::AsProbe { probing_since: now })
+      }
+      CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => None,
+    }
+  }
+
+  fn record(
+    state: &Mutex<CircuitState>,
+    policy: CircuitBreakerPolicy,
+    sent: Sent,
+    failure: Option<&str>,
+  ) {
+    let mut state = state.lock().unwrap();
+    let open = || CircuitState::Open {
+      until: Instant::now() + policy.cool_down,
+    };
+    let is_probe = |probing_since| sent == Sent::AsProbe { probing_since };
+    *state = match (*state, failure) {
+      (CircuitState::Closed { .. }, None) => CircuitState::Closed {
+        consecutive_failures: 0,
+      },
+      // Processes sent before the probe (or by a probe which has since been replaced) don't affect
+      // a half open breaker.
+      (CircuitState::HalfOpen { probing_since }, _) if !is_probe(probing_since) => {
+        CircuitState::HalfOpen { probing_since }
+      }
+      (CircuitState::HalfOpen { .. }, None) => {
+        info!("Remote execution has recovered: running processes remotely again");
+        CircuitState::Closed {
+          consecutive_failures: 0,
+        }
+      }
+      (
+        CircuitState::Closed {
+          consecutive_failures,
+        },
+        Some(err),
+      ) => {
+        let consecutive_failures = consecutive_failures + 1;
+        if consecutive_failures >= policy.failure_threshold {
+          warn!(
+            "Remote execution failed for {} processes in a row: running processes locally for \
+             {:?}. Last failure: {}",
+            consecutive_failures, policy.cool_down, err
+          );
+          open()
+        } else {
+          CircuitState::Closed {
+            consecutive_failures,
+          }
+        }
+      }
+      (CircuitState::HalfOpen { .. }, Some(err)) => {
+        warn!(
+          "Remote execution is still failing: running processes locally for another {:?}. \
+           Failure: {}",
+          policy.cool_down, err
+        );
+        open()
+      }
+      // Processes sent before the breaker tripped don't affect it: only probes can close it.
+      (open_state @ CircuitState::Open { .. }, _) => open_state,
+    };
+  }
+}
+
+impl CommandRunner for CircuitBreakingCommandRunner {
+  fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    if !req.is_remotable() {
+      return self.local.run(req);
+    }
+    let sent = match self.run_remotely() {
+      Some(sent) => sent,
+      None => {
+        debug!(
+          "Running {:?} locally while remote execution is unavailable",
+          req.description
+        );
+        return self.local.run(req);
+      }
+    };
+
+    let state = self.state.clone();
+    let policy = self.policy;
+    let local = self.local.clone();
+    self
+      .remote
+      .run(req.clone())
+      .then(move |res| match res {
+        Ok(result) => {
+          Self::record(&state, policy, sent, None);
+          future::ok(result).to_boxed()
+        }
+        Err(err) => {
+          Self::record(&state, policy, sent, Some(&err));
+          warn!(
+            "Running {:?} locally after failing to run it remotely: {}",
+            req.description, err
+          );
+          local.run(req)
+        }
+      })
+      .to_boxed()
+  }
+
+  fn platform(&self) -> Result<Platform, String> {
+    self.remote.platform()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{CircuitBreakerPolicy, CircuitBreakingCommandRunner, CircuitState, Sent};
+  use crate::{CommandRunner, ExecuteProcessRequest, FallibleExecuteProcessResult};
+  use boxfuture::{BoxFuture, Boxable};
+  use bytes::Bytes;
+  use futures::{future, Future};
+  use std::collections::{BTreeMap, BTreeSet};
+  use std::sync::{Arc, Mutex};
+  use std::time::{Duration, Instant};
+
+  #[test]
+  fn falls_back_to_local_for_failures() {
+    let (remote, remote_runs) = FakeCommandRunner::new(Err("Connection reset"));
+    let (local, local_runs) = FakeCommandRunner::new(Ok(0));
+    let runner = breaker(remote, local, Duration::from_secs(60));
+
+    assert_eq!(run(&runner, true), Ok(0));
+    assert_eq!(*remote_runs.lock().unwrap(), 1);
+    assert_eq!(*local_runs.lock().unwrap(), 1);
+    assert_eq!(
+      runner.state(),
+      CircuitState::Closed {
+        consecutive_failures: 1
+      }
+    );
+  }
+
+  #[test]
+  fn exit_codes_are_not_failures() {
+    let (remote, remote_runs) = FakeCommandRunner::new(Ok(1));
+    let (local, local_runs) = FakeCommandRunner::new(Ok(0));
+    let runner = breaker(remote, local, Duration::from_secs(60));
+
+    for _ in 0..3 {
+      assert_eq!(run(&runner, true), Ok(1));
+    }
+    assert_eq!(*remote_runs.lock().unwrap(), 3);
+    assert_eq!(*local_runs.lock().unwrap(), 0);
+  }
+
+  #[test]
+  fn trips_after_consecutive_failures() {
+    let (remote, remote_runs) = FakeCommandRunner::new(Err("Unavailable"));
+    let (local, local_runs) = FakeCommandRunner::new(Ok(0));
+    let runner = breaker(remote, local, Duration::from_secs(60));
+
+    for _ in 0..4 {
+      assert_eq!(run(&runner, true), Ok(0));
+    }
+    // The third failure trips the breaker, so the fourth process runs only locally.
+    assert_eq!(*remote_runs.lock().unwrap(), 3);
+    assert_eq!(*local_runs.lock().unwrap(), 4);
+    match runner.state() {
+      CircuitState::Open { .. } => {}
+      state => panic!("Want open breaker, got {:?}", state),
+    }
+  }
+
+  #[test]
+  fn probes_and_recovers_after_cool_down() {
+    let (remote, remote_runs) = FakeCommandRunner::new(Err("Unavailable"));
+    let (local, _) = FakeCommandRunner::new(Ok(0));
+    let runner = breaker(remote.clone(), local, Duration::from_millis(10));
+    for _ in 0..3 {
+      run(&runner, true).unwrap();
+    }
+
+    // A failed probe reopens the breaker.
+    std::thread::sleep(Duration::from_millis(20));
+    run(&runner, true).unwrap();
+    assert_eq!(*remote_runs.lock().unwrap(), 4);
+    run(&runner, true).unwrap();
+    assert_eq!(*remote_runs.lock().unwrap(), 4);
+
+    // And a successful one closes it.
+    *remote.result.lock().unwrap() = Ok(0);
+    std::thread::sleep(Duration::from_millis(20));
+    run(&runner, true).unwrap();
+    run(&runner, true).unwrap();
+    assert_eq!(*remote_runs.lock().unwrap(), 6);
+    assert_eq!(
+      runner.state(),
+      CircuitState::Closed {
+        consecutive_failures: 0
+      }
+    );
+  }
+
+  #[test]
+  fn only_the_probe_closes_a_half_open_breaker() {
+    let policy = CircuitBreakerPolicy {
+      failure_threshold: 3,
+      cool_down: Duration::from_secs(60),
+    };
+    let replaced_probing_since = Instant::now();
+    let probing_since = replaced_probing_since + Duration::from_millis(1);
+    let state = Mutex::new(CircuitState::HalfOpen { probing_since });
+
+    // Processes which were sent while the breaker was closed, and a probe which has been replaced.
+    CircuitBreakingCommandRunner::record(&state, policy, Sent::WhileClosed, None);
+    CircuitBreakingCommandRunner::record(&state, policy, Sent::WhileClosed, Some("Unavailable"));
+    CircuitBreakingCommandRunner::record(
+      &state,
+      policy,
+      Sent::AsProbe {
+        probing_since: replaced_probing_since,
+      },
+      None,
+    );
+    assert_eq!(
+      *state.lock().unwrap(),
+      CircuitState::HalfOpen { probing_since }
+    );
+
+    CircuitBreakingCommandRunner::record(&state, policy, Sent::AsProbe { probing_since }, None);
+    assert_eq!(
+      *state.lock().unwrap(),
+      CircuitState::Closed {
+        consecutive_failures: 0
+      }
+    );
+  }
+
+  #[test]
+  fn runs_unremotable_processes_locally() {
+    let (remote, remote_runs) = FakeCommandRunner::new(Ok(0));
+    let (local, local_runs) = FakeCommandRunner::new(Ok(0));
+    let runner = breaker(remote, local, Duration::from_secs(60));
+
+    assert_eq!(run(&runner, false), Ok(0));
+    assert_eq!(*remote_runs.lock().unwrap(), 0);
+    assert_eq!(*local_runs.lock().unwrap(), 1);
+  }
+
+  fn breaker(
+    remote: FakeCommandRunner,
+    local: FakeCommandRunner,
+    cool_down: Duration,
+  ) -> CircuitBreakingCommandRunner {
+    CircuitBreakingCommandRunner::new(
+      Box::new(remote),
+      Box::new(local),
+      CircuitBreakerPolicy {
+        failure_threshold: 3,
+        cool_down,
+      },
+    )
+  }
+
+  fn run(runner: &CircuitBreakingCommandRunner, remotable: bool) -> Result<i32, String> {
+    runner
+      .run(ExecuteProcessRequest {
+        argv: vec![],
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "breaker".to_string(),
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: if remotable {
+          BTreeSet::new()
+        } else {
+          vec!["HOME".to_owned()].into_iter().collect()
+        },
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      })
+      .wait()
+      .map(|result| result.exit_code)
+  }
+
+  ///
+  /// A CommandRunner which always exits with the given code (or fails with the given error), and
+  /// counts its runs.
+  ///
+  #[derive(Clone)]
+  struct FakeCommandRunner {
+    result: Arc<Mutex<Result<i32, &'static str>>>,
+    runs: Arc<Mutex<usize>>,
+  }
+
+  impl FakeCommandRunner {
+    fn new(result: Result<i32, &'static str>) -> (FakeCommandRunner, Arc<Mutex<usize>>) {
+      let runs = Arc::new(Mutex::new(0));
+      (
+        FakeCommandRunner {
+          result: Arc::new(Mutex::new(result)),
+          runs: runs.clone(),
+        },
+        runs,
+      )
+    }
+  }
+
+  impl CommandRunner for FakeCommandRunner {
+    fn run(&self, _req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
+      *self.runs.lock().unwrap() += 1;
+      let exit_code = match *self.result.lock().unwrap() {
+        Ok(exit_code) => exit_code,
+        Err(err) => return future::err(err.to_owned()).to_boxed(),
+      };
+      future::ok(FallibleExecuteProcessResult {
+        stdout: Bytes::new(),
+        stderr: Bytes::new(),
+        exit_code,
+        output_directory: fs::EMPTY_DIGEST,
+        execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
+      })
+      .to_boxed()
+    }
+  }
+}
@@ -0,0 +1,307 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use boxfuture::{try_future, BoxFuture};
+
+use super::CommandRunner as CommandRunnerTrait;
+use super::{local, tools, ExecuteProcessRequest, FallibleExecuteProcessResult, Platform};
+
+///
+/// The path at which the sandbox is mounted inside of containers, which is also the working
+/// directory of the processes run in them.
+///
+const CONTAINER_SANDBOX: &str = "/pants-sandbox";
+
+///
+/// The environment variables which configure the docker client (rather than the processes run in
+/// containers), which are passed through from our own environment.
+///
+const DOCKER_CLIENT_ENV_VARS: &[&str] = &[
+  "DOCKER_CERT_PATH",
+  "DOCKER_CONFIG",
+  "DOCKER_HOST",
+  "DOCKER_TLS_VERIFY",
+  "HOME",
+];
+
+///
+/// Runs processes inside of containers of a docker image.
+///
+/// Inputs are materialized (and outputs captured) by a local::CommandRunner, whose sandbox for each
+/// process is bind-mounted into the container. Processes run as the current user, so that the
+/// outputs they create can be captured and cleaned up. Named caches are bind-mounted at their host
+/// paths (and tools, read-only, at their resolved paths), so that the symlinks to them in the
+/// sandbox resolve inside of the container.
+///
+/// Timeouts are enforced by signalling the docker client, which forwards the signal to the
+/// container: if the client has to be killed, the container may outlive it.
+///
+pub struct CommandRunner {
+  local: local::CommandRunner,
+  docker_binary: PathBuf,
+  image: String,
+  client_env: BTreeMap<String, String>,
+}
+
+impl CommandRunner {
+  pub fn new(local: local::CommandRunner, docker_binary: PathBuf, image: String) -> CommandRunner {
+    let client_env = DOCKER_CLIENT_ENV_VARS
+      .iter()
+      .filter_map(|name| {
+        std::env::var(name)
+          .ok()
+          .map(|value| ((*name).to_owned(), value))
+      })
+      .collect();
+    CommandRunner {
+      local,
+      docker_binary,
+      image,
+      client_env,
+    }
+  }
+
+  ///
+  /// Converts a request into a request to run the docker client locally, in the sandbox that will
+  /// be mounted into the container.
+  ///
+  fn docker_request(&self, req: ExecuteProcessRequest) -> Result<ExecuteProcessRequest, String> {
+    let docker_binary = self
+      .docker_binary
+      .to_str()
+      .ok_or_else(|| format!("Non-UTF8 docker binary path: {:?}", self.docker_binary))?;
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+    let mut argv = vec![
+      "/bin/sh".to_owned(),
+      "-c".to_owned(),
+      // The host path of the sandbox is only known once it has been created, so the shell expands
+      // it. Everything else is passed positionally, to avoid needing to quote it.
+      format!(
+        "exec \"$0\" run --rm --init --volume \"$PWD:{0}\" --workdir {0} \"$@\"",
+        CONTAINER_SANDBOX
+      ),
+      docker_binary.to_owned(),
+      format!("--user={}:{}", uid, gid),
+    ];
+    if let Some(named_caches) = self.local.named_caches() {
+      for name in req.named_caches.keys() {
+        let cache_dir = named_caches.cache_dir(name);
+        let cache_dir = cache_dir
+          .to_str()
+          .ok_or_else(|| format!("Non-UTF8 named cache path: {:?}", cache_dir))?;
+        argv.push(format!("--volume={0}:{0}", cache_dir));
+      }
+    }
+    // Tools are bind-mounted at the paths that the symlinks to them in the sandbox point to.
+    for tool in tools::resolve(&req.tool_mounts)? {
+      let tool_path = tool
+        .path
+        .to_str()
+        .ok_or_else(|| format!("Non-UTF8 tool path: {:?}", tool.path))?;
+      argv.push(format!("--volume={0}:{0}:ro", tool_path));
+    }
+    // Passthrough variables are named without values, so that the client copies them from its own
+    // environment: their values must not be part of the argv (which is part of the cache key).
+    let env_passthrough: BTreeSet<String> = req
+      .env_passthrough
+      .iter()
+      .filter(|name| std::env::var_os(name).is_some())
+      .cloned()
+      .collect();
+    argv.extend(
+      req
+        .env
+        .iter()
+        .filter(|(name, _)| !env_passthrough.contains(*name))
+        .map(|(name, value)| format!("--env={}={}", name, value)),
+    );
+    argv.extend(env_passthrough.iter().map(|name| format!("--env={}", name)));
+    argv.push(self.image.clone());
+    argv.extend(req.argv);
+
+    Ok(ExecuteProcessRequest {
+      argv,
+      env: self.client_env.clone(),
+      env_passthrough,
+      ..req
+    })
+  }
+}
+
+impl super::CommandRunner for CommandRunner {
+  fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let req = try_future!(self.docker_request(req));
+    self.local.run(req)
+  }
+
+  ///
+  /// Processes run in (Linux) containers, whatever the platform of this machine.
+  ///
+  fn platform(&self) -> Result<Platform, String> {
+    Ok(Platform::Linux)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::CommandRunner;
+  use crate::{local, ExecuteProcessRequest};
+  use std::collections::{BTreeMap, BTreeSet};
+  use std::env;
+  use std::path::PathBuf;
+  use std::time::Duration;
+  use tempfile::TempDir;
+  use testutil::owned_string_vec;
+
+  #[test]
+  fn docker_request() {
+    let store_dir = TempDir::new().unwrap();
+    let work_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner = CommandRunner::new(
+      local::CommandRunner::new(store, work_dir.path().to_owned(), true),
+      PathBuf::from("/usr/bin/docker"),
+      "python:3.6".to_owned(),
+    );
+
+    let mut env = BTreeMap::new();
+    env.insert("PYTHONPATH".to_owned(), "src".to_owned());
+    let req = runner
+      .docker_request(ExecuteProcessRequest {
+        argv: owned_string_vec(&["python", "-c", "print('hi')"]),
+        env,
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "say hi".to_string(),
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      })
+      .unwrap();
+
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    assert_eq!(
+      req.argv[3..].to_vec(),
+      vec![
+        "/usr/bin/docker".to_owned(),
+        format!("--user={}:{}", uid, gid),
+        "--env=PYTHONPATH=src".to_owned(),
+        "python:3.6".to_owned(),
+        "python".to_owned(),
+        "-c".to_owned(),
+        "print('hi')".to_owned(),
+      ]
+    );
+    assert!(!req.env.contains_key("PYTHONPATH"));
+    assert_eq!(req.description, "say hi");
+  }
+
+  #[test]
+  fn docker_request_with_jdk() {
+    let jdk_home = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let work_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner = CommandRunner::new(
+      local::CommandRunner::new(store, work_dir.path().to_owned(), true),
+      PathBuf::from("/usr/bin/docker"),
+      "openjdk:8".to_owned(),
+    );
+
+    let req = runner
+      .docker_request(ExecuteProcessRequest {
+        argv: owned_string_vec(&["java", "-version"]),
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "java version".to_string(),
+        tool_mounts: crate::jdk_tool_mounts(Some(jdk_home.path().to_owned())),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      })
+      .unwrap();
+
+    let jdk_path = jdk_home.path().canonicalize().unwrap();
+    assert!(req
+      .argv
+      .contains(&format!("--volume={0}:{0}:ro", jdk_path.to_str().unwrap())));
+  }
+
+  #[test]
+  fn docker_request_passes_through_variables_by_name() {
+    let store_dir = TempDir::new().unwrap();
+    let work_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner = CommandRunner::new(
+      local::CommandRunner::new(store, work_dir.path().to_owned(), true),
+      PathBuf::from("/usr/bin/docker"),
+      "python:3.6".to_owned(),
+    );
+
+    let docker_request = |value: &str| {
+      env::set_var("PANTS_TEST_DOCKER_PASSTHROUGH", value);
+      runner
+        .docker_request(ExecuteProcessRequest {
+          argv: owned_string_vec(&["/usr/bin/env"]),
+          env: BTreeMap::new(),
+          input_files: fs::EMPTY_DIGEST,
+          output_files: BTreeSet::new(),
+          output_directories: BTreeSet::new(),
+          timeout: Duration::from_millis(1000),
+          description: "run env".to_string(),
+          tool_mounts: BTreeMap::new(),
+          named_caches: BTreeMap::new(),
+          resource_limits: crate::ResourceLimits::default(),
+          reusable_workdir: None,
+          env_passthrough: vec!["PANTS_TEST_DOCKER_PASSTHROUGH".to_owned()]
+            .into_iter()
+            .collect(),
+          concurrency_weight: 1,
+          tee_output: false,
+          platform_properties: BTreeMap::new(),
+          priority: 0,
+          execution_headers: BTreeMap::new(),
+        })
+        .unwrap()
+    };
+
+    let first = docker_request("first-secret");
+    let second = docker_request("second-secret");
+    env::remove_var("PANTS_TEST_DOCKER_PASSTHROUGH");
+
+    assert!(first
+      .argv
+      .contains(&"--env=PANTS_TEST_DOCKER_PASSTHROUGH".to_owned()));
+    assert!(!first.argv.iter().any(|arg| arg.contains("first-secret")));
+    assert_eq!(first.argv, second.argv);
+    // The client gets the value from its own environment.
+    assert!(first
+      .env_passthrough
+      .contains("PANTS_TEST_DOCKER_PASSTHROUGH"));
+    assert!(!first.env.contains_key("PANTS_TEST_DOCKER_PASSTHROUGH"));
+    assert!(local::action_cache_key(&first).is_some());
+    assert_eq!(
+      local::action_cache_key(&first),
+      local::action_cache_key(&second)
+    );
+  }
+}
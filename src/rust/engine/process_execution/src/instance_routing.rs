@@ -0,0 +1,115 @@
+// Routing of processes to one of several remote instances (e.g. separate Linux and macOS clusters
+// behind a single endpoint) according to their platform properties.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+///
+/// A rule which routes the processes with the given platform property to the given instance.
+///
+/// Platform properties are both the extra properties configured for every process (e.g. OSFamily)
+/// and the properties of each process, so processes can be routed by platform, or by properties
+/// which tag them (e.g. a pool for processes which need a lot of memory).
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InstanceRoute {
+  pub property: String,
+  pub value: String,
+  pub instance_name: String,
+}
+
+impl FromStr for InstanceRoute {
+  type Err = String;
+
+  ///
+  /// Parses a route of the form `property=value:instance_name`. Values may contain colons (e.g.
+  /// container images), but instance names may not.
+  ///
+  fn from_str(s: &str) -> Result<InstanceRoute, String> {
+    let invalid = || {
+      format!(
+        "Instance routes must be of the form property=value:instance_name, but got {:?}",
+        s
+      )
+    };
+    let mut parts = s.splitn(2, '=');
+    let property = parts.next().filter(|p| !p.is_empty()).ok_or_else(invalid)?;
+    let mut parts = parts.next().ok_or_else(invalid)?.rsplitn(2, ':');
+    let instance_name = parts.next().filter(|i| !i.is_empty()).ok_or_else(invalid)?;
+    let value = parts.next().ok_or_else(invalid)?;
+    Ok(InstanceRoute {
+      property: property.to_owned(),
+      value: value.to_owned(),
+      instance_name: instance_name.to_owned(),
+    })
+  }
+}
+
+///
+/// The instance to which the first of the given routes which matches the given platform properties
+/// routes, if any does.
+///
+pub fn route<'a>(
+  routes: &'a [InstanceRoute],
+  platform_properties: &BTreeMap<String, String>,
+) -> Option<&'a str> {
+  routes
+    .iter()
+    .find(|route| platform_properties.get(&route.property) == Some(&route.value))
+    .map(|route| route.instance_name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{route, InstanceRoute};
+  use std::collections::BTreeMap;
+
+  fn instance_route(property: &str, value: &str, instance_name: &str) -> InstanceRoute {
+    InstanceRoute {
+      property: property.to_owned(),
+      value: value.to_owned(),
+      instance_name: instance_name.to_owned(),
+    }
+  }
+
+  #[test]
+  fn parse() {
+    assert_eq!(
+      "OSFamily=macos:mac-cluster".parse(),
+      Ok(instance_route("OSFamily", "macos", "mac-cluster"))
+    );
+    assert_eq!(
+      "container-image=docker://ubuntu:18.04:projects/p/instances/default".parse(),
+      Ok(instance_route(
+        "container-image",
+        "docker://ubuntu:18.04",
+        "projects/p/instances/default"
+      ))
+    );
+    "OSFamily:mac-cluster"
+      .parse::<InstanceRoute>()
+      .expect_err("Want Err for missing value");
+    "OSFamily=macos"
+      .parse::<InstanceRoute>()
+      .expect_err("Want Err for missing instance name");
+    "=macos:mac-cluster"
+      .parse::<InstanceRoute>()
+      .expect_err("Want Err for missing property");
+  }
+
+  #[test]
+  fn first_matching_route_wins() {
+    let routes = vec![
+      instance_route("pool", "large", "big-machines"),
+      instance_route("OSFamily", "macos", "mac-cluster"),
+    ];
+    let mut platform_properties = BTreeMap::new();
+    assert_eq!(route(&routes, &platform_properties), None);
+
+    platform_properties.insert("OSFamily".to_owned(), "macos".to_owned());
+    assert_eq!(route(&routes, &platform_properties), Some("mac-cluster"));
+
+    platform_properties.insert("pool".to_owned(), "large".to_owned());
+    assert_eq!(route(&routes, &platform_properties), Some("big-machines"));
+  }
+}
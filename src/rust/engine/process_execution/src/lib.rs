@@ -26,18 +26,40 @@
 // Arc<Mutex> can be more clear than needing to grok Orderings:
 #![allow(clippy::mutex_atomic)]
 
-use boxfuture::BoxFuture;
+use boxfuture::{try_future, BoxFuture, Boxable};
 use bytes::Bytes;
+use futures::future::{self, Either, Future};
+use futures_timer::Delay;
+use std::cmp;
 use std::collections::{BTreeMap, BTreeSet};
 use std::ops::AddAssign;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::Duration;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use async_semaphore::AsyncSemaphore;
 
+pub mod circuit_breaker;
+#[cfg(unix)]
+pub mod docker;
+pub mod instance_routing;
 pub mod local;
+#[cfg(unix)]
+pub mod nailgun;
+pub mod named_caches;
+mod process_tree;
+mod process_user;
+#[cfg(unix)]
+mod pty;
 pub mod remote;
+pub mod remote_cache;
+mod resource_limits;
+pub mod retry;
+pub mod speculate;
+mod tools;
+#[cfg(unix)]
+pub mod trace;
+pub mod verification;
 
 ///
 /// A process to be executed.
@@ -65,6 +87,12 @@ pub struct ExecuteProcessRequest {
 
   pub output_files: BTreeSet<PathBuf>,
 
+  ///
+  /// Directories to capture (recursively, including empty directories) from the execution.
+  ///
+  /// For local execution only, these may also be globs (e.g. `dist/**` or `out/*/classes`): see
+  /// `is_output_glob`. Symlinks are captured as the files or directories that they point to.
+  ///
   pub output_directories: BTreeSet<PathBuf>,
 
   pub timeout: std::time::Duration,
@@ -72,14 +100,484 @@ pub struct ExecuteProcessRequest {
   pub description: String,
 
   ///
-  /// If present, a symlink will be created at .jdk which points to this directory for local
-  /// execution, or a system-installed JDK (ignoring the value of the present Some) for remote
+  /// Tools from this machine (e.g. a JDK) to make available to the process, keyed by name: see
+  /// `ToolMount`.
+  ///
+  pub tool_mounts: BTreeMap<String, ToolMount>,
+
+  ///
+  /// Named caches to make available to the process, keyed by name, with the relative paths in the
+  /// sandbox at which they should appear: see `named_caches::NamedCaches`.
+  ///
+  /// Named caches are ignored by remote execution, since they shouldn't affect the results of the
+  /// processes using them.
+  ///
+  pub named_caches: BTreeMap<String, PathBuf>,
+
+  ///
+  /// Limits on the resources that the process may use, which are only enforced for local
   /// execution.
   ///
-  /// This is some technical debt we should clean up;
-  /// see https://github.com/pantsbuild/pants/issues/6416.
+  pub resource_limits: ResourceLimits,
+
+  ///
+  /// If set, the process runs in a stable working directory which is reused by every process with
+  /// the same scope (e.g. to preserve the incremental state of tools like Gradle or Cargo between
+  /// runs), rather than in a fresh sandbox: see `local::CommandRunner`.
+  ///
+  /// Reusable working directories are ignored by remote execution.
+  ///
+  pub reusable_workdir: Option<String>,
+
+  ///
+  /// The names of environment variables to copy from our own environment when the process is
+  /// executed (e.g. `SSH_AUTH_SOCK`, or proxy configuration). Their values are not part of the
+  /// cache key of the process, so they must not affect its outputs.
+  ///
+  /// Processes which use passthrough variables are not remotable: see `is_remotable`.
+  ///
+  pub env_passthrough: BTreeSet<String>,
+
+  ///
+  /// How many of the slots for concurrent local processes the process occupies while it runs
+  /// (e.g. a linker which uses a lot of memory might count as 4), which is at least 1: see
+  /// `BoundedCommandRunner`.
+  ///
+  pub concurrency_weight: usize,
+
+  ///
+  /// Whether to stream the output of the process to the console as it is produced (in addition to
+  /// capturing it), which is useful for long running processes whose output the user wants to
+  /// watch (e.g. test runs). This is only supported by local execution: see
+  /// `local::CommandRunner::with_console`.
+  ///
+  pub tee_output: bool,
+
+  ///
+  /// Platform properties with which to execute the process remotely (e.g. `OSFamily`, `ISA`,
+  /// `container-image` or `Pool`), which select the workers that it may run on. These are merged
+  /// with (and take precedence over) the remote CommandRunner's platform properties, and are
+  /// ignored by local execution.
+  ///
+  pub platform_properties: BTreeMap<String, String>,
+
+  ///
+  /// The priority with which to execute the process remotely, relative to the other actions on the
+  /// cluster: lower values run sooner, and 0 is the server's default. This lets interactive builds
+  /// preempt bulk (e.g. CI) builds on shared clusters. It is not part of the cache key of the
+  /// process, and is ignored by local execution.
+  ///
+  pub priority: i32,
+
+  ///
+  /// gRPC metadata headers to send with the Execute (and WaitExecution) calls for the process, for
+  /// remote execution services which route actions on headers (e.g. `x-worker-pool: gpu`) rather
+  /// than on platform properties. These are merged with (and take precedence over) the remote
+  /// CommandRunner's headers. They are not part of the cache key of the process, and are ignored by
+  /// local execution.
+  ///
+  pub execution_headers: BTreeMap<String, String>,
+}
+
+impl ExecuteProcessRequest {
+  ///
+  /// Whether the process may be executed remotely, which it may not be if it needs anything from
+  /// the environment of this machine. The only tool which remote execution can provide is a JDK
+  /// at `.jdk`, which is supplied by the worker rather than by this machine.
+  ///
+  pub fn is_remotable(&self) -> bool {
+    self.env_passthrough.is_empty()
+      && self
+        .tool_mounts
+        .iter()
+        .all(|(name, mount)| name == JDK_TOOL && mount.sandbox_path == Path::new(JDK_SANDBOX_PATH))
+  }
+
+  ///
+  /// The environment to run the process with: its `env`, plus the current values of its
+  /// passthrough variables (which are skipped if they are unset).
+  ///
+  pub fn env_with_passthrough(&self) -> BTreeMap<String, String> {
+    let mut env = self.env.clone();
+    for name in &self.env_passthrough {
+      if let Ok(value) = std::env::var(name) {
+        env.insert(name.clone(), value);
+      }
+    }
+    env
+  }
+
+  ///
+  /// Checks that the request could be executed, so that bad requests fail with a clear error when
+  /// they are made rather than deep inside of execution: see `ExecuteProcessRequestBuilder`.
+  ///
+  pub fn validate(&self) -> Result<(), String> {
+    let fail = |problem: String| {
+      Err(format!(
+        "Invalid process {:?}: {}",
+        self.description, problem
+      ))
+    };
+    if let Err(problem) = validate_argv_and_env(&self.argv, &self.env) {
+      return fail(problem);
+    }
+    if self.timeout == Duration::from_millis(0) || self.timeout > MAX_TIMEOUT {
+      return fail(format!(
+        "timeout must be positive and at most {:?}, but was {:?}",
+        MAX_TIMEOUT, self.timeout
+      ));
+    }
+    if let Some(name) = self
+      .env_passthrough
+      .iter()
+      .find(|name| !is_valid_env_var_name(name))
+    {
+      return fail(format!(
+        "invalid passthrough environment variable name {:?}",
+        name
+      ));
+    }
+    if self.concurrency_weight == 0 {
+      return fail("concurrency_weight must be at least 1".to_owned());
+    }
+    for (name, value) in &self.execution_headers {
+      if let Err(err) = validate_execution_header(name, value) {
+        return fail(err);
+      }
+    }
+    for output in self.output_files.iter().chain(&self.output_directories) {
+      if output.as_os_str().is_empty()
+        || output.components().any(|component| match component {
+          Component::Normal(_) | Component::CurDir => false,
+          _ => true,
+        })
+      {
+        return fail(format!(
+          "output {:?} must be a relative path within the sandbox",
+          output
+        ));
+      }
+    }
+    Ok(())
+  }
+}
+
+///
+/// The longest timeout that a process may have.
+///
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn is_valid_env_var_name(name: &str) -> bool {
+  !name.is_empty() && !name.contains(|c| c == '=' || c == '\0')
+}
+
+///
+/// Checks the argv and env of a process (whether or not it is interactive), returning the problem
+/// with them if there is one.
+///
+fn validate_argv_and_env(argv: &[String], env: &BTreeMap<String, String>) -> Result<(), String> {
+  match argv.first() {
+    None => return Err("argv must not be empty".to_owned()),
+    Some(binary) if binary.is_empty() => {
+      return Err("the binary to execute must not be empty".to_owned())
+    }
+    Some(_) => {}
+  }
+  for (name, value) in env {
+    if !is_valid_env_var_name(name) || value.contains('\0') {
+      return Err(format!(
+        "invalid environment variable {:?}={:?}",
+        name, value
+      ));
+    }
+  }
+  Ok(())
+}
+
+///
+/// Checks that the given header may be sent with Execute calls: its name must be a valid (lowercase
+/// and non-binary) gRPC header name which isn't reserved by gRPC or used by us, and its value must
+/// be printable ASCII.
+///
+pub fn validate_execution_header(name: &str, value: &str) -> Result<(), String> {
+  if name.is_empty()
+    || !name
+      .chars()
+      .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_' || c == '.')
+  {
+    return Err(format!(
+      "header names must consist of lowercase letters, digits, '-', '_' and '.', but got {:?}",
+      name
+    ));
+  }
+  if name.starts_with("grpc-")
+    || name.ends_with("-bin")
+    || name == "authorization"
+    || name == "te"
+    || name == "content-type"
+    || name == "user-agent"
+  {
+    return Err(format!("header {:?} is reserved", name));
+  }
+  if !value.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+    return Err(format!(
+      "the value of header {:?} must be printable ASCII, but got {:?}",
+      name, value
+    ));
+  }
+  Ok(())
+}
+
+///
+/// Builds ExecuteProcessRequests which are validated when they are built, with defaults for
+/// everything except for the argv, timeout and description.
+///
+#[derive(Clone, Debug)]
+pub struct ExecuteProcessRequestBuilder {
+  req: ExecuteProcessRequest,
+}
+
+impl ExecuteProcessRequestBuilder {
+  pub fn new(
+    argv: Vec<String>,
+    timeout: Duration,
+    description: String,
+  ) -> ExecuteProcessRequestBuilder {
+    ExecuteProcessRequestBuilder {
+      req: ExecuteProcessRequest {
+        argv,
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout,
+        description,
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      },
+    }
+  }
+
+  pub fn env(mut self, env: BTreeMap<String, String>) -> ExecuteProcessRequestBuilder {
+    self.req.env = env;
+    self
+  }
+
+  pub fn input_files(mut self, input_files: hashing::Digest) -> ExecuteProcessRequestBuilder {
+    self.req.input_files = input_files;
+    self
+  }
+
+  pub fn output_files(mut self, output_files: BTreeSet<PathBuf>) -> ExecuteProcessRequestBuilder {
+    self.req.output_files = output_files;
+    self
+  }
+
+  pub fn output_directories(
+    mut self,
+    output_directories: BTreeSet<PathBuf>,
+  ) -> ExecuteProcessRequestBuilder {
+    self.req.output_directories = output_directories;
+    self
+  }
+
+  pub fn tool_mounts(
+    mut self,
+    tool_mounts: BTreeMap<String, ToolMount>,
+  ) -> ExecuteProcessRequestBuilder {
+    self.req.tool_mounts = tool_mounts;
+    self
+  }
+
+  pub fn named_caches(
+    mut self,
+    named_caches: BTreeMap<String, PathBuf>,
+  ) -> ExecuteProcessRequestBuilder {
+    self.req.named_caches = named_caches;
+    self
+  }
+
+  pub fn resource_limits(
+    mut self,
+    resource_limits: ResourceLimits,
+  ) -> ExecuteProcessRequestBuilder {
+    self.req.resource_limits = resource_limits;
+    self
+  }
+
+  pub fn reusable_workdir(mut self, scope: String) -> ExecuteProcessRequestBuilder {
+    self.req.reusable_workdir = Some(scope);
+    self
+  }
+
+  pub fn env_passthrough(mut self, names: BTreeSet<String>) -> ExecuteProcessRequestBuilder {
+    self.req.env_passthrough = names;
+    self
+  }
+
+  pub fn concurrency_weight(mut self, weight: usize) -> ExecuteProcessRequestBuilder {
+    self.req.concurrency_weight = weight;
+    self
+  }
+
+  pub fn tee_output(mut self, tee_output: bool) -> ExecuteProcessRequestBuilder {
+    self.req.tee_output = tee_output;
+    self
+  }
+
+  pub fn platform_properties(
+    mut self,
+    platform_properties: BTreeMap<String, String>,
+  ) -> ExecuteProcessRequestBuilder {
+    self.req.platform_properties = platform_properties;
+    self
+  }
+
+  pub fn priority(mut self, priority: i32) -> ExecuteProcessRequestBuilder {
+    self.req.priority = priority;
+    self
+  }
+
+  pub fn execution_headers(
+    mut self,
+    execution_headers: BTreeMap<String, String>,
+  ) -> ExecuteProcessRequestBuilder {
+    self.req.execution_headers = execution_headers;
+    self
+  }
+
+  pub fn build(self) -> Result<ExecuteProcessRequest, String> {
+    self.req.validate()?;
+    Ok(self.req)
+  }
+}
+
+///
+/// The name of the tool that is a JDK, and the path at which JDKs are conventionally mounted.
+///
+pub const JDK_TOOL: &str = "jdk";
+pub const JDK_SANDBOX_PATH: &str = ".jdk";
+
+///
+/// A tool on this machine which is made available to a process at a stable path in its sandbox,
+/// as a symlink to the tool.
+///
+/// The host path is resolved when the process runs (so it may be a symlink to, for example, the
+/// currently selected version of the tool), and a fingerprint of the tool that it resolves to is
+/// part of the key under which the result of the process is cached: see `tools::resolve`.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ToolMount {
+  pub host_path: PathBuf,
+  ///
+  /// A relative path within the sandbox.
+  ///
+  pub sandbox_path: PathBuf,
+}
+
+///
+/// The tool mounts for an optional JDK home, which is mounted at `.jdk`.
+///
+pub fn jdk_tool_mounts(jdk_home: Option<PathBuf>) -> BTreeMap<String, ToolMount> {
+  jdk_home
+    .into_iter()
+    .map(|host_path| {
+      (
+        JDK_TOOL.to_owned(),
+        ToolMount {
+          host_path,
+          sandbox_path: PathBuf::from(JDK_SANDBOX_PATH),
+        },
+      )
+    })
+    .collect()
+}
+
+///
+/// Limits on the resources used by a process. Limits which are exceeded in a way that can be
+/// detected are reported as the `failure_reason` of the result.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct ResourceLimits {
+  ///
+  /// Enforced by a cgroup (which reports being exceeded) on Linux if cgroups v2 are available and
+  /// delegated to us. Otherwise, enforced as a limit on address space, which causes allocations to
+  /// fail.
+  ///
+  pub memory_bytes: Option<u64>,
+  ///
+  /// Exceeding this causes attempts to open more files to fail, which is not reported.
+  ///
+  pub open_files: Option<u64>,
+  pub cpu_seconds: Option<u64>,
+}
+
+///
+/// A resource limited by ResourceLimits.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResourceLimit {
+  Memory,
+  CpuTime,
+}
+
+///
+/// Why a process failed, if we know more than its exit code.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FailureReason {
+  ResourceLimitExceeded(ResourceLimit),
+  ///
+  /// The process didn't exit within its timeout, and so was terminated.
+  ///
+  Timeout,
+}
+
+///
+/// A process to be run interactively: attached to a pseudo-terminal which is proxied to the client,
+/// rather than having its output captured. Used for goals like `repl` and `run`.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct InteractiveProcessRequest {
+  ///
+  /// The arguments to execute: see `ExecuteProcessRequest::argv`.
+  ///
+  pub argv: Vec<String>,
+
+  ///
+  /// The environment variables to set for the execution: see `ExecuteProcessRequest::env`.
   ///
-  pub jdk_home: Option<PathBuf>,
+  pub env: BTreeMap<String, String>,
+
+  ///
+  /// Materialized into the sandbox in which the process runs.
+  ///
+  pub input_files: hashing::Digest,
+
+  pub description: String,
+}
+
+impl InteractiveProcessRequest {
+  ///
+  /// Checks that the request could be executed: see `ExecuteProcessRequest::validate`.
+  ///
+  pub fn validate(&self) -> Result<(), String> {
+    validate_argv_and_env(&self.argv, &self.env)
+      .map_err(|problem| format!("Invalid process {:?}: {}", self.description, problem))
+  }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InteractiveProcessResult {
+  pub exit_code: i32,
 }
 
 ///
@@ -96,6 +594,37 @@ pub struct FallibleExecuteProcessResult {
   pub output_directory: hashing::Digest,
 
   pub execution_attempts: Vec<ExecutionStats>,
+
+  pub failure_reason: Option<FailureReason>,
+
+  ///
+  /// The resources that the process used, if they were measured (which they are only for local
+  /// execution).
+  ///
+  pub resource_usage: Option<ResourceUsage>,
+
+  ///
+  /// The digest of the trace of the system calls made by the process, if it was run under a
+  /// tracer: see `trace::CommandRunner`.
+  ///
+  pub trace: Option<hashing::Digest>,
+}
+
+///
+/// The resources used by a process, as reported by `wait4`.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ResourceUsage {
+  pub wall_time: Duration,
+  pub user_time: Duration,
+  pub system_time: Duration,
+  pub max_rss_bytes: u64,
+  ///
+  /// The number of times that the filesystem had to perform input (i.e. read from disk rather
+  /// than from the cache).
+  ///
+  pub block_input_operations: u64,
+  pub block_output_operations: u64,
 }
 
 #[cfg(test)]
@@ -104,6 +633,11 @@ impl FallibleExecuteProcessResult {
     self.execution_attempts = vec![];
     self
   }
+
+  pub fn without_resource_usage(mut self) -> Self {
+    self.resource_usage = None;
+    self
+  }
 }
 
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
@@ -112,10 +646,84 @@ pub struct ExecutionStats {
   uploaded_file_count: usize,
   upload: Duration,
   remote_queue: Option<Duration>,
+  // From the worker starting on the action until it started executing it (including fetching
+  // its inputs).
+  remote_worker_setup: Option<Duration>,
   remote_input_fetch: Option<Duration>,
   remote_execution: Option<Duration>,
   remote_output_store: Option<Duration>,
   was_cache_hit: bool,
+  // How many times the stream of updates to the Operation broke, and waiting on it was retried.
+  retries: usize,
+}
+
+///
+/// The total time which the remote executions of a session spent in each phase (queued, setting up
+/// on the worker, executing, and uploading their outputs), according to the ExecutedActionMetadata
+/// of their results, so that it can be told whether slow remote executions are slow because of
+/// the queue or because of the actions. Shared between clones.
+///
+#[derive(Clone, Default)]
+pub struct RemoteExecutionTimings(Arc<Mutex<RemoteExecutionTotals>>);
+
+#[derive(Default)]
+struct RemoteExecutionTotals {
+  executions: usize,
+  queue: Duration,
+  worker_setup: Duration,
+  input_fetch: Duration,
+  execution: Duration,
+  output_upload: Duration,
+}
+
+impl RemoteExecutionTimings {
+  ///
+  /// Adds the timings of the given attempts to execute a process (those which were executed
+  /// remotely, rather than being cache hits).
+  ///
+  pub fn record(&self, attempts: &[ExecutionStats]) {
+    let mut totals = self.0.lock().unwrap();
+    for attempt in attempts {
+      if attempt.remote_execution.is_none() {
+        continue;
+      }
+      totals.executions += 1;
+      totals.queue += attempt.remote_queue.unwrap_or_default();
+      totals.worker_setup += attempt.remote_worker_setup.unwrap_or_default();
+      totals.input_fetch += attempt.remote_input_fetch.unwrap_or_default();
+      totals.execution += attempt.remote_execution.unwrap_or_default();
+      totals.output_upload += attempt.remote_output_store.unwrap_or_default();
+    }
+  }
+
+  pub fn reset(&self) {
+    *self.0.lock().unwrap() = RemoteExecutionTotals::default();
+  }
+
+  ///
+  /// The totals, as metrics: a count of executions, and times in milliseconds.
+  ///
+  pub fn metrics(&self) -> Vec<(&'static str, i64)> {
+    let totals = self.0.lock().unwrap();
+    let millis = |duration: Duration| duration.as_millis() as i64;
+    vec![
+      ("remote_executions", totals.executions as i64),
+      ("remote_execution_queue_time_ms", millis(totals.queue)),
+      (
+        "remote_execution_worker_setup_time_ms",
+        millis(totals.worker_setup),
+      ),
+      (
+        "remote_execution_input_fetch_time_ms",
+        millis(totals.input_fetch),
+      ),
+      ("remote_execution_time_ms", millis(totals.execution)),
+      (
+        "remote_execution_output_upload_time_ms",
+        millis(totals.output_upload),
+      ),
+    ]
+  }
 }
 
 impl AddAssign<fs::UploadSummary> for ExecutionStats {
@@ -126,29 +734,543 @@ impl AddAssign<fs::UploadSummary> for ExecutionStats {
   }
 }
 
+///
+/// Whether the given output directory is a glob, rather than a literal path.
+///
+pub fn is_output_glob(path: &Path) -> bool {
+  path
+    .to_str()
+    .map(|path| path.contains(|c| c == '*' || c == '?' || c == '['))
+    .unwrap_or(false)
+}
+
+///
+/// A platform on which processes may be run.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Platform {
+  Darwin,
+  Linux,
+}
+
+impl Platform {
+  ///
+  /// The platform of this machine.
+  ///
+  pub fn current() -> Result<Platform, String> {
+    if cfg!(target_os = "macos") {
+      Ok(Platform::Darwin)
+    } else if cfg!(target_os = "linux") {
+      Ok(Platform::Linux)
+    } else {
+      Err(format!(
+        "Unsupported platform for process execution: {}",
+        std::env::consts::OS
+      ))
+    }
+  }
+}
+
+///
+/// The platforms which a variant of a MultiPlatformExecuteProcessRequest may run on.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PlatformConstraint {
+  ///
+  /// Any platform.
+  ///
+  None,
+  Darwin,
+  Linux,
+}
+
+impl PlatformConstraint {
+  pub fn matches(self, platform: Platform) -> bool {
+    match (self, platform) {
+      (PlatformConstraint::None, _)
+      | (PlatformConstraint::Darwin, Platform::Darwin)
+      | (PlatformConstraint::Linux, Platform::Linux) => true,
+      _ => false,
+    }
+  }
+}
+
+impl From<Platform> for PlatformConstraint {
+  fn from(platform: Platform) -> PlatformConstraint {
+    match platform {
+      Platform::Darwin => PlatformConstraint::Darwin,
+      Platform::Linux => PlatformConstraint::Linux,
+    }
+  }
+}
+
+///
+/// Variants of a process request (e.g. with different command lines) for different platforms, of
+/// which a CommandRunner runs the variant matching the platform that it runs processes on.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct MultiPlatformExecuteProcessRequest(
+  pub BTreeMap<PlatformConstraint, ExecuteProcessRequest>,
+);
+
+impl MultiPlatformExecuteProcessRequest {
+  ///
+  /// The variant for the given platform: a variant constrained to exactly that platform is
+  /// preferred over an unconstrained variant.
+  ///
+  pub fn select(&self, platform: Platform) -> Option<&ExecuteProcessRequest> {
+    self
+      .0
+      .get(&PlatformConstraint::from(platform))
+      .or_else(|| self.0.get(&PlatformConstraint::None))
+  }
+}
+
+impl From<ExecuteProcessRequest> for MultiPlatformExecuteProcessRequest {
+  fn from(req: ExecuteProcessRequest) -> MultiPlatformExecuteProcessRequest {
+    let mut variants = BTreeMap::new();
+    variants.insert(PlatformConstraint::None, req);
+    MultiPlatformExecuteProcessRequest(variants)
+  }
+}
+
 pub trait CommandRunner: Send + Sync {
   fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String>;
+
+  ///
+  /// The platform on which this CommandRunner runs processes, which by default is this machine's.
+  ///
+  fn platform(&self) -> Result<Platform, String> {
+    Platform::current()
+  }
+
+  ///
+  /// Runs the variant of the given request which matches `platform`.
+  ///
+  fn run_multi_platform(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let platform = try_future!(self.platform());
+    match req.select(platform) {
+      Some(req) => self.run(req.clone()),
+      None => future::err(format!(
+        "No variant of the process request is compatible with {:?}: got variants for {:?}",
+        platform,
+        req.0.keys().collect::<Vec<_>>()
+      ))
+      .to_boxed(),
+    }
+  }
+}
+
+///
+/// The time by which processes must have completed (e.g. the end of a session's budget), which
+/// may be set and cleared after it has been handed to a BoundedCommandRunner.
+///
+#[derive(Clone, Default)]
+pub struct Deadline(Arc<Mutex<Option<Instant>>>);
+
+impl Deadline {
+  pub fn set(&self, deadline: Option<Instant>) {
+    *self.0.lock().unwrap() = deadline;
+  }
+
+  pub fn get(&self) -> Option<Instant> {
+    *self.0.lock().unwrap()
+  }
 }
 
 ///
 /// A CommandRunner wrapper that limits the number of concurrent requests.
 ///
+/// If concurrency weights are enabled (which they should be for processes running on this
+/// machine), each request occupies as many slots as its `concurrency_weight`, capped at the bound
+/// so that heavyweight requests can still run (alone).
+///
+/// If a deadline is set, each request's timeout is capped at the time remaining when it starts,
+/// and requests which are still waiting to start when it passes fail.
+///
 #[derive(Clone)]
 pub struct BoundedCommandRunner {
   inner: Arc<(Box<dyn CommandRunner>, AsyncSemaphore)>,
+  bound: usize,
+  weighted: bool,
+  deadline: Deadline,
 }
 
 impl BoundedCommandRunner {
   pub fn new(inner: Box<dyn CommandRunner>, bound: usize) -> BoundedCommandRunner {
     BoundedCommandRunner {
       inner: Arc::new((inner, AsyncSemaphore::new(bound))),
+      bound,
+      weighted: false,
+      deadline: Deadline::default(),
+    }
+  }
+
+  pub fn with_concurrency_weights(mut self) -> BoundedCommandRunner {
+    self.weighted = true;
+    self
+  }
+
+  pub fn with_deadline(mut self, deadline: Deadline) -> BoundedCommandRunner {
+    self.deadline = deadline;
+    self
+  }
+
+  fn slots_for(&self, req: &ExecuteProcessRequest) -> usize {
+    if self.weighted {
+      cmp::max(1, cmp::min(req.concurrency_weight, self.bound))
+    } else {
+      1
     }
   }
 }
 
+fn deadline_exceeded(description: &str) -> String {
+  format!("Deadline exceeded before {} could start", description)
+}
+
 impl CommandRunner for BoundedCommandRunner {
   fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
     let inner = self.inner.clone();
-    self.inner.1.with_acquired(move || inner.0.run(req))
+    let acquired = self
+      .inner
+      .1
+      .acquire_many(self.slots_for(&req))
+      .map_err(String::from);
+    let deadline = match self.deadline.get() {
+      Some(deadline) => deadline,
+      None => {
+        return acquired
+          .and_then(move |permit| {
+            inner.0.run(req).then(move |result| {
+              drop(permit);
+              result
+            })
+          })
+          .to_boxed()
+      }
+    };
+    if Instant::now() >= deadline {
+      return future::err(deadline_exceeded(&req.description)).to_boxed();
+    }
+    let description = req.description.clone();
+    acquired
+      .select2(Delay::new_at(deadline))
+      .then(move |res| match res {
+        Ok(Either::A((permit, _))) => Ok(permit),
+        Ok(Either::B(((), _))) => Err(deadline_exceeded(&description)),
+        Err(Either::A((err, _))) => Err(err),
+        Err(Either::B((err, _))) => Err(format!("Error waiting for deadline: {}", err)),
+      })
+      .and_then(move |permit| {
+        // The deadline may have passed while the permit was being handed over.
+        let now = Instant::now();
+        if now >= deadline {
+          return future::err(deadline_exceeded(&req.description)).to_boxed();
+        }
+        let req = ExecuteProcessRequest {
+          timeout: cmp::min(req.timeout, deadline - now),
+          ..req
+        };
+        inner
+          .0
+          .run(req)
+          .then(move |result| {
+            drop(permit);
+            result
+          })
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  fn platform(&self) -> Result<Platform, String> {
+    self.inner.0.platform()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{
+    BoundedCommandRunner, CommandRunner, Deadline, ExecuteProcessRequest,
+    ExecuteProcessRequestBuilder, ExecutionStats, FallibleExecuteProcessResult,
+    MultiPlatformExecuteProcessRequest, Platform, PlatformConstraint, RemoteExecutionTimings,
+  };
+  use boxfuture::{BoxFuture, Boxable};
+  use bytes::Bytes;
+  use futures::{future, Future};
+  use futures_timer::Delay;
+  use std::collections::{BTreeMap, BTreeSet};
+  use std::path::PathBuf;
+  use std::sync::{Arc, Mutex};
+  use std::time::{Duration, Instant};
+
+  #[test]
+  fn select_platform_variant() {
+    let req = |argv: &str| ExecuteProcessRequest {
+      argv: vec![argv.to_owned()],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "select".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    };
+
+    let mut variants = BTreeMap::new();
+    variants.insert(PlatformConstraint::Darwin, req("darwin"));
+    let multi = MultiPlatformExecuteProcessRequest(variants.clone());
+    assert_eq!(multi.select(Platform::Darwin), Some(&req("darwin")));
+    assert_eq!(multi.select(Platform::Linux), None);
+
+    variants.insert(PlatformConstraint::None, req("any"));
+    let multi = MultiPlatformExecuteProcessRequest(variants);
+    assert_eq!(multi.select(Platform::Darwin), Some(&req("darwin")));
+    assert_eq!(multi.select(Platform::Linux), Some(&req("any")));
+  }
+
+  #[test]
+  fn builder_validates() {
+    let builder = || {
+      ExecuteProcessRequestBuilder::new(
+        vec!["/bin/echo".to_owned()],
+        Duration::from_secs(1),
+        "echo".to_owned(),
+      )
+    };
+    let req = builder().build().unwrap();
+    assert_eq!(req.argv, vec!["/bin/echo".to_owned()]);
+    assert_eq!(req.input_files, fs::EMPTY_DIGEST);
+
+    ExecuteProcessRequestBuilder::new(vec![], Duration::from_secs(1), "empty".to_owned())
+      .build()
+      .expect_err("Want Err for empty argv");
+    ExecuteProcessRequestBuilder::new(
+      vec!["/bin/echo".to_owned()],
+      Duration::from_millis(0),
+      "no time".to_owned(),
+    )
+    .build()
+    .expect_err("Want Err for zero timeout");
+
+    let output = |path: &str| {
+      vec![PathBuf::from(path)]
+        .into_iter()
+        .collect::<BTreeSet<_>>()
+    };
+    builder()
+      .output_files(output("../escape"))
+      .build()
+      .expect_err("Want Err for output outside of the sandbox");
+    builder()
+      .output_directories(output("/abs"))
+      .build()
+      .expect_err("Want Err for absolute output");
+    builder()
+      .output_directories(output("dist/**"))
+      .build()
+      .unwrap();
+
+    let mut env = BTreeMap::new();
+    env.insert("A=B".to_owned(), "c".to_owned());
+    builder()
+      .env(env)
+      .build()
+      .expect_err("Want Err for invalid env var name");
+
+    builder()
+      .concurrency_weight(0)
+      .build()
+      .expect_err("Want Err for zero concurrency weight");
+
+    let headers = |name: &str, value: &str| {
+      let mut headers = BTreeMap::new();
+      headers.insert(name.to_owned(), value.to_owned());
+      headers
+    };
+    builder()
+      .execution_headers(headers("x-worker-pool", "gpu"))
+      .build()
+      .unwrap();
+    builder()
+      .execution_headers(headers("X-Worker-Pool", "gpu"))
+      .build()
+      .expect_err("Want Err for uppercase header name");
+    builder()
+      .execution_headers(headers("authorization", "Bearer sneaky"))
+      .build()
+      .expect_err("Want Err for reserved header");
+    builder()
+      .execution_headers(headers("x-worker-pool", "gpu\n"))
+      .build()
+      .expect_err("Want Err for unprintable header value");
+  }
+
+  #[test]
+  fn bounded_runner_weights() {
+    let max_running = |weighted: bool, weight: usize| {
+      let inner = ConcurrencyCountingRunner::default();
+      let counts = inner.counts.clone();
+      let mut runner = BoundedCommandRunner::new(Box::new(inner), 4);
+      if weighted {
+        runner = runner.with_concurrency_weights();
+      }
+      let runs = (0..4)
+        .map(|_| {
+          runner.run(
+            ExecuteProcessRequestBuilder::new(
+              vec!["/bin/true".to_owned()],
+              Duration::from_secs(1),
+              "weighted".to_owned(),
+            )
+            .concurrency_weight(weight)
+            .build()
+            .unwrap(),
+          )
+        })
+        .collect::<Vec<_>>();
+      future::join_all(runs).wait().unwrap();
+      let counts = counts.lock().unwrap();
+      counts.1
+    };
+
+    assert_eq!(max_running(true, 1), 4);
+    assert_eq!(max_running(true, 2), 2);
+    // Weights above the bound are capped, rather than never running.
+    assert_eq!(max_running(true, 10), 1);
+    assert_eq!(max_running(false, 2), 4);
+  }
+
+  #[test]
+  fn bounded_runner_deadline() {
+    let inner = ConcurrencyCountingRunner::default();
+    let timeouts = inner.timeouts.clone();
+    let deadline = Deadline::default();
+    let runner = BoundedCommandRunner::new(Box::new(inner), 1).with_deadline(deadline.clone());
+    let req = || {
+      ExecuteProcessRequestBuilder::new(
+        vec!["/bin/true".to_owned()],
+        Duration::from_secs(60 * 60),
+        "deadlined".to_owned(),
+      )
+      .build()
+      .unwrap()
+    };
+
+    // Processes run one at a time for 20ms each, so only the first few start before the deadline.
+    deadline.set(Some(Instant::now() + Duration::from_millis(100)));
+    let runs = (0..20)
+      .map(|_| runner.run(req()).then(Ok::<_, ()>))
+      .collect::<Vec<_>>();
+    let results = future::join_all(runs).wait().unwrap();
+    let started = results.iter().filter(|result| result.is_ok()).count();
+    assert!(started > 0 && started < 20, "{} processes started", started);
+    assert!(results
+      .iter()
+      .filter_map(|result| result.as_ref().err())
+      .all(|err| err == "Deadline exceeded before deadlined could start"));
+    // Each timeout was capped at the time remaining when its process started.
+    assert!(timeouts
+      .lock()
+      .unwrap()
+      .iter()
+      .all(|timeout| *timeout <= Duration::from_millis(100)));
+
+    // Once the deadline has passed, processes fail without waiting.
+    runner
+      .run(req())
+      .wait()
+      .expect_err("Want Err after deadline");
+
+    deadline.set(None);
+    runner.run(req()).wait().unwrap();
+    assert_eq!(
+      timeouts.lock().unwrap().last(),
+      Some(&Duration::from_secs(60 * 60))
+    );
+  }
+
+  #[test]
+  fn remote_execution_timings_count_remote_executions() {
+    let secs = |secs| Some(Duration::from_secs(secs));
+    let executed = ExecutionStats {
+      remote_queue: secs(10),
+      remote_worker_setup: secs(3),
+      remote_input_fetch: secs(2),
+      remote_execution: secs(5),
+      remote_output_store: secs(1),
+      ..ExecutionStats::default()
+    };
+    let cache_hit = ExecutionStats {
+      was_cache_hit: true,
+      ..ExecutionStats::default()
+    };
+
+    let timings = RemoteExecutionTimings::default();
+    timings.record(&[executed, executed]);
+    timings.clone().record(&[cache_hit]);
+    let metrics = timings.metrics().into_iter().collect::<BTreeMap<_, _>>();
+    assert_eq!(metrics["remote_executions"], 2);
+    assert_eq!(metrics["remote_execution_queue_time_ms"], 20_000);
+    assert_eq!(metrics["remote_execution_worker_setup_time_ms"], 6_000);
+    assert_eq!(metrics["remote_execution_input_fetch_time_ms"], 4_000);
+    assert_eq!(metrics["remote_execution_time_ms"], 10_000);
+    assert_eq!(metrics["remote_execution_output_upload_time_ms"], 2_000);
+
+    timings.reset();
+    let metrics = timings.metrics().into_iter().collect::<BTreeMap<_, _>>();
+    assert_eq!(metrics["remote_executions"], 0);
+    assert_eq!(metrics["remote_execution_queue_time_ms"], 0);
+  }
+
+  ///
+  /// A CommandRunner whose processes each take a little while, which records the (current, and
+  /// maximum) numbers of them running concurrently, and their timeouts.
+  ///
+  #[derive(Default)]
+  struct ConcurrencyCountingRunner {
+    counts: Arc<Mutex<(usize, usize)>>,
+    timeouts: Arc<Mutex<Vec<Duration>>>,
+  }
+
+  impl CommandRunner for ConcurrencyCountingRunner {
+    fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
+      self.timeouts.lock().unwrap().push(req.timeout);
+      let counts = self.counts.clone();
+      let counts2 = self.counts.clone();
+      future::lazy(move || {
+        let mut counts = counts.lock().unwrap();
+        counts.0 += 1;
+        counts.1 = std::cmp::max(counts.0, counts.1);
+        Delay::new(Duration::from_millis(20))
+      })
+      .then(move |_| {
+        counts2.lock().unwrap().0 -= 1;
+        Ok(FallibleExecuteProcessResult {
+          stdout: Bytes::new(),
+          stderr: Bytes::new(),
+          exit_code: 0,
+          output_directory: fs::EMPTY_DIGEST,
+          execution_attempts: vec![],
+          failure_reason: None,
+          resource_usage: None,
+          trace: None,
+        })
+      })
+      .to_boxed()
+    }
   }
 }
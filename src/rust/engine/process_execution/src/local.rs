@@ -1,30 +1,198 @@
 use log;
 use tempfile;
 
+use async_semaphore::KeyedSemaphore;
 use boxfuture::{try_future, BoxFuture, Boxable};
-use fs::{self, GlobExpansionConjunction, GlobMatching, PathGlobs, Snapshot, StrictGlobMatching};
-use futures::{future, Future, Stream};
-use log::info;
-use std::collections::{BTreeSet, HashSet};
+use fs::{
+  self, GlobExpansionConjunction, GlobMatching, MaterializationStrategy, PathGlobs, Snapshot,
+  StrictGlobMatching,
+};
+use futures::future::{self, Either};
+use futures::{Future, Stream};
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::ffi::OsStr;
 use std::fs::create_dir_all;
+use std::io::{Read, Write};
 use std::ops::Neg;
-use std::os::unix::{fs::symlink, process::ExitStatusExt};
-use std::path::{Path, PathBuf};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::{CommandExt as UnixCommandExt, ExitStatusExt};
+use std::path::{Component, Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::sync::mpsc;
+use futures_timer::Delay;
+
+use super::named_caches::NamedCaches;
+use super::process_tree::{self, ProcessTree};
+use super::process_user::PreparedUser;
+use super::pty::Pty;
+use super::resource_limits::{self, Cgroup};
+use super::tools;
+use super::{
+  is_output_glob, ExecuteProcessRequest, ExecutionStats, FailureReason,
+  FallibleExecuteProcessResult, InteractiveProcessRequest, InteractiveProcessResult, ResourceLimit,
+  ResourceLimits, ResourceUsage,
+};
 
-use tokio_codec::{BytesCodec, FramedRead};
-use tokio_process::CommandExt;
+use bytes::{Bytes, BytesMut};
+use hashing::{Digest, Fingerprint};
+use protobuf::Message;
 
-use super::{ExecuteProcessRequest, FallibleExecuteProcessResult};
+///
+/// How long a process which has exceeded its timeout is given to exit after being sent SIGTERM,
+/// before its process group is sent SIGKILL.
+///
+const TERMINATION_GRACE_PERIOD: Duration = Duration::from_secs(3);
 
-use bytes::{Bytes, BytesMut};
+///
+/// The output streams of a process.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputStream {
+  Stdout,
+  Stderr,
+}
+
+impl OutputStream {
+  fn name(self) -> &'static str {
+    match self {
+      OutputStream::Stdout => "stdout",
+      OutputStream::Stderr => "stderr",
+    }
+  }
+}
+
+///
+/// Caps on how much output each process may produce, so that a runaway process can't exhaust our
+/// memory: see `CommandRunner::with_output_limits`.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct OutputLimits {
+  ///
+  /// The most bytes of each of stdout and stderr to hold in memory, which is handled according to
+  /// the `policy` when it is exceeded.
+  ///
+  pub stdio_bytes: Option<usize>,
+  ///
+  /// The most bytes of output files (in total) that a process may capture. Exceeding this always
+  /// fails the process, because truncating files would corrupt them.
+  ///
+  pub output_file_bytes: Option<u64>,
+  pub policy: OutputLimitPolicy,
+}
+
+///
+/// What to do with a stream of output which exceeds `OutputLimits::stdio_bytes`.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputLimitPolicy {
+  ///
+  /// Kill the process, and fail its execution.
+  ///
+  Fail,
+  ///
+  /// Discard the rest of the stream, and append a marker recording how much was discarded.
+  ///
+  Truncate,
+  ///
+  /// Write the stream to disk once it exceeds the limit, and then store it in full in the Store,
+  /// keeping only its start (and a marker containing its digest) in the result.
+  ///
+  Spill,
+}
+
+impl Default for OutputLimitPolicy {
+  fn default() -> OutputLimitPolicy {
+    OutputLimitPolicy::Fail
+  }
+}
 
+///
+/// When to keep the sandboxes of local processes once they have completed (rather than deleting
+/// them), for debugging. Every sandbox contains a `__run.sh` script which reproduces its process.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeepSandboxes {
+  Never,
+  OnFailure,
+  Always,
+}
+
+impl FromStr for KeepSandboxes {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<KeepSandboxes, String> {
+    match s {
+      "never" => Ok(KeepSandboxes::Never),
+      "on-failure" => Ok(KeepSandboxes::OnFailure),
+      "always" => Ok(KeepSandboxes::Always),
+      _ => Err(format!(
+        "Expected one of never, on-failure or always to keep sandboxes, but got {:?}",
+        s
+      )),
+    }
+  }
+}
+
+///
+/// Who local processes run as, when it shouldn't be the user running us: see
+/// `CommandRunner::with_process_user`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProcessUser {
+  ///
+  /// Switch to the given uid and gid, which requires us to be privileged (e.g. root, or to have
+  /// CAP_SETUID and CAP_SETGID). Sandboxes are given to the user before processes run, but named
+  /// caches and tools must already be usable by it.
+  ///
+  Ids { uid: u32, gid: u32 },
+  ///
+  /// Linux only: keep our own uid and gid, but run in new user and mount namespaces in which the
+  /// given paths (e.g. our home directory) are hidden beneath empty tmpfs mounts. Sandboxes, named
+  /// caches and tools beneath hidden paths remain visible. This doesn't need privileges, but does
+  /// need unprivileged user namespaces to be enabled.
+  ///
+  Namespaced { hidden_paths: Vec<PathBuf> },
+}
+
+///
+/// Called for each line of output of a process as it is produced, with the description of the
+/// process. Lines include their trailing newline, except for a final unterminated line.
+///
+pub type OutputListener = Arc<dyn Fn(&str, OutputStream, &[u8]) + Send + Sync>;
+
+///
+/// An attempt by a process (or by the request for it) to depend on or affect the machine outside
+/// of its sandbox, recorded by a CommandRunner with strict hermeticity enabled.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HermeticityViolation {
+  pub process_description: String,
+  pub violation: String,
+}
+
+#[derive(Clone)]
 pub struct CommandRunner {
   store: fs::Store,
   work_dir: PathBuf,
-  cleanup_local_dirs: bool,
+  keep_sandboxes: KeepSandboxes,
+  output_listener: Option<OutputListener>,
+  console: Option<OutputListener>,
+  named_caches: Option<NamedCaches>,
+  action_cache: bool,
+  materialization_strategy: MaterializationStrategy,
+  output_limits: OutputLimits,
+  process_user: Option<ProcessUser>,
+  // Processes sharing a reusable workdir must run one at a time.
+  reusable_workdir_locks: KeyedSemaphore<String>,
+  // Only present if strict hermeticity is enabled.
+  hermeticity_violations: Option<Arc<Mutex<Vec<HermeticityViolation>>>>,
 }
 
 impl CommandRunner {
@@ -32,19 +200,236 @@ impl CommandRunner {
     CommandRunner {
       store,
       work_dir,
-      cleanup_local_dirs,
+      keep_sandboxes: if cleanup_local_dirs {
+        KeepSandboxes::Never
+      } else {
+        KeepSandboxes::Always
+      },
+      output_listener: None,
+      console: None,
+      named_caches: None,
+      action_cache: false,
+      materialization_strategy: MaterializationStrategy::Copy,
+      output_limits: OutputLimits::default(),
+      process_user: None,
+      reusable_workdir_locks: KeyedSemaphore::new(1),
+      hermeticity_violations: None,
+    }
+  }
+
+  ///
+  /// Streams the output of processes to the given listener as it is produced, in addition to
+  /// collecting it into their results.
+  ///
+  pub fn with_output_listener(mut self, output_listener: OutputListener) -> CommandRunner {
+    self.output_listener = Some(output_listener);
+    self
+  }
+
+  ///
+  /// Streams the output of processes which request it (see `ExecuteProcessRequest::tee_output`)
+  /// to the given console. Processes whose results were cached replay their output to it.
+  ///
+  pub fn with_console(mut self, console: OutputListener) -> CommandRunner {
+    self.console = Some(console);
+    self
+  }
+
+  ///
+  /// The listener for the output of the given request: the output listener (if any), and the
+  /// console if the request tees its output.
+  ///
+  fn output_listener_for(&self, req: &ExecuteProcessRequest) -> Option<OutputListener> {
+    let console = if req.tee_output {
+      self.console.clone()
+    } else {
+      None
+    };
+    match (self.output_listener.clone(), console) {
+      (Some(listener), Some(console)) => Some(Arc::new(
+        move |description: &str, stream: OutputStream, line: &[u8]| {
+          listener(description, stream, line);
+          console(description, stream, line);
+        },
+      )),
+      (listener, console) => listener.or(console),
     }
   }
 
+  ///
+  /// Makes the given named caches available to processes which request them.
+  ///
+  pub fn with_named_caches(mut self, named_caches: NamedCaches) -> CommandRunner {
+    self.named_caches = Some(named_caches);
+    self
+  }
+
+  ///
+  /// Caches the results of processes which exit successfully in the Store, so that re-running them
+  /// (even after a restart) doesn't need to run them again. This is only safe for hermetic
+  /// processes: see `with_strict_hermeticity`.
+  ///
+  pub fn with_action_cache(mut self) -> CommandRunner {
+    self.action_cache = true;
+    self
+  }
+
+  ///
+  /// Lays out the inputs of processes in their sandboxes using the given strategy, rather than by
+  /// copying them: see `fs::MaterializationStrategy`.
+  ///
+  pub fn with_materialization_strategy(
+    mut self,
+    materialization_strategy: MaterializationStrategy,
+  ) -> CommandRunner {
+    self.materialization_strategy = materialization_strategy;
+    self
+  }
+
+  ///
+  /// Overrides whether sandboxes are kept (which is otherwise always or never, depending on
+  /// whether local dirs are cleaned up): see `KeepSandboxes`.
+  ///
+  pub fn with_keep_sandboxes(mut self, keep_sandboxes: KeepSandboxes) -> CommandRunner {
+    self.keep_sandboxes = keep_sandboxes;
+    self
+  }
+
+  ///
+  /// Caps the output of processes: see `OutputLimits`. Output streamed to the output listener (if
+  /// any) is capped too.
+  ///
+  pub fn with_output_limits(mut self, output_limits: OutputLimits) -> CommandRunner {
+    self.output_limits = output_limits;
+    self
+  }
+
+  ///
+  /// Runs processes as the given user, so that untrusted processes can't read our credentials or
+  /// home directory: see `ProcessUser`. Interactive processes still run as us.
+  ///
+  pub fn with_process_user(mut self, process_user: ProcessUser) -> CommandRunner {
+    self.process_user = Some(process_user);
+    self
+  }
+
+  ///
+  /// Runs the given process interactively in a sandbox containing its inputs, with a
+  /// pseudo-terminal as its stdio which is proxied to the given input and output (i.e. to the
+  /// client).
+  ///
+  pub fn run_interactive<I, O>(
+    &self,
+    req: InteractiveProcessRequest,
+    input: I,
+    output: O,
+  ) -> BoxFuture<InteractiveProcessResult, String>
+  where
+    I: Read + Send + 'static,
+    O: Write + Send + 'static,
+  {
+    try_future!(req.validate());
+    let workdir = try_future!(tempfile::Builder::new()
+      .prefix("interactive-process")
+      .tempdir_in(&self.work_dir)
+      .map_err(|err| format!(
+        "Error making tempdir for interactive process execution: {:?}",
+        err
+      )));
+    let workdir_path = workdir.path().to_owned();
+    let InteractiveProcessRequest {
+      argv,
+      env,
+      input_files,
+      description,
+    } = req;
+    self
+      .store
+      .materialize_directory(workdir_path.clone(), input_files)
+      .and_then(move |()| {
+        let pty = Pty::open()?;
+        let mut command = Command::new(&argv[0]);
+        command
+          .args(&argv[1..])
+          .current_dir(&workdir_path)
+          .env_clear()
+          // See StreamedHermeticCommand.
+          .env("PATH", "")
+          .envs(env);
+        let child = pty.spawn(command)?;
+        let output_proxy = pty.proxy(input, output)?;
+        Ok((child, output_proxy))
+      })
+      .and_then(move |(child, output_proxy)| {
+        child
+          .map_err(move |err| format!("Error waiting for {}: {}", description, err))
+          .map(move |exit_status| {
+            // Ensure that all output has reached the client before completing.
+            let _ = output_proxy.join();
+            // The sandbox is removed once the process has exited.
+            drop(workdir);
+            InteractiveProcessResult {
+              exit_code: exit_status
+                .code()
+                .or_else(|| exit_status.signal().map(Neg::neg))
+                .expect("Child process should exit via returned code or signal."),
+            }
+          })
+      })
+      .to_boxed()
+  }
+
+  pub(crate) fn named_caches(&self) -> Option<&NamedCaches> {
+    self.named_caches.as_ref()
+  }
+
+  ///
+  /// Runs processes with strict hermeticity, so that their results are safe to share with other
+  /// machines (e.g. via a remote cache). In addition to the scrubbed environment and private TMPDIR
+  /// that all processes get, processes are given:
+  ///  * a private TMPDIR even if their request overrides it.
+  ///  * a PATH containing only the unique absolute entries of the PATH in their request (if any).
+  ///
+  /// Attempted violations (overriding TMPDIR, TEMP or TMP, relative PATH entries, and outputs
+  /// which are symlinks to paths outside of the sandbox) are logged, and recorded for
+  /// `hermeticity_violations`.
+  ///
+  pub fn with_strict_hermeticity(mut self) -> CommandRunner {
+    self.hermeticity_violations = Some(Arc::new(Mutex::new(Vec::new())));
+    self
+  }
+
+  ///
+  /// The hermeticity violations recorded so far, which will always be empty unless strict
+  /// hermeticity is enabled.
+  ///
+  pub fn hermeticity_violations(&self) -> Vec<HermeticityViolation> {
+    self
+      .hermeticity_violations
+      .as_ref()
+      .map(|violations| violations.lock().unwrap().clone())
+      .unwrap_or_else(Vec::new)
+  }
+
+  ///
+  /// Captures the outputs of a process from its sandbox, failing (before anything is stored) if
+  /// the output files total more than `output_file_bytes`.
+  ///
   fn construct_output_snapshot(
     store: fs::Store,
     posix_fs: Arc<fs::PosixFS>,
+    sandbox: PathBuf,
     output_file_paths: BTreeSet<PathBuf>,
     output_dir_paths: BTreeSet<PathBuf>,
+    output_file_bytes: Option<u64>,
   ) -> BoxFuture<Snapshot, String> {
     let output_paths: Result<Vec<String>, String> = output_dir_paths
       .into_iter()
       .flat_map(|p| {
+        // A glob which is already recursive doesn't need its contents matched separately.
+        if p.ends_with("**") {
+          return vec![p.into_os_string()];
+        }
         let mut dir_glob = p.into_os_string();
         let dir = dir_glob.clone();
         dir_glob.push("/**");
@@ -67,7 +452,12 @@ impl CommandRunner {
     posix_fs
       .expand(output_globs)
       .map_err(|err| format!("Error expanding output globs: {}", err))
-      .and_then(|path_stats| {
+      .and_then(move |path_stats| {
+        try_future!(check_output_file_bytes(
+          &sandbox,
+          &path_stats,
+          output_file_bytes
+        ));
         fs::Snapshot::from_path_stats(
           store.clone(),
           &fs::OneOffStoreFileByDigest::new(store, posix_fs),
@@ -78,6 +468,112 @@ impl CommandRunner {
   }
 }
 
+///
+/// Records hermeticity violations for a single process.
+///
+#[derive(Clone)]
+struct ViolationRecorder {
+  process_description: String,
+  violations: Arc<Mutex<Vec<HermeticityViolation>>>,
+}
+
+impl ViolationRecorder {
+  fn record(&self, violation: String) {
+    warn!(
+      "Hermeticity violation by local process execution {:?}: {}",
+      self.process_description, violation
+    );
+    self.violations.lock().unwrap().push(HermeticityViolation {
+      process_description: self.process_description.clone(),
+      violation,
+    });
+  }
+
+  ///
+  /// Replaces the temp directories and PATH in the given environment with hermetic values.
+  ///
+  fn sanitize_env(&self, env: &mut BTreeMap<String, String>, private_tmpdir: &Path) {
+    for name in TMPDIR_ENV_VARS {
+      if let Some(tmpdir) = env.remove(*name) {
+        self.record(format!("Attempted to set {} to {:?}", name, tmpdir));
+      }
+      env.insert(
+        (*name).to_owned(),
+        private_tmpdir.to_string_lossy().into_owned(),
+      );
+    }
+
+    if let Some(path) = env.remove("PATH") {
+      let mut entries: Vec<PathBuf> = vec![];
+      for entry in std::env::split_paths(&path) {
+        if !entry.is_absolute() {
+          self.record(format!("Relative PATH entry {:?} was removed", entry));
+        } else if !entries.contains(&entry) {
+          entries.push(entry);
+        }
+      }
+      let path = std::env::join_paths(entries)
+        .map_err(|err| format!("Error joining PATH entries: {}", err))
+        .and_then(|path| {
+          path
+            .into_string()
+            .map_err(|path| format!("Non-UTF8 PATH: {:?}", path))
+        });
+      match path {
+        Ok(path) => {
+          env.insert("PATH".to_owned(), path);
+        }
+        Err(err) => self.record(err),
+      }
+    }
+  }
+
+  ///
+  /// Records any of the given output paths (or paths beneath output directories) which are
+  /// symlinks to paths outside of the sandbox.
+  ///
+  fn check_outputs(&self, sandbox: &Path, output_paths: &[PathBuf]) {
+    let sandbox = match sandbox.canonicalize() {
+      Ok(sandbox) => sandbox,
+      Err(err) => {
+        warn!(
+          "Could not canonicalize sandbox {:?} to check outputs for hermeticity: {}",
+          sandbox, err
+        );
+        return;
+      }
+    };
+    let mut to_check: Vec<PathBuf> = output_paths.iter().map(|p| sandbox.join(p)).collect();
+    while let Some(path) = to_check.pop() {
+      let metadata = match std::fs::symlink_metadata(&path) {
+        Ok(metadata) => metadata,
+        // Missing outputs are not a hermeticity violation.
+        Err(_) => continue,
+      };
+      if metadata.file_type().is_symlink() {
+        // A dangling symlink can't leak anything from outside of the sandbox.
+        if let Ok(target) = path.canonicalize() {
+          if !target.starts_with(&sandbox) {
+            self.record(format!(
+              "Output {:?} is a symlink to {:?}, which is outside of the sandbox",
+              path.strip_prefix(&sandbox).unwrap_or(&path),
+              target
+            ));
+          }
+        }
+      } else if metadata.is_dir() {
+        if let Ok(entries) = std::fs::read_dir(&path) {
+          to_check.extend(
+            entries
+              .filter_map(|entry| entry.ok())
+              .map(|entry| entry.path()),
+          );
+        }
+      }
+    }
+  }
+}
+
 struct StreamedHermeticCommand {
   inner: Command,
 }
@@ -89,7 +585,7 @@ struct StreamedHermeticCommand {
 enum ChildOutput {
   Stdout(Bytes),
   Stderr(Bytes),
-  Exit(i32),
+  Exit(i32, ResourceUsage),
 }
 
 ///
@@ -103,6 +599,9 @@ impl StreamedHermeticCommand {
       // It would be really nice not to have to manually set PATH but this is sadly the only way
       // to stop automatic PATH searching.
       .env("PATH", "");
+    // Run the child as the root of its own process tree, so that if it times out, it can be killed
+    // along with any processes that it has spawned.
+    process_tree::isolate(&mut inner);
     StreamedHermeticCommand { inner }
   }
 
@@ -130,38 +629,127 @@ impl StreamedHermeticCommand {
     self
   }
 
-  fn stream(&mut self) -> Result<impl Stream<Item = ChildOutput, Error = String> + Send, String> {
+  ///
+  /// Applies the given limits to the child (and moves it into the given cgroup, if any) before it
+  /// execs.
+  ///
+  fn resource_limits(
+    &mut self,
+    limits: ResourceLimits,
+    cgroup: Option<Arc<Cgroup>>,
+  ) -> &mut StreamedHermeticCommand {
+    if limits == ResourceLimits::default() && cgroup.is_none() {
+      return self;
+    }
+    unsafe {
+      self.inner.before_exec(move || {
+        if let Some(ref cgroup) = cgroup {
+          cgroup.enter()?;
+        }
+        resource_limits::set_rlimits(&limits)
+      });
+    }
+    self
+  }
+
+  ///
+  /// Switches the child to the given user before it execs. This is registered after any resource
+  /// limits, because entering a cgroup needs our own permissions (which rules out
+  /// `CommandExt::uid`, which switches before any `before_exec` callbacks run).
+  ///
+  fn user(&mut self, user: Option<PreparedUser>) -> &mut StreamedHermeticCommand {
+    if let Some(user) = user {
+      unsafe {
+        self.inner.before_exec(move || user.enter());
+      }
+    }
     self
+  }
+
+  ///
+  /// Spawns the command, returning the tree of processes rooted at the child, and a stream of its
+  /// outputs.
+  ///
+  /// The child is reaped by a thread of our own (rather than by tokio-process), so that its
+  /// resource usage can be collected: see `ProcessTree::wait`. Its outputs are read by threads
+  /// too.
+  ///
+  fn stream(
+    &mut self,
+  ) -> Result<
+    (
+      ProcessTree,
+      impl Stream<Item = ChildOutput, Error = String> + Send,
+    ),
+    String,
+  > {
+    let started = Instant::now();
+    let mut child = self
       .inner
       .stdin(Stdio::null())
       .stdout(Stdio::piped())
       .stderr(Stdio::piped())
-      .spawn_async()
-      .map_err(|e| format!("Error launching process: {:?}", e))
-      .and_then(|mut child| {
-        let stdout_stream = FramedRead::new(child.stdout().take().unwrap(), BytesCodec::new())
-          .map(|bytes| ChildOutput::Stdout(bytes.into()));
-        let stderr_stream = FramedRead::new(child.stderr().take().unwrap(), BytesCodec::new())
-          .map(|bytes| ChildOutput::Stderr(bytes.into()));
-        let exit_stream = child.into_stream().map(|exit_status| {
-          ChildOutput::Exit(
-            exit_status
-              .code()
-              .or_else(|| exit_status.signal().map(Neg::neg))
-              .expect("Child process should exit via returned code or signal."),
-          )
-        });
-
-        Ok(
-          stdout_stream
-            .select(stderr_stream)
-            .select(exit_stream)
-            .map_err(|e| format!("Failed to consume process outputs: {:?}", e)),
-        )
+      .spawn()
+      .map_err(|e| format!("Error launching process: {:?}", e))?;
+    let pid = child.id();
+    let tree = ProcessTree::adopt(&child)?;
+    let tree2 = tree.clone();
+    let (sender, receiver) = mpsc::unbounded();
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    spawn_reader(stdout, ChildOutput::Stdout, sender.clone())?;
+    spawn_reader(stderr, ChildOutput::Stderr, sender.clone())?;
+    thread::Builder::new()
+      .name(format!("process-execution-wait-{}", pid))
+      .spawn(move || {
+        let exit = tree2
+          .wait(child, started)
+          .map(|(exit_code, usage)| ChildOutput::Exit(exit_code, usage));
+        let _ = sender.unbounded_send(exit);
       })
+      .map_err(|e| format!("Error starting thread to wait for process: {:?}", e))?;
+
+    // The stream ends once all of the threads have completed, and so have dropped their senders.
+    Ok((
+      tree,
+      receiver
+        .map_err(|()| "Failed to consume process outputs".to_owned())
+        .and_then(|output| output),
+    ))
   }
 }
 
+///
+/// Spawns a thread which reads the given output of a child process until EOF, sending each chunk
+/// that it reads.
+///
+fn spawn_reader<R: Read + Send + 'static>(
+  mut output: R,
+  wrap: fn(Bytes) -> ChildOutput,
+  sender: mpsc::UnboundedSender<Result<ChildOutput, String>>,
+) -> Result<(), String> {
+  thread::Builder::new()
+    .name("process-execution-output".to_owned())
+    .spawn(move || {
+      let mut buf = [0; 8192];
+      loop {
+        let chunk = match output.read(&mut buf) {
+          Ok(0) => break,
+          Ok(n) => Ok(wrap(Bytes::from(&buf[..n]))),
+          Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+          Err(e) => Err(format!("Failed to consume process outputs: {:?}", e)),
+        };
+        let failed = chunk.is_err();
+        if sender.unbounded_send(chunk).is_err() || failed {
+          break;
+        }
+      }
+    })
+    .map(|_| ())
+    .map_err(|e| format!("Error starting thread to read process output: {:?}", e))
+}
+
 ///
 /// The fully collected outputs of a completed child process.
 ///
@@ -169,109 +757,733 @@ struct ChildResults {
   stdout: Bytes,
   stderr: Bytes,
   exit_code: i32,
+  failure_reason: Option<FailureReason>,
+  resource_usage: Option<ResourceUsage>,
+  // Streams which exceeded their limit under OutputLimitPolicy::Spill.
+  spilled: Vec<SpilledOutput>,
 }
 
 impl ChildResults {
-  fn collect_from<E>(
-    stream: impl Stream<Item = ChildOutput, Error = E> + Send,
-  ) -> impl Future<Item = ChildResults, Error = E> {
+  fn collect_from(
+    stream: impl Stream<Item = ChildOutput, Error = String> + Send,
+    forwarder: Option<OutputForwarder>,
+    limits: OutputLimits,
+    spill_dir: PathBuf,
+  ) -> impl Future<Item = ChildResults, Error = String> {
     let init = (
-      BytesMut::with_capacity(8192),
-      BytesMut::with_capacity(8192),
+      CapturedOutput::new(OutputStream::Stdout),
+      CapturedOutput::new(OutputStream::Stderr),
       0,
+      None,
+      forwarder,
     );
     stream
       .fold(
         init,
-        |(mut stdout, mut stderr, mut exit_code), child_output| {
-          match child_output {
-            ChildOutput::Stdout(bytes) => stdout.extend_from_slice(&bytes),
-            ChildOutput::Stderr(bytes) => stderr.extend_from_slice(&bytes),
-            ChildOutput::Exit(code) => exit_code = code,
+        move |(mut stdout, mut stderr, mut exit_code, mut resource_usage, mut forwarder),
+              child_output| {
+          let appended = match child_output {
+            ChildOutput::Stdout(bytes) => {
+              let appended = stdout.append(&bytes, &limits, &spill_dir);
+              if let Some(ref mut forwarder) = forwarder {
+                forwarder.forward_lines(OutputStream::Stdout, &stdout.kept);
+              }
+              appended
+            }
+            ChildOutput::Stderr(bytes) => {
+              let appended = stderr.append(&bytes, &limits, &spill_dir);
+              if let Some(ref mut forwarder) = forwarder {
+                forwarder.forward_lines(OutputStream::Stderr, &stderr.kept);
+              }
+              appended
+            }
+            ChildOutput::Exit(code, usage) => {
+              exit_code = code;
+              resource_usage = Some(usage);
+              Ok(())
+            }
           };
-          Ok((stdout, stderr, exit_code)) as Result<_, E>
+          appended.map(|()| (stdout, stderr, exit_code, resource_usage, forwarder))
+        },
+      )
+      .map(
+        move |(stdout, stderr, exit_code, resource_usage, forwarder)| {
+          if let Some(mut forwarder) = forwarder {
+            forwarder.forward_remainder(OutputStream::Stdout, &stdout.kept);
+            forwarder.forward_remainder(OutputStream::Stderr, &stderr.kept);
+          }
+          let mut spilled = vec![];
+          let stdout = stdout.finish(&limits, &mut spilled);
+          let stderr = stderr.finish(&limits, &mut spilled);
+          ChildResults {
+            stdout,
+            stderr,
+            exit_code,
+            failure_reason: None,
+            resource_usage,
+            spilled,
+          }
         },
       )
-      .map(|(stdout, stderr, exit_code)| ChildResults {
-        stdout: stdout.into(),
-        stderr: stderr.into(),
-        exit_code,
-      })
   }
-}
 
-impl super::CommandRunner for CommandRunner {
   ///
-  /// Runs a command on this machine in the passed working directory.
+  /// Stores each spilled stream in the given Store, appending a marker containing its digest to
+  /// the part of it that was kept.
   ///
-  fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
-    let workdir = try_future!(tempfile::Builder::new()
-      .prefix("process-execution")
-      .tempdir_in(&self.work_dir)
-      .map_err(|err| format!(
-        "Error making tempdir for local process execution: {:?}",
+  fn store_spilled(mut self, store: &fs::Store, limits: OutputLimits) -> BoxFuture<Self, String> {
+    if self.spilled.is_empty() {
+      return future::ok(self).to_boxed();
+    }
+    let store = store.clone();
+    let spilled = std::mem::replace(&mut self.spilled, vec![]);
+    future::join_all(spilled.into_iter().map(move |spilled| {
+      let bytes = try_future!(std::fs::read(spilled.file.path()).map_err(|err| format!(
+        "Error reading spilled {} of local process execution: {:?}",
+        spilled.stream.name(),
         err
       )));
-    let workdir_path = workdir.path().to_owned();
-    let workdir_path2 = workdir_path.clone();
-    let workdir_path3 = workdir_path.clone();
-    let store = self.store.clone();
+      store
+        .store_file_bytes(Bytes::from(bytes), true)
+        .map(move |digest| (spilled, digest))
+        .to_boxed()
+    }))
+    .map(move |stored| {
+      for (spilled, digest) in stored {
+        let marker = format!(
+          "\n[{} was {} bytes, exceeding the limit of {} bytes: it was stored in full as the file \
+           with digest {} {}]\n",
+          spilled.stream.name(),
+          spilled.total_bytes,
+          limits.stdio_bytes.unwrap_or(0),
+          digest.0.to_hex(),
+          digest.1
+        );
+        let output = match spilled.stream {
+          OutputStream::Stdout => &mut self.stdout,
+          OutputStream::Stderr => &mut self.stderr,
+        };
+        let mut bytes = BytesMut::from(&output[..]);
+        bytes.extend_from_slice(marker.as_bytes());
+        *output = bytes.freeze();
+      }
+      self
+    })
+    .to_boxed()
+  }
+}
 
-    let env = req.env;
-    let output_file_paths = req.output_files;
-    let output_file_paths2 = output_file_paths.clone();
-    let output_dir_paths = req.output_directories;
-    let output_dir_paths2 = output_dir_paths.clone();
-    let cleanup_local_dirs = self.cleanup_local_dirs;
-    let argv = req.argv;
-    let req_description = req.description;
-    let maybe_jdk_home = req.jdk_home;
-    self
-      .store
-      .materialize_directory(workdir_path.clone(), req.input_files)
-      .and_then(move |()| {
-        maybe_jdk_home.map_or(Ok(()), |jdk_home| {
-          symlink(jdk_home, workdir_path3.clone().join(".jdk"))
-            .map_err(|err| format!("Error making symlink for local execution: {:?}", err))
-        })?;
-        // The bazel remote execution API specifies that the parent directories for output files and
-        // output directories should be created before execution completes: see
-        //   https://github.com/pantsbuild/pants/issues/7084.
-        let parent_paths_to_create: HashSet<_> = output_file_paths2
-          .iter()
-          .chain(output_dir_paths2.iter())
-          .filter_map(|rel_path| rel_path.parent())
-          .map(|parent_relpath| workdir_path3.join(parent_relpath))
-          .collect();
-        // TODO: we use a HashSet to deduplicate directory paths to create, but it would probably be
-        // even more efficient to only retain the directories at greatest nesting depth, as
-        // create_dir_all() will ensure all parents are created. At that point, we might consider
-        // explicitly enumerating all the directories to be created and just using create_dir(),
-        // unless there is some optimization in create_dir_all() that makes that less efficient.
-        for path in parent_paths_to_create {
-          create_dir_all(path.clone()).map_err(|err| {
-            format!(
-              "Error making parent directory {:?} for local execution: {:?}",
-              path, err
-            )
-          })?;
-        }
-        Ok(())
-      })
-      .and_then(move |()| {
-        StreamedHermeticCommand::new(&argv[0])
-          .args(&argv[1..])
-          .current_dir(&workdir_path)
-          .envs(env)
-          .stream()
+///
+/// One output stream of a child process, as much of which is kept in memory as its limit allows.
+///
+struct CapturedOutput {
+  stream: OutputStream,
+  kept: BytesMut,
+  total_bytes: u64,
+  // The whole stream, once it has exceeded its limit under OutputLimitPolicy::Spill.
+  spill: Option<tempfile::NamedTempFile>,
+}
+
+struct SpilledOutput {
+  stream: OutputStream,
+  total_bytes: u64,
+  file: tempfile::NamedTempFile,
+}
+
+impl CapturedOutput {
+  fn new(stream: OutputStream) -> CapturedOutput {
+    CapturedOutput {
+      stream,
+      kept: BytesMut::with_capacity(8192),
+      total_bytes: 0,
+      spill: None,
+    }
+  }
+
+  fn append(
+    &mut self,
+    bytes: &[u8],
+    limits: &OutputLimits,
+    spill_dir: &Path,
+  ) -> Result<(), String> {
+    self.total_bytes += bytes.len() as u64;
+    let limit = match limits.stdio_bytes {
+      Some(limit) => limit,
+      None => {
+        self.kept.extend_from_slice(bytes);
+        return Ok(());
+      }
+    };
+    let kept = std::cmp::min(limit.saturating_sub(self.kept.len()), bytes.len());
+    self.kept.extend_from_slice(&bytes[..kept]);
+    if kept == bytes.len() {
+      return Ok(());
+    }
+    match limits.policy {
+      OutputLimitPolicy::Fail => Err(format!(
+        "Local process execution exceeded the limit of {} bytes of {}",
+        limit,
+        self.stream.name()
+      )),
+      OutputLimitPolicy::Truncate => Ok(()),
+      OutputLimitPolicy::Spill => {
+        let name = self.stream.name();
+        let write_err = |err| format!("Error spilling {}: {:?}", name, err);
+        if self.spill.is_none() {
+          let mut file = tempfile::NamedTempFile::new_in(spill_dir).map_err(write_err)?;
+          file.write_all(&self.kept).map_err(write_err)?;
+          self.spill = Some(file);
+        }
+        if let Some(ref mut file) = self.spill {
+          file.write_all(&bytes[kept..]).map_err(write_err)?;
+        }
+        Ok(())
+      }
+    }
+  }
+
+  ///
+  /// Returns the kept part of the stream, with a marker appended if any of it was truncated.
+  /// Spilled streams are recorded to be stored (and marked) later.
+  ///
+  fn finish(self, limits: &OutputLimits, spilled: &mut Vec<SpilledOutput>) -> Bytes {
+    let CapturedOutput {
+      stream,
+      mut kept,
+      total_bytes,
+      spill,
+    } = self;
+    if let Some(file) = spill {
+      spilled.push(SpilledOutput {
+        stream,
+        total_bytes,
+        file,
+      });
+    } else if total_bytes > kept.len() as u64 {
+      kept.extend_from_slice(
+        format!(
+          "\n[Truncated {} bytes of {} exceeding the limit of {} bytes]\n",
+          total_bytes - kept.len() as u64,
+          stream.name(),
+          limits.stdio_bytes.unwrap_or(0)
+        )
+        .as_bytes(),
+      );
+    }
+    kept.freeze()
+  }
+}
+
+///
+/// Forwards complete lines of the output of a process to an OutputListener, by tracking how much
+/// of each (fully buffered) stream has already been forwarded.
+///
+struct OutputForwarder {
+  listener: OutputListener,
+  description: String,
+  stdout_forwarded: usize,
+  stderr_forwarded: usize,
+}
+
+impl OutputForwarder {
+  fn new(listener: OutputListener, description: String) -> OutputForwarder {
+    OutputForwarder {
+      listener,
+      description,
+      stdout_forwarded: 0,
+      stderr_forwarded: 0,
+    }
+  }
+
+  fn forwarded(&mut self, stream: OutputStream) -> &mut usize {
+    match stream {
+      OutputStream::Stdout => &mut self.stdout_forwarded,
+      OutputStream::Stderr => &mut self.stderr_forwarded,
+    }
+  }
+
+  fn forward_lines(&mut self, stream: OutputStream, buffered: &[u8]) {
+    let mut start = *self.forwarded(stream);
+    while let Some(newline) = buffered[start..].iter().position(|&b| b == b'\n') {
+      let end = start + newline + 1;
+      (self.listener)(&self.description, stream, &buffered[start..end]);
+      start = end;
+    }
+    *self.forwarded(stream) = start;
+  }
+
+  fn forward_remainder(&mut self, stream: OutputStream, buffered: &[u8]) {
+    let start = *self.forwarded(stream);
+    if start < buffered.len() {
+      (self.listener)(&self.description, stream, &buffered[start..]);
+      *self.forwarded(stream) = buffered.len();
+    }
+  }
+}
+
+///
+/// Kills a process tree when dropped, unless its root has exited. Cancelling the execution of a
+/// process (i.e. dropping the Future which is running it) thus kills it along with any processes
+/// that it has spawned; the sandbox is then removed as usual when it is dropped.
+///
+struct ProcessTreeGuard {
+  tree: ProcessTree,
+  exited: bool,
+}
+
+impl Drop for ProcessTreeGuard {
+  fn drop(&mut self) {
+    if !self.exited {
+      self.tree.kill();
+    }
+  }
+}
+
+///
+/// Returns a Future which, once the timeout has elapsed, terminates the given process tree (with
+/// SIGTERM on Unix), then (if the tree hasn't exited within TERMINATION_GRACE_PERIOD) kills it.
+/// The Future never completes successfully: it is intended to be raced against the collection of
+/// the process' outputs, which will complete once the process has exited.
+///
+fn kill_after_timeout(
+  tree: ProcessTree,
+  timeout: Duration,
+  timed_out: Arc<AtomicBool>,
+) -> BoxFuture<(), String> {
+  let tree2 = tree.clone();
+  Delay::new(timeout)
+    .and_then(move |()| {
+      timed_out.store(true, Ordering::SeqCst);
+      tree.terminate();
+      Delay::new(TERMINATION_GRACE_PERIOD)
+    })
+    .map_err(|err| format!("Error waiting for local process execution timeout: {}", err))
+    .and_then(move |()| {
+      tree2.kill();
+      future::empty()
+    })
+    .to_boxed()
+}
+
+///
+/// The working directory in which a process runs.
+///
+enum Sandbox {
+  ///
+  /// A fresh directory, which is removed once the process has completed (unless local dirs are
+  /// not being cleaned up).
+  ///
+  Temporary(tempfile::TempDir),
+  ///
+  /// A stable directory for a reusable workdir scope, alongside a record of the inputs that were
+  /// last materialized into it.
+  ///
+  Reusable {
+    workdir: PathBuf,
+    inputs_record: PathBuf,
+  },
+}
+
+impl Sandbox {
+  fn path(&self) -> &Path {
+    match self {
+      Sandbox::Temporary(workdir) => workdir.path(),
+      Sandbox::Reusable { workdir, .. } => workdir.as_path(),
+    }
+  }
+
+  ///
+  /// Materializes the given inputs into the sandbox: for a reusable workdir, only the inputs which
+  /// have changed since they were last materialized are written. Inputs which are (or are beneath)
+  /// any of the writable paths are copied whatever the strategy, because processes may modify them.
+  ///
+  fn materialize(
+    &self,
+    store: &fs::Store,
+    input_files: Digest,
+    strategy: MaterializationStrategy,
+    writable_paths: &BTreeSet<PathBuf>,
+  ) -> BoxFuture<(), String> {
+    match self {
+      Sandbox::Temporary(workdir) => store.materialize_directory_with(
+        workdir.path().to_owned(),
+        input_files,
+        strategy,
+        writable_paths,
+      ),
+      Sandbox::Reusable {
+        workdir,
+        inputs_record,
+      } => {
+        let previous = read_inputs_record(inputs_record);
+        // The record is removed while the inputs are updated, so that if we fail part way through,
+        // the next run materializes all of its inputs.
+        match std::fs::remove_file(inputs_record) {
+          Ok(()) => {}
+          Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {}
+          Err(err) => {
+            return future::err(format!(
+              "Error removing record of inputs {:?}: {:?}",
+              inputs_record, err
+            ))
+            .to_boxed();
+          }
+        }
+        let inputs_record = inputs_record.clone();
+        store
+          .materialize_directory_incrementally(
+            workdir.clone(),
+            previous,
+            input_files,
+            strategy,
+            writable_paths,
+          )
+          .and_then(move |()| {
+            std::fs::write(
+              &inputs_record,
+              format!("{} {}", input_files.0.to_hex(), input_files.1),
+            )
+            .map_err(|err| {
+              format!(
+                "Error writing record of inputs {:?}: {:?}",
+                inputs_record, err
+              )
+            })
+          })
+          .to_boxed()
+      }
+    }
+  }
+
+  ///
+  /// Removes the sandbox (unless it is to be kept, or is a reusable workdir), returning the path
+  /// of a kept sandbox.
+  ///
+  fn finish(self, keep: bool, description: &str) -> Option<PathBuf> {
+    if !keep {
+      // A temporary workdir gets dropped (and so deleted) here.
+      return None;
+    }
+    let preserved_path = match self {
+      // This consumes the `TempDir` without deleting directory on the filesystem, meaning
+      // that the temporary directory will no longer be automatically deleted when dropped.
+      Sandbox::Temporary(workdir) => workdir.into_path(),
+      Sandbox::Reusable { workdir, .. } => workdir,
+    };
+    info!(
+      "preserved local process execution dir `{:?}` for {:?}",
+      preserved_path, description
+    );
+    Some(preserved_path)
+  }
+}
+
+fn read_inputs_record(path: &Path) -> Option<Digest> {
+  let record = std::fs::read_to_string(path).ok()?;
+  let mut parts = record.split_whitespace();
+  let fingerprint = Fingerprint::from_hex_string(parts.next()?).ok()?;
+  let size_bytes = parts.next()?.parse().ok()?;
+  Some(Digest(fingerprint, size_bytes))
+}
+
+impl CommandRunner {
+  ///
+  /// Runs a command on this machine, in a fresh sandbox or (if the request has a reusable workdir)
+  /// in the workdir for its scope.
+  ///
+  /// Reusable workdirs are append-only: files which processes create in them persist between
+  /// runs, and are only removed if they were inputs which have since been removed. Processes must
+  /// not modify their inputs, which are not re-materialized unless they change. Stale outputs
+  /// from earlier runs may be captured if a process doesn't overwrite them.
+  ///
+  fn run_uncached(
+    &self,
+    req: ExecuteProcessRequest,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let scope = match req.reusable_workdir {
+      Some(ref scope) => scope.clone(),
+      None => {
+        let workdir = try_future!(tempfile::Builder::new()
+          .prefix("process-execution")
+          .tempdir_in(&self.work_dir)
+          .map_err(|err| format!(
+            "Error making tempdir for local process execution: {:?}",
+            err
+          )));
+        return self.run_in_sandbox(req, Sandbox::Temporary(workdir));
+      }
+    };
+    let mut components = Path::new(&scope).components();
+    match (components.next(), components.next()) {
+      (Some(Component::Normal(_)), None) => {}
+      _ => {
+        return future::err(format!(
+          "Invalid reusable workdir scope {:?} for {}: scopes must be valid file names",
+          scope, req.description
+        ))
+        .to_boxed();
+      }
+    }
+    if !req.named_caches.is_empty() {
+      return future::err(format!(
+        "Named caches are not supported in reusable workdirs, but were requested for {}",
+        req.description
+      ))
+      .to_boxed();
+    }
+    let scope_dir = self.work_dir.join("reusable").join(&scope);
+    let sandbox = Sandbox::Reusable {
+      workdir: scope_dir.join("workdir"),
+      inputs_record: scope_dir.join("inputs"),
+    };
+    let runner = self.clone();
+    self
+      .reusable_workdir_locks
+      .with_acquired(scope, move |_slot| runner.run_in_sandbox(req, sandbox))
+      .to_boxed()
+  }
+
+  fn run_in_sandbox(
+    &self,
+    req: ExecuteProcessRequest,
+    sandbox: Sandbox,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let workdir_path = sandbox.path().to_owned();
+    let workdir_path2 = workdir_path.clone();
+    let workdir_path3 = workdir_path.clone();
+    let store = self.store.clone();
+    let output_listener = self.output_listener_for(&req);
+
+    let mut env = req.env_with_passthrough();
+    let violation_recorder =
+      self
+        .hermeticity_violations
+        .clone()
+        .map(|violations| ViolationRecorder {
+          process_description: req.description.clone(),
+          violations,
+        });
+    let private_tmpdir = workdir_path.join(PRIVATE_TMPDIR);
+    let private_tmpdir2 = private_tmpdir.clone();
+    let private_tmpdir3 = private_tmpdir.clone();
+    if let Some(ref violation_recorder) = violation_recorder {
+      violation_recorder.sanitize_env(&mut env, &private_tmpdir);
+    } else {
+      for name in TMPDIR_ENV_VARS {
+        env
+          .entry((*name).to_owned())
+          .or_insert_with(|| private_tmpdir.to_string_lossy().into_owned());
+      }
+    }
+    let output_file_paths = req.output_files;
+    let output_file_paths2 = output_file_paths.clone();
+    let output_dir_paths = req.output_directories;
+    let output_dir_paths2 = output_dir_paths.clone();
+    let keep_sandboxes = self.keep_sandboxes;
+    let argv = req.argv;
+    let run_script = run_script(&argv, &env, &workdir_path);
+    let timeout = req.timeout;
+    let req_description = req.description;
+    let req_description2 = req_description.clone();
+    let req_description3 = req_description.clone();
+    let req_description4 = req_description.clone();
+    let output_limits = self.output_limits;
+    let spill_dir = self.work_dir.clone();
+    let store2 = self.store.clone();
+    let tools = try_future!(tools::resolve(&req.tool_mounts));
+    let resource_limits = req.resource_limits;
+    let requested_caches = req.named_caches;
+    let cache_names: Vec<String> = requested_caches.keys().cloned().collect();
+    let named_caches = if requested_caches.is_empty() {
+      None
+    } else {
+      Some(try_future!(self.named_caches.clone().ok_or_else(
+        || format!(
+          "Named caches were requested for {}, but none are configured for local execution",
+          req_description
+        )
+      )))
+    };
+    let named_caches2 = named_caches.clone();
+    let reusable = req.reusable_workdir.is_some();
+    // The paths which processes run as another user need to be able to write, and to read.
+    let user_paths = self.process_user.clone().map(|process_user| {
+      let writable = vec![workdir_path.clone()];
+      let readable: Vec<PathBuf> = tools
+        .iter()
+        .map(|tool| tool.path.clone())
+        .chain(named_caches.iter().flat_map(|named_caches| {
+          cache_names
+            .iter()
+            .map(move |name| named_caches.cache_dir(name))
+        }))
+        .collect();
+      (process_user, writable, readable)
+    });
+    // Inputs which are also outputs may be modified by the process.
+    let writable_paths: BTreeSet<PathBuf> = output_file_paths
+      .iter()
+      .chain(output_dir_paths.iter())
+      .cloned()
+      .collect();
+    sandbox
+      .materialize(
+        &self.store,
+        req.input_files,
+        self.materialization_strategy,
+        &writable_paths,
+      )
+      .and_then(move |()| {
+        // A reusable workdir will still contain the links from its previous run.
+        tools::link_into(&workdir_path3, &tools, reusable)?;
+        make_private_tmpdir(&private_tmpdir2)?;
+        let script_path = workdir_path3.join(RUN_SCRIPT);
+        std::fs::write(&script_path, run_script)
+          .and_then(|()| {
+            std::fs::set_permissions(&script_path, std::fs::Permissions::from_mode(0o755))
+          })
+          .map_err(|err| format!("Error writing {:?}: {:?}", script_path, err))?;
+        debug!(
+          "Wrote {:?} to reproduce local process execution {:?}",
+          script_path, req_description4
+        );
+        if let Some(named_caches) = named_caches2 {
+          named_caches.link_into(&workdir_path3, &requested_caches)?;
+        }
+        // The bazel remote execution API specifies that the parent directories for output files and
+        // output directories should be created before execution completes: see
+        //   https://github.com/pantsbuild/pants/issues/7084.
+        // Globs don't have a meaningful parent directory, so it is up to the process to create the
+        // directories that they match.
+        let parent_paths_to_create: HashSet<_> = output_file_paths2
+          .iter()
+          .chain(
+            output_dir_paths2
+              .iter()
+              .filter(|rel_path| !is_output_glob(rel_path)),
+          )
+          .filter_map(|rel_path| rel_path.parent())
+          .map(|parent_relpath| workdir_path3.join(parent_relpath))
+          .collect();
+        // TODO: we use a HashSet to deduplicate directory paths to create, but it would probably be
+        // even more efficient to only retain the directories at greatest nesting depth, as
+        // create_dir_all() will ensure all parents are created. At that point, we might consider
+        // explicitly enumerating all the directories to be created and just using create_dir(),
+        // unless there is some optimization in create_dir_all() that makes that less efficient.
+        for path in parent_paths_to_create {
+          create_dir_all(path.clone()).map_err(|err| {
+            format!(
+              "Error making parent directory {:?} for local execution: {:?}",
+              path, err
+            )
+          })?;
+        }
+        Ok(())
+      })
+      .and_then(move |()| {
+        let execution = future::lazy(move || {
+          // If a cgroup is limiting memory, an address space limit would only prevent us from
+          // detecting that the limit was exceeded.
+          let cgroup = resource_limits
+            .memory_bytes
+            .and_then(Cgroup::create)
+            .map(Arc::new);
+          let rlimits = if cgroup.is_some() {
+            ResourceLimits {
+              memory_bytes: None,
+              ..resource_limits
+            }
+          } else {
+            resource_limits
+          };
+          // Sandboxes are prepared for the user once they have been fully laid out.
+          let user = match user_paths {
+            Some((process_user, writable, readable)) => {
+              Some(PreparedUser::prepare(&process_user, &writable, &readable)?)
+            }
+            None => None,
+          };
+          StreamedHermeticCommand::new(&argv[0])
+            .args(&argv[1..])
+            .current_dir(&workdir_path)
+            .envs(env)
+            .resource_limits(rlimits, cgroup.clone())
+            .user(user)
+            .stream()
+            .map(|(tree, outputs)| (tree, outputs, cgroup))
+        })
+        // NB: We fully buffer up the `Stream` above into final `ChildResults` below, but also pass
+        // complete lines on to the output listener (if any) as they are produced, for streaming
+        // process results to console logs, etc: see https://github.com/pantsbuild/pants/issues/6089
+        .and_then(move |(tree, outputs, cgroup)| {
+          let mut tree_guard = ProcessTreeGuard {
+            tree: tree.clone(),
+            exited: false,
+          };
+          let timed_out = Arc::new(AtomicBool::new(false));
+          let forwarder =
+            output_listener.map(|listener| OutputForwarder::new(listener, req_description3));
+          ChildResults::collect_from(outputs, forwarder, output_limits, spill_dir)
+            .select2(kill_after_timeout(tree, timeout, timed_out.clone()))
+            .then(|res| match res {
+              Ok(Either::A((child_results, _))) => Ok(child_results),
+              Ok(Either::B(((), _))) => unreachable!("Timeout Future should never complete."),
+              Err(Either::A((err, _))) | Err(Either::B((err, _))) => Err(err),
+            })
+            .map(move |mut child_results| {
+              tree_guard.exited = true;
+              if timed_out.load(Ordering::SeqCst) {
+                let mut stderr = BytesMut::from(&child_results.stderr[..]);
+                stderr.extend_from_slice(
+                  format!(
+                    "\nExceeded timeout of {:?} for local process execution, {}",
+                    timeout, req_description2
+                  )
+                  .as_bytes(),
+                );
+                child_results.stderr = stderr.freeze();
+              }
+              if let Some(resource_usage) = child_results.resource_usage {
+                debug!(
+                  "Resource usage of local process execution {:?}: {:?}",
+                  req_description2, resource_usage
+                );
+              }
+              child_results.failure_reason = if timed_out.load(Ordering::SeqCst) {
+                Some(FailureReason::Timeout)
+              } else {
+                limit_exceeded(
+                  &resource_limits,
+                  child_results.exit_code,
+                  cgroup.as_ref().map(|c| &**c),
+                )
+                .map(FailureReason::ResourceLimitExceeded)
+              };
+              child_results
+            })
+        });
+        // Processes using named caches hold their locks until they have exited.
+        match named_caches {
+          Some(named_caches) => named_caches.with_locked(cache_names, execution.to_boxed()),
+          None => execution.to_boxed(),
+        }
       })
-      // NB: We fully buffer up the `Stream` above into final `ChildResults` below and so could
-      // instead be using `CommandExt::output_async` above to avoid the `ChildResults::collect_from`
-      // code. The idea going forward though is we eventually want to pass incremental results on
-      // down the line for streaming process results to console logs, etc. as tracked by:
-      //   https://github.com/pantsbuild/pants/issues/6089
-      .and_then(ChildResults::collect_from)
+      .and_then(move |child_results| child_results.store_spilled(&store2, output_limits))
       .and_then(move |child_results| {
+        // The private TMPDIR is removed before outputs are captured, so that output globs can't
+        // match anything in it.
+        try_future!(remove_private_tmpdir(&private_tmpdir3));
+        if let Some(violation_recorder) = violation_recorder {
+          let output_paths: Vec<PathBuf> = output_file_paths
+            .iter()
+            .chain(output_dir_paths.iter().filter(|path| !is_output_glob(path)))
+            .cloned()
+            .collect();
+          violation_recorder.check_outputs(&workdir_path2, &output_paths);
+        }
+
+        let sandbox_path = workdir_path2.clone();
+        let output_file_bytes = output_limits.output_file_bytes;
         let output_snapshot = if output_file_paths.is_empty() && output_dir_paths.is_empty() {
           future::ok(fs::Snapshot::empty()).to_boxed()
         } else {
@@ -288,8 +1500,10 @@ impl super::CommandRunner for CommandRunner {
               CommandRunner::construct_output_snapshot(
                 store,
                 posix_fs,
+                sandbox_path,
                 output_file_paths,
                 output_dir_paths,
+                output_file_bytes,
               )
             })
             .to_boxed()
@@ -302,39 +1516,402 @@ impl super::CommandRunner for CommandRunner {
             exit_code: child_results.exit_code,
             output_directory: snapshot.digest,
             execution_attempts: vec![],
+            failure_reason: child_results.failure_reason,
+            resource_usage: child_results.resource_usage,
+            trace: None,
           })
           .to_boxed()
       })
       .then(move |result| {
-        // Force workdir not to get dropped until after we've ingested the outputs
-        if !cleanup_local_dirs {
-          // This consumes the `TempDir` without deleting directory on the filesystem, meaning
-          // that the temporary directory will no longer be automatically deleted when dropped.
-          let preserved_path = workdir.into_path();
-          info!(
-            "preserved local process execution dir `{:?}` for {:?}",
-            preserved_path, req_description
-          );
-        } // Else, workdir gets dropped here
-        result
+        let failed = match result {
+          Ok(ref result) => result.exit_code != 0,
+          Err(_) => true,
+        };
+        let keep = match keep_sandboxes {
+          KeepSandboxes::Never => false,
+          KeepSandboxes::OnFailure => failed,
+          KeepSandboxes::Always => true,
+        };
+        // Force the sandbox not to get dropped until after we've ingested the outputs
+        let preserved_path = sandbox.finish(keep, &req_description);
+        match preserved_path {
+          Some(preserved_path) if failed => {
+            let note = format!(
+              "The sandbox of {} was preserved at {:?}: run {} in it to reproduce the process",
+              req_description, preserved_path, RUN_SCRIPT
+            );
+            match result {
+              Ok(mut result) => {
+                let mut stderr = BytesMut::from(&result.stderr[..]);
+                stderr.extend_from_slice(format!("\n{}\n", note).as_bytes());
+                result.stderr = stderr.freeze();
+                Ok(result)
+              }
+              Err(err) => Err(format!("{}\n{}", err, note)),
+            }
+          }
+          _ => result,
+        }
+      })
+      .to_boxed()
+  }
+}
+
+///
+/// Fails if the given output files of the given sandbox total more than the given limit. Their
+/// sizes are checked on disk, before they are stored, so that a runaway process can't fill the
+/// Store.
+///
+fn check_output_file_bytes(
+  sandbox: &Path,
+  path_stats: &[fs::PathStat],
+  limit: Option<u64>,
+) -> Result<(), String> {
+  let limit = match limit {
+    Some(limit) => limit,
+    None => return Ok(()),
+  };
+  let total: u64 = path_stats
+    .iter()
+    .filter_map(|path_stat| match path_stat {
+      fs::PathStat::File { ref path, .. } => std::fs::metadata(sandbox.join(path)).ok(),
+      fs::PathStat::Dir { .. } => None,
+    })
+    .map(|metadata| metadata.len())
+    .sum();
+  if total > limit {
+    Err(format!(
+      "Local process execution captured {} bytes of output files, exceeding the limit of {} bytes",
+      total, limit
+    ))
+  } else {
+    Ok(())
+  }
+}
+
+///
+/// The name of the script in each sandbox which reproduces its process: see `run_script`.
+///
+const RUN_SCRIPT: &str = "__run.sh";
+
+///
+/// The name of the private TMPDIR in each sandbox, which is created empty before its process runs
+/// and removed once it exits.
+///
+const PRIVATE_TMPDIR: &str = "__tmp";
+
+///
+/// The environment variables pointing at temp directories, which are set to the private TMPDIR
+/// (unless the request sets them) so that processes don't share state via the system /tmp.
+///
+const TMPDIR_ENV_VARS: &[&str] = &["TEMP", "TMP", "TMPDIR"];
+
+///
+/// Creates an empty private TMPDIR, replacing any left behind in a reusable workdir (e.g. by a
+/// process which was killed by a restart).
+///
+fn make_private_tmpdir(path: &Path) -> Result<(), String> {
+  remove_private_tmpdir(path)?;
+  std::fs::create_dir(path)
+    .map_err(|err| format!("Error making private TMPDIR {:?}: {:?}", path, err))
+}
+
+fn remove_private_tmpdir(path: &Path) -> Result<(), String> {
+  match std::fs::remove_dir_all(path) {
+    Ok(()) => Ok(()),
+    Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+    Err(err) => Err(format!(
+      "Error removing private TMPDIR {:?}: {:?}",
+      path, err
+    )),
+  }
+}
+
+///
+/// A shell script which runs the given command line in the given sandbox, with exactly the given
+/// environment, so that engineers can re-run a process interactively.
+///
+fn run_script(argv: &[String], env: &BTreeMap<String, String>, sandbox: &Path) -> String {
+  let quote = |s: &str| format!("'{}'", s.replace('\'', "'\\''"));
+  let mut script =
+    "#!/bin/sh\n# This reproduces a process that was executed by pants.\n".to_owned();
+  script.push_str(&format!("cd {}\n", quote(&sandbox.to_string_lossy())));
+  // The private TMPDIR is removed once the process exits.
+  script.push_str(&format!("mkdir -p {}\n", PRIVATE_TMPDIR));
+  script.push_str("exec env -i");
+  // The process gets an empty PATH unless it sets one.
+  if !env.contains_key("PATH") {
+    script.push_str(" PATH=");
+  }
+  for (name, value) in env {
+    script.push_str(&format!(" {}", quote(&format!("{}={}", name, value))));
+  }
+  for arg in argv {
+    script.push_str(&format!(" {}", quote(arg)));
+  }
+  script.push_str("\n");
+  script
+}
+
+///
+/// Which of the given limits (if any) the process was detected to have exceeded.
+///
+fn limit_exceeded(
+  limits: &ResourceLimits,
+  exit_code: i32,
+  cgroup: Option<&Cgroup>,
+) -> Option<ResourceLimit> {
+  if cgroup.map(Cgroup::oom_killed).unwrap_or(false) {
+    Some(ResourceLimit::Memory)
+  } else if limits.cpu_seconds.is_some() && exit_code == -libc::SIGXCPU {
+    Some(ResourceLimit::CpuTime)
+  } else {
+    None
+  }
+}
+
+impl super::CommandRunner for CommandRunner {
+  fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let action_digest = if self.action_cache {
+      action_cache_key(&req)
+    } else {
+      None
+    };
+    let action_digest = match action_digest {
+      Some(action_digest) => action_digest,
+      None => return self.run_uncached(req),
+    };
+
+    let store = self.store.clone();
+    let runner = self.clone();
+    let console = if req.tee_output {
+      self.console.clone()
+    } else {
+      None
+    };
+    let description = req.description.clone();
+    load_cached_result(&self.store, action_digest)
+      .and_then(move |cached_result| match cached_result {
+        Some(cached_result) => {
+          if let Some(console) = console {
+            let mut forwarder = OutputForwarder::new(console, description);
+            for &(stream, output) in &[
+              (OutputStream::Stdout, &cached_result.stdout),
+              (OutputStream::Stderr, &cached_result.stderr),
+            ] {
+              forwarder.forward_lines(stream, output);
+              forwarder.forward_remainder(stream, output);
+            }
+          }
+          future::ok(cached_result).to_boxed()
+        }
+        None => runner
+          .run_uncached(req)
+          .and_then(move |result| {
+            if result.exit_code != 0 {
+              return future::ok(result).to_boxed();
+            }
+            record_cached_result(&store, action_digest, &result)
+              .then(move |res| {
+                if let Err(err) = res {
+                  warn!("Failed to cache local process execution result: {}", err);
+                }
+                Ok(result) as Result<_, String>
+              })
+              .to_boxed()
+          })
+          .to_boxed(),
       })
       .to_boxed()
   }
 }
 
+///
+/// The key under which the result of the given request is cached: the digest of the Action that
+/// remote execution would use for it, with the tools that it uses (their sandbox paths, canonical
+/// paths and fingerprints) and the names of its passthrough variables as platform properties.
+///
+/// The values of passthrough variables are deliberately not part of the key (see
+/// `ExecuteProcessRequest::env_passthrough`). Requests whose tools can't be resolved (which will
+/// fail when they run) aren't cached.
+///
+pub(crate) fn action_cache_key(req: &ExecuteProcessRequest) -> Option<Digest> {
+  let mut platform_properties: BTreeMap<String, String> = tools::resolve(&req.tool_mounts)
+    .ok()?
+    .into_iter()
+    .map(|tool| {
+      (
+        format!("pants-tool-{}", tool.name),
+        format!(
+          "{}:{}:{}",
+          tool.sandbox_path.display(),
+          tool.path.display(),
+          tool.fingerprint.to_hex()
+        ),
+      )
+    })
+    .collect();
+  if !req.env_passthrough.is_empty() {
+    platform_properties.insert(
+      "pants-env-passthrough".to_owned(),
+      req
+        .env_passthrough
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(","),
+    );
+  }
+  // Passthrough variables and tools can't be executed remotely (and so would prevent the Action
+  // being made), but are covered by the platform properties.
+  let req = ExecuteProcessRequest {
+    env_passthrough: BTreeSet::new(),
+    tool_mounts: BTreeMap::new(),
+    ..req.clone()
+  };
+  super::remote::make_execute_request(&req, &None, &None, platform_properties)
+    .and_then(|(action, _, _)| super::remote::digest(&action))
+    .ok()
+}
+
+fn record_cached_result(
+  store: &fs::Store,
+  action_digest: Digest,
+  result: &FallibleExecuteProcessResult,
+) -> BoxFuture<(), String> {
+  let store = store.clone();
+  let exit_code = result.exit_code;
+  let output_directory = result.output_directory;
+  store
+    .store_file_bytes(result.stdout.clone(), true)
+    .join3(
+      store.store_file_bytes(result.stderr.clone(), true),
+      store.record_tree(output_directory),
+    )
+    .and_then(move |(stdout_digest, stderr_digest, tree_digest)| {
+      let mut action_result = bazel_protos::remote_execution::ActionResult::new();
+      action_result.set_exit_code(exit_code);
+      action_result.set_stdout_digest((&stdout_digest).into());
+      action_result.set_stderr_digest((&stderr_digest).into());
+      // As for remote execution, the outputs are recorded as a Tree of the root Directory.
+      let mut output_directory_proto = bazel_protos::remote_execution::OutputDirectory::new();
+      output_directory_proto.set_tree_digest((&tree_digest).into());
+      action_result
+        .mut_output_directories()
+        .push(output_directory_proto);
+      let bytes = try_future!(action_result
+        .write_to_bytes()
+        .map_err(|err| format!("Error serializing action result: {:?}", err)));
+      store.record_action_result(action_digest, Bytes::from(bytes))
+    })
+    .to_boxed()
+}
+
+///
+/// Loads a cached result, if there is one and everything that it refers to (its stdout and
+/// stderr, and every Directory and file of its outputs) is still in the Store: entries may have
+/// been garbage collected or evicted since the result was recorded, in which case it is a miss.
+///
+fn load_cached_result(
+  store: &fs::Store,
+  action_digest: Digest,
+) -> BoxFuture<Option<FallibleExecuteProcessResult>, String> {
+  let store = store.clone();
+  store
+    .load_action_result(action_digest)
+    .and_then(move |maybe_bytes| {
+      let bytes = match maybe_bytes {
+        Some(bytes) => bytes,
+        None => return future::ok(None).to_boxed(),
+      };
+      let mut action_result = bazel_protos::remote_execution::ActionResult::new();
+      try_future!(action_result
+        .merge_from_bytes(&bytes)
+        .map_err(|err| format!(
+          "Invalid cached action result for {:?}: {:?}",
+          action_digest, err
+        )));
+      let stdout_digest: Result<Digest, String> = action_result.get_stdout_digest().into();
+      let stdout_digest = try_future!(stdout_digest);
+      let stderr_digest: Result<Digest, String> = action_result.get_stderr_digest().into();
+      let stderr_digest = try_future!(stderr_digest);
+      let tree_digest: Digest = try_future!(action_result
+        .get_output_directories()
+        .first()
+        .ok_or_else(|| format!(
+          "Cached action result for {:?} had no outputs",
+          action_digest
+        ))
+        .and_then(|output_directory| output_directory.get_tree_digest().into()));
+      let exit_code = action_result.get_exit_code();
+
+      let output_directory = {
+        let store = store.clone();
+        store
+          .load_tree(tree_digest)
+          .and_then(move |maybe_root| match maybe_root {
+            Some(root) => store
+              .contains_directory_recursive(root)
+              .map(move |complete| if complete { Some(root) } else { None })
+              .to_boxed(),
+            None => future::ok(None).to_boxed(),
+          })
+      };
+
+      store
+        .load_file_bytes_with(stdout_digest, |bytes| bytes)
+        .join3(
+          store.load_file_bytes_with(stderr_digest, |bytes| bytes),
+          output_directory,
+        )
+        .map(move |loaded| match loaded {
+          (Some(stdout), Some(stderr), Some(output_directory)) => {
+            Some(FallibleExecuteProcessResult {
+              stdout,
+              stderr,
+              exit_code,
+              output_directory,
+              execution_attempts: vec![ExecutionStats {
+                was_cache_hit: true,
+                ..ExecutionStats::default()
+              }],
+              failure_reason: None,
+              resource_usage: None,
+              trace: None,
+            })
+          }
+          _ => None,
+        })
+        .to_boxed()
+    })
+    .to_boxed()
+}
+
 #[cfg(test)]
 mod tests {
   use tempfile;
   use testutil;
 
   use super::super::CommandRunner as CommandRunnerTrait;
-  use super::{ExecuteProcessRequest, FallibleExecuteProcessResult};
-  use fs;
+  use super::{
+    ExecuteProcessRequest, FallibleExecuteProcessResult, HermeticityViolation, KeepSandboxes,
+    OutputLimitPolicy, OutputLimits, OutputListener, OutputStream,
+  };
+  use crate::named_caches::NamedCaches;
+  use crate::{
+    ExecuteProcessRequestBuilder, FailureReason, InteractiveProcessRequest,
+    InteractiveProcessResult, ResourceLimit,
+  };
+  use fs::{self, MaterializationStrategy};
+  use futures::future::{self, Either, Loop};
+  use futures::Future;
+  use futures_timer::Delay;
   use std;
   use std::collections::{BTreeMap, BTreeSet};
   use std::env;
   use std::os::unix::fs::PermissionsExt;
   use std::path::{Path, PathBuf};
+  use std::sync::{Arc, Mutex};
   use std::time::Duration;
   use tempfile::TempDir;
   use testutil::data::{TestData, TestDirectory};
@@ -351,7 +1928,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "echo foo".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -362,6 +1948,9 @@ mod tests {
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     )
   }
@@ -377,7 +1966,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "echo foo and fail".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -388,6 +1986,9 @@ mod tests {
         exit_code: 1,
         output_directory: fs::EMPTY_DIGEST,
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     )
   }
@@ -404,7 +2005,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "kill self".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -415,6 +2025,9 @@ mod tests {
         exit_code: -15,
         output_directory: fs::EMPTY_DIGEST,
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     )
   }
@@ -434,7 +2047,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "run env".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
 
     let stdout = String::from_utf8(result.unwrap().stdout.to_vec()).unwrap();
@@ -449,6 +2071,8 @@ mod tests {
         )
       })
       .filter(|x| x.0 != "PATH")
+      // The private TMPDIR is specific to the sandbox: see private_tmpdir.
+      .filter(|x| !super::TMPDIR_ENV_VARS.contains(&x.0.as_str()))
       .collect();
 
     assert_eq!(env, got_env);
@@ -456,22 +2080,68 @@ mod tests {
 
   #[test]
   #[cfg(unix)]
-  fn env_is_deterministic() {
-    fn make_request() -> ExecuteProcessRequest {
-      let mut env = BTreeMap::new();
-      env.insert("FOO".to_string(), "foo".to_string());
-      env.insert("BAR".to_string(), "not foo".to_string());
-
-      ExecuteProcessRequest {
-        argv: owned_string_vec(&["/usr/bin/env"]),
-        env: env,
-        input_files: fs::EMPTY_DIGEST,
-        output_files: BTreeSet::new(),
-        output_directories: BTreeSet::new(),
-        timeout: Duration::from_millis(1000),
-        description: "run env".to_string(),
-        jdk_home: None,
-      }
+  fn env_passthrough() {
+    env::set_var("PANTS_TEST_ENV_PASSTHROUGH", "passed");
+    let result = run_command_locally(ExecuteProcessRequest {
+      argv: owned_string_vec(&["/usr/bin/env"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "run env".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: vec![
+        "PANTS_TEST_ENV_PASSTHROUGH".to_owned(),
+        "PANTS_TEST_ENV_PASSTHROUGH_UNSET".to_owned(),
+      ]
+      .into_iter()
+      .collect(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    });
+
+    let stdout = String::from_utf8(result.unwrap().stdout.to_vec()).unwrap();
+    assert!(stdout
+      .lines()
+      .any(|line| line == "PANTS_TEST_ENV_PASSTHROUGH=passed"));
+    assert!(!stdout.contains("PANTS_TEST_ENV_PASSTHROUGH_UNSET"));
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn env_is_deterministic() {
+    fn make_request() -> ExecuteProcessRequest {
+      let mut env = BTreeMap::new();
+      env.insert("FOO".to_string(), "foo".to_string());
+      env.insert("BAR".to_string(), "not foo".to_string());
+
+      ExecuteProcessRequest {
+        // The private TMPDIR differs between sandboxes.
+        argv: owned_string_vec(&["/usr/bin/env", "-u", "TEMP", "-u", "TMP", "-u", "TMPDIR"]),
+        env: env,
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "run env".to_string(),
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      }
     }
 
     let result1 = run_command_locally(make_request());
@@ -490,7 +2160,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "echo foo".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     })
     .expect_err("Want Err");
   }
@@ -509,7 +2188,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "bash".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
     assert_eq!(
       result.unwrap(),
@@ -519,6 +2207,9 @@ mod tests {
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     )
   }
@@ -537,7 +2228,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "bash".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -548,6 +2248,9 @@ mod tests {
         exit_code: 0,
         output_directory: TestDirectory::containing_roland().digest(),
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     )
   }
@@ -571,7 +2274,16 @@ mod tests {
       output_directories: vec![PathBuf::from("cats")].into_iter().collect(),
       timeout: Duration::from_millis(1000),
       description: "bash".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -582,6 +2294,9 @@ mod tests {
         exit_code: 0,
         output_directory: TestDirectory::recursive().digest(),
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     )
   }
@@ -606,7 +2321,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "treats-roland".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -617,6 +2341,9 @@ mod tests {
         exit_code: 0,
         output_directory: TestDirectory::recursive().digest(),
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     )
   }
@@ -639,7 +2366,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "echo foo".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -650,6 +2386,9 @@ mod tests {
         exit_code: 1,
         output_directory: TestDirectory::containing_roland().digest(),
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     )
   }
@@ -670,7 +2409,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "echo-roland".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -681,6 +2429,9 @@ mod tests {
         exit_code: 0,
         output_directory: TestDirectory::containing_roland().digest(),
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     )
   }
@@ -699,7 +2450,16 @@ mod tests {
       output_directories: vec![PathBuf::from("cats")].into_iter().collect(),
       timeout: Duration::from_millis(1000),
       description: "bash".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -710,10 +2470,298 @@ mod tests {
         exit_code: 0,
         output_directory: TestDirectory::nested().digest(),
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     )
   }
 
+  #[test]
+  #[cfg(unix)]
+  fn timeout_terminates_process_group() {
+    // Both the shell and the sleep it spawns should be terminated.
+    let start = std::time::Instant::now();
+    let result = run_command_locally(ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/bash", "-c", "echo -n foo ; /bin/sleep 10 ; echo bar"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(500),
+      description: "sleepy".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    })
+    .unwrap();
+
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(result.stdout, as_bytes("foo"));
+    assert_eq!(result.exit_code, -15);
+    assert_eq!(result.failure_reason, Some(FailureReason::Timeout));
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn timeout_escalates_to_sigkill() {
+    let result = run_command_locally(ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/bash", "-c", "trap '' TERM ; /bin/sleep 10"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(500),
+      description: "ignores sigterm".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    })
+    .unwrap();
+
+    assert_eq!(result.exit_code, -9);
+    assert_eq!(result.failure_reason, Some(FailureReason::Timeout));
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn output_listener_receives_lines() {
+    let lines = Arc::new(Mutex::new(vec![]));
+    let lines2 = lines.clone();
+    let listener: OutputListener = Arc::new(move |description, stream, line| {
+      assert_eq!("echo lines", description);
+      lines2.lock().unwrap().push((stream, line.to_vec()));
+    });
+
+    let store_dir = TempDir::new().unwrap();
+    let work_dir = TempDir::new().unwrap();
+    let runner = super::CommandRunner::new(
+      fs::Store::local_only(store_dir.path()).unwrap(),
+      work_dir.path().to_owned(),
+      true,
+    )
+    .with_output_listener(listener);
+    let result = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(runner.run(ExecuteProcessRequest {
+        argv: owned_string_vec(&["/bin/bash", "-c", "echo foo ; echo >&2 bar ; echo -n baz"]),
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "echo lines".to_string(),
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      }))
+      .unwrap();
+    assert_eq!(result.stdout, as_bytes("foo\nbaz"));
+
+    let lines = lines.lock().unwrap();
+    let lines_for = |wanted: OutputStream| -> Vec<Vec<u8>> {
+      lines
+        .iter()
+        .filter(|(stream, _)| *stream == wanted)
+        .map(|(_, line)| line.clone())
+        .collect()
+    };
+    assert_eq!(
+      vec![b"foo\n".to_vec(), b"baz".to_vec()],
+      lines_for(OutputStream::Stdout)
+    );
+    assert_eq!(vec![b"bar\n".to_vec()], lines_for(OutputStream::Stderr));
+  }
+
+  #[test]
+  fn tee_output_to_console() {
+    let lines = Arc::new(Mutex::new(vec![]));
+    let lines2 = lines.clone();
+    let console: OutputListener = Arc::new(move |_description: &str, stream, line: &[u8]| {
+      lines2.lock().unwrap().push((stream, line.to_vec()));
+    });
+
+    let store_dir = TempDir::new().unwrap();
+    let work_dir = TempDir::new().unwrap();
+    let runner = super::CommandRunner::new(
+      fs::Store::local_only(store_dir.path()).unwrap(),
+      work_dir.path().to_owned(),
+      true,
+    )
+    .with_action_cache()
+    .with_console(console);
+    let req = |tee_output: bool| {
+      ExecuteProcessRequestBuilder::new(
+        owned_string_vec(&["/bin/bash", "-c", "echo foo ; echo >&2 bar"]),
+        Duration::from_millis(1000),
+        format!("tee {}", tee_output),
+      )
+      .tee_output(tee_output)
+      .build()
+      .unwrap()
+    };
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let expected = vec![
+      (OutputStream::Stdout, b"foo\n".to_vec()),
+      (OutputStream::Stderr, b"bar\n".to_vec()),
+    ];
+
+    runtime.block_on(runner.run(req(false))).unwrap();
+    assert!(lines.lock().unwrap().is_empty());
+
+    let result = runtime.block_on(runner.run(req(true))).unwrap();
+    assert_eq!(result.stdout, as_bytes("foo\n"));
+    // The streams are read concurrently, so their lines may be teed in either order.
+    let teed = lines.lock().unwrap().drain(..).collect::<Vec<_>>();
+    assert_eq!(teed.len(), expected.len());
+    assert!(expected.iter().all(|line| teed.contains(line)));
+
+    // Cache hits replay their output.
+    let result = runtime.block_on(runner.run(req(true))).unwrap();
+    assert!(result.execution_attempts.iter().all(|a| a.was_cache_hit));
+    assert_eq!(*lines.lock().unwrap(), expected);
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn namespaced_process_user_hides_paths() {
+    let hidden_dir = TempDir::new().unwrap();
+    std::fs::write(hidden_dir.path().join("secret"), "hunter2").unwrap();
+    // The sandbox is beneath the hidden path, but remains visible.
+    let work_dir = hidden_dir.path().join("work");
+    std::fs::create_dir(&work_dir).unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let runner = super::CommandRunner::new(
+      fs::Store::local_only(store_dir.path()).unwrap(),
+      work_dir,
+      true,
+    )
+    .with_process_user(super::ProcessUser::Namespaced {
+      hidden_paths: vec![hidden_dir.path().to_owned()],
+    });
+    let req = ExecuteProcessRequestBuilder::new(
+      owned_string_vec(&[
+        "/bin/sh",
+        "-c",
+        &format!(
+          "/bin/cat {}/secret; /bin/echo -n visible > out",
+          hidden_dir.path().display()
+        ),
+      ]),
+      Duration::from_millis(5000),
+      "hide secret".to_owned(),
+    )
+    .output_files(vec![PathBuf::from("out")].into_iter().collect())
+    .build()
+    .unwrap();
+
+    let result = match tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(runner.run(req))
+    {
+      Ok(result) => result,
+      Err(ref err) if err.starts_with("Error launching process") => {
+        eprintln!("Skipping test: user namespaces are unavailable: {}", err);
+        return;
+      }
+      Err(err) => panic!("Error running process: {}", err),
+    };
+    assert_eq!(result.stdout, as_bytes(""));
+    assert_eq!(result.exit_code, 0);
+    assert_ne!(result.output_directory, fs::EMPTY_DIGEST);
+  }
+
+  #[test]
+  fn output_dir_globs() {
+    let result = run_command_locally(ExecuteProcessRequest {
+      argv: vec![
+        find_bash(),
+        "-c".to_owned(),
+        format!(
+          "/bin/mkdir cats falcons && echo -n {} > cats/roland",
+          TestData::roland().string()
+        ),
+      ],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: vec![PathBuf::from("f*")].into_iter().collect(),
+      timeout: Duration::from_millis(1000),
+      description: "bash".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    });
+
+    assert_eq!(
+      result.unwrap().output_directory,
+      TestDirectory::containing_falcons_dir().digest()
+    );
+  }
+
+  #[test]
+  fn output_dir_recursive_glob_follows_symlinks() {
+    let result = run_command_locally(ExecuteProcessRequest {
+      argv: vec![
+        find_bash(),
+        "-c".to_owned(),
+        format!(
+          "/bin/mkdir cats && echo -n {} > real_roland && /bin/ln -s ../real_roland cats/roland",
+          TestData::roland().string()
+        ),
+      ],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: vec![PathBuf::from("cats/**")].into_iter().collect(),
+      timeout: Duration::from_millis(1000),
+      description: "bash".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    });
+
+    assert_eq!(
+      result.unwrap().output_directory,
+      TestDirectory::nested().digest()
+    );
+  }
+
   #[test]
   fn jdk_symlink() {
     let preserved_work_tmpdir = TempDir::new().unwrap();
@@ -729,7 +2777,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "cat roland".to_string(),
-      jdk_home: Some(preserved_work_tmpdir.path().to_path_buf()),
+      tool_mounts: crate::jdk_tool_mounts(Some(preserved_work_tmpdir.path().to_path_buf())),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
     assert_eq!(
       result,
@@ -739,6 +2796,9 @@ mod tests {
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       })
     )
   }
@@ -761,7 +2821,16 @@ mod tests {
         output_directories: BTreeSet::new(),
         timeout: Duration::from_millis(1000),
         description: "bash".to_string(),
-        jdk_home: None,
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
       },
       preserved_work_root.clone(),
       false,
@@ -796,7 +2865,16 @@ mod tests {
         output_directories: BTreeSet::new(),
         timeout: Duration::from_millis(1000),
         description: "failing execution".to_string(),
-        jdk_home: None,
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
       },
       preserved_work_root.clone(),
       false,
@@ -808,6 +2886,97 @@ mod tests {
     assert_eq!(testutil::file::list_dir(&preserved_work_root).len(), 1);
   }
 
+  #[test]
+  fn keep_sandboxes_on_failure() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner = super::CommandRunner::new(store, work_dir.path().to_owned(), true)
+      .with_keep_sandboxes(KeepSandboxes::OnFailure);
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let req = |exit_code: i32| {
+      let mut env = BTreeMap::new();
+      env.insert("GREETING".to_owned(), "it's me".to_owned());
+      ExecuteProcessRequest {
+        argv: vec![
+          find_bash(),
+          "-c".to_owned(),
+          format!("echo -n \"$GREETING\" && exit {}", exit_code),
+        ],
+        env,
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "greet".to_string(),
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      }
+    };
+
+    runtime.block_on(runner.run(req(0))).unwrap();
+    assert_eq!(testutil::file::list_dir(work_dir.path()).len(), 0);
+
+    let result = runtime.block_on(runner.run(req(3))).unwrap();
+    let subdirs = testutil::file::list_dir(work_dir.path());
+    assert_eq!(subdirs.len(), 1);
+    let sandbox = work_dir.path().join(&subdirs[0]);
+    assert!(String::from_utf8_lossy(&result.stderr).contains(&format!("{:?}", sandbox)));
+
+    // The script reproduces the process, including its environment.
+    let reproduced = std::process::Command::new(sandbox.join("__run.sh"))
+      .output()
+      .unwrap();
+    assert_eq!(reproduced.status.code(), Some(3));
+    assert_eq!(reproduced.stdout, b"it's me".to_vec());
+  }
+
+  #[test]
+  fn run_script_is_written_to_sandbox() {
+    let result = run_command_locally(ExecuteProcessRequest {
+      argv: vec![
+        find_bash(),
+        "-c".to_owned(),
+        "test -x __run.sh && /bin/cat __run.sh".to_owned(),
+      ],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "cat run script".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    })
+    .unwrap();
+    assert_eq!(result.exit_code, 0);
+    let script = String::from_utf8_lossy(&result.stdout);
+    assert!(script.contains("\nmkdir -p __tmp\n"));
+    // The private TMPDIR variables are set to a path in the sandbox.
+    let exec_line = script.lines().last().unwrap();
+    assert!(exec_line.starts_with("exec env -i PATH= 'TEMP="));
+    assert!(exec_line.ends_with(&format!(
+      "/__tmp' '{}' '-c' 'test -x __run.sh && /bin/cat __run.sh'",
+      find_bash()
+    )));
+  }
+
   #[test]
   fn all_containing_directories_for_outputs_are_created() {
     let result = run_command_locally(ExecuteProcessRequest {
@@ -828,7 +2997,16 @@ mod tests {
       output_directories: vec![PathBuf::from("birds/falcons")].into_iter().collect(),
       timeout: Duration::from_millis(1000),
       description: "create nonoverlapping directories and file".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -839,6 +3017,9 @@ mod tests {
         exit_code: 0,
         output_directory: TestDirectory::nested_dir_and_file().digest(),
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     )
   }
@@ -857,7 +3038,16 @@ mod tests {
       output_directories: vec![PathBuf::from("falcons")].into_iter().collect(),
       timeout: Duration::from_millis(1000),
       description: "bash".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     });
 
     assert_eq!(
@@ -868,10 +3058,738 @@ mod tests {
         exit_code: 0,
         output_directory: TestDirectory::containing_falcons_dir().digest(),
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     )
   }
 
+  #[test]
+  fn strict_hermeticity_sanitizes_env() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner =
+      super::CommandRunner::new(store, work_dir.path().to_owned(), true).with_strict_hermeticity();
+
+    let mut env = BTreeMap::new();
+    env.insert("PATH".to_owned(), "/bin:relative:/bin".to_owned());
+    let result = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(runner.run(ExecuteProcessRequest {
+        argv: vec![
+          find_bash(),
+          "-c".to_owned(),
+          "test -d \"$TMPDIR\" && echo -n $PATH".to_owned(),
+        ],
+        env,
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "echo path".to_string(),
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      }))
+      .unwrap();
+
+    assert_eq!(result.stdout, as_bytes("/bin"));
+    assert_eq!(result.exit_code, 0);
+    assert_eq!(
+      runner.hermeticity_violations(),
+      vec![HermeticityViolation {
+        process_description: "echo path".to_owned(),
+        violation: "Relative PATH entry \"relative\" was removed".to_owned(),
+      }]
+    );
+  }
+
+  #[test]
+  fn private_tmpdir() {
+    let result = run_command_locally(ExecuteProcessRequest {
+      argv: vec![
+        find_bash(),
+        "-c".to_owned(),
+        concat!(
+          "test \"$TMPDIR\" = \"$PWD/__tmp\" && test \"$TEMP\" = \"$TMPDIR\" && ",
+          "test \"$TMP\" = \"$TMPDIR\" && echo -n leaked > \"$TMPDIR/leak\""
+        )
+        .to_owned(),
+      ],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: vec![PathBuf::from("__tmp/leak")].into_iter().collect(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "write to tmpdir".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    })
+    .unwrap();
+
+    assert_eq!(result.exit_code, 0);
+    // The private TMPDIR is removed before outputs are captured, so nothing in it can be.
+    assert_eq!(result.output_directory, fs::EMPTY_DIGEST);
+  }
+
+  #[test]
+  fn strict_hermeticity_records_escaping_output_symlinks() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner =
+      super::CommandRunner::new(store, work_dir.path().to_owned(), true).with_strict_hermeticity();
+
+    // Capturing the symlink will fail, but the violation should still be recorded.
+    let _ = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(runner.run(ExecuteProcessRequest {
+        argv: owned_string_vec(&["/bin/ln", "-s", "/etc/passwd", "passwd"]),
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: vec![PathBuf::from("passwd")].into_iter().collect(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "steal passwd".to_string(),
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      }));
+
+    let violations = runner.hermeticity_violations();
+    assert_eq!(violations.len(), 1);
+    assert!(violations[0].violation.contains("outside of the sandbox"));
+  }
+
+  #[test]
+  fn resource_usage() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner = super::CommandRunner::new(store, work_dir.path().to_owned(), true);
+
+    let result = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(runner.run(ExecuteProcessRequest {
+        argv: owned_string_vec(&["/bin/sleep", "0.1"]),
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "sleep".to_string(),
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      }))
+      .unwrap();
+
+    let resource_usage = result.resource_usage.expect("Want resource usage");
+    assert!(resource_usage.wall_time >= Duration::from_millis(100));
+    assert!(resource_usage.max_rss_bytes > 0);
+  }
+
+  #[test]
+  fn cancellation_kills_process_group() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let pid_dir = TempDir::new().unwrap();
+    let pid_file = pid_dir.path().join("pid");
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner = super::CommandRunner::new(store, work_dir.path().to_owned(), true);
+
+    let execution = runner.run(ExecuteProcessRequest {
+      argv: vec![
+        find_bash(),
+        "-c".to_owned(),
+        format!(
+          "sleep 1000 & echo $! > {}.tmp && mv {0}.tmp {0} && wait",
+          pid_file.display()
+        ),
+      ],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_secs(1000),
+      description: "sleep".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    });
+    let sleep_pid_file = pid_file.clone();
+    let started = future::loop_fn((), move |()| {
+      let pid_file = sleep_pid_file.clone();
+      Delay::new(Duration::from_millis(10))
+        .map_err(|err| format!("{}", err))
+        .map(move |()| {
+          if pid_file.exists() {
+            Loop::Break(())
+          } else {
+            Loop::Continue(())
+          }
+        })
+    });
+
+    // Run the process until its child has started, and then drop (i.e. cancel) its execution.
+    match tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(execution.select2(started))
+    {
+      Ok(Either::B(((), execution))) => drop(execution),
+      _ => panic!("Process should not have completed"),
+    }
+
+    // The sandbox is removed, and the child is killed (and reaped, because it was orphaned).
+    assert_eq!(std::fs::read_dir(work_dir.path()).unwrap().count(), 0);
+    let sleep_pid: libc::pid_t = std::fs::read_to_string(&pid_file)
+      .unwrap()
+      .trim()
+      .parse()
+      .unwrap();
+    let killed = (0..100).any(|_| {
+      std::thread::sleep(Duration::from_millis(50));
+      unsafe { libc::kill(sleep_pid, 0) != 0 }
+    });
+    assert!(
+      killed,
+      "Child process {} should have been killed",
+      sleep_pid
+    );
+  }
+
+  #[test]
+  fn named_caches() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let caches_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner = super::CommandRunner::new(store, work_dir.path().to_owned(), true)
+      .with_named_caches(NamedCaches::new(caches_dir.path().to_owned(), 1));
+
+    let mut named_caches = BTreeMap::new();
+    named_caches.insert("pex_root".to_owned(), PathBuf::from(".cache/pex_root"));
+    let result = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(runner.run(ExecuteProcessRequest {
+        argv: vec![
+          find_bash(),
+          "-c".to_owned(),
+          "echo -n wheel > .cache/pex_root/some.whl".to_owned(),
+        ],
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "populate cache".to_string(),
+        tool_mounts: BTreeMap::new(),
+        named_caches,
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      }))
+      .unwrap();
+
+    assert_eq!(result.exit_code, 0);
+    assert!(caches_dir.path().join("pex_root/some.whl").exists());
+  }
+
+  #[test]
+  fn reusable_workdir() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner = super::CommandRunner::new(store.clone(), work_dir.path().to_owned(), true);
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime
+      .block_on(store.record_directory(&TestDirectory::containing_roland().directory(), false))
+      .unwrap();
+    runtime
+      .block_on(store.store_file_bytes(TestData::roland().bytes(), false))
+      .unwrap();
+
+    let req = |command: &str, input_files| ExecuteProcessRequest {
+      argv: vec![find_bash(), "-c".to_owned(), command.to_owned()],
+      env: BTreeMap::new(),
+      input_files,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "incremental".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: Some("incremental".to_owned()),
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    };
+
+    let result = runtime
+      .block_on(runner.run(req("echo -n state > state", fs::EMPTY_DIGEST)))
+      .unwrap();
+    assert_eq!(result.exit_code, 0);
+
+    // The state from the first run is preserved, and the new input is added alongside it.
+    let result = runtime
+      .block_on(runner.run(req(
+        "cat roland state",
+        TestDirectory::containing_roland().digest(),
+      )))
+      .unwrap();
+    assert_eq!(result.exit_code, 0);
+    assert_eq!(
+      result.stdout,
+      as_bytes(&format!("{}state", TestData::roland().string()))
+    );
+  }
+
+  #[test]
+  fn symlinked_inputs() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner = super::CommandRunner::new(store.clone(), work_dir.path().to_owned(), true)
+      .with_materialization_strategy(MaterializationStrategy::Symlink);
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime
+      .block_on(store.record_directory(&TestDirectory::containing_roland().directory(), false))
+      .unwrap();
+    runtime
+      .block_on(store.store_file_bytes(TestData::roland().bytes(), false))
+      .unwrap();
+
+    let result = runtime
+      .block_on(runner.run(ExecuteProcessRequest {
+        argv: vec![
+          find_bash(),
+          "-c".to_owned(),
+          "test -L roland && cat roland".to_owned(),
+        ],
+        env: BTreeMap::new(),
+        input_files: TestDirectory::containing_roland().digest(),
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "cat symlinked roland".to_string(),
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      }))
+      .unwrap();
+
+    assert_eq!(result.exit_code, 0);
+    assert_eq!(result.stdout, TestData::roland().bytes());
+  }
+
+  #[test]
+  fn action_cache() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let counter_dir = TempDir::new().unwrap();
+    let counter = counter_dir.path().join("counter");
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner =
+      super::CommandRunner::new(store, work_dir.path().to_owned(), true).with_action_cache();
+
+    let req = ExecuteProcessRequest {
+      argv: vec![
+        find_bash(),
+        "-c".to_owned(),
+        format!("echo -n run >> {} && echo -n foo", counter.display()),
+      ],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "cached echo".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    };
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let first = runtime.block_on(runner.run(req.clone())).unwrap();
+    let second = runtime.block_on(runner.run(req)).unwrap();
+
+    assert_eq!(first.stdout, as_bytes("foo"));
+    assert_eq!(second.stdout, as_bytes("foo"));
+    assert!(!first.execution_attempts.iter().any(|a| a.was_cache_hit));
+    assert!(second.execution_attempts.iter().all(|a| a.was_cache_hit));
+    assert_eq!(std::fs::read_to_string(&counter).unwrap(), "run");
+  }
+
+  #[test]
+  fn action_cache_with_tools() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let tool_dir = TempDir::new().unwrap();
+    std::fs::write(tool_dir.path().join("version"), "1").unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner =
+      super::CommandRunner::new(store, work_dir.path().to_owned(), true).with_action_cache();
+
+    let mut tool_mounts = BTreeMap::new();
+    tool_mounts.insert(
+      "thing".to_owned(),
+      crate::ToolMount {
+        host_path: tool_dir.path().to_owned(),
+        sandbox_path: PathBuf::from("tools/thing"),
+      },
+    );
+    let req = ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/cat", "tools/thing/version"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "cat tool version".to_string(),
+      tool_mounts,
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    };
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let first = runtime.block_on(runner.run(req.clone())).unwrap();
+    let second = runtime.block_on(runner.run(req.clone())).unwrap();
+    assert_eq!(first.stdout, as_bytes("1"));
+    assert!(second.execution_attempts.iter().all(|a| a.was_cache_hit));
+
+    // Changing the tool changes the cache key.
+    std::fs::write(tool_dir.path().join("version"), "22").unwrap();
+    let third = runtime.block_on(runner.run(req)).unwrap();
+    assert_eq!(third.stdout, as_bytes("22"));
+    assert!(!third.execution_attempts.iter().any(|a| a.was_cache_hit));
+  }
+
+  #[test]
+  fn action_cache_key_covers_passthrough_names_and_tool_paths() {
+    let tool_dir = TempDir::new().unwrap();
+    let other_tool_dir = TempDir::new().unwrap();
+    std::fs::write(tool_dir.path().join("version"), "1").unwrap();
+    std::fs::write(other_tool_dir.path().join("version"), "1").unwrap();
+    let tool_mounts = |host_path: &Path| -> BTreeMap<String, crate::ToolMount> {
+      vec![(
+        "thing".to_owned(),
+        crate::ToolMount {
+          host_path: host_path.to_owned(),
+          sandbox_path: PathBuf::from("tools/thing"),
+        },
+      )]
+      .into_iter()
+      .collect()
+    };
+    let req = crate::ExecuteProcessRequestBuilder::new(
+      owned_string_vec(&["/bin/cat", "tools/thing/version"]),
+      Duration::from_millis(1000),
+      "cat tool version".to_owned(),
+    )
+    .tool_mounts(tool_mounts(tool_dir.path()))
+    .build()
+    .unwrap();
+    let key = super::action_cache_key(&req).unwrap();
+
+    let with_passthrough = ExecuteProcessRequest {
+      env_passthrough: vec!["HOME".to_owned()].into_iter().collect(),
+      ..req.clone()
+    };
+    assert_ne!(super::action_cache_key(&with_passthrough), Some(key));
+
+    // An identical tool elsewhere is a different tool.
+    let with_other_tool = ExecuteProcessRequest {
+      tool_mounts: tool_mounts(other_tool_dir.path()),
+      ..req
+    };
+    assert_ne!(super::action_cache_key(&with_other_tool), Some(key));
+  }
+
+  #[test]
+  fn output_limits() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let full_digest = runtime
+      .block_on(store.store_file_bytes(as_bytes("0123456789abcdef"), false))
+      .unwrap();
+    let req = ExecuteProcessRequest {
+      argv: vec![
+        find_bash(),
+        "-c".to_owned(),
+        "echo -n 0123456789abcdef | tee out.txt".to_owned(),
+      ],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: vec![PathBuf::from("out.txt")].into_iter().collect(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "echo too much".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    };
+    let mut run = |policy: OutputLimitPolicy, output_file_bytes: Option<u64>| {
+      let runner = super::CommandRunner::new(store.clone(), work_dir.path().to_owned(), true)
+        .with_output_limits(OutputLimits {
+          stdio_bytes: Some(10),
+          output_file_bytes,
+          policy,
+        });
+      runtime.block_on(runner.run(req.clone()))
+    };
+
+    let truncated = run(OutputLimitPolicy::Truncate, None).unwrap();
+    assert_eq!(
+      truncated.stdout,
+      as_bytes("0123456789\n[Truncated 6 bytes of stdout exceeding the limit of 10 bytes]\n")
+    );
+
+    run(OutputLimitPolicy::Fail, None).expect_err("Want Err for exceeding stdout limit");
+
+    let spilled = run(OutputLimitPolicy::Spill, None).unwrap();
+    assert_eq!(
+      spilled.stdout,
+      as_bytes(&format!(
+        "0123456789\n[stdout was 16 bytes, exceeding the limit of 10 bytes: it was stored in full \
+         as the file with digest {} 16]\n",
+        full_digest.0.to_hex()
+      ))
+    );
+
+    run(OutputLimitPolicy::Truncate, Some(10)).expect_err("Want Err for exceeding file limit");
+    run(OutputLimitPolicy::Truncate, Some(16)).unwrap();
+  }
+
+  #[test]
+  fn output_files_over_the_limit_are_not_stored() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let other_store_dir = TempDir::new().unwrap();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let output_digest = runtime
+      .block_on(
+        fs::Store::local_only(other_store_dir.path())
+          .unwrap()
+          .store_file_bytes(as_bytes("European Burmese"), false),
+      )
+      .unwrap();
+
+    let runner = super::CommandRunner::new(store.clone(), work_dir.path().to_owned(), true)
+      .with_output_limits(OutputLimits {
+        stdio_bytes: None,
+        output_file_bytes: Some(10),
+        policy: OutputLimitPolicy::Fail,
+      });
+    runtime
+      .block_on(runner.run(ExecuteProcessRequest {
+        argv: vec![
+          find_bash(),
+          "-c".to_owned(),
+          "echo -n European Burmese > roland".to_owned(),
+        ],
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: vec![PathBuf::from("roland")].into_iter().collect(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "write too much".to_string(),
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      }))
+      .expect_err("Want Err for exceeding file limit");
+
+    assert_eq!(
+      runtime
+        .block_on(store.load_file_bytes_with(output_digest, |bytes| bytes))
+        .unwrap(),
+      None
+    );
+  }
+
+  #[test]
+  fn cpu_limit_exceeded() {
+    let result = run_command_locally(ExecuteProcessRequest {
+      argv: vec![
+        find_bash(),
+        "-c".to_owned(),
+        "while true; do :; done".to_owned(),
+      ],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(10000),
+      description: "spin".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits {
+        cpu_seconds: Some(1),
+        ..crate::ResourceLimits::default()
+      },
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    })
+    .unwrap();
+
+    assert_eq!(
+      result.failure_reason,
+      Some(FailureReason::ResourceLimitExceeded(ResourceLimit::CpuTime))
+    );
+  }
+
+  #[test]
+  fn interactive_process_has_a_terminal() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner = super::CommandRunner::new(store, work_dir.path().to_owned(), true);
+
+    let output = SharedBuffer::default();
+    let result = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(runner.run_interactive(
+        InteractiveProcessRequest {
+          argv: owned_string_vec(&["/bin/sh", "-c", "test -t 0 && test -t 1 && echo tty"]),
+          env: BTreeMap::new(),
+          input_files: fs::EMPTY_DIGEST,
+          description: "check for tty".to_string(),
+        },
+        std::io::empty(),
+        output.clone(),
+      ))
+      .unwrap();
+
+    assert_eq!(result, InteractiveProcessResult { exit_code: 0 });
+    // The terminal translates newlines.
+    assert_eq!(*output.0.lock().unwrap(), b"tty\r\n".to_vec());
+  }
+
+  #[test]
+  fn interactive_process_with_empty_argv_is_error() {
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+    let runner = super::CommandRunner::new(store, work_dir.path().to_owned(), true);
+
+    let err = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(runner.run_interactive(
+        InteractiveProcessRequest {
+          argv: vec![],
+          env: BTreeMap::new(),
+          input_files: fs::EMPTY_DIGEST,
+          description: "nothing".to_string(),
+        },
+        std::io::empty(),
+        SharedBuffer::default(),
+      ))
+      .expect_err("Want Err for empty argv");
+    assert!(err.contains("argv must not be empty"), "{}", err);
+  }
+
+  #[derive(Clone, Default)]
+  struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+  impl std::io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      self.0.lock().unwrap().extend_from_slice(buf);
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
   fn run_command_locally(
     req: ExecuteProcessRequest,
   ) -> Result<FallibleExecuteProcessResult, String> {
@@ -896,11 +3814,25 @@ mod tests {
     let runner = super::CommandRunner {
       store: store,
       work_dir: dir,
-      cleanup_local_dirs: cleanup,
+      keep_sandboxes: if cleanup {
+        super::KeepSandboxes::Never
+      } else {
+        super::KeepSandboxes::Always
+      },
+      output_listener: None,
+      console: None,
+      named_caches: None,
+      action_cache: false,
+      materialization_strategy: MaterializationStrategy::Copy,
+      output_limits: super::OutputLimits::default(),
+      process_user: None,
+      reusable_workdir_locks: KeyedSemaphore::new(1),
+      hermeticity_violations: None,
     };
     tokio::runtime::Runtime::new()
       .unwrap()
       .block_on(runner.run(req))
+      .map(FallibleExecuteProcessResult::without_resource_usage)
   }
 
   fn find_bash() -> String {
@@ -0,0 +1,488 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use async_semaphore::{AsyncSemaphore, Permit};
+use boxfuture::{try_future, BoxFuture, Boxable};
+use futures::future::{self, Either, Future, Loop};
+use futures::sync::oneshot;
+use futures_timer::Delay;
+use log::{debug, warn};
+use tempfile::TempDir;
+
+///
+/// How long to wait between attempts to find a permit for a new server, when all of them are held
+/// by servers which are in use.
+///
+const PERMIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+///
+/// How long a health check waits to connect to a server.
+///
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+///
+/// How to start a nailgun server: servers started from equal specs are interchangeable.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ServerSpec {
+  ///
+  /// A command line which starts a nailgun server listening on an ephemeral port of localhost, and
+  /// which prints the port that it is listening on as nailgun's NGServer does (e.g. `java -cp
+  /// nailgun-server.jar com.martiansoftware.nailgun.NGServer 127.0.0.1:0`).
+  ///
+  pub argv: Vec<String>,
+  ///
+  /// The environment to start the server with: no other environment variables are set.
+  ///
+  pub env: BTreeMap<String, String>,
+}
+
+///
+/// A pool of nailgun servers (i.e. of warm JVMs), which are started on demand, and reused by
+/// later borrowers with the same ServerSpec.
+///
+/// The number of live servers (in use or idle) is capped: when the cap has been reached, idle
+/// servers for other specs are killed to make room, or else borrowers wait for a server to be
+/// returned. Idle servers are health-checked before they are reused, and are killed once they have
+/// been idle for longer than the idle timeout. Expiry happens whenever the pool is used, or when
+/// `expire_idle` is called.
+///
+#[derive(Clone)]
+pub struct NailgunPool {
+  inner: Arc<Inner>,
+}
+
+struct Inner {
+  work_dir: PathBuf,
+  startup_timeout: Duration,
+  idle_timeout: Duration,
+  live_servers: AsyncSemaphore,
+  // Ordered from least to most recently returned.
+  idle: Mutex<Vec<IdleServer>>,
+}
+
+impl NailgunPool {
+  pub fn new(
+    work_dir: PathBuf,
+    max_servers: usize,
+    startup_timeout: Duration,
+    idle_timeout: Duration,
+  ) -> NailgunPool {
+    NailgunPool {
+      inner: Arc::new(Inner {
+        work_dir,
+        startup_timeout,
+        idle_timeout,
+        live_servers: AsyncSemaphore::new(max_servers),
+        idle: Mutex::new(vec![]),
+      }),
+    }
+  }
+
+  ///
+  /// Borrows a healthy server for the given spec, starting one if no idle server is available.
+  ///
+  pub fn acquire(&self, spec: ServerSpec) -> BoxFuture<BorrowedServer, String> {
+    let pool = self.clone();
+    future::loop_fn((), move |()| {
+      pool.expire_idle();
+      if let Some(server) = pool.take_healthy_idle(&spec) {
+        return future::ok(Loop::Break(pool.borrowed(server))).to_boxed();
+      }
+      if let Some(permit) = pool.inner.live_servers.try_acquire() {
+        let pool2 = pool.clone();
+        return Server::start(&pool.inner, spec.clone(), permit)
+          .map(move |server| Loop::Break(pool2.borrowed(server)))
+          .to_boxed();
+      }
+      // Every permit is held: make room by killing the least recently used idle server (which
+      // must be for another spec), or else wait for a server to be returned.
+      let evicted = {
+        let mut idle = pool.inner.idle.lock().unwrap();
+        if idle.is_empty() {
+          None
+        } else {
+          Some(idle.remove(0))
+        }
+      };
+      match evicted {
+        Some(evicted) => {
+          debug!("Evicting idle nailgun server for {:?}", evicted.server.spec);
+          drop(evicted);
+          future::ok(Loop::Continue(())).to_boxed()
+        }
+        None => Delay::new(PERMIT_POLL_INTERVAL)
+          .map_err(|err| format!("Error waiting for a nailgun server: {}", err))
+          .map(|()| Loop::Continue(()))
+          .to_boxed(),
+      }
+    })
+    .to_boxed()
+  }
+
+  ///
+  /// Kills any servers which have been idle for longer than the idle timeout.
+  ///
+  pub fn expire_idle(&self) {
+    let expired: Vec<IdleServer> = {
+      let mut idle = self.inner.idle.lock().unwrap();
+      let (expired, remaining): (Vec<_>, Vec<_>) = idle
+        .drain(..)
+        .partition(|server| server.idle_since.elapsed() >= self.inner.idle_timeout);
+      *idle = remaining;
+      expired
+    };
+    for server in &expired {
+      debug!("Expiring idle nailgun server for {:?}", server.server.spec);
+    }
+  }
+
+  fn take_healthy_idle(&self, spec: &ServerSpec) -> Option<Server> {
+    loop {
+      let candidate = {
+        let mut idle = self.inner.idle.lock().unwrap();
+        let position = idle
+          .iter()
+          .rposition(|server| &server.server.spec == spec)?;
+        idle.remove(position)
+      };
+      let mut server = candidate.server;
+      match server.health_check() {
+        Ok(()) => return Some(server),
+        Err(err) => warn!(
+          "Discarding unhealthy nailgun server for {:?}: {}",
+          spec, err
+        ),
+      }
+    }
+  }
+
+  fn borrowed(&self, server: Server) -> BorrowedServer {
+    BorrowedServer {
+      server: Some(server),
+      pool: self.inner.clone(),
+    }
+  }
+}
+
+///
+/// A live server, which is killed when dropped (releasing its permit).
+///
+struct Server {
+  spec: ServerSpec,
+  child: Child,
+  port: u16,
+  workdir: TempDir,
+  _permit: Permit,
+}
+
+impl Server {
+  fn start(inner: &Inner, spec: ServerSpec, permit: Permit) -> BoxFuture<Server, String> {
+    if spec.argv.is_empty() {
+      return future::err("Nailgun server argv must not be empty".to_owned()).to_boxed();
+    }
+    let workdir = try_future!(tempfile::Builder::new()
+      .prefix("nailgun-server")
+      .tempdir_in(&inner.work_dir)
+      .map_err(|err| format!("Error making workdir for nailgun server: {:?}", err)));
+    let mut child = try_future!(Command::new(&spec.argv[0])
+      .args(&spec.argv[1..])
+      .env_clear()
+      .envs(&spec.env)
+      .current_dir(workdir.path())
+      .stdin(Stdio::null())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::null())
+      .spawn()
+      .map_err(|err| format!("Error starting nailgun server {:?}: {:?}", spec.argv, err)));
+
+    // The server announces its port on stdout: the rest of its output is discarded, so that it
+    // never blocks writing it.
+    let stdout = child.stdout.take().unwrap();
+    let (sender, receiver) = oneshot::channel();
+    let spawned = thread::Builder::new()
+      .name("nailgun-server-output".to_owned())
+      .spawn(move || {
+        let mut sender = Some(sender);
+        for line in BufReader::new(stdout).lines() {
+          let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+          };
+          if let Some(port) = parse_port(&line) {
+            if let Some(sender) = sender.take() {
+              let _ = sender.send(port);
+            }
+          }
+        }
+      });
+    if let Err(err) = spawned {
+      let _ = child.kill();
+      let _ = child.wait();
+      return future::err(format!(
+        "Error starting thread to read nailgun server output: {:?}",
+        err
+      ))
+      .to_boxed();
+    }
+
+    let startup_timeout = inner.startup_timeout;
+    let started = receiver
+      .map_err(|_| "Nailgun server exited without announcing its port".to_owned())
+      .select2(Delay::new(startup_timeout))
+      .then(move |res| match res {
+        Ok(Either::A((port, _))) => Ok(port),
+        Ok(Either::B(((), _))) => Err(format!(
+          "Nailgun server did not announce its port within {:?}",
+          startup_timeout
+        )),
+        Err(Either::A((err, _))) => Err(err),
+        Err(Either::B((err, _))) => Err(format!("Error waiting for nailgun server: {}", err)),
+      });
+    started
+      .then(move |res| match res {
+        Ok(port) => {
+          debug!("Started nailgun server for {:?} on port {}", spec, port);
+          Ok(Server {
+            spec,
+            child,
+            port,
+            workdir,
+            _permit: permit,
+          })
+        }
+        Err(err) => {
+          let _ = child.kill();
+          let _ = child.wait();
+          Err(err)
+        }
+      })
+      .to_boxed()
+  }
+
+  fn address(&self) -> SocketAddr {
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, self.port))
+  }
+
+  ///
+  /// Checks that the server is still running, and accepting connections.
+  ///
+  fn health_check(&mut self) -> Result<(), String> {
+    match self.child.try_wait() {
+      Ok(None) => {}
+      Ok(Some(status)) => return Err(format!("Exited with {}", status)),
+      Err(err) => return Err(format!("Error checking whether it is running: {}", err)),
+    }
+    TcpStream::connect_timeout(&self.address(), HEALTH_CHECK_TIMEOUT)
+      .map(|_| ())
+      .map_err(|err| format!("Error connecting to port {}: {}", self.port, err))
+  }
+}
+
+impl Drop for Server {
+  fn drop(&mut self) {
+    let _ = self.child.kill();
+    let _ = self.child.wait();
+  }
+}
+
+struct IdleServer {
+  server: Server,
+  idle_since: Instant,
+}
+
+///
+/// A server borrowed from a NailgunPool, which is returned to the pool when dropped.
+///
+pub struct BorrowedServer {
+  // Only None once the server has been discarded or returned.
+  server: Option<Server>,
+  pool: Arc<Inner>,
+}
+
+impl BorrowedServer {
+  pub fn address(&self) -> SocketAddr {
+    self.server().address()
+  }
+
+  pub fn pid(&self) -> u32 {
+    self.server().child.id()
+  }
+
+  ///
+  /// The working directory of the server, which the nails that it runs share.
+  ///
+  pub fn workdir(&self) -> &Path {
+    self.server().workdir.path()
+  }
+
+  ///
+  /// Kills the server rather than returning it to the pool, e.g. because a nail left it in a bad
+  /// state.
+  ///
+  pub fn discard(mut self) {
+    self.server = None;
+  }
+
+  fn server(&self) -> &Server {
+    self
+      .server
+      .as_ref()
+      .expect("A BorrowedServer always has a server until it is dropped.")
+  }
+}
+
+impl Drop for BorrowedServer {
+  fn drop(&mut self) {
+    if let Some(server) = self.server.take() {
+      self.pool.idle.lock().unwrap().push(IdleServer {
+        server,
+        idle_since: Instant::now(),
+      });
+    }
+  }
+}
+
+///
+/// Parses the port from the line that NGServer prints once it has started, e.g.
+/// `NGServer 0.9.1 started on 127.0.0.1, port 43567.`
+///
+fn parse_port(line: &str) -> Option<u16> {
+  let start = line.find("port ")? + "port ".len();
+  let digits: String = line[start..]
+    .chars()
+    .take_while(char::is_ascii_digit)
+    .collect();
+  digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{parse_port, NailgunPool, ServerSpec};
+  use futures::Future;
+  use std::collections::BTreeMap;
+  use std::net::TcpListener;
+  use std::time::Duration;
+  use tempfile::TempDir;
+
+  ///
+  /// A spec for a fake server, which announces the port of a listener in the test process.
+  ///
+  fn spec(listener: &TcpListener, name: &str) -> ServerSpec {
+    let port = listener.local_addr().unwrap().port();
+    ServerSpec {
+      argv: vec![
+        "/bin/sh".to_owned(),
+        "-c".to_owned(),
+        format!(
+          "echo 'NGServer started on 127.0.0.1, port {}.' && exec /bin/sleep 60 # {}",
+          port, name
+        ),
+      ],
+      env: BTreeMap::new(),
+    }
+  }
+
+  fn pool(work_dir: &TempDir, max_servers: usize, idle_timeout: Duration) -> NailgunPool {
+    NailgunPool::new(
+      work_dir.path().to_owned(),
+      max_servers,
+      Duration::from_secs(5),
+      idle_timeout,
+    )
+  }
+
+  #[test]
+  fn parses_port() {
+    assert_eq!(
+      parse_port("NGServer 0.9.1 started on 127.0.0.1, port 43567."),
+      Some(43567)
+    );
+    assert_eq!(parse_port("Starting up"), None);
+  }
+
+  #[test]
+  fn reuses_idle_servers() {
+    let work_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let pool = pool(&work_dir, 2, Duration::from_secs(60));
+
+    let server = pool.acquire(spec(&listener, "a")).wait().unwrap();
+    assert_eq!(server.address(), listener.local_addr().unwrap());
+    let pid = server.pid();
+    drop(server);
+    assert_eq!(
+      pool.acquire(spec(&listener, "a")).wait().unwrap().pid(),
+      pid
+    );
+
+    // A discarded server is not reused.
+    let server = pool.acquire(spec(&listener, "a")).wait().unwrap();
+    server.discard();
+    assert_ne!(
+      pool.acquire(spec(&listener, "a")).wait().unwrap().pid(),
+      pid
+    );
+  }
+
+  #[test]
+  fn replaces_unhealthy_servers() {
+    let work_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let pool = pool(&work_dir, 2, Duration::from_secs(60));
+
+    let server = pool.acquire(spec(&listener, "a")).wait().unwrap();
+    let pid = server.pid();
+    drop(server);
+    unsafe {
+      libc::kill(pid as libc::pid_t, libc::SIGKILL);
+    }
+    assert_ne!(
+      pool.acquire(spec(&listener, "a")).wait().unwrap().pid(),
+      pid
+    );
+  }
+
+  #[test]
+  fn expires_idle_servers() {
+    let work_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let pool = pool(&work_dir, 2, Duration::from_millis(0));
+
+    let pid = pool.acquire(spec(&listener, "a")).wait().unwrap().pid();
+    assert_ne!(
+      pool.acquire(spec(&listener, "a")).wait().unwrap().pid(),
+      pid
+    );
+  }
+
+  #[test]
+  fn caps_live_servers() {
+    let work_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let pool = pool(&work_dir, 1, Duration::from_secs(60));
+
+    let a = pool.acquire(spec(&listener, "a")).wait().unwrap();
+    let a_pid = a.pid();
+    let b = pool.acquire(spec(&listener, "b"));
+    // While a is in use, b can't be started...
+    let b = match b
+      .select2(futures_timer::Delay::new(Duration::from_millis(200)))
+      .wait()
+    {
+      Ok(futures::future::Either::B((_, b))) => b,
+      _ => panic!("Want the second server to wait for the first"),
+    };
+    // ...but once a is returned, it is evicted to make room for b.
+    drop(a);
+    let b = b.wait().unwrap();
+    assert_ne!(b.pid(), a_pid);
+    assert_ne!(unsafe { libc::kill(a_pid as libc::pid_t, 0) }, 0);
+  }
+}
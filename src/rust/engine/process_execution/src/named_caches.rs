@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::fs::create_dir_all;
+use std::os::unix::fs::symlink;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use async_semaphore::KeyedSemaphore;
+use boxfuture::{try_future, BoxFuture, Boxable};
+use fs::FileLock;
+use futures::future::{self, Loop};
+use futures::Future;
+use futures_timer::Delay;
+
+///
+/// How often a process waits to retry taking the lock of a cache which another process holds.
+///
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+///
+/// Persistent, append-only directories (e.g. for the caches of resolvers like pip or coursier)
+/// which are shared between sandboxes by name: processes declare the names of the caches they use,
+/// and where in their sandbox to find them.
+///
+/// Tools are expected to only ever add to their caches. Processes using a cache hold a per-cache
+/// lock while they run, of which there are `concurrency_per_cache` permits: tools which can
+/// safely append to their caches concurrently should use more than one.
+///
+/// Since the base directory may be shared by several pantsd instances, each cache is also locked
+/// with a lock file next to it: exclusively if there is one permit per cache, and otherwise shared
+/// (so that the processes of other instances may use it concurrently).
+///
+#[derive(Clone)]
+pub struct NamedCaches {
+  base_dir: PathBuf,
+  concurrency_per_cache: usize,
+  locks: KeyedSemaphore<String>,
+}
+
+impl NamedCaches {
+  pub fn new(base_dir: PathBuf, concurrency_per_cache: usize) -> NamedCaches {
+    NamedCaches {
+      base_dir,
+      concurrency_per_cache,
+      locks: KeyedSemaphore::new(concurrency_per_cache),
+    }
+  }
+
+  pub fn cache_dir(&self, name: &str) -> PathBuf {
+    self.base_dir.join(name)
+  }
+
+  ///
+  /// Symlinks each of the given caches (creating them if necessary) to its relative path in the
+  /// given sandbox.
+  ///
+  pub fn link_into(
+    &self,
+    sandbox: &Path,
+    caches: &BTreeMap<String, PathBuf>,
+  ) -> Result<(), String> {
+    for (name, dest) in caches {
+      if name.is_empty()
+        || !name
+          .chars()
+          .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+      {
+        return Err(format!(
+          "Named cache names must consist of lowercase letters, digits and underscores: {:?}",
+          name
+        ));
+      }
+      if dest.components().any(|component| match component {
+        Component::Normal(_) | Component::CurDir => false,
+        _ => true,
+      }) {
+        return Err(format!(
+          "Named cache {:?} must be linked to a relative path within the sandbox, but got {:?}",
+          name, dest
+        ));
+      }
+
+      let cache_dir = self.cache_dir(name);
+      create_dir_all(&cache_dir)
+        .map_err(|err| format!("Error making named cache {:?}: {:?}", cache_dir, err))?;
+      let link = sandbox.join(dest);
+      if let Some(parent) = link.parent() {
+        create_dir_all(parent).map_err(|err| {
+          format!(
+            "Error making parent directory for named cache {:?}: {:?}",
+            link, err
+          )
+        })?;
+      }
+      symlink(&cache_dir, &link)
+        .map_err(|err| format!("Error linking named cache {:?}: {:?}", link, err))?;
+    }
+    Ok(())
+  }
+
+  ///
+  /// Runs the given (lazy) Future while holding the locks for the given caches.
+  ///
+  /// The locks are always acquired in the order of the names, so that processes using overlapping
+  /// sets of caches cannot deadlock.
+  ///
+  pub fn with_locked<T: Send + 'static>(
+    &self,
+    mut names: Vec<String>,
+    f: BoxFuture<T, String>,
+  ) -> BoxFuture<T, String> {
+    names.sort();
+    names.dedup();
+    names.reverse();
+    self.with_locked_sorted(names, f)
+  }
+
+  fn with_locked_sorted<T: Send + 'static>(
+    &self,
+    mut names: Vec<String>,
+    f: BoxFuture<T, String>,
+  ) -> BoxFuture<T, String> {
+    match names.pop() {
+      Some(name) => {
+        let named_caches = self.clone();
+        self.locks.with_acquired(name.clone(), move |_slot| {
+          named_caches
+            .lock_file(&name)
+            .and_then(move |lock| {
+              named_caches.with_locked_sorted(names, f).map(move |t| {
+                drop(lock);
+                t
+              })
+            })
+            .to_boxed()
+        })
+      }
+      None => f,
+    }
+  }
+
+  ///
+  /// Takes the lock file of the given cache, which excludes the processes of other pantsd
+  /// instances (which can't see our in-process locks). Locks are polled for, rather than waited
+  /// for, so that waiting doesn't block the thread.
+  ///
+  fn lock_file(&self, name: &str) -> BoxFuture<FileLock, String> {
+    try_future!(create_dir_all(&self.base_dir).map_err(|err| format!(
+      "Error making named caches directory {:?}: {:?}",
+      self.base_dir, err
+    )));
+    let path = self.base_dir.join(format!("{}.lock", name));
+    let exclusive = self.concurrency_per_cache == 1;
+    future::loop_fn((), move |()| {
+      let lock = if exclusive {
+        FileLock::try_exclusive(&path)
+      } else {
+        FileLock::try_shared(&path)
+      };
+      match lock {
+        Ok(Some(lock)) => future::ok(Loop::Break(lock)).to_boxed(),
+        Ok(None) => Delay::new(LOCK_POLL_INTERVAL)
+          .map(Loop::Continue)
+          .map_err(|err| format!("Error waiting for named cache lock: {}", err))
+          .to_boxed(),
+        Err(err) => future::err(err).to_boxed(),
+      }
+    })
+    .to_boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::NamedCaches;
+  use boxfuture::Boxable;
+  use fs::FileLock;
+  use futures::{future, Future};
+  use std::collections::BTreeMap;
+  use std::path::PathBuf;
+  use std::sync::mpsc;
+  use std::time::Duration;
+  use tempfile::TempDir;
+
+  #[test]
+  fn link_into() {
+    let base_dir = TempDir::new().unwrap();
+    let sandbox = TempDir::new().unwrap();
+    let named_caches = NamedCaches::new(base_dir.path().to_owned(), 1);
+
+    let mut caches = BTreeMap::new();
+    caches.insert("pex_root".to_owned(), PathBuf::from(".cache/pex_root"));
+    named_caches.link_into(sandbox.path(), &caches).unwrap();
+
+    std::fs::write(sandbox.path().join(".cache/pex_root/marker"), "hi").unwrap();
+    assert_eq!(
+      std::fs::read_to_string(base_dir.path().join("pex_root/marker")).unwrap(),
+      "hi"
+    );
+  }
+
+  #[test]
+  fn link_into_rejects_invalid_caches() {
+    let base_dir = TempDir::new().unwrap();
+    let sandbox = TempDir::new().unwrap();
+    let named_caches = NamedCaches::new(base_dir.path().to_owned(), 1);
+
+    let mut caches = BTreeMap::new();
+    caches.insert("../escape".to_owned(), PathBuf::from("cache"));
+    named_caches
+      .link_into(sandbox.path(), &caches)
+      .expect_err("Want Err for invalid name");
+
+    let mut caches = BTreeMap::new();
+    caches.insert("coursier".to_owned(), PathBuf::from("../cache"));
+    named_caches
+      .link_into(sandbox.path(), &caches)
+      .expect_err("Want Err for destination outside of the sandbox");
+  }
+
+  #[test]
+  fn with_locked_waits_for_other_processes() {
+    let base_dir = TempDir::new().unwrap();
+    let named_caches = NamedCaches::new(base_dir.path().to_owned(), 1);
+
+    // As if the process of another pantsd instance were using the cache.
+    let lock = FileLock::try_exclusive(&base_dir.path().join("pip.lock"))
+      .unwrap()
+      .expect("Cache was already locked");
+    let (sender, receiver) = mpsc::channel();
+    let thread = std::thread::spawn(move || {
+      named_caches
+        .with_locked(vec!["pip".to_owned()], future::ok(()).to_boxed())
+        .wait()
+        .map(|()| sender.send(()).unwrap())
+    });
+    assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+
+    std::mem::drop(lock);
+    receiver
+      .recv_timeout(Duration::from_secs(5))
+      .expect("Cache wasn't locked once the other process released it");
+    thread
+      .join()
+      .unwrap()
+      .expect("Error running with cache locked");
+  }
+}
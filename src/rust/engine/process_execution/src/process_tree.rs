@@ -0,0 +1,138 @@
+// Trees of processes, rooted at a spawned child, which can be killed together and whose resource
+// usage can be measured: process groups.
+//
+// `isolate` prepares a Command so that the child it spawns can be adopted as the root of a new
+// ProcessTree, which can then wait for the child to exit (returning its exit code, or the negation
+// of the signal which killed it, and its resource usage) or kill the whole tree.
+
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+use crate::ResourceUsage;
+
+///
+/// Runs the child in its own process group.
+///
+pub fn isolate(command: &mut Command) {
+  unsafe {
+    command.before_exec(|| {
+      if libc::setpgid(0, 0) == 0 {
+        Ok(())
+      } else {
+        Err(std::io::Error::last_os_error())
+      }
+    });
+  }
+}
+
+///
+/// The process group of an isolated child, whose id is the pid of the child.
+///
+#[derive(Clone, Debug)]
+pub struct ProcessTree {
+  pgid: u32,
+}
+
+impl ProcessTree {
+  pub fn adopt(child: &Child) -> Result<ProcessTree, String> {
+    Ok(ProcessTree { pgid: child.id() })
+  }
+
+  ///
+  /// Asks every process in the tree to exit, with SIGTERM.
+  ///
+  pub fn terminate(&self) {
+    self.signal(libc::SIGTERM)
+  }
+
+  pub fn kill(&self) {
+    self.signal(libc::SIGKILL)
+  }
+
+  ///
+  /// Sends the given signal to every process in the group, ignoring failures (which are most
+  /// likely because the group has already exited).
+  ///
+  fn signal(&self, signal: libc::c_int) {
+    unsafe {
+      libc::kill(-(self.pgid as libc::pid_t), signal);
+    }
+  }
+
+  ///
+  /// The child is reaped using `wait4` (rather than via the Child), so that its resource usage
+  /// can be collected. This only covers the descendants of the child which it waited for.
+  ///
+  pub fn wait(&self, child: Child, started: Instant) -> Result<(i32, ResourceUsage), String> {
+    let pid = child.id();
+    let mut status = 0;
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+      let waited = unsafe { libc::wait4(pid as libc::pid_t, &mut status, 0, &mut rusage) };
+      if waited == pid as libc::pid_t {
+        break;
+      }
+      let err = std::io::Error::last_os_error();
+      if err.kind() != std::io::ErrorKind::Interrupted {
+        return Err(format!("Error waiting for process {}: {}", pid, err));
+      }
+    }
+    let exit_code = if libc::WIFEXITED(status) {
+      libc::WEXITSTATUS(status)
+    } else {
+      -libc::WTERMSIG(status)
+    };
+    Ok((exit_code, resource_usage(started.elapsed(), &rusage)))
+  }
+}
+
+fn resource_usage(wall_time: Duration, rusage: &libc::rusage) -> ResourceUsage {
+  let duration = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, (tv.tv_usec as u32) * 1000);
+  // ru_maxrss is in kilobytes on Linux, but in bytes on macOS.
+  let max_rss_bytes = if cfg!(target_os = "macos") {
+    rusage.ru_maxrss as u64
+  } else {
+    rusage.ru_maxrss as u64 * 1024
+  };
+  ResourceUsage {
+    wall_time,
+    user_time: duration(rusage.ru_utime),
+    system_time: duration(rusage.ru_stime),
+    max_rss_bytes,
+    block_input_operations: rusage.ru_inblock as u64,
+    block_output_operations: rusage.ru_oublock as u64,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{isolate, ProcessTree};
+  use std::process::Command;
+  use std::time::{Duration, Instant};
+  use tempfile::TempDir;
+
+  #[test]
+  fn kill_kills_descendants() {
+    let dir = TempDir::new().unwrap();
+    let marker = dir.path().join("marker");
+    let mut command = Command::new("/bin/sh");
+    // The grandchild outlives the child (and creates the marker) unless it is killed with it.
+    command.args(&[
+      "-c",
+      &format!("(sleep 1; touch {}) & wait", marker.display()),
+    ]);
+    isolate(&mut command);
+    let child = command.spawn().unwrap();
+    let tree = ProcessTree::adopt(&child).unwrap();
+
+    let started = Instant::now();
+    tree.kill();
+    let (exit_code, usage) = tree.wait(child, started).unwrap();
+    assert_eq!(exit_code, -libc::SIGKILL);
+    assert!(usage.wall_time < Duration::from_secs(1));
+
+    std::thread::sleep(Duration::from_millis(1500));
+    assert!(!marker.exists());
+  }
+}
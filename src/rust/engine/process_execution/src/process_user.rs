@@ -0,0 +1,340 @@
+// Running local processes as a ProcessUser other than ourselves, so that untrusted processes can't
+// read our credentials or home directory: by switching uid and gid (which requires privileges), or
+// on Linux by hiding paths in a private mount namespace (which doesn't).
+
+use std::path::PathBuf;
+
+use super::local::ProcessUser;
+
+///
+/// A ProcessUser, prepared (before the child is forked) so that the child can switch to it
+/// between fork and exec without allocating.
+///
+pub struct PreparedUser {
+  kind: imp::Kind,
+}
+
+impl PreparedUser {
+  ///
+  /// Prepares to run a process as the given user, with the given writable paths (its sandbox, which
+  /// contains its private TMPDIR) and referring to the given readable paths (its tools and named
+  /// caches).
+  ///
+  pub fn prepare(
+    user: &ProcessUser,
+    writable: &[PathBuf],
+    readable: &[PathBuf],
+  ) -> Result<PreparedUser, String> {
+    imp::prepare(user, writable, readable)
+  }
+
+  ///
+  /// Switches the current process to the user. Only to be called in a child process between fork
+  /// and exec.
+  ///
+  pub fn enter(&self) -> std::io::Result<()> {
+    self.kind.enter()
+  }
+}
+
+mod imp {
+  use std::ffi::CString;
+  use std::fs;
+  use std::io;
+  use std::os::unix::ffi::OsStrExt;
+  use std::path::{Path, PathBuf};
+  use std::ptr;
+
+  use super::super::local::ProcessUser;
+  use super::PreparedUser;
+
+  pub enum Kind {
+    Ids {
+      uid: libc::uid_t,
+      gid: libc::gid_t,
+    },
+    #[cfg(target_os = "linux")]
+    Namespaced(namespace::Namespace),
+  }
+
+  impl Kind {
+    pub fn enter(&self) -> io::Result<()> {
+      match self {
+        Kind::Ids { uid, gid } => unsafe {
+          // Supplementary groups are dropped first, because doing so needs privileges.
+          check(libc::setgroups(0, ptr::null()))?;
+          check(libc::setgid(*gid))?;
+          check(libc::setuid(*uid))
+        },
+        #[cfg(target_os = "linux")]
+        Kind::Namespaced(namespace) => namespace.enter(),
+      }
+    }
+  }
+
+  pub fn prepare(
+    user: &ProcessUser,
+    writable: &[PathBuf],
+    readable: &[PathBuf],
+  ) -> Result<PreparedUser, String> {
+    let kind = match user {
+      ProcessUser::Ids { uid, gid } => {
+        // Processes need to be able to create their outputs, and we are privileged, so can still
+        // capture and clean them up afterwards.
+        for path in writable {
+          chown_tree(path, *uid, *gid)
+            .map_err(|err| format!("Error giving {:?} to uid {}: {:?}", path, uid, err))?;
+        }
+        Kind::Ids {
+          uid: *uid,
+          gid: *gid,
+        }
+      }
+      #[cfg(target_os = "linux")]
+      ProcessUser::Namespaced { hidden_paths } => {
+        let exposed: Vec<PathBuf> = writable.iter().chain(readable).cloned().collect();
+        Kind::Namespaced(namespace::Namespace::new(hidden_paths, &exposed)?)
+      }
+      #[cfg(not(target_os = "linux"))]
+      ProcessUser::Namespaced { .. } => {
+        let _ = readable;
+        return Err("Namespaced process users are only supported on Linux".to_owned());
+      }
+    };
+    Ok(PreparedUser { kind })
+  }
+
+  fn chown_tree(path: &Path, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    let c_path = c_path(path)?;
+    if unsafe { libc::lchown(c_path.as_ptr(), uid, gid) } != 0 {
+      return Err(io::Error::last_os_error());
+    }
+    if fs::symlink_metadata(path)?.is_dir() {
+      for entry in fs::read_dir(path)? {
+        chown_tree(&entry?.path(), uid, gid)?;
+      }
+    }
+    Ok(())
+  }
+
+  fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+      .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+  }
+
+  fn check(res: libc::c_int) -> io::Result<()> {
+    if res == 0 {
+      Ok(())
+    } else {
+      Err(io::Error::last_os_error())
+    }
+  }
+
+  #[cfg(target_os = "linux")]
+  mod namespace {
+    use std::ffi::CString;
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::{Path, PathBuf};
+    use std::ptr;
+
+    use super::{c_path, check};
+
+    ///
+    /// A path which is exposed to the process despite being beneath a hidden path, by
+    /// bind-mounting it back into place from a file descriptor opened before it was hidden.
+    ///
+    struct Exposed {
+      // Held open until the process has been spawned.
+      _source: File,
+      source_path: CString,
+      // The directories (from the outermost) between the hidden path and the exposed path, which
+      // need to be recreated in the empty tmpfs over the hidden path.
+      ancestors: Vec<CString>,
+      target: CString,
+      is_dir: bool,
+    }
+
+    ///
+    /// New user and mount namespaces in which our own uid and gid are mapped to themselves, and in
+    /// which the hidden paths are covered by empty tmpfs mounts.
+    ///
+    pub struct Namespace {
+      setgroups_path: CString,
+      uid_map_path: CString,
+      uid_map: Vec<u8>,
+      gid_map_path: CString,
+      gid_map: Vec<u8>,
+      root: CString,
+      tmpfs: CString,
+      hidden: Vec<CString>,
+      exposed: Vec<Exposed>,
+    }
+
+    fn c_string(s: &str) -> CString {
+      CString::new(s).expect("Static strings contain no nul bytes")
+    }
+
+    impl Namespace {
+      pub fn new(hidden_paths: &[PathBuf], exposed_paths: &[PathBuf]) -> Result<Namespace, String> {
+        let canonicalize = |path: &PathBuf| {
+          path
+            .canonicalize()
+            .map_err(|err| format!("Error resolving {:?}: {:?}", path, err))
+        };
+        let hidden_paths = hidden_paths
+          .iter()
+          .map(canonicalize)
+          .collect::<Result<Vec<_>, _>>()?;
+        // Paths beneath other hidden paths are already hidden (and mounting over them would fail,
+        // because they don't exist in the tmpfs over the outer path).
+        let hidden_paths: Vec<PathBuf> = hidden_paths
+          .iter()
+          .filter(|path| {
+            !hidden_paths
+              .iter()
+              .any(|other| other != *path && path.starts_with(other))
+          })
+          .cloned()
+          .collect();
+
+        let mut exposed = Vec::new();
+        for path in exposed_paths {
+          let path = canonicalize(path)?;
+          if let Some(hidden_path) = hidden_paths
+            .iter()
+            .find(|hidden_path| path.starts_with(hidden_path))
+          {
+            exposed.push(Self::expose(hidden_path, &path)?);
+          }
+        }
+
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        Ok(Namespace {
+          setgroups_path: c_string("/proc/self/setgroups"),
+          uid_map_path: c_string("/proc/self/uid_map"),
+          uid_map: format!("{0} {0} 1\n", uid).into_bytes(),
+          gid_map_path: c_string("/proc/self/gid_map"),
+          gid_map: format!("{0} {0} 1\n", gid).into_bytes(),
+          root: c_string("/"),
+          tmpfs: c_string("tmpfs"),
+          hidden: hidden_paths
+            .iter()
+            .map(|path| c_path(path))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("Invalid hidden path: {:?}", err))?,
+          exposed,
+        })
+      }
+
+      fn expose(hidden_path: &Path, path: &Path) -> Result<Exposed, String> {
+        let source = OpenOptions::new()
+          .read(true)
+          .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+          .open(path)
+          .map_err(|err| format!("Error opening {:?} to expose it: {:?}", path, err))?;
+        let is_dir = source
+          .metadata()
+          .map_err(|err| format!("Error statting {:?}: {:?}", path, err))?
+          .is_dir();
+        let mut ancestors = path
+          .ancestors()
+          .skip(1)
+          .take_while(|ancestor| *ancestor != hidden_path)
+          .map(c_path)
+          .collect::<Result<Vec<_>, _>>()
+          .map_err(|err| format!("Invalid exposed path {:?}: {:?}", path, err))?;
+        ancestors.reverse();
+        Ok(Exposed {
+          source_path: c_string(&format!("/proc/self/fd/{}", source.as_raw_fd())),
+          _source: source,
+          ancestors,
+          target: c_path(path).map_err(|err| format!("Invalid exposed path: {:?}", err))?,
+          is_dir,
+        })
+      }
+
+      pub fn enter(&self) -> io::Result<()> {
+        unsafe {
+          check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS))?;
+          // Unprivileged processes may only map their gid once setgroups has been denied.
+          write_file(&self.setgroups_path, b"deny")?;
+          write_file(&self.gid_map_path, &self.gid_map)?;
+          write_file(&self.uid_map_path, &self.uid_map)?;
+          // Stop our mounts from propagating back out of the namespace.
+          check(libc::mount(
+            ptr::null(),
+            self.root.as_ptr(),
+            ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            ptr::null(),
+          ))?;
+          for hidden in &self.hidden {
+            check(libc::mount(
+              self.tmpfs.as_ptr(),
+              hidden.as_ptr(),
+              self.tmpfs.as_ptr(),
+              libc::MS_NOSUID | libc::MS_NODEV,
+              ptr::null(),
+            ))?;
+          }
+          for exposed in &self.exposed {
+            for ancestor in &exposed.ancestors {
+              make_dir(ancestor)?;
+            }
+            if exposed.is_dir {
+              make_dir(&exposed.target)?;
+            } else {
+              let fd = libc::open(
+                exposed.target.as_ptr(),
+                libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC,
+                0o644,
+              );
+              if fd < 0 {
+                return Err(io::Error::last_os_error());
+              }
+              libc::close(fd);
+            }
+            check(libc::mount(
+              exposed.source_path.as_ptr(),
+              exposed.target.as_ptr(),
+              ptr::null(),
+              libc::MS_BIND | libc::MS_REC,
+              ptr::null(),
+            ))?;
+          }
+        }
+        Ok(())
+      }
+    }
+
+    unsafe fn make_dir(path: &CString) -> io::Result<()> {
+      if libc::mkdir(path.as_ptr(), 0o755) == 0 {
+        return Ok(());
+      }
+      let err = io::Error::last_os_error();
+      if err.raw_os_error() == Some(libc::EEXIST) {
+        Ok(())
+      } else {
+        Err(err)
+      }
+    }
+
+    unsafe fn write_file(path: &CString, contents: &[u8]) -> io::Result<()> {
+      let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+      if fd < 0 {
+        return Err(io::Error::last_os_error());
+      }
+      let written = libc::write(fd, contents.as_ptr() as *const libc::c_void, contents.len());
+      let err = io::Error::last_os_error();
+      libc::close(fd);
+      if written == contents.len() as isize {
+        Ok(())
+      } else {
+        Err(err)
+      }
+    }
+  }
+}
@@ -0,0 +1,148 @@
+// Pseudo-terminals, for running interactive processes.
+
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+use tokio_process::{Child, CommandExt as TokioCommandExt};
+
+pub struct Pty {
+  master: File,
+  slave: File,
+}
+
+impl Pty {
+  pub fn open() -> Result<Pty, String> {
+    let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+    if master < 0 {
+      return Err(format!(
+        "Error opening pseudo-terminal: {}",
+        io::Error::last_os_error()
+      ));
+    }
+    let master = unsafe { File::from_raw_fd(master) };
+    let slave_name = unsafe {
+      if libc::grantpt(master.as_raw_fd()) != 0 || libc::unlockpt(master.as_raw_fd()) != 0 {
+        return Err(format!(
+          "Error unlocking pseudo-terminal: {}",
+          io::Error::last_os_error()
+        ));
+      }
+      slave_name(&master)?
+    };
+    let slave = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .custom_flags(libc::O_NOCTTY)
+      .open(&slave_name)
+      .map_err(|err| format!("Error opening pseudo-terminal {}: {}", slave_name, err))?;
+    Ok(Pty { master, slave })
+  }
+
+  ///
+  /// Spawns the given command as the leader of a new session, with the pseudo-terminal as its
+  /// controlling terminal and stdio.
+  ///
+  pub fn spawn(&self, mut command: Command) -> Result<Child, String> {
+    let clone_slave = || {
+      self
+        .slave
+        .try_clone()
+        .map(Stdio::from)
+        .map_err(|err| format!("Error duplicating pseudo-terminal: {}", err))
+    };
+    command
+      .stdin(clone_slave()?)
+      .stdout(clone_slave()?)
+      .stderr(clone_slave()?);
+    unsafe {
+      command.before_exec(|| {
+        if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0 {
+          Err(io::Error::last_os_error())
+        } else {
+          Ok(())
+        }
+      });
+    }
+    // The command (and so its copies of the slave) is dropped once the child is spawned.
+    command
+      .spawn_async()
+      .map_err(|err| format!("Error launching interactive process: {}", err))
+  }
+
+  ///
+  /// Proxies the pseudo-terminal to the given input and output, until the pseudo-terminal is
+  /// closed (i.e. until all processes using it have exited).
+  ///
+  /// Returns the thread which is copying output, which should be joined to ensure that all output
+  /// has been copied. The thread copying input is detached, since it may be blocked on input which
+  /// will never arrive.
+  ///
+  pub fn proxy<I, O>(self, mut input: I, mut output: O) -> Result<JoinHandle<()>, String>
+  where
+    I: Read + Send + 'static,
+    O: Write + Send + 'static,
+  {
+    let Pty { master, slave } = self;
+    // Our copy of the slave must be closed, so that reads from the master fail once all of the
+    // processes using it have exited.
+    drop(slave);
+    let mut master_input = master
+      .try_clone()
+      .map_err(|err| format!("Error duplicating pseudo-terminal: {}", err))?;
+    let mut master_output = master;
+
+    thread::spawn(move || {
+      let _ = io::copy(&mut input, &mut master_input);
+    });
+    Ok(thread::spawn(move || {
+      let mut buf = [0; 4096];
+      loop {
+        // Reads fail (rather than returning EOF) once the pseudo-terminal is closed.
+        match master_output.read(&mut buf) {
+          Ok(0) | Err(_) => break,
+          Ok(n) => {
+            if output
+              .write_all(&buf[..n])
+              .and_then(|()| output.flush())
+              .is_err()
+            {
+              break;
+            }
+          }
+        }
+      }
+    }))
+  }
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn slave_name(master: &File) -> Result<String, String> {
+  let mut buf = [0 as libc::c_char; 128];
+  if libc::ptsname_r(master.as_raw_fd(), buf.as_mut_ptr(), buf.len()) != 0 {
+    return Err(format!(
+      "Error getting pseudo-terminal name: {}",
+      io::Error::last_os_error()
+    ));
+  }
+  Ok(CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned())
+}
+
+// ptsname_r isn't available everywhere: ptsname uses a static buffer, so isn't thread-safe, but
+// we copy out of it immediately.
+#[cfg(not(target_os = "linux"))]
+unsafe fn slave_name(master: &File) -> Result<String, String> {
+  let name = libc::ptsname(master.as_raw_fd());
+  if name.is_null() {
+    return Err(format!(
+      "Error getting pseudo-terminal name: {}",
+      io::Error::last_os_error()
+    ));
+  }
+  Ok(CStr::from_ptr(name).to_string_lossy().into_owned())
+}
@@ -1,7 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::mem::drop;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use bazel_protos;
@@ -9,7 +9,8 @@ use boxfuture::{try_future, BoxFuture, Boxable};
 use bytes::Bytes;
 use digest::{Digest as DigestTrait, FixedOutput};
 use fs::{self, File, PathStat, Store};
-use futures::{future, Future, Stream};
+use futures::future::{self, Either};
+use futures::{Future, Stream};
 use futures_timer::Delay;
 use grpcio;
 use hashing::{Digest, Fingerprint};
@@ -18,7 +19,10 @@ use protobuf::{self, Message, ProtobufEnum};
 use sha2::Sha256;
 use time;
 
-use super::{ExecuteProcessRequest, ExecutionStats, FallibleExecuteProcessResult};
+use super::instance_routing::{self, InstanceRoute};
+use super::verification;
+use super::{ExecuteProcessRequest, ExecutionStats, FallibleExecuteProcessResult, Platform};
+use async_semaphore::{AsyncSemaphore, Permit};
 use std;
 use std::cmp::min;
 use std::collections::btree_map::BTreeMap;
@@ -34,18 +38,40 @@ enum OperationOrStatus {
   Status(bazel_protos::status::Status),
 }
 
+///
+/// How far waiting on a stream of updates to an Operation (from Execute or WaitExecution) got.
+///
+#[derive(Debug)]
+enum StreamOutcome {
+  // The Operation finished, or the request failed with the given Status.
+  Complete(OperationOrStatus),
+  // The stream broke or ended before the Operation finished. If the server named the Operation,
+  // waiting on it can be resumed with WaitExecution.
+  Broken(Option<String>),
+  // The deadline passed before the Operation finished.
+  TimedOut(Option<String>),
+}
+
 #[derive(Clone)]
 pub struct CommandRunner {
   cache_key_gen_version: Option<String>,
   instance_name: Option<String>,
-  authorization_header: Option<String>,
+  instance_routes: Vec<InstanceRoute>,
+  oauth_bearer_token: Option<fs::BearerToken>,
+  request_metadata: Option<fs::RequestMetadata>,
   platform_properties: BTreeMap<String, String>,
+  execution_headers: BTreeMap<String, String>,
   channel: grpcio::Channel,
   env: Arc<grpcio::Environment>,
   execution_client: Arc<bazel_protos::remote_execution_grpc::ExecutionClient>,
-  operations_client: Arc<bazel_protos::operations_grpc::OperationsClient>,
+  capabilities_client: Arc<bazel_protos::remote_execution_grpc::CapabilitiesClient>,
+  output_declaration: Arc<Mutex<Option<OutputDeclaration>>>,
+  retry_policy: fs::RetryPolicy,
+  rpc_timeout: Duration,
   store: Store,
   futures_timer_thread: resettable::Resettable<futures_timer::HelperThread>,
+  verify_results: bool,
+  execution_semaphore: Option<AsyncSemaphore>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -54,10 +80,24 @@ enum ExecutionError {
   Fatal(String),
   // Digests are Files and Directories which have been reported to be missing. May be incomplete.
   MissingDigests(Vec<Digest>),
-  // String is the operation name which can be used to poll the GetOperation gRPC API.
+  // String is the operation name which can be used to resume waiting on the operation with the
+  // WaitExecution gRPC API.
   NotFinished(String),
 }
 
+///
+/// The action on whose behalf Execute and WaitExecution calls are made, which identifies them in
+/// their RequestMetadata: by the hash of the Action's digest, and by the name of the executable
+/// which it runs (as a short description of the kind of action, e.g. "javac"). The calls are made
+/// with its (validated) execution headers.
+///
+#[derive(Debug)]
+struct ActionDetails {
+  id: String,
+  mnemonic: String,
+  headers: BTreeMap<String, String>,
+}
+
 #[derive(Default)]
 struct ExecutionHistory {
   attempts: Vec<ExecutionStats>,
@@ -65,44 +105,129 @@ struct ExecutionHistory {
 }
 
 impl CommandRunner {
-  // The Execute API used to be unary, and became streaming. The contract of the streaming API is
-  // that if the client closes the stream after one request, it should continue to function exactly
-  // like the unary API.
-  // For maximal compatibility with servers, we fall back to this unary-like behavior, and control
-  // our own polling rates.
-  // In the future, we may want to remove this behavior if servers reliably support the full stream
-  // behavior.
-  fn oneshot_execute(
+  ///
+  /// Calls Execute, and waits on the stream of updates to the resulting Operation. A call which
+  /// fails with a transient error is treated as a broken stream, so that it is retried.
+  ///
+  fn execute(
     &self,
     execute_request: &Arc<bazel_protos::remote_execution::ExecuteRequest>,
-  ) -> BoxFuture<OperationOrStatus, String> {
-    let stream = try_future!(self
+    action: &ActionDetails,
+    deadline: Instant,
+  ) -> BoxFuture<StreamOutcome, String> {
+    let stream = match self
       .execution_client
-      .execute_opt(&execute_request, self.call_option())
-      .map_err(rpcerror_to_string));
-    stream
-      .take(1)
-      .into_future()
-      // If there was a response, drop the _stream to disconnect so that the server doesn't keep
-      // the connection alive and continue sending on it.
-      .map(|(maybe_operation, stream)| {
-        drop(stream);
-        maybe_operation
-      })
-      // If there was an error, drop the _stream to disconnect so that the server doesn't keep the
-      // connection alive and continue sending on it.
-      .map_err(|(error, stream)| {
-        drop(stream);
-        error
-      })
-      .then(|maybe_operation_result| match maybe_operation_result {
-        Ok(Some(operation)) => Ok(OperationOrStatus::Operation(operation)),
-        Ok(None) => {
-          Err("Didn't get proper stream response from server during remote execution".to_owned())
-        }
-        Err(err) => rpcerror_to_status_or_string(err).map(OperationOrStatus::Status),
-      })
-      .to_boxed()
+      .execute_opt(&execute_request, self.call_option_until(action, deadline))
+    {
+      Ok(stream) => stream,
+      Err(ref err) if fs::is_retryable_rpc_error(err) => {
+        return future::ok(StreamOutcome::Broken(None)).to_boxed();
+      }
+      Err(err) => return future::err(rpcerror_to_string(err)).to_boxed(),
+    };
+    self.wait_on_operation_stream(stream, None, deadline)
+  }
+
+  ///
+  /// Calls WaitExecution to resume waiting on an Operation whose stream broke.
+  ///
+  fn wait_execution(
+    &self,
+    operation_name: String,
+    action: &ActionDetails,
+    deadline: Instant,
+  ) -> BoxFuture<StreamOutcome, String> {
+    let mut wait_execution_request = bazel_protos::remote_execution::WaitExecutionRequest::new();
+    wait_execution_request.set_name(operation_name.clone());
+    let stream = match self.execution_client.wait_execution_opt(
+      &wait_execution_request,
+      self.call_option_until(action, deadline),
+    ) {
+      Ok(stream) => stream,
+      Err(ref err) if fs::is_retryable_rpc_error(err) => {
+        return future::ok(StreamOutcome::Broken(Some(operation_name))).to_boxed();
+      }
+      Err(err) => return future::err(rpcerror_to_string(err)).to_boxed(),
+    };
+    self.wait_on_operation_stream(stream, Some(operation_name), deadline)
+  }
+
+  ///
+  /// Consumes a stream of updates to an Operation until it finishes, the stream breaks or ends, or
+  /// the deadline passes. The stream is dropped (disconnecting from the server) once it finishes.
+  ///
+  /// A stream which fails with a transient error (or is cancelled) is treated as broken, unless it
+  /// exceeded its deadline because the deadline for the execution passed. So is a stream which is
+  /// rejected as unauthenticated if refreshing the bearer token changes it, so that waiting is
+  /// resumed with the new token.
+  ///
+  fn wait_on_operation_stream(
+    &self,
+    stream: grpcio::ClientSStreamReceiver<bazel_protos::operations::Operation>,
+    operation_name: Option<String>,
+    deadline: Instant,
+  ) -> BoxFuture<StreamOutcome, String> {
+    let futures_timer_thread = self.futures_timer_thread.clone();
+    let oauth_bearer_token = self.oauth_bearer_token.clone();
+    future::loop_fn(
+      (stream, operation_name, None),
+      move |(stream, operation_name, stage)| {
+        let timeout = Delay::new_handle(
+          deadline,
+          futures_timer_thread.with(futures_timer::HelperThread::handle),
+        );
+        stream
+          .into_future()
+          .select2(timeout)
+          .then(move |next| match next {
+            Ok(Either::A(((Some(operation), stream), _))) => {
+              if operation.get_done() {
+                drop(stream);
+                return Ok(future::Loop::Break(StreamOutcome::Complete(
+                  OperationOrStatus::Operation(operation),
+                )));
+              }
+              let stage = log_stage_change(&operation, stage);
+              Ok(future::Loop::Continue((
+                stream,
+                Some(operation.get_name().to_owned()),
+                stage,
+              )))
+            }
+            Ok(Either::A(((None, _), _))) => {
+              Ok(future::Loop::Break(StreamOutcome::Broken(operation_name)))
+            }
+            Err(Either::A(((err, _), _))) => {
+              let refreshed_token = oauth_bearer_token
+                .as_ref()
+                .map(|token| token.refresh_if_unauthenticated(&err))
+                .unwrap_or(false);
+              let (cancelled, deadline_exceeded) = match err {
+                grpcio::Error::RpcFailure(ref status) => (
+                  status.status == grpcio::RpcStatusCode::Cancelled,
+                  status.status == grpcio::RpcStatusCode::DeadlineExceeded,
+                ),
+                _ => (false, false),
+              };
+              if deadline_exceeded && Instant::now() >= deadline {
+                Ok(future::Loop::Break(StreamOutcome::TimedOut(operation_name)))
+              } else if refreshed_token || cancelled || fs::is_retryable_rpc_error(&err) {
+                Ok(future::Loop::Break(StreamOutcome::Broken(operation_name)))
+              } else {
+                rpcerror_to_status_or_string(err).map(|status| {
+                  future::Loop::Break(StreamOutcome::Complete(OperationOrStatus::Status(status)))
+                })
+              }
+            }
+            Ok(Either::B(_)) => Ok(future::Loop::Break(StreamOutcome::TimedOut(operation_name))),
+            Err(Either::B((err, _))) => Err(format!(
+              "Future-Delay errored while waiting for remote execution: {}",
+              err
+            )),
+          })
+      },
+    )
+    .to_boxed()
   }
 }
 
@@ -121,163 +246,268 @@ impl super::CommandRunner for CommandRunner {
   /// user has changed, or files which aren't known to the local git repository, but these are
   /// optimizations to shave off a round-trip in the future.
   ///
-  /// Loops until the server gives a response, either successful or error. Does not have any
-  /// timeout: polls in a tight loop.
+  /// Before anything is uploaded, the remote ActionCache is checked for a result for the Action:
+  /// on a hit, its outputs are downloaded rather than executing it.
   ///
-  /// TODO: Request jdk_home be created if set.
+  /// Waits on the stream of updates to the Operation until the server gives a response, either
+  /// successful or error. If the stream breaks before then, waiting is resumed with WaitExecution
+  /// (after an exponential backoff, with jitter), or if the server never named the Operation, it is
+  /// executed again. Fails if the Operation has not finished within the timeout of the request, or
+  /// if the stream breaks more times in a row than the retry policy allows.
   ///
   fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
-    let operations_client = self.operations_client.clone();
+    let mut platform_properties = self.platform_properties.clone();
+    platform_properties.extend(req.platform_properties.clone());
+    if let Some(instance_name) =
+      instance_routing::route(&self.instance_routes, &platform_properties)
+    {
+      if self.instance_name.as_ref().map(String::as_str) != Some(instance_name) {
+        debug!("Routing {} to instance {}", req.description, instance_name);
+        return self
+          .with_instance_name(Some(instance_name.to_owned()))
+          .run(req);
+      }
+    }
+
+    let output_declaration = *self.output_declaration.lock().unwrap();
+    let output_declaration = match output_declaration {
+      Some(output_declaration) => output_declaration,
+      None => {
+        let command_runner = self.clone();
+        return self
+          .fetch_output_declaration()
+          .and_then(move |_| command_runner.run(req))
+          .to_boxed();
+      }
+    };
 
     let store = self.store.clone();
-    let execute_request_result = make_execute_request(
+    let execute_request_result = make_execute_request_with_output_declaration(
       &req,
       &self.instance_name,
       &self.cache_key_gen_version,
       self.platform_properties.clone(),
+      output_declaration,
     );
+    let action_mnemonic = action_mnemonic(&req.argv);
+    let mut execution_headers = self.execution_headers.clone();
+    execution_headers.extend(req.execution_headers.clone());
+    let verify_results = self.verify_results;
 
     let ExecuteProcessRequest {
       description,
       timeout,
       input_files,
+      output_files,
+      output_directories,
+      priority,
       ..
     } = req;
+    let (output_files, output_directories) = match output_declaration {
+      OutputDeclaration::FilesAndDirectories => (output_files, output_directories),
+      // Servers decide whether each of the output paths is a file or a directory.
+      OutputDeclaration::Paths => {
+        let output_paths: BTreeSet<PathBuf> =
+          output_files.union(&output_directories).cloned().collect();
+        (output_paths.clone(), output_paths)
+      }
+    };
 
     let description2 = description.clone();
+    let description3 = description.clone();
+    let description4 = description.clone();
 
     match execute_request_result {
       Ok((action, command, execute_request)) => {
+        let store0 = store.clone();
         let command_runner = self.clone();
         let command_runner2 = self.clone();
         let command_runner3 = self.clone();
+        let action_details = Arc::new(ActionDetails {
+          id: execute_request.get_action_digest().get_hash().to_owned(),
+          mnemonic: action_mnemonic,
+          headers: execution_headers,
+        });
+        let action_details2 = action_details.clone();
         let execute_request = Arc::new(execute_request);
         let execute_request2 = execute_request.clone();
         let futures_timer_thread = self.futures_timer_thread.clone();
 
         let store2 = store.clone();
+        let store3 = store.clone();
         let mut history = ExecutionHistory::default();
 
         self
           .store_proto_locally(&command)
           .join(self.store_proto_locally(&action))
           .and_then(move |(command_digest, action_digest)| {
-            store2.ensure_remote_has_recursive(vec![command_digest, action_digest, input_files])
-          })
-          .and_then(move |summary| {
-            history.current_attempt += summary;
-            trace!(
-              "Executing remotely request: {:?} (command: {:?})",
-              execute_request,
-              command
-            );
-            command_runner
-              .oneshot_execute(&execute_request)
-              .join(future::ok(history))
+            lookup_action_result(&store0, action_digest)
+              .map(move |cached| (command_digest, action_digest, cached))
           })
-          .and_then(move |(operation, history)| {
-            let start_time = Instant::now();
-
-            future::loop_fn(
-              (history, operation, 0),
-              move |(mut history, operation, iter_num)| {
-                let description = description.clone();
-
-                let execute_request2 = execute_request2.clone();
-                let store = store.clone();
-                let operations_client = operations_client.clone();
-                let command_runner2 = command_runner2.clone();
-                let command_runner3 = command_runner3.clone();
-                let futures_timer_thread = futures_timer_thread.clone();
-                let f = command_runner2.extract_execute_response(operation, &mut history);
-                f.map(future::Loop::Break).or_else(move |value| {
-                  match value {
-                    ExecutionError::Fatal(err) => future::err(err).to_boxed(),
-                    ExecutionError::MissingDigests(missing_digests) => {
-                      let ExecutionHistory {
-                        mut attempts,
-                        current_attempt,
-                      } = history;
-
-                      trace!(
-                        "Server reported missing digests ({:?}); trying to upload: {:?}",
-                        current_attempt,
-                        missing_digests,
-                      );
-
-                      attempts.push(current_attempt);
-                      let history = ExecutionHistory {
-                        attempts,
-                        current_attempt: ExecutionStats::default(),
-                      };
-
-                      let execute_request = execute_request2.clone();
-                      store
-                        .ensure_remote_has_recursive(missing_digests)
-                        .and_then(move |summary| {
-                          let mut history = history;
-                          history.current_attempt += summary;
-                          command_runner2
-                            .oneshot_execute(&execute_request)
-                            .join(future::ok(history))
-                        })
-                        // Reset `iter_num` on `MissingDigests`
-                        .map(|(operation, history)| future::Loop::Continue((history, operation, 0)))
-                        .to_boxed()
-                    }
-                    ExecutionError::NotFinished(operation_name) => {
-                      let mut operation_request =
-                        bazel_protos::operations::GetOperationRequest::new();
-                      operation_request.set_name(operation_name.clone());
-
-                      let backoff_period = min(
-                        CommandRunner::BACKOFF_MAX_WAIT_MILLIS,
-                        (1 + iter_num) * CommandRunner::BACKOFF_INCR_WAIT_MILLIS,
-                      );
-
-                      // take the grpc result and cancel the op if too much time has passed.
-                      let elapsed = start_time.elapsed();
-
-                      if elapsed > timeout {
-                        future::err(format!(
+          .and_then(move |(command_digest, action_digest, cached)| {
+            if let Some(result) = cached {
+              debug!("Got a remote action cache hit for {}", description3);
+              return future::ok(result).to_boxed();
+            }
+            store2
+              .ensure_remote_has_recursive(vec![command_digest, action_digest, input_files])
+              .and_then(move |summary| {
+                command_runner3
+                  .acquire_execution_permit(priority)
+                  .map(move |permit| (summary, permit))
+              })
+              .and_then(move |(summary, permit)| {
+                history.current_attempt += summary;
+                trace!(
+                  "Executing remotely request: {:?} (command: {:?})",
+                  execute_request,
+                  command
+                );
+                let start_time = Instant::now();
+                let deadline = start_time + timeout;
+                command_runner
+                  .execute(&execute_request, &action_details, deadline)
+                  .join(future::ok(history))
+                  .map(move |(outcome, history)| (outcome, history, start_time, deadline, permit))
+              })
+              .and_then(move |(outcome, history, start_time, deadline, permit)| {
+                future::loop_fn(
+                  (history, outcome, 0),
+                  move |(mut history, outcome, reconnects)| {
+                    let description = description.clone();
+
+                    let execute_request = execute_request2.clone();
+                    let action_details = action_details2.clone();
+                    let store = store.clone();
+                    let command_runner = command_runner2.clone();
+                    let futures_timer_thread = futures_timer_thread.clone();
+
+                    let operation_name = match outcome {
+                      StreamOutcome::Complete(operation) => {
+                        let command_runner2 = command_runner.clone();
+                        let action_details = action_details.clone();
+                        return command_runner
+                          .extract_execute_response(operation, &mut history)
+                          .map(future::Loop::Break)
+                          .or_else(move |value| match value {
+                            ExecutionError::Fatal(err) => future::err(err).to_boxed(),
+                            ExecutionError::MissingDigests(missing_digests) => {
+                              let ExecutionHistory {
+                                mut attempts,
+                                current_attempt,
+                              } = history;
+
+                              if attempts.len() >= CommandRunner::MAX_MISSING_DIGEST_UPLOADS {
+                                return future::err(format!(
+                                  "Server still reported missing digests {:?} after uploading \
+                                   missing digests {} times, {}",
+                                  missing_digests,
+                                  attempts.len(),
+                                  description
+                                ))
+                                .to_boxed();
+                              }
+
+                              trace!(
+                                "Server reported missing digests ({:?}); trying to upload: {:?}",
+                                current_attempt,
+                                missing_digests,
+                              );
+
+                              attempts.push(current_attempt);
+                              let history = ExecutionHistory {
+                                attempts,
+                                current_attempt: ExecutionStats::default(),
+                              };
+
+                              store
+                                .ensure_remote_has(missing_digests)
+                                .map_err(|err| {
+                                  format!(
+                                    "Failed to upload digests which the server reported \
+                                     missing: {}",
+                                    err
+                                  )
+                                })
+                                .and_then(move |summary| {
+                                  let mut history = history;
+                                  history.current_attempt += summary;
+                                  command_runner2
+                                    .execute(&execute_request, &action_details, deadline)
+                                    .join(future::ok(history))
+                                })
+                                // Reset `reconnects` on `MissingDigests`
+                                .map(|(outcome, history)| {
+                                  future::Loop::Continue((history, outcome, 0))
+                                })
+                                .to_boxed()
+                            }
+                            ExecutionError::NotFinished(operation_name) => {
+                              future::ok(future::Loop::Continue((
+                                history,
+                                StreamOutcome::Broken(Some(operation_name)),
+                                reconnects,
+                              )))
+                              .to_boxed()
+                            }
+                          })
+                          .to_boxed();
+                      }
+                      StreamOutcome::Broken(operation_name) => operation_name,
+                      StreamOutcome::TimedOut(operation_name) => {
+                        return future::err(format!(
                           "Exceeded time out of {:?} with {:?} for operation {}, {}",
-                          timeout, elapsed, operation_name, description
+                          timeout,
+                          start_time.elapsed(),
+                          operation_name.unwrap_or_else(|| "(unnamed)".to_owned()),
+                          description
                         ))
-                        .to_boxed()
-                      } else {
-                        // maybe the delay here should be the min of remaining time and the backoff period
-                        Delay::new_handle(
-                          Instant::now() + Duration::from_millis(backoff_period),
-                          futures_timer_thread.with(futures_timer::HelperThread::handle),
-                        )
-                        .map_err(move |e| {
-                          format!(
-                            "Future-Delay errored at operation result polling for {}, {}: {}",
-                            operation_name, description, e
-                          )
-                        })
-                        .and_then(move |_| {
-                          future::done(
-                            operations_client
-                              .get_operation_opt(&operation_request, command_runner3.call_option())
-                              .or_else(move |err| {
-                                rpcerror_recover_cancelled(operation_request.take_name(), err)
-                              })
-                              .map(OperationOrStatus::Operation)
-                              .map_err(rpcerror_to_string),
-                          )
-                          .map(move |operation| {
-                            future::Loop::Continue((history, operation, iter_num + 1))
-                          })
-                          .to_boxed()
-                        })
-                        .to_boxed()
+                        .to_boxed();
                       }
+                    };
+
+                    let retry_policy = command_runner.retry_policy;
+                    if reconnects + 1 >= retry_policy.max_attempts() {
+                      return future::err(format!(
+                        "Failed after {} retries; stream of updates to operation {} broke, {}",
+                        reconnects,
+                        operation_name.unwrap_or_else(|| "(unnamed)".to_owned()),
+                        description
+                      ))
+                      .to_boxed();
                     }
-                  }
+                    let backoff_period = retry_policy.backoff(reconnects + 1);
+                    debug!(
+                      "Stream of updates to operation {:?} broke: reconnecting in {:?}, {}",
+                      operation_name, backoff_period, description
+                    );
+                    history.current_attempt.retries += 1;
+                    Delay::new_handle(
+                      Instant::now() + backoff_period,
+                      futures_timer_thread.with(futures_timer::HelperThread::handle),
+                    )
+                    .map_err(move |e| {
+                      format!(
+                        "Future-Delay errored while reconnecting to remote execution for {}: {}",
+                        description, e
+                      )
+                    })
+                    .and_then(move |_| match operation_name {
+                      Some(operation_name) => {
+                        command_runner.wait_execution(operation_name, &action_details, deadline)
+                      }
+                      None => command_runner.execute(&execute_request, &action_details, deadline),
+                    })
+                    .map(move |outcome| future::Loop::Continue((history, outcome, reconnects + 1)))
+                    .to_boxed()
+                  },
+                )
+                .then(move |result| {
+                  drop(permit);
+                  result
                 })
-              },
-            )
+              })
+              .to_boxed()
           })
           .map(move |resp| {
             let mut attempts = String::new();
@@ -292,73 +522,309 @@ impl super::CommandRunner for CommandRunner {
             );
             resp
           })
+          .and_then(move |resp| {
+            if !verify_results {
+              return future::ok(resp).to_boxed();
+            }
+            verification::verify_output_directory(
+              &store3,
+              &output_files,
+              &output_directories,
+              resp.output_directory,
+            )
+            .map(move |()| resp)
+            .map_err(move |err| {
+              format!(
+                "Rejected the result of remote execution of {}: {}",
+                description4, err
+              )
+            })
+            .to_boxed()
+          })
           .to_boxed()
       }
       Err(err) => future::err(err).to_boxed(),
     }
   }
+
+  ///
+  /// The platform of the remote workers, according to the (conventional) "OSFamily" platform
+  /// property.
+  ///
+  fn platform(&self) -> Result<Platform, String> {
+    platform_of_workers(&self.platform_properties)
+  }
+}
+
+///
+/// Workers are assumed to run Linux unless their platform properties say otherwise, since that is
+/// by far the most common case.
+///
+pub(crate) fn platform_of_workers(
+  platform_properties: &BTreeMap<String, String>,
+) -> Result<Platform, String> {
+  match platform_properties.get("OSFamily").map(String::as_str) {
+    None => Ok(Platform::Linux),
+    Some(os_family) if os_family.eq_ignore_ascii_case("linux") => Ok(Platform::Linux),
+    Some(os_family)
+      if os_family.eq_ignore_ascii_case("darwin") || os_family.eq_ignore_ascii_case("macos") =>
+    {
+      Ok(Platform::Darwin)
+    }
+    Some(os_family) => Err(format!(
+      "Unsupported OSFamily platform property for remote execution: {}",
+      os_family
+    )),
+  }
+}
+
+///
+/// The file name of the executable that the process runs, which (for lack of anything better)
+/// serves as the mnemonic of its action in RequestMetadata.
+///
+fn action_mnemonic(argv: &[String]) -> String {
+  argv
+    .first()
+    .and_then(|executable| Path::new(executable).file_name())
+    .map(|file_name| file_name.to_string_lossy().into_owned())
+    .unwrap_or_default()
 }
 
 impl CommandRunner {
-  const BACKOFF_INCR_WAIT_MILLIS: u64 = 500;
+  const BACKOFF_INITIAL_WAIT_MILLIS: u64 = 500;
   const BACKOFF_MAX_WAIT_MILLIS: u64 = 5000;
+  // Servers report every blob which is missing at once, so needing to upload missing blobs more
+  // than a few times for one execution means that uploads aren't sticking.
+  const MAX_MISSING_DIGEST_UPLOADS: usize = 5;
 
   pub fn new(
     address: &str,
     cache_key_gen_version: Option<String>,
     instance_name: Option<String>,
     root_ca_certs: Option<Vec<u8>>,
-    oauth_bearer_token: Option<String>,
+    client_certificate: Option<fs::ClientCertificate>,
+    proxy: Option<fs::Proxy>,
+    channel_policy: fs::ChannelPolicy,
+    oauth_bearer_token: Option<fs::BearerToken>,
+    request_metadata: Option<fs::RequestMetadata>,
     platform_properties: BTreeMap<String, String>,
     thread_count: usize,
+    rpc_retries: usize,
+    rpc_timeout: Duration,
     store: Store,
     futures_timer_thread: resettable::Resettable<futures_timer::HelperThread>,
   ) -> CommandRunner {
     let env = Arc::new(grpcio::Environment::new(thread_count));
     let channel = {
-      let builder = grpcio::ChannelBuilder::new(env.clone());
-      if let Some(root_ca_certs) = root_ca_certs {
-        let creds = grpcio::ChannelCredentialsBuilder::new()
-          .root_cert(root_ca_certs)
-          .build();
-        builder.secure_connect(address, creds)
-      } else {
-        builder.connect(address)
+      let builder = channel_policy.configure(grpcio::ChannelBuilder::new(env.clone()));
+      let connect = |builder: grpcio::ChannelBuilder| match fs::channel_credentials(
+        &root_ca_certs,
+        &client_certificate,
+      ) {
+        Some(creds) => builder.secure_connect(address, creds),
+        None => builder.connect(address),
+      };
+      match proxy {
+        Some(ref proxy) => proxy.connect(builder, connect),
+        None => connect(builder),
       }
     };
     let execution_client = Arc::new(bazel_protos::remote_execution_grpc::ExecutionClient::new(
       channel.clone(),
     ));
-    let operations_client = Arc::new(bazel_protos::operations_grpc::OperationsClient::new(
-      channel.clone(),
-    ));
+    let capabilities_client =
+      Arc::new(bazel_protos::remote_execution_grpc::CapabilitiesClient::new(channel.clone()));
 
     CommandRunner {
       cache_key_gen_version,
       instance_name,
-      authorization_header: oauth_bearer_token.map(|t| format!("Bearer {}", t)),
+      instance_routes: vec![],
+      oauth_bearer_token,
+      request_metadata,
       platform_properties,
+      execution_headers: BTreeMap::new(),
       channel,
       env,
       execution_client,
-      operations_client,
+      capabilities_client,
+      output_declaration: Arc::new(Mutex::new(None)),
+      retry_policy: fs::RetryPolicy::new(
+        Duration::from_millis(CommandRunner::BACKOFF_INITIAL_WAIT_MILLIS),
+        Duration::from_millis(CommandRunner::BACKOFF_MAX_WAIT_MILLIS),
+        rpc_retries + 1,
+      )
+      .expect("The backoff constants are valid"),
+      rpc_timeout,
       store,
       futures_timer_thread,
+      verify_results: false,
+      execution_semaphore: None,
+    }
+  }
+
+  ///
+  /// Routes each process which matches one of the given routes to its instance (both for execution
+  /// and for the CAS and ActionCache), rather than to the default instance. The first matching
+  /// route wins.
+  ///
+  pub fn with_instance_routes(mut self, instance_routes: Vec<InstanceRoute>) -> CommandRunner {
+    self.instance_routes = instance_routes;
+    self
+  }
+
+  ///
+  /// This CommandRunner, but executing (and caching) against the given instance.
+  ///
+  fn with_instance_name(&self, instance_name: Option<String>) -> CommandRunner {
+    let mut command_runner = self.clone();
+    command_runner.store = self.store.with_instance_name(instance_name.clone());
+    command_runner.instance_name = instance_name;
+    command_runner
+  }
+
+  ///
+  /// Sends the given headers with every Execute and WaitExecution call (e.g. for services which
+  /// route actions to worker pools on headers). The headers of each process take precedence.
+  ///
+  pub fn with_execution_headers(
+    mut self,
+    execution_headers: BTreeMap<String, String>,
+  ) -> Result<CommandRunner, String> {
+    for (name, value) in &execution_headers {
+      super::validate_execution_header(name, value)
+        .map_err(|err| format!("Invalid execution header: {}", err))?;
+    }
+    self.execution_headers = execution_headers;
+    Ok(self)
+  }
+
+  ///
+  /// Verifies the outputs of every result (whether executed or from the ActionCache) before
+  /// returning it, as for `verification::verify_output_directory`. Results which fail verification
+  /// are errors.
+  ///
+  pub fn with_result_verification(mut self, verify_results: bool) -> CommandRunner {
+    self.verify_results = verify_results;
+    self
+  }
+
+  ///
+  /// Limits the number of executions which may be in flight at once (from the first Execute call
+  /// for a process until its Operation finishes), independently of any bound on the processes
+  /// given to this CommandRunner: so that those can check the ActionCache and upload their inputs
+  /// meanwhile. Processes wait for executions to finish in the order of their priorities.
+  ///
+  pub fn with_execution_concurrency_limit(mut self, limit: usize) -> CommandRunner {
+    self.execution_semaphore = Some(AsyncSemaphore::new(limit));
+    self
+  }
+
+  ///
+  /// Waits for the execution of a process with the given (remote) priority to be allowed to start,
+  /// returning the Permit which allows it, if executions are limited.
+  ///
+  fn acquire_execution_permit(&self, priority: i32) -> BoxFuture<Option<Permit>, String> {
+    match self.execution_semaphore {
+      // Lower remote priorities run sooner, whereas higher semaphore priorities are served sooner.
+      Some(ref semaphore) => semaphore
+        .acquire_with_priority(0i32.saturating_sub(priority))
+        .map(Some)
+        .map_err(String::from)
+        .to_boxed(),
+      None => future::ok(None).to_boxed(),
     }
   }
 
-  fn call_option(&self) -> grpcio::CallOption {
+  ///
+  /// Asks the server which version of the API it supports (once: the answer is shared between
+  /// clones), in order to declare outputs in the form which it expects. Servers which don't
+  /// implement the Capabilities service are assumed to support v2.0.
+  ///
+  fn fetch_output_declaration(&self) -> BoxFuture<OutputDeclaration, String> {
+    let mut request = bazel_protos::remote_execution::GetCapabilitiesRequest::new();
+    if let Some(ref instance_name) = self.instance_name {
+      request.set_instance_name(instance_name.clone());
+    }
+    let cached_output_declaration = self.output_declaration.clone();
+    future::done(
+      self
+        .capabilities_client
+        .get_capabilities_async_opt(&request, self.call_option(None).timeout(self.rpc_timeout)),
+    )
+    .flatten()
+    .then(|result| match result {
+      Ok(capabilities) => Ok(OutputDeclaration::supported_by(&capabilities)),
+      Err(grpcio::Error::RpcFailure(ref status))
+        if status.status == grpcio::RpcStatusCode::Unimplemented =>
+      {
+        Ok(OutputDeclaration::FilesAndDirectories)
+      }
+      Err(err) => Err(format!(
+        "Error from server in response to GetCapabilities: {:?}",
+        err
+      )),
+    })
+    .map(move |output_declaration| {
+      debug!(
+        "Declaring the outputs of remote executions as {:?}",
+        output_declaration
+      );
+      *cached_output_declaration.lock().unwrap() = Some(output_declaration);
+      output_declaration
+    })
+    .to_boxed()
+  }
+
+  ///
+  /// A CallOption with the headers for calls made on behalf of the given action (if any).
+  ///
+  fn call_option(&self, action: Option<&ActionDetails>) -> grpcio::CallOption {
     let mut call_option = grpcio::CallOption::default();
-    if let Some(ref authorization_header) = self.authorization_header {
-      let mut builder = grpcio::MetadataBuilder::with_capacity(1);
-      builder
-        .add_str("authorization", &authorization_header)
-        .unwrap();
+    let headers = action.map(|action| &action.headers);
+    if self.oauth_bearer_token.is_some()
+      || self.request_metadata.is_some()
+      || headers.map(|headers| !headers.is_empty()).unwrap_or(false)
+    {
+      let mut builder =
+        grpcio::MetadataBuilder::with_capacity(2 + headers.map(BTreeMap::len).unwrap_or(0));
+      for (name, value) in headers.into_iter().flatten() {
+        builder
+          .add_str(name, value)
+          .expect("Execution headers are validated");
+      }
+      if let Some(ref oauth_bearer_token) = self.oauth_bearer_token {
+        builder
+          .add_str("authorization", &oauth_bearer_token.authorization_header())
+          .unwrap();
+      }
+      if let Some(ref request_metadata) = self.request_metadata {
+        request_metadata.add_header(
+          &mut builder,
+          action.map(|action| (action.id.as_str(), action.mnemonic.as_str())),
+        );
+      }
       call_option = call_option.headers(builder.build());
     }
     call_option
   }
 
+  ///
+  /// A CallOption whose deadline is the sooner of the given deadline (of the whole execution) and
+  /// the deadline of each Execute or WaitExecution call, so that servers can schedule (or give up
+  /// on) executions according to how long we are prepared to wait for them.
+  ///
+  fn call_option_until(&self, action: &ActionDetails, deadline: Instant) -> grpcio::CallOption {
+    let now = Instant::now();
+    let remaining = if deadline > now {
+      deadline - now
+    } else {
+      Duration::from_secs(0)
+    };
+    self
+      .call_option(Some(action))
+      .timeout(min(self.rpc_timeout, remaining))
+  }
+
   fn store_proto_locally<P: protobuf::Message>(
     &self,
     proto: &P,
@@ -418,6 +884,10 @@ impl CommandRunner {
             Ok(duration) => attempts.current_attempt.remote_queue = Some(duration),
             Err(err) => warn!("Got negative remote queue time: {}", err),
           }
+          match (execution_start - worker_start).to_std() {
+            Ok(duration) => attempts.current_attempt.remote_worker_setup = Some(duration),
+            Err(err) => warn!("Got negative remote worker setup time: {}", err),
+          }
           match (input_fetch_completed - input_fetch_start).to_std() {
             Ok(duration) => attempts.current_attempt.remote_input_fetch = Some(duration),
             Err(err) => warn!("Got negative remote input fetch time: {}", err),
@@ -438,20 +908,11 @@ impl CommandRunner {
 
         let status = execute_response.take_status();
         if grpcio::RpcStatusCode::from(status.get_code()) == grpcio::RpcStatusCode::Ok {
-          return self
-            .extract_stdout(&execute_response)
-            .join(self.extract_stderr(&execute_response))
-            .join(self.extract_output_files(&execute_response))
-            .and_then(move |((stdout, stderr), output_directory)| {
-              Ok(FallibleExecuteProcessResult {
-                stdout: stdout,
-                stderr: stderr,
-                exit_code: execute_response.get_result().get_exit_code(),
-                output_directory: output_directory,
-                execution_attempts: execution_attempts,
-              })
-            })
-            .to_boxed();
+          return extract_action_result(
+            &self.store,
+            execute_response.get_result(),
+            execution_attempts,
+          );
         }
         status
       }
@@ -460,76 +921,11 @@ impl CommandRunner {
 
     match grpcio::RpcStatusCode::from(status.get_code()) {
       grpcio::RpcStatusCode::Ok => unreachable!(),
-      grpcio::RpcStatusCode::FailedPrecondition => {
-        if status.get_details().len() != 1 {
-          return future::err(ExecutionError::Fatal(format!(
-            "Received multiple details in FailedPrecondition ExecuteResponse's status field: {:?}",
-            status.get_details()
-          )))
-          .to_boxed();
-        }
-        let details = status.get_details().get(0).unwrap();
-        let mut precondition_failure = bazel_protos::error_details::PreconditionFailure::new();
-        if details.get_type_url()
-          != format!(
-            "type.googleapis.com/{}",
-            precondition_failure.descriptor().full_name()
-          )
-        {
-          return future::err(ExecutionError::Fatal(format!(
-            "Received FailedPrecondition, but didn't know how to resolve it: {},\
-             protobuf type {}",
-            status.get_message(),
-            details.get_type_url()
-          )))
-          .to_boxed();
-        }
-        try_future!(precondition_failure
-          .merge_from_bytes(details.get_value())
-          .map_err(|e| ExecutionError::Fatal(format!(
-            "Error deserializing FailedPrecondition proto: {:?}",
-            e
-          ))));
-
-        let mut missing_digests = Vec::with_capacity(precondition_failure.get_violations().len());
-
-        for violation in precondition_failure.get_violations() {
-          if violation.get_field_type() != "MISSING" {
-            return future::err(ExecutionError::Fatal(format!(
-              "Didn't know how to process PreconditionFailure violation: {:?}",
-              violation
-            )))
-            .to_boxed();
-          }
-          let parts: Vec<_> = violation.get_subject().split('/').collect();
-          if parts.len() != 3 || parts[0] != "blobs" {
-            return future::err(ExecutionError::Fatal(format!(
-              "Received FailedPrecondition MISSING but didn't recognize subject {}",
-              violation.get_subject()
-            )))
-            .to_boxed();
-          }
-          let digest = Digest(
-            try_future!(Fingerprint::from_hex_string(parts[1]).map_err(|e| {
-              ExecutionError::Fatal(format!("Bad digest in missing blob: {}: {}", parts[1], e))
-            })),
-            try_future!(parts[2]
-              .parse::<usize>()
-              .map_err(|e| ExecutionError::Fatal(format!(
-                "Missing blob had bad size: {}: {}",
-                parts[2], e
-              )))),
-          );
-          missing_digests.push(digest);
-        }
-        if missing_digests.is_empty() {
-          return future::err(ExecutionError::Fatal(
-            "Error from remote execution: FailedPrecondition, but no details".to_owned(),
-          ))
-          .to_boxed();
-        }
-        future::err(ExecutionError::MissingDigests(missing_digests)).to_boxed()
+      grpcio::RpcStatusCode::FailedPrecondition => match missing_digests(&status) {
+        Ok(missing_digests) => future::err(ExecutionError::MissingDigests(missing_digests)),
+        Err(err) => future::err(ExecutionError::Fatal(err)),
       }
+      .to_boxed(),
       code => future::err(ExecutionError::Fatal(format!(
         "Error from remote execution: {:?}: {:?}",
         code,
@@ -539,205 +935,412 @@ impl CommandRunner {
     }
     .to_boxed()
   }
+}
 
-  fn extract_stdout(
-    &self,
-    execute_response: &bazel_protos::remote_execution::ExecuteResponse,
-  ) -> BoxFuture<Bytes, ExecutionError> {
-    if execute_response.get_result().has_stdout_digest() {
-      let stdout_digest_result: Result<Digest, String> =
-        execute_response.get_result().get_stdout_digest().into();
-      let stdout_digest = try_future!(stdout_digest_result
-        .map_err(|err| ExecutionError::Fatal(format!("Error extracting stdout: {}", err))));
-      self
-        .store
-        .load_file_bytes_with(stdout_digest, |v| v)
-        .map_err(move |error| {
+///
+/// The blobs which a FailedPrecondition status reports as missing from the CAS (i.e. its MISSING
+/// violations, whose subjects are of the form `blobs/{hash}/{size}`), which can be resolved by
+/// uploading them and executing again.
+///
+/// Details other than the PreconditionFailure (e.g. DebugInfo) are ignored. An Err describes why
+/// the failure can't be resolved by uploading blobs: e.g. a violation other than MISSING.
+///
+fn missing_digests(status: &bazel_protos::status::Status) -> Result<Vec<Digest>, String> {
+  let precondition_failure_type_url = format!(
+    "type.googleapis.com/{}",
+    bazel_protos::error_details::PreconditionFailure::new()
+      .descriptor()
+      .full_name()
+  );
+  let details = status
+    .get_details()
+    .iter()
+    .find(|details| details.get_type_url() == precondition_failure_type_url)
+    .ok_or_else(|| {
+      format!(
+        "Error from remote execution: FailedPrecondition, but no details: {}",
+        status.get_message()
+      )
+    })?;
+  let mut precondition_failure = bazel_protos::error_details::PreconditionFailure::new();
+  precondition_failure
+    .merge_from_bytes(details.get_value())
+    .map_err(|e| format!("Error deserializing FailedPrecondition proto: {:?}", e))?;
+
+  let unresolvable = precondition_failure
+    .get_violations()
+    .iter()
+    .filter(|violation| violation.get_field_type() != "MISSING")
+    .map(|violation| {
+      format!(
+        "{} {}: {}",
+        violation.get_field_type(),
+        violation.get_subject(),
+        violation.get_description()
+      )
+    })
+    .collect::<Vec<_>>();
+  if !unresolvable.is_empty() {
+    return Err(format!(
+      "Remote execution failed a precondition ({}): {}",
+      status.get_message(),
+      unresolvable.join("; ")
+    ));
+  }
+
+  let missing_digests = precondition_failure
+    .get_violations()
+    .iter()
+    .map(|violation| {
+      let parts: Vec<_> = violation.get_subject().split('/').collect();
+      if parts.len() != 3 || parts[0] != "blobs" {
+        return Err(format!(
+          "Received FailedPrecondition MISSING but didn't recognize subject {}",
+          violation.get_subject()
+        ));
+      }
+      let fingerprint = Fingerprint::from_hex_string(parts[1])
+        .map_err(|e| format!("Bad digest in missing blob: {}: {}", parts[1], e))?;
+      let size = parts[2]
+        .parse::<usize>()
+        .map_err(|e| format!("Missing blob had bad size: {}: {}", parts[2], e))?;
+      Ok(Digest(fingerprint, size))
+    })
+    .collect::<Result<Vec<_>, String>>()?;
+  if missing_digests.is_empty() {
+    return Err(format!(
+      "Error from remote execution: FailedPrecondition, but no missing blobs: {}",
+      status.get_message()
+    ));
+  }
+  Ok(missing_digests)
+}
+
+///
+/// Converts an ActionResult into a FallibleExecuteProcessResult, fetching its stdout and stderr
+/// (if they weren't inlined) and recording its outputs in the Store.
+///
+fn extract_action_result(
+  store: &Store,
+  action_result: &bazel_protos::remote_execution::ActionResult,
+  execution_attempts: Vec<ExecutionStats>,
+) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+  let exit_code = action_result.get_exit_code();
+  extract_stdout(store, action_result)
+    .join(extract_stderr(store, action_result))
+    .join(extract_output_files(store, action_result))
+    .and_then(move |((stdout, stderr), output_directory)| {
+      Ok(FallibleExecuteProcessResult {
+        stdout: stdout,
+        stderr: stderr,
+        exit_code: exit_code,
+        output_directory: output_directory,
+        execution_attempts: execution_attempts,
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
+      })
+    })
+    .to_boxed()
+}
+
+///
+/// Looks up the result of the Action with the given digest in the remote ActionCache, and if
+/// there is one, downloads its outputs. Failures are logged and treated as misses, because the
+/// Action can still be executed.
+///
+pub(crate) fn lookup_action_result(
+  store: &Store,
+  action_digest: Digest,
+) -> BoxFuture<Option<FallibleExecuteProcessResult>, String> {
+  let store = store.clone();
+  store
+    .load_remote_action_result(action_digest)
+    .map_err(ExecutionError::Fatal)
+    .and_then(move |maybe_action_result| match maybe_action_result {
+      Some(action_result) => {
+        let attempt = ExecutionStats {
+          was_cache_hit: true,
+          ..ExecutionStats::default()
+        };
+        extract_action_result(&store, &action_result, vec![attempt])
+          .and_then(move |result| {
+            store
+              .ensure_local_has_recursive_directory(result.output_directory)
+              .map(move |()| Some(result))
+              .map_err(ExecutionError::Fatal)
+          })
+          .to_boxed()
+      }
+      None => future::ok(None).to_boxed(),
+    })
+    .then(move |res| match res {
+      Ok(maybe_result) => Ok(maybe_result),
+      Err(err) => {
+        warn!(
+          "Failed to use the remote action cache result for {:?}: {:?}",
+          action_digest, err
+        );
+        Ok(None)
+      }
+    })
+    .to_boxed()
+}
+
+fn extract_stdout(
+  store: &Store,
+  action_result: &bazel_protos::remote_execution::ActionResult,
+) -> BoxFuture<Bytes, ExecutionError> {
+  if action_result.has_stdout_digest() {
+    let stdout_digest_result: Result<Digest, String> = action_result.get_stdout_digest().into();
+    let stdout_digest = try_future!(stdout_digest_result
+      .map_err(|err| ExecutionError::Fatal(format!("Error extracting stdout: {}", err))));
+    store
+      .load_file_bytes_with(stdout_digest, |v| v)
+      .map_err(move |error| {
+        ExecutionError::Fatal(format!(
+          "Error fetching stdout digest ({:?}): {:?}",
+          stdout_digest, error
+        ))
+      })
+      .and_then(move |maybe_value| {
+        maybe_value.ok_or_else(|| {
           ExecutionError::Fatal(format!(
-            "Error fetching stdout digest ({:?}): {:?}",
-            stdout_digest, error
+            "Couldn't find stdout digest ({:?}), when fetching.",
+            stdout_digest
           ))
         })
-        .and_then(move |maybe_value| {
-          maybe_value.ok_or_else(|| {
-            ExecutionError::Fatal(format!(
-              "Couldn't find stdout digest ({:?}), when fetching.",
-              stdout_digest
-            ))
-          })
-        })
-        .to_boxed()
-    } else {
-      let stdout_raw = Bytes::from(execute_response.get_result().get_stdout_raw());
-      let stdout_copy = stdout_raw.clone();
-      self
-        .store
-        .store_file_bytes(stdout_raw, true)
-        .map_err(move |error| {
-          ExecutionError::Fatal(format!("Error storing raw stdout: {:?}", error))
-        })
-        .map(|_| stdout_copy)
-        .to_boxed()
-    }
+      })
+      .to_boxed()
+  } else {
+    let stdout_raw = Bytes::from(action_result.get_stdout_raw());
+    let stdout_copy = stdout_raw.clone();
+    store
+      .store_file_bytes(stdout_raw, true)
+      .map_err(move |error| ExecutionError::Fatal(format!("Error storing raw stdout: {:?}", error)))
+      .map(|_| stdout_copy)
+      .to_boxed()
   }
+}
 
-  fn extract_stderr(
-    &self,
-    execute_response: &bazel_protos::remote_execution::ExecuteResponse,
-  ) -> BoxFuture<Bytes, ExecutionError> {
-    if execute_response.get_result().has_stderr_digest() {
-      let stderr_digest_result: Result<Digest, String> =
-        execute_response.get_result().get_stderr_digest().into();
-      let stderr_digest = try_future!(stderr_digest_result
-        .map_err(|err| ExecutionError::Fatal(format!("Error extracting stderr: {}", err))));
-      self
-        .store
-        .load_file_bytes_with(stderr_digest, |v| v)
-        .map_err(move |error| {
+fn extract_stderr(
+  store: &Store,
+  action_result: &bazel_protos::remote_execution::ActionResult,
+) -> BoxFuture<Bytes, ExecutionError> {
+  if action_result.has_stderr_digest() {
+    let stderr_digest_result: Result<Digest, String> = action_result.get_stderr_digest().into();
+    let stderr_digest = try_future!(stderr_digest_result
+      .map_err(|err| ExecutionError::Fatal(format!("Error extracting stderr: {}", err))));
+    store
+      .load_file_bytes_with(stderr_digest, |v| v)
+      .map_err(move |error| {
+        ExecutionError::Fatal(format!(
+          "Error fetching stderr digest ({:?}): {:?}",
+          stderr_digest, error
+        ))
+      })
+      .and_then(move |maybe_value| {
+        maybe_value.ok_or_else(|| {
           ExecutionError::Fatal(format!(
-            "Error fetching stderr digest ({:?}): {:?}",
-            stderr_digest, error
+            "Couldn't find stderr digest ({:?}), when fetching.",
+            stderr_digest
           ))
         })
-        .and_then(move |maybe_value| {
-          maybe_value.ok_or_else(|| {
-            ExecutionError::Fatal(format!(
-              "Couldn't find stderr digest ({:?}), when fetching.",
-              stderr_digest
-            ))
-          })
+      })
+      .to_boxed()
+  } else {
+    let stderr_raw = Bytes::from(action_result.get_stderr_raw());
+    let stderr_copy = stderr_raw.clone();
+    store
+      .store_file_bytes(stderr_raw, true)
+      .map_err(move |error| ExecutionError::Fatal(format!("Error storing raw stderr: {:?}", error)))
+      .map(|_| stderr_copy)
+      .to_boxed()
+  }
+}
+
+fn extract_output_files(
+  store: &Store,
+  action_result: &bazel_protos::remote_execution::ActionResult,
+) -> BoxFuture<Digest, ExecutionError> {
+  // Get Digests of output Directories.
+  // Then we'll make a Directory for the output files, and merge them.
+  let mut directory_digests = Vec::with_capacity(action_result.get_output_directories().len() + 1);
+  // TODO: Maybe take rather than clone
+  let output_directories = action_result.get_output_directories().to_owned();
+  for dir in output_directories {
+    // Output directories refer to Tree protos, which contain every Directory in the output
+    // directory, so are recorded locally in one go.
+    let tree_digest_result: Result<Digest, String> = dir.get_tree_digest().into();
+    let store2 = store.clone();
+    let mut digest = future::done(tree_digest_result)
+      .and_then(move |tree_digest| {
+        store2.load_tree(tree_digest).and_then(move |maybe_root| {
+          maybe_root.ok_or_else(|| format!("Couldn't find output Tree {:?}", tree_digest))
         })
-        .to_boxed()
-    } else {
-      let stderr_raw = Bytes::from(execute_response.get_result().get_stderr_raw());
-      let stderr_copy = stderr_raw.clone();
-      self
-        .store
-        .store_file_bytes(stderr_raw, true)
-        .map_err(move |error| {
-          ExecutionError::Fatal(format!("Error storing raw stderr: {:?}", error))
+      })
+      .to_boxed();
+    for component in dir.get_path().rsplit('/') {
+      let component = component.to_owned();
+      let store = store.clone();
+      digest = digest
+        .and_then(move |digest| {
+          let mut directory = bazel_protos::remote_execution::Directory::new();
+          directory.mut_directories().push({
+            let mut node = bazel_protos::remote_execution::DirectoryNode::new();
+            node.set_name(component);
+            node.set_digest((&digest).into());
+            node
+          });
+          store.record_directory(&directory, true)
         })
-        .map(|_| stderr_copy)
-        .to_boxed()
+        .to_boxed();
     }
+    directory_digests.push(digest.map_err(|err| {
+      ExecutionError::Fatal(format!("Error saving remote output directory: {}", err))
+    }));
   }
 
-  fn extract_output_files(
-    &self,
-    execute_response: &bazel_protos::remote_execution::ExecuteResponse,
-  ) -> BoxFuture<Digest, ExecutionError> {
-    // Get Digests of output Directories.
-    // Then we'll make a Directory for the output files, and merge them.
-    let mut directory_digests =
-      Vec::with_capacity(execute_response.get_result().get_output_directories().len() + 1);
-    // TODO: Maybe take rather than clone
-    let output_directories = execute_response
-      .get_result()
-      .get_output_directories()
-      .to_owned();
-    for dir in output_directories {
-      let digest_result: Result<Digest, String> = dir.get_tree_digest().into();
-      let mut digest = future::done(digest_result).to_boxed();
-      for component in dir.get_path().rsplit('/') {
-        let component = component.to_owned();
-        let store = self.store.clone();
-        digest = digest
-          .and_then(move |digest| {
-            let mut directory = bazel_protos::remote_execution::Directory::new();
-            directory.mut_directories().push({
-              let mut node = bazel_protos::remote_execution::DirectoryNode::new();
-              node.set_name(component);
-              node.set_digest((&digest).into());
-              node
-            });
-            store.record_directory(&directory, true)
-          })
-          .to_boxed();
-      }
-      directory_digests.push(digest.map_err(|err| {
-        ExecutionError::Fatal(format!("Error saving remote output directory: {}", err))
-      }));
-    }
-
-    // Make a directory for the files
-    let mut path_map = HashMap::new();
-    let path_stats_result: Result<Vec<PathStat>, String> = execute_response
-      .get_result()
-      .get_output_files()
-      .iter()
-      .map(|output_file| {
-        let output_file_path_buf = PathBuf::from(output_file.get_path());
-        let digest: Result<Digest, String> = output_file.get_digest().into();
-        path_map.insert(output_file_path_buf.clone(), digest?);
-        Ok(PathStat::file(
-          output_file_path_buf.clone(),
-          File {
-            path: output_file_path_buf,
-            is_executable: output_file.get_is_executable(),
-          },
-        ))
-      })
-      .collect();
+  // Make a directory for the files
+  let mut path_map = HashMap::new();
+  let path_stats_result: Result<Vec<PathStat>, String> = action_result
+    .get_output_files()
+    .iter()
+    .map(|output_file| {
+      let output_file_path_buf = PathBuf::from(output_file.get_path());
+      let digest: Result<Digest, String> = output_file.get_digest().into();
+      path_map.insert(output_file_path_buf.clone(), digest?);
+      Ok(PathStat::file(
+        output_file_path_buf.clone(),
+        File {
+          path: output_file_path_buf,
+          is_executable: output_file.get_is_executable(),
+        },
+      ))
+    })
+    .collect();
 
-    let path_stats = try_future!(path_stats_result.map_err(ExecutionError::Fatal));
+  let path_stats = try_future!(path_stats_result.map_err(ExecutionError::Fatal));
 
-    #[derive(Clone)]
-    struct StoreOneOffRemoteDigest {
-      map_of_paths_to_digests: HashMap<PathBuf, Digest>,
-    }
+  #[derive(Clone)]
+  struct StoreOneOffRemoteDigest {
+    map_of_paths_to_digests: HashMap<PathBuf, Digest>,
+  }
 
-    impl StoreOneOffRemoteDigest {
-      fn new(map: HashMap<PathBuf, Digest>) -> StoreOneOffRemoteDigest {
-        StoreOneOffRemoteDigest {
-          map_of_paths_to_digests: map,
-        }
+  impl StoreOneOffRemoteDigest {
+    fn new(map: HashMap<PathBuf, Digest>) -> StoreOneOffRemoteDigest {
+      StoreOneOffRemoteDigest {
+        map_of_paths_to_digests: map,
       }
     }
+  }
 
-    impl fs::StoreFileByDigest<String> for StoreOneOffRemoteDigest {
-      fn store_by_digest(&self, file: File) -> BoxFuture<Digest, String> {
-        match self.map_of_paths_to_digests.get(&file.path) {
-          Some(digest) => future::ok(*digest),
-          None => future::err(format!(
-            "Didn't know digest for path in remote execution response: {:?}",
-            file.path
-          )),
-        }
-        .to_boxed()
+  impl fs::StoreFileByDigest<String> for StoreOneOffRemoteDigest {
+    fn store_by_digest(&self, file: File) -> BoxFuture<Digest, String> {
+      match self.map_of_paths_to_digests.get(&file.path) {
+        Some(digest) => future::ok(*digest),
+        None => future::err(format!(
+          "Didn't know digest for path in remote execution response: {:?}",
+          file.path
+        )),
       }
+      .to_boxed()
     }
+  }
 
-    let store = self.store.clone();
-    fs::Snapshot::digest_from_path_stats(
-      self.store.clone(),
-      &StoreOneOffRemoteDigest::new(path_map),
-      &path_stats,
-    )
-    .map_err(move |error| {
+  let store = store.clone();
+  fs::Snapshot::digest_from_path_stats(
+    store.clone(),
+    &StoreOneOffRemoteDigest::new(path_map),
+    &path_stats,
+  )
+  .map_err(move |error| {
+    ExecutionError::Fatal(format!(
+      "Error when storing the output file directory info in the remote CAS: {:?}",
+      error
+    ))
+  })
+  .join(future::join_all(directory_digests))
+  .and_then(|(files_digest, mut directory_digests)| {
+    directory_digests.push(files_digest);
+    fs::Snapshot::merge_directories(store, directory_digests).map_err(|err| {
       ExecutionError::Fatal(format!(
-        "Error when storing the output file directory info in the remote CAS: {:?}",
-        error
+        "Error when merging output files and directories: {}",
+        err
       ))
     })
-    .join(future::join_all(directory_digests))
-    .and_then(|(files_digest, mut directory_digests)| {
-      directory_digests.push(files_digest);
-      fs::Snapshot::merge_directories(store, directory_digests).map_err(|err| {
-        ExecutionError::Fatal(format!(
-          "Error when merging output files and directories: {}",
-          err
-        ))
-      })
-    })
-    .to_boxed()
+  })
+  .to_boxed()
+}
+
+///
+/// How the outputs of a process are declared in its Command.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum OutputDeclaration {
+  ///
+  /// As `output_files` and `output_directories`, as of v2.0 of the API.
+  ///
+  FilesAndDirectories,
+  ///
+  /// As `output_paths`, as of v2.1 of the API, which supersedes the fields of v2.0 (and which
+  /// servers which have dropped support for v2.0 require).
+  ///
+  Paths,
+}
+
+impl OutputDeclaration {
+  ///
+  /// The form in which servers with the given capabilities expect outputs to be declared. Servers
+  /// which don't advertise a version of the API support v2.0.
+  ///
+  fn supported_by(
+    capabilities: &bazel_protos::remote_execution::ServerCapabilities,
+  ) -> OutputDeclaration {
+    let high_api_version = capabilities.get_high_api_version();
+    // Pre-release versions are earlier than any released version.
+    if high_api_version.get_prerelease().is_empty()
+      && (high_api_version.get_major(), high_api_version.get_minor()) >= (2, 1)
+    {
+      OutputDeclaration::Paths
+    } else {
+      OutputDeclaration::FilesAndDirectories
+    }
   }
 }
 
-fn make_execute_request(
+pub(crate) fn make_execute_request(
+  req: &ExecuteProcessRequest,
+  instance_name: &Option<String>,
+  cache_key_gen_version: &Option<String>,
+  platform_properties: BTreeMap<String, String>,
+) -> Result<
+  (
+    bazel_protos::remote_execution::Action,
+    bazel_protos::remote_execution::Command,
+    bazel_protos::remote_execution::ExecuteRequest,
+  ),
+  String,
+> {
+  make_execute_request_with_output_declaration(
+    req,
+    instance_name,
+    cache_key_gen_version,
+    platform_properties,
+    OutputDeclaration::FilesAndDirectories,
+  )
+}
+
+///
+/// As for `make_execute_request`, but declaring outputs in the given form. Because the form is part
+/// of the Command, Actions whose outputs are declared differently have different digests.
+///
+pub(crate) fn make_execute_request_with_output_declaration(
   req: &ExecuteProcessRequest,
   instance_name: &Option<String>,
   cache_key_gen_version: &Option<String>,
   mut platform_properties: BTreeMap<String, String>,
+  output_declaration: OutputDeclaration,
 ) -> Result<
   (
     bazel_protos::remote_execution::Action,
@@ -746,6 +1349,15 @@ fn make_execute_request(
   ),
   String,
 > {
+  if !req.is_remotable() {
+    return Err(format!(
+      "Processes which pass through environment variables ({:?}) or use tools from this machine \
+       ({:?}) can't be executed remotely: {}",
+      req.env_passthrough,
+      req.tool_mounts.keys().collect::<Vec<_>>(),
+      req.description
+    ));
+  }
   let mut command = bazel_protos::remote_execution::Command::new();
   command.set_arguments(protobuf::RepeatedField::from_vec(req.argv.clone()));
   for (ref name, ref value) in &req.env {
@@ -776,30 +1388,51 @@ fn make_execute_request(
     })
     .collect::<Result<Vec<String>, String>>()?;
   output_files.sort();
-  command.set_output_files(protobuf::RepeatedField::from_vec(output_files));
 
   let mut output_directories = req
     .output_directories
     .iter()
     .map(|p| {
+      if super::is_output_glob(p) {
+        return Err(format!(
+          "Output directory globs are not supported for remote execution: {:?}",
+          p
+        ));
+      }
       p.to_str()
         .map(str::to_owned)
         .ok_or_else(|| format!("Non-UTF8 output directory path: {:?}", p))
     })
     .collect::<Result<Vec<String>, String>>()?;
   output_directories.sort();
-  command.set_output_directories(protobuf::RepeatedField::from_vec(output_directories));
 
-  if req.jdk_home.is_some() {
+  match output_declaration {
+    OutputDeclaration::FilesAndDirectories => {
+      command.set_output_files(protobuf::RepeatedField::from_vec(output_files));
+      command.set_output_directories(protobuf::RepeatedField::from_vec(output_directories));
+    }
+    OutputDeclaration::Paths => {
+      let mut output_paths = output_files;
+      output_paths.extend(output_directories);
+      output_paths.sort();
+      output_paths.dedup();
+      command.set_output_paths(protobuf::RepeatedField::from_vec(output_paths));
+    }
+  }
+
+  if req.tool_mounts.contains_key(super::JDK_TOOL) {
     // Ideally, the JDK would be brought along as part of the input directory, but we don't
     // currently have support for that. Scoot supports this property, and will symlink .jdk to a
     // system-installed JDK https://github.com/twitter/scoot/pull/391 - we should probably come to
     // some kind of consensus across tools as to how this should work; RBE appears to work by
     // allowing you to specify a jdk-version platform property, and it will put a JDK at a
     // well-known path in the docker container you specify in which to run.
-    platform_properties.insert("JDK_SYMLINK".to_owned(), ".jdk".to_owned());
+    platform_properties.insert("JDK_SYMLINK".to_owned(), super::JDK_SANDBOX_PATH.to_owned());
   }
 
+  // Properties of the request take precedence over the defaults for every request.
+  platform_properties.extend(req.platform_properties.clone());
+
   for (name, value) in platform_properties {
     command.mut_platform().mut_properties().push({
       let mut property = bazel_protos::remote_execution::Platform_Property::new();
@@ -818,6 +1451,11 @@ fn make_execute_request(
     execute_request.set_instance_name(instance_name.clone());
   }
   execute_request.set_action_digest((&digest(&action)?).into());
+  if req.priority != 0 {
+    execute_request
+      .mut_execution_policy()
+      .set_priority(req.priority);
+  }
 
   Ok((action, command, execute_request))
 }
@@ -832,24 +1470,30 @@ fn format_error(error: &bazel_protos::status::Status) -> String {
 }
 
 ///
-/// If the given operation represents a cancelled request, recover it into
-/// ExecutionError::NotFinished.
+/// Logs the stage (e.g. queued or executing) of an unfinished operation if it has changed from the
+/// given previous stage, returning its stage.
 ///
-fn rpcerror_recover_cancelled(
-  operation_name: String,
-  err: grpcio::Error,
-) -> Result<bazel_protos::operations::Operation, grpcio::Error> {
-  // If the error represented cancellation, return an Operation for the given Operation name.
-  match &err {
-    &grpcio::Error::RpcFailure(ref rs) if rs.status == grpcio::RpcStatusCode::Cancelled => {
-      let mut next_operation = bazel_protos::operations::Operation::new();
-      next_operation.set_name(operation_name);
-      return Ok(next_operation);
-    }
-    _ => {}
+fn log_stage_change(
+  operation: &bazel_protos::operations::Operation,
+  previous_stage: Option<bazel_protos::remote_execution::ExecutionStage_Value>,
+) -> Option<bazel_protos::remote_execution::ExecutionStage_Value> {
+  if !operation.has_metadata() {
+    return previous_stage;
+  }
+  let mut metadata = bazel_protos::remote_execution::ExecuteOperationMetadata::new();
+  if let Err(err) = metadata.merge_from_bytes(operation.get_metadata().get_value()) {
+    warn!(
+      "Invalid ExecuteOperationMetadata for operation {}: {:?}",
+      operation.get_name(),
+      err
+    );
+    return previous_stage;
   }
-  // Did not represent cancellation.
-  Err(err)
+  let stage = metadata.get_stage();
+  if previous_stage != Some(stage) {
+    debug!("Operation {} is now {:?}", operation.get_name(), stage);
+  }
+  Some(stage)
 }
 
 fn rpcerror_to_status_or_string(
@@ -861,7 +1505,9 @@ fn rpcerror_to_status_or_string(
       ..
     }) => {
       let mut status_proto = bazel_protos::status::Status::new();
-      status_proto.merge_from_bytes(&status_proto_bytes).unwrap();
+      status_proto
+        .merge_from_bytes(&status_proto_bytes)
+        .map_err(|e| format!("Error deserializing Status proto: {:?}", e))?;
       Ok(status_proto)
     }
     grpcio::Error::RpcFailure(grpcio::RpcStatus {
@@ -886,7 +1532,7 @@ fn rpcerror_to_string(error: grpcio::Error) -> String {
   }
 }
 
-fn digest(message: &dyn Message) -> Result<Digest, String> {
+pub(crate) fn digest(message: &dyn Message) -> Result<Digest, String> {
   let bytes = message.write_to_bytes().map_err(|e| format!("{:?}", e))?;
 
   let mut hasher = Sha256::default();
@@ -913,13 +1559,14 @@ mod tests {
   use mock;
   use protobuf::{self, Message, ProtobufEnum};
   use tempfile::TempDir;
-  use testutil::data::{TestData, TestDirectory};
+  use testutil::data::{TestData, TestDirectory, TestTree};
   use testutil::{as_bytes, owned_string_vec};
 
+  use super::super::instance_routing::InstanceRoute;
   use super::super::CommandRunner as CommandRunnerTrait;
   use super::{
-    CommandRunner, ExecuteProcessRequest, ExecutionError, ExecutionHistory,
-    FallibleExecuteProcessResult,
+    action_mnemonic, CommandRunner, ExecuteProcessRequest, ExecutionError, ExecutionHistory,
+    ExecutionStats, FallibleExecuteProcessResult, OutputDeclaration, Platform,
   };
   use mock::execution_server::MockOperation;
   use std::collections::{BTreeMap, BTreeSet};
@@ -960,7 +1607,16 @@ mod tests {
         .collect(),
       timeout: Duration::from_millis(1000),
       description: "some description".to_owned(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     };
 
     let mut want_command = bazel_protos::remote_execution::Command::new();
@@ -1033,7 +1689,16 @@ mod tests {
         .collect(),
       timeout: Duration::from_millis(1000),
       description: "some description".to_owned(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     };
 
     let mut want_command = bazel_protos::remote_execution::Command::new();
@@ -1107,7 +1772,16 @@ mod tests {
         .collect(),
       timeout: Duration::from_millis(1000),
       description: "some description".to_owned(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     };
 
     let mut want_command = bazel_protos::remote_execution::Command::new();
@@ -1166,6 +1840,121 @@ mod tests {
     );
   }
 
+  #[test]
+  fn make_execute_request_with_output_paths() {
+    let mut req = echo_foo_request();
+    // Intentionally poorly sorted:
+    req.output_files = vec!["path/to/file", "other/file"]
+      .into_iter()
+      .map(PathBuf::from)
+      .collect();
+    req.output_directories = vec![PathBuf::from("directory/name")].into_iter().collect();
+
+    let (legacy_action, _, _) =
+      super::make_execute_request(&req, &None, &None, BTreeMap::new()).unwrap();
+    let (action, command, _) = super::make_execute_request_with_output_declaration(
+      &req,
+      &None,
+      &None,
+      BTreeMap::new(),
+      OutputDeclaration::Paths,
+    )
+    .unwrap();
+
+    assert_eq!(
+      command.get_output_paths().to_vec(),
+      owned_string_vec(&["directory/name", "other/file", "path/to/file"])
+    );
+    assert!(command.get_output_files().is_empty());
+    assert!(command.get_output_directories().is_empty());
+    assert_ne!(action, legacy_action);
+  }
+
+  #[test]
+  fn output_declaration_supported_by_server() {
+    let capabilities = |major, minor, prerelease: &str| {
+      let mut capabilities = bazel_protos::remote_execution::ServerCapabilities::new();
+      let version = capabilities.mut_high_api_version();
+      version.set_major(major);
+      version.set_minor(minor);
+      version.set_prerelease(prerelease.to_owned());
+      capabilities
+    };
+
+    assert_eq!(
+      OutputDeclaration::supported_by(&bazel_protos::remote_execution::ServerCapabilities::new()),
+      OutputDeclaration::FilesAndDirectories
+    );
+    assert_eq!(
+      OutputDeclaration::supported_by(&capabilities(2, 0, "")),
+      OutputDeclaration::FilesAndDirectories
+    );
+    assert_eq!(
+      OutputDeclaration::supported_by(&capabilities(2, 1, "")),
+      OutputDeclaration::Paths
+    );
+    assert_eq!(
+      OutputDeclaration::supported_by(&capabilities(3, 0, "")),
+      OutputDeclaration::Paths
+    );
+    assert_eq!(
+      OutputDeclaration::supported_by(&capabilities(0, 0, "2.1-rc1")),
+      OutputDeclaration::FilesAndDirectories
+    );
+  }
+
+  #[test]
+  fn make_execute_request_with_output_dir_glob() {
+    let req = ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/echo", "yo"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: vec![PathBuf::from("dist/**")].into_iter().collect(),
+      timeout: Duration::from_millis(1000),
+      description: "some description".to_owned(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    };
+
+    super::make_execute_request(&req, &None, &None, BTreeMap::new())
+      .expect_err("Want Err for glob output directory");
+  }
+
+  #[test]
+  fn make_execute_request_with_env_passthrough() {
+    let req = ExecuteProcessRequest {
+      argv: owned_string_vec(&["/usr/bin/ssh", "-T", "git@example.com"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "some description".to_owned(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: vec!["SSH_AUTH_SOCK".to_owned()].into_iter().collect(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    };
+
+    super::make_execute_request(&req, &None, &None, BTreeMap::new())
+      .expect_err("Want Err for env passthrough");
+  }
+
   #[test]
   fn make_execute_request_with_jdk() {
     let input_directory = TestDirectory::containing_roland();
@@ -1177,7 +1966,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "some description".to_owned(),
-      jdk_home: Some(PathBuf::from("/tmp")),
+      tool_mounts: crate::jdk_tool_mounts(Some(PathBuf::from("/tmp"))),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     };
 
     let mut want_command = bazel_protos::remote_execution::Command::new();
@@ -1232,7 +2030,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "some description".to_owned(),
-      jdk_home: Some(PathBuf::from("/tmp")),
+      tool_mounts: crate::jdk_tool_mounts(Some(PathBuf::from("/tmp"))),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     };
 
     let mut want_command = bazel_protos::remote_execution::Command::new();
@@ -1298,6 +2105,96 @@ mod tests {
     );
   }
 
+  #[test]
+  fn make_execute_request_with_request_platform_properties() {
+    let req = crate::ExecuteProcessRequestBuilder::new(
+      owned_string_vec(&["/bin/echo", "yo"]),
+      Duration::from_millis(1000),
+      "some description".to_owned(),
+    )
+    .platform_properties(
+      vec![
+        ("container-image".to_owned(), "docker://ubuntu".to_owned()),
+        ("last".to_owned(), "baz".to_owned()),
+      ]
+      .into_iter()
+      .collect(),
+    )
+    .build()
+    .unwrap();
+
+    let (_, command, _) = super::make_execute_request(
+      &req,
+      &None,
+      &None,
+      vec![
+        ("FIRST".to_owned(), "foo".to_owned()),
+        ("last".to_owned(), "bar".to_owned()),
+      ]
+      .into_iter()
+      .collect(),
+    )
+    .unwrap();
+
+    // Properties of the request override the defaults with the same names.
+    assert_eq!(
+      command
+        .get_platform()
+        .get_properties()
+        .iter()
+        .map(|property| (property.get_name(), property.get_value()))
+        .collect::<Vec<_>>(),
+      vec![
+        ("FIRST", "foo"),
+        ("container-image", "docker://ubuntu"),
+        ("last", "baz"),
+      ]
+    );
+  }
+
+  #[test]
+  fn action_mnemonic_is_executable_name() {
+    assert_eq!(
+      action_mnemonic(&owned_string_vec(&["/usr/lib/jvm/bin/javac", "Foo.java"])),
+      "javac"
+    );
+    assert_eq!(action_mnemonic(&owned_string_vec(&["python"])), "python");
+    assert_eq!(action_mnemonic(&[]), "");
+  }
+
+  #[test]
+  fn make_execute_request_with_priority() {
+    let builder = crate::ExecuteProcessRequestBuilder::new(
+      owned_string_vec(&["/bin/echo", "yo"]),
+      Duration::from_millis(1000),
+      "some description".to_owned(),
+    );
+    let (want_action, _, want_execute_request) = super::make_execute_request(
+      &builder.clone().build().unwrap(),
+      &None,
+      &None,
+      BTreeMap::new(),
+    )
+    .unwrap();
+    assert!(!want_execute_request.has_execution_policy());
+
+    let (action, _, execute_request) = super::make_execute_request(
+      &builder.priority(-1).build().unwrap(),
+      &None,
+      &None,
+      BTreeMap::new(),
+    )
+    .unwrap();
+
+    // The priority is a property of the execution, rather than of the Action (i.e. the cache key).
+    assert_eq!(action, want_action);
+    assert_eq!(
+      execute_request.get_action_digest(),
+      want_execute_request.get_action_digest()
+    );
+    assert_eq!(execute_request.get_execution_policy().get_priority(), -1);
+  }
+
   #[test]
   fn server_rejecting_execute_request_gives_error() {
     let execute_request = echo_foo_request();
@@ -1314,7 +2211,16 @@ mod tests {
             output_directories: BTreeSet::new(),
             timeout: Duration::from_millis(1000),
             description: "wrong command".to_string(),
-            jdk_home: None,
+            tool_mounts: BTreeMap::new(),
+            named_caches: BTreeMap::new(),
+            resource_limits: crate::ResourceLimits::default(),
+            reusable_workdir: None,
+            env_passthrough: BTreeSet::new(),
+            concurrency_weight: 1,
+            tee_output: false,
+            platform_properties: BTreeMap::new(),
+            priority: 0,
+            execution_headers: BTreeMap::new(),
           },
           &None,
           &None,
@@ -1334,7 +2240,7 @@ mod tests {
   }
 
   #[test]
-  fn successful_execution_after_one_getoperation() {
+  fn successful_execution_after_one_update() {
     let execute_request = echo_foo_request();
 
     let mock_server = {
@@ -1367,10 +2273,163 @@ mod tests {
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     );
   }
 
+  #[test]
+  fn routed_execution_uses_routed_instance() {
+    let mut execute_request = echo_foo_request();
+    execute_request
+      .platform_properties
+      .insert("pool".to_owned(), "large".to_owned());
+
+    let mock_server = {
+      let op_name = "gimme-foo".to_string();
+
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        super::make_execute_request(
+          &execute_request,
+          &Some("dark-tower".to_owned()),
+          &None,
+          BTreeMap::new(),
+        )
+        .unwrap()
+        .2,
+        vec![make_successful_operation(
+          &op_name,
+          StdoutType::Raw("foo".to_owned()),
+          StderrType::Raw("".to_owned()),
+          0,
+        )],
+      ))
+    };
+    let cas = mock::StubCAS::builder()
+      .instance_name("dark-tower".to_owned())
+      .build();
+
+    let command_runner =
+      create_command_runner(mock_server.address(), &cas).with_instance_routes(vec![
+        InstanceRoute {
+          property: "pool".to_owned(),
+          value: "large".to_owned(),
+          instance_name: "dark-tower".to_owned(),
+        },
+      ]);
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let result = runtime
+      .block_on(command_runner.run(execute_request))
+      .unwrap();
+
+    assert_eq!(result.stdout, as_bytes("foo"));
+    assert_eq!(result.exit_code, 0);
+  }
+
+  #[test]
+  fn execution_headers_are_merged_and_sent() {
+    let mut execute_request = echo_foo_request();
+    execute_request
+      .execution_headers
+      .insert("x-worker-pool".to_owned(), "gpu".to_owned());
+
+    let mock_server = {
+      let op_name = "gimme-foo".to_string();
+
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        super::make_execute_request(&execute_request, &None, &None, BTreeMap::new())
+          .unwrap()
+          .2,
+        vec![make_successful_operation(
+          &op_name,
+          StdoutType::Raw("foo".to_owned()),
+          StderrType::Raw("".to_owned()),
+          0,
+        )],
+      ))
+    };
+    let cas = mock::StubCAS::builder().build();
+
+    let mut execution_headers = BTreeMap::new();
+    execution_headers.insert("x-worker-pool".to_owned(), "cpu".to_owned());
+    execution_headers.insert("x-team".to_owned(), "build".to_owned());
+    let command_runner = create_command_runner(mock_server.address(), &cas)
+      .with_execution_headers(execution_headers)
+      .unwrap();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime
+      .block_on(command_runner.run(execute_request))
+      .unwrap();
+
+    let received_messages = mock_server.mock_responder.received_messages.lock();
+    let headers = &received_messages[0].headers;
+    let header = |name: &str| {
+      headers
+        .iter()
+        .filter(|(key, _)| key == name)
+        .map(|(_, value)| value.clone())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(header("x-worker-pool"), vec![b"gpu".to_vec()]);
+    assert_eq!(header("x-team"), vec![b"build".to_vec()]);
+  }
+
+  #[test]
+  fn invalid_execution_headers_are_rejected() {
+    let cas = mock::StubCAS::builder().build();
+    let mut execution_headers = BTreeMap::new();
+    execution_headers.insert("grpc-timeout".to_owned(), "1S".to_owned());
+    create_command_runner("127.0.0.1:0".to_owned(), &cas)
+      .with_execution_headers(execution_headers)
+      .map(|_| ())
+      .expect_err("Want Err for reserved header");
+  }
+
+  #[test]
+  fn remote_action_cache_hit_skips_execution() {
+    let execute_request = echo_foo_request();
+    let action_digest = super::digest(
+      &super::make_execute_request(&execute_request, &None, &None, BTreeMap::new())
+        .unwrap()
+        .0,
+    )
+    .unwrap();
+
+    let mut action_result = bazel_protos::remote_execution::ActionResult::new();
+    action_result.set_stdout_raw(Bytes::from("foo"));
+    action_result.set_exit_code(0);
+    let cas = mock::StubCAS::builder()
+      .action_result(action_digest, action_result)
+      .build();
+
+    // The server expects no requests at all.
+    let mock_server =
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        "gimme-foo".to_owned(),
+        bazel_protos::remote_execution::ExecuteRequest::new(),
+        vec![],
+      ));
+
+    let command_runner = create_command_runner(mock_server.address(), &cas);
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let result = runtime
+      .block_on(command_runner.run(execute_request))
+      .unwrap();
+
+    assert_eq!(result.stdout, as_bytes("foo"));
+    assert_eq!(result.exit_code, 0);
+    assert!(result.execution_attempts[0].was_cache_hit);
+    assert!(mock_server
+      .mock_responder
+      .received_messages
+      .lock()
+      .is_empty());
+  }
+
   #[test]
   fn extract_response_with_digest_stdout() {
     let op_name = "gimme-foo".to_string();
@@ -1396,6 +2455,9 @@ mod tests {
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     );
   }
@@ -1425,6 +2487,9 @@ mod tests {
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     );
   }
@@ -1463,11 +2528,19 @@ mod tests {
       &[cas.address()],
       None,
       &None,
+      &None,
+      &None,
+      fs::ChannelPolicy::default(),
+      None,
       None,
+      fs::Compression::None,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
       fs::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      fs::LoadBalancing::RoundRobin,
       1,
       timer_thread.with(|t| t.handle()),
     )
@@ -1479,8 +2552,14 @@ mod tests {
       None,
       None,
       None,
+      None,
+      fs::ChannelPolicy::default(),
+      None,
+      None,
       BTreeMap::new(),
       1,
+      10,
+      Duration::from_secs(60),
       store,
       timer_thread,
     );
@@ -1495,6 +2574,9 @@ mod tests {
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     );
 
@@ -1516,7 +2598,7 @@ mod tests {
   }
 
   #[test]
-  fn successful_execution_after_four_getoperations() {
+  fn successful_execution_after_four_updates() {
     let execute_request = echo_foo_request();
 
     let mock_server = {
@@ -1550,12 +2632,15 @@ mod tests {
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     );
   }
 
   #[test]
-  fn timeout_after_sufficiently_delayed_getoperations() {
+  fn timeout_after_sufficiently_delayed_updates() {
     let request_timeout = Duration::new(4, 0);
     let delayed_operation_time = Duration::new(5, 0);
 
@@ -1567,7 +2652,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: request_timeout,
       description: "echo-a-foo".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     };
 
     let mock_server = {
@@ -1626,6 +2720,9 @@ mod tests {
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     );
   }
@@ -1701,7 +2798,7 @@ mod tests {
   }
 
   #[test]
-  fn getoperation_response_error() {
+  fn update_response_error() {
     let execute_request = echo_foo_request();
 
     let mock_server = {
@@ -1762,7 +2859,7 @@ mod tests {
   }
 
   #[test]
-  fn getoperation_missing_response_and_error() {
+  fn update_missing_response_and_error() {
     let execute_request = echo_foo_request();
 
     let mock_server = {
@@ -1829,11 +2926,19 @@ mod tests {
       &[cas.address()],
       None,
       &None,
+      &None,
+      &None,
+      fs::ChannelPolicy::default(),
+      None,
       None,
+      fs::Compression::None,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
       fs::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      fs::LoadBalancing::RoundRobin,
       1,
       timer_thread.with(|t| t.handle()),
     )
@@ -1850,8 +2955,14 @@ mod tests {
       None,
       None,
       None,
+      None,
+      fs::ChannelPolicy::default(),
+      None,
+      None,
       BTreeMap::new(),
       1,
+      10,
+      Duration::from_secs(60),
       store,
       timer_thread,
     );
@@ -1867,6 +2978,9 @@ mod tests {
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       }
     );
     {
@@ -1875,6 +2989,77 @@ mod tests {
     }
   }
 
+  #[test]
+  fn execute_gives_up_if_uploads_of_missing_digests_dont_stick() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let roland = TestData::roland();
+
+    let mock_server = {
+      let op_name = "cat".to_owned();
+      let missing_roland = || {
+        make_precondition_failure_operation(vec![missing_preconditionfailure_violation(
+          &roland.digest(),
+        )])
+      };
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        super::make_execute_request(&cat_roland_request(), &None, &None, BTreeMap::new())
+          .unwrap()
+          .2,
+        (0..=CommandRunner::MAX_MISSING_DIGEST_UPLOADS)
+          .map(|_| missing_roland())
+          .collect(),
+      ))
+    };
+    let cas = mock::StubCAS::empty();
+    let command_runner = create_command_runner(mock_server.address(), &cas);
+    runtime
+      .block_on(command_runner.store.store_file_bytes(roland.bytes(), false))
+      .expect("Saving file bytes to store");
+    runtime
+      .block_on(
+        command_runner
+          .store
+          .record_directory(&TestDirectory::containing_roland().directory(), false),
+      )
+      .expect("Saving directory bytes to store");
+
+    let error = runtime
+      .block_on(command_runner.run(cat_roland_request()))
+      .expect_err("Want Err when the server keeps reporting missing digests");
+    assert_contains(&error, "Server still reported missing digests");
+    assert_eq!(
+      mock_server.mock_responder.received_messages.lock().len(),
+      CommandRunner::MAX_MISSING_DIGEST_UPLOADS + 1
+    );
+  }
+
+  #[test]
+  fn missing_digests_ignores_other_details() {
+    let mut status = make_precondition_failure_status(vec![missing_preconditionfailure_violation(
+      &TestData::roland().digest(),
+    )]);
+    status.mut_details().insert(
+      0,
+      make_any_proto(&bazel_protos::remote_execution::RequestMetadata::new()),
+    );
+    assert_eq!(
+      super::missing_digests(&status),
+      Ok(vec![TestData::roland().digest()])
+    );
+
+    let mut violation = bazel_protos::error_details::PreconditionFailure_Violation::new();
+    violation.set_field_type("OUT_OF_CAPACITY".to_owned());
+    violation.set_subject("workers".to_owned());
+    violation.set_description("No workers available".to_owned());
+    let error = super::missing_digests(&make_precondition_failure_status(vec![
+      missing_preconditionfailure_violation(&TestData::roland().digest()),
+      violation,
+    ]))
+    .expect_err("Want Err for violation which can't be resolved by uploading");
+    assert_contains(&error, "OUT_OF_CAPACITY workers: No workers available");
+  }
+
   //#[test] // TODO: Unignore this test when the server can actually fail with status protos.
   // See https://github.com/pantsbuild/pants/issues/6597
   #[allow(dead_code)]
@@ -1927,11 +3112,19 @@ mod tests {
       &[cas.address()],
       None,
       &None,
+      &None,
+      &None,
+      fs::ChannelPolicy::default(),
       None,
+      None,
+      fs::Compression::None,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
       fs::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      fs::LoadBalancing::RoundRobin,
       1,
       timer_thread.with(|t| t.handle()),
     )
@@ -1947,8 +3140,14 @@ mod tests {
       None,
       None,
       None,
+      None,
+      fs::ChannelPolicy::default(),
+      None,
+      None,
       BTreeMap::new(),
       1,
+      10,
+      Duration::from_secs(60),
       store,
       timer_thread,
     )
@@ -1962,6 +3161,9 @@ mod tests {
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
         execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
       })
     );
     {
@@ -1999,11 +3201,19 @@ mod tests {
       &[cas.address()],
       None,
       &None,
+      &None,
+      &None,
+      fs::ChannelPolicy::default(),
       None,
+      None,
+      fs::Compression::None,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
       fs::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      fs::LoadBalancing::RoundRobin,
       1,
       timer_thread.with(|t| t.handle()),
     )
@@ -2016,8 +3226,14 @@ mod tests {
       None,
       None,
       None,
+      None,
+      fs::ChannelPolicy::default(),
+      None,
+      None,
       BTreeMap::new(),
       1,
+      10,
+      Duration::from_secs(60),
       store,
       timer_thread,
     );
@@ -2050,6 +3266,52 @@ mod tests {
     );
   }
 
+  #[test]
+  fn extract_execute_response_timings() {
+    let timestamp = |seconds| {
+      let mut timestamp = protobuf::well_known_types::Timestamp::new();
+      timestamp.set_seconds(seconds);
+      timestamp
+    };
+    let mut operation = bazel_protos::operations::Operation::new();
+    operation.set_name("cat".to_owned());
+    operation.set_done(true);
+    operation.set_response(make_any_proto(&{
+      let mut response = bazel_protos::remote_execution::ExecuteResponse::new();
+      response.set_result({
+        let mut result = bazel_protos::remote_execution::ActionResult::new();
+        result.set_execution_metadata({
+          let mut metadata = bazel_protos::remote_execution::ExecutedActionMetadata::new();
+          metadata.set_queued_timestamp(timestamp(100));
+          metadata.set_worker_start_timestamp(timestamp(110));
+          metadata.set_input_fetch_start_timestamp(timestamp(111));
+          metadata.set_input_fetch_completed_timestamp(timestamp(113));
+          metadata.set_execution_start_timestamp(timestamp(114));
+          metadata.set_execution_completed_timestamp(timestamp(119));
+          metadata.set_output_upload_start_timestamp(timestamp(119));
+          metadata.set_output_upload_completed_timestamp(timestamp(120));
+          metadata
+        });
+        result
+      });
+      response
+    }));
+
+    let result = extract_execute_response(operation).unwrap();
+    let secs = |secs| Some(Duration::from_secs(secs));
+    assert_eq!(
+      result.execution_attempts,
+      vec![ExecutionStats {
+        remote_queue: secs(10),
+        remote_worker_setup: secs(4),
+        remote_input_fetch: secs(2),
+        remote_execution: secs(5),
+        remote_output_store: secs(1),
+        ..ExecutionStats::default()
+      }]
+    );
+  }
+
   #[test]
   fn extract_execute_response_success() {
     let want_result = FallibleExecuteProcessResult {
@@ -2058,6 +3320,9 @@ mod tests {
       exit_code: 17,
       output_directory: TestDirectory::nested().digest(),
       execution_attempts: vec![],
+      failure_reason: None,
+      resource_usage: None,
+      trace: None,
     };
 
     let mut output_file = bazel_protos::remote_execution::OutputFile::new();
@@ -2231,97 +3496,330 @@ mod tests {
   }
 
   #[test]
-  fn wait_between_request_1_retry() {
-    // wait at least 500 milli for one retry
-    {
-      let execute_request = echo_foo_request();
-      let mock_server = {
-        let op_name = "gimme-foo".to_string();
-        mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
-          op_name.clone(),
-          super::make_execute_request(&execute_request, &None, &None, BTreeMap::new())
-            .unwrap()
-            .2,
-          vec![
-            make_incomplete_operation(&op_name),
-            make_successful_operation(
-              &op_name,
-              StdoutType::Raw("foo".to_owned()),
-              StderrType::Raw("".to_owned()),
-              0,
-            ),
-          ],
-        ))
-      };
-      run_command_remote(mock_server.address(), execute_request).unwrap();
+  fn wait_execution_after_broken_stream() {
+    // wait at least 250 milli (the first backoff of 500 milli, less jitter) before reconnecting.
+    let execute_request = echo_foo_request();
+    let mock_server = {
+      let op_name = "gimme-foo".to_string();
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        super::make_execute_request(&execute_request, &None, &None, BTreeMap::new())
+          .unwrap()
+          .2,
+        vec![
+          make_incomplete_operation(&op_name),
+          make_canceled_operation(None),
+          make_successful_operation(
+            &op_name,
+            StdoutType::Raw("foo".to_owned()),
+            StderrType::Raw("".to_owned()),
+            0,
+          ),
+        ],
+      ))
+    };
+    run_command_remote(mock_server.address(), execute_request).unwrap();
+
+    let messages = mock_server.mock_responder.received_messages.lock();
+    assert_eq!(
+      messages
+        .iter()
+        .map(|message| message.message_type.as_str())
+        .collect::<Vec<_>>(),
+      vec!["ExecuteRequest", "WaitExecutionRequest"]
+    );
+    assert!(messages[1].received_at.sub(messages[0].received_at) >= Duration::from_millis(250));
+  }
 
-      let messages = mock_server.mock_responder.received_messages.lock();
-      assert!(messages.len() == 2);
-      assert!(
-        messages
-          .get(1)
+  #[test]
+  fn wait_execution_backs_off() {
+    // Backoffs double from 500 milli, but jitter may halve them: wait at least 250 + 500 + 1000
+    // milli for 3 reconnections.
+    let execute_request = echo_foo_request();
+    let mock_server = {
+      let op_name = "gimme-foo".to_string();
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        super::make_execute_request(&execute_request, &None, &None, BTreeMap::new())
           .unwrap()
-          .received_at
-          .sub(messages.get(0).unwrap().received_at)
-          >= Duration::from_millis(500)
-      );
-    }
+          .2,
+        vec![
+          make_incomplete_operation(&op_name),
+          make_canceled_operation(None),
+          make_canceled_operation(None),
+          make_incomplete_operation(&op_name),
+          make_canceled_operation(None),
+          make_successful_operation(
+            &op_name,
+            StdoutType::Raw("foo".to_owned()),
+            StderrType::Raw("".to_owned()),
+            0,
+          ),
+        ],
+      ))
+    };
+    let result = run_command_remote(mock_server.address(), execute_request).unwrap();
+    assert_eq!(result.execution_attempts[0].retries, 3);
+
+    let messages = mock_server.mock_responder.received_messages.lock();
+    assert_eq!(
+      messages
+        .iter()
+        .map(|message| message.message_type.as_str())
+        .collect::<Vec<_>>(),
+      vec![
+        "ExecuteRequest",
+        "WaitExecutionRequest",
+        "WaitExecutionRequest",
+        "WaitExecutionRequest"
+      ]
+    );
+    assert!(messages[1].received_at.sub(messages[0].received_at) >= Duration::from_millis(250));
+    assert!(messages[2].received_at.sub(messages[1].received_at) >= Duration::from_millis(500));
+    assert!(messages[3].received_at.sub(messages[2].received_at) >= Duration::from_millis(1000));
   }
 
   #[test]
-  fn wait_between_request_3_retry() {
-    // wait at least 500 + 1000 + 1500 = 3000 milli for 3 retries.
-    {
-      let execute_request = echo_foo_request();
-      let mock_server = {
-        let op_name = "gimme-foo".to_string();
-        mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
-          op_name.clone(),
-          super::make_execute_request(&execute_request, &None, &None, BTreeMap::new())
-            .unwrap()
-            .2,
-          vec![
-            make_incomplete_operation(&op_name),
-            make_incomplete_operation(&op_name),
-            make_incomplete_operation(&op_name),
-            make_successful_operation(
-              &op_name,
-              StdoutType::Raw("foo".to_owned()),
-              StderrType::Raw("".to_owned()),
-              0,
-            ),
-          ],
-        ))
-      };
-      run_command_remote(mock_server.address(), execute_request).unwrap();
+  fn wait_execution_gives_up_after_retries() {
+    let execute_request = echo_foo_request();
+    let mock_server = {
+      let op_name = "gimme-foo".to_string();
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        super::make_execute_request(&execute_request, &None, &None, BTreeMap::new())
+          .unwrap()
+          .2,
+        vec![
+          make_incomplete_operation(&op_name),
+          make_canceled_operation(None),
+          make_canceled_operation(None),
+          make_successful_operation(
+            &op_name,
+            StdoutType::Raw("foo".to_owned()),
+            StderrType::Raw("".to_owned()),
+            0,
+          ),
+        ],
+      ))
+    };
+    let cas = mock::StubCAS::builder()
+      .file(&TestData::roland())
+      .directory(&TestDirectory::containing_roland())
+      .build();
+    let command_runner = create_command_runner_with_retries(mock_server.address(), &cas, 1);
+    let error = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(command_runner.run(execute_request))
+      .expect_err("Want Err after exhausting retries");
+    assert_contains(&error, "Failed after 1 retries");
+
+    let messages = mock_server.mock_responder.received_messages.lock();
+    assert_eq!(
+      messages
+        .iter()
+        .map(|message| message.message_type.as_str())
+        .collect::<Vec<_>>(),
+      vec!["ExecuteRequest", "WaitExecutionRequest"]
+    );
+  }
 
-      let messages = mock_server.mock_responder.received_messages.lock();
-      assert!(messages.len() == 4);
-      assert!(
-        messages
-          .get(1)
+  #[test]
+  fn execute_again_if_stream_breaks_before_operation_is_named() {
+    let execute_request = echo_foo_request();
+    let mock_server = {
+      let op_name = "gimme-foo".to_string();
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        super::make_execute_request(&execute_request, &None, &None, BTreeMap::new())
           .unwrap()
-          .received_at
-          .sub(messages.get(0).unwrap().received_at)
-          >= Duration::from_millis(500)
-      );
-      assert!(
-        messages
-          .get(2)
+          .2,
+        vec![
+          make_canceled_operation(None),
+          make_successful_operation(
+            &op_name,
+            StdoutType::Raw("foo".to_owned()),
+            StderrType::Raw("".to_owned()),
+            0,
+          ),
+        ],
+      ))
+    };
+    let result = run_command_remote(mock_server.address(), execute_request).unwrap();
+    assert_eq!(result.stdout, as_bytes("foo"));
+
+    let messages = mock_server.mock_responder.received_messages.lock();
+    assert_eq!(
+      messages
+        .iter()
+        .map(|message| message.message_type.as_str())
+        .collect::<Vec<_>>(),
+      vec!["ExecuteRequest", "ExecuteRequest"]
+    );
+  }
+
+  #[test]
+  fn concurrent_executions_are_limited() {
+    let execute_request = echo_foo_request();
+    let op_name = "gimme-foo".to_string();
+    let delay = Duration::from_millis(500);
+    let successful_operation = || {
+      make_successful_operation(
+        &op_name,
+        StdoutType::Raw("foo".to_owned()),
+        StderrType::Raw("".to_owned()),
+        0,
+      )
+    };
+    let mock_server =
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        super::make_execute_request(&execute_request, &None, &None, BTreeMap::new())
           .unwrap()
-          .received_at
-          .sub(messages.get(1).unwrap().received_at)
-          >= Duration::from_millis(1000)
-      );
-      assert!(
-        messages
-          .get(3)
+          .2,
+        vec![
+          MockOperation {
+            duration: Some(delay),
+            ..successful_operation()
+          },
+          successful_operation(),
+        ],
+      ));
+    let cas = mock::StubCAS::empty();
+    let command_runner =
+      create_command_runner(mock_server.address(), &cas).with_execution_concurrency_limit(1);
+
+    let (first, second) = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(
+        command_runner
+          .run(execute_request.clone())
+          .join(command_runner.run(execute_request)),
+      )
+      .unwrap();
+    assert_eq!(first.stdout, as_bytes("foo"));
+    assert_eq!(second.stdout, as_bytes("foo"));
+
+    // The second execution can only start once the first finishes.
+    let messages = mock_server.mock_responder.received_messages.lock();
+    assert_eq!(messages.len(), 2);
+    assert!(messages[1].received_at - messages[0].received_at >= delay);
+  }
+
+  #[test]
+  fn verified_results_must_only_have_declared_outputs() {
+    let execute_request = echo_foo_request();
+    let op_name = "gimme-foo".to_string();
+    let mut operation = bazel_protos::operations::Operation::new();
+    operation.set_name(op_name.clone());
+    operation.set_done(true);
+    operation.set_response(make_any_proto(&{
+      let mut response = bazel_protos::remote_execution::ExecuteResponse::new();
+      response.set_result({
+        let mut result = bazel_protos::remote_execution::ActionResult::new();
+        result.set_stdout_raw(Bytes::from("foo"));
+        result.mut_output_files().push({
+          let mut output_file = bazel_protos::remote_execution::OutputFile::new();
+          output_file.set_path("roland".into());
+          output_file.set_digest((&TestData::roland().digest()).into());
+          output_file
+        });
+        result
+      });
+      response
+    }));
+    let mock_server =
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        op_name,
+        super::make_execute_request(&execute_request, &None, &None, BTreeMap::new())
           .unwrap()
-          .received_at
-          .sub(messages.get(2).unwrap().received_at)
-          >= Duration::from_millis(1500)
-      );
-    }
+          .2,
+        vec![
+          MockOperation::new(operation.clone()),
+          MockOperation::new(operation),
+        ],
+      ));
+    let cas = mock::StubCAS::builder()
+      .file(&TestData::roland())
+      .directory(&TestDirectory::containing_roland())
+      .build();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let command_runner = create_command_runner(mock_server.address(), &cas);
+    let result = runtime
+      .block_on(command_runner.run(execute_request.clone()))
+      .unwrap();
+    assert_eq!(
+      result.output_directory,
+      TestDirectory::containing_roland().digest()
+    );
+
+    let command_runner = command_runner.with_result_verification(true);
+    let error = runtime
+      .block_on(command_runner.run(execute_request))
+      .expect_err("Want Err for undeclared output");
+    assert_contains(&error, "Undeclared output file \"roland\"");
+  }
+
+  #[test]
+  fn servers_supporting_output_paths_decide_the_types_of_outputs() {
+    // Declared as a directory, but returned as a file: which is up to servers which are given
+    // output paths.
+    let mut execute_request = echo_foo_request();
+    execute_request.output_directories = vec![PathBuf::from("roland")].into_iter().collect();
+
+    let op_name = "gimme-foo".to_string();
+    let mut operation = bazel_protos::operations::Operation::new();
+    operation.set_name(op_name.clone());
+    operation.set_done(true);
+    operation.set_response(make_any_proto(&{
+      let mut response = bazel_protos::remote_execution::ExecuteResponse::new();
+      response.set_result({
+        let mut result = bazel_protos::remote_execution::ActionResult::new();
+        result.set_stdout_raw(Bytes::from("foo"));
+        result.mut_output_files().push({
+          let mut output_file = bazel_protos::remote_execution::OutputFile::new();
+          output_file.set_path("roland".into());
+          output_file.set_digest((&TestData::roland().digest()).into());
+          output_file
+        });
+        result
+      });
+      response
+    }));
+    let mut capabilities = bazel_protos::remote_execution::ServerCapabilities::new();
+    capabilities.mut_high_api_version().set_major(2);
+    capabilities.mut_high_api_version().set_minor(1);
+    let mock_server = mock::execution_server::TestServer::new(
+      mock::execution_server::MockExecution::new(
+        op_name,
+        super::make_execute_request_with_output_declaration(
+          &execute_request,
+          &None,
+          &None,
+          BTreeMap::new(),
+          OutputDeclaration::Paths,
+        )
+        .unwrap()
+        .2,
+        vec![MockOperation::new(operation)],
+      )
+      .with_capabilities(capabilities),
+    );
+    let cas = mock::StubCAS::builder()
+      .file(&TestData::roland())
+      .directory(&TestDirectory::containing_roland())
+      .build();
+
+    let command_runner =
+      create_command_runner(mock_server.address(), &cas).with_result_verification(true);
+    let result = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(command_runner.run(execute_request))
+      .unwrap();
+    assert_eq!(
+      result.output_directory,
+      TestDirectory::containing_roland().digest()
+    );
   }
 
   #[test]
@@ -2409,7 +3907,8 @@ mod tests {
   fn extract_output_files_from_response_just_directory() {
     let mut output_directory = bazel_protos::remote_execution::OutputDirectory::new();
     output_directory.set_path("cats".into());
-    output_directory.set_tree_digest((&TestDirectory::containing_roland().digest()).into());
+    output_directory
+      .set_tree_digest((&TestTree::new(&TestDirectory::containing_roland(), &[]).digest()).into());
     let mut output_directories = protobuf::RepeatedField::new();
     output_directories.push(output_directory);
 
@@ -2437,13 +3936,16 @@ mod tests {
     output_directories.push({
       let mut output_directory = bazel_protos::remote_execution::OutputDirectory::new();
       output_directory.set_path("pets/cats".into());
-      output_directory.set_tree_digest((&TestDirectory::containing_roland().digest()).into());
+      output_directory.set_tree_digest(
+        (&TestTree::new(&TestDirectory::containing_roland(), &[]).digest()).into(),
+      );
       output_directory
     });
     output_directories.push({
       let mut output_directory = bazel_protos::remote_execution::OutputDirectory::new();
       output_directory.set_path("pets/dogs".into());
-      output_directory.set_tree_digest((&TestDirectory::containing_robin().digest()).into());
+      output_directory
+        .set_tree_digest((&TestTree::new(&TestDirectory::containing_robin(), &[]).digest()).into());
       output_directory
     });
 
@@ -2486,7 +3988,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(5000),
       description: "echo a foo".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     }
   }
 
@@ -2601,7 +4112,31 @@ mod tests {
     runtime.block_on(command_runner.run(request))
   }
 
+  #[test]
+  fn platform_of_workers() {
+    let mut platform_properties = BTreeMap::new();
+    assert_eq!(
+      super::platform_of_workers(&platform_properties),
+      Ok(Platform::Linux)
+    );
+    platform_properties.insert("OSFamily".to_owned(), "Darwin".to_owned());
+    assert_eq!(
+      super::platform_of_workers(&platform_properties),
+      Ok(Platform::Darwin)
+    );
+    platform_properties.insert("OSFamily".to_owned(), "Windows".to_owned());
+    super::platform_of_workers(&platform_properties).expect_err("Want Err for Windows");
+  }
+
   fn create_command_runner(address: String, cas: &mock::StubCAS) -> CommandRunner {
+    create_command_runner_with_retries(address, cas, 10)
+  }
+
+  fn create_command_runner_with_retries(
+    address: String,
+    cas: &mock::StubCAS,
+    rpc_retries: usize,
+  ) -> CommandRunner {
     let store_dir = TempDir::new().unwrap();
     let timer_thread = timer_thread();
     let store = fs::Store::with_remote(
@@ -2609,11 +4144,19 @@ mod tests {
       &[cas.address()],
       None,
       &None,
+      &None,
+      &None,
+      fs::ChannelPolicy::default(),
       None,
+      None,
+      fs::Compression::None,
       1,
       10 * 1024 * 1024,
       Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
       fs::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      fs::LoadBalancing::RoundRobin,
       1,
       timer_thread.with(|t| t.handle()),
     )
@@ -2625,8 +4168,14 @@ mod tests {
       None,
       None,
       None,
+      None,
+      fs::ChannelPolicy::default(),
+      None,
+      None,
       BTreeMap::new(),
       1,
+      rpc_retries,
+      Duration::from_secs(60),
       store,
       timer_thread,
     )
@@ -2659,11 +4208,20 @@ mod tests {
     let cas = mock::StubCAS::builder()
       .file(&TestData::roland())
       .directory(&TestDirectory::containing_roland())
+      .tree(&TestTree::new(&TestDirectory::containing_roland(), &[]))
+      .tree(&TestTree::new(&TestDirectory::containing_robin(), &[]))
+      .tree(&TestTree::new(
+        &TestDirectory::recursive(),
+        &[TestDirectory::containing_roland()],
+      ))
       .build();
     let command_runner = create_command_runner("".to_owned(), &cas);
 
     let mut runtime = tokio::runtime::Runtime::new().unwrap();
-    runtime.block_on(command_runner.extract_output_files(&execute_response))
+    runtime.block_on(super::extract_output_files(
+      &command_runner.store,
+      execute_response.get_result(),
+    ))
   }
 
   fn make_any_proto(message: &dyn Message) -> protobuf::well_known_types::Any {
@@ -2705,7 +4263,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "cat a roland".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     }
   }
 
@@ -2718,7 +4285,16 @@ mod tests {
       output_directories: BTreeSet::new(),
       timeout: Duration::from_millis(1000),
       description: "unleash a roaring meow".to_string(),
-      jdk_home: None,
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
     }
   }
 }
@@ -0,0 +1,492 @@
+// Sharing the results of processes which ran locally with remote execution (and with other
+// machines), by recording them in the remote ActionCache, and reusing the results recorded there
+// rather than running processes: a remote cache without remote execution.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use boxfuture::{try_future, BoxFuture, Boxable};
+use futures::future::{self, Future, Loop};
+use hashing::Digest;
+use log::{debug, warn};
+
+use super::instance_routing::{self, InstanceRoute};
+use super::{remote, ExecuteProcessRequest, FallibleExecuteProcessResult, Platform};
+
+///
+/// Runs processes with an underlying CommandRunner (which runs them locally), keyed in the remote
+/// ActionCache by the Actions that a remote::CommandRunner with the same configuration would look
+/// them up by (for servers which support v2.0 of the API: servers which support v2.1 are given the
+/// outputs of processes in a different form, so the Actions of remote executions against them are
+/// different). If `read` is set, the ActionCache is checked before running hermetic processes, and
+/// on a hit their outputs are downloaded rather than running them. If `write` is set, the results
+/// of hermetic processes which succeed are recorded in the ActionCache.
+///
+/// Processes are treated as hermetic unless they use tools or named caches from this machine,
+/// pass through its environment variables, or reuse workdirs. The ActionCache is not used at all if
+/// this machine's platform is not that of the remote workers.
+///
+pub struct CommandRunner {
+  underlying: Arc<dyn super::CommandRunner>,
+  store: fs::Store,
+  instance_name: Option<String>,
+  instance_routes: Vec<InstanceRoute>,
+  cache_key_gen_version: Option<String>,
+  platform_properties: BTreeMap<String, String>,
+  read: bool,
+  write: bool,
+}
+
+impl CommandRunner {
+  pub fn new(
+    underlying: Box<dyn super::CommandRunner>,
+    store: fs::Store,
+    instance_name: Option<String>,
+    cache_key_gen_version: Option<String>,
+    platform_properties: BTreeMap<String, String>,
+    read: bool,
+    write: bool,
+  ) -> CommandRunner {
+    CommandRunner {
+      underlying: Arc::from(underlying),
+      store,
+      instance_name,
+      instance_routes: vec![],
+      cache_key_gen_version,
+      platform_properties,
+      read,
+      write,
+    }
+  }
+
+  ///
+  /// Looks up and records the results of each process which matches one of the given routes in
+  /// the ActionCache of its instance, as a remote::CommandRunner with the same routes would.
+  ///
+  pub fn with_instance_routes(mut self, instance_routes: Vec<InstanceRoute>) -> CommandRunner {
+    self.instance_routes = instance_routes;
+    self
+  }
+
+  ///
+  /// The store with which to look up and record the result of the given request: that of the
+  /// instance to which it is routed, if it is routed to one.
+  ///
+  fn store_for(&self, req: &ExecuteProcessRequest) -> fs::Store {
+    let mut platform_properties = self.platform_properties.clone();
+    platform_properties.extend(req.platform_properties.clone());
+    match instance_routing::route(&self.instance_routes, &platform_properties) {
+      Some(instance_name) => self
+        .store
+        .with_instance_name(Some(instance_name.to_owned())),
+      None => self.store.clone(),
+    }
+  }
+
+  ///
+  /// The digest of the Action under which the result of the given request should be looked up and
+  /// recorded, or None if it shouldn't be.
+  ///
+  fn action_digest(&self, req: &ExecuteProcessRequest) -> Result<Option<Digest>, String> {
+    let hermetic = req.tool_mounts.is_empty()
+      && req.named_caches.is_empty()
+      && req.env_passthrough.is_empty()
+      && req.reusable_workdir.is_none();
+    let mut platform_properties = self.platform_properties.clone();
+    platform_properties.extend(req.platform_properties.clone());
+    if !hermetic
+      || self.underlying.platform()? != remote::platform_of_workers(&platform_properties)?
+    {
+      return Ok(None);
+    }
+    let (action, _, _) = remote::make_execute_request(
+      req,
+      &self.instance_name,
+      &self.cache_key_gen_version,
+      self.platform_properties.clone(),
+    )?;
+    remote::digest(&action).map(Some)
+  }
+}
+
+impl super::CommandRunner for CommandRunner {
+  fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let action_digest = match self.action_digest(&req) {
+      Ok(Some(action_digest)) => action_digest,
+      Ok(None) => return self.underlying.run(req),
+      Err(err) => {
+        warn!(
+          "Not using the remote action cache for {}: {}",
+          req.description, err
+        );
+        return self.underlying.run(req);
+      }
+    };
+    let store = self.store_for(&req);
+    let lookup = if self.read {
+      remote::lookup_action_result(&store, action_digest)
+    } else {
+      future::ok(None).to_boxed()
+    };
+    let underlying = self.underlying.clone();
+    let write = self.write;
+    lookup
+      .and_then(move |cached| {
+        if let Some(result) = cached {
+          debug!("Got a remote action cache hit for {}", req.description);
+          return future::ok(result).to_boxed();
+        }
+        let description = req.description.clone();
+        let output_files = req.output_files.clone();
+        let output_directories = req.output_directories.clone();
+        underlying
+          .run(req)
+          .and_then(move |result| {
+            if !write || result.exit_code != 0 {
+              return future::ok(result).to_boxed();
+            }
+            record_action_result(
+              &store,
+              action_digest,
+              &result,
+              output_files,
+              output_directories,
+            )
+            .then(move |res| {
+              match res {
+                Ok(()) => debug!(
+                  "Recorded the result of {} in the remote action cache",
+                  description
+                ),
+                Err(err) => warn!(
+                  "Failed to record the result of {} in the remote action cache: {}",
+                  description, err
+                ),
+              }
+              Ok(result) as Result<_, String>
+            })
+            .to_boxed()
+          })
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  fn platform(&self) -> Result<Platform, String> {
+    self.underlying.platform()
+  }
+}
+
+///
+/// Uploads the given result (and its outputs) to the remote, and records it as the result of the
+/// Action with the given digest.
+///
+fn record_action_result(
+  store: &fs::Store,
+  action_digest: Digest,
+  result: &FallibleExecuteProcessResult,
+  output_files: BTreeSet<PathBuf>,
+  output_directories: BTreeSet<PathBuf>,
+) -> BoxFuture<(), String> {
+  let exit_code = result.exit_code;
+  let output_directory = result.output_directory;
+  let store2 = store.clone();
+  let store3 = store.clone();
+
+  let output_file_nodes = output_files
+    .into_iter()
+    .map(|path| {
+      load_parent_directory(store, output_directory, &path).map(move |parent| {
+        parent.and_then(|parent| {
+          let name = path.file_name()?.to_string_lossy().into_owned();
+          let node = parent
+            .get_files()
+            .iter()
+            .find(|node| node.get_name() == name)?;
+          let mut output_file = bazel_protos::remote_execution::OutputFile::new();
+          output_file.set_path(path.to_string_lossy().into_owned());
+          output_file.set_digest(node.get_digest().clone());
+          output_file.set_is_executable(node.get_is_executable());
+          Some(output_file)
+        })
+      })
+    })
+    .collect::<Vec<_>>();
+  let output_directory_nodes = output_directories
+    .into_iter()
+    .map(|path| {
+      let store = store.clone();
+      load_parent_directory(&store, output_directory, &path).and_then(move |parent| {
+        let digest: Option<Result<Digest, String>> = parent.and_then(|parent| {
+          let name = path.file_name()?.to_string_lossy().into_owned();
+          let node = parent
+            .get_directories()
+            .iter()
+            .find(|node| node.get_name() == name)?;
+          Some(node.get_digest().into())
+        });
+        match digest {
+          // As remote::CommandRunner expects, output directories refer to Trees, which are
+          // uploaded alongside the Directories that they contain.
+          Some(digest) => store
+            .record_tree(try_future!(digest))
+            .map(move |tree_digest| {
+              let mut output_directory = bazel_protos::remote_execution::OutputDirectory::new();
+              output_directory.set_path(path.to_string_lossy().into_owned());
+              output_directory.set_tree_digest((&tree_digest).into());
+              Some((tree_digest, output_directory))
+            })
+            .to_boxed(),
+          None => future::ok(None).to_boxed(),
+        }
+      })
+    })
+    .collect::<Vec<_>>();
+
+  store
+    .store_file_bytes(result.stdout.clone(), true)
+    .join(store.store_file_bytes(result.stderr.clone(), true))
+    .join(future::join_all(output_file_nodes).join(future::join_all(output_directory_nodes)))
+    .and_then(
+      move |((stdout_digest, stderr_digest), (output_files, output_directories))| {
+        let output_directories = output_directories.into_iter().flatten().collect::<Vec<_>>();
+        let mut digests = vec![stdout_digest, stderr_digest, output_directory];
+        digests.extend(
+          output_directories
+            .iter()
+            .map(|(tree_digest, _)| *tree_digest),
+        );
+        store2.ensure_remote_has_recursive(digests).map(move |_| {
+          let mut action_result = bazel_protos::remote_execution::ActionResult::new();
+          action_result.set_exit_code(exit_code);
+          action_result.set_stdout_digest((&stdout_digest).into());
+          action_result.set_stderr_digest((&stderr_digest).into());
+          action_result.set_output_files(output_files.into_iter().flatten().collect());
+          action_result.set_output_directories(
+            output_directories
+              .into_iter()
+              .map(|(_, output_directory)| output_directory)
+              .collect(),
+          );
+          action_result
+        })
+      },
+    )
+    .and_then(move |action_result| store3.record_remote_action_result(action_digest, action_result))
+    .to_boxed()
+}
+
+///
+/// Loads the Directory containing the given (relative) path within the Directory with the given
+/// digest, if it exists.
+///
+fn load_parent_directory(
+  store: &fs::Store,
+  root: Digest,
+  path: &Path,
+) -> BoxFuture<Option<bazel_protos::remote_execution::Directory>, String> {
+  let mut names = try_future!(path
+    .parent()
+    .map(|parent| {
+      parent
+        .components()
+        .map(|component| match component {
+          Component::Normal(name) => Ok(name.to_string_lossy().into_owned()),
+          _ => Err(format!("Output path {:?} is not a relative path", path)),
+        })
+        .collect::<Result<Vec<_>, _>>()
+    })
+    .unwrap_or_else(|| Ok(vec![])));
+  // Popped from the outermost directory inwards.
+  names.reverse();
+  let store = store.clone();
+  future::loop_fn((root, names), move |(digest, mut names)| {
+    store.load_directory(digest).and_then(move |directory| {
+      let directory = match directory {
+        Some(directory) => directory,
+        None => return Ok(Loop::Break(None)),
+      };
+      let name = match names.pop() {
+        Some(name) => name,
+        None => return Ok(Loop::Break(Some(directory))),
+      };
+      match directory
+        .get_directories()
+        .iter()
+        .find(|node| node.get_name() == name)
+      {
+        Some(node) => {
+          let digest: Result<Digest, String> = node.get_digest().into();
+          Ok(Loop::Continue((digest?, names)))
+        }
+        None => Ok(Loop::Break(None)),
+      }
+    })
+  })
+  .to_boxed()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::CommandRunner;
+  use crate::{
+    local, remote, CommandRunner as CommandRunnerTrait, ExecuteProcessRequest,
+    FallibleExecuteProcessResult,
+  };
+  use bytes::Bytes;
+  use hashing::Digest;
+  use std::collections::{BTreeMap, BTreeSet};
+  use std::time::Duration;
+  use tempfile::TempDir;
+  use testutil::data::TestData;
+  use testutil::{as_bytes, owned_string_vec};
+
+  fn echo_foo_request() -> ExecuteProcessRequest {
+    ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/echo", "-n", "foo"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(5000),
+      description: "echo a foo".to_string(),
+      tool_mounts: BTreeMap::new(),
+      named_caches: BTreeMap::new(),
+      resource_limits: crate::ResourceLimits::default(),
+      reusable_workdir: None,
+      env_passthrough: BTreeSet::new(),
+      concurrency_weight: 1,
+      tee_output: false,
+      platform_properties: BTreeMap::new(),
+      priority: 0,
+      execution_headers: BTreeMap::new(),
+    }
+  }
+
+  fn action_digest(req: &ExecuteProcessRequest) -> Digest {
+    remote::digest(
+      &remote::make_execute_request(req, &None, &None, BTreeMap::new())
+        .unwrap()
+        .0,
+    )
+    .unwrap()
+  }
+
+  fn run(
+    cas: &mock::StubCAS,
+    req: ExecuteProcessRequest,
+    read: bool,
+    write: bool,
+  ) -> FallibleExecuteProcessResult {
+    let store_dir = TempDir::new().unwrap();
+    let work_dir = TempDir::new().unwrap();
+    let timer_thread = futures_timer::HelperThread::new().unwrap();
+    let store = fs::Store::with_remote(
+      store_dir.path(),
+      &[cas.address()],
+      None,
+      &None,
+      &None,
+      &None,
+      fs::ChannelPolicy::default(),
+      None,
+      None,
+      fs::Compression::None,
+      1,
+      10 * 1024 * 1024,
+      Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
+      fs::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      fs::LoadBalancing::RoundRobin,
+      1,
+      timer_thread.handle(),
+    )
+    .unwrap();
+    let runner = CommandRunner::new(
+      Box::new(local::CommandRunner::new(
+        store.clone(),
+        work_dir.path().to_owned(),
+        true,
+      )),
+      store,
+      None,
+      None,
+      BTreeMap::new(),
+      read,
+      write,
+    );
+    tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(runner.run(req))
+      .unwrap()
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn records_hermetic_result() {
+    let cas = mock::StubCAS::empty();
+    let req = echo_foo_request();
+    let action_digest = action_digest(&req);
+    run(&cas, req, false, true);
+
+    let action_result = cas
+      .action_results
+      .lock()
+      .get(&action_digest.0)
+      .cloned()
+      .expect("Want recorded action result");
+    assert_eq!(action_result.get_exit_code(), 0);
+    let stdout = TestData::new("foo");
+    assert_eq!(
+      action_result.get_stdout_digest().get_hash(),
+      stdout.fingerprint().to_hex()
+    );
+    assert_eq!(
+      cas.blobs.lock().get(&stdout.fingerprint()),
+      Some(&stdout.bytes())
+    );
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn does_not_record_non_hermetic_result() {
+    let cas = mock::StubCAS::empty();
+    let mut req = echo_foo_request();
+    req.env_passthrough.insert("HOME".to_owned());
+    run(&cas, req, false, true);
+
+    assert!(cas.action_results.lock().is_empty());
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn uses_cached_result_rather_than_running() {
+    let req = echo_foo_request();
+    let mut action_result = bazel_protos::remote_execution::ActionResult::new();
+    action_result.set_stdout_raw(Bytes::from("cached foo"));
+    action_result.set_exit_code(0);
+    let cas = mock::StubCAS::builder()
+      .action_result(action_digest(&req), action_result)
+      .build();
+
+    let result = run(&cas, req.clone(), true, true);
+    assert_eq!(result.stdout, as_bytes("cached foo"));
+    assert!(result.execution_attempts[0].was_cache_hit);
+
+    // Without reading from the cache, the process runs.
+    let result = run(&cas, req, false, true);
+    assert_eq!(result.stdout, as_bytes("foo"));
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn runs_and_does_not_record_cache_misses_if_read_only() {
+    let cas = mock::StubCAS::empty();
+    let result = run(&cas, echo_foo_request(), true, false);
+
+    assert_eq!(result.stdout, as_bytes("foo"));
+    assert!(cas.action_results.lock().is_empty());
+  }
+}
@@ -0,0 +1,195 @@
+// Enforcement of ResourceLimits for local processes: via setrlimit everywhere, and via cgroups (v2)
+// for memory on Linux, when they are available and delegated to us.
+
+use std::io;
+
+use super::ResourceLimits;
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type RlimitResource = libc::c_int;
+
+fn setrlimit(resource: RlimitResource, soft: u64, hard: u64) -> io::Result<()> {
+  let limit = libc::rlimit {
+    rlim_cur: soft as libc::rlim_t,
+    rlim_max: hard as libc::rlim_t,
+  };
+  if unsafe { libc::setrlimit(resource, &limit) } == 0 {
+    Ok(())
+  } else {
+    Err(io::Error::last_os_error())
+  }
+}
+
+///
+/// Applies the given limits to the current process via setrlimit. Only to be called in a child
+/// process between fork and exec, so must not allocate.
+///
+pub fn set_rlimits(limits: &ResourceLimits) -> io::Result<()> {
+  if let Some(memory_bytes) = limits.memory_bytes {
+    setrlimit(libc::RLIMIT_AS, memory_bytes, memory_bytes)?;
+  }
+  if let Some(open_files) = limits.open_files {
+    setrlimit(libc::RLIMIT_NOFILE, open_files, open_files)?;
+  }
+  if let Some(cpu_seconds) = limits.cpu_seconds {
+    // Exceeding the soft limit sends SIGXCPU, which is how we detect that the limit was exceeded.
+    // The hard limit (which sends SIGKILL) is a backstop for processes which handle SIGXCPU.
+    setrlimit(libc::RLIMIT_CPU, cpu_seconds, cpu_seconds + 1)?;
+  }
+  Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+  use std::fs::{self, File, OpenOptions};
+  use std::io;
+  use std::os::unix::io::AsRawFd;
+  use std::path::{Path, PathBuf};
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  use log::debug;
+
+  const CGROUP2_ROOT: &str = "/sys/fs/cgroup";
+
+  static NEXT_CGROUP_ID: AtomicUsize = AtomicUsize::new(0);
+
+  ///
+  /// A cgroup (v2) limiting the memory of the processes in it, which is removed when dropped.
+  ///
+  pub struct Cgroup {
+    path: PathBuf,
+    // Opened before forking, so that the child can enter the cgroup without allocating.
+    procs: File,
+  }
+
+  impl Cgroup {
+    ///
+    /// Creates a cgroup as a child of the cgroup of this process, if cgroups v2 are available and
+    /// delegated to us.
+    ///
+    pub fn create(memory_bytes: u64) -> Option<Cgroup> {
+      let root = Path::new(CGROUP2_ROOT);
+      if !root.join("cgroup.controllers").exists() {
+        return None;
+      }
+      let own_cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+      let own_cgroup = own_cgroups
+        .lines()
+        .find(|line| line.starts_with("0::"))?
+        .trim_start_matches("0::")
+        .trim_start_matches('/');
+      let path = root.join(own_cgroup).join(format!(
+        "pants-process-{}-{}",
+        std::process::id(),
+        NEXT_CGROUP_ID.fetch_add(1, Ordering::SeqCst)
+      ));
+
+      let create = || -> io::Result<File> {
+        fs::create_dir(&path)?;
+        fs::write(path.join("memory.max"), memory_bytes.to_string())?;
+        OpenOptions::new()
+          .write(true)
+          .open(path.join("cgroup.procs"))
+      };
+      match create() {
+        Ok(procs) => Some(Cgroup { path, procs }),
+        Err(err) => {
+          debug!("Not using a cgroup to limit memory at {:?}: {}", path, err);
+          let _ = fs::remove_dir(&path);
+          None
+        }
+      }
+    }
+
+    ///
+    /// Moves the current process into the cgroup. Only to be called in a child process between
+    /// fork and exec, so must not allocate.
+    ///
+    pub fn enter(&self) -> io::Result<()> {
+      // Writing 0 to cgroup.procs moves the writing process.
+      let pid = b"0";
+      let written = unsafe {
+        libc::write(
+          self.procs.as_raw_fd(),
+          pid.as_ptr() as *const libc::c_void,
+          pid.len(),
+        )
+      };
+      if written == pid.len() as isize {
+        Ok(())
+      } else {
+        Err(io::Error::last_os_error())
+      }
+    }
+
+    ///
+    /// Whether any process in the cgroup has been killed for exceeding the memory limit.
+    ///
+    pub fn oom_killed(&self) -> bool {
+      fs::read_to_string(self.path.join("memory.events"))
+        .ok()
+        .and_then(|events| {
+          events
+            .lines()
+            .find(|line| line.starts_with("oom_kill "))
+            .and_then(|line| line["oom_kill ".len()..].trim().parse::<u64>().ok())
+        })
+        .map(|count| count > 0)
+        .unwrap_or(false)
+    }
+  }
+
+  impl Drop for Cgroup {
+    fn drop(&mut self) {
+      // This will fail if any processes in the cgroup are still alive, which is harmless.
+      let _ = fs::remove_dir(&self.path);
+    }
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+  use std::io;
+
+  pub struct Cgroup;
+
+  impl Cgroup {
+    pub fn create(_memory_bytes: u64) -> Option<Cgroup> {
+      None
+    }
+
+    pub fn enter(&self) -> io::Result<()> {
+      Ok(())
+    }
+
+    pub fn oom_killed(&self) -> bool {
+      false
+    }
+  }
+}
+
+pub use self::imp::Cgroup;
+
+#[cfg(test)]
+mod tests {
+  use super::set_rlimits;
+  use crate::ResourceLimits;
+  use std::process::Command;
+
+  #[test]
+  fn open_files_limit() {
+    let limits = ResourceLimits {
+      open_files: Some(32),
+      ..ResourceLimits::default()
+    };
+    let mut command = Command::new("/bin/sh");
+    command.args(&["-c", "ulimit -n"]);
+    unsafe {
+      std::os::unix::process::CommandExt::before_exec(&mut command, move || set_rlimits(&limits));
+    }
+    let output = command.output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "32");
+  }
+}
@@ -0,0 +1,304 @@
+use std::cmp;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::future::{self, Future, Loop};
+use futures_timer::Delay;
+use log::warn;
+
+use super::{
+  CommandRunner, ExecuteProcessRequest, ExecutionStats, FallibleExecuteProcessResult, Platform,
+};
+
+///
+/// Which failed process executions to retry, and how.
+///
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+  ///
+  /// The maximum number of times to attempt a process, including the first attempt.
+  ///
+  pub max_attempts: usize,
+  ///
+  /// How long to wait before the first retry: the wait doubles for each subsequent retry.
+  ///
+  pub backoff: Duration,
+  ///
+  /// Processes which exit with any of these codes are retried.
+  ///
+  pub exit_codes: BTreeSet<i32>,
+  ///
+  /// Whether to retry when the process could not be run at all (e.g. because of an error
+  /// materializing its inputs, or talking to a remote execution cluster).
+  ///
+  pub infrastructure_errors: bool,
+  ///
+  /// Processes which exit with a non-zero code, and whose stdout or stderr contains any of these
+  /// strings, are retried.
+  ///
+  pub output_patterns: Vec<String>,
+}
+
+impl RetryPolicy {
+  fn should_retry(&self, result: &Result<FallibleExecuteProcessResult, String>) -> bool {
+    match result {
+      Ok(result) if result.exit_code == 0 => false,
+      Ok(result) => {
+        self.exit_codes.contains(&result.exit_code)
+          || self.output_patterns.iter().any(|pattern| {
+            let pattern = pattern.as_bytes();
+            contains(&result.stdout, pattern) || contains(&result.stderr, pattern)
+          })
+      }
+      Err(_) => self.infrastructure_errors,
+    }
+  }
+
+  fn backoff_before(&self, attempt: usize) -> Duration {
+    // Cap the exponent to avoid overflow for (unreasonably) large numbers of attempts.
+    let exponent = cmp::min(attempt.saturating_sub(2), 16) as u32;
+    self.backoff * 2_u32.pow(exponent)
+  }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+  needle.is_empty()
+    || haystack
+      .windows(needle.len())
+      .any(|window| window == needle)
+}
+
+///
+/// A CommandRunner wrapper which retries failed processes according to a RetryPolicy.
+///
+/// The `execution_attempts` of the result contain the attempts of each try (or a single empty
+/// ExecutionStats for a try which recorded none), so that they at least indicate how many times the
+/// process was attempted.
+///
+#[derive(Clone)]
+pub struct RetryingCommandRunner {
+  inner: Arc<dyn CommandRunner>,
+  policy: Arc<RetryPolicy>,
+}
+
+impl RetryingCommandRunner {
+  pub fn new(inner: Box<dyn CommandRunner>, policy: RetryPolicy) -> RetryingCommandRunner {
+    RetryingCommandRunner {
+      inner: Arc::from(inner),
+      policy: Arc::new(policy),
+    }
+  }
+}
+
+impl CommandRunner for RetryingCommandRunner {
+  fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let inner = self.inner.clone();
+    let policy = self.policy.clone();
+    future::loop_fn(
+      (1, Vec::<ExecutionStats>::new()),
+      move |(attempt, mut execution_attempts)| {
+        let policy = policy.clone();
+        let description = req.description.clone();
+        inner.run(req.clone()).then(move |result| {
+          match result {
+            Ok(ref result) if !result.execution_attempts.is_empty() => {
+              execution_attempts.extend(result.execution_attempts.iter().cloned())
+            }
+            _ => execution_attempts.push(ExecutionStats::default()),
+          }
+
+          if attempt < policy.max_attempts && policy.should_retry(&result) {
+            let backoff = policy.backoff_before(attempt + 1);
+            warn!(
+              "Attempt {} of {} for {:?} failed: retrying in {:?}",
+              attempt, policy.max_attempts, description, backoff
+            );
+            return Delay::new(backoff)
+              .map_err(|err| format!("Error waiting to retry process execution: {}", err))
+              .map(move |()| Loop::Continue((attempt + 1, execution_attempts)))
+              .to_boxed();
+          }
+
+          let result = match result {
+            Ok(mut result) => {
+              result.execution_attempts = execution_attempts;
+              Ok(Loop::Break(result))
+            }
+            Err(err) if attempt > 1 => Err(format!("{} (after {} attempts)", err, attempt)),
+            Err(err) => Err(err),
+          };
+          future::done(result).to_boxed()
+        })
+      },
+    )
+    .to_boxed()
+  }
+
+  fn platform(&self) -> Result<Platform, String> {
+    self.inner.platform()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{RetryPolicy, RetryingCommandRunner};
+  use crate::{CommandRunner, ExecuteProcessRequest, FallibleExecuteProcessResult};
+  use boxfuture::{BoxFuture, Boxable};
+  use bytes::Bytes;
+  use futures::{future, Future};
+  use std::collections::{BTreeMap, BTreeSet};
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
+
+  #[test]
+  fn retries_exit_codes_until_success() {
+    let (inner, attempts) = FlakyCommandRunner::new(2, Ok(3));
+    let runner = RetryingCommandRunner::new(Box::new(inner), policy(3));
+
+    let result = run(&runner).unwrap();
+    assert_eq!(result.exit_code, 0);
+    assert_eq!(result.execution_attempts.len(), 3);
+    assert_eq!(*attempts.lock().unwrap(), 3);
+  }
+
+  #[test]
+  fn gives_up_after_max_attempts() {
+    let (inner, attempts) = FlakyCommandRunner::new(5, Ok(3));
+    let runner = RetryingCommandRunner::new(Box::new(inner), policy(2));
+
+    let result = run(&runner).unwrap();
+    assert_eq!(result.exit_code, 3);
+    assert_eq!(result.execution_attempts.len(), 2);
+    assert_eq!(*attempts.lock().unwrap(), 2);
+  }
+
+  #[test]
+  fn does_not_retry_other_exit_codes() {
+    let (inner, attempts) = FlakyCommandRunner::new(1, Ok(1));
+    let runner = RetryingCommandRunner::new(Box::new(inner), policy(3));
+
+    assert_eq!(run(&runner).unwrap().exit_code, 1);
+    assert_eq!(*attempts.lock().unwrap(), 1);
+  }
+
+  #[test]
+  fn retries_output_patterns() {
+    let (inner, attempts) = FlakyCommandRunner::new(1, Ok(1));
+    let mut policy = policy(3);
+    policy.output_patterns = vec!["flaked".to_owned()];
+    let runner = RetryingCommandRunner::new(Box::new(inner), policy);
+
+    assert_eq!(run(&runner).unwrap().exit_code, 0);
+    assert_eq!(*attempts.lock().unwrap(), 2);
+  }
+
+  #[test]
+  fn retries_infrastructure_errors() {
+    let (inner, attempts) = FlakyCommandRunner::new(5, Err("Connection reset"));
+    let runner = RetryingCommandRunner::new(Box::new(inner.clone()), policy(2));
+    assert_eq!(
+      run(&runner),
+      Err("Connection reset (after 2 attempts)".to_owned())
+    );
+    assert_eq!(*attempts.lock().unwrap(), 2);
+
+    let mut policy = policy(2);
+    policy.infrastructure_errors = false;
+    let runner = RetryingCommandRunner::new(Box::new(inner), policy);
+    assert_eq!(run(&runner), Err("Connection reset".to_owned()));
+    assert_eq!(*attempts.lock().unwrap(), 3);
+  }
+
+  fn policy(max_attempts: usize) -> RetryPolicy {
+    RetryPolicy {
+      max_attempts,
+      backoff: Duration::from_millis(1),
+      exit_codes: vec![3].into_iter().collect(),
+      infrastructure_errors: true,
+      output_patterns: vec![],
+    }
+  }
+
+  fn run(runner: &RetryingCommandRunner) -> Result<FallibleExecuteProcessResult, String> {
+    runner
+      .run(ExecuteProcessRequest {
+        argv: vec![],
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "flaky".to_string(),
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      })
+      .wait()
+  }
+
+  ///
+  /// A CommandRunner which fails (with the given exit code, or error) for its first `failures`
+  /// attempts, and then succeeds.
+  ///
+  #[derive(Clone)]
+  struct FlakyCommandRunner {
+    failures: usize,
+    failure: Result<i32, &'static str>,
+    attempts: Arc<Mutex<usize>>,
+  }
+
+  impl FlakyCommandRunner {
+    fn new(
+      failures: usize,
+      failure: Result<i32, &'static str>,
+    ) -> (FlakyCommandRunner, Arc<Mutex<usize>>) {
+      let attempts = Arc::new(Mutex::new(0));
+      (
+        FlakyCommandRunner {
+          failures,
+          failure,
+          attempts: attempts.clone(),
+        },
+        attempts,
+      )
+    }
+  }
+
+  impl CommandRunner for FlakyCommandRunner {
+    fn run(&self, _req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
+      let attempt = {
+        let mut attempts = self.attempts.lock().unwrap();
+        *attempts += 1;
+        *attempts
+      };
+      let exit_code = if attempt > self.failures {
+        0
+      } else {
+        match self.failure {
+          Ok(exit_code) => exit_code,
+          Err(err) => return future::err(err.to_owned()).to_boxed(),
+        }
+      };
+      future::ok(FallibleExecuteProcessResult {
+        stdout: Bytes::new(),
+        stderr: Bytes::from(if exit_code == 0 { "" } else { "flaked" }),
+        exit_code,
+        output_directory: fs::EMPTY_DIGEST,
+        execution_attempts: vec![],
+        failure_reason: None,
+        resource_usage: None,
+        trace: None,
+      })
+      .to_boxed()
+    }
+  }
+}
@@ -0,0 +1,441 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::future::{self, Either, Future};
+use futures_timer::Delay;
+use log::{debug, info};
+
+use super::{
+  CommandRunner, ExecuteProcessRequest, FallibleExecuteProcessResult,
+  MultiPlatformExecuteProcessRequest, Platform,
+};
+
+///
+/// The number of processes won by each side of a SpeculatingCommandRunner.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SpeculationCounts {
+  pub primary_wins: usize,
+  pub secondary_wins: usize,
+}
+
+///
+/// The outcomes of all of the processes run by a SpeculatingCommandRunner, which can be used to
+/// tune its speculation delay: a primary which often wins shortly after speculation starts
+/// suggests that the delay is too short, and a secondary which often wins quickly suggests that it
+/// is too long.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SpeculationSummary {
+  pub counts: SpeculationCounts,
+  pub speculation_delay: Duration,
+  ///
+  /// The number of processes which were still running after the speculation delay, so that the
+  /// secondary was started.
+  ///
+  pub speculated: usize,
+  ///
+  /// The total, across speculated processes, of the time from starting the secondary until either
+  /// side completed.
+  ///
+  pub total_latency_delta: Duration,
+  ///
+  /// The total time spent cancelling (i.e. dropping) the losing sides.
+  ///
+  pub total_cancellation_overhead: Duration,
+}
+
+impl SpeculationSummary {
+  fn record(&mut self, outcome: &SpeculationOutcome) {
+    if outcome.primary_won {
+      self.counts.primary_wins += 1;
+    } else {
+      self.counts.secondary_wins += 1;
+    }
+    if let Some(latency_delta) = outcome.latency_delta {
+      self.speculated += 1;
+      self.total_latency_delta += latency_delta;
+    }
+    self.total_cancellation_overhead += outcome.cancellation_overhead;
+  }
+
+  pub fn processes(&self) -> usize {
+    self.counts.primary_wins + self.counts.secondary_wins
+  }
+}
+
+impl fmt::Display for SpeculationSummary {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "Speculated for {} of {} processes after {:?}: the primary won {} and the secondary won {}",
+      self.speculated,
+      self.processes(),
+      self.speculation_delay,
+      self.counts.primary_wins,
+      self.counts.secondary_wins
+    )?;
+    if self.speculated > 0 {
+      write!(
+        f,
+        ", on average {:?} after speculating",
+        self.total_latency_delta / self.speculated as u32
+      )?;
+    }
+    write!(
+      f,
+      ", and cancelling the losers took {:?}",
+      self.total_cancellation_overhead
+    )
+  }
+}
+
+///
+/// The outcome of the speculation for a single process.
+///
+struct SpeculationOutcome {
+  primary_won: bool,
+  ///
+  /// If the secondary was started, the time from then until either side completed.
+  ///
+  latency_delta: Option<Duration>,
+  cancellation_overhead: Duration,
+}
+
+///
+/// The SpeculationSummary shared between the clones of a SpeculatingCommandRunner, which is logged
+/// once the last of them is dropped at the end of the run.
+///
+struct SharedSummary(Mutex<SpeculationSummary>);
+
+impl Drop for SharedSummary {
+  fn drop(&mut self) {
+    let summary = self.0.lock().unwrap();
+    if summary.processes() > 0 {
+      info!("{}", *summary);
+    }
+  }
+}
+
+///
+/// A CommandRunner which runs each process with a primary CommandRunner (e.g. remote execution),
+/// and if it has not completed within a delay, races it against a secondary CommandRunner (e.g.
+/// local execution). Whichever completes successfully first wins, and the other is cancelled by
+/// being dropped.
+///
+/// If one side fails, the result of the other side is used, so the secondary also acts as a
+/// fallback for failures of the primary.
+///
+#[derive(Clone)]
+pub struct SpeculatingCommandRunner {
+  primary: Arc<dyn CommandRunner>,
+  secondary: Arc<dyn CommandRunner>,
+  speculation_delay: Duration,
+  summary: Arc<SharedSummary>,
+}
+
+impl SpeculatingCommandRunner {
+  pub fn new(
+    primary: Box<dyn CommandRunner>,
+    secondary: Box<dyn CommandRunner>,
+    speculation_delay: Duration,
+  ) -> SpeculatingCommandRunner {
+    SpeculatingCommandRunner {
+      primary: Arc::from(primary),
+      secondary: Arc::from(secondary),
+      speculation_delay,
+      summary: Arc::new(SharedSummary(Mutex::new(SpeculationSummary {
+        speculation_delay,
+        ..SpeculationSummary::default()
+      }))),
+    }
+  }
+
+  pub fn counts(&self) -> SpeculationCounts {
+    self.summary().counts
+  }
+
+  pub fn summary(&self) -> SpeculationSummary {
+    *self.summary.0.lock().unwrap()
+  }
+
+  fn speculate<S>(
+    &self,
+    description: String,
+    primary: BoxFuture<FallibleExecuteProcessResult, String>,
+    secondary: S,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String>
+  where
+    S: FnOnce() -> BoxFuture<FallibleExecuteProcessResult, String> + Send + 'static,
+  {
+    let summary = self.summary.clone();
+    let speculated_at = Arc::new(Mutex::new(None));
+    let speculated_at2 = speculated_at.clone();
+    let delayed_secondary = Delay::new(self.speculation_delay)
+      .map_err(|err| format!("Error waiting to speculate: {}", err))
+      .and_then(move |()| {
+        *speculated_at2.lock().unwrap() = Some(Instant::now());
+        secondary()
+      });
+
+    // The losing side is cancelled by dropping it, which is timed separately from the race.
+    let cancel = |loser: BoxFuture<FallibleExecuteProcessResult, String>| {
+      let started = Instant::now();
+      drop(loser);
+      started.elapsed()
+    };
+    let no_cancellation = Duration::from_millis(0);
+
+    primary
+      .select2(delayed_secondary)
+      .then(move |res| match res {
+        Ok(Either::A((result, secondary))) => {
+          let finished = Instant::now();
+          future::ok((true, result, finished, cancel(secondary.to_boxed()))).to_boxed()
+        }
+        Ok(Either::B((result, primary))) => {
+          let finished = Instant::now();
+          future::ok((false, result, finished, cancel(primary))).to_boxed()
+        }
+        Err(Either::A((_, secondary))) => secondary
+          .map(move |result| (false, result, Instant::now(), no_cancellation))
+          .to_boxed(),
+        Err(Either::B((_, primary))) => primary
+          .map(move |result| (true, result, Instant::now(), no_cancellation))
+          .to_boxed(),
+      })
+      .map(
+        move |(primary_won, result, finished, cancellation_overhead)| {
+          let outcome = SpeculationOutcome {
+            primary_won,
+            latency_delta: speculated_at
+              .lock()
+              .unwrap()
+              .map(|speculated_at| finished.duration_since(speculated_at)),
+            cancellation_overhead,
+          };
+          debug!(
+            "{} won speculation for {:?} ({:?} after speculating, and cancelling took {:?})",
+            if primary_won { "Primary" } else { "Secondary" },
+            description,
+            outcome.latency_delta,
+            outcome.cancellation_overhead
+          );
+          summary.0.lock().unwrap().record(&outcome);
+          result
+        },
+      )
+      .to_boxed()
+  }
+}
+
+impl CommandRunner for SpeculatingCommandRunner {
+  fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let secondary = self.secondary.clone();
+    self.speculate(
+      req.description.clone(),
+      self.primary.run(req.clone()),
+      move || secondary.run(req),
+    )
+  }
+
+  fn platform(&self) -> Result<Platform, String> {
+    self.primary.platform()
+  }
+
+  ///
+  /// The primary and secondary may run processes on different platforms (e.g. remotely on Linux,
+  /// and locally on macOS), so each runs the variant matching its own platform.
+  ///
+  fn run_multi_platform(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let description = req
+      .0
+      .values()
+      .next()
+      .map(|variant| variant.description.clone())
+      .unwrap_or_else(String::new);
+    let secondary = self.secondary.clone();
+    self.speculate(
+      description,
+      self.primary.run_multi_platform(req.clone()),
+      move || secondary.run_multi_platform(req),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{SpeculatingCommandRunner, SpeculationCounts, SpeculationSummary};
+  use crate::{CommandRunner, ExecuteProcessRequest, FallibleExecuteProcessResult};
+  use boxfuture::{BoxFuture, Boxable};
+  use bytes::Bytes;
+  use futures::Future;
+  use futures_timer::Delay;
+  use std::collections::{BTreeMap, BTreeSet};
+  use std::time::Duration;
+
+  #[test]
+  fn primary_wins_if_fast() {
+    let runner = SpeculatingCommandRunner::new(
+      DelayedCommandRunner::boxed(10, Ok("primary")),
+      DelayedCommandRunner::boxed(0, Ok("secondary")),
+      Duration::from_millis(500),
+    );
+    assert_eq!(run(&runner), Ok(Bytes::from("primary")));
+    assert_eq!(
+      runner.counts(),
+      SpeculationCounts {
+        primary_wins: 1,
+        secondary_wins: 0
+      }
+    );
+  }
+
+  #[test]
+  fn secondary_wins_if_primary_is_slow() {
+    let runner = SpeculatingCommandRunner::new(
+      DelayedCommandRunner::boxed(5000, Ok("primary")),
+      DelayedCommandRunner::boxed(0, Ok("secondary")),
+      Duration::from_millis(10),
+    );
+    assert_eq!(run(&runner), Ok(Bytes::from("secondary")));
+    assert_eq!(
+      runner.counts(),
+      SpeculationCounts {
+        primary_wins: 0,
+        secondary_wins: 1
+      }
+    );
+  }
+
+  #[test]
+  fn summary_records_speculation() {
+    let runner = SpeculatingCommandRunner::new(
+      DelayedCommandRunner::boxed(5000, Ok("primary")),
+      DelayedCommandRunner::boxed(100, Ok("secondary")),
+      Duration::from_millis(10),
+    );
+    assert_eq!(run(&runner), Ok(Bytes::from("secondary")));
+    let summary = runner.summary();
+    assert_eq!(summary.speculated, 1);
+    assert!(summary.total_latency_delta >= Duration::from_millis(100));
+    assert!(summary.total_latency_delta < Duration::from_millis(5000));
+    assert!(summary
+      .to_string()
+      .starts_with("Speculated for 1 of 1 processes after 10ms"));
+  }
+
+  #[test]
+  fn summary_excludes_unspeculated_processes() {
+    let runner = SpeculatingCommandRunner::new(
+      DelayedCommandRunner::boxed(0, Ok("primary")),
+      DelayedCommandRunner::boxed(0, Ok("secondary")),
+      Duration::from_millis(500),
+    );
+    assert_eq!(run(&runner), Ok(Bytes::from("primary")));
+    assert_eq!(
+      runner.summary(),
+      SpeculationSummary {
+        counts: SpeculationCounts {
+          primary_wins: 1,
+          secondary_wins: 0
+        },
+        speculation_delay: Duration::from_millis(500),
+        speculated: 0,
+        total_latency_delta: Duration::from_millis(0),
+        total_cancellation_overhead: runner.summary().total_cancellation_overhead,
+      }
+    );
+  }
+
+  #[test]
+  fn secondary_is_used_if_primary_fails() {
+    let runner = SpeculatingCommandRunner::new(
+      DelayedCommandRunner::boxed(0, Err("primary")),
+      DelayedCommandRunner::boxed(0, Ok("secondary")),
+      Duration::from_millis(10),
+    );
+    assert_eq!(run(&runner), Ok(Bytes::from("secondary")));
+  }
+
+  #[test]
+  fn fails_if_both_fail() {
+    let runner = SpeculatingCommandRunner::new(
+      DelayedCommandRunner::boxed(0, Err("primary")),
+      DelayedCommandRunner::boxed(0, Err("secondary")),
+      Duration::from_millis(10),
+    );
+    assert_eq!(run(&runner), Err("secondary".to_owned()));
+  }
+
+  fn run(runner: &SpeculatingCommandRunner) -> Result<Bytes, String> {
+    runner
+      .run(ExecuteProcessRequest {
+        argv: vec![],
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "speculate".to_string(),
+        tool_mounts: BTreeMap::new(),
+        named_caches: BTreeMap::new(),
+        resource_limits: crate::ResourceLimits::default(),
+        reusable_workdir: None,
+        env_passthrough: BTreeSet::new(),
+        concurrency_weight: 1,
+        tee_output: false,
+        platform_properties: BTreeMap::new(),
+        priority: 0,
+        execution_headers: BTreeMap::new(),
+      })
+      .wait()
+      .map(|result| result.stdout)
+  }
+
+  ///
+  /// A CommandRunner which, after a delay, either succeeds with the given stdout or fails with the
+  /// given error.
+  ///
+  struct DelayedCommandRunner {
+    delay: Duration,
+    result: Result<&'static str, &'static str>,
+  }
+
+  impl DelayedCommandRunner {
+    fn boxed(millis: u64, result: Result<&'static str, &'static str>) -> Box<dyn CommandRunner> {
+      Box::new(DelayedCommandRunner {
+        delay: Duration::from_millis(millis),
+        result,
+      })
+    }
+  }
+
+  impl CommandRunner for DelayedCommandRunner {
+    fn run(&self, _req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
+      let result = self.result;
+      Delay::new(self.delay)
+        .map_err(|err| err.to_string())
+        .and_then(move |()| {
+          result
+            .map(|stdout| FallibleExecuteProcessResult {
+              stdout: Bytes::from(stdout),
+              stderr: Bytes::new(),
+              exit_code: 0,
+              output_directory: fs::EMPTY_DIGEST,
+              execution_attempts: vec![],
+              failure_reason: None,
+              resource_usage: None,
+              trace: None,
+            })
+            .map_err(str::to_owned)
+        })
+        .to_boxed()
+    }
+  }
+}
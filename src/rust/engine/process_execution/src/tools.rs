@@ -0,0 +1,201 @@
+// Resolution of the ToolMounts of processes: to the tools on this machine that they refer to, and
+// to fingerprints of those tools for use in cache keys.
+
+use std::collections::BTreeMap;
+use std::fs::{self, create_dir_all};
+use std::os::unix::fs::symlink;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use digest::{Digest as DigestTrait, FixedOutput};
+use hashing::Fingerprint;
+use sha2::Sha256;
+
+use super::ToolMount;
+
+///
+/// A ToolMount whose host path has been resolved to the tool that it currently refers to.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ResolvedTool {
+  pub name: String,
+  pub sandbox_path: PathBuf,
+  ///
+  /// The canonical path of the tool, with all symlinks resolved.
+  ///
+  pub path: PathBuf,
+  pub fingerprint: Fingerprint,
+}
+
+///
+/// Resolves each of the given tools, failing if any of them is invalid or doesn't exist.
+///
+pub fn resolve(tools: &BTreeMap<String, ToolMount>) -> Result<Vec<ResolvedTool>, String> {
+  tools
+    .iter()
+    .map(|(name, mount)| {
+      if name.is_empty()
+        || !name
+          .chars()
+          .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+      {
+        return Err(format!(
+          "Tool names must consist of lowercase letters, digits and underscores: {:?}",
+          name
+        ));
+      }
+      if mount.sandbox_path.as_os_str().is_empty()
+        || mount
+          .sandbox_path
+          .components()
+          .any(|component| match component {
+            Component::Normal(_) => false,
+            _ => true,
+          })
+      {
+        return Err(format!(
+          "Tool {:?} must be mounted at a relative path within the sandbox, but got {:?}",
+          name, mount.sandbox_path
+        ));
+      }
+      let path = fs::canonicalize(&mount.host_path)
+        .map_err(|err| format!("Error resolving tool {:?}: {:?}", name, err))?;
+      let fingerprint = fingerprint(&path)
+        .map_err(|err| format!("Error fingerprinting tool {:?}: {}", name, err))?;
+      Ok(ResolvedTool {
+        name: name.clone(),
+        sandbox_path: mount.sandbox_path.clone(),
+        path,
+        fingerprint,
+      })
+    })
+    .collect()
+}
+
+///
+/// Symlinks each of the given tools to its path in the given sandbox. If `replace` is set, any
+/// existing links (e.g. from a previous run in a reusable workdir) are replaced.
+///
+pub fn link_into(sandbox: &Path, tools: &[ResolvedTool], replace: bool) -> Result<(), String> {
+  for tool in tools {
+    let link = sandbox.join(&tool.sandbox_path);
+    if replace && link.symlink_metadata().is_ok() {
+      fs::remove_file(&link)
+        .map_err(|err| format!("Error removing link for tool {:?}: {:?}", tool.name, err))?;
+    }
+    if let Some(parent) = link.parent() {
+      create_dir_all(parent).map_err(|err| {
+        format!(
+          "Error making parent directory for tool {:?}: {:?}",
+          tool.name, err
+        )
+      })?;
+    }
+    symlink(&tool.path, &link)
+      .map_err(|err| format!("Error linking tool {:?}: {:?}", tool.name, err))?;
+  }
+  Ok(())
+}
+
+///
+/// A fingerprint of the tool at the given (canonical) path, which changes if the tool is replaced
+/// or upgraded in place.
+///
+/// Tools like JDKs are too large to hash on every run, so this covers the path of the tool and the
+/// sizes and modification times of it and of the entries directly inside of it, rather than all of
+/// its contents.
+///
+fn fingerprint(path: &Path) -> Result<Fingerprint, String> {
+  let mut hasher = Sha256::default();
+  hasher.input(path.to_string_lossy().as_bytes());
+  hash_metadata(&mut hasher, path)?;
+  if path.is_dir() {
+    let mut entries = fs::read_dir(path)
+      .and_then(|entries| {
+        entries
+          .map(|entry| entry.map(|entry| entry.path()))
+          .collect::<Result<Vec<_>, _>>()
+      })
+      .map_err(|err| format!("Error listing {:?}: {:?}", path, err))?;
+    entries.sort();
+    for entry in entries {
+      hasher.input(entry.to_string_lossy().as_bytes());
+      hash_metadata(&mut hasher, &entry)?;
+    }
+  }
+  Ok(Fingerprint::from_bytes_unsafe(&hasher.fixed_result()))
+}
+
+fn hash_metadata(hasher: &mut Sha256, path: &Path) -> Result<(), String> {
+  let metadata =
+    fs::symlink_metadata(path).map_err(|err| format!("Error statting {:?}: {:?}", path, err))?;
+  let modified = metadata
+    .modified()
+    .ok()
+    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+    .map(|modified| modified.as_nanos())
+    .unwrap_or(0);
+  hasher.input(format!("\0{}\0{}\0", metadata.len(), modified).as_bytes());
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{link_into, resolve};
+  use crate::ToolMount;
+  use std::collections::BTreeMap;
+  use std::path::PathBuf;
+  use tempfile::TempDir;
+
+  fn tools(host_path: PathBuf, sandbox_path: &str) -> BTreeMap<String, ToolMount> {
+    let mut tools = BTreeMap::new();
+    tools.insert(
+      "jdk".to_owned(),
+      ToolMount {
+        host_path,
+        sandbox_path: PathBuf::from(sandbox_path),
+      },
+    );
+    tools
+  }
+
+  #[test]
+  fn resolve_and_link() {
+    let tool_dir = TempDir::new().unwrap();
+    let sandbox = TempDir::new().unwrap();
+    std::fs::write(tool_dir.path().join("release"), "1.8").unwrap();
+
+    let resolved = resolve(&tools(tool_dir.path().to_owned(), "tools/jdk")).unwrap();
+    link_into(sandbox.path(), &resolved, false).unwrap();
+    assert_eq!(
+      std::fs::read_to_string(sandbox.path().join("tools/jdk/release")).unwrap(),
+      "1.8"
+    );
+
+    // Relinking an existing link requires replacing it.
+    link_into(sandbox.path(), &resolved, false).expect_err("Want Err for existing link");
+    link_into(sandbox.path(), &resolved, true).unwrap();
+  }
+
+  #[test]
+  fn fingerprint_changes_with_tool() {
+    let tool_dir = TempDir::new().unwrap();
+    let tools = tools(tool_dir.path().to_owned(), ".jdk");
+    let before = resolve(&tools).unwrap()[0].fingerprint;
+    assert_eq!(resolve(&tools).unwrap()[0].fingerprint, before);
+
+    std::fs::write(tool_dir.path().join("release"), "11").unwrap();
+    assert_ne!(resolve(&tools).unwrap()[0].fingerprint, before);
+  }
+
+  #[test]
+  fn resolve_rejects_invalid_tools() {
+    let tool_dir = TempDir::new().unwrap();
+    resolve(&tools(tool_dir.path().to_owned(), "../jdk"))
+      .expect_err("Want Err for mount outside of the sandbox");
+    resolve(&tools(tool_dir.path().to_owned(), ""))
+      .expect_err("Want Err for mount at the sandbox root");
+    resolve(&tools(tool_dir.path().join("missing"), ".jdk"))
+      .expect_err("Want Err for missing tool");
+  }
+}
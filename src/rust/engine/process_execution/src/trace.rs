@@ -0,0 +1,236 @@
+// Running local processes under tracers of their system calls, to audit which files they actually
+// touch (e.g. to tighten their input digests).
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use boxfuture::{try_future, BoxFuture, Boxable};
+use bytes::Bytes;
+use futures::future::{self, Future};
+
+use super::{local, ExecuteProcessRequest, FallibleExecuteProcessResult, Platform};
+
+///
+/// A tracer of the system calls of processes (and of all of the processes that they spawn).
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Tracer {
+  ///
+  /// strace, at the given path, tracing file and process related system calls.
+  ///
+  Strace(PathBuf),
+  ///
+  /// dtruss, at the given path, which generally needs to run as root.
+  ///
+  Dtruss(PathBuf),
+}
+
+impl Tracer {
+  ///
+  /// The tracer at the given path, whose kind is determined by its file name.
+  ///
+  pub fn for_binary(binary: PathBuf) -> Result<Tracer, String> {
+    match binary.file_name().and_then(|name| name.to_str()) {
+      Some("strace") => Ok(Tracer::Strace(binary)),
+      Some("dtruss") => Ok(Tracer::Dtruss(binary)),
+      _ => Err(format!(
+        "Unsupported tracer {:?}: expected strace or dtruss",
+        binary
+      )),
+    }
+  }
+
+  ///
+  /// The argv which runs the given argv under this tracer, writing the trace to the given file.
+  ///
+  fn wrap(&self, argv: Vec<String>, trace_file: &Path) -> Result<Vec<String>, String> {
+    let trace_file = trace_file
+      .to_str()
+      .ok_or_else(|| format!("Non-UTF8 trace file path: {:?}", trace_file))?
+      .to_owned();
+    let binary = |binary: &Path| {
+      binary
+        .to_str()
+        .map(str::to_owned)
+        .ok_or_else(|| format!("Non-UTF8 tracer path: {:?}", binary))
+    };
+    let mut wrapped = match self {
+      Tracer::Strace(strace) => vec![
+        binary(strace)?,
+        "-f".to_owned(),
+        "-q".to_owned(),
+        "-e".to_owned(),
+        "trace=file,process".to_owned(),
+        "-o".to_owned(),
+        trace_file,
+        "--".to_owned(),
+      ],
+      // dtruss writes its trace to stderr, which the shell redirects to the trace file: the traced
+      // process gets the original stderr back via fd 3. Everything is passed positionally, to avoid
+      // needing to quote it.
+      Tracer::Dtruss(dtruss) => vec![
+        "/bin/sh".to_owned(),
+        "-c".to_owned(),
+        concat!(
+          "trace=\"$1\"; shift; exec 3>&2; ",
+          "exec \"$0\" -f /bin/sh -c 'exec 2>&3 3>&-; exec \"$@\"' sh \"$@\" 2>\"$trace\""
+        )
+        .to_owned(),
+        binary(dtruss)?,
+        trace_file,
+      ],
+    };
+    wrapped.extend(argv);
+    Ok(wrapped)
+  }
+}
+
+///
+/// Runs processes locally under a Tracer, storing their traces in the Store and attaching the
+/// digests of the traces to their results.
+///
+/// Traces are written outside of sandboxes, so that they aren't captured as outputs. Processes
+/// whose results were cached by the local::CommandRunner didn't run, so have no trace.
+///
+pub struct CommandRunner {
+  local: local::CommandRunner,
+  store: fs::Store,
+  tracer: Tracer,
+  trace_dir: PathBuf,
+}
+
+impl CommandRunner {
+  pub fn new(
+    local: local::CommandRunner,
+    store: fs::Store,
+    tracer: Tracer,
+    trace_dir: PathBuf,
+  ) -> CommandRunner {
+    CommandRunner {
+      local,
+      store,
+      tracer,
+      trace_dir,
+    }
+  }
+}
+
+impl super::CommandRunner for CommandRunner {
+  fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let trace_dir = try_future!(tempfile::Builder::new()
+      .prefix("process-trace")
+      .tempdir_in(&self.trace_dir)
+      .map_err(|err| format!("Error making tempdir for process trace: {:?}", err)));
+    let trace_file = trace_dir.path().join("trace");
+    let argv = try_future!(self.tracer.wrap(req.argv.clone(), &trace_file));
+    let store = self.store.clone();
+    self
+      .local
+      .run(ExecuteProcessRequest { argv, ..req })
+      .and_then(move |mut result| {
+        let trace = match std::fs::read(&trace_file) {
+          Ok(trace) => trace,
+          Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return future::ok(result).to_boxed()
+          }
+          Err(err) => {
+            return future::err(format!("Error reading process trace: {:?}", err)).to_boxed()
+          }
+        };
+        // The trace has been read, so its tempdir can be removed.
+        drop(trace_dir);
+        store
+          .store_file_bytes(Bytes::from(trace), true)
+          .map(move |digest| {
+            result.trace = Some(digest);
+            result
+          })
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  fn platform(&self) -> Result<Platform, String> {
+    self.local.platform()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{CommandRunner, Tracer};
+  use crate::{local, CommandRunner as CommandRunnerTrait, ExecuteProcessRequestBuilder};
+  use std::os::unix::fs::PermissionsExt;
+  use std::path::PathBuf;
+  use std::time::Duration;
+  use tempfile::TempDir;
+  use testutil::data::TestData;
+  use testutil::{as_bytes, owned_string_vec};
+
+  #[test]
+  fn tracer_for_binary() {
+    assert_eq!(
+      Tracer::for_binary(PathBuf::from("/usr/bin/strace")),
+      Ok(Tracer::Strace(PathBuf::from("/usr/bin/strace")))
+    );
+    assert_eq!(
+      Tracer::for_binary(PathBuf::from("/usr/bin/dtruss")),
+      Ok(Tracer::Dtruss(PathBuf::from("/usr/bin/dtruss")))
+    );
+    Tracer::for_binary(PathBuf::from("/usr/bin/ltrace")).expect_err("Want Err for ltrace");
+  }
+
+  #[test]
+  fn trace_is_stored() {
+    let store_dir = TempDir::new().unwrap();
+    let work_dir = TempDir::new().unwrap();
+    let tracer_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(store_dir.path()).unwrap();
+
+    // A fake strace, which records a trace and runs the process.
+    let strace = tracer_dir.path().join("strace");
+    std::fs::write(
+      &strace,
+      concat!(
+        "#!/bin/sh\n",
+        "while [ \"$1\" != -- ]; do\n",
+        "  if [ \"$1\" = -o ]; then out=\"$2\"; shift; fi\n",
+        "  shift\n",
+        "done\n",
+        "shift\n",
+        "echo traced > \"$out\"\n",
+        "exec \"$@\"\n",
+      ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&strace, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let runner = CommandRunner::new(
+      local::CommandRunner::new(store.clone(), work_dir.path().to_owned(), true),
+      store.clone(),
+      Tracer::for_binary(strace).unwrap(),
+      work_dir.path().to_owned(),
+    );
+    let req = ExecuteProcessRequestBuilder::new(
+      owned_string_vec(&["/bin/echo", "-n", "foo"]),
+      Duration::from_secs(5),
+      "traced echo".to_owned(),
+    )
+    .build()
+    .unwrap();
+    let result = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(runner.run(req))
+      .unwrap();
+
+    assert_eq!(result.stdout, as_bytes("foo"));
+    let trace = TestData::new("traced\n");
+    assert_eq!(result.trace, Some(trace.digest()));
+    assert_eq!(
+      tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(store.load_file_bytes_with(trace.digest(), |bytes| bytes))
+        .unwrap(),
+      Some(trace.bytes())
+    );
+  }
+}
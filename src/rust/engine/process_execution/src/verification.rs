@@ -0,0 +1,302 @@
+// Verification of the outputs of remote executions, for when workers can't be trusted to return
+// exactly what they say they do (e.g. because of bad disks or buggy caching on the workers).
+
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use boxfuture::{try_future, BoxFuture, Boxable};
+use digest::{Digest as DigestTrait, FixedOutput};
+use fs::Store;
+use futures::future::{self, Future};
+use hashing::{Digest, Fingerprint};
+use sha2::Sha256;
+
+///
+/// Checks that the output directory of a process only contains the output files and directories
+/// that it declared (given as relative paths within its sandbox), and that every file in it has the
+/// digest that it is recorded with. Every file is downloaded (if it wasn't already) and re-hashed.
+///
+/// Missing outputs are not an error, because processes are allowed to not create some of their
+/// outputs.
+///
+pub fn verify_output_directory(
+  store: &Store,
+  output_files: &BTreeSet<PathBuf>,
+  output_directories: &BTreeSet<PathBuf>,
+  output_directory: Digest,
+) -> BoxFuture<(), String> {
+  let outputs = Arc::new(DeclaredOutputs {
+    files: output_files.iter().map(|path| normalize(path)).collect(),
+    directories: output_directories
+      .iter()
+      .map(|path| normalize(path))
+      .collect(),
+  });
+  let store2 = store.clone();
+  store
+    .ensure_local_has_recursive_directory(output_directory)
+    .and_then(move |()| {
+      store2.walk(output_directory, move |store, path_so_far, _, directory| {
+        for dir_node in directory.get_directories() {
+          try_future!(outputs.check_directory(&path_so_far.join(dir_node.get_name())));
+        }
+        future::join_all(
+          directory
+            .get_files()
+            .iter()
+            .map(|file_node| {
+              let path = path_so_far.join(file_node.get_name());
+              try_future!(outputs.check_file(&path));
+              let digest: Digest = try_future!(file_node.get_digest().into());
+              store
+                .load_file_bytes_with(digest, |bytes| digest_of(&bytes))
+                .and_then(move |maybe_actual_digest| match maybe_actual_digest {
+                  Some(actual_digest) if actual_digest == digest => Ok(()),
+                  Some(actual_digest) => Err(format!(
+                    "Output file {:?} was recorded with digest {:?}, but has digest {:?}",
+                    path, digest, actual_digest
+                  )),
+                  None => Err(format!(
+                    "Couldn't find output file {:?} with digest {:?}",
+                    path, digest
+                  )),
+                })
+                .to_boxed()
+            })
+            .collect::<Vec<_>>(),
+        )
+        .map(|_| ())
+        .to_boxed()
+      })
+    })
+    .map(|_| ())
+    .to_boxed()
+}
+
+struct DeclaredOutputs {
+  files: BTreeSet<PathBuf>,
+  directories: BTreeSet<PathBuf>,
+}
+
+impl DeclaredOutputs {
+  ///
+  /// Files must either be declared output files, or be within declared output directories.
+  ///
+  fn check_file(&self, path: &Path) -> Result<(), String> {
+    if self.files.contains(path) || self.is_within_output_directory(path) {
+      Ok(())
+    } else if self.directories.contains(path) {
+      Err(format!(
+        "Output directory {:?} was returned as a file",
+        path
+      ))
+    } else {
+      Err(format!("Undeclared output file {:?}", path))
+    }
+  }
+
+  ///
+  /// Directories must either be declared output directories, be within them, or contain declared
+  /// outputs.
+  ///
+  fn check_directory(&self, path: &Path) -> Result<(), String> {
+    if self.directories.contains(path)
+      || self.is_within_output_directory(path)
+      || self
+        .files
+        .iter()
+        .chain(&self.directories)
+        .any(|output| output != path && output.starts_with(path))
+    {
+      Ok(())
+    } else if self.files.contains(path) {
+      Err(format!(
+        "Output file {:?} was returned as a directory",
+        path
+      ))
+    } else {
+      Err(format!("Undeclared output directory {:?}", path))
+    }
+  }
+
+  fn is_within_output_directory(&self, path: &Path) -> bool {
+    self
+      .directories
+      .iter()
+      .any(|directory| directory != path && path.starts_with(directory))
+  }
+}
+
+///
+/// Strips `.` components, which declared outputs may have, but paths in Directories don't.
+///
+fn normalize(path: &Path) -> PathBuf {
+  path
+    .components()
+    .filter(|component| *component != Component::CurDir)
+    .collect()
+}
+
+fn digest_of(bytes: &[u8]) -> Digest {
+  let mut hasher = Sha256::default();
+  hasher.input(bytes);
+  Digest(
+    Fingerprint::from_bytes_unsafe(&hasher.fixed_result()),
+    bytes.len(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::verify_output_directory;
+  use fs::Store;
+  use hashing::Digest;
+  use std::collections::BTreeSet;
+  use std::path::PathBuf;
+  use std::time::Duration;
+  use tempfile::TempDir;
+  use testutil::data::{TestData, TestDirectory};
+
+  fn paths(paths: &[&str]) -> BTreeSet<PathBuf> {
+    paths.iter().map(PathBuf::from).collect()
+  }
+
+  fn local_store(dir: &TempDir) -> Store {
+    let store = Store::local_only(dir.path()).unwrap();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime
+      .block_on(store.store_file_bytes(TestData::roland().bytes(), false))
+      .unwrap();
+    runtime
+      .block_on(store.store_file_bytes(TestData::catnip().bytes(), false))
+      .unwrap();
+    for directory in &[
+      TestDirectory::containing_roland(),
+      TestDirectory::recursive(),
+    ] {
+      runtime
+        .block_on(store.record_directory(&directory.directory(), false))
+        .unwrap();
+    }
+    store
+  }
+
+  fn verify(
+    store: &Store,
+    output_files: &[&str],
+    output_directories: &[&str],
+    output_directory: Digest,
+  ) -> Result<(), String> {
+    tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(verify_output_directory(
+        store,
+        &paths(output_files),
+        &paths(output_directories),
+        output_directory,
+      ))
+  }
+
+  #[test]
+  fn declared_outputs() {
+    let dir = TempDir::new().unwrap();
+    let store = local_store(&dir);
+    let recursive = TestDirectory::recursive().digest();
+
+    verify(&store, &["treats", "cats/roland"], &[], recursive).unwrap();
+    verify(&store, &["./treats"], &["cats"], recursive).unwrap();
+    verify(
+      &store,
+      &["treats", "cats/roland", "missing"],
+      &[],
+      recursive,
+    )
+    .unwrap();
+    verify(&store, &[], &[], fs::EMPTY_DIGEST).unwrap();
+  }
+
+  #[test]
+  fn undeclared_outputs() {
+    let dir = TempDir::new().unwrap();
+    let store = local_store(&dir);
+    let recursive = TestDirectory::recursive().digest();
+
+    let err = verify(&store, &["cats/roland"], &[], recursive).expect_err("Want Err");
+    assert!(err.contains("Undeclared output file \"treats\""), "{}", err);
+    let err = verify(&store, &["treats"], &[], recursive).expect_err("Want Err");
+    assert!(
+      err.contains("Undeclared output directory \"cats\""),
+      "{}",
+      err
+    );
+    let err = verify(&store, &["treats", "cats"], &[], recursive).expect_err("Want Err");
+    assert!(err.contains("returned as a directory"), "{}", err);
+    let err = verify(&store, &[], &["treats", "cats"], recursive).expect_err("Want Err");
+    assert!(err.contains("returned as a file"), "{}", err);
+  }
+
+  #[test]
+  fn corrupt_outputs() {
+    let cas = mock::StubCAS::builder()
+      .directory(&TestDirectory::containing_roland())
+      .unverified_content(TestData::roland().fingerprint(), TestData::catnip().bytes())
+      .build();
+    let dir = TempDir::new().unwrap();
+    let timer_thread = resettable::Resettable::new(|| futures_timer::HelperThread::new().unwrap());
+    let store = Store::with_remote(
+      dir.path(),
+      &[cas.address()],
+      None,
+      &None,
+      &None,
+      &None,
+      fs::ChannelPolicy::default(),
+      None,
+      None,
+      fs::Compression::None,
+      1,
+      10 * 1024 * 1024,
+      Duration::from_secs(1),
+      Duration::from_secs(1),
+      Duration::from_secs(1),
+      fs::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      fs::LoadBalancing::RoundRobin,
+      1,
+      timer_thread.with(|t| t.handle()),
+    )
+    .unwrap();
+
+    let err = verify(
+      &store,
+      &["roland"],
+      &[],
+      TestDirectory::containing_roland().digest(),
+    )
+    .expect_err("Want Err for corrupt output");
+    assert!(err.contains("wrong digest"), "{}", err);
+  }
+
+  #[test]
+  fn missing_outputs() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::local_only(dir.path()).unwrap();
+    tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(store.record_directory(&TestDirectory::containing_roland().directory(), false))
+      .unwrap();
+
+    let err = verify(
+      &store,
+      &["roland"],
+      &[],
+      TestDirectory::containing_roland().digest(),
+    )
+    .expect_err("Want Err for missing output");
+    assert!(
+      err.contains("Couldn't find output file \"roland\""),
+      "{}",
+      err
+    );
+  }
+}
@@ -37,7 +37,7 @@ use futures::future::Future;
 use hashing::{Digest, Fingerprint};
 use std::collections::{BTreeMap, BTreeSet};
 use std::iter::Iterator;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::time::Duration;
 
@@ -58,6 +58,38 @@ fn main() {
         .takes_value(true)
         .help("Path to workdir"),
     )
+    .arg(
+      Arg::with_name("keep-sandboxes")
+        .long("keep-sandboxes")
+        .takes_value(true)
+        .possible_values(&["never", "on-failure", "always"])
+        .default_value("never")
+        .help("When to keep the sandbox of a local process, with a __run.sh script which reproduces the process."),
+    )
+    .arg(
+      Arg::with_name("run-as-ids")
+        .long("run-as-ids")
+        .takes_value(true)
+        .required(false)
+        .conflicts_with("hide-path")
+        .help("UID:GID to run a local process as, which requires privileges to switch to."),
+    )
+    .arg(
+      Arg::with_name("hide-path")
+        .long("hide-path")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .required(false)
+        .help("Path (e.g. your home directory) to hide from a local process, by running it in new user and mount namespaces (Linux only)."),
+    )
+    .arg(
+      Arg::with_name("trace-with")
+        .long("trace-with")
+        .takes_value(true)
+        .required(false)
+        .help("Path to a tracer (strace or dtruss) to run a local process under, printing the digest of its trace to stderr."),
+    )
     .arg(
       Arg::with_name("local-store-path")
         .long("local-store-path")
@@ -68,16 +100,29 @@ fn main() {
       Arg::with_name("input-digest")
         .long("input-digest")
         .takes_value(true)
-        .required(true)
+        .required_unless("import-input-archive")
         .help("Fingerprint (hex string) of the digest to use as the input file tree."),
     )
     .arg(
       Arg::with_name("input-digest-length")
         .long("input-digest-length")
         .takes_value(true)
-        .required(true)
+        .required_unless("import-input-archive")
         .help("Length of the proto-bytes whose digest to use as the input file tree."),
     )
+    .arg(
+      Arg::with_name("import-input-archive")
+        .long("import-input-archive")
+        .takes_value(true)
+        .conflicts_with_all(&["input-digest", "input-digest-length"])
+        .help("Path to an archive (written by --export-input-archive) to import into the local store, and whose root to use as the input file tree."),
+    )
+    .arg(
+      Arg::with_name("export-input-archive")
+        .long("export-input-archive")
+        .takes_value(true)
+        .help("Path to write an archive of the input file tree (and everything in it, fetched from the CAS if necessary) to before running the process, e.g. to reproduce the process on another machine with --import-input-archive."),
+    )
     .arg(
       Arg::with_name("server")
         .long("server")
@@ -94,6 +139,22 @@ fn main() {
             .long("execution-root-ca-cert-file")
             .required(false)
       )
+      .arg(
+        Arg::with_name("execution-client-cert-file")
+            .help("Path to file containing a certificate chain with which to authenticate to the execution server, if it requires mutual TLS.")
+            .takes_value(true)
+            .long("execution-client-cert-file")
+            .required(false)
+            .requires("execution-client-key-file")
+      )
+      .arg(
+        Arg::with_name("execution-client-key-file")
+            .help("Path to file containing the private key of --execution-client-cert-file.")
+            .takes_value(true)
+            .long("execution-client-key-file")
+            .required(false)
+            .requires("execution-client-cert-file")
+      )
       .arg(
         Arg::with_name("execution-oauth-bearer-token-path")
             .help("Path to file containing oauth bearer token for communication with the execution server. If not set, no authorization will be provided to remote servers.")
@@ -101,6 +162,21 @@ fn main() {
             .long("execution-oauth-bearer-token-path")
             .required(false)
       )
+      .arg(
+        Arg::with_name("proxy")
+            .help("The HTTP proxy through which to connect to the execution and CAS servers, as [http://][user:password@]host[:port]. If not set, the proxy (if any) is taken from $https_proxy or $http_proxy.")
+            .takes_value(true)
+            .long("proxy")
+            .required(false)
+      )
+      .arg(
+        Arg::with_name("keepalive-interval-seconds")
+            .help("How often to ping the remote servers (in seconds) to detect connections which have silently died, which the servers must permit. 0 disables keepalive pings.")
+            .takes_value(true)
+            .long("keepalive-interval-seconds")
+            .required(false)
+            .default_value("0")
+      )
       .arg(
       Arg::with_name("cas-server")
         .long("cas-server")
@@ -114,6 +190,22 @@ fn main() {
             .long("cas-root-ca-cert-file")
             .required(false)
       )
+      .arg(
+        Arg::with_name("cas-client-cert-file")
+            .help("Path to file containing a certificate chain with which to authenticate to the CAS server, if it requires mutual TLS.")
+            .takes_value(true)
+            .long("cas-client-cert-file")
+            .required(false)
+            .requires("cas-client-key-file")
+      )
+      .arg(
+        Arg::with_name("cas-client-key-file")
+            .help("Path to file containing the private key of --cas-client-cert-file.")
+            .takes_value(true)
+            .long("cas-client-key-file")
+            .required(false)
+            .requires("cas-client-cert-file")
+      )
       .arg(
         Arg::with_name("cas-oauth-bearer-token-path")
             .help("Path to file containing oauth bearer token for communication with the CAS server. If not set, no authorization will be provided to remote servers.")
@@ -121,6 +213,14 @@ fn main() {
             .long("cas-oauth-bearer-token-path")
             .required(false)
       )
+      .arg(
+        Arg::with_name("cas-compression")
+            .help("How to compress messages to the CAS server, which must support the chosen algorithm.")
+            .takes_value(true)
+            .long("cas-compression")
+            .possible_values(&["none", "deflate", "gzip"])
+            .default_value("none")
+      )
       .arg(Arg::with_name("remote-instance-name")
           .takes_value(true)
           .long("remote-instance-name")
@@ -137,6 +237,23 @@ fn main() {
             .required(false)
             .default_value("3145728") // 3MB
       )
+      .arg(
+        Arg::with_name("rpc-retries")
+            .help("Number of times to retry RPCs to the execution and CAS servers which fail with transient errors, with exponential backoff.")
+            .takes_value(true)
+            .long("rpc-retries")
+            .required(false)
+            .default_value("3")
+      )
+      .arg(
+        Arg::with_name("priority")
+            .help("The priority with which to execute the process remotely: lower values run sooner, and 0 is the server's default.")
+            .takes_value(true)
+            .long("priority")
+            .allow_hyphen_values(true)
+            .required(false)
+            .default_value("0")
+      )
     .arg(
       Arg::with_name("extra-platform-property")
         .long("extra-platform-property")
@@ -144,6 +261,26 @@ fn main() {
         .multiple(true)
         .help("Extra platform properties to set on the execution request."),
     )
+    .arg(
+      Arg::with_name("execution-header")
+        .long("execution-header")
+        .takes_value(true)
+        .multiple(true)
+        .help("Extra gRPC metadata headers to send with the execution request, as name=value."),
+    )
+    .arg(
+      Arg::with_name("instance-route")
+        .long("instance-route")
+        .takes_value(true)
+        .multiple(true)
+        .help("Routes processes with a platform property to another instance name, as property=value:instance_name. The first matching route wins."),
+    )
+    .arg(
+      Arg::with_name("verify-results")
+        .long("verify-results")
+        .takes_value(false)
+        .help("Download and re-hash the outputs of the remote execution, and check that they are the declared outputs, before accepting its result."),
+    )
     .arg(
       Arg::with_name("env")
         .long("env")
@@ -156,7 +293,7 @@ fn main() {
             .long("jdk")
             .takes_value(true)
             .required(false)
-            .help("Symlink a JDK from .jdk in the working directory. For local execution, symlinks to the value of this flag. For remote execution, just requests that some JDK is symlinked if this flag has any value. https://github.com/pantsbuild/pants/issues/6416 will make this less weird in the future.")
+            .help("Mount a JDK at .jdk in the working directory. For local execution, symlinks to the value of this flag. For remote execution, just requests that the worker provides some JDK if this flag has any value.")
       )
     .setting(AppSettings::TrailingVarArg)
     .arg(
@@ -214,6 +351,12 @@ fn main() {
   let timer_thread = resettable::Resettable::new(|| futures_timer::HelperThread::new().unwrap());
   let server_arg = args.value_of("server");
   let remote_instance_arg = args.value_of("remote-instance-name").map(str::to_owned);
+  let proxy = fs::Proxy::given_or_from_env(args.value_of("proxy")).expect("Invalid proxy");
+  let channel_policy = fs::ChannelPolicy::with_keepalive_interval_secs(
+    value_t!(args.value_of("keepalive-interval-seconds"), u64)
+      .expect("Bad keepalive-interval-seconds flag"),
+  )
+  .expect("Invalid keepalive interval");
   let output_files = if let Some(values) = args.values_of("output-file-path") {
     values.map(PathBuf::from).collect()
   } else {
@@ -229,6 +372,8 @@ fn main() {
     (Some(_server), Some(cas_server)) => {
       let chunk_size =
         value_t!(args.value_of("upload-chunk-bytes"), usize).expect("Bad upload-chunk-bytes flag");
+      let rpc_retries =
+        value_t!(args.value_of("rpc-retries"), usize).expect("Bad rpc-retries flag");
 
       let root_ca_certs = if let Some(path) = args.value_of("cas-root-ca-cert-file") {
         Some(std::fs::read(path).expect("Error reading root CA certs file"))
@@ -237,7 +382,10 @@ fn main() {
       };
 
       let oauth_bearer_token = if let Some(path) = args.value_of("cas-oauth-bearer-token-path") {
-        Some(std::fs::read_to_string(path).expect("Error reading oauth bearer token file"))
+        Some(
+          fs::BearerToken::new(fs::TokenSource::File(PathBuf::from(path)))
+            .expect("Error reading oauth bearer token file"),
+        )
       } else {
         None
       };
@@ -247,13 +395,22 @@ fn main() {
         &[cas_server.to_owned()],
         remote_instance_arg.clone(),
         &root_ca_certs,
+        &client_certificate(&args, "cas"),
+        &proxy,
+        channel_policy,
         oauth_bearer_token,
+        None,
+        value_t!(args.value_of("cas-compression"), fs::Compression)
+          .expect("Bad cas-compression flag"),
         1,
         chunk_size,
         Duration::from_secs(30),
+        Duration::from_secs(30),
+        Duration::from_secs(30),
         // TODO: Take a command line arg.
         fs::BackoffConfig::new(Duration::from_secs(1), 1.2, Duration::from_secs(20)).unwrap(),
-        3,
+        fs::LoadBalancing::RoundRobin,
+        rpc_retries,
         timer_thread.with(futures_timer::HelperThread::handle),
       )
     }
@@ -262,7 +419,12 @@ fn main() {
   }
   .expect("Error making store");
 
-  let input_files = {
+  let input_files = if let Some(archive_path) = args.value_of("import-input-archive") {
+    store
+      .import_archive(Path::new(archive_path))
+      .wait()
+      .expect("Error importing input archive")
+  } else {
     let fingerprint = Fingerprint::from_hex_string(args.value_of("input-digest").unwrap())
       .expect("Bad input-digest");
     let length = args
@@ -273,16 +435,28 @@ fn main() {
     Digest(fingerprint, length)
   };
 
-  let request = process_execution::ExecuteProcessRequest {
+  if let Some(archive_path) = args.value_of("export-input-archive") {
+    store
+      .export_archive(input_files, Path::new(archive_path))
+      .wait()
+      .expect("Error exporting input archive");
+  }
+
+  let request = process_execution::ExecuteProcessRequestBuilder::new(
     argv,
-    env,
-    input_files,
-    output_files,
-    output_directories,
-    timeout: Duration::new(15 * 60, 0),
-    description: "process_executor".to_string(),
-    jdk_home: args.value_of("jdk").map(PathBuf::from),
-  };
+    Duration::new(15 * 60, 0),
+    "process_executor".to_string(),
+  )
+  .env(env)
+  .input_files(input_files)
+  .output_files(output_files)
+  .output_directories(output_directories)
+  .tool_mounts(process_execution::jdk_tool_mounts(
+    args.value_of("jdk").map(PathBuf::from),
+  ))
+  .priority(value_t!(args.value_of("priority"), i32).expect("Bad priority flag"))
+  .build()
+  .expect("Invalid process");
 
   let runner: Box<dyn process_execution::CommandRunner> = match server_arg {
     Some(address) => {
@@ -294,28 +468,93 @@ fn main() {
 
       let oauth_bearer_token =
         if let Some(path) = args.value_of("execution-oauth-bearer-token-path") {
-          Some(std::fs::read_to_string(path).expect("Error reading oauth bearer token file"))
+          Some(
+            fs::BearerToken::new(fs::TokenSource::File(PathBuf::from(path)))
+              .expect("Error reading oauth bearer token file"),
+          )
         } else {
           None
         };
 
-      Box::new(process_execution::remote::CommandRunner::new(
-        address,
-        args.value_of("cache-key-gen-version").map(str::to_owned),
-        remote_instance_arg,
-        root_ca_certs,
-        oauth_bearer_token,
-        platform_properties,
-        1,
-        store.clone(),
-        timer_thread,
-      )) as Box<dyn process_execution::CommandRunner>
+      Box::new(
+        process_execution::remote::CommandRunner::new(
+          address,
+          args.value_of("cache-key-gen-version").map(str::to_owned),
+          remote_instance_arg,
+          root_ca_certs,
+          client_certificate(&args, "execution"),
+          proxy,
+          channel_policy,
+          oauth_bearer_token,
+          None,
+          platform_properties,
+          1,
+          value_t!(args.value_of("rpc-retries"), usize).expect("Bad rpc-retries flag"),
+          // The deadline of the whole execution applies, too.
+          Duration::from_secs(15 * 60),
+          store.clone(),
+          timer_thread,
+        )
+        .with_instance_routes(
+          args
+            .values_of("instance-route")
+            .map(|routes| {
+              routes
+                .map(|route| route.parse().expect("Bad instance-route flag"))
+                .collect()
+            })
+            .unwrap_or_default(),
+        )
+        .with_execution_headers(
+          args
+            .values_of("execution-header")
+            .map(btreemap_from_keyvalues)
+            .unwrap_or_default(),
+        )
+        .expect("Bad execution-header flag")
+        .with_result_verification(args.is_present("verify-results")),
+      ) as Box<dyn process_execution::CommandRunner>
+    }
+    None => {
+      let local =
+        process_execution::local::CommandRunner::new(store.clone(), work_dir.clone(), true)
+          .with_keep_sandboxes(
+            value_t!(
+              args.value_of("keep-sandboxes"),
+              process_execution::local::KeepSandboxes
+            )
+            .expect("Bad keep-sandboxes flag"),
+          );
+      let process_user = if let Some(ids) = args.value_of("run-as-ids") {
+        let mut parts = ids.splitn(2, ':').map(str::parse::<u32>);
+        match (parts.next(), parts.next()) {
+          (Some(Ok(uid)), Some(Ok(gid))) => {
+            Some(process_execution::local::ProcessUser::Ids { uid, gid })
+          }
+          _ => panic!("Bad run-as-ids flag: expected UID:GID, but got {:?}", ids),
+        }
+      } else if let Some(hidden_paths) = args.values_of("hide-path") {
+        Some(process_execution::local::ProcessUser::Namespaced {
+          hidden_paths: hidden_paths.map(PathBuf::from).collect(),
+        })
+      } else {
+        None
+      };
+      let local = match process_user {
+        Some(process_user) => local.with_process_user(process_user),
+        None => local,
+      };
+      match args.value_of("trace-with") {
+        Some(tracer) => Box::new(process_execution::trace::CommandRunner::new(
+          local,
+          store.clone(),
+          process_execution::trace::Tracer::for_binary(PathBuf::from(tracer))
+            .expect("Bad trace-with flag"),
+          work_dir,
+        )) as Box<dyn process_execution::CommandRunner>,
+        None => Box::new(local) as Box<dyn process_execution::CommandRunner>,
+      }
     }
-    None => Box::new(process_execution::local::CommandRunner::new(
-      store.clone(),
-      work_dir,
-      true,
-    )) as Box<dyn process_execution::CommandRunner>,
   };
 
   let result = runner.run(request).wait().expect("Error executing");
@@ -327,11 +566,24 @@ fn main() {
       .unwrap();
   }
 
+  if let Some(trace) = result.trace {
+    eprintln!("Trace: {} {}", trace.0, trace.1);
+  }
+
   print!("{}", String::from_utf8(result.stdout.to_vec()).unwrap());
   eprint!("{}", String::from_utf8(result.stderr.to_vec()).unwrap());
   exit(result.exit_code);
 }
 
+fn client_certificate(args: &clap::ArgMatches<'_>, prefix: &str) -> Option<fs::ClientCertificate> {
+  let cert_path = args.value_of(format!("{}-client-cert-file", prefix))?;
+  let key_path = args.value_of(format!("{}-client-key-file", prefix))?;
+  Some(fs::ClientCertificate {
+    cert_chain: std::fs::read(cert_path).expect("Error reading client cert file"),
+    private_key: std::fs::read(key_path).expect("Error reading client key file"),
+  })
+}
+
 fn btreemap_from_keyvalues<'a, It: Iterator<Item = &'a str>>(
   keyvalues: It,
 ) -> BTreeMap<String, String> {
@@ -33,12 +33,13 @@ use boxfuture::{BoxFuture, Boxable};
 use futures::Future;
 use futures_timer::Delay;
 use parking_lot::Mutex;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 mod retry;
-pub use crate::retry::Retry;
+pub use crate::retry::{AttemptError, Retry, RetryPolicy};
 
 ///
 /// A collection of resources which are observed to be healthy or unhealthy.
@@ -80,9 +81,42 @@ struct Inner<T> {
 
   backoff_config: BackoffConfig,
 
+  load_balancing: LoadBalancing,
+
   timer_handle: futures_timer::TimerHandle,
 }
 
+///
+/// How a Serverset chooses between its healthy resources.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LoadBalancing {
+  ///
+  /// Spread load across every healthy resource in turn.
+  ///
+  RoundRobin,
+  ///
+  /// Use the first healthy resource, in the order in which they were given, so that later
+  /// resources are only used while earlier resources are unhealthy.
+  ///
+  PickFirst,
+}
+
+impl FromStr for LoadBalancing {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<LoadBalancing, String> {
+    match s {
+      "round-robin" => Ok(LoadBalancing::RoundRobin),
+      "pick-first" => Ok(LoadBalancing::PickFirst),
+      _ => Err(format!(
+        "Expected one of round-robin or pick-first load balancing, but got {:?}",
+        s
+      )),
+    }
+  }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum Health {
   Healthy,
@@ -185,6 +219,7 @@ impl<T: Clone + Send + Sync + 'static> Serverset<T> {
   pub fn new(
     servers: Vec<T>,
     backoff_config: BackoffConfig,
+    load_balancing: LoadBalancing,
     timer_handle: futures_timer::TimerHandle,
   ) -> Result<Self, String> {
     if servers.is_empty() {
@@ -202,13 +237,14 @@ impl<T: Clone + Send + Sync + 'static> Serverset<T> {
           .collect(),
         next: AtomicUsize::new(0),
         backoff_config,
+        load_balancing,
         timer_handle,
       }),
     })
   }
 
   ///
-  /// Get the next (probably) healthy backend to use.
+  /// Get the next (probably) healthy backend to use, according to the LoadBalancing strategy.
   ///
   /// The caller will be given a backend to use, and should call Serverset::report_health with the
   /// supplied token, and the observed health of that backend.
@@ -227,8 +263,11 @@ impl<T: Clone + Send + Sync + 'static> Serverset<T> {
     let server_count = self.inner.servers.len();
 
     let mut earliest_future = None;
-    for _ in 0..server_count {
-      let i = self.inner.next.fetch_add(1, Ordering::Relaxed) % server_count;
+    for offset in 0..server_count {
+      let i = match self.inner.load_balancing {
+        LoadBalancing::RoundRobin => self.inner.next.fetch_add(1, Ordering::Relaxed) % server_count,
+        LoadBalancing::PickFirst => offset,
+      };
       let server = &self.inner.servers[i];
       let unhealthy_info = server.unhealthy_info.lock();
       if let Some(ref unhealthy_info) = *unhealthy_info {
@@ -296,7 +335,7 @@ impl<T: std::fmt::Debug> std::fmt::Debug for Serverset<T> {
 
 #[cfg(test)]
 mod tests {
-  use super::{BackoffConfig, Health, Serverset};
+  use super::{BackoffConfig, Health, LoadBalancing, Serverset};
   use futures::{self, Future};
   use futures_timer::TimerHandle;
   use parking_lot::Mutex;
@@ -313,8 +352,13 @@ mod tests {
   #[test]
   fn no_servers_is_error() {
     let servers: Vec<String> = vec![];
-    Serverset::new(servers, backoff_config(), TimerHandle::default())
-      .expect_err("Want error constructing with no servers");
+    Serverset::new(
+      servers,
+      backoff_config(),
+      LoadBalancing::RoundRobin,
+      TimerHandle::default(),
+    )
+    .expect_err("Want error constructing with no servers");
   }
 
   #[test]
@@ -322,6 +366,7 @@ mod tests {
     let s = Serverset::new(
       vec!["good", "bad"],
       backoff_config(),
+      LoadBalancing::RoundRobin,
       TimerHandle::default(),
     )
     .unwrap();
@@ -334,6 +379,7 @@ mod tests {
     let s = Serverset::new(
       vec!["good", "bad"],
       backoff_config(),
+      LoadBalancing::RoundRobin,
       TimerHandle::default(),
     )
     .unwrap();
@@ -355,6 +401,7 @@ mod tests {
     let s = Serverset::new(
       vec!["good", "bad"],
       backoff_config(),
+      LoadBalancing::RoundRobin,
       TimerHandle::default(),
     )
     .unwrap();
@@ -371,6 +418,7 @@ mod tests {
     let s = Serverset::new(
       vec!["good", "bad"],
       backoff_config(),
+      LoadBalancing::RoundRobin,
       TimerHandle::default(),
     )
     .unwrap();
@@ -387,6 +435,7 @@ mod tests {
     let s = Serverset::new(
       vec!["good", "bad"],
       backoff_config(),
+      LoadBalancing::RoundRobin,
       TimerHandle::default(),
     )
     .unwrap();
@@ -416,10 +465,51 @@ mod tests {
     expect_both(&s, 2);
   }
 
+  #[test]
+  fn picks_first_healthy() {
+    let s = Serverset::new(
+      vec!["good", "bad"],
+      backoff_config(),
+      LoadBalancing::PickFirst,
+      TimerHandle::default(),
+    )
+    .unwrap();
+
+    expect_only_good(&s, Duration::from_millis(10));
+
+    let (server, token) = s.next().wait().unwrap();
+    assert_eq!(server, "good");
+    s.report_health(token, Health::Unhealthy);
+    let (server, token) = s.next().wait().unwrap();
+    assert_eq!(server, "bad");
+    s.report_health(token, Health::Healthy);
+
+    // Once its backoff has passed, the first server is preferred again.
+    std::thread::sleep(Duration::from_millis(20));
+    let (server, token) = s.next().wait().unwrap();
+    assert_eq!(server, "good");
+    s.report_health(token, Health::Healthy);
+  }
+
+  #[test]
+  fn parse_load_balancing() {
+    assert_eq!("round-robin".parse(), Ok(LoadBalancing::RoundRobin));
+    assert_eq!("pick-first".parse(), Ok(LoadBalancing::PickFirst));
+    "random"
+      .parse::<LoadBalancing>()
+      .expect_err("Want Err for unsupported load balancing");
+  }
+
   #[test]
   fn waits_if_all_unhealthy() {
     let backoff_config = backoff_config();
-    let s = Serverset::new(vec!["good", "bad"], backoff_config, TimerHandle::default()).unwrap();
+    let s = Serverset::new(
+      vec!["good", "bad"],
+      backoff_config,
+      LoadBalancing::RoundRobin,
+      TimerHandle::default(),
+    )
+    .unwrap();
 
     for _ in 0..2 {
       s.next()
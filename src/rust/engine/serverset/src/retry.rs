@@ -1,8 +1,100 @@
 use crate::{Health, Serverset};
+use boxfuture::{BoxFuture, Boxable};
 use futures::{self, Future, IntoFuture};
+use futures_timer::Delay;
+use rand::Rng;
+use std::cmp::min;
+use std::time::{Duration, Instant};
 
 pub struct Retry<T>(pub Serverset<T>);
 
+///
+/// The failure of an attempt, which may be worth retrying (e.g. because the server was unavailable)
+/// or not (e.g. because the request was invalid).
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AttemptError {
+  pub message: String,
+  pub retryable: bool,
+}
+
+impl AttemptError {
+  pub fn retryable(message: String) -> AttemptError {
+    AttemptError {
+      message,
+      retryable: true,
+    }
+  }
+
+  pub fn fatal(message: String) -> AttemptError {
+    AttemptError {
+      message,
+      retryable: false,
+    }
+  }
+}
+
+impl From<String> for AttemptError {
+  fn from(message: String) -> AttemptError {
+    AttemptError::fatal(message)
+  }
+}
+
+///
+/// How failed attempts are retried. After each retryable failure, the next attempt is delayed by a
+/// backoff which doubles with each retry (up to a cap), half of which is random jitter so that
+/// clients which failed together don't retry together. Attempts stop once the budget is used up.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+  initial_backoff: Duration,
+  max_backoff: Duration,
+  max_attempts: usize,
+}
+
+impl RetryPolicy {
+  pub fn new(
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_attempts: usize,
+  ) -> Result<RetryPolicy, String> {
+    if max_attempts == 0 {
+      return Err("Retry policies must allow at least one attempt".to_owned());
+    }
+    if initial_backoff > max_backoff {
+      return Err(format!(
+        "Initial backoff {:?} must not exceed max backoff {:?}",
+        initial_backoff, max_backoff
+      ));
+    }
+    Ok(RetryPolicy {
+      initial_backoff,
+      max_backoff,
+      max_attempts,
+    })
+  }
+
+  pub fn max_attempts(&self) -> usize {
+    self.max_attempts
+  }
+
+  ///
+  /// The delay before the given retry (counting from 1): a random duration between half and all of
+  /// the capped exponential backoff for it.
+  ///
+  pub fn backoff(&self, retry: usize) -> Duration {
+    let exponent = min(retry.saturating_sub(1), 31) as u32;
+    let backoff = self
+      .initial_backoff
+      .checked_mul(1 << exponent)
+      .map(|backoff| min(backoff, self.max_backoff))
+      .unwrap_or(self.max_backoff);
+    let half = backoff / 2;
+    let half_nanos = half.as_secs() * 1_000_000_000 + u64::from(half.subsec_nanos());
+    half + Duration::from_nanos(rand::thread_rng().gen_range(0, half_nanos + 1))
+  }
+}
+
 impl<T: Clone + Send + Sync + 'static> Retry<T> {
   ///
   /// Runs `f` up to `times` times, taking servers from the underlying serverset.
@@ -46,20 +138,90 @@ impl<T: Clone + Send + Sync + 'static> Retry<T> {
         })
     })
   }
+
+  ///
+  /// Runs `f`, taking servers from the underlying serverset, until it succeeds, fails with an error
+  /// which isn't retryable, or has been attempted as many times as the policy allows. After each
+  /// retryable failure (for which the server is reported unhealthy), the next attempt is delayed by
+  /// the policy's backoff.
+  ///
+  /// Returns the value, along with the number of retries it took.
+  ///
+  pub fn with_policy<
+    Value: Send + 'static,
+    Fut: Future<Item = Value, Error = AttemptError>,
+    IntoFut: IntoFuture<Future = Fut, Item = Value, Error = AttemptError>,
+    F: FnMut(T) -> IntoFut + Send + Sync + Clone + 'static,
+  >(
+    &self,
+    f: F,
+    policy: RetryPolicy,
+  ) -> impl Future<Item = (Value, usize), Error = String> {
+    let serverset = self.0.clone();
+    futures::future::loop_fn(0_usize, move |retries| {
+      let serverset = serverset.clone();
+      let serverset2 = serverset.clone();
+      let f = f.clone();
+      let delay: BoxFuture<(), String> = if retries == 0 {
+        futures::future::ok(()).to_boxed()
+      } else {
+        Delay::new_handle(
+          Instant::now() + policy.backoff(retries),
+          serverset.inner.timer_handle.clone(),
+        )
+        .map_err(|err| format!("Error delaying before retrying: {}", err))
+        .to_boxed()
+      };
+      delay
+        .and_then(move |()| serverset.next())
+        .map_err(AttemptError::fatal)
+        .and_then(move |(server, token)| {
+          futures::future::ok::<_, AttemptError>(server)
+            .and_then(f)
+            .then(move |result| {
+              let health = match &result {
+                &Err(ref err) if err.retryable => Health::Unhealthy,
+                _ => Health::Healthy,
+              };
+              serverset2.report_health(token, health);
+              result
+            })
+        })
+        .then(move |result| match result {
+          Ok(value) => Ok(futures::future::Loop::Break((value, retries))),
+          Err(ref err) if err.retryable && retries + 1 < policy.max_attempts => {
+            Ok(futures::future::Loop::Continue(retries + 1))
+          }
+          Err(err) => {
+            if retries == 0 {
+              Err(err.message)
+            } else {
+              Err(format!(
+                "Failed after {} retries; last failure: {}",
+                retries, err.message
+              ))
+            }
+          }
+        })
+    })
+  }
 }
 
 #[cfg(test)]
 mod tests {
-  use crate::{BackoffConfig, Retry, Serverset};
+  use crate::{AttemptError, BackoffConfig, LoadBalancing, Retry, RetryPolicy, Serverset};
   use futures::Future;
   use futures_timer::TimerHandle;
-  use std::time::Duration;
+  use parking_lot::Mutex;
+  use std::sync::Arc;
+  use std::time::{Duration, Instant};
 
   #[test]
   fn retries() {
     let s = Serverset::new(
       vec![Ok("good"), Err("bad".to_owned()), Ok("enough")],
       BackoffConfig::new(Duration::from_millis(10), 2.0, Duration::from_millis(100)).unwrap(),
+      LoadBalancing::RoundRobin,
       TimerHandle::default(),
     )
     .unwrap();
@@ -80,6 +242,7 @@ mod tests {
     let s = Serverset::new(
       vec![Err("bad".to_owned())],
       BackoffConfig::new(Duration::from_millis(1), 1.0, Duration::from_millis(1)).unwrap(),
+      LoadBalancing::RoundRobin,
       TimerHandle::default(),
     )
     .unwrap();
@@ -90,4 +253,96 @@ mod tests {
         .wait()
     );
   }
+
+  fn counting_attempts(
+    errors: Vec<AttemptError>,
+  ) -> (
+    Arc<Mutex<usize>>,
+    impl FnMut(&'static str) -> Result<&'static str, AttemptError> + Send + Sync + Clone,
+  ) {
+    let attempts = Arc::new(Mutex::new(0));
+    let attempts2 = attempts.clone();
+    let errors = Arc::new(Mutex::new(errors));
+    let f = move |server| {
+      *attempts2.lock() += 1;
+      let mut errors = errors.lock();
+      if errors.is_empty() {
+        Ok(server)
+      } else {
+        Err(errors.remove(0))
+      }
+    };
+    (attempts, f)
+  }
+
+  fn serverset() -> Serverset<&'static str> {
+    Serverset::new(
+      vec!["server"],
+      BackoffConfig::new(Duration::from_millis(1), 1.0, Duration::from_millis(1)).unwrap(),
+      LoadBalancing::RoundRobin,
+      TimerHandle::default(),
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn with_policy_retries_retryable_errors_with_backoff() {
+    let (attempts, f) = counting_attempts(vec![
+      AttemptError::retryable("unavailable".to_owned()),
+      AttemptError::retryable("unavailable".to_owned()),
+    ]);
+    let policy =
+      RetryPolicy::new(Duration::from_millis(100), Duration::from_millis(1000), 3).unwrap();
+
+    let start = Instant::now();
+    assert_eq!(
+      Retry(serverset()).with_policy(f, policy).wait(),
+      Ok(("server", 2))
+    );
+    assert_eq!(*attempts.lock(), 3);
+    // Backoffs of at least half of 100ms and 200ms.
+    assert!(start.elapsed() >= Duration::from_millis(150));
+  }
+
+  #[test]
+  fn with_policy_does_not_retry_fatal_errors() {
+    let (attempts, f) = counting_attempts(vec![AttemptError::fatal("invalid".to_owned())]);
+    let policy = RetryPolicy::new(Duration::from_millis(1), Duration::from_millis(1), 3).unwrap();
+
+    assert_eq!(
+      Retry(serverset()).with_policy(f, policy).wait(),
+      Err("invalid".to_owned())
+    );
+    assert_eq!(*attempts.lock(), 1);
+  }
+
+  #[test]
+  fn with_policy_gives_up_after_max_attempts() {
+    let (attempts, f) = counting_attempts(vec![
+      AttemptError::retryable("unavailable".to_owned()),
+      AttemptError::retryable("unavailable".to_owned()),
+      AttemptError::retryable("unavailable".to_owned()),
+    ]);
+    let policy = RetryPolicy::new(Duration::from_millis(1), Duration::from_millis(1), 2).unwrap();
+
+    assert_eq!(
+      Retry(serverset()).with_policy(f, policy).wait(),
+      Err("Failed after 1 retries; last failure: unavailable".to_owned())
+    );
+    assert_eq!(*attempts.lock(), 2);
+  }
+
+  #[test]
+  fn backoff_is_capped_and_jittered() {
+    let policy =
+      RetryPolicy::new(Duration::from_millis(100), Duration::from_millis(300), 10).unwrap();
+    for _ in 0..10 {
+      let first = policy.backoff(1);
+      assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+      let capped = policy.backoff(5);
+      assert!(capped >= Duration::from_millis(150) && capped <= Duration::from_millis(300));
+    }
+    RetryPolicy::new(Duration::from_millis(1), Duration::from_millis(1), 0)
+      .expect_err("Want Err for no attempts");
+  }
 }
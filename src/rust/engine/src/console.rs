@@ -0,0 +1,68 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::io::{self, Write};
+use std::mem;
+
+use parking_lot::Mutex;
+use process_execution::local::OutputStream;
+
+///
+/// The output of processes which tee it to the console (see `ExecuteProcessRequest::tee_output`).
+///
+/// While an EngineDisplay is rendering, output is queued so that the rendering loop can emit it
+/// between frames (which would otherwise draw over it). Otherwise, it is written directly to our
+/// own stdout or stderr.
+///
+pub struct TeedOutput {
+  queued: Mutex<Option<Vec<(OutputStream, Vec<u8>)>>>,
+}
+
+impl TeedOutput {
+  pub fn new() -> TeedOutput {
+    TeedOutput {
+      queued: Mutex::new(None),
+    }
+  }
+
+  pub fn write(&self, stream: OutputStream, line: &[u8]) {
+    // Lines are written while holding the lock, so that the lines of concurrent processes don't
+    // interleave.
+    let mut queued = self.queued.lock();
+    if let Some(ref mut queued) = *queued {
+      queued.push((stream, line.to_vec()));
+      return;
+    }
+    let _ = match stream {
+      OutputStream::Stdout => {
+        let mut stdout = io::stdout();
+        stdout.write_all(line).and_then(|()| stdout.flush())
+      }
+      OutputStream::Stderr => io::stderr().write_all(line),
+    };
+  }
+
+  pub fn start_queueing(&self) {
+    let mut queued = self.queued.lock();
+    if queued.is_none() {
+      *queued = Some(vec![]);
+    }
+  }
+
+  ///
+  /// Takes the output which has been queued since the last call.
+  ///
+  pub fn drain(&self) -> Vec<(OutputStream, Vec<u8>)> {
+    match *self.queued.lock() {
+      Some(ref mut queued) => mem::replace(queued, vec![]),
+      None => vec![],
+    }
+  }
+
+  ///
+  /// Stops queueing output, and returns any output which is still queued.
+  ///
+  pub fn stop_queueing(&self) -> Vec<(OutputStream, Vec<u8>)> {
+    self.queued.lock().take().unwrap_or_else(Vec::new)
+  }
+}
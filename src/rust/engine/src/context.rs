@@ -12,6 +12,7 @@ use tokio::runtime::Runtime;
 
 use futures::Future;
 
+use crate::console::TeedOutput;
 use crate::core::{Failure, TypeId};
 use crate::handles::maybe_drop_handles;
 use crate::nodes::{NodeKey, WrappedNode};
@@ -24,7 +25,12 @@ use fs::{self, safe_create_dir_all_ioerror, PosixFS, Store};
 use graph::{EntryId, Graph, NodeContext};
 use log::debug;
 use parking_lot::RwLock;
-use process_execution::{self, BoundedCommandRunner, CommandRunner};
+use process_execution::circuit_breaker::{CircuitBreakerPolicy, CircuitBreakingCommandRunner};
+use process_execution::instance_routing::InstanceRoute;
+use process_execution::local::OutputStream;
+use process_execution::{
+  self, BoundedCommandRunner, CommandRunner, Deadline, Platform, RemoteExecutionTimings,
+};
 use rand::seq::SliceRandom;
 use reqwest;
 use resettable::Resettable;
@@ -49,6 +55,37 @@ pub struct Core {
     Resettable<(Store, BoundedCommandRunner, reqwest::r#async::Client)>,
   pub vfs: PosixFS,
   pub build_root: PathBuf,
+  pub teed_output: Arc<TeedOutput>,
+  ///
+  /// The deadline of the current session (if any), which processes started by it must meet.
+  ///
+  pub process_deadline: Deadline,
+  ///
+  /// The metadata attached to every remote RPC, whose invocation id identifies the current session.
+  ///
+  pub request_metadata: fs::RequestMetadata,
+  ///
+  /// The time which the remote executions of the current session spent queued, executing, etc.
+  ///
+  pub remote_execution_timings: RemoteExecutionTimings,
+  ///
+  /// Whether to ask the remote store to fetch downloaded files with the Remote Asset API, rather
+  /// than downloading them here.
+  ///
+  pub remote_asset_fetch: bool,
+  ///
+  /// The number of bytes to which garbage collection shrinks the local store.
+  ///
+  pub local_store_gc_target_bytes: usize,
+  ///
+  /// Which entries garbage collection evicts from the local store before shrinking it.
+  ///
+  pub local_store_eviction_policy: fs::EvictionPolicy,
+  ///
+  /// Whether garbage collection only logs what the eviction policy would evict, rather than
+  /// evicting it.
+  ///
+  pub local_store_eviction_dry_run: bool,
 }
 
 impl Core {
@@ -64,19 +101,81 @@ impl Core {
     remote_execution_server: Option<String>,
     remote_execution_process_cache_namespace: Option<String>,
     remote_instance_name: Option<String>,
+    remote_instance_routes: Vec<InstanceRoute>,
     remote_root_ca_certs_path: Option<PathBuf>,
+    remote_client_cert_path: Option<PathBuf>,
+    remote_client_key_path: Option<PathBuf>,
     remote_oauth_bearer_token_path: Option<PathBuf>,
+    remote_oauth_bearer_token_helper: Option<PathBuf>,
+    remote_proxy: Option<String>,
+    remote_keepalive_interval: Option<Duration>,
+    remote_keepalive_timeout: Duration,
+    remote_max_reconnect_backoff: Duration,
+    tool_version: String,
+    remote_correlated_invocations_id: Option<String>,
+    remote_store_compression: fs::Compression,
+    remote_store_load_balancing: fs::LoadBalancing,
     remote_store_thread_count: usize,
     remote_store_chunk_bytes: usize,
     remote_store_chunk_upload_timeout: Duration,
+    remote_store_read_timeout: Duration,
+    remote_action_cache_timeout: Duration,
     remote_store_rpc_retries: usize,
+    remote_store_lease_extension_interval: Option<Duration>,
+    remote_execution_rpc_retries: usize,
+    remote_execution_rpc_timeout: Duration,
     remote_execution_extra_platform_properties: BTreeMap<String, String>,
+    remote_execution_headers: BTreeMap<String, String>,
+    remote_execution_circuit_breaker_failures: usize,
+    remote_execution_circuit_breaker_cool_down: Duration,
+    remote_execution_verify_results: bool,
     process_execution_parallelism: usize,
+    process_execution_remote_parallelism: usize,
     process_execution_cleanup_local_dirs: bool,
+    remote_cache_write: bool,
+    remote_cache_read: bool,
+    remote_asset_fetch: bool,
+    local_store_gc_target_bytes: usize,
+    local_store_verify_digests: bool,
+    local_store_compression_threshold_bytes: Option<usize>,
+    local_store_options: fs::LocalStoreOptions,
+    remote_store_small_blob_cache: Option<fs::SmallBlobCache>,
+    local_store_eviction_policy: fs::EvictionPolicy,
+    local_store_eviction_dry_run: bool,
+    local_store_hashing_parallelism: usize,
+    local_store_mmap_threshold_bytes: Option<usize>,
+    remote_store_tiering_policy: fs::TieringPolicy,
+    remote_store_known_blobs_capacity: usize,
+    remote_store_known_blobs_lifetime: Duration,
+    process_execution_local_materialization: fs::MaterializationStrategy,
   ) -> Core {
-    // Randomize CAS address order to avoid thundering herds from common config.
+    // Randomize CAS address order to avoid thundering herds from common config. When picking the
+    // first healthy CAS, the order is the preference, so is kept.
     let mut remote_store_servers = remote_store_servers;
-    remote_store_servers.shuffle(&mut rand::thread_rng());
+    if remote_execution_server.is_some()
+      && remote_store_servers.iter().any(|address| {
+        fs::is_http_cache_url(address) || fs::is_s3_url(address) || fs::is_gcs_url(address)
+      })
+    {
+      panic!("Remote execution requires a gRPC remote store, rather than an HTTP cache or bucket");
+    }
+    // Shared between forks, so that the blobs which each learns of are saved together.
+    let remote_store_known_blobs = if remote_store_servers.is_empty()
+      || remote_store_known_blobs_capacity == 0
+      || remote_store_known_blobs_lifetime == Duration::from_secs(0)
+    {
+      None
+    } else {
+      Some(fs::KnownRemoteBlobs::for_remote(
+        &local_store_dir,
+        &remote_store_servers,
+        remote_store_known_blobs_capacity,
+        remote_store_known_blobs_lifetime,
+      ))
+    };
+    if remote_store_load_balancing == fs::LoadBalancing::RoundRobin {
+      remote_store_servers.shuffle(&mut rand::thread_rng());
+    }
 
     let runtime = Resettable::new(|| {
       Arc::new(RwLock::new(Runtime::new().unwrap_or_else(|e| {
@@ -93,67 +192,207 @@ impl Core {
       None
     };
 
-    // We re-use this token for both the execution and store service; they're generally tied together.
-    let oauth_bearer_token = if let Some(path) = remote_oauth_bearer_token_path {
-      Some(
-        std::fs::read_to_string(&path)
-          .unwrap_or_else(|err| panic!("Error reading root CA certs file {:?}: {}", path, err)),
-      )
-    } else {
-      None
+    // As with the root CA certs, the client certificate is used for both services.
+    let client_certificate = match (remote_client_cert_path, remote_client_key_path) {
+      (Some(cert_path), Some(key_path)) => Some(fs::ClientCertificate {
+        cert_chain: std::fs::read(&cert_path)
+          .unwrap_or_else(|err| panic!("Error reading client cert file {:?}: {}", cert_path, err)),
+        private_key: std::fs::read(&key_path)
+          .unwrap_or_else(|err| panic!("Error reading client key file {:?}: {}", key_path, err)),
+      }),
+      (None, None) => None,
+      _ => panic!("A client cert and a client key must be configured together"),
     };
 
+    // We re-use this token for both the execution and store service; they're generally tied together.
+    // It is refreshed (by re-reading the file, or re-running the helper) whenever an RPC is
+    // rejected as unauthenticated.
+    let oauth_bearer_token = match (
+      remote_oauth_bearer_token_path,
+      remote_oauth_bearer_token_helper,
+    ) {
+      (Some(path), None) => Some(fs::TokenSource::File(path)),
+      (None, Some(helper)) => Some(fs::TokenSource::Helper(helper)),
+      (None, None) => None,
+      _ => panic!("An oauth bearer token file and credential helper can't both be configured"),
+    }
+    .map(|source| {
+      fs::BearerToken::new(source)
+        .unwrap_or_else(|err| panic!("Error loading oauth bearer token: {}", err))
+    });
+
+    // As with the certificates and token, the proxy is used for both services.
+    let proxy = fs::Proxy::given_or_from_env(remote_proxy.as_ref().map(String::as_str))
+      .unwrap_or_else(|err| panic!("Invalid remote proxy: {}", err));
+    let channel_policy = fs::ChannelPolicy::new(
+      remote_keepalive_interval,
+      remote_keepalive_timeout,
+      remote_max_reconnect_backoff,
+    )
+    .unwrap_or_else(|err| panic!("Invalid remote channel policy: {}", err));
+    // Shared by both services (and between forks), so that servers can group all of the RPCs of
+    // each session together.
+    let request_metadata = fs::RequestMetadata::new(tool_version, remote_correlated_invocations_id);
+    let request_metadata2 = request_metadata.clone();
+
     let futures_timer_thread = Resettable::new(|| futures_timer::HelperThread::new().unwrap());
     let futures_timer_thread2 = futures_timer_thread.clone();
+    let teed_output = Arc::new(TeedOutput::new());
+    let teed_output2 = teed_output.clone();
+    let process_deadline = Deadline::default();
+    let process_deadline2 = process_deadline.clone();
     let store_and_command_runner_and_http_client = Resettable::new(move || {
       let local_store_dir = local_store_dir.clone();
       let store = safe_create_dir_all_ioerror(&local_store_dir)
         .map_err(|e| format!("Error making directory {:?}: {:?}", local_store_dir, e))
-        .and_then(|()| {
+        .and_then(|()| Store::local_only_with_options(local_store_dir, local_store_options))
+        .and_then(|store| {
           if remote_store_servers.is_empty() {
-            Store::local_only(local_store_dir)
+            Ok(store)
           } else {
-            Store::with_remote(
-              local_store_dir,
-              &remote_store_servers,
-              remote_instance_name.clone(),
-              &root_ca_certs,
-              oauth_bearer_token.clone(),
-              remote_store_thread_count,
-              remote_store_chunk_bytes,
-              remote_store_chunk_upload_timeout,
-              // TODO: Take a parameter
-              fs::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10))
-                .unwrap(),
-              remote_store_rpc_retries,
-              futures_timer_thread2.with(futures_timer::HelperThread::handle),
-            )
+            store
+              .into_with_remote(
+                &remote_store_servers,
+                remote_instance_name.clone(),
+                &root_ca_certs,
+                &client_certificate,
+                &proxy,
+                channel_policy,
+                oauth_bearer_token.clone(),
+                Some(request_metadata2.clone()),
+                remote_store_compression,
+                remote_store_thread_count,
+                remote_store_chunk_bytes,
+                remote_store_chunk_upload_timeout,
+                remote_store_read_timeout,
+                remote_action_cache_timeout,
+                // TODO: Take a parameter
+                fs::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10))
+                  .unwrap(),
+                remote_store_load_balancing,
+                remote_store_rpc_retries,
+                futures_timer_thread2.with(futures_timer::HelperThread::handle),
+              )
+              .map(|store| {
+                store
+                  .with_remote_lease_extension_interval(remote_store_lease_extension_interval)
+                  .with_small_blob_cache(
+                    remote_store_small_blob_cache
+                      .as_ref()
+                      .map(fs::SmallBlobCache::without_connections),
+                  )
+                  .with_tiering_policy(remote_store_tiering_policy)
+                  .with_known_remote_blobs(remote_store_known_blobs.clone())
+              })
           }
         })
+        .map(|store| {
+          store
+            .with_local_digest_verification(local_store_verify_digests)
+            .with_local_compression_threshold(local_store_compression_threshold_bytes)
+            .with_local_hashing_parallelism(local_store_hashing_parallelism)
+            .with_local_mmap_threshold(local_store_mmap_threshold_bytes)
+        })
         .unwrap_or_else(|e| panic!("Could not initialize Store: {:?}", e));
 
+      let local_command_runner = || -> Box<dyn CommandRunner> {
+        let teed_output = teed_output2.clone();
+        Box::new(
+          process_execution::local::CommandRunner::new(
+            store.clone(),
+            work_dir.clone(),
+            process_execution_cleanup_local_dirs,
+          )
+          .with_materialization_strategy(process_execution_local_materialization)
+          .with_console(Arc::new(
+            move |_description: &str, stream: OutputStream, line: &[u8]| {
+              teed_output.write(stream, line)
+            },
+          )),
+        )
+      };
+
       let underlying_command_runner: Box<dyn CommandRunner> = match &remote_execution_server {
-        Some(ref address) => Box::new(process_execution::remote::CommandRunner::new(
-          address,
-          remote_execution_process_cache_namespace.clone(),
-          remote_instance_name.clone(),
-          root_ca_certs.clone(),
-          oauth_bearer_token.clone(),
-          remote_execution_extra_platform_properties.clone(),
-          // Allow for some overhead for bookkeeping threads (if any).
-          process_execution_parallelism + 2,
-          store.clone(),
-          futures_timer_thread2.clone(),
-        )),
-        None => Box::new(process_execution::local::CommandRunner::new(
-          store.clone(),
-          work_dir.clone(),
-          process_execution_cleanup_local_dirs,
-        )),
+        Some(ref address) => {
+          let remote_command_runner = process_execution::remote::CommandRunner::new(
+            address,
+            remote_execution_process_cache_namespace.clone(),
+            remote_instance_name.clone(),
+            root_ca_certs.clone(),
+            client_certificate.clone(),
+            proxy.clone(),
+            channel_policy,
+            oauth_bearer_token.clone(),
+            Some(request_metadata2.clone()),
+            remote_execution_extra_platform_properties.clone(),
+            // Allow for some overhead for bookkeeping threads (if any).
+            process_execution_parallelism + 2,
+            remote_execution_rpc_retries,
+            remote_execution_rpc_timeout,
+            store.clone(),
+            futures_timer_thread2.clone(),
+          )
+          .with_instance_routes(remote_instance_routes.clone())
+          .with_execution_headers(remote_execution_headers.clone())
+          .unwrap_or_else(|e| panic!("Could not initialize remote execution: {}", e))
+          .with_result_verification(remote_execution_verify_results)
+          .with_execution_concurrency_limit(process_execution_remote_parallelism);
+          // Processes can only fall back to running locally if they were built for this platform.
+          let same_platform = remote_command_runner.platform() == Platform::current();
+          if remote_execution_circuit_breaker_failures > 0 && same_platform {
+            // The outer bound admits remote parallelism, so processes which fall back to running
+            // locally are bounded separately.
+            let local_fallback =
+              BoundedCommandRunner::new(local_command_runner(), process_execution_parallelism)
+                .with_concurrency_weights()
+                .with_deadline(process_deadline2.clone());
+            Box::new(CircuitBreakingCommandRunner::new(
+              Box::new(remote_command_runner),
+              Box::new(local_fallback),
+              CircuitBreakerPolicy {
+                failure_threshold: remote_execution_circuit_breaker_failures,
+                cool_down: remote_execution_circuit_breaker_cool_down,
+              },
+            ))
+          } else {
+            Box::new(remote_command_runner)
+          }
+        }
+        None => {
+          let local_command_runner = local_command_runner();
+          if (remote_cache_read || remote_cache_write) && !remote_store_servers.is_empty() {
+            Box::new(
+              process_execution::remote_cache::CommandRunner::new(
+                local_command_runner,
+                store.clone(),
+                remote_instance_name.clone(),
+                remote_execution_process_cache_namespace.clone(),
+                remote_execution_extra_platform_properties.clone(),
+                remote_cache_read,
+                remote_cache_write,
+              )
+              .with_instance_routes(remote_instance_routes.clone()),
+            )
+          } else {
+            local_command_runner
+          }
+        }
       };
 
-      let command_runner =
-        BoundedCommandRunner::new(underlying_command_runner, process_execution_parallelism);
+      // Only processes running on this machine compete for its resources. Remote executions are
+      // bounded by the remote CommandRunner, but up to `process_execution_parallelism` more
+      // processes may be checking the ActionCache or uploading their inputs meanwhile.
+      let command_runner = if remote_execution_server.is_some() {
+        BoundedCommandRunner::new(
+          underlying_command_runner,
+          process_execution_remote_parallelism + process_execution_parallelism,
+        )
+        .with_deadline(process_deadline2.clone())
+      } else {
+        BoundedCommandRunner::new(underlying_command_runner, process_execution_parallelism)
+          .with_deadline(process_deadline2.clone())
+          .with_concurrency_weights()
+      };
 
       let http_client = reqwest::r#async::Client::new();
 
@@ -176,6 +415,14 @@ impl Core {
         panic!("Could not initialize VFS: {:?}", e);
       }),
       build_root: build_root,
+      teed_output: teed_output,
+      process_deadline: process_deadline,
+      request_metadata: request_metadata,
+      remote_execution_timings: RemoteExecutionTimings::default(),
+      remote_asset_fetch: remote_asset_fetch,
+      local_store_gc_target_bytes: local_store_gc_target_bytes,
+      local_store_eviction_policy,
+      local_store_eviction_dry_run,
     }
   }
 
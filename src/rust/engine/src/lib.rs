@@ -31,6 +31,7 @@
 #![allow(clippy::not_unsafe_ptr_arg_deref)]
 
 pub mod cffi_externs;
+mod console;
 mod context;
 mod core;
 mod externs;
@@ -79,7 +80,7 @@ use crate::tasks::Tasks;
 use crate::types::Types;
 use futures::Future;
 use hashing::Digest;
-use log::{error, Log};
+use log::{debug, error, info, Log};
 use logging::logger::LOGGER;
 use logging::{Destination, Logger};
 
@@ -208,15 +209,60 @@ pub extern "C" fn scheduler_create(
   remote_execution_server: Buffer,
   remote_execution_process_cache_namespace: Buffer,
   remote_instance_name: Buffer,
+  remote_instance_routes_buf: BufferBuffer,
   remote_root_ca_certs_path_buffer: Buffer,
+  remote_client_cert_path_buffer: Buffer,
+  remote_client_key_path_buffer: Buffer,
   remote_oauth_bearer_token_path_buffer: Buffer,
+  remote_oauth_bearer_token_helper_buffer: Buffer,
+  remote_proxy_buf: Buffer,
+  remote_keepalive_interval_seconds: u64,
+  remote_keepalive_timeout_seconds: u64,
+  remote_max_reconnect_backoff_seconds: u64,
+  tool_version_buf: Buffer,
+  remote_correlated_invocations_id_buf: Buffer,
+  remote_store_compression_buf: Buffer,
+  remote_store_load_balancing_buf: Buffer,
   remote_store_thread_count: u64,
   remote_store_chunk_bytes: u64,
   remote_store_chunk_upload_timeout_seconds: u64,
+  remote_store_read_timeout_seconds: u64,
+  remote_action_cache_timeout_seconds: u64,
   remote_store_rpc_retries: u64,
+  remote_store_lease_extension_interval_seconds: u64,
+  remote_execution_rpc_retries: u64,
+  remote_execution_rpc_timeout_seconds: u64,
   remote_execution_extra_platform_properties_buf: BufferBuffer,
+  remote_execution_headers_buf: BufferBuffer,
+  remote_execution_circuit_breaker_failures: u64,
+  remote_execution_circuit_breaker_cool_down_seconds: u64,
+  remote_execution_verify_results: bool,
   process_execution_parallelism: u64,
+  process_execution_remote_parallelism: u64,
   process_execution_cleanup_local_dirs: bool,
+  remote_cache_write: bool,
+  remote_cache_read: bool,
+  remote_asset_fetch: bool,
+  local_store_target_size: u64,
+  local_store_verify_digests: bool,
+  local_store_compression_threshold_buf: Buffer,
+  local_store_shard_count: u64,
+  local_store_shard_map_size_buf: Buffer,
+  local_store_max_readers: u64,
+  remote_store_small_blob_cache_buf: Buffer,
+  local_store_max_unused_age_days: u64,
+  local_store_file_shard_quota_buf: Buffer,
+  local_store_directory_shard_quota_buf: Buffer,
+  local_store_eviction_dry_run: bool,
+  local_store_hashing_parallelism: u64,
+  local_store_mmap_threshold_buf: Buffer,
+  remote_store_read_through: bool,
+  remote_store_write_through: bool,
+  remote_store_max_upload_file_size_buf: Buffer,
+  remote_store_max_upload_directory_size_buf: Buffer,
+  remote_store_known_blobs_capacity: u64,
+  remote_store_known_blobs_lifetime_seconds: u64,
+  process_execution_local_materialization_buf: Buffer,
 ) -> *const Scheduler {
   let root_type_ids = root_type_ids.to_vec();
   let ignore_patterns = ignore_patterns_buf
@@ -261,6 +307,16 @@ pub extern "C" fn scheduler_create(
   let remote_instance_name_string = remote_instance_name
     .to_string()
     .expect("remote_instance_name was not valid UTF8");
+  let remote_instance_routes: Vec<process_execution::instance_routing::InstanceRoute> =
+    remote_instance_routes_buf
+      .to_strings()
+      .expect("Failed to decode remote_instance_routes")
+      .into_iter()
+      .map(|s| {
+        s.parse()
+          .unwrap_or_else(|e| panic!("Invalid remote_instance_routes: {}", e))
+      })
+      .collect();
   let remote_execution_extra_platform_properties_map: BTreeMap<_, _> = remote_execution_extra_platform_properties_buf
       .to_strings()
       .expect("Failed to decode remote_execution_extra_platform_properties")
@@ -272,6 +328,22 @@ pub extern "C" fn scheduler_create(
         (key, value)
       }).collect();
 
+  let remote_execution_headers: BTreeMap<String, String> = remote_execution_headers_buf
+    .to_strings()
+    .expect("Failed to decode remote_execution_headers")
+    .into_iter()
+    .map(|s| {
+      let mut parts = s.splitn(2, '=');
+      match (parts.next(), parts.next()) {
+        (Some(name), Some(value)) => (name.to_owned(), value.to_owned()),
+        _ => panic!(
+          "Got invalid remote_execution_headers - must be of format name=value but got {}",
+          s
+        ),
+      }
+    })
+    .collect();
+
   let remote_root_ca_certs_path = {
     let path = remote_root_ca_certs_path_buffer.to_os_string();
     if path.is_empty() {
@@ -281,6 +353,24 @@ pub extern "C" fn scheduler_create(
     }
   };
 
+  let remote_client_cert_path = {
+    let path = remote_client_cert_path_buffer.to_os_string();
+    if path.is_empty() {
+      None
+    } else {
+      Some(PathBuf::from(path))
+    }
+  };
+
+  let remote_client_key_path = {
+    let path = remote_client_key_path_buffer.to_os_string();
+    if path.is_empty() {
+      None
+    } else {
+      Some(PathBuf::from(path))
+    }
+  };
+
   let remote_oauth_bearer_token_path = {
     let path = remote_oauth_bearer_token_path_buffer.to_os_string();
     if path.is_empty() {
@@ -290,6 +380,122 @@ pub extern "C" fn scheduler_create(
     }
   };
 
+  let remote_oauth_bearer_token_helper = {
+    let path = remote_oauth_bearer_token_helper_buffer.to_os_string();
+    if path.is_empty() {
+      None
+    } else {
+      Some(PathBuf::from(path))
+    }
+  };
+
+  let remote_proxy_string = remote_proxy_buf
+    .to_string()
+    .expect("remote_proxy was not valid UTF8");
+  let tool_version = tool_version_buf
+    .to_string()
+    .expect("tool_version was not valid UTF8");
+  let remote_correlated_invocations_id_string = remote_correlated_invocations_id_buf
+    .to_string()
+    .expect("remote_correlated_invocations_id was not valid UTF8");
+
+  let remote_store_compression = remote_store_compression_buf
+    .to_string()
+    .expect("remote_store_compression was not valid UTF8")
+    .parse()
+    .unwrap_or_else(|e| panic!("Invalid remote_store_compression: {}", e));
+  let remote_store_load_balancing = remote_store_load_balancing_buf
+    .to_string()
+    .expect("remote_store_load_balancing was not valid UTF8")
+    .parse()
+    .unwrap_or_else(|e| panic!("Invalid remote_store_load_balancing: {}", e));
+  let process_execution_local_materialization = process_execution_local_materialization_buf
+    .to_string()
+    .expect("process_execution_local_materialization was not valid UTF8")
+    .parse()
+    .unwrap_or_else(|e| panic!("Invalid process_execution_local_materialization: {}", e));
+  let local_store_compression_threshold_bytes = fs::parse_size_bytes(
+    &local_store_compression_threshold_buf
+      .to_string()
+      .expect("local_store_compression_threshold was not valid UTF8"),
+  )
+  .unwrap_or_else(|e| panic!("Invalid local_store_compression_threshold: {}", e));
+  let local_store_options = fs::parse_size_bytes(
+    &local_store_shard_map_size_buf
+      .to_string()
+      .expect("local_store_shard_map_size was not valid UTF8"),
+  )
+  .and_then(|local_store_shard_map_size_bytes| {
+    fs::LocalStoreOptions::new(
+      local_store_shard_count as usize,
+      local_store_shard_map_size_bytes,
+      local_store_max_readers as u32,
+    )
+  })
+  .unwrap_or_else(|e| panic!("Invalid local store options: {}", e));
+  let remote_store_small_blob_cache_string = remote_store_small_blob_cache_buf
+    .to_string()
+    .expect("remote_store_small_blob_cache was not valid UTF8");
+  let remote_store_small_blob_cache = if remote_store_small_blob_cache_string.is_empty() {
+    None
+  } else {
+    Some(
+      fs::SmallBlobCache::new(&remote_store_small_blob_cache_string)
+        .unwrap_or_else(|e| panic!("Invalid remote_store_small_blob_cache: {}", e)),
+    )
+  };
+  let optional_size_bytes = |size_buf: Buffer, name: &str| {
+    let size_bytes = fs::parse_size_bytes(
+      &size_buf
+        .to_string()
+        .unwrap_or_else(|e| panic!("{} was not valid UTF8: {:?}", name, e)),
+    )
+    .unwrap_or_else(|e| panic!("Invalid {}: {}", name, e));
+    if size_bytes == 0 {
+      None
+    } else {
+      Some(size_bytes)
+    }
+  };
+  let local_store_mmap_threshold_bytes = fs::parse_size_bytes(
+    &local_store_mmap_threshold_buf
+      .to_string()
+      .expect("local_store_mmap_threshold was not valid UTF8"),
+  )
+  .unwrap_or_else(|e| panic!("Invalid local_store_mmap_threshold: {}", e));
+  let local_store_eviction_policy = fs::EvictionPolicy {
+    max_unused_age: if local_store_max_unused_age_days == 0 {
+      None
+    } else {
+      Some(Duration::from_secs(
+        local_store_max_unused_age_days * 24 * 60 * 60,
+      ))
+    },
+    file_shard_quota_bytes: optional_size_bytes(
+      local_store_file_shard_quota_buf,
+      "local_store_file_shard_quota",
+    ),
+    directory_shard_quota_bytes: optional_size_bytes(
+      local_store_directory_shard_quota_buf,
+      "local_store_directory_shard_quota",
+    ),
+  };
+  let remote_store_tier_rules = |max_upload_size_buf: Buffer, name: &str| fs::TierRules {
+    read_through: remote_store_read_through,
+    write_through: remote_store_write_through,
+    max_upload_bytes: optional_size_bytes(max_upload_size_buf, name),
+  };
+  let remote_store_tiering_policy = fs::TieringPolicy {
+    files: remote_store_tier_rules(
+      remote_store_max_upload_file_size_buf,
+      "remote_store_max_upload_file_size",
+    ),
+    directories: remote_store_tier_rules(
+      remote_store_max_upload_directory_size_buf,
+      "remote_store_max_upload_directory_size",
+    ),
+  };
+
   Box::into_raw(Box::new(Scheduler::new(Core::new(
     root_type_ids.clone(),
     tasks,
@@ -314,15 +520,79 @@ pub extern "C" fn scheduler_create(
     } else {
       Some(remote_instance_name_string)
     },
+    remote_instance_routes,
     remote_root_ca_certs_path,
+    remote_client_cert_path,
+    remote_client_key_path,
     remote_oauth_bearer_token_path,
+    remote_oauth_bearer_token_helper,
+    if remote_proxy_string.is_empty() {
+      None
+    } else {
+      Some(remote_proxy_string)
+    },
+    if remote_keepalive_interval_seconds == 0 {
+      None
+    } else {
+      Some(Duration::from_secs(remote_keepalive_interval_seconds))
+    },
+    Duration::from_secs(remote_keepalive_timeout_seconds),
+    Duration::from_secs(remote_max_reconnect_backoff_seconds),
+    tool_version,
+    if remote_correlated_invocations_id_string.is_empty() {
+      None
+    } else {
+      Some(remote_correlated_invocations_id_string)
+    },
+    remote_store_compression,
+    remote_store_load_balancing,
     remote_store_thread_count as usize,
     remote_store_chunk_bytes as usize,
     Duration::from_secs(remote_store_chunk_upload_timeout_seconds),
+    Duration::from_secs(remote_store_read_timeout_seconds),
+    Duration::from_secs(remote_action_cache_timeout_seconds),
     remote_store_rpc_retries as usize,
+    if remote_store_lease_extension_interval_seconds == 0 {
+      None
+    } else {
+      Some(Duration::from_secs(
+        remote_store_lease_extension_interval_seconds,
+      ))
+    },
+    remote_execution_rpc_retries as usize,
+    Duration::from_secs(remote_execution_rpc_timeout_seconds),
     remote_execution_extra_platform_properties_map,
+    remote_execution_headers,
+    remote_execution_circuit_breaker_failures as usize,
+    Duration::from_secs(remote_execution_circuit_breaker_cool_down_seconds),
+    remote_execution_verify_results,
     process_execution_parallelism as usize,
+    process_execution_remote_parallelism as usize,
     process_execution_cleanup_local_dirs as bool,
+    remote_cache_write,
+    remote_cache_read,
+    remote_asset_fetch,
+    local_store_target_size as usize,
+    local_store_verify_digests,
+    if local_store_compression_threshold_bytes == 0 {
+      None
+    } else {
+      Some(local_store_compression_threshold_bytes)
+    },
+    local_store_options,
+    remote_store_small_blob_cache,
+    local_store_eviction_policy,
+    local_store_eviction_dry_run,
+    local_store_hashing_parallelism as usize,
+    if local_store_mmap_threshold_bytes == 0 {
+      None
+    } else {
+      Some(local_store_mmap_threshold_bytes)
+    },
+    remote_store_tiering_policy,
+    remote_store_known_blobs_capacity as usize,
+    Duration::from_secs(remote_store_known_blobs_lifetime_seconds),
+    process_execution_local_materialization,
   ))))
 }
 
@@ -549,12 +819,20 @@ pub extern "C" fn session_create(
   scheduler_ptr: *mut Scheduler,
   should_render_ui: bool,
   ui_worker_count: u64,
+  build_deadline_millis: u64,
 ) -> *const Session {
+  // Zero means that there is no deadline.
+  let build_deadline = if build_deadline_millis == 0 {
+    None
+  } else {
+    Some(Duration::from_millis(build_deadline_millis))
+  };
   with_scheduler(scheduler_ptr, |scheduler| {
     Box::into_raw(Box::new(Session::new(
       scheduler,
       should_render_ui,
       ui_worker_count as usize,
+      build_deadline,
     )))
   })
 }
@@ -643,8 +921,33 @@ pub extern "C" fn set_panic_handler() {
 #[no_mangle]
 pub extern "C" fn garbage_collect_store(scheduler_ptr: *mut Scheduler) {
   with_scheduler(scheduler_ptr, |scheduler| {
+    let policy = &scheduler.core.local_store_eviction_policy;
+    let dry_run = scheduler.core.local_store_eviction_dry_run;
+    if !policy.is_empty() {
+      match scheduler.core.store().evict(policy, dry_run) {
+        Ok(evictions) => {
+          for eviction in &evictions {
+            if dry_run {
+              info!("Would evict {}", eviction);
+            } else {
+              debug!("Evicted {}", eviction);
+            }
+          }
+          info!(
+            "{} {} entries ({} bytes) from the local store",
+            if dry_run { "Would evict" } else { "Evicted" },
+            evictions.len(),
+            evictions
+              .iter()
+              .map(|eviction| eviction.stored_bytes)
+              .sum::<usize>()
+          );
+        }
+        Err(err) => error!("{}", err),
+      }
+    }
     match scheduler.core.store().garbage_collect(
-      fs::DEFAULT_LOCAL_STORE_GC_TARGET_BYTES,
+      scheduler.core.local_store_gc_target_bytes,
       fs::ShrinkBehavior::Fast,
     ) {
       Ok(_) => {}
@@ -653,6 +956,29 @@ pub extern "C" fn garbage_collect_store(scheduler_ptr: *mut Scheduler) {
   });
 }
 
+///
+/// Returns a dictionary of statistics about the local store (which are expensive to compute,
+/// because every entry is read) and the reads of the store, by name.
+///
+#[no_mangle]
+pub extern "C" fn store_stats(scheduler_ptr: *mut Scheduler) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler
+      .core
+      .store()
+      .stats()
+      .map(|stats| {
+        let values = stats
+          .metrics()
+          .into_iter()
+          .flat_map(|(metric, value)| vec![externs::store_utf8(&metric), externs::store_i64(value)])
+          .collect::<Vec<_>>();
+        externs::store_dict(&values)
+      })
+      .into()
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn lease_files_in_graph(scheduler_ptr: *mut Scheduler) {
   with_scheduler(scheduler_ptr, |scheduler| {
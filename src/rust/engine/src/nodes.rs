@@ -27,6 +27,7 @@ use fs::{
   PathGlobs, PathStat, StoreFileByDigest, StrictGlobMatching, VFS,
 };
 use hashing;
+use log::{debug, warn};
 use process_execution::{self, CommandRunner};
 
 use graph::{Entry, Node, NodeError, NodeTracer, NodeVisualizer};
@@ -342,17 +343,9 @@ impl ExecuteProcess {
   /// Lifts a Key representing a python ExecuteProcessRequest value into a ExecuteProcess Node.
   ///
   fn lift(value: &Value) -> Result<ExecuteProcess, String> {
-    let mut env: BTreeMap<String, String> = BTreeMap::new();
-    let env_var_parts = externs::project_multi_strs(&value, "env");
-    if env_var_parts.len() % 2 != 0 {
-      return Err("Error parsing env: odd number of parts".to_owned());
-    }
-    for i in 0..(env_var_parts.len() / 2) {
-      env.insert(
-        env_var_parts[2 * i].clone(),
-        env_var_parts[2 * i + 1].clone(),
-      );
-    }
+    let env = Self::lift_pairs(value, "env")?;
+    let platform_properties = Self::lift_pairs(value, "platform_properties")?;
+    let execution_headers = Self::lift_pairs(value, "execution_headers")?;
     let digest = lift_digest(&externs::project_ignoring_type(&value, "input_files"))
       .map_err(|err| format!("Error parsing digest {}", err))?;
 
@@ -377,6 +370,11 @@ impl ExecuteProcess {
 
     let description = externs::project_str(&value, "description");
 
+    let priority_str = externs::project_str(&value, "priority");
+    let priority = priority_str
+      .parse::<i32>()
+      .map_err(|err| format!("Priority was not an int: {:?}", err))?;
+
     let jdk_home = {
       let val = externs::project_str(&value, "jdk_home");
       if val.is_empty() {
@@ -386,16 +384,38 @@ impl ExecuteProcess {
       }
     };
 
-    Ok(ExecuteProcess(process_execution::ExecuteProcessRequest {
-      argv: externs::project_multi_strs(&value, "argv"),
-      env: env,
-      input_files: digest,
-      output_files: output_files,
-      output_directories: output_directories,
-      timeout: Duration::from_millis((timeout_in_seconds * 1000.0) as u64),
-      description: description,
-      jdk_home: jdk_home,
-    }))
+    process_execution::ExecuteProcessRequestBuilder::new(
+      externs::project_multi_strs(&value, "argv"),
+      Duration::from_millis((timeout_in_seconds * 1000.0) as u64),
+      description,
+    )
+    .env(env)
+    .input_files(digest)
+    .output_files(output_files)
+    .output_directories(output_directories)
+    .tool_mounts(process_execution::jdk_tool_mounts(jdk_home))
+    .tee_output(externs::project_str(&value, "tee_output") == "True")
+    .platform_properties(platform_properties)
+    .priority(priority)
+    .execution_headers(execution_headers)
+    .build()
+    .map(ExecuteProcess)
+  }
+
+  ///
+  /// Lifts a field holding a flattened list of alternating keys and values into a map.
+  ///
+  fn lift_pairs(value: &Value, field: &str) -> Result<BTreeMap<String, String>, String> {
+    let parts = externs::project_multi_strs(value, field);
+    if parts.len() % 2 != 0 {
+      return Err(format!("Error parsing {}: odd number of parts", field));
+    }
+    Ok(
+      parts
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect(),
+    )
   }
 }
 
@@ -407,12 +427,18 @@ impl WrappedNode for ExecuteProcess {
 
   fn run(self, context: Context) -> NodeFuture<ProcessResult> {
     let request = self.0;
+    let core = context.core.clone();
 
     context
       .core
       .command_runner()
       .run(request)
-      .map(ProcessResult)
+      .map(move |result| {
+        core
+          .remote_execution_timings
+          .record(&result.execution_attempts);
+        ProcessResult(result)
+      })
       .map_err(|e| throw(&format!("Failed to execute process: {}", e)))
       .to_boxed()
   }
@@ -467,16 +493,9 @@ impl WrappedNode for DigestFile {
     let file = self.0.clone();
     context
       .core
-      .vfs
-      .read_file(&self.0)
-      .map_err(move |e| throw(&format!("Error reading file {:?}: {:?}", file, e,)))
-      .and_then(move |c| {
-        context
-          .core
-          .store()
-          .store_file_bytes(c.content, true)
-          .map_err(|e| throw(&e))
-      })
+      .store()
+      .store_file(&context.core.vfs.file_path(&self.0), true)
+      .map_err(move |e| throw(&format!("Error storing file {:?}: {}", file, e)))
       .to_boxed()
   }
 }
@@ -660,7 +679,7 @@ impl DownloadedFile {
       .and_then(move |maybe_bytes| {
         maybe_bytes
           .map(|()| future::ok(()).to_boxed())
-          .unwrap_or_else(|| DownloadedFile::download(core.clone(), url, file_name.clone(), digest))
+          .unwrap_or_else(|| DownloadedFile::fetch(core.clone(), url, file_name.clone(), digest))
           .and_then(move |()| {
             DownloadedFile::snapshot_of_one_file(core.store(), PathBuf::from(file_name), digest)
           })
@@ -697,6 +716,39 @@ impl DownloadedFile {
     )
   }
 
+  ///
+  /// Fetches the file remotely with the Remote Asset API if possible (so that it is only
+  /// downloaded to this machine if something needs its content) or otherwise downloads it.
+  ///
+  fn fetch(
+    core: Arc<Core>,
+    url: Url,
+    file_name: String,
+    expected_digest: hashing::Digest,
+  ) -> BoxFuture<(), String> {
+    if !core.remote_asset_fetch {
+      return DownloadedFile::download(core, url, file_name, expected_digest);
+    }
+    core
+      .store()
+      .fetch_remote_file(vec![url.to_string()], expected_digest)
+      .then(move |res| match res {
+        Ok(true) => {
+          debug!("Fetched {} remotely", url);
+          future::ok(()).to_boxed()
+        }
+        Ok(false) => DownloadedFile::download(core, url, file_name, expected_digest),
+        Err(err) => {
+          warn!(
+            "Failed to fetch {} remotely, so downloading it: {}",
+            url, err
+          );
+          DownloadedFile::download(core, url, file_name, expected_digest)
+        }
+      })
+      .to_boxed()
+  }
+
   fn download(
     core: Arc<Core>,
     url: Url,
@@ -6,7 +6,7 @@ use std::convert::TryInto;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::{mpsc, Arc};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::future::{self, Future};
 
@@ -18,6 +18,7 @@ use graph::{EntryId, Graph, InvalidationResult, NodeContext};
 use indexmap::IndexMap;
 use log::{debug, info, warn};
 use parking_lot::Mutex;
+use process_execution::local::OutputStream;
 use ui::EngineDisplay;
 
 ///
@@ -37,7 +38,27 @@ pub struct Session {
 }
 
 impl Session {
-  pub fn new(scheduler: &Scheduler, should_render_ui: bool, ui_worker_count: usize) -> Session {
+  ///
+  /// Creates a Session, whose processes must complete within the given deadline (if any) of its
+  /// creation. The deadline is shared by all processes, so this replaces the deadline of any
+  /// previous Session (which pantsd will have finished using).
+  ///
+  /// Each Session is a new invocation, with its own tool invocation id in the RequestMetadata of
+  /// remote RPCs.
+  ///
+  pub fn new(
+    scheduler: &Scheduler,
+    should_render_ui: bool,
+    ui_worker_count: usize,
+    build_deadline: Option<Duration>,
+  ) -> Session {
+    scheduler
+      .core
+      .process_deadline
+      .set(build_deadline.map(|deadline| Instant::now() + deadline));
+    scheduler.core.request_metadata.new_invocation();
+    scheduler.core.store().forget_remote_leases();
+    scheduler.core.remote_execution_timings.reset();
     Session {
       preceding_graph_size: scheduler.core.graph.len(),
       roots: Mutex::new(HashSet::new()),
@@ -171,6 +192,8 @@ impl Scheduler {
     );
     m.insert("preceding_graph_size", session.preceding_graph_size as i64);
     m.insert("resulting_graph_size", self.core.graph.len() as i64);
+    m.extend(self.core.remote_execution_timings.metrics());
+    m.extend(self.core.store().read_metrics());
     m
   }
 
@@ -271,6 +294,7 @@ impl Scheduler {
     let mut tasks_to_display = IndexMap::new();
 
     if let Some(ref mut display) = maybe_display {
+      self.core.teed_output.start_queueing();
       display.start();
     };
 
@@ -278,16 +302,25 @@ impl Scheduler {
       if let Ok(res) = receiver.recv_timeout(Duration::from_millis(100)) {
         break res;
       } else if let Some(ref mut display) = maybe_display {
+        Scheduler::emit_teed_output(display, self.core.teed_output.drain());
         Scheduler::display_ongoing_tasks(&self.core.graph, &roots, display, &mut tasks_to_display);
       }
     };
     if let Some(ref mut display) = maybe_display {
+      Scheduler::emit_teed_output(display, self.core.teed_output.stop_queueing());
       display.finish();
     };
 
     results
   }
 
+  fn emit_teed_output(display: &mut EngineDisplay, teed_output: Vec<(OutputStream, Vec<u8>)>) {
+    // The display renders to stdout, so output to either stream is emitted there.
+    for (_stream, line) in teed_output {
+      display.emit(&String::from_utf8_lossy(&line));
+    }
+  }
+
   fn display_ongoing_tasks(
     graph: &Graph<NodeKey>,
     roots: &[NodeKey],
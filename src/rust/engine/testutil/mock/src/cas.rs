@@ -9,26 +9,39 @@ use bytes::Bytes;
 use futures::{Future, IntoFuture, Stream};
 use hashing::{Digest, Fingerprint};
 use parking_lot::Mutex;
-use testutil::data::{TestData, TestDirectory};
+use protobuf::ProtobufEnum;
+use testutil::data::{TestData, TestDirectory, TestTree};
 
 ///
 /// Implements the ContentAddressableStorage gRPC API, answering read requests with either known
 /// content, NotFound for valid but unknown content, or InvalidArguments for bad arguments.
 ///
+/// Also implements the ActionCache gRPC API, answering with known action results (keyed by the
+/// fingerprints of their actions), or NotFound.
+///
 pub struct StubCAS {
   server_transport: grpcio::Server,
   read_request_count: Arc<Mutex<usize>>,
+  batch_request_count: Arc<Mutex<usize>>,
+  find_missing_request_count: Arc<Mutex<usize>>,
   pub write_message_sizes: Arc<Mutex<Vec<usize>>>,
   pub blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
+  pub action_results:
+    Arc<Mutex<HashMap<Fingerprint, bazel_protos::remote_execution::ActionResult>>>,
 }
 
 pub struct StubCASBuilder {
   always_errors: bool,
   chunk_size_bytes: Option<usize>,
   content: HashMap<Fingerprint, Bytes>,
+  action_results: HashMap<Fingerprint, bazel_protos::remote_execution::ActionResult>,
   port: Option<u16>,
   instance_name: Option<String>,
   required_auth_token: Option<String>,
+  capabilities: Option<bazel_protos::remote_execution::ServerCapabilities>,
+  batch_rpcs: bool,
+  interrupt_write_after_bytes: Option<usize>,
+  assets: HashMap<String, (Digest, Bytes)>,
 }
 
 impl StubCASBuilder {
@@ -37,9 +50,14 @@ impl StubCASBuilder {
       always_errors: false,
       chunk_size_bytes: None,
       content: HashMap::new(),
+      action_results: HashMap::new(),
       port: None,
       instance_name: None,
       required_auth_token: None,
+      capabilities: None,
+      batch_rpcs: false,
+      interrupt_write_after_bytes: None,
+      assets: HashMap::new(),
     }
   }
 }
@@ -73,6 +91,31 @@ impl StubCASBuilder {
     self
   }
 
+  pub fn tree(mut self, tree: &TestTree) -> Self {
+    self.content.insert(tree.fingerprint(), tree.bytes());
+    self
+  }
+
+  pub fn action_result(
+    mut self,
+    action_digest: Digest,
+    action_result: bazel_protos::remote_execution::ActionResult,
+  ) -> Self {
+    self.action_results.insert(action_digest.0, action_result);
+    self
+  }
+
+  ///
+  /// Content which the Remote Asset Fetch service can fetch from the given URI. If no assets are
+  /// configured, the Fetch service is unimplemented.
+  ///
+  pub fn asset(mut self, uri: &str, content: &TestData) -> Self {
+    self
+      .assets
+      .insert(uri.to_owned(), (content.digest(), content.bytes()));
+    self
+  }
+
   pub fn unverified_content(mut self, fingerprint: Fingerprint, content: Bytes) -> Self {
     self.content.insert(fingerprint, content);
     self
@@ -99,14 +142,55 @@ impl StubCASBuilder {
     self
   }
 
+  ///
+  /// The capabilities with which to answer GetCapabilities requests, which are otherwise rejected
+  /// as unimplemented.
+  ///
+  pub fn capabilities(
+    mut self,
+    capabilities: bazel_protos::remote_execution::ServerCapabilities,
+  ) -> Self {
+    if self.capabilities.is_some() {
+      panic!("Can't set capabilities twice");
+    }
+    self.capabilities = Some(capabilities);
+    self
+  }
+
+  ///
+  /// Implements BatchUpdateBlobs and BatchReadBlobs, which are otherwise rejected as
+  /// unimplemented.
+  ///
+  pub fn batch_rpcs(mut self) -> Self {
+    self.batch_rpcs = true;
+    self
+  }
+
+  ///
+  /// Interrupts the first upload (with an Unavailable error) once it has written at least the given
+  /// number of bytes, keeping what it wrote so that it can be resumed.
+  ///
+  pub fn interrupt_write_after_bytes(mut self, bytes: usize) -> Self {
+    if self.interrupt_write_after_bytes.is_some() {
+      panic!("Can't set interrupt_write_after_bytes twice");
+    }
+    self.interrupt_write_after_bytes = Some(bytes);
+    self
+  }
+
   pub fn build(self) -> StubCAS {
     StubCAS::new(
       self.chunk_size_bytes.unwrap_or(1024),
       self.content,
+      self.action_results,
       self.port.unwrap_or(0),
       self.always_errors,
       self.instance_name,
       self.required_auth_token,
+      self.capabilities,
+      self.batch_rpcs,
+      self.interrupt_write_after_bytes,
+      self.assets,
     )
   }
 }
@@ -124,27 +208,49 @@ impl StubCAS {
   ///                        If a negative value is given, all requests will receive an error.
   /// * `blobs`            - Known Fingerprints and their content responses. These are not checked
   ///                        for correctness.
+  /// * `action_results`   - Known Fingerprints of actions and their results.
   /// * `port`             - The port for the CAS to listen to.
+  /// * `capabilities`     - The capabilities to advertise, if any.
+  /// * `batch_rpcs`       - Whether to implement the batch CAS RPCs.
+  /// * `interrupt_write_after_bytes` - The number of bytes after which to interrupt the first
+  ///                        upload, if any.
+  /// * `assets`           - The content which the Fetch service can fetch, by URI.
   fn new(
     chunk_size_bytes: usize,
     blobs: HashMap<Fingerprint, Bytes>,
+    action_results: HashMap<Fingerprint, bazel_protos::remote_execution::ActionResult>,
     port: u16,
     always_errors: bool,
     instance_name: Option<String>,
     required_auth_token: Option<String>,
+    capabilities: Option<bazel_protos::remote_execution::ServerCapabilities>,
+    batch_rpcs: bool,
+    interrupt_write_after_bytes: Option<usize>,
+    assets: HashMap<String, (Digest, Bytes)>,
   ) -> StubCAS {
     let env = Arc::new(grpcio::Environment::new(1));
     let read_request_count = Arc::new(Mutex::new(0));
+    let batch_request_count = Arc::new(Mutex::new(0));
+    let find_missing_request_count = Arc::new(Mutex::new(0));
     let write_message_sizes = Arc::new(Mutex::new(Vec::new()));
     let blobs = Arc::new(Mutex::new(blobs));
+    let action_results = Arc::new(Mutex::new(action_results));
     let responder = StubCASResponder {
       chunk_size_bytes: chunk_size_bytes,
       instance_name: instance_name,
       blobs: blobs.clone(),
+      action_results: action_results.clone(),
       always_errors: always_errors,
       read_request_count: read_request_count.clone(),
       write_message_sizes: write_message_sizes.clone(),
       required_auth_header: required_auth_token.map(|t| format!("Bearer {}", t)),
+      capabilities: capabilities,
+      batch_rpcs: batch_rpcs,
+      batch_request_count: batch_request_count.clone(),
+      find_missing_request_count: find_missing_request_count.clone(),
+      partial_writes: Arc::new(Mutex::new(HashMap::new())),
+      interrupt_write_after_bytes: Arc::new(Mutex::new(interrupt_write_after_bytes)),
+      assets: Arc::new(assets),
     };
     let mut server_transport = grpcio::ServerBuilder::new(env)
       .register_service(bazel_protos::bytestream_grpc::create_byte_stream(
@@ -153,6 +259,15 @@ impl StubCAS {
       .register_service(
         bazel_protos::remote_execution_grpc::create_content_addressable_storage(responder.clone()),
       )
+      .register_service(bazel_protos::remote_execution_grpc::create_action_cache(
+        responder.clone(),
+      ))
+      .register_service(bazel_protos::remote_execution_grpc::create_capabilities(
+        responder.clone(),
+      ))
+      .register_service(bazel_protos::remote_asset_grpc::create_fetch(
+        responder.clone(),
+      ))
       .bind("localhost", port)
       .build()
       .unwrap();
@@ -161,8 +276,11 @@ impl StubCAS {
     StubCAS {
       server_transport,
       read_request_count,
+      batch_request_count,
+      find_missing_request_count,
       write_message_sizes,
       blobs,
+      action_results,
     }
   }
 
@@ -185,6 +303,20 @@ impl StubCAS {
   pub fn read_request_count(&self) -> usize {
     *self.read_request_count.lock()
   }
+
+  ///
+  /// The number of BatchUpdateBlobs and BatchReadBlobs requests that have been answered.
+  ///
+  pub fn batch_request_count(&self) -> usize {
+    *self.batch_request_count.lock()
+  }
+
+  ///
+  /// The number of FindMissingBlobs requests that have been answered.
+  ///
+  pub fn find_missing_request_count(&self) -> usize {
+    *self.find_missing_request_count.lock()
+  }
 }
 
 #[derive(Clone, Debug)]
@@ -192,8 +324,17 @@ pub struct StubCASResponder {
   chunk_size_bytes: usize,
   instance_name: Option<String>,
   blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
+  action_results: Arc<Mutex<HashMap<Fingerprint, bazel_protos::remote_execution::ActionResult>>>,
   always_errors: bool,
   required_auth_header: Option<String>,
+  capabilities: Option<bazel_protos::remote_execution::ServerCapabilities>,
+  batch_rpcs: bool,
+  batch_request_count: Arc<Mutex<usize>>,
+  find_missing_request_count: Arc<Mutex<usize>>,
+  // The bytes written so far by interrupted uploads, by resource name.
+  partial_writes: Arc<Mutex<HashMap<String, Bytes>>>,
+  interrupt_write_after_bytes: Arc<Mutex<Option<usize>>>,
+  assets: Arc<HashMap<String, (Digest, Bytes)>>,
   pub read_request_count: Arc<Mutex<usize>>,
   pub write_message_sizes: Arc<Mutex<Vec<usize>>>,
 }
@@ -283,6 +424,48 @@ impl StubCASResponder {
   ///
   /// Sends a stream of responses down a sink, in ctx's threadpool.
   ///
+  fn check_batch_request(
+    &self,
+    ctx: &grpcio::RpcContext<'_>,
+    instance_name: &str,
+  ) -> Result<(), grpcio::RpcStatus> {
+    if !self.batch_rpcs {
+      return Err(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Unimplemented,
+        None,
+      ));
+    }
+    if let Some(ref required_auth_header) = self.required_auth_header {
+      let authorized = ctx.request_headers().iter().any(|(key, value)| {
+        key.to_lowercase() == "authorization" && value == required_auth_header.as_bytes()
+      });
+      if !authorized {
+        return Err(grpcio::RpcStatus::new(
+          grpcio::RpcStatusCode::Unauthenticated,
+          Some("Bad Authorization header".to_owned()),
+        ));
+      }
+    }
+    if self.always_errors {
+      return Err(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Internal,
+        Some("StubCAS is configured to always fail".to_owned()),
+      ));
+    }
+    if instance_name != self.instance_name() {
+      return Err(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::NotFound,
+        Some(format!(
+          "Wrong instance_name; want {:?} got {:?}",
+          self.instance_name(),
+          instance_name
+        )),
+      ));
+    }
+    *self.batch_request_count.lock() += 1;
+    Ok(())
+  }
+
   fn send<Item, S>(
     &self,
     ctx: &grpcio::RpcContext<'_>,
@@ -337,6 +520,8 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
     let write_message_sizes = self.write_message_sizes.clone();
     let blobs = self.blobs.clone();
     let instance_name = self.instance_name();
+    let partial_writes = self.partial_writes.clone();
+    let interrupt_write_after_bytes = self.interrupt_write_after_bytes.clone();
     ctx.spawn(
       stream
         .collect()
@@ -347,7 +532,14 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
           let mut bytes = Bytes::new();
           for req in reqs {
             match maybe_resource_name {
-              None => maybe_resource_name = Some(req.get_resource_name().to_owned()),
+              None => {
+                // Interrupted uploads resume from where they were interrupted.
+                if let Some(partial) = partial_writes.lock().remove(req.get_resource_name()) {
+                  want_next_offset = partial.len() as i64;
+                  bytes = partial;
+                }
+                maybe_resource_name = Some(req.get_resource_name().to_owned());
+              }
               Some(ref resource_name) => {
                 if resource_name != req.get_resource_name() {
                   return Err(grpcio::Error::RpcFailure(grpcio::RpcStatus::new(
@@ -374,6 +566,21 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
             want_next_offset += req.get_data().len() as i64;
             write_message_sizes.lock().push(req.get_data().len());
             bytes.extend(req.get_data());
+
+            let mut interrupt_write_after_bytes = interrupt_write_after_bytes.lock();
+            match *interrupt_write_after_bytes {
+              Some(limit) if bytes.len() >= limit && !req.get_finish_write() => {
+                *interrupt_write_after_bytes = None;
+                partial_writes
+                  .lock()
+                  .insert(req.get_resource_name().to_owned(), bytes);
+                return Err(grpcio::Error::RpcFailure(grpcio::RpcStatus::new(
+                  grpcio::RpcStatusCode::Unavailable,
+                  Some("StubCAS interrupted the upload".to_owned()),
+                )));
+              }
+              _ => {}
+            }
           }
           Ok((maybe_resource_name, bytes))
         })
@@ -459,14 +666,38 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
 
   fn query_write_status(
     &self,
-    _ctx: grpcio::RpcContext<'_>,
-    _req: bazel_protos::bytestream::QueryWriteStatusRequest,
+    ctx: grpcio::RpcContext<'_>,
+    req: bazel_protos::bytestream::QueryWriteStatusRequest,
     sink: grpcio::UnarySink<bazel_protos::bytestream::QueryWriteStatusResponse>,
   ) {
-    sink.fail(grpcio::RpcStatus::new(
-      grpcio::RpcStatusCode::Unimplemented,
-      None,
-    ));
+    check_auth!(self, ctx, sink);
+
+    let mut response = bazel_protos::bytestream::QueryWriteStatusResponse::new();
+    if let Some(partial) = self.partial_writes.lock().get(req.get_resource_name()) {
+      response.set_committed_size(partial.len() as i64);
+      sink.success(response);
+      return;
+    }
+    // Resource names are of the form {instance_name}/uploads/{uuid}/blobs/{hash}/{size}.
+    let parts: Vec<_> = req.get_resource_name().rsplitn(3, '/').collect();
+    let written = match (parts.get(0), parts.get(1)) {
+      (Some(size), Some(hash)) => Fingerprint::from_hex_string(hash)
+        .ok()
+        .and_then(|fingerprint| self.blobs.lock().get(&fingerprint).map(Bytes::len))
+        .filter(|len| size.parse::<usize>() == Ok(*len)),
+      _ => None,
+    };
+    match written {
+      Some(len) => {
+        response.set_committed_size(len as i64);
+        response.set_complete(true);
+        sink.success(response);
+      }
+      None => sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::NotFound,
+        Some(format!("No upload to {}", req.get_resource_name())),
+      )),
+    }
   }
 }
 
@@ -497,6 +728,7 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
       ));
       return;
     }
+    *self.find_missing_request_count.lock() += 1;
     let blobs = self.blobs.lock();
     let mut response = bazel_protos::remote_execution::FindMissingBlobsResponse::new();
     for digest in req.get_blob_digests() {
@@ -511,15 +743,57 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
 
   fn batch_update_blobs(
     &self,
-    _ctx: grpcio::RpcContext<'_>,
-    _req: bazel_protos::remote_execution::BatchUpdateBlobsRequest,
+    ctx: grpcio::RpcContext<'_>,
+    req: bazel_protos::remote_execution::BatchUpdateBlobsRequest,
     sink: grpcio::UnarySink<bazel_protos::remote_execution::BatchUpdateBlobsResponse>,
   ) {
-    sink.fail(grpcio::RpcStatus::new(
-      grpcio::RpcStatusCode::Unimplemented,
-      None,
-    ));
+    if let Err(status) = self.check_batch_request(&ctx, &req.instance_name) {
+      sink.fail(status);
+      return;
+    }
+    let mut blobs = self.blobs.lock();
+    let mut response = bazel_protos::remote_execution::BatchUpdateBlobsResponse::new();
+    for request in req.get_requests() {
+      let digest: Result<Digest, String> = request.get_content_digest().into();
+      let digest = digest.expect("Bad digest");
+      blobs.insert(digest.0, request.get_data().clone());
+      let mut blob_response =
+        bazel_protos::remote_execution::BatchUpdateBlobsResponse_Response::new();
+      blob_response.set_blob_digest(request.get_content_digest().clone());
+      response.mut_responses().push(blob_response);
+    }
+    sink.success(response);
   }
+
+  fn batch_read_blobs(
+    &self,
+    ctx: grpcio::RpcContext<'_>,
+    req: bazel_protos::remote_execution::BatchReadBlobsRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_execution::BatchReadBlobsResponse>,
+  ) {
+    if let Err(status) = self.check_batch_request(&ctx, &req.instance_name) {
+      sink.fail(status);
+      return;
+    }
+    let blobs = self.blobs.lock();
+    let mut response = bazel_protos::remote_execution::BatchReadBlobsResponse::new();
+    for digest in req.get_digests() {
+      let hashing_digest: Result<Digest, String> = digest.into();
+      let hashing_digest = hashing_digest.expect("Bad digest");
+      let mut blob_response =
+        bazel_protos::remote_execution::BatchReadBlobsResponse_Response::new();
+      blob_response.set_digest(digest.clone());
+      match blobs.get(&hashing_digest.0) {
+        Some(bytes) => blob_response.set_data(bytes.clone()),
+        None => blob_response
+          .mut_status()
+          .set_code(bazel_protos::code::Code::NOT_FOUND.value()),
+      }
+      response.mut_responses().push(blob_response);
+    }
+    sink.success(response);
+  }
+
   fn get_tree(
     &self,
     _ctx: grpcio::RpcContext<'_>,
@@ -531,3 +805,157 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
     unimplemented!()
   }
 }
+
+impl bazel_protos::remote_execution_grpc::ActionCache for StubCASResponder {
+  fn get_action_result(
+    &self,
+    ctx: grpcio::RpcContext<'_>,
+    req: bazel_protos::remote_execution::GetActionResultRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_execution::ActionResult>,
+  ) {
+    check_auth!(self, ctx, sink);
+
+    if self.always_errors {
+      sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Internal,
+        Some("StubCAS is configured to always fail".to_owned()),
+      ));
+      return;
+    }
+    let action_digest: Result<Digest, String> = req.get_action_digest().into();
+    let action_digest = action_digest.expect("Bad digest");
+    let action_result = self.action_results.lock().get(&action_digest.0).cloned();
+    match action_result {
+      Some(action_result) => sink.success(action_result),
+      None => sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::NotFound,
+        Some(format!(
+          "Did not find action result for {:?}",
+          action_digest
+        )),
+      )),
+    };
+  }
+
+  fn update_action_result(
+    &self,
+    ctx: grpcio::RpcContext<'_>,
+    req: bazel_protos::remote_execution::UpdateActionResultRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_execution::ActionResult>,
+  ) {
+    check_auth!(self, ctx, sink);
+
+    if self.always_errors {
+      sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Internal,
+        Some("StubCAS is configured to always fail".to_owned()),
+      ));
+      return;
+    }
+    let action_digest: Result<Digest, String> = req.get_action_digest().into();
+    let action_digest = action_digest.expect("Bad digest");
+    self
+      .action_results
+      .lock()
+      .insert(action_digest.0, req.get_action_result().clone());
+    sink.success(req.get_action_result().clone());
+  }
+}
+
+impl bazel_protos::remote_execution_grpc::Capabilities for StubCASResponder {
+  fn get_capabilities(
+    &self,
+    ctx: grpcio::RpcContext<'_>,
+    _req: bazel_protos::remote_execution::GetCapabilitiesRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_execution::ServerCapabilities>,
+  ) {
+    check_auth!(self, ctx, sink);
+
+    match self.capabilities {
+      Some(ref capabilities) => sink.success(capabilities.clone()),
+      None => sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Unimplemented,
+        None,
+      )),
+    };
+  }
+}
+
+impl bazel_protos::remote_asset_grpc::Fetch for StubCASResponder {
+  fn fetch_blob(
+    &self,
+    ctx: grpcio::RpcContext<'_>,
+    req: bazel_protos::remote_asset::FetchBlobRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_asset::FetchBlobResponse>,
+  ) {
+    check_auth!(self, ctx, sink);
+
+    if self.assets.is_empty() {
+      sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Unimplemented,
+        None,
+      ));
+      return;
+    }
+    if req.get_instance_name() != self.instance_name() {
+      sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::NotFound,
+        Some(format!(
+          "Wrong instance_name; want {:?} got {:?}",
+          self.instance_name(),
+          req.get_instance_name()
+        )),
+      ));
+      return;
+    }
+    let mut checksum = None;
+    for qualifier in req.get_qualifiers() {
+      if qualifier.get_name() == "checksum.sri" {
+        checksum = Some(qualifier.get_value().to_owned());
+      } else {
+        sink.fail(grpcio::RpcStatus::new(
+          grpcio::RpcStatusCode::InvalidArgument,
+          Some(format!("\"{}\" not supported", qualifier.get_name())),
+        ));
+        return;
+      }
+    }
+
+    let mut response = bazel_protos::remote_asset::FetchBlobResponse::new();
+    let asset = req
+      .get_uris()
+      .iter()
+      .find_map(|uri| self.assets.get(uri).map(|asset| (uri, asset)));
+    match asset {
+      Some((uri, (digest, content))) => {
+        response.set_uri(uri.clone());
+        if checksum.map_or(true, |checksum| {
+          checksum == format!("sha256-{}", digest.0.to_base64())
+        }) {
+          self.blobs.lock().insert(digest.0, content.clone());
+          response.set_blob_digest(digest.into());
+        } else {
+          response
+            .mut_status()
+            .set_code(bazel_protos::code::Code::ABORTED.value());
+        }
+      }
+      None => response
+        .mut_status()
+        .set_code(bazel_protos::code::Code::NOT_FOUND.value()),
+    }
+    sink.success(response);
+  }
+
+  fn fetch_directory(
+    &self,
+    _ctx: grpcio::RpcContext<'_>,
+    _req: bazel_protos::remote_asset::FetchDirectoryRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_asset::FetchDirectoryResponse>,
+  ) {
+    sink.fail(grpcio::RpcStatus::new(
+      grpcio::RpcStatusCode::Unimplemented,
+      None,
+    ));
+  }
+}
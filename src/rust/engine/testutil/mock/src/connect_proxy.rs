@@ -0,0 +1,112 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use parking_lot::Mutex;
+
+///
+/// An HTTP proxy which tunnels connections with HTTP CONNECT, until it is dropped, recording the
+/// authority that each tunnel was opened to.
+///
+pub struct StubConnectProxy {
+  address: SocketAddr,
+  shutdown: Arc<AtomicBool>,
+  tunnels: Arc<Mutex<Vec<String>>>,
+}
+
+impl StubConnectProxy {
+  pub fn new() -> StubConnectProxy {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Error binding stub proxy");
+    let proxy = StubConnectProxy {
+      address: listener.local_addr().unwrap(),
+      shutdown: Arc::new(AtomicBool::new(false)),
+      tunnels: Arc::new(Mutex::new(vec![])),
+    };
+    let shutdown = proxy.shutdown.clone();
+    let tunnels = proxy.tunnels.clone();
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        if shutdown.load(Ordering::SeqCst) {
+          break;
+        }
+        if let Ok(stream) = stream {
+          let tunnels = tunnels.clone();
+          thread::spawn(move || tunnel(stream, &tunnels));
+        }
+      }
+    });
+    proxy
+  }
+
+  pub fn address(&self) -> SocketAddr {
+    self.address
+  }
+
+  ///
+  /// The authorities (as `host:port`) which tunnels were opened to, in order.
+  ///
+  pub fn tunnels(&self) -> Vec<String> {
+    self.tunnels.lock().clone()
+  }
+}
+
+impl Drop for StubConnectProxy {
+  fn drop(&mut self) {
+    self.shutdown.store(true, Ordering::SeqCst);
+    // Wake up the listener, so that it sees the shutdown.
+    let _ = TcpStream::connect(self.address);
+  }
+}
+
+fn tunnel(client: TcpStream, tunnels: &Mutex<Vec<String>>) {
+  let mut reader = BufReader::new(client.try_clone().unwrap());
+  let mut request_line = String::new();
+  if reader.read_line(&mut request_line).is_err() {
+    return;
+  }
+  loop {
+    let mut header = String::new();
+    if reader.read_line(&mut header).is_err() || header.trim().is_empty() {
+      break;
+    }
+  }
+
+  let mut client = client;
+  let mut parts = request_line.split_whitespace();
+  let authority = match (parts.next(), parts.next()) {
+    (Some("CONNECT"), Some(authority)) => authority.to_owned(),
+    _ => {
+      let _ = write!(
+        client,
+        "HTTP/1.1 405 Method Not Allowed\r\nConnection: close\r\n\r\n"
+      );
+      return;
+    }
+  };
+  let mut server = match TcpStream::connect(&authority) {
+    Ok(server) => server,
+    Err(_) => {
+      let _ = write!(
+        client,
+        "HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n"
+      );
+      return;
+    }
+  };
+  tunnels.lock().push(authority);
+  if write!(client, "HTTP/1.1 200 Connection established\r\n\r\n").is_err() {
+    return;
+  }
+
+  let mut server_reader = server.try_clone().unwrap();
+  let mut client_writer = client.try_clone().unwrap();
+  thread::spawn(move || {
+    let _ = io::copy(&mut server_reader, &mut client_writer);
+    let _ = client_writer.shutdown(Shutdown::Write);
+  });
+  // Starts with anything that the client sent after the CONNECT request, which the reader buffered.
+  let _ = io::copy(&mut reader, &mut server);
+  let _ = server.shutdown(Shutdown::Write);
+}
@@ -8,7 +8,7 @@ use std::time::Duration;
 use std::time::Instant;
 
 use bazel_protos;
-use futures::{Future, Sink};
+use futures::{future, Future, Sink};
 use grpcio;
 use parking_lot::Mutex;
 use protobuf;
@@ -20,6 +20,10 @@ use protobuf;
 /// client. If the duration is not None, it represents a delay before either responding or
 /// canceling for the operation.
 ///
+/// Operations are streamed in response to Execute and WaitExecution requests: each stream sends
+/// operations until it has sent a finished operation, has failed or been canceled, or has run out
+/// of operations, and then closes.
+///
 #[derive(Clone, Debug)]
 pub struct MockOperation {
   pub op: Result<Option<bazel_protos::operations::Operation>, grpcio::RpcStatus>,
@@ -40,6 +44,7 @@ pub struct MockExecution {
   name: String,
   execute_request: bazel_protos::remote_execution::ExecuteRequest,
   operation_responses: Arc<Mutex<VecDeque<MockOperation>>>,
+  capabilities: Option<bazel_protos::remote_execution::ServerCapabilities>,
 }
 
 impl MockExecution {
@@ -48,8 +53,8 @@ impl MockExecution {
   ///  * `name` - The name of the operation. It is assumed that all operation_responses use this
   ///             name.
   ///  * `execute_request` - The expected ExecuteRequest.
-  ///  * `operation_responses` - Vec of Operation response for Execution or GetOperation requests.
-  ///                            Will be returned in order.
+  ///  * `operation_responses` - Vec of Operation response for Execute, WaitExecution or
+  ///                            GetOperation requests. Will be returned in order.
   ///
   pub fn new(
     name: String,
@@ -60,13 +65,26 @@ impl MockExecution {
       name: name,
       execute_request: execute_request,
       operation_responses: Arc::new(Mutex::new(VecDeque::from(operation_responses))),
+      capabilities: None,
     }
   }
+
+  ///
+  /// The capabilities with which to answer GetCapabilities requests, which are otherwise rejected
+  /// as unimplemented. GetCapabilities requests aren't recorded as received messages.
+  ///
+  pub fn with_capabilities(
+    mut self,
+    capabilities: bazel_protos::remote_execution::ServerCapabilities,
+  ) -> MockExecution {
+    self.capabilities = Some(capabilities);
+    self
+  }
 }
 
 ///
-/// A server which will answer ExecuteRequest and GetOperation gRPC requests with pre-canned
-/// responses.
+/// A server which will answer ExecuteRequest, WaitExecutionRequest and GetOperation gRPC requests
+/// with pre-canned responses.
 ///
 pub struct TestServer {
   pub mock_responder: MockResponder,
@@ -77,11 +95,11 @@ impl TestServer {
   ///
   /// # Arguments
   /// * `mock_execution` - The canned responses to issue. Returns the MockExecution's
-  ///                      operation_responses in order to any ExecuteRequest or GetOperation
-  ///                      requests.
+  ///                      operation_responses in order to any ExecuteRequest, WaitExecutionRequest
+  ///                      or GetOperation requests.
   ///                      If an ExecuteRequest request is received which is not equal to this
   ///                      MockExecution's execute_request, an error will be returned.
-  ///                      If a GetOperation request is received whose name is not equal to this
+  ///                      If a WaitExecutionRequest is received whose name is not equal to this
   ///                      MockExecution's name, or more requests are received than stub responses
   ///                      are available for, an error will be returned.
   pub fn new(mock_execution: MockExecution) -> TestServer {
@@ -95,6 +113,9 @@ impl TestServer {
       .register_service(bazel_protos::operations_grpc::create_operations(
         mock_responder.clone(),
       ))
+      .register_service(bazel_protos::remote_execution_grpc::create_capabilities(
+        mock_responder.clone(),
+      ))
       .bind("localhost", 0)
       .build()
       .unwrap();
@@ -154,6 +175,10 @@ pub struct ReceivedMessage {
   pub message_type: String,
   pub message: Box<dyn protobuf::Message>,
   pub received_at: Instant,
+  ///
+  /// The metadata headers with which the message was sent.
+  ///
+  pub headers: Vec<(String, Vec<u8>)>,
 }
 
 #[derive(Clone, Debug)]
@@ -170,11 +195,16 @@ impl MockResponder {
     }
   }
 
-  fn log<T: protobuf::Message + Sized>(&self, message: T) {
+  fn log<T: protobuf::Message + Sized>(&self, ctx: &grpcio::RpcContext<'_>, message: T) {
     self.received_messages.lock().push(ReceivedMessage {
       message_type: message.descriptor().name().to_string(),
       message: Box::new(message),
       received_at: Instant::now(),
+      headers: ctx
+        .request_headers()
+        .iter()
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect(),
     });
   }
 
@@ -213,32 +243,28 @@ impl MockResponder {
     }
   }
 
-  fn send_next_operation_stream(
+  fn send_next_operations_stream(
     &self,
     ctx: &grpcio::RpcContext<'_>,
     sink: grpcio::ServerStreamingSink<bazel_protos::operations::Operation>,
   ) {
-    match self.mock_execution.operation_responses.lock().pop_front() {
-      Some(MockOperation { op, duration }) => {
-        if let Some(d) = duration {
-          sleep(d);
-        }
-        if let Ok(Some(op)) = op {
-          ctx.spawn(
-            sink
-              .send((op.clone(), grpcio::WriteFlags::default()))
-              .map(|mut stream| stream.close())
-              .map(|_| ())
-              .map_err(|_| ()),
-          )
-        } else if let Err(status) = op {
-          sink.fail(status);
-        } else {
-          // Cancel the request by dropping the sink.
-          drop(sink)
+    let mut operations = vec![];
+    {
+      let mut operation_responses = self.mock_execution.operation_responses.lock();
+      while let Some(operation) = operation_responses.pop_front() {
+        let ends_stream = match operation.op {
+          Ok(Some(ref op)) => op.get_done(),
+          _ => true,
+        };
+        operations.push(operation);
+        if ends_stream {
+          break;
         }
       }
-      None => ctx.spawn(
+    }
+
+    if operations.is_empty() {
+      ctx.spawn(
         sink
           .fail(grpcio::RpcStatus::new(
             grpcio::RpcStatusCode::InvalidArgument,
@@ -246,21 +272,44 @@ impl MockResponder {
           ))
           .map(|_| ())
           .map_err(|_| ()),
-      ),
+      );
+      return;
     }
+
+    // The operations are sent from another thread, so that their delays don't block the server.
+    std::thread::spawn(move || {
+      let mut sink = sink;
+      for MockOperation { op, duration } in operations {
+        if let Some(d) = duration {
+          sleep(d);
+        }
+        match op {
+          Ok(Some(op)) => match sink.send((op, grpcio::WriteFlags::default())).wait() {
+            Ok(s) => sink = s,
+            // The client has gone away.
+            Err(_) => return,
+          },
+          Err(status) => {
+            let _ = sink.fail(status).wait();
+            return;
+          }
+          // Cancel the request by dropping the sink.
+          Ok(None) => return,
+        }
+      }
+      let _ = future::poll_fn(|| sink.close()).wait();
+    });
   }
 }
 
 impl bazel_protos::remote_execution_grpc::Execution for MockResponder {
-  // We currently only support the one-shot "stream and disconnect" client behavior.
-  // If we start supporting the "stream updates" variant, we will need to do so here.
   fn execute(
     &self,
     ctx: grpcio::RpcContext<'_>,
     req: bazel_protos::remote_execution::ExecuteRequest,
     sink: grpcio::ServerStreamingSink<bazel_protos::operations::Operation>,
   ) {
-    self.log(req.clone());
+    self.log(&ctx, req.clone());
 
     if self.mock_execution.execute_request != req {
       ctx.spawn(
@@ -274,27 +323,61 @@ impl bazel_protos::remote_execution_grpc::Execution for MockResponder {
       return;
     }
 
-    self.send_next_operation_stream(&ctx, sink);
+    self.send_next_operations_stream(&ctx, sink);
   }
 
   fn wait_execution(
     &self,
-    _ctx: grpcio::RpcContext<'_>,
-    _req: bazel_protos::remote_execution::WaitExecutionRequest,
-    _sink: grpcio::ServerStreamingSink<bazel_protos::operations::Operation>,
+    ctx: grpcio::RpcContext<'_>,
+    req: bazel_protos::remote_execution::WaitExecutionRequest,
+    sink: grpcio::ServerStreamingSink<bazel_protos::operations::Operation>,
+  ) {
+    self.log(&ctx, req.clone());
+
+    if self.mock_execution.name != req.get_name() {
+      ctx.spawn(
+        sink
+          .fail(grpcio::RpcStatus::new(
+            grpcio::RpcStatusCode::NotFound,
+            Some(format!(
+              "Did not expect to wait on operation {}",
+              req.get_name()
+            )),
+          ))
+          .map_err(|_| ()),
+      );
+      return;
+    }
+
+    self.send_next_operations_stream(&ctx, sink);
+  }
+}
+
+impl bazel_protos::remote_execution_grpc::Capabilities for MockResponder {
+  fn get_capabilities(
+    &self,
+    _: grpcio::RpcContext<'_>,
+    _: bazel_protos::remote_execution::GetCapabilitiesRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_execution::ServerCapabilities>,
   ) {
-    unimplemented!()
+    match self.mock_execution.capabilities {
+      Some(ref capabilities) => sink.success(capabilities.clone()),
+      None => sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Unimplemented,
+        None,
+      )),
+    };
   }
 }
 
 impl bazel_protos::operations_grpc::Operations for MockResponder {
   fn get_operation(
     &self,
-    _: grpcio::RpcContext<'_>,
+    ctx: grpcio::RpcContext<'_>,
     req: bazel_protos::operations::GetOperationRequest,
     sink: grpcio::UnarySink<bazel_protos::operations::Operation>,
   ) {
-    self.log(req.clone());
+    self.log(&ctx, req.clone());
 
     self.send_next_operation_unary(sink)
   }
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use bytes::Bytes;
+use hashing::Fingerprint;
+use parking_lot::Mutex;
+use testutil::data::{TestData, TestDirectory};
+
+///
+/// Implements the HTTP caching protocol of Bazel: blobs are stored with PUT, and fetched with GET
+/// (or checked for with HEAD) at `/cas/<fingerprint>`, and ActionResults likewise at
+/// `/ac/<fingerprint of the action>`, optionally below a prefix (e.g. an instance name).
+///
+/// Serves one request per connection, which is closed after each response.
+///
+pub struct StubHttpCache {
+  address: SocketAddr,
+  shutdown: Arc<AtomicBool>,
+  always_errors: bool,
+  pub blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
+  pub action_results: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
+  ///
+  /// The method and path of each request received.
+  ///
+  pub requests: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl StubHttpCache {
+  pub fn empty() -> StubHttpCache {
+    StubHttpCache::new(false)
+  }
+
+  ///
+  /// A cache which fails every request with a server error.
+  ///
+  pub fn always_errors() -> StubHttpCache {
+    StubHttpCache::new(true)
+  }
+
+  fn new(always_errors: bool) -> StubHttpCache {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Error binding stub HTTP cache");
+    let address = listener.local_addr().unwrap();
+    let cache = StubHttpCache {
+      address,
+      shutdown: Arc::new(AtomicBool::new(false)),
+      always_errors,
+      blobs: Arc::new(Mutex::new(HashMap::new())),
+      action_results: Arc::new(Mutex::new(HashMap::new())),
+      requests: Arc::new(Mutex::new(vec![])),
+    };
+    let responder = cache.responder();
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        if responder.shutdown.load(Ordering::SeqCst) {
+          break;
+        }
+        if let Ok(stream) = stream {
+          let responder = responder.clone();
+          thread::spawn(move || responder.serve(stream));
+        }
+      }
+    });
+    cache
+  }
+
+  fn responder(&self) -> Responder {
+    Responder {
+      shutdown: self.shutdown.clone(),
+      always_errors: self.always_errors,
+      blobs: self.blobs.clone(),
+      action_results: self.action_results.clone(),
+      requests: self.requests.clone(),
+    }
+  }
+
+  pub fn file(self, file: &TestData) -> StubHttpCache {
+    self.blobs.lock().insert(file.fingerprint(), file.bytes());
+    self
+  }
+
+  pub fn directory(self, directory: &TestDirectory) -> StubHttpCache {
+    self
+      .blobs
+      .lock()
+      .insert(directory.fingerprint(), directory.bytes());
+    self
+  }
+
+  ///
+  /// Serves the given content for the given fingerprint, whether or not it matches.
+  ///
+  pub fn unverified_content(self, fingerprint: Fingerprint, content: Bytes) -> StubHttpCache {
+    self.blobs.lock().insert(fingerprint, content);
+    self
+  }
+
+  pub fn address(&self) -> String {
+    format!("http://{}", self.address)
+  }
+
+  pub fn request_count(&self, method: &str) -> usize {
+    self
+      .requests
+      .lock()
+      .iter()
+      .filter(|(request_method, _)| request_method == method)
+      .count()
+  }
+}
+
+impl Drop for StubHttpCache {
+  fn drop(&mut self) {
+    self.shutdown.store(true, Ordering::SeqCst);
+    // Wake up the listener, so that it sees the shutdown.
+    let _ = TcpStream::connect(self.address);
+  }
+}
+
+#[derive(Clone)]
+struct Responder {
+  shutdown: Arc<AtomicBool>,
+  always_errors: bool,
+  blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
+  action_results: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
+  requests: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl Responder {
+  fn serve(&self, stream: TcpStream) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+      return;
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let path = parts.next().unwrap_or_default().to_owned();
+
+    let mut content_length = 0;
+    loop {
+      let mut header = String::new();
+      if reader.read_line(&mut header).is_err() || header.trim().is_empty() {
+        break;
+      }
+      let mut header_parts = header.splitn(2, ':');
+      if let (Some(name), Some(value)) = (header_parts.next(), header_parts.next()) {
+        if name.trim().eq_ignore_ascii_case("content-length") {
+          content_length = value.trim().parse().unwrap_or(0);
+        }
+      }
+    }
+    let mut body = vec![0; content_length];
+    if reader.read_exact(&mut body).is_err() {
+      return;
+    }
+
+    self.requests.lock().push((method.clone(), path.clone()));
+    let (status, content) = self.respond(&method, &path, Bytes::from(body));
+    let content = if method == "HEAD" {
+      Bytes::new()
+    } else {
+      content
+    };
+    let mut stream = stream;
+    let _ = write!(
+      stream,
+      "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+      status,
+      content.len()
+    )
+    .and_then(|()| stream.write_all(&content));
+  }
+
+  fn respond(&self, method: &str, path: &str, body: Bytes) -> (&'static str, Bytes) {
+    if self.always_errors {
+      return ("500 Internal Server Error", Bytes::from("Stub error"));
+    }
+    // Paths are of the form [/prefix]/{ac,cas}/<fingerprint>.
+    let mut segments = path.rsplit('/');
+    let fingerprint = segments
+      .next()
+      .and_then(|fingerprint| Fingerprint::from_hex_string(fingerprint).ok());
+    let store = match segments.next() {
+      Some("cas") => &self.blobs,
+      Some("ac") => &self.action_results,
+      _ => return ("404 Not Found", Bytes::new()),
+    };
+    let fingerprint = match fingerprint {
+      Some(fingerprint) => fingerprint,
+      None => return ("400 Bad Request", Bytes::from("Invalid fingerprint")),
+    };
+    match method {
+      "GET" | "HEAD" => match store.lock().get(&fingerprint) {
+        Some(content) => ("200 OK", content.clone()),
+        None => ("404 Not Found", Bytes::new()),
+      },
+      "PUT" => {
+        store.lock().insert(fingerprint, body);
+        ("200 OK", Bytes::new())
+      }
+      _ => ("405 Method Not Allowed", Bytes::new()),
+    }
+  }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+///
+/// A request received by a stub HTTP server.
+///
+#[derive(Clone, Debug)]
+pub struct StubRequest {
+  pub method: String,
+  pub path: String,
+  pub query: String,
+  ///
+  /// With lowercase names.
+  ///
+  pub headers: HashMap<String, String>,
+}
+
+impl StubRequest {
+  ///
+  /// The (undecoded) values of the parameters of the query.
+  ///
+  pub fn query_parameters(&self) -> HashMap<String, String> {
+    self
+      .query
+      .split('&')
+      .filter(|parameter| !parameter.is_empty())
+      .map(|parameter| {
+        let mut parameter = parameter.splitn(2, '=');
+        (
+          parameter.next().unwrap_or_default().to_owned(),
+          parameter.next().unwrap_or_default().to_owned(),
+        )
+      })
+      .collect()
+  }
+}
+
+///
+/// A response of a stub HTTP server.
+///
+pub struct StubResponse {
+  pub status: &'static str,
+  pub headers: Vec<(String, String)>,
+  pub content: Bytes,
+}
+
+impl StubResponse {
+  pub fn new(status: &'static str, content: Bytes) -> StubResponse {
+    StubResponse {
+      status,
+      headers: vec![],
+      content,
+    }
+  }
+
+  pub fn header(mut self, name: &str, value: &str) -> StubResponse {
+    self.headers.push((name.to_owned(), value.to_owned()));
+    self
+  }
+}
+
+///
+/// An HTTP server which serves one request per connection (closing it after each response) until
+/// it is dropped, recording every request that it receives.
+///
+pub struct StubServer {
+  address: SocketAddr,
+  shutdown: Arc<AtomicBool>,
+  pub requests: Arc<Mutex<Vec<StubRequest>>>,
+}
+
+impl StubServer {
+  pub fn new<F: Fn(&StubRequest, Bytes) -> StubResponse + Send + Sync + 'static>(
+    respond: F,
+  ) -> StubServer {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Error binding stub server");
+    let server = StubServer {
+      address: listener.local_addr().unwrap(),
+      shutdown: Arc::new(AtomicBool::new(false)),
+      requests: Arc::new(Mutex::new(vec![])),
+    };
+    let shutdown = server.shutdown.clone();
+    let requests = server.requests.clone();
+    let respond = Arc::new(respond);
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        if shutdown.load(Ordering::SeqCst) {
+          break;
+        }
+        if let Ok(stream) = stream {
+          let requests = requests.clone();
+          let respond = respond.clone();
+          thread::spawn(move || serve(stream, &requests, &*respond));
+        }
+      }
+    });
+    server
+  }
+
+  pub fn address(&self) -> SocketAddr {
+    self.address
+  }
+
+  pub fn request_count(&self, method: &str) -> usize {
+    self
+      .requests
+      .lock()
+      .iter()
+      .filter(|request| request.method == method)
+      .count()
+  }
+}
+
+impl Drop for StubServer {
+  fn drop(&mut self) {
+    self.shutdown.store(true, Ordering::SeqCst);
+    // Wake up the listener, so that it sees the shutdown.
+    let _ = TcpStream::connect(self.address);
+  }
+}
+
+fn serve<F: Fn(&StubRequest, Bytes) -> StubResponse>(
+  stream: TcpStream,
+  requests: &Mutex<Vec<StubRequest>>,
+  respond: &F,
+) {
+  let mut reader = BufReader::new(stream.try_clone().unwrap());
+  let mut request_line = String::new();
+  if reader.read_line(&mut request_line).is_err() {
+    return;
+  }
+  let mut parts = request_line.split_whitespace();
+  let method = parts.next().unwrap_or_default().to_owned();
+  let mut target = parts.next().unwrap_or_default().splitn(2, '?');
+  let path = target.next().unwrap_or_default().to_owned();
+  let query = target.next().unwrap_or_default().to_owned();
+
+  let mut headers = HashMap::new();
+  loop {
+    let mut header = String::new();
+    if reader.read_line(&mut header).is_err() || header.trim().is_empty() {
+      break;
+    }
+    let mut header_parts = header.splitn(2, ':');
+    if let (Some(name), Some(value)) = (header_parts.next(), header_parts.next()) {
+      headers.insert(name.trim().to_lowercase(), value.trim().to_owned());
+    }
+  }
+  let content_length = headers
+    .get("content-length")
+    .and_then(|length| length.parse().ok())
+    .unwrap_or(0);
+  let mut body = vec![0; content_length];
+  if reader.read_exact(&mut body).is_err() {
+    return;
+  }
+
+  let request = StubRequest {
+    method,
+    path,
+    query,
+    headers,
+  };
+  requests.lock().push(request.clone());
+  let response = respond(&request, Bytes::from(body));
+  let content = if request.method == "HEAD" {
+    Bytes::new()
+  } else {
+    response.content
+  };
+  let headers = response
+    .headers
+    .iter()
+    .map(|(name, value)| format!("{}: {}\r\n", name, value))
+    .collect::<String>();
+  let mut stream = stream;
+  let _ = write!(
+    stream,
+    "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+    response.status,
+    headers,
+    content.len()
+  )
+  .and_then(|()| stream.write_all(&content));
+}
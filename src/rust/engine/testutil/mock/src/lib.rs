@@ -28,4 +28,16 @@
 
 mod cas;
 pub use crate::cas::StubCAS;
+mod connect_proxy;
+pub use crate::connect_proxy::StubConnectProxy;
 pub mod execution_server;
+mod gcs;
+pub use crate::gcs::{StubGcs, STUB_GCS_ACCESS_TOKEN};
+mod http_cache;
+pub use crate::http_cache::StubHttpCache;
+mod http_server;
+pub use crate::http_server::StubRequest;
+mod memory_cache;
+pub use crate::memory_cache::StubMemoryCache;
+mod s3;
+pub use crate::s3::StubS3;
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+#[derive(Clone, Copy)]
+enum Protocol {
+  Redis,
+  Memcached,
+}
+
+///
+/// Implements GET and SET of Redis (in RESP), or get and set of memcached (in its text protocol),
+/// serving any number of commands per connection until it is dropped. Expiry is ignored.
+///
+pub struct StubMemoryCache {
+  protocol: Protocol,
+  address: SocketAddr,
+  shutdown: Arc<AtomicBool>,
+  connections: Arc<AtomicUsize>,
+  pub entries: Arc<Mutex<HashMap<String, Bytes>>>,
+}
+
+impl StubMemoryCache {
+  pub fn redis() -> StubMemoryCache {
+    StubMemoryCache::new(Protocol::Redis)
+  }
+
+  pub fn memcached() -> StubMemoryCache {
+    StubMemoryCache::new(Protocol::Memcached)
+  }
+
+  fn new(protocol: Protocol) -> StubMemoryCache {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Error binding stub memory cache");
+    let cache = StubMemoryCache {
+      protocol,
+      address: listener.local_addr().unwrap(),
+      shutdown: Arc::new(AtomicBool::new(false)),
+      connections: Arc::new(AtomicUsize::new(0)),
+      entries: Arc::new(Mutex::new(HashMap::new())),
+    };
+    let shutdown = cache.shutdown.clone();
+    let connections = cache.connections.clone();
+    let entries = cache.entries.clone();
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        if shutdown.load(Ordering::SeqCst) {
+          break;
+        }
+        if let Ok(stream) = stream {
+          connections.fetch_add(1, Ordering::SeqCst);
+          let entries = entries.clone();
+          let shutdown = shutdown.clone();
+          thread::spawn(move || serve(protocol, stream, &entries, &shutdown));
+        }
+      }
+    });
+    cache
+  }
+
+  ///
+  /// The URL of the cache, with the given parameters.
+  ///
+  pub fn url(&self, parameters: &str) -> String {
+    let scheme = match self.protocol {
+      Protocol::Redis => "redis",
+      Protocol::Memcached => "memcached",
+    };
+    format!("{}://{}?{}", scheme, self.address, parameters)
+  }
+
+  ///
+  /// The number of connections which have been made to the cache.
+  ///
+  pub fn connection_count(&self) -> usize {
+    self.connections.load(Ordering::SeqCst)
+  }
+}
+
+impl Drop for StubMemoryCache {
+  fn drop(&mut self) {
+    self.shutdown.store(true, Ordering::SeqCst);
+    // Wake up the listener, so that it sees the shutdown.
+    let _ = TcpStream::connect(self.address);
+  }
+}
+
+fn serve(
+  protocol: Protocol,
+  stream: TcpStream,
+  entries: &Mutex<HashMap<String, Bytes>>,
+  shutdown: &AtomicBool,
+) {
+  let mut reader = BufReader::new(stream.try_clone().unwrap());
+  let mut writer = stream;
+  while !shutdown.load(Ordering::SeqCst) {
+    let reply = match protocol {
+      Protocol::Redis => serve_redis(&mut reader, entries),
+      Protocol::Memcached => serve_memcached(&mut reader, entries),
+    };
+    match reply {
+      Some(reply) => {
+        if writer.write_all(&reply).is_err() {
+          return;
+        }
+      }
+      None => return,
+    }
+  }
+}
+
+fn read_line(reader: &mut BufReader<TcpStream>) -> Option<String> {
+  let mut line = String::new();
+  match reader.read_line(&mut line) {
+    Ok(0) | Err(_) => None,
+    Ok(_) => Some(line.trim_end_matches("\r\n").to_owned()),
+  }
+}
+
+fn read_exactly(reader: &mut BufReader<TcpStream>, length: usize) -> Option<Vec<u8>> {
+  // Including the CRLF which follows.
+  let mut value = vec![0; length + 2];
+  reader.read_exact(&mut value).ok()?;
+  value.truncate(length);
+  Some(value)
+}
+
+fn serve_redis(
+  reader: &mut BufReader<TcpStream>,
+  entries: &Mutex<HashMap<String, Bytes>>,
+) -> Option<Vec<u8>> {
+  let argument_count: usize = read_line(reader)?.trim_start_matches('*').parse().ok()?;
+  let mut arguments = vec![];
+  for _ in 0..argument_count {
+    let length = read_line(reader)?.trim_start_matches('$').parse().ok()?;
+    arguments.push(read_exactly(reader, length)?);
+  }
+  let command = String::from_utf8_lossy(arguments.first()?).to_uppercase();
+  let reply = match (command.as_str(), arguments.len()) {
+    ("GET", 2) => match entries
+      .lock()
+      .get(String::from_utf8_lossy(&arguments[1]).as_ref())
+    {
+      Some(value) => {
+        let mut reply = format!("${}\r\n", value.len()).into_bytes();
+        reply.extend_from_slice(value);
+        reply.extend_from_slice(b"\r\n");
+        reply
+      }
+      None => b"$-1\r\n".to_vec(),
+    },
+    ("SET", _) if arguments.len() >= 3 => {
+      entries.lock().insert(
+        String::from_utf8_lossy(&arguments[1]).into_owned(),
+        Bytes::from(arguments[2].clone()),
+      );
+      b"+OK\r\n".to_vec()
+    }
+    _ => format!("-ERR unknown command '{}'\r\n", command).into_bytes(),
+  };
+  Some(reply)
+}
+
+fn serve_memcached(
+  reader: &mut BufReader<TcpStream>,
+  entries: &Mutex<HashMap<String, Bytes>>,
+) -> Option<Vec<u8>> {
+  let line = read_line(reader)?;
+  let words = line.split(' ').collect::<Vec<_>>();
+  let reply = match words.as_slice() {
+    ["get", key] => match entries.lock().get(*key) {
+      Some(value) => {
+        let mut reply = format!("VALUE {} 0 {}\r\n", key, value.len()).into_bytes();
+        reply.extend_from_slice(value);
+        reply.extend_from_slice(b"\r\nEND\r\n");
+        reply
+      }
+      None => b"END\r\n".to_vec(),
+    },
+    ["set", key, _flags, _ttl, length] => {
+      let value = read_exactly(reader, length.parse().ok()?)?;
+      entries.lock().insert((*key).to_owned(), Bytes::from(value));
+      b"STORED\r\n".to_vec()
+    }
+    _ => b"ERROR\r\n".to_vec(),
+  };
+  Some(reply)
+}
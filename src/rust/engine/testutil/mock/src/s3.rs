@@ -0,0 +1,125 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use crate::http_server::{StubRequest, StubResponse, StubServer};
+
+///
+/// Implements the parts of the S3 API which buckets are used with as remote stores: objects are
+/// stored with PUT (in one request, or in parts with a multipart upload), and fetched with GET (or
+/// checked for with HEAD), at `/<bucket>/<key>`. Requests aren't authenticated.
+///
+pub struct StubS3 {
+  server: StubServer,
+  pub objects: Arc<Mutex<HashMap<String, Bytes>>>,
+  ///
+  /// Each request received.
+  ///
+  pub requests: Arc<Mutex<Vec<StubRequest>>>,
+}
+
+impl StubS3 {
+  pub fn empty() -> StubS3 {
+    let objects = Arc::new(Mutex::new(HashMap::new()));
+    let bucket = Bucket {
+      objects: objects.clone(),
+      uploads: Arc::new(Mutex::new(HashMap::new())),
+      next_upload_id: Arc::new(AtomicUsize::new(0)),
+    };
+    let server = StubServer::new(move |request, body| bucket.respond(request, body));
+    StubS3 {
+      requests: server.requests.clone(),
+      server,
+      objects,
+    }
+  }
+
+  ///
+  /// The URL of the bucket named `bucket`, with the given parameters.
+  ///
+  pub fn url(&self, parameters: &str) -> String {
+    format!(
+      "s3://bucket?endpoint=http://{}&{}",
+      self.server.address(),
+      parameters
+    )
+  }
+
+  pub fn request_count(&self, method: &str) -> usize {
+    self.server.request_count(method)
+  }
+}
+
+struct Bucket {
+  objects: Arc<Mutex<HashMap<String, Bytes>>>,
+  // The parts of each multipart upload in progress, by upload id.
+  uploads: Arc<Mutex<HashMap<String, BTreeMap<usize, Bytes>>>>,
+  next_upload_id: Arc<AtomicUsize>,
+}
+
+impl Bucket {
+  fn respond(&self, request: &StubRequest, body: Bytes) -> StubResponse {
+    let not_found = |code: &str| StubResponse::new("404 Not Found", Bytes::from(code));
+    let ok = |content: Bytes| StubResponse::new("200 OK", content);
+    let key = match request.path.trim_start_matches('/').splitn(2, '/').nth(1) {
+      Some(key) if request.path.starts_with("/bucket/") => key.to_owned(),
+      _ => return not_found("NoSuchBucket"),
+    };
+    let query = request.query_parameters();
+    match (request.method.as_str(), query.get("uploadId")) {
+      ("GET", None) | ("HEAD", None) => match self.objects.lock().get(&key) {
+        Some(content) => ok(content.clone()),
+        None => not_found("NoSuchKey"),
+      },
+      ("PUT", None) => {
+        self.objects.lock().insert(key, body);
+        ok(Bytes::new())
+      }
+      ("POST", None) if query.contains_key("uploads") => {
+        let upload_id = format!(
+          "upload-{}",
+          self.next_upload_id.fetch_add(1, Ordering::SeqCst)
+        );
+        self
+          .uploads
+          .lock()
+          .insert(upload_id.clone(), BTreeMap::new());
+        ok(Bytes::from(format!(
+          "<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+          upload_id
+        )))
+      }
+      ("PUT", Some(upload_id)) => {
+        let part_number = query.get("partNumber").and_then(|n| n.parse().ok());
+        match (self.uploads.lock().get_mut(upload_id), part_number) {
+          (Some(parts), Some(part_number)) => {
+            parts.insert(part_number, body);
+            ok(Bytes::new()).header("ETag", &format!("\"etag-{}\"", part_number))
+          }
+          _ => not_found("NoSuchUpload"),
+        }
+      }
+      ("POST", Some(upload_id)) => match self.uploads.lock().remove(upload_id) {
+        Some(parts) => {
+          let content = parts
+            .values()
+            .flat_map(|part| part.iter().cloned())
+            .collect::<Vec<_>>();
+          self.objects.lock().insert(key, Bytes::from(content));
+          ok(Bytes::from(
+            "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>",
+          ))
+        }
+        None => not_found("NoSuchUpload"),
+      },
+      ("DELETE", Some(upload_id)) => {
+        self.uploads.lock().remove(upload_id);
+        StubResponse::new("204 No Content", Bytes::new())
+      }
+      _ => StubResponse::new("405 Method Not Allowed", Bytes::new()),
+    }
+  }
+}
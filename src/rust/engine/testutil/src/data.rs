@@ -301,6 +301,36 @@ impl TestDirectory {
   }
 }
 
+///
+/// A Tree proto: a root Directory and all of the Directories beneath it, as referred to by the
+/// output directories of ActionResults.
+///
+#[derive(Clone)]
+pub struct TestTree {
+  tree: bazel_protos::remote_execution::Tree,
+}
+
+impl TestTree {
+  pub fn new(root: &TestDirectory, children: &[TestDirectory]) -> TestTree {
+    let mut tree = bazel_protos::remote_execution::Tree::new();
+    tree.set_root(root.directory());
+    tree.set_children(children.iter().map(TestDirectory::directory).collect());
+    TestTree { tree }
+  }
+
+  pub fn bytes(&self) -> bytes::Bytes {
+    bytes::Bytes::from(self.tree.write_to_bytes().expect("Error serializing proto"))
+  }
+
+  pub fn fingerprint(&self) -> hashing::Fingerprint {
+    hash(&self.bytes())
+  }
+
+  pub fn digest(&self) -> hashing::Digest {
+    hashing::Digest(self.fingerprint(), self.bytes().len())
+  }
+}
+
 fn hash(bytes: &bytes::Bytes) -> hashing::Fingerprint {
   let mut hasher = sha2::Sha256::default();
   hasher.input(bytes);
@@ -159,7 +159,10 @@ impl EngineDisplay {
   fn get_max_log_rows(&self) -> usize {
     // TODO: If the terminal size is smaller than the action map, we should fall back
     // to non-tty mode output to avoid.
-    self.terminal_size.1 as usize - self.action_map.len() - 1
+    // Rows above the start of the frame are taken by emitted output: see `emit`.
+    (self.terminal_size.1 as usize)
+      .saturating_sub((self.cursor_start.1 as usize).saturating_sub(1))
+      .saturating_sub(self.action_map.len() + 1)
   }
 
   // Prep the screen for painting by clearing it from the cursor start position.
@@ -333,6 +336,22 @@ impl EngineDisplay {
     self.logs.push_front(log_entry)
   }
 
+  // Writes output (e.g. of a process) above the current frame, where it persists rather than
+  // being redrawn: subsequent frames are rendered below it.
+  pub fn emit(&mut self, output: &str) {
+    self.clear();
+    // Newlines don't return the cursor to the start of the line in raw mode.
+    let mut output = output.replace('\n', "\r\n");
+    if !output.ends_with('\n') {
+      output.push_str("\r\n");
+    }
+    self.write(&output).expect("could not write to terminal");
+    match self.get_cursor_pos() {
+      (0, 0) => {}
+      cursor_pos => self.cursor_start = (1, cursor_pos.1),
+    }
+  }
+
   pub fn worker_count(&self) -> usize {
     self.action_map.len()
   }
@@ -7,7 +7,8 @@ from __future__ import absolute_import, division, print_function, unicode_litera
 import unittest
 from textwrap import dedent
 
-from pants.option.custom_types import ListValueComponent, UnsetBool, dict_option, list_option
+from pants.option.custom_types import (ListValueComponent, UnsetBool, dict_option,
+                                       duration_option, list_option, memory_size_option)
 from pants.option.errors import ParseError
 
 
@@ -63,6 +64,26 @@ class CustomTypesTest(unittest.TestCase):
     self._do_test([1, 2], '+[1,2]')
     self._do_test(['\\'], '\\')
 
+  def test_duration(self):
+    self.assertEqual(90.0, duration_option('90'))
+    self.assertEqual(1.5, duration_option('1.5s'))
+    self.assertEqual(1800.0, duration_option('30m'))
+    self.assertEqual(7200.0, duration_option(' 2h '))
+    self.assertEqual(5.0, duration_option(5))
+    for invalid in ('', 'm', '30 minutes', '-5s', '1d'):
+      with self.assertRaises(ParseError):
+        duration_option(invalid)
+
+  def test_memory_size(self):
+    self.assertEqual(1024, memory_size_option('1024'))
+    self.assertEqual(512, memory_size_option('512B'))
+    self.assertEqual(4 * 1024 * 1024, memory_size_option(' 4 MiB '))
+    self.assertEqual(20 * 1024 * 1024 * 1024, memory_size_option('20GiB'))
+    self.assertEqual(5, memory_size_option(5))
+    for invalid in ('', 'GiB', '20GB', '-1', '1.5GiB'):
+      with self.assertRaises(ParseError):
+        memory_size_option(invalid)
+
   def test_split_list_modifier_expressions(self):
     self._do_split('1', ['1'])
     self._do_split('foo', ['foo'])